@@ -0,0 +1,143 @@
+//! Compares writing the game data to the archive with serializing it to a `String` and
+//! writing that, which is how it used to be done. The archive builds the compressed data in
+//! memory, and checksums and maybe encrypts it before it's written, so it isn't faster.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    hint::black_box,
+    io::{Seek, SeekFrom, Write},
+};
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use engine::{
+    game::{
+        Codex, GameData, ImageCache, PcDescription, StoredImageInfo, Summary, SummaryLevel,
+        TurnData, TurnInput, TurnOutput, WorldDescription, WorldState, migration,
+    },
+    save_archive::SaveArchive,
+};
+use tempfile::NamedTempFile;
+
+fn make_game_data(turns: usize) -> GameData {
+    let text =
+        "The rain drums on the roof of the tavern while the bard tunes her lute. ".repeat(30);
+    let turn_data = (0..turns)
+        .map(|i| TurnData {
+            summary_before_input: (i >= 8).then(|| i / 8 - 1),
+            input: TurnInput {
+                player_action: format!("Do action {i}"),
+                gm_instruction: "".into(),
+            },
+            output: TurnOutput {
+                text: text.clone(),
+                secret_info: format!("Secret info {i}"),
+                elapsed_minutes: None,
+                proposed_next_actions: [
+                    format!("Action A{i}"),
+                    format!("Action B{i}"),
+                    format!("Action C{i}"),
+                ],
+                input_tokens: 5,
+                output_tokens: 10,
+                image_description: text[..400].to_string(),
+                image_caption: format!("caption {i}"),
+                codex_updates: vec![],
+                world_state_updates: vec![],
+                rolls: vec![],
+            },
+            images: vec![StoredImageInfo {
+                id: i,
+                caption: format!("caption {i}"),
+                original_id: None,
+                seed: None,
+            }],
+            bookmarked: false,
+            bookmark_label: None,
+            world_state: None,
+        })
+        .collect();
+    let summaries = (0..turns / 8)
+        .map(|i| Summary {
+            content: text.clone(),
+            bday: i * 8,
+            level: SummaryLevel::Chapter,
+        })
+        .collect();
+
+    GameData {
+        schema_version: migration::CURRENT_SCHEMA_VERSION,
+        world_description: WorldDescription {
+            main_description: "A fantasy world with dragons".into(),
+            pc_descriptions: BTreeMap::from([(
+                "Alice".to_string(),
+                PcDescription {
+                    description: "A brave warrior".into(),
+                    initial_action: "".into(),
+                    stats: BTreeMap::new(),
+                },
+            )]),
+            init_action: "Look around".into(),
+            name: "World name".into(),
+            sampling: Default::default(),
+            target_output_words: None,
+            max_tokens: None,
+            system_prompt_template: None,
+            summary_prompt: None,
+            history_turns: None,
+            preferred_llm: None,
+            preferred_image_model: None,
+            text_only: false,
+            content_rating: None,
+            tone: Default::default(),
+            locations: vec![],
+            factions: vec![],
+            npcs: vec![],
+            script: None,
+            cover: None,
+        },
+        pc: "Alice".into(),
+        summaries,
+        turn_data,
+        costs: Default::default(),
+        in_flight_turn: None,
+        image_cache: ImageCache::default(),
+        text_only: false,
+        difficulty: Default::default(),
+        pinned_seeds: BTreeMap::new(),
+        snapshots: vec![],
+        codex: Codex::default(),
+        world_state: WorldState::new(),
+        branch: String::new(),
+        branches: vec![],
+        recap: None,
+    }
+}
+
+fn write_game_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_game_data");
+    group.sample_size(20);
+    for turns in [100, 1000, 4000] {
+        let data = make_game_data(turns);
+
+        let tmpfile = NamedTempFile::new().unwrap();
+        let mut archive = SaveArchive::create(tmpfile.path()).unwrap();
+        group.bench_with_input(BenchmarkId::new("archive", turns), &data, |b, data| {
+            b.iter(|| archive.write_game_data(black_box(data)).unwrap())
+        });
+
+        let mut file: File = NamedTempFile::new().unwrap().into_file();
+        group.bench_with_input(BenchmarkId::new("via_string", turns), &data, |b, data| {
+            b.iter(|| {
+                let json = serde_json::to_string(black_box(data)).unwrap();
+                file.seek(SeekFrom::Start(SaveArchive::HEADER_SIZE))
+                    .unwrap();
+                file.write_all(json.as_bytes()).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, write_game_data);
+criterion_main!(benches);
@@ -0,0 +1,422 @@
+//! Runs all accesses to a `SaveArchive` on a dedicated thread.
+//!
+//! Serializing the game data and writing images takes long enough to cause visible hitches
+//! when it happens while the GUI handles a message. Therefore writes are only queued, and
+//! the worker performs them in order. Reads wait for their answer, and see every write
+//! that was queued before them.
+//!
+//! Image ids are assigned sequentially, so `append_image` can return the id of an image
+//! before it's written. A write that fails is reported by the next call to the worker, which
+//! then takes the number of images from the archive again, so an image that wasn't appended
+//! doesn't shift the ids of the later ones.
+
+use std::{
+    mem,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+    thread::{self, JoinHandle},
+};
+
+use color_eyre::eyre::eyre;
+use log::error;
+use tokio::sync::oneshot;
+
+use crate::{
+    audit_log::AuditEntry,
+    error::EngineError,
+    export::{self, StoryFormat},
+    game::GameData,
+    save_archive::{BackupSettings, Compaction, SaveArchive},
+};
+
+type Reply<T> = Sender<Result<T, EngineError>>;
+
+enum Command {
+    WriteGameData(Arc<GameData>),
+    AppendImage(Vec<u8>),
+    AppendTranscript(usize, Vec<AuditEntry>),
+    ReadTranscript(usize, Reply<Option<Vec<AuditEntry>>>),
+    ReadGameData(Reply<GameData>),
+    ReadImage(usize, Reply<Vec<u8>>),
+    ReadImageLater(usize, oneshot::Sender<Result<Vec<u8>, EngineError>>),
+    ReadThumbnailLater(usize, oneshot::Sender<Result<Vec<u8>, EngineError>>),
+    /// replies with the number of images that are left
+    ClipAfterTurn(usize, Reply<usize>),
+    ForkAfter(usize, String, Reply<()>),
+    SwitchBranch(usize, Reply<()>),
+    WriteTo(PathBuf, Reply<()>),
+    SaveAs(PathBuf, Reply<()>),
+    ForkClipped(PathBuf, usize, Reply<()>),
+    /// replies with the number of exported images
+    ExportImages(PathBuf, Reply<usize>),
+    /// replies with the number of exported turns
+    ExportStory(PathBuf, StoryFormat, bool, Reply<usize>),
+    Compact(Reply<Compaction>),
+    SetBackups(BackupSettings),
+}
+
+/// The first failed write, that wasn't reported yet
+#[derive(Debug)]
+struct Failure {
+    error: EngineError,
+    /// the number of images in the archive, after the last command was performed
+    n_images: usize,
+}
+
+#[derive(Debug)]
+pub struct ArchiveWorker {
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+    failure: Arc<Mutex<Option<Failure>>>,
+    n_images: usize,
+}
+
+impl ArchiveWorker {
+    pub fn spawn(archive: SaveArchive) -> Self {
+        let (commands, rx) = mpsc::channel();
+        let failure = Arc::new(Mutex::new(None));
+        let n_images = archive.n_images();
+        let thread = thread::Builder::new()
+            .name("archive worker".into())
+            .spawn({
+                let failure = failure.clone();
+                move || run(archive, rx, failure)
+            })
+            .expect("Failed to spawn the archive worker");
+
+        Self {
+            commands,
+            thread: Some(thread),
+            failure,
+            n_images,
+        }
+    }
+
+    /// Queues writing the game data. If several writes are queued, only the latest one
+    /// is performed.
+    pub fn write_game_data(&mut self, data: Arc<GameData>) -> Result<(), EngineError> {
+        self.send(Command::WriteGameData(data))
+    }
+
+    /// Queues appending an image and returns its id
+    pub fn append_image(&mut self, image_bytes: Vec<u8>) -> Result<usize, EngineError> {
+        self.send(Command::AppendImage(image_bytes))?;
+        self.n_images += 1;
+        Ok(self.n_images - 1)
+    }
+
+    /// See [`SaveArchive::append_transcript`]. Like images, it's written in the background.
+    pub fn append_transcript(
+        &mut self,
+        turn: usize,
+        entries: Vec<AuditEntry>,
+    ) -> Result<(), EngineError> {
+        self.send(Command::AppendTranscript(turn, entries))
+    }
+
+    pub fn read_transcript(&mut self, turn: usize) -> Result<Option<Vec<AuditEntry>>, EngineError> {
+        self.request(|reply| Command::ReadTranscript(turn, reply))
+    }
+
+    pub fn read_game_data(&mut self) -> Result<GameData, EngineError> {
+        self.request(Command::ReadGameData)
+    }
+
+    pub fn read_image(&mut self, id: usize) -> Result<Vec<u8>, EngineError> {
+        self.request(|reply| Command::ReadImage(id, reply))
+    }
+
+    /// Like `read_image`, but doesn't wait for the worker
+    pub fn read_image_later(
+        &mut self,
+        id: usize,
+    ) -> Result<impl Future<Output = Result<Vec<u8>, EngineError>> + Send + 'static, EngineError>
+    {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ReadImageLater(id, reply))?;
+        Ok(async move {
+            rx.await
+                .map_err(|_| EngineError::Other(eyre!("The archive worker stopped")))?
+        })
+    }
+
+    /// Like `read_image_later`, but reads the stored thumbnail of the image, or the image
+    /// itself if it has none. Either way the bytes need to be downscaled to the wanted size.
+    pub fn read_thumbnail_later(
+        &mut self,
+        id: usize,
+    ) -> Result<impl Future<Output = Result<Vec<u8>, EngineError>> + Send + 'static, EngineError>
+    {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::ReadThumbnailLater(id, reply))?;
+        Ok(async move {
+            rx.await
+                .map_err(|_| EngineError::Other(eyre!("The archive worker stopped")))?
+        })
+    }
+
+    pub fn clip_after_turn(&mut self, turn: usize) -> Result<(), EngineError> {
+        self.n_images = self.request(|reply| Command::ClipAfterTurn(turn, reply))?;
+        Ok(())
+    }
+
+    /// See [`SaveArchive::fork_after`]
+    pub fn fork_after(&mut self, turn: usize, name: String) -> Result<(), EngineError> {
+        self.request(|reply| Command::ForkAfter(turn, name, reply))
+    }
+
+    /// See [`SaveArchive::switch_branch`]
+    pub fn switch_branch(&mut self, index: usize) -> Result<(), EngineError> {
+        self.request(|reply| Command::SwitchBranch(index, reply))
+    }
+
+    /// Copies the archive to `path`, after all queued writes are done
+    pub fn write_to(&mut self, path: PathBuf) -> Result<(), EngineError> {
+        self.request(|reply| Command::WriteTo(path, reply))
+    }
+
+    /// See [`SaveArchive::save_as`]. All later writes go to the copy.
+    pub fn save_as(&mut self, path: PathBuf) -> Result<(), EngineError> {
+        self.request(|reply| Command::SaveAs(path, reply))
+    }
+
+    /// See [`SaveArchive::fork_clipped`]
+    pub fn fork_clipped(&mut self, path: PathBuf, turn: usize) -> Result<(), EngineError> {
+        self.request(|reply| Command::ForkClipped(path, turn, reply))
+    }
+
+    /// Writes all images to the directory `dir`, after all queued writes are done.
+    /// See [`export::export_images`].
+    pub fn export_images(&mut self, dir: PathBuf) -> Result<usize, EngineError> {
+        self.request(|reply| Command::ExportImages(dir, reply))
+    }
+
+    /// Writes the story to `path`, after all queued writes are done.
+    /// See [`export::export_story`].
+    pub fn export_story(
+        &mut self,
+        path: PathBuf,
+        format: StoryFormat,
+        with_images: bool,
+    ) -> Result<usize, EngineError> {
+        self.request(|reply| Command::ExportStory(path, format, with_images, reply))
+    }
+
+    /// See [`SaveArchive::compact`]. The ids of the images change, so the game data must be
+    /// read again afterwards.
+    pub fn compact(&mut self) -> Result<Compaction, EngineError> {
+        let compaction = self.request(Command::Compact)?;
+        self.n_images -= compaction.removed_images;
+        Ok(compaction)
+    }
+
+    /// See [`SaveArchive::set_backups`]
+    pub fn set_backups(&mut self, settings: BackupSettings) -> Result<(), EngineError> {
+        self.send(Command::SetBackups(settings))
+    }
+
+    fn send(&mut self, command: Command) -> Result<(), EngineError> {
+        self.take_failure()?;
+        self.commands
+            .send(command)
+            .map_err(|_| EngineError::Other(eyre!("The archive worker stopped")))
+    }
+
+    fn request<T>(&mut self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, EngineError> {
+        let (reply, rx) = mpsc::channel();
+        self.send(command(reply))?;
+        let res = rx
+            .recv()
+            .map_err(|_| EngineError::Other(eyre!("The archive worker stopped")))?;
+        // a write that was queued before this request might have failed
+        self.take_failure()?;
+        res
+    }
+
+    fn take_failure(&mut self) -> Result<(), EngineError> {
+        match self.failure.lock().unwrap().take() {
+            Some(failure) => {
+                self.n_images = failure.n_images;
+                Err(failure.error)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ArchiveWorker {
+    /// Waits until all queued writes are done
+    fn drop(&mut self) {
+        let (disconnected, _) = mpsc::channel();
+        drop(mem::replace(&mut self.commands, disconnected));
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            error!("The archive worker panicked");
+        }
+    }
+}
+
+fn run(mut archive: SaveArchive, rx: Receiver<Command>, failure: Arc<Mutex<Option<Failure>>>) {
+    let mut next = rx.recv().ok();
+    while let Some(command) = next.take() {
+        match command {
+            Command::WriteGameData(mut data) => {
+                // skip writes that are outdated by the time they would be performed
+                loop {
+                    match rx.try_recv() {
+                        Ok(Command::WriteGameData(newer)) => data = newer,
+                        Ok(other) => {
+                            next = Some(other);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                let res = archive.write_game_data(&data);
+                report(&failure, &archive, res);
+            }
+            Command::AppendImage(bytes) => {
+                let res = archive.append_image(&bytes).map(|_| ());
+                report(&failure, &archive, res);
+            }
+            Command::AppendTranscript(turn, entries) => {
+                let res = archive.append_transcript(turn, &entries);
+                report(&failure, &archive, res);
+            }
+            Command::ReadTranscript(turn, reply) => _ = reply.send(archive.read_transcript(turn)),
+            Command::ReadGameData(reply) => _ = reply.send(archive.read_game_data()),
+            Command::ReadImage(id, reply) => _ = reply.send(archive.read_image(id)),
+            Command::ReadImageLater(id, reply) => _ = reply.send(archive.read_image(id)),
+            Command::ReadThumbnailLater(id, reply) => {
+                let res = archive
+                    .read_thumbnail(id)
+                    .transpose()
+                    .unwrap_or_else(|| archive.read_image(id));
+                _ = reply.send(res);
+            }
+            Command::ClipAfterTurn(turn, reply) => {
+                let res = archive.clip_after_turn(turn).map(|_| archive.n_images());
+                _ = reply.send(res);
+            }
+            Command::ForkAfter(turn, name, reply) => {
+                _ = reply.send(archive.fork_after(turn, &name));
+            }
+            Command::SwitchBranch(index, reply) => _ = reply.send(archive.switch_branch(index)),
+            Command::WriteTo(path, reply) => _ = reply.send(archive.write_to(&path)),
+            Command::SaveAs(path, reply) => _ = reply.send(archive.save_as(&path)),
+            Command::ForkClipped(path, turn, reply) => {
+                _ = reply.send(archive.fork_clipped(&path, turn));
+            }
+            Command::ExportImages(dir, reply) => {
+                let res = export::export_images(&mut archive, &dir).map_err(EngineError::from);
+                _ = reply.send(res);
+            }
+            Command::ExportStory(path, format, with_images, reply) => {
+                let res = export::export_story(&mut archive, &path, format, with_images)
+                    .map_err(EngineError::from);
+                _ = reply.send(res);
+            }
+            Command::Compact(reply) => _ = reply.send(archive.compact()),
+            Command::SetBackups(settings) => archive.set_backups(settings),
+        }
+        if next.is_none() {
+            next = rx.recv().ok();
+        }
+    }
+}
+
+/// Keeps the first error until it's reported, together with the current number of images
+fn report(failure: &Mutex<Option<Failure>>, archive: &SaveArchive, res: Result<(), EngineError>) {
+    let mut failure = failure.lock().unwrap();
+    match (res, failure.as_mut()) {
+        (Err(error), None) => {
+            error!("Writing to the save archive failed: {error}");
+            *failure = Some(Failure {
+                error,
+                n_images: archive.n_images(),
+            });
+        }
+        (Err(error), Some(first)) => {
+            error!("Writing to the save archive failed: {error}");
+            first.n_images = archive.n_images();
+        }
+        (Ok(()), Some(first)) => first.n_images = archive.n_images(),
+        (Ok(()), None) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::save_archive::tests::make_sample_game_data;
+
+    #[tokio::test]
+    async fn writes_are_visible_to_later_reads() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let mut worker = ArchiveWorker::spawn(SaveArchive::create(tmpfile.path())?);
+
+        let first = worker.append_image(vec![1, 2, 3])?;
+        let second = worker.append_image(vec![4, 5])?;
+        assert_eq!((first, second), (0, 1));
+        for n_turns in 1..=3 {
+            worker.write_game_data(Arc::new(make_sample_game_data(n_turns)))?;
+        }
+
+        assert_eq!(worker.read_image(second)?, vec![4, 5]);
+        let later = worker.read_image_later(first)?;
+        assert_eq!(later.await?, vec![1, 2, 3]);
+        assert_eq!(worker.read_game_data()?.turn_data.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn queued_writes_are_done_on_drop() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        {
+            let mut worker = ArchiveWorker::spawn(SaveArchive::create(tmpfile.path())?);
+            worker.append_image(vec![7; 1024])?;
+            worker.write_game_data(Arc::new(make_sample_game_data(2)))?;
+        }
+
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 2);
+        assert_eq!(archive.read_image(0)?, vec![7; 1024]);
+        Ok(())
+    }
+
+    #[test]
+    fn clipping_resets_image_ids() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let mut worker = ArchiveWorker::spawn(SaveArchive::create(tmpfile.path())?);
+        for i in 0..3 {
+            worker.append_image(vec![i])?;
+        }
+        worker.write_game_data(Arc::new(make_sample_game_data(3)))?;
+
+        worker.clip_after_turn(0)?;
+        assert_eq!(worker.append_image(vec![9])?, 1);
+        assert_eq!(worker.read_image(1)?, vec![9]);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_images_dont_take_an_id() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        {
+            let mut archive = SaveArchive::create(tmpfile.path())?;
+            archive.append_image(&[1])?;
+        }
+        let mut worker = ArchiveWorker::spawn(SaveArchive::open_read_only(tmpfile.path(), None)?);
+
+        assert_eq!(worker.append_image(vec![2])?, 1);
+        assert!(worker.read_image(0).is_err());
+        assert_eq!(worker.n_images, 1);
+        Ok(())
+    }
+}
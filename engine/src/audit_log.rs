@@ -0,0 +1,358 @@
+//! A log of every request that was sent to an LLM, and what it answered.
+//!
+//! When a turn parses badly, or costs much more than expected, the game data doesn't show
+//! why: it only contains the parsed output. The log keeps the requests and responses as
+//! they were, in a JSON lines file next to the save archive. The entries of each turn are
+//! also stored in the archive as its transcript, see [`AuditLog::take_pending`]. Requests
+//! that don't belong to a turn, like recaps or images that are made again later, are only
+//! logged, see [`AuditLog::wrap_outside_turn`].
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_stream::stream;
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+use crate::{
+    LLMBox,
+    error::EngineError,
+    llm::{
+        Content, LLM, LLMStream, ModelProvider, Request, ResponseFragment, RetryPolicy, Timeouts,
+        ToolCall,
+    },
+};
+
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+    /// the entries since the last [`AuditLog::take_pending`]
+    pending: Mutex<Vec<AuditEntry>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestPurpose {
+    Turn,
+    Summary,
+    /// asks the model to fix the format of a turn it wrote
+    Repair,
+    /// asks the model for a tamer image description, after the image was moderated
+    ImageRewrite,
+    /// asks the model for a title and a recap of the story
+    Recap,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// when the request was sent, in milliseconds since the unix epoch
+    pub sent_at_ms: u64,
+    /// until the response was complete, or failed
+    pub duration_ms: u64,
+    pub purpose: RequestPurpose,
+    pub request: Request,
+    /// the text received so far, if the request failed
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// what the model reasoned before it answered
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub thinking: String,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn sent_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.sent_at_ms)
+    }
+
+    /// The request and the response as a markdown document, for reading
+    pub fn to_markdown(&self) -> String {
+        let mut md = format!(
+            "# {} {:?} request, {} ms, {} input / {} output tokens\n",
+            humantime::format_rfc3339_seconds(self.sent_at()),
+            self.purpose,
+            self.duration_ms,
+            self.input_tokens,
+            self.output_tokens,
+        );
+        if let Some(system) = &self.request.system {
+            md += &format!("## System Message\n{system}\n");
+        }
+        for m in &self.request.messages {
+            let content = match &m.content {
+                Content::Text(text) => text.clone(),
+                Content::Blocks(blocks) => serde_json::to_string_pretty(blocks).unwrap_or_default(),
+            };
+            md += &format!("## {:?}\n{content}\n", m.role);
+        }
+        md += &format!("## Response\n{}\n", self.response);
+        for call in &self.tool_calls {
+            md += &format!("## Tool Call {}\n{}\n", call.name, call.input);
+        }
+        if let Some(err) = &self.error {
+            md += &format!("## Error\n{err}\n");
+        }
+        md
+    }
+}
+
+impl AuditLog {
+    /// The log that belongs to the save archive at `save_path`
+    pub fn path_for(save_path: &Path) -> PathBuf {
+        let mut name = save_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".audit.jsonl");
+        save_path.with_file_name(name)
+    }
+
+    /// Opens the log for appending, and creates it if it doesn't exist
+    pub fn open(path: &Path) -> Result<Self, EngineError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            pending: Mutex::default(),
+        })
+    }
+
+    /// Logs the entry, and keeps it for the transcript of the turn that is generated
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), EngineError> {
+        self.pending.lock().unwrap().push(entry.clone());
+        self.append_outside_turn(entry)
+    }
+
+    /// Logs the entry without keeping it for a transcript
+    pub fn append_outside_turn(&self, entry: &AuditEntry) -> Result<(), EngineError> {
+        let mut line = serde_json::to_vec(entry).map_err(|e| EngineError::Other(e.into()))?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+
+    /// The entries of turn requests that were appended since the last call, so they can be
+    /// stored with the turn they belong to. When a turn is abandoned, they are taken and
+    /// dropped, so they don't end up with the next one.
+    pub fn take_pending(&self) -> Vec<AuditEntry> {
+        std::mem::take(&mut self.pending.lock().unwrap())
+    }
+
+    pub fn read(path: &Path) -> Result<Vec<AuditEntry>, EngineError> {
+        BufReader::new(File::open(path)?)
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+            .map(|line| serde_json::from_str(&line?).map_err(|e| EngineError::Other(e.into())))
+            .collect()
+    }
+
+    /// Returns an LLM that behaves like `llm`, and logs all its requests, as part of the turn
+    /// that is generated
+    pub fn wrap(self: &Arc<Self>, llm: LLMBox, purpose: RequestPurpose) -> LLMBox {
+        Box::new(Audited {
+            inner: llm,
+            log: self.clone(),
+            purpose,
+            in_turn: true,
+        })
+    }
+
+    /// Like [`AuditLog::wrap`], for requests that don't belong to the turn that is generated
+    pub fn wrap_outside_turn(self: &Arc<Self>, llm: LLMBox, purpose: RequestPurpose) -> LLMBox {
+        Box::new(Audited {
+            inner: llm,
+            log: self.clone(),
+            purpose,
+            in_turn: false,
+        })
+    }
+}
+
+struct Audited {
+    inner: LLMBox,
+    log: Arc<AuditLog>,
+    purpose: RequestPurpose,
+    /// whether the entries go into the transcript of the turn that is generated
+    in_turn: bool,
+}
+
+impl LLM for Audited {
+    fn send_request_stream(&mut self, req: Request) -> LLMStream<'_> {
+        let mut entry = AuditEntry {
+            sent_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            duration_ms: 0,
+            purpose: self.purpose,
+            request: req.clone(),
+            response: String::new(),
+            tool_calls: vec![],
+            thinking: String::new(),
+            input_tokens: 0,
+            output_tokens: 0,
+            error: None,
+        };
+        let started = Instant::now();
+        let log = self.log.clone();
+        let in_turn = self.in_turn;
+        let mut inner = self.inner.send_request_stream(req);
+
+        Box::pin(stream! {
+            let mut logged = false;
+            while let Some(item) = inner.next().await {
+                match &item {
+                    Ok(ResponseFragment::TextDelta(text)) => entry.response.push_str(text),
+                    Ok(ResponseFragment::ThinkingDelta(text)) => entry.thinking.push_str(text),
+                    Ok(ResponseFragment::MessageComplete(msg)) => {
+                        entry.response = msg.text.clone();
+                        entry.tool_calls = msg.tool_calls.clone();
+                        entry.input_tokens = msg.input_tokens;
+                        entry.output_tokens = msg.output_tokens;
+                    }
+                    Err(e) => entry.error = Some(format!("{e:#}")),
+                }
+                let is_delta = matches!(
+                    item,
+                    Ok(ResponseFragment::TextDelta(_) | ResponseFragment::ThinkingDelta(_))
+                );
+                if !logged && !is_delta {
+                    entry.duration_ms = started.elapsed().as_millis() as u64;
+                    append(&log, &entry, in_turn);
+                    logged = true;
+                }
+                yield item;
+            }
+            if !logged {
+                entry.duration_ms = started.elapsed().as_millis() as u64;
+                entry.error = Some("The response ended before it was complete".into());
+                append(&log, &entry, in_turn);
+            }
+        })
+    }
+
+    fn clone(&self) -> LLMBox {
+        Box::new(Audited {
+            inner: self.inner.clone(),
+            log: self.log.clone(),
+            purpose: self.purpose,
+            in_turn: self.in_turn,
+        })
+    }
+
+    fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.inner.set_retry_policy(policy);
+    }
+
+    fn provider(&self) -> ModelProvider {
+        self.inner.provider()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.inner.supports_tools()
+    }
+
+    fn set_thinking_budget(&mut self, budget: Option<u32>) {
+        self.inner.set_thinking_budget(budget);
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.inner.set_timeouts(timeouts);
+    }
+
+    fn set_batched(&mut self, batched: bool) {
+        self.inner.set_batched(batched);
+    }
+}
+
+/// A failing log mustn't end the turn
+fn append(log: &AuditLog, entry: &AuditEntry, in_turn: bool) {
+    let res = if in_turn {
+        log.append(entry)
+    } else {
+        log.append_outside_turn(entry)
+    };
+    if let Err(e) = res {
+        error!("Failed to write the audit log: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::llm::{
+        InputMessage,
+        tests::{ScriptedLLM, message},
+    };
+
+    fn hello() -> LLMBox {
+        Box::new(ScriptedLLM::new([message("Hello", 12, 3)]))
+    }
+
+    #[tokio::test]
+    async fn requests_and_responses_are_logged() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let path = AuditLog::path_for(&dir.path().join("game.wwsave"));
+        assert!(path.ends_with("game.wwsave.audit.jsonl"));
+
+        let log = Arc::new(AuditLog::open(&path)?);
+        let mut llm = log.wrap(
+            Box::new(ScriptedLLM::new(vec![message("Hello", 12, 3); 2])),
+            RequestPurpose::Summary,
+        );
+        for _ in 0..2 {
+            let stream = llm.send_request_stream(Request {
+                system: Some("Summarize".into()),
+                messages: vec![InputMessage::user("turns".into())],
+                max_tokens: 100,
+                sampling: Default::default(),
+                tools: vec![],
+            });
+            assert_eq!(stream.collect::<Vec<_>>().await.len(), 2);
+        }
+
+        let entries = AuditLog::read(&path)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].purpose, RequestPurpose::Summary);
+        assert_eq!(entries[0].request.system.as_deref(), Some("Summarize"));
+        assert_eq!(entries[0].response, "Hello");
+        assert_eq!((entries[0].input_tokens, entries[0].output_tokens), (12, 3));
+        assert!(entries[0].error.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_turn_requests_are_pending() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let path = AuditLog::path_for(&dir.path().join("game.wwsave"));
+        let log = Arc::new(AuditLog::open(&path)?);
+        let request = || Request {
+            system: None,
+            messages: vec![InputMessage::user("turns".into())],
+            max_tokens: 100,
+            sampling: Default::default(),
+            tools: vec![],
+        };
+        let turn = log.wrap(hello(), RequestPurpose::Turn);
+        let recap = log.wrap_outside_turn(hello(), RequestPurpose::Recap);
+        for mut llm in [turn, recap] {
+            let stream = llm.send_request_stream(request());
+            assert_eq!(stream.collect::<Vec<_>>().await.len(), 2);
+        }
+
+        let pending = log.take_pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].purpose, RequestPurpose::Turn);
+        assert!(log.take_pending().is_empty());
+        assert_eq!(AuditLog::read(&path)?.len(), 2);
+        Ok(())
+    }
+}
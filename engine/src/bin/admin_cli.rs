@@ -1,13 +1,22 @@
-use std::{fs, path::{Path, PathBuf}};
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
 
 use clap::{Parser, Subcommand};
 use color_eyre::{Result, eyre::eyre};
 use engine::{
-    game::{TurnInput, WorldDescription},
-    save_archive::SaveArchive,
+    audit_log::AuditLog,
+    export::{StoryFormat, export_images, export_story},
+    game::{
+        DEFAULT_HISTORY_TURNS, Game, StoredImageInfo, TurnInput, WorldDescription, migration,
+        summary,
+    },
+    image_model::{self, ModelStyle, StorageSettings},
+    llm,
+    replay::{Recorder, Replay},
+    save_archive::{SaveArchive, backup, doctor},
     world_markdown::{world_from_markdown, world_to_markdown},
 };
 use serde::Deserialize;
+use tokio_stream::StreamExt;
 
 #[derive(Debug, Parser)]
 struct Cli {
@@ -21,6 +30,106 @@ enum Command {
     ExportWorldsMarkdown {
         target_dir: PathBuf,
     },
+    /// Prints the LLM requests and responses that were logged for a save, by default the
+    /// active one
+    DumpAuditLog {
+        save_path: Option<PathBuf>,
+        /// print the entries as they are stored, one JSON object per line
+        #[arg(long)]
+        json: bool,
+    },
+    /// Writes the images of a save into a directory, with a manifest of their turns and
+    /// captions
+    ExportImages {
+        save_path: PathBuf,
+        target_dir: PathBuf,
+    },
+    /// Writes the story of a save to a single document, with the player's actions, the
+    /// narration and the images of its turns
+    Export {
+        save_path: PathBuf,
+        target_path: PathBuf,
+        /// markdown, html or epub. By default, it's taken from the extension of the target.
+        #[arg(long)]
+        format: Option<StoryFormat>,
+        /// leave out the images
+        #[arg(long)]
+        no_images: bool,
+    },
+    /// Makes a new image for every turn of a save, with the image model and the active style
+    /// of the GUI's config. They are added as variants of the turns.
+    Restyle {
+        save_path: PathBuf,
+    },
+    /// Removes the images from a save that no turn refers to anymore, e.g. the ones of
+    /// turns that were discarded by loading an earlier turn
+    Compact {
+        save_path: PathBuf,
+    },
+    /// Lists the backups of a save, the newest first
+    Backups {
+        save_path: PathBuf,
+    },
+    /// Replaces a save with one of its backups, as numbered by `backups`
+    RestoreBackup {
+        save_path: PathBuf,
+        number: usize,
+    },
+    /// Prints the LLM requests and responses of a turn, as they are stored in the save.
+    /// Turns are counted from 1, like in the game.
+    Transcript {
+        save_path: PathBuf,
+        turn: usize,
+    },
+    /// Writes a save to a zip file with its game data as JSON and its images
+    ExportBundle {
+        save_path: PathBuf,
+        bundle_path: PathBuf,
+    },
+    /// Creates a save from a zip file that `export-bundle` wrote
+    ImportBundle {
+        bundle_path: PathBuf,
+        save_path: PathBuf,
+    },
+    /// Checks a save for damage, and replaces a damaged one with what is intact of it. The
+    /// damaged save is kept next to it, as `<name>.damaged`.
+    Doctor {
+        save_path: PathBuf,
+        /// only print what is damaged, and what a repair would keep
+        #[arg(long)]
+        dry_run: bool,
+        /// needed for encrypted saves
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Prints the game data of a save as JSON, to be edited and written back with `patch`
+    Dump {
+        save_path: PathBuf,
+        /// needed for encrypted saves
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Replaces the game data of a save with the JSON in a file, e.g. one that `dump`
+    /// printed. It's only written if it's valid for the save.
+    Patch {
+        save_path: PathBuf,
+        json_path: PathBuf,
+        /// needed for encrypted saves
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Plays a save again from its first turn, with the actions of the save and the answers
+    /// that were recorded while it was played. Prints which turns end differently than in
+    /// the save.
+    Replay {
+        save_path: PathBuf,
+        /// by default the recording next to the save
+        #[arg(long)]
+        recording: Option<PathBuf>,
+        /// needed for encrypted saves
+        #[arg(long)]
+        password: Option<String>,
+    },
 }
 
 pub fn main() -> Result<()> {
@@ -29,24 +138,321 @@ pub fn main() -> Result<()> {
 
     match cli
         .command
-        .ok_or(eyre!("No command given. Try `print-active-game-request` or `export-worlds-markdown`"))?
+        .ok_or(eyre!("No command given. Try `print-active-game-request`, `export-worlds-markdown`, `dump-audit-log`, `export-images`, `export`, `restyle`, `compact`, `backups`, `restore-backup`, `transcript`, `export-bundle`, `import-bundle`, `doctor`, `dump`, `patch` or `replay`"))?
     {
         Command::PrintActiveGameRequest => print_active_game_request(),
         Command::ExportWorldsMarkdown { target_dir } => export_worlds_markdown(&target_dir),
+        Command::DumpAuditLog { save_path, json } => dump_audit_log(save_path, json),
+        Command::ExportImages { save_path, target_dir } => {
+            let n_images = export_images(&mut SaveArchive::open_read_only(save_path, None)?, &target_dir)?;
+            println!("Exported {n_images} images to {target_dir:?}");
+            Ok(())
+        }
+        Command::Export {
+            save_path,
+            target_path,
+            format,
+            no_images,
+        } => {
+            let format = format
+                .or_else(|| StoryFormat::from_path(&target_path))
+                .ok_or(eyre!("Can't tell the format from {target_path:?}, pass --format"))?;
+            let n_turns = export_story(
+                &mut SaveArchive::open_read_only(save_path, None)?,
+                &target_path,
+                format,
+                !no_images,
+            )?;
+            println!("Exported {n_turns} turns to {target_path:?} as {format}");
+            Ok(())
+        }
+        Command::Restyle { save_path } => {
+            tokio::runtime::Runtime::new()?.block_on(restyle(&save_path))
+        }
+        Command::Compact { save_path } => {
+            let compaction = SaveArchive::open(&save_path)?.compact()?;
+            println!(
+                "Removed {} images, {} KiB",
+                compaction.removed_images,
+                compaction.freed_bytes / 1024
+            );
+            Ok(())
+        }
+        Command::Backups { save_path } => {
+            for (n, (path, made)) in backup::list(&save_path).into_iter().enumerate() {
+                let made = humantime::format_rfc3339_seconds(made);
+                println!("{}: {} ({made})", n + 1, path.display());
+            }
+            Ok(())
+        }
+        Command::RestoreBackup { save_path, number } => {
+            backup::restore(&save_path, number)?;
+            println!("Restored {:?}", backup::path_for(&save_path, number));
+            Ok(())
+        }
+        Command::Transcript { save_path, turn } => {
+            let mut archive = SaveArchive::open_read_only(&save_path, None)?;
+            let transcript = archive
+                .read_transcript(turn.checked_sub(1).ok_or(eyre!("Turns start at 1"))?)?
+                .ok_or(eyre!("There is no transcript of turn {turn}"))?;
+            for entry in transcript {
+                println!("{}", entry.to_markdown());
+            }
+            Ok(())
+        }
+        Command::ExportBundle { save_path, bundle_path } => {
+            SaveArchive::open_read_only(&save_path, None)?.export_bundle(&bundle_path)?;
+            println!("Exported {save_path:?} to {bundle_path:?}");
+            Ok(())
+        }
+        Command::ImportBundle { bundle_path, save_path } => {
+            let archive = SaveArchive::import_bundle(&bundle_path, &save_path)?;
+            println!("Created {save_path:?} with {} images", archive.n_images());
+            Ok(())
+        }
+        Command::Doctor {
+            save_path,
+            dry_run,
+            password,
+        } => run_doctor(&save_path, dry_run, password.as_deref()),
+        Command::Dump { save_path, password } => {
+            let data =
+                SaveArchive::open_read_only(&save_path, password.as_deref())?.read_game_data()?;
+            println!("{}", serde_json::to_string_pretty(&data)?);
+            Ok(())
+        }
+        Command::Patch {
+            save_path,
+            json_path,
+            password,
+        } => patch(&save_path, &json_path, password.as_deref()),
+        Command::Replay {
+            save_path,
+            recording,
+            password,
+        } => tokio::runtime::Runtime::new()?.block_on(replay(
+            &save_path,
+            recording,
+            password.as_deref(),
+        )),
     }
 }
 
+fn patch(save_path: &Path, json_path: &Path, password: Option<&str>) -> Result<()> {
+    let data = migration::game_data_from_json(&fs::read_to_string(json_path)?)?;
+    let mut archive = match password {
+        Some(password) => SaveArchive::open_with_password(save_path, password)?,
+        None => SaveArchive::open(save_path)?,
+    };
+    data.validate(archive.n_images())
+        .map_err(|e| eyre!("{json_path:?} doesn't fit the save:\n{e}"))?;
+    archive.write_game_data(&data)?;
+    println!("Wrote the game data of {json_path:?} to {save_path:?}");
+    Ok(())
+}
+
+fn run_doctor(save_path: &Path, dry_run: bool, password: Option<&str>) -> Result<()> {
+    let checkup = doctor::examine(save_path, password)?;
+    if checkup.is_intact() {
+        println!("{save_path:?} is intact");
+        return Ok(());
+    }
+    for problem in &checkup.problems {
+        println!("- {problem}");
+    }
+    if checkup.index_rebuilt {
+        println!("The index was rebuilt, {} images were found", checkup.n_images);
+    }
+    let Some(turns) = checkup.turns else {
+        return Err(eyre!(
+            "The game data is unreadable, the save can only be restored from a backup"
+        ));
+    };
+    println!(
+        "A repair keeps {} of {} images and {} of {turns} turns",
+        checkup.intact_images, checkup.n_images, checkup.kept_turns
+    );
+    if dry_run {
+        return Ok(());
+    }
+    doctor::repair(save_path, password)?;
+    println!(
+        "Repaired {save_path:?}, the damaged save is {:?}",
+        save_path.with_extension("damaged")
+    );
+    Ok(())
+}
+
+/// The images are made one after the other, and the save is written after each one, so
+/// nothing is lost when it's interrupted
+async fn restyle(save_path: &Path) -> Result<()> {
+    let config = load_gui_config()?;
+    let model = config.current_img_model;
+    if model == image_model::ProvidedModel::ManualImage {
+        return Err(eyre!("Image files can only be picked in the game"));
+    }
+    let key = config.img_model_tokens.get(&model.provider()).cloned();
+    if key.is_none() && model.provider().needs_api_key() {
+        return Err(eyre!("There is no API key for {model} in the config"));
+    }
+    let llm_key = config
+        .llm_tokens
+        .get(&config.current_llm.provider())
+        .ok_or(eyre!("There is no API key for {:?} in the config", config.current_llm))?;
+    // the LLM rewrites descriptions that the image model refuses
+    let llm = config
+        .current_llm
+        .make(llm_key.clone(), &config.openrouter_model_id);
+    let style = config
+        .active_model_style
+        .get(&model.model())
+        .and_then(|name| {
+            config.styles.get(&StyleKey {
+                model: model.model(),
+                name: name.clone(),
+            })
+        })
+        .cloned();
+
+    let mut archive = SaveArchive::open(save_path)?;
+    let data = archive.read_game_data()?;
+    let mut game = Game::load(llm, Some(model.make(key.unwrap_or_default())), data, style);
+    let jobs = game.restyle_images()?;
+    let n_jobs = jobs.len();
+    for (i, (turn, image)) in jobs.into_iter().enumerate() {
+        println!("Turn {} ({}/{n_jobs})", turn + 1, i + 1);
+        let image = match image.await {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("Failed to make the image of turn {}: {e:#}", turn + 1);
+                continue;
+            }
+        };
+        let original_id = if config.image_storage.store_originals {
+            Some(archive.append_image(&image.bytes)?)
+        } else {
+            None
+        };
+        let id = archive.append_image(&config.image_storage.prepare(&image.bytes)?.bytes)?;
+        game.record_image(turn, &image);
+        game.data_mut().turn_data[turn].add_image_variant(StoredImageInfo {
+            id,
+            caption: image.caption,
+            original_id,
+            seed: image.seed,
+        });
+        archive.write_game_data(&game.data)?;
+    }
+    Ok(())
+}
+
+/// The turns are played one after the other, like the game does, with the settings of the
+/// GUI's config. The images and summaries are asked for too, so the recording is used up in
+/// the order it was made.
+async fn replay(
+    save_path: &Path,
+    recording: Option<PathBuf>,
+    password: Option<&str>,
+) -> Result<()> {
+    let recording = recording.unwrap_or_else(|| Recorder::path_for(save_path));
+    if !recording.exists() {
+        return Err(eyre!("There is no recording at {recording:?}"));
+    }
+    let replay = Replay::load(&recording)?;
+    let saved = SaveArchive::open_read_only(save_path, password)?.read_game_data()?;
+    let config = load_gui_config()?;
+    let world = &saved.world_description;
+    let llm = replay.llm(world.preferred_llm.unwrap_or(config.current_llm).provider());
+    let imgmod = (!saved.text_only).then(|| {
+        replay.image_model(
+            world
+                .preferred_image_model
+                .unwrap_or(config.current_img_model),
+        )
+    });
+    let mut game = Game::try_new(
+        llm,
+        imgmod,
+        world.clone(),
+        saved.pc.clone(),
+        None,
+        saved.difficulty,
+    )?;
+    game.context_budget = config.context_budget;
+    game.history_turns = config.history_turns.unwrap_or(DEFAULT_HISTORY_TURNS);
+    game.summary_interval = config.summary_interval.unwrap_or(summary::DEFAULT_INTERVAL);
+
+    let mut n_different = 0;
+    for (turn, saved_turn) in saved.turn_data.iter().enumerate() {
+        let summary = game.mk_summary_if_neccessary();
+        let res = game.send_to_llm(saved_turn.input.clone());
+        res.text_stream.collect::<Result<Vec<_>, _>>().await?;
+        for image in res.image.into_iter().chain(res.image_variants) {
+            if let Err(e) = image.await {
+                eprintln!("The image of turn {} failed: {e:#}", turn + 1);
+            }
+        }
+        let output = res.round_output.await?;
+        let summary = summary.await?;
+        if output.text == saved_turn.output.text {
+            println!("Turn {}: as in the save", turn + 1);
+        } else {
+            n_different += 1;
+            println!("Turn {}: differs from the save", turn + 1);
+        }
+        game.update(
+            saved_turn.input.clone(),
+            output,
+            saved_turn.images.clone(),
+            summary,
+        )?;
+    }
+    println!(
+        "{n_different} of {} turns differ, {} recorded answers weren't used",
+        saved.turn_data.len(),
+        replay.remaining()
+    );
+    Ok(())
+}
+
 fn print_active_game_request() -> Result<()> {
     let save_path = load_active_game_save_path()?
         .ok_or(eyre!("No active save path stored in the state dir"))?;
-    let mut archive = SaveArchive::open(save_path)?;
+    let mut archive = SaveArchive::open_read_only(save_path, None)?;
     let data = archive.read_game_data()?;
-    let request = data.construct_request(&TurnInput::default(), "");
+    let history_turns = data
+        .world_description
+        .history_turns
+        .unwrap_or(DEFAULT_HISTORY_TURNS);
+    let request = data.construct_request(&TurnInput::default(), "", history_turns);
 
     println!("# System Message\n{}", request.system.unwrap());
     println!("# Messages");
     for m in request.messages {
-        println!("{}", m.content);
+        println!("{}", m.content.text());
+    }
+
+    Ok(())
+}
+
+fn dump_audit_log(save_path: Option<PathBuf>, json: bool) -> Result<()> {
+    let save_path = match save_path {
+        Some(path) => path,
+        None => load_active_game_save_path()?
+            .ok_or(eyre!("No save given, and no active save path stored in the state dir"))?,
+    };
+    let log_path = AuditLog::path_for(&save_path);
+    if !log_path.exists() {
+        return Err(eyre!("There is no audit log for {save_path:?}"));
+    }
+
+    for entry in AuditLog::read(&log_path)? {
+        if json {
+            println!("{}", serde_json::to_string(&entry)?);
+            continue;
+        }
+
+        println!("{}", entry.to_markdown());
     }
 
     Ok(())
@@ -79,6 +485,12 @@ pub fn remembered_worlds_path() -> Result<PathBuf> {
     Ok(data_dir()?.join("remembered_worlds.ron"))
 }
 
+pub fn config_path() -> Result<PathBuf> {
+    Ok(dirs::config_local_dir()
+        .ok_or(eyre!("Couldn't get config dir"))?
+        .join("world_weaver.ron"))
+}
+
 pub fn active_game_save_path_ref_path() -> Result<PathBuf> {
     Ok(data_dir()?.join("active_game_save_path.ron"))
 }
@@ -92,6 +504,15 @@ pub fn load_active_game_save_path() -> Result<Option<PathBuf>> {
     Ok(Some(ron::from_str(&src)?))
 }
 
+fn load_gui_config() -> Result<GuiConfig> {
+    let path = config_path()?;
+    if !path.exists() {
+        return Err(eyre!("There is no config at {path:?}, start World Weaver once"));
+    }
+    let src = fs::read_to_string(path)?;
+    Ok(ron::from_str(&src)?)
+}
+
 fn load_remembered_worlds() -> Result<Vec<RememberedWorld>> {
     let path = remembered_worlds_path()?;
     if !path.exists() {
@@ -105,3 +526,31 @@ fn load_remembered_worlds() -> Result<Vec<RememberedWorld>> {
 struct RememberedWorld {
     path: PathBuf,
 }
+
+/// The parts of the GUI's config that are needed to make images and requests like the GUI
+/// does
+#[derive(Debug, Deserialize)]
+struct GuiConfig {
+    current_img_model: image_model::ProvidedModel,
+    current_llm: llm::ProvidedModel,
+    img_model_tokens: BTreeMap<image_model::ModelProvider, String>,
+    llm_tokens: BTreeMap<llm::ModelProvider, String>,
+    active_model_style: BTreeMap<image_model::Model, String>,
+    styles: BTreeMap<StyleKey, ModelStyle>,
+    #[serde(default)]
+    image_storage: StorageSettings,
+    #[serde(default)]
+    openrouter_model_id: String,
+    #[serde(default)]
+    context_budget: Option<usize>,
+    #[serde(default)]
+    history_turns: Option<usize>,
+    #[serde(default)]
+    summary_interval: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct StyleKey {
+    model: image_model::Model,
+    name: String,
+}
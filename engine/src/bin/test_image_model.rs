@@ -19,7 +19,9 @@ async fn main() -> Result<()> {
     } = Arg::parse();
     let llm = model.make(key);
 
-    let image = llm.get_image(&description).await?;
+    let image = llm
+        .get_image(&description, None, Default::default())
+        .await?;
     std::fs::write("output.jpeg", &image.data)?;
     println!("Saved image, {} bytes", image.data.len());
 
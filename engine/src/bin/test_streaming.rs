@@ -15,6 +15,9 @@ pub struct Cli {
     api_key: String,
     model: ProvidedModel,
     max_tokens: usize,
+    /// the model id, if the model is `openrouter-custom`
+    #[arg(long, default_value = "")]
+    model_id: String,
 }
 
 #[tokio::main]
@@ -29,7 +32,7 @@ async fn main() -> Result<()> {
         .init();
     color_eyre::install().unwrap();
 
-    let mut model = args.model.make(args.api_key);
+    let mut model = args.model.make(args.api_key, &args.model_id);
     let stream = model.send_request_stream(Request {
         messages: vec![InputMessage {
             role: Role::User,
@@ -37,6 +40,8 @@ async fn main() -> Result<()> {
         }],
         max_tokens: args.max_tokens,
         system: None,
+        sampling: Default::default(),
+        tools: vec![],
     });
 
     pin!(stream);
@@ -46,6 +51,9 @@ async fn main() -> Result<()> {
                 print!("{t}");
                 stdout().flush()?;
             }
+            ResponseFragment::ThinkingDelta(t) => {
+                eprint!("{t}");
+            }
             ResponseFragment::MessageComplete(output_message) => {
                 println!(
                     "Cost: input: {}, output: {}",
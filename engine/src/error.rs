@@ -0,0 +1,152 @@
+//! The error type of the engine's public API.
+//!
+//! Internally the engine uses `color_eyre`, and errors that callers might want to react to are
+//! created as `EngineError`s and wrapped into a `Report`. At the API boundary the report is
+//! converted back via `From<Report>`, which recovers the classified error if there is one.
+//! Everything else ends up in `EngineError::Other`.
+
+use std::io;
+
+use color_eyre::Report;
+use reqwest::StatusCode;
+use thiserror::Error;
+
+use crate::image_model::ImageDownloadError;
+
+#[derive(Debug, Error)]
+pub enum EngineError {
+    /// The API key is missing, invalid, or lacks permissions
+    #[error("{provider} rejected the API key: {message}")]
+    ApiAuth { provider: String, message: String },
+
+    #[error("{provider} rate limit exceeded: {message}")]
+    RateLimited { provider: String, message: String },
+
+    /// The provider is temporarily unavailable, retrying later usually works
+    #[error("{provider} is overloaded: {message}")]
+    Overloaded { provider: String, message: String },
+
+    /// The provider didn't answer in time, or stopped sending in the middle of the response
+    #[error("{provider} timed out: {message}")]
+    Timeout { provider: String, message: String },
+
+    /// A content filter rejected the request or its result
+    #[error("The request was moderated: {message}")]
+    Moderated { message: String },
+
+    /// The LLM response didn't follow the expected format
+    #[error("Failed to parse the LLM response: {message}")]
+    ParseFailure { message: String },
+
+    #[error("The save archive is corrupt: {message}")]
+    ArchiveCorrupt { message: String },
+
+    /// A checksum doesn't match, the bytes of the section were changed since they were written
+    #[error("The save archive is corrupted at {section}, starting at byte {offset}")]
+    ChecksumMismatch { section: String, offset: u64 },
+
+    /// The save was written by a newer version of World Weaver, whose format isn't known
+    #[error("The save archive has the unknown format version {version}")]
+    ArchiveTooNew { version: u64 },
+
+    /// The save is encrypted, see `SaveArchive::open_with_password`
+    #[error("The save is encrypted, its password is needed to open it")]
+    PasswordRequired,
+
+    #[error("The password of the save is wrong")]
+    WrongPassword,
+
+    /// Another instance has the save open for writing, see `SaveArchive::open_read_only`
+    #[error("The save is in use by another instance of World Weaver")]
+    ArchiveLocked,
+
+    /// The turn was cancelled via `AdvanceResult::cancel`
+    #[error("The turn was cancelled")]
+    Cancelled,
+
+    #[error(transparent)]
+    ImageDownload(#[from] ImageDownloadError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("{0:#}")]
+    Other(Report),
+}
+
+impl EngineError {
+    /// Classifies a non-success HTTP response of a provider
+    pub fn from_status(provider: &str, status: StatusCode, body: &str) -> Self {
+        let provider = provider.to_string();
+        let message = body.to_string();
+        match status.as_u16() {
+            401 | 403 => Self::ApiAuth { provider, message },
+            429 => Self::RateLimited { provider, message },
+            503 | 529 => Self::Overloaded { provider, message },
+            _ => Self::Other(color_eyre::eyre::eyre!("{provider} error {status}: {body}")),
+        }
+    }
+
+    pub fn archive_corrupt(message: impl std::fmt::Display) -> Self {
+        Self::ArchiveCorrupt {
+            message: message.to_string(),
+        }
+    }
+
+    /// Whether sending the same request again later is likely to succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. }
+                | Self::Overloaded { .. }
+                | Self::Timeout { .. }
+                | Self::ImageDownload(_)
+        )
+    }
+}
+
+impl From<Report> for EngineError {
+    fn from(report: Report) -> Self {
+        let report = match report.downcast::<EngineError>() {
+            Ok(e) => return e,
+            Err(report) => report,
+        };
+        let report = match report.downcast::<ImageDownloadError>() {
+            Ok(e) => return e.into(),
+            Err(report) => report,
+        };
+        match report.downcast::<io::Error>() {
+            Ok(e) => e.into(),
+            Err(report) => Self::Other(report),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::WrapErr;
+
+    use super::*;
+
+    #[test]
+    fn classification_survives_context() {
+        let report: Report =
+            EngineError::from_status("Anthropic", StatusCode::UNAUTHORIZED, "").into();
+        let report = Err::<(), _>(report).context("outer").unwrap_err();
+        assert!(matches!(
+            EngineError::from(report),
+            EngineError::ApiAuth { .. }
+        ));
+    }
+
+    #[test]
+    fn unclassified_errors_become_other() {
+        let err = EngineError::from(color_eyre::eyre::eyre!("boom"));
+        assert!(matches!(err, EngineError::Other(_)));
+        assert!(!err.is_transient());
+        assert!(
+            EngineError::from_status("BFL", StatusCode::TOO_MANY_REQUESTS, "slow down")
+                .is_transient()
+        );
+    }
+}
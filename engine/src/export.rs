@@ -0,0 +1,113 @@
+//! Writes the contents of a save archive to plain files, so they can be used outside of
+//! World Weaver.
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::{image_model::ImageFormat, save_archive::SaveArchive};
+
+pub mod story;
+pub use story::{StoryFormat, export_story};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// An entry of the manifest that is written with the exported images
+#[derive(Debug, Serialize)]
+pub struct ExportedImage {
+    pub file: String,
+    /// the turn as it's displayed, starting at 1. `None` if no turn refers to the image,
+    /// e.g. because its turn was never completed.
+    pub turn: Option<usize>,
+    pub caption: String,
+    /// the image as it was received, before it was downscaled
+    pub original: bool,
+}
+
+/// Writes every image of the archive into `dir`, numbered by their ids, and a
+/// [`MANIFEST_NAME`] with their turns and captions. Returns the number of images.
+pub fn export_images(archive: &mut SaveArchive, dir: &Path) -> Result<usize> {
+    fs::create_dir_all(dir)?;
+    write_images(archive, |file, bytes| Ok(fs::write(dir.join(file), bytes)?))
+}
+
+/// Like [`export_images`], but `write` receives each file name and its content
+pub(crate) fn write_images(
+    archive: &mut SaveArchive,
+    mut write: impl FnMut(&str, &[u8]) -> Result<()>,
+) -> Result<usize> {
+    let data = archive.read_game_data()?;
+    // (turn, caption, original) by image id
+    let mut turns = BTreeMap::new();
+    for (turn, turn_data) in data.turn_data.iter().enumerate() {
+        for info in &turn_data.images {
+            turns.insert(info.id, (turn, &info.caption, false));
+            if let Some(id) = info.original_id {
+                turns.insert(id, (turn, &info.caption, true));
+            }
+        }
+    }
+
+    let mut manifest = vec![];
+    for id in 0..archive.n_images() {
+        let bytes = archive.read_image(id)?;
+        let extension = archive
+            .image_format(id)
+            .or_else(|| ImageFormat::detect(&bytes))
+            .map_or("bin", |format| format.extension());
+        let file = format!("{:04}.{extension}", id + 1);
+        write(&file, &bytes)?;
+
+        let (turn, caption, original) = match turns.get(&id) {
+            Some((turn, caption, original)) => (Some(turn + 1), caption.to_string(), *original),
+            None => (None, String::new(), false),
+        };
+        manifest.push(ExportedImage {
+            file,
+            turn,
+            caption,
+            original,
+        });
+    }
+    write(
+        MANIFEST_NAME,
+        serde_json::to_string_pretty(&manifest)?.as_bytes(),
+    )?;
+    Ok(manifest.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::*;
+    use crate::save_archive::tests::make_sample_game_data;
+
+    #[test]
+    fn images_are_written_with_a_manifest() -> Result<()> {
+        let save = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create(save.path())?;
+        let mut png = vec![];
+        image::RgbImage::new(2, 2).write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+        let mut data = make_sample_game_data(1);
+        archive.append_image(&png)?;
+        archive.append_image(&[1, 2, 3])?;
+        data.turn_data[0].images[0].original_id = Some(1);
+        archive.write_game_data(&data)?;
+
+        let dir = TempDir::new()?;
+        assert_eq!(export_images(&mut archive, dir.path())?, 2);
+        assert_eq!(fs::read(dir.path().join("0001.png"))?, png);
+        assert_eq!(fs::read(dir.path().join("0002.bin"))?, vec![1, 2, 3]);
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.path().join(MANIFEST_NAME))?)?;
+        assert_eq!(manifest[0]["turn"], 1);
+        assert_eq!(manifest[0]["caption"], "caption 0");
+        assert_eq!(manifest[1]["original"], true);
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+//! The story of a save as a single document, to be read or shared like a book. It contains
+//! the player's action, the narration and, optionally, the shown image of every turn.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    time::SystemTime,
+};
+
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use color_eyre::Result;
+use log::warn;
+use pulldown_cmark::{Parser, html};
+use strum::{Display, EnumString};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{game::sanitize_markdown, image_model::ImageFormat, save_archive::SaveArchive};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum StoryFormat {
+    Markdown,
+    Html,
+    Epub,
+}
+
+impl StoryFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            StoryFormat::Markdown => "md",
+            StoryFormat::Html => "html",
+            StoryFormat::Epub => "epub",
+        }
+    }
+
+    /// The format that the extension of `path` stands for
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "md" | "markdown" => Some(StoryFormat::Markdown),
+            "html" | "htm" => Some(StoryFormat::Html),
+            "epub" => Some(StoryFormat::Epub),
+            _ => None,
+        }
+    }
+}
+
+/// One turn of the story
+struct Passage {
+    action: String,
+    /// markdown, as the LLM wrote it
+    text: String,
+    image: Option<StoryImage>,
+}
+
+struct StoryImage {
+    caption: String,
+    format: ImageFormat,
+    bytes: Vec<u8>,
+}
+
+const STYLE: &str = "body { max-width: 40em; margin: 0 auto; padding: 1em; \
+                     font-family: serif; line-height: 1.5; }
+.action { font-style: italic; color: #555; margin: 2em 0 1em; }
+figure { margin: 1em 0; text-align: center; }
+figure img { max-width: 100%; }
+figcaption { font-size: 0.9em; color: #555; }
+";
+
+/// Writes the story of the archive's game to `path`. Images are embedded, so the document
+/// is a single file. Returns the number of turns.
+pub fn export_story(
+    archive: &mut SaveArchive,
+    path: &Path,
+    format: StoryFormat,
+    with_images: bool,
+) -> Result<usize> {
+    let data = archive.read_game_data()?;
+    let mut passages = vec![];
+    for turn_data in &data.turn_data {
+        let image = match turn_data.images.last() {
+            Some(info) if with_images => {
+                let bytes = archive.read_image(info.id)?;
+                let format = archive
+                    .image_format(info.id)
+                    .or_else(|| ImageFormat::detect(&bytes));
+                if format.is_none() {
+                    warn!(
+                        "Leaving out image {} of the story, its format is unknown",
+                        info.id
+                    );
+                }
+                format.map(|format| StoryImage {
+                    caption: info.caption.clone(),
+                    format,
+                    bytes,
+                })
+            }
+            _ => None,
+        };
+        passages.push(Passage {
+            action: turn_data.input.player_action.trim().to_string(),
+            text: turn_data.output.text.clone(),
+            image,
+        });
+    }
+
+    let title = &data.world_description.name;
+    match format {
+        StoryFormat::Markdown => fs::write(path, to_markdown(title, &passages))?,
+        StoryFormat::Html => fs::write(path, to_html(title, &passages))?,
+        StoryFormat::Epub => write_epub(File::create(path)?, title, &passages)?,
+    }
+    Ok(passages.len())
+}
+
+fn to_markdown(title: &str, passages: &[Passage]) -> String {
+    let mut res = format!("# {title}\n");
+    for passage in passages {
+        if !passage.action.is_empty() {
+            res.push('\n');
+            for line in passage.action.lines() {
+                res.push_str(&format!("> {line}\n"));
+            }
+        }
+        res.push_str(&format!("\n{}\n", sanitize_markdown(passage.text.trim())));
+        if let Some(image) = &passage.image {
+            let alt = image.caption.replace(['[', ']'], "");
+            res.push_str(&format!("\n![{alt}]({})\n", data_uri(image)));
+        }
+    }
+    res
+}
+
+fn to_html(title: &str, passages: &[Passage]) -> String {
+    let title = escape(title);
+    let body = body_html(passages, |_, image| data_uri(image));
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\"/>\n<title>{title}</title>\n\
+         <style>\n{STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n{body}</body>\n</html>\n"
+    )
+}
+
+/// The passages as XHTML, which is valid HTML too. `image_src` gives the `src` of the image
+/// of the passage with the given index.
+fn body_html(passages: &[Passage], image_src: impl Fn(usize, &StoryImage) -> String) -> String {
+    let mut res = String::new();
+    for (i, passage) in passages.iter().enumerate() {
+        res.push_str("<section>\n");
+        if !passage.action.is_empty() {
+            let action = escape(&passage.action).replace('\n', "<br/>");
+            res.push_str(&format!("<p class=\"action\">{action}</p>\n"));
+        }
+        // sanitizing escapes `<`, so HTML in the narration is shown as text
+        let text = sanitize_markdown(passage.text.trim());
+        html::push_html(&mut res, Parser::new(&text));
+        if let Some(image) = &passage.image {
+            let caption = escape(&image.caption);
+            res.push_str(&format!(
+                "<figure>\n<img src=\"{}\" alt=\"{caption}\"/>\n\
+                 <figcaption>{caption}</figcaption>\n</figure>\n",
+                image_src(i, image)
+            ));
+        }
+        res.push_str("</section>\n");
+    }
+    res
+}
+
+fn write_epub(file: File, title: &str, passages: &[Passage]) -> Result<()> {
+    let mut zip = ZipWriter::new(file);
+    // readers expect the mimetype first and uncompressed
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default();
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(EPUB_CONTAINER.as_bytes())?;
+
+    let image_file =
+        |i: usize, image: &StoryImage| format!("images/{:04}.{}", i + 1, image.format.extension());
+    let title = escape(title);
+    let modified = humantime::format_rfc3339_seconds(SystemTime::now());
+    let mut manifest = String::new();
+    for (i, passage) in passages.iter().enumerate() {
+        let Some(image) = &passage.image else {
+            continue;
+        };
+        let file = image_file(i, image);
+        manifest.push_str(&format!(
+            "    <item id=\"image{i}\" href=\"{file}\" media-type=\"{}\"/>\n",
+            image.format.mime_type()
+        ));
+        zip.start_file(format!("OEBPS/{file}"), stored)?;
+        zip.write_all(&image.bytes)?;
+    }
+
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="id">urn:world-weaver:{modified}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">{modified}</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="story" href="story.xhtml" media-type="application/xhtml+xml"/>
+    <item id="style" href="style.css" media-type="text/css"/>
+{manifest}  </manifest>
+  <spine>
+    <itemref idref="story"/>
+  </spine>
+</package>
+"#
+        )
+        .as_bytes(),
+    )?;
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    zip.write_all(
+        xhtml_document(
+            &title,
+            &format!(
+                "<nav epub:type=\"toc\">\n<ol>\n<li><a href=\"story.xhtml\">{title}</a></li>\n\
+                 </ol>\n</nav>\n"
+            ),
+        )
+        .as_bytes(),
+    )?;
+    zip.start_file("OEBPS/story.xhtml", deflated)?;
+    let body = format!("<h1>{title}</h1>\n{}", body_html(passages, image_file));
+    zip.write_all(xhtml_document(&title, &body).as_bytes())?;
+    zip.start_file("OEBPS/style.css", deflated)?;
+    zip.write_all(STYLE.as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+const EPUB_CONTAINER: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+/// `title` must be escaped already
+fn xhtml_document(title: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n\
+         <head>\n<title>{title}</title>\n\
+         <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\"/>\n</head>\n\
+         <body>\n{body}</body>\n</html>\n"
+    )
+}
+
+fn data_uri(image: &StoryImage) -> String {
+    format!(
+        "data:{};base64,{}",
+        image.format.mime_type(),
+        BASE64_STANDARD.encode(&image.bytes)
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use tempfile::{NamedTempFile, TempDir};
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::save_archive::tests::make_sample_game_data;
+
+    fn sample_archive() -> Result<(NamedTempFile, SaveArchive)> {
+        let save = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create(save.path())?;
+        let mut png = vec![];
+        image::RgbImage::new(2, 2).write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+        let mut data = make_sample_game_data(2);
+        archive.append_image(&png)?;
+        archive.append_image(&png)?;
+        data.turn_data[1].output.text = "The door <b>creaks</b> & *opens*.".into();
+        archive.write_game_data(&data)?;
+        Ok((save, archive))
+    }
+
+    #[test]
+    fn actions_narration_and_images_alternate() -> Result<()> {
+        let (_save, mut archive) = sample_archive()?;
+        let dir = TempDir::new()?;
+        let path = dir.path().join("story.md");
+        assert_eq!(
+            export_story(&mut archive, &path, StoryFormat::Markdown, true)?,
+            2
+        );
+        let md = fs::read_to_string(&path)?;
+        let positions = [
+            "# World name",
+            "> Do action 0",
+            "Result of action 0",
+            "![caption 0](data:image/png;base64,",
+            "> Do action 1",
+            "The door",
+            "![caption 1](data:image/png;base64,",
+        ]
+        .map(|part| md.find(part).unwrap());
+        assert!(positions.is_sorted());
+
+        export_story(&mut archive, &path, StoryFormat::Markdown, false)?;
+        assert!(!fs::read_to_string(&path)?.contains("data:image"));
+        Ok(())
+    }
+
+    #[test]
+    fn html_in_the_narration_is_escaped() -> Result<()> {
+        let (_save, mut archive) = sample_archive()?;
+        let dir = TempDir::new()?;
+        let path = dir.path().join("story.html");
+        export_story(&mut archive, &path, StoryFormat::Html, false)?;
+        let html = fs::read_to_string(&path)?;
+        assert!(html.contains("<p class=\"action\">Do action 1</p>"));
+        assert!(html.contains("&lt;b&gt;creaks&lt;/b&gt; &amp; <em>opens</em>"));
+        Ok(())
+    }
+
+    #[test]
+    fn epubs_contain_the_story_and_images() -> Result<()> {
+        let (_save, mut archive) = sample_archive()?;
+        let dir = TempDir::new()?;
+        let path = dir.path().join("story.epub");
+        export_story(&mut archive, &path, StoryFormat::Epub, true)?;
+
+        let mut zip = ZipArchive::new(File::open(&path)?)?;
+        assert_eq!(zip.name_for_index(0), Some("mimetype"));
+        let mut story = String::new();
+        zip.by_name("OEBPS/story.xhtml")?
+            .read_to_string(&mut story)?;
+        assert!(story.contains("<img src=\"images/0002.png\" alt=\"caption 1\"/>"));
+        let mut opf = String::new();
+        zip.by_name("OEBPS/content.opf")?.read_to_string(&mut opf)?;
+        assert!(opf.contains("href=\"images/0001.png\" media-type=\"image/png\""));
+        Ok(())
+    }
+
+    #[test]
+    fn formats_are_chosen_by_extension() {
+        assert_eq!(
+            StoryFormat::from_path(Path::new("a/story.EPUB")),
+            Some(StoryFormat::Epub)
+        );
+        assert_eq!(StoryFormat::from_path(Path::new("story.txt")), None);
+        assert_eq!("html".parse(), Ok(StoryFormat::Html));
+    }
+}
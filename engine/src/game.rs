@@ -1,10 +1,17 @@
-use std::{collections::BTreeMap, pin::Pin};
+use std::{collections::BTreeMap, pin::Pin, sync::Arc};
 
 use crate::{
     ImgModBox, LLMBox,
+    archive_worker::ArchiveWorker,
+    audit_log::{AuditLog, RequestPurpose},
+    error::EngineError,
     game::stream_finder::StreamFinder,
-    image_model::{self, ModelStyle},
-    llm::{InputMessage, OutputMessage, Request, ResponseFragment},
+    image_model::{self, ImageModel, ImageProgress, ModelStyle, ProgressReporter},
+    llm::{InputMessage, LLM, OutputMessage, ProvidedModel, Request, ResponseFragment, Sampling},
+    replay::Recorder,
+    token_estimate,
+    tools::{self, ToolRegistry},
+    world_cover::Cover,
 };
 
 use async_stream::try_stream;
@@ -12,40 +19,126 @@ use color_eyre::{
     Result,
     eyre::{Context, ensure, eyre},
 };
-use log::{debug, error};
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
-use tokio::{pin, sync::oneshot};
-use tokio_stream::{Stream, StreamExt};
-
+use tokio::{
+    pin,
+    sync::{mpsc, oneshot, watch},
+};
+use tokio_stream::{Stream, StreamExt, wrappers::UnboundedReceiverStream};
+pub use tokio_util::sync::CancellationToken;
+
+mod branch;
+mod clock;
+mod codex;
+mod content_rating;
+mod cost_tracker;
+mod difficulty;
+mod fragment_coalescer;
+mod image_cache;
+pub mod migration;
+mod moderation;
+mod recap;
+mod repair;
+mod sanitize;
+pub mod script;
 mod stream_finder;
+pub mod summary;
+pub mod system_prompt;
+pub mod tone;
 mod turn_output;
 mod turn_stream_processor;
-
+pub mod world_details;
+pub mod world_state;
+
+pub use branch::Branch;
+pub use clock::InGameTime;
+pub use codex::{Codex, CodexEntry, CodexUpdate, EntryKind};
+pub use content_rating::ContentRating;
+pub use cost_tracker::{Cost, CostSummary, CostTracker, ProviderCosts};
+pub use difficulty::Difficulty;
+pub use image_cache::{CachedImage, ImageCache};
+pub use recap::Recap;
+pub use sanitize::sanitize_markdown;
+pub use script::Script;
+pub use summary::SummaryLevel;
+use system_prompt::PromptVariables;
+pub use tone::Tone;
 pub use turn_output::TurnOutput;
 use turn_stream_processor::{ProcessorEvent, TurnStreamProcessor};
+pub use world_details::{DetailKind, WorldDetail};
+pub use world_state::{WorldState, WorldStateUpdate};
 
-const SUMMARY_INTERVAL: usize = 5;
-const TURNS_KEPT_AFTER_SUMMARY: usize = 2;
+/// how many of the turns a summary covers are still sent verbatim, unless configured otherwise
+pub const DEFAULT_HISTORY_TURNS: usize = 2;
 const SECTION_IMAGE_DESCRIPTION: &str = "[SECTION IMAGE DESCRIPTION]";
 const SECTION_IMAGE_CAPTION: &str = "[SECTION IMAGE CAPTION]";
 const SECTION_OUTPUT: &str = "[SECTION OUTPUT]";
 const SECTION_SECRET_INFO: &str = "[SECTION SECRET INFO]";
+const SECTION_TIME: &str = "[SECTION TIME]";
+const SECTION_CODEX: &str = "[SECTION CODEX]";
+const SECTION_WORLD_STATE: &str = "[SECTION WORLD STATE]";
 const ACTION_SEPARATOR: &str = "[ACTION SEPARATOR]";
 
 pub struct Game {
     pub llm: LLMBox,
-    pub imgmod: ImgModBox,
+    /// Writes the summaries, which doesn't need an expensive model. If it's `None`, `llm`
+    /// is used.
+    pub summary_llm: Option<LLMBox>,
+    /// Without an image model, the game runs text-only
+    pub imgmod: Option<ImgModBox>,
     pub img_style: Option<ModelStyle>,
-    pub data: GameData,
+    /// Offered to `llm` while it writes a turn, if it supports tools
+    pub tools: Arc<ToolRegistry>,
+    /// Records all requests to the LLMs, if it's set
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Records what the models answer, so the game can be replayed, if it's set
+    pub recorder: Option<Arc<Recorder>>,
+    /// the hooks of the world's script, see [`script`]
+    pub script: Option<Arc<Script>>,
+    /// The estimated number of tokens a turn's prompt may take. If it's larger, the oldest
+    /// turns are left out. `None` means no limit.
+    pub context_budget: Option<usize>,
+    /// How many tokens `llm` takes in, if it's known. Prompts that are larger are still sent,
+    /// but [`PromptEstimate::exceeds_context`] warns about them.
+    pub context_window: Option<usize>,
+    /// used if the world doesn't set [`WorldDescription::history_turns`]
+    pub history_turns: usize,
+    /// every how many turns the story is summarized, see [`summary`]. 0 disables the summaries.
+    pub summary_interval: usize,
+    /// Sends the summary requests via a batch API, if the provider has one. They are cheaper
+    /// then, but can take minutes, so callers shouldn't wait for them, and add them with
+    /// [`Game::add_summary`] when they arrive.
+    pub batch_summaries: bool,
+    /// how many images are made per turn in addition to the main one, from the same description
+    pub extra_image_variants: usize,
+    /// every how many turns a snapshot is recorded, see [`GameData::snapshots`]. 0 records
+    /// none.
+    pub snapshot_every: usize,
+    /// Shared between clones, so cloning a game doesn't copy all turns. Use
+    /// [`Game::data_mut`] to modify it.
+    pub data: Arc<GameData>,
 }
 
 impl Clone for Game {
     fn clone(&self) -> Self {
         Self {
             llm: self.llm.clone(),
+            summary_llm: self.summary_llm.as_deref().map(LLM::clone),
             data: self.data.clone(),
             img_style: self.img_style.clone(),
-            imgmod: self.imgmod.clone(),
+            imgmod: self.imgmod.as_deref().map(ImageModel::clone),
+            tools: self.tools.clone(),
+            audit_log: self.audit_log.clone(),
+            recorder: self.recorder.clone(),
+            script: self.script.clone(),
+            context_budget: self.context_budget,
+            context_window: self.context_window,
+            history_turns: self.history_turns,
+            summary_interval: self.summary_interval,
+            batch_summaries: self.batch_summaries,
+            extra_image_variants: self.extra_image_variants,
+            snapshot_every: self.snapshot_every,
         }
     }
 }
@@ -58,10 +151,34 @@ enum SendToLLMState {
     FinishingUp,
 }
 
+pub type ImageFuture = Pin<Box<dyn Future<Output = Result<Image, EngineError>> + Send>>;
+
 pub struct AdvanceResult {
-    pub image: Pin<Box<dyn Future<Output = Result<Image>> + Send>>,
-    pub text_stream: Pin<Box<dyn Stream<Item = Result<String>> + Send>>,
-    pub round_output: Pin<Box<dyn Future<Output = Result<TurnOutput>> + Send>>,
+    /// `None` if the game has no image model
+    pub image: Option<ImageFuture>,
+    /// more images from the same description, made in parallel to `image`. The turn doesn't
+    /// wait for them, see [`TurnData::add_image_variant`].
+    pub image_variants: Vec<ImageFuture>,
+    pub text_stream: Pin<Box<dyn Stream<Item = Result<String, EngineError>> + Send>>,
+    /// What the LLM reasoned before it answered, if thinking is enabled. It ends with the
+    /// text stream, which has to be polled for it to make progress.
+    pub thought_stream: Pin<Box<dyn Stream<Item = String> + Send>>,
+    /// What the image model tells about `image` while it's made, if anything. It ends with
+    /// `image`, which has to be polled for it to make progress.
+    pub image_progress: Pin<Box<dyn Stream<Item = ImageProgress> + Send>>,
+    pub round_output: Pin<Box<dyn Future<Output = Result<TurnOutput, EngineError>> + Send>>,
+    /// Stops the turn. The LLM response and the image model are no longer polled, and
+    /// everything that's still pending fails with `EngineError::Cancelled`.
+    pub cancel: CancellationToken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptEstimate {
+    pub tokens: usize,
+    /// the number of turns that don't fit into the context budget, and are left out
+    pub dropped_turns: usize,
+    /// the prompt is larger than the context window of the LLM, which will likely reject it
+    pub exceeds_context: bool,
 }
 
 enum IncompleteStreamEnd {
@@ -72,61 +189,168 @@ enum IncompleteStreamEnd {
 impl Game {
     pub fn load(
         llm: LLMBox,
-        imgmod: ImgModBox,
+        imgmod: Option<ImgModBox>,
         data: GameData,
         img_style: Option<ModelStyle>,
     ) -> Self {
+        let tools = match data.world_description.pc_descriptions.get(&data.pc) {
+            Some(pc) => ToolRegistry::for_stats(&pc.stats, data.difficulty.roll_modifier()),
+            None => ToolRegistry::default(),
+        };
         Game {
             llm,
-            data,
+            summary_llm: None,
+            script: Script::for_world(&data.world_description).map(Arc::new),
+            data: Arc::new(data),
             imgmod,
             img_style,
+            tools: Arc::new(tools),
+            audit_log: None,
+            recorder: None,
+            context_budget: None,
+            context_window: None,
+            history_turns: DEFAULT_HISTORY_TURNS,
+            summary_interval: summary::DEFAULT_INTERVAL,
+            batch_summaries: false,
+            extra_image_variants: 0,
+            snapshot_every: 0,
         }
     }
 
     pub fn try_new(
         llm: LLMBox,
-        imgmod: ImgModBox,
+        imgmod: Option<ImgModBox>,
         world_description: WorldDescription,
         player_character: String,
         img_style: Option<ModelStyle>,
-    ) -> Result<Self> {
-        ensure!(
-            world_description
-                .pc_descriptions
-                .contains_key(&player_character),
-            "Invalid character name: {player_character}"
-        );
+        difficulty: Difficulty,
+    ) -> Result<Self, EngineError> {
+        let Some(pc) = world_description.pc_descriptions.get(&player_character) else {
+            return Err(EngineError::Other(eyre!(
+                "Invalid character name: {player_character}"
+            )));
+        };
+        let tools = ToolRegistry::for_stats(&pc.stats, difficulty.roll_modifier());
 
         Ok(Game {
             llm,
+            summary_llm: None,
             imgmod,
             img_style,
-            data: GameData {
+            tools: Arc::new(tools),
+            audit_log: None,
+            recorder: None,
+            script: Script::for_world(&world_description).map(Arc::new),
+            context_budget: None,
+            context_window: None,
+            history_turns: DEFAULT_HISTORY_TURNS,
+            summary_interval: summary::DEFAULT_INTERVAL,
+            batch_summaries: false,
+            extra_image_variants: 0,
+            snapshot_every: 0,
+            data: Arc::new(GameData {
+                schema_version: migration::CURRENT_SCHEMA_VERSION,
+                text_only: world_description.text_only,
+                difficulty,
+                pinned_seeds: BTreeMap::new(),
+                snapshots: vec![],
+                codex: Codex::default(),
+                world_state: WorldState::new(),
+                branch: String::new(),
+                branches: vec![],
+                recap: None,
                 world_description,
                 pc: player_character,
                 summaries: vec![],
                 turn_data: vec![],
-            },
+                costs: CostTracker::default(),
+                in_flight_turn: None,
+                image_cache: ImageCache::default(),
+            }),
         })
     }
 
+    /// Mutable access to the game data. If the data is shared with a clone of this game,
+    /// it's copied first.
+    pub fn data_mut(&mut self) -> &mut GameData {
+        Arc::make_mut(&mut self.data)
+    }
+
+    /// The request for the next turn, and the number of turns that were left out to fit
+    /// into the context budget. The script's `on_before_request` isn't called here, it has
+    /// to see only the requests that are sent.
+    fn turn_request(&self, input: &TurnInput) -> (Request, usize) {
+        let extra_img_infos = self.imgmod.as_ref().map_or("", |imgmod| {
+            imgmod
+                .provided_model()
+                .model()
+                .extra_generation_instructions()
+        });
+        let history_turns = self
+            .data
+            .world_description
+            .history_turns
+            .unwrap_or(self.history_turns);
+        let mut req = self
+            .data
+            .construct_request(input, extra_img_infos, history_turns);
+        if self.llm.supports_tools() && !self.tools.is_empty() {
+            req.tools = self.tools.specs();
+            if let Some(system) = &mut req.system {
+                system.push_str(tools::INSTRUCTIONS);
+                if self.tools.contains("skill_check") {
+                    system.push_str(tools::SKILL_CHECK_INSTRUCTIONS);
+                }
+            }
+        }
+        let dropped_turns = self
+            .context_budget
+            .map_or(0, |budget| token_estimate::trim_to_budget(&mut req, budget));
+        (req, dropped_turns)
+    }
+
+    /// How large the prompt for the next turn will be, if `input` is sent. What the world
+    /// script adds to the input isn't counted.
+    pub fn estimate_prompt(&self, input: &TurnInput) -> PromptEstimate {
+        let (req, dropped_turns) = self.turn_request(input);
+        let tokens = token_estimate::estimate_request(&req);
+        PromptEstimate {
+            tokens,
+            dropped_turns,
+            exceeds_context: self.context_window.is_some_and(|window| tokens > window),
+        }
+    }
+
     pub fn send_to_llm(&self, input: TurnInput) -> AdvanceResult {
         let (tx_output, rx_output) = oneshot::channel();
-        let (tx_img_description, rx_img_description) = oneshot::channel();
+        // every image variant waits for the description
+        let (tx_img_description, rx_img_description) = watch::channel(None);
+        let (tx_thoughts, rx_thoughts) = mpsc::unbounded_channel();
+        let (tx_rolls, mut rx_rolls) = mpsc::unbounded_channel();
         let mut tx_img_description = Some(tx_img_description);
-        let extra_img_infos = self
-            .imgmod
-            .provided_model()
-            .model()
-            .extra_generation_instructions();
-        let req = self.data.construct_request(&input, extra_img_infos);
-        let mut llm = self.llm.clone();
+        let input = match &self.script {
+            Some(script) => {
+                script.on_before_request(self.current_turn(), &input, &self.data.world_state)
+            }
+            None => input,
+        };
+        let (req, dropped_turns) = self.turn_request(&input);
+        if dropped_turns > 0 {
+            debug!("Left out the oldest {dropped_turns} turns to fit into the context budget");
+        }
+        let llm = self.audited(self.llm.clone(), RequestPurpose::Turn);
+        let mut repair_llm = self.audited(self.llm.clone(), RequestPurpose::Repair);
+        let tools = self.tools.clone();
+        let cancel = CancellationToken::new();
+        let with_image = !self.data.text_only;
+        let script = self.script.clone();
+        let turn = self.current_turn();
+        let world_state = self.data.world_state.clone();
 
         let stream = try_stream! {
-            let output = {
-                let stream = llm.send_request_stream(req);
-                let mut processor = TurnStreamProcessor::new();
+            let mut output = {
+                let stream = tools::run_with_tools(llm, req.clone(), tools, tx_rolls);
+                let mut processor = TurnStreamProcessor::new(with_image);
 
                 pin!(stream);
                 let output = 'receive: loop {
@@ -145,22 +369,43 @@ impl Game {
                             IncompleteStreamEnd::Error(err),
                         )?,
                     };
+                    let fragment = match fragment {
+                        ResponseFragment::ThinkingDelta(thought) => {
+                            _ = tx_thoughts.send(thought);
+                            continue;
+                        }
+                        other => other,
+                    };
 
-                    for event in processor.push(fragment)? {
+                    let events = match fragment {
+                        ResponseFragment::MessageComplete(msg) => {
+                            match processor.push(ResponseFragment::MessageComplete(msg.clone())) {
+                                Ok(events) => events,
+                                Err(err) if repair::is_parse_failure(&err) => {
+                                    let output =
+                                        repair::repair_output(&mut repair_llm, &req, msg, err, with_image).await?;
+                                    vec![ProcessorEvent::TurnComplete(output)]
+                                }
+                                Err(err) => Err(err)?,
+                            }
+                        }
+                        other => processor.push(other)?,
+                    };
+                    for event in events {
                         match event {
                             ProcessorEvent::VisibleText(text) => yield text,
                             ProcessorEvent::ImageDescriptionReady(description) => {
                                 debug!("Sending image description");
                                 _ = tx_img_description.take()
                                     .expect("finished parsing image description a second time. This should be impossible. It's a bug")
-                                    .send(description);
+                                    .send(Some(description));
                             }
                             ProcessorEvent::TurnComplete(output) => {
                                 if let Some(tx) = tx_img_description {
-                                    _ = tx.send(ImageDescription {
+                                    _ = tx.send(Some(ImageDescription {
                                         description: output.image_description.clone(),
                                         caption: output.image_caption.clone(),
-                                    });
+                                    }));
                                 }
                                 break 'receive output;
                             }
@@ -173,18 +418,92 @@ impl Game {
                 let _ = stream.try_next().await;
                 output
             };
+            // the tools are done once the message is complete
+            output.rolls = std::iter::from_fn(|| rx_rolls.try_recv().ok()).collect();
+            if let Some(script) = &script {
+                script.on_parse_output(turn, &mut output, &world_state);
+            }
             _ = tx_output.send(output);
 
         };
 
-        AdvanceResult {
-            image: Box::pin(get_image(
-                rx_img_description,
-                self.imgmod.clone(),
+        let image_future = |imgmod: &(dyn ImageModel + Send),
+                            cache: Option<ImageCache>,
+                            pinned_seeds: BTreeMap<String, u64>,
+                            progress: ProgressReporter| {
+            let image = get_image(
+                rx_img_description.clone(),
+                imgmod.clone(),
                 self.img_style.clone(),
-            )),
-            text_stream: Box::pin(stream),
-            round_output: Box::pin(async move { Ok(rx_output.await?) }),
+                cache,
+                pinned_seeds,
+                progress,
+                self.audited(self.llm.clone(), RequestPurpose::ImageRewrite),
+            );
+            let cancel = cancel.clone();
+            Box::pin(async move {
+                cancel
+                    .run_until_cancelled(image)
+                    .await
+                    .ok_or(EngineError::Cancelled)?
+                    .map_err(EngineError::from)
+            }) as ImageFuture
+        };
+        let imgmod = self.image_model().filter(|_| with_image);
+        let imgmod = imgmod.as_deref();
+        // only the image that is shown first reports its progress
+        let (progress, rx_image_progress) = ProgressReporter::new();
+        let image = imgmod.map(|imgmod| {
+            image_future(
+                imgmod,
+                Some(self.data.image_cache.clone()),
+                self.data.pinned_seeds.clone(),
+                progress,
+            )
+        });
+        let image_variants = imgmod
+            .map(|imgmod| {
+                // they're meant to be different from the cached image
+                (0..self.extra_image_variants)
+                    .map(|_| {
+                        image_future(imgmod, None, BTreeMap::new(), ProgressReporter::default())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        AdvanceResult {
+            image,
+            image_variants,
+            text_stream: Box::pin(
+                fragment_coalescer::coalesce(
+                    cancellable(stream, cancel.clone()),
+                    fragment_coalescer::MAX_DELAY,
+                    fragment_coalescer::MAX_BATCH_LEN,
+                )
+                .map(|res| res.map_err(EngineError::from)),
+            ),
+            thought_stream: Box::pin(
+                fragment_coalescer::coalesce(
+                    UnboundedReceiverStream::new(rx_thoughts).map(Ok),
+                    fragment_coalescer::MAX_DELAY,
+                    fragment_coalescer::MAX_BATCH_LEN,
+                )
+                .filter_map(Result::ok),
+            ),
+            image_progress: Box::pin(UnboundedReceiverStream::new(rx_image_progress)),
+            round_output: Box::pin({
+                let cancel = cancel.clone();
+                async move {
+                    rx_output.await.map_err(|e| {
+                        if cancel.is_cancelled() {
+                            EngineError::Cancelled
+                        } else {
+                            EngineError::Other(eyre!("The LLM stream was dropped: {e}"))
+                        }
+                    })
+                }
+            }),
+            cancel,
         }
     }
 
@@ -231,33 +550,66 @@ impl Game {
         self.data.turn_data.len()
     }
 
+    /// Wraps `llm` with the recorder and the audit log, if they are set. Its requests go into
+    /// the transcript of the turn that is generated.
+    fn audited(&self, llm: LLMBox, purpose: RequestPurpose) -> LLMBox {
+        let llm = self.recorded(llm);
+        match &self.audit_log {
+            Some(log) => log.wrap(llm, purpose),
+            None => llm,
+        }
+    }
+
+    /// Like [`Game::audited`], for requests that don't belong to the turn that is generated
+    fn audited_outside_turn(&self, llm: LLMBox, purpose: RequestPurpose) -> LLMBox {
+        let llm = self.recorded(llm);
+        match &self.audit_log {
+            Some(log) => log.wrap_outside_turn(llm, purpose),
+            None => llm,
+        }
+    }
+
+    fn recorded(&self, llm: LLMBox) -> LLMBox {
+        match &self.recorder {
+            Some(recorder) => recorder.wrap_llm(llm),
+            None => llm,
+        }
+    }
+
+    /// A copy of the image model, wrapped with the recorder if it's set
+    fn image_model(&self) -> Option<ImgModBox> {
+        let imgmod = self.imgmod.as_deref()?.clone();
+        Some(match &self.recorder {
+            Some(recorder) => recorder.wrap_image_model(imgmod),
+            None => imgmod,
+        })
+    }
+
     pub fn world_name(&self) -> &str {
         &self.data.world_description.name
     }
 
     pub fn mk_summary_if_neccessary(
         &self,
-    ) -> Pin<Box<dyn Future<Output = Result<Option<OutputMessage>>> + Send + 'static>> {
-        let current_turn = self.current_turn() as isize;
-        let should_summarize = match self.data.summaries.last() {
-            Some(s) => current_turn - s.bday as isize >= SUMMARY_INTERVAL as isize,
-            None => current_turn >= SUMMARY_INTERVAL as isize,
-        };
-        let tdl = self.data.turn_data.len();
-
-        if should_summarize {
+    ) -> Pin<Box<dyn Future<Output = Result<Option<OutputMessage>, EngineError>> + Send + 'static>>
+    {
+        if let Some(turns) = summary::due_turns(&self.data, self.summary_interval) {
             debug!("updating summary");
-            let llm = self.llm.clone();
-            let last_summary = self
-                .data
-                .summaries
-                .last()
-                .map(|s| s.content.as_str())
-                .unwrap_or("")
-                .to_string();
-            let turns = self.data.turn_data[tdl - SUMMARY_INTERVAL..tdl].to_vec();
+            let mut llm = self.summary_llm.as_deref().unwrap_or(&*self.llm).clone();
+            llm.set_batched(self.batch_summaries);
+            // a batched summary arrives after the turn it was requested with
+            let llm = if self.batch_summaries {
+                self.audited_outside_turn(llm, RequestPurpose::Summary)
+            } else {
+                self.audited(llm, RequestPurpose::Summary)
+            };
+            let level = summary::next_level(&self.data.summaries);
+            let story_so_far = summary::story_so_far(&self.data.summaries);
+            let prompt = self.data.world_description.summary_prompt().to_string();
+            let turns = self.data.turn_data[turns].to_vec();
             Box::pin(async move {
-                let summary = create_new_summary(llm, &last_summary, turns).await?;
+                let summary =
+                    summary::summarize(llm, &prompt, level, &story_so_far, turns).await?;
                 debug!("Received new summary");
                 Ok(Some(summary))
             })
@@ -266,6 +618,46 @@ impl Game {
         }
     }
 
+    /// Asks the summary LLM for a title and a recap of the story so far. Add them with
+    /// [`Game::set_recap`].
+    pub fn mk_recap(
+        &self,
+    ) -> impl Future<Output = Result<(Recap, OutputMessage), EngineError>> + Send + 'static {
+        let llm = self.summary_llm.as_deref().unwrap_or(&*self.llm).clone();
+        let llm = self.audited_outside_turn(llm, RequestPurpose::Recap);
+        let story_so_far = summary::story_so_far(&self.data.summaries);
+        let turns = self.data.turn_data.len();
+        let since_summary = self.data.summaries.last().map_or(0, |s| s.bday);
+        let start = since_summary.max(turns.saturating_sub(recap::RECENT_TURNS));
+        let recent = self.data.turn_data[start..].to_vec();
+        async move {
+            recap::write_recap(llm, &story_so_far, &recent, turns)
+                .await
+                .map_err(EngineError::from)
+        }
+    }
+
+    /// Keeps a recap that [`Game::mk_recap`] wrote, and what it cost
+    pub fn set_recap(&mut self, recap: Recap, message: OutputMessage) {
+        let provider = self
+            .summary_llm
+            .as_deref()
+            .unwrap_or(&*self.llm)
+            .provider()
+            .to_string();
+        let data = self.data_mut();
+        data.costs.add(
+            recap.turns.saturating_sub(1),
+            provider,
+            Cost {
+                input_tokens: message.input_tokens,
+                output_tokens: message.output_tokens,
+                ..Default::default()
+            },
+        );
+        data.recap = Some(recap);
+    }
+
     pub fn get_latest_image_info(&self) -> Option<&StoredImageInfo> {
         self.data.turn_data.iter().flat_map(|td| &td.images).last()
     }
@@ -282,29 +674,256 @@ impl Game {
         input: TurnInput,
         output: TurnOutput,
         images: Vec<StoredImageInfo>,
-        summary: Option<String>,
-    ) -> Result<()> {
+        summary: Option<OutputMessage>,
+    ) -> Result<(), EngineError> {
+        let llm_provider = self.llm.provider().to_string();
+        let snapshot_every = self.snapshot_every;
+        let script = self.script.clone();
+        let data = self.data_mut();
+        data.codex.apply(&output.codex_updates, data.turn_data.len());
+        world_state::apply(
+            &mut data.world_state,
+            &world_state::gm_directives(&input.gm_instruction),
+        );
+        world_state::apply(&mut data.world_state, &output.world_state_updates);
         let turn_data = TurnData {
             summary_before_input: {
-                let len = self.data.summaries.len();
+                let len = data.summaries.len();
                 if len > 0 { Some(len - 1) } else { None }
             },
             input,
             output: output.clone(),
             images,
+            bookmarked: false,
+            bookmark_label: None,
+            // recorded once the script ran
+            world_state: None,
         };
-        self.data.turn_data.push(turn_data);
+        data.turn_data.push(turn_data);
+        data.in_flight_turn = None;
+        let turn = data.turn_data.len() - 1;
+        if let Some(script) = script {
+            script.on_turn_complete(turn, &data.turn_data[turn], &mut data.world_state);
+        }
+        data.turn_data[turn].world_state = Some(data.world_state.clone());
+        data.costs.add(
+            turn,
+            llm_provider,
+            Cost {
+                input_tokens: output.input_tokens,
+                output_tokens: output.output_tokens,
+                ..Default::default()
+            },
+        );
+        // nothing is a multiple of 0
+        if (turn + 1).is_multiple_of(snapshot_every) {
+            data.snapshots.push(turn);
+        }
 
-        if let Some(content) = summary {
-            self.data.summaries.push(Summary {
-                content,
-                bday: self.data.turn_data.len() - 1,
-            });
+        if let Some(summary) = summary {
+            self.add_summary(summary, turn);
         }
 
         Ok(())
     }
 
+    /// Adds a summary that was requested before the turn `bday` was added. Summaries that
+    /// are older than the latest one are ignored. The level follows from the summaries
+    /// before it, like it did when the summary was requested.
+    pub fn add_summary(&mut self, summary: OutputMessage, bday: usize) {
+        let provider = self
+            .summary_llm
+            .as_deref()
+            .unwrap_or(&*self.llm)
+            .provider()
+            .to_string();
+        let data = self.data_mut();
+        if data.summaries.last().is_some_and(|s| s.bday >= bday) {
+            warn!("Ignoring the summary for turn {bday}, there is a newer one");
+            return;
+        }
+        data.costs.add(
+            bday,
+            provider,
+            Cost {
+                input_tokens: summary.input_tokens,
+                output_tokens: summary.output_tokens,
+                ..Default::default()
+            },
+        );
+        let level = summary::next_level(&data.summaries);
+        data.summaries.push(Summary {
+            content: summary.text,
+            bday,
+            level,
+        });
+    }
+
+    /// Completes the in-flight turn with what was received of it. Since the output is
+    /// incomplete, there are no proposed actions or secret info.
+    pub fn finalize_in_flight_turn(&mut self) -> Result<(), EngineError> {
+        let InFlightTurn {
+            input,
+            partial_text,
+            image,
+        } = self
+            .data_mut()
+            .in_flight_turn
+            .take()
+            .ok_or_else(|| EngineError::Other(eyre!("There is no in-flight turn")))?;
+        let caption = image
+            .as_ref()
+            .map(|i| i.caption.clone())
+            .unwrap_or_default();
+        let output =
+            TurnOutput::from_parts(String::new(), caption, partial_text, None, vec![], 0, 0);
+        self.update(input, output, image.into_iter().collect(), None)
+    }
+
+    /// Removes the last turn, together with its images. The archive is clipped first, so the
+    /// game data is only changed if that worked, and then read from it again, see
+    /// `SaveArchive::clip_after_turn`.
+    pub fn undo_last_turn(&mut self, save: &mut ArchiveWorker) -> Result<(), EngineError> {
+        let data = &self.data;
+        if data.in_flight_turn.is_some() {
+            return Err(EngineError::Other(eyre!(
+                "A turn can't be undone while the next one is generated"
+            )));
+        }
+        if data.turn_data.len() < 2 {
+            return Err(EngineError::Other(eyre!("The first turn can't be undone")));
+        }
+        save.clip_after_turn(data.turn_data.len() - 2)?;
+        self.data = Arc::new(save.read_game_data()?);
+        Ok(())
+    }
+
+    /// Adds a received image of `turn` to the costs, with the rewrite of its description, if
+    /// there was one. The turn may still be in flight.
+    pub fn record_image(&mut self, turn: usize, image: &Image) {
+        if let Some(rewrite) = &image.rewrite {
+            let llm_provider = self.llm.provider().to_string();
+            self.data_mut().costs.add(
+                turn,
+                llm_provider,
+                Cost {
+                    input_tokens: rewrite.input_tokens,
+                    output_tokens: rewrite.output_tokens,
+                    ..Default::default()
+                },
+            );
+        }
+        let Some(imgmod) = &self.imgmod else {
+            return;
+        };
+        let provider = imgmod.provided_model().provider().to_string();
+        self.data_mut().costs.add(
+            turn,
+            provider,
+            Cost {
+                images: 1,
+                dollars: image.cost.unwrap_or_default(),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Makes another image for a completed turn, from the description that was written for it.
+    /// Use [`Game::record_image`] when it's added to the turn.
+    /// With the `seed` of an earlier image, the new one looks like it, e.g. after the style
+    /// was changed. Without, a random one is used, ignoring the pinned seeds.
+    pub fn regenerate_image(
+        &self,
+        turn: usize,
+        seed: Option<u64>,
+    ) -> Result<impl Future<Output = Result<Image>> + Send + 'static> {
+        let output = &self
+            .data
+            .turn_data
+            .get(turn)
+            .ok_or_else(|| eyre!("Invalid turn: {turn}"))?
+            .output;
+        let description = ImageDescription {
+            description: output.image_description.clone(),
+            caption: output.image_caption.clone(),
+        };
+        let imgmod = self
+            .image_model()
+            .ok_or_else(|| eyre!("There is no image model configured"))?;
+        // a new image is wanted, so the cache isn't asked
+        Ok(make_image(
+            description,
+            imgmod,
+            self.img_style.clone(),
+            None,
+            seed,
+            ProgressReporter::default(),
+            self.audited_outside_turn(self.llm.clone(), RequestPurpose::ImageRewrite),
+        ))
+    }
+
+    /// Changes an image of a completed turn as the `instruction` says. `original` is the image,
+    /// as it's stored. Use [`Game::record_image`] when the edit is added to the turn.
+    pub fn edit_image(
+        &self,
+        turn: usize,
+        original: Vec<u8>,
+        instruction: String,
+    ) -> Result<impl Future<Output = Result<Image>> + Send + 'static> {
+        let caption = self
+            .data
+            .turn_data
+            .get(turn)
+            .ok_or_else(|| eyre!("Invalid turn: {turn}"))?
+            .output
+            .image_caption
+            .clone();
+        let imgmod = self
+            .image_model()
+            .ok_or_else(|| eyre!("There is no image model configured"))?;
+        ensure!(
+            imgmod.can_edit(),
+            "{} can't edit images",
+            imgmod.provided_model()
+        );
+        Ok(async move {
+            let image = imgmod
+                .edit_image(&original, &instruction, ProgressReporter::default())
+                .await?;
+            Ok(Image {
+                caption,
+                description: instruction,
+                cost: image.cost,
+                bytes: image.data,
+                // the seed of an edit doesn't make the same image from the description
+                seed: None,
+                cache_key: None,
+                cached: None,
+                rewrite: None,
+            })
+        })
+    }
+
+    /// Makes a new image for every completed turn that has one, from the description that was
+    /// written for it, in the current style. The seed of the displayed image is reused, so the
+    /// new images show the same scenes. Add them with [`TurnData::add_image_variant`] and
+    /// [`Game::record_image`].
+    pub fn restyle_images(
+        &self,
+    ) -> Result<Vec<(usize, impl Future<Output = Result<Image>> + Send + 'static)>> {
+        self.data
+            .turn_data
+            .iter()
+            .enumerate()
+            .filter_map(|(turn, turn_data)| Some((turn, turn_data.images.last()?.seed)))
+            .map(|(turn, seed)| Ok((turn, self.regenerate_image(turn, seed)?)))
+            .collect()
+    }
+
+    pub fn cost_summary(&self) -> CostSummary {
+        self.data.costs.summary()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.data.turn_data.is_empty()
     }
@@ -330,165 +949,22 @@ impl Game {
     }
 }
 
-async fn create_new_summary(
-    mut llm: LLMBox,
-    last_summary: &str,
-    turns: Vec<TurnData>,
-) -> Result<OutputMessage> {
-    let system_message = indoc::indoc! {r#"
-            You are a summarization component for an ongoing narrative game.
-
-            This game normally runs in a storyteller mode with strict input and output formats.
-            You must UNDERSTAND those formats, but you must NOT produce output in those formats.
-
-            NORMAL GAME CONTEXT (for understanding only):
-
-            - The game progresses in discrete TURNS.
-            - Each turn consists of:
-              1) An input, consisting of the turn number and three optional components:
-                  - a player instruction that is a command for the player character to execute.
-                    these commands can fail, if appropriate in the world
-                  - a gm instruction, which should be respected by the story teller, and which
-                    might contain important information that should end up in the summary.
-                  - hidden information about the last output that weren't displayed to the player,
-                    but may not the less contain important information.
-              2) A storyteller (assistant) response shown to the player
-
-            INPUT FORMAT FOR THIS TASK:
-
-            You will receive:
-            - The previous summary (if it exists)
-            - A sequence of turns since that summary
-
-            Each turn is represented as:
-
-            START EXAMPLE
-            # player action
-            *whatever I want {player} to do or say.*
-            # gm command
-            *whatever I want you to respect while generating the next message.*
-            # assistant output
-            * the output that was provided by the assistant*
-            # secret info
-            * The secret Info generated for the output*
-            END EXAMPLE
-
-            Turns are separated by the delimiter:
-            ---
-
-            TASK:
-
-            - Produce an updated summary that incorporates all rounds since the previous summary and
-              the previous summary itself.
-              Or create a new one, if there is no old summary to update. Keep the summary as concicse as
-              possible. It may at most be 2000 words in size, the shorter the better.
-
-            RULES:
-
-            - Do NOT continue the story.
-            - Do NOT roleplay.
-            - Do NOT respond as the storyteller.
-            - Do NOT follow the storyteller output format.
-            - Do NOT invent new events.
-            - Do NOT address the player.
-
-            OUTPUT REQUIREMENTS:
-
-            - Output ONLY the updated summary.
-            - Use concise, neutral, factual language.
-            - Past tense.
-            - Focus on:
-              - Major plot developments
-              - Important world or character state changes
-              - Decisions, events and consequences that affect future gameplay
-            - The purpose of the summary is to allow the story teller to
-              continue the story without contradicting himself. So it should contain
-              all relevant facts, and timepoints.
-            - The summary doesn't need to be well readable prose, it needs to
-              well readable prose, it needs to contain all relevant information
-              and be as short as possible.
-            - No formatting beyond plain paragraphs unless explicitly requested.
-            - Make sure to include all relevant information from the last summary
-              and all provided turns, don't overweight the latest ones.
-            - You may drop the least important information to keep the word-limit.
-            - Add a section with GM instructions if there are commands that need to be remembered long-term
-            - Never drop a character from the summary that had a meaningful interaction with the player
-
-            You are a summarization tool, not a storyteller.
-        "#};
-
-    let term_strs = turns
-        .iter()
-        .map(|t| {
-            let TurnInput {
-                player_action,
-                gm_instruction,
-            } = &t.input;
-            indoc::formatdoc! {
-                "## player action
-                {}
-                ## gm command
-                {}
-                ## assistant output
-                {}
-                ## secret info
-                {}", player_action,
-               gm_instruction,
-               t.output.text,
-               t.output.secret_info
-            }
-        })
-        .collect::<Vec<_>>();
-
-    let user_message = indoc::formatdoc! {r#"
-            # Last Summary
-
-            {last_summary}
-                
-            # Turns to summarize
-
-            {}
-
-            # Instructions
-
-            Use the old summary (if it exists) and the provided turns to create a new summary
-        "#, term_strs.join("\n---\n")};
-
-    debug!("Sending summary request");
-    let mut stream = llm.send_request_stream(Request {
-        system: Some(system_message.into()),
-        messages: vec![InputMessage::user(user_message)],
-        max_tokens: 3000,
-    });
-    let mut received_text = String::new();
-
-    let response = loop {
-        let fragment = match stream.try_next().await {
-            Ok(Some(fragment)) => fragment,
-            Ok(None) => {
-                error!(
-                    "Summary stream ended before message completion. Received text so far:\n{}",
-                    received_text
-                );
-                Err(eyre!("summary stream ended before message completion"))?
+/// Ends `stream` with `EngineError::Cancelled` once `cancel` is triggered. The inner stream
+/// is dropped then, which closes its connection.
+fn cancellable<T: Send>(
+    stream: impl Stream<Item = Result<T>> + Send,
+    cancel: CancellationToken,
+) -> impl Stream<Item = Result<T>> + Send {
+    try_stream! {
+        pin!(stream);
+        loop {
+            match cancel.run_until_cancelled(stream.next()).await {
+                Some(Some(item)) => yield item?,
+                Some(None) => break,
+                None => Err(EngineError::Cancelled)?,
             }
-            Err(err) => {
-                error!(
-                    "Summary stream failed before message completion. Received text so far:\n{}\nError: {err:?}",
-                    received_text
-                );
-                Err(err)?
-            }
-        };
-
-        match fragment {
-            ResponseFragment::TextDelta(text) => received_text.push_str(&text),
-            ResponseFragment::MessageComplete(m) => break m,
         }
-    };
-
-    ensure!(matches!(stream.try_next().await, Ok(None)));
-    Ok(response)
+    }
 }
 
 fn parse_image_description(src: &str) -> Result<ImageDescription> {
@@ -526,34 +1002,129 @@ fn trim_leading_markers<'a>(mut src: &'a str, markers: &[&str]) -> &'a str {
 }
 
 async fn get_image(
-    rx_img_description: oneshot::Receiver<ImageDescription>,
+    mut rx_img_description: watch::Receiver<Option<ImageDescription>>,
     imgmod: ImgModBox,
     style: Option<ModelStyle>,
+    cache: Option<ImageCache>,
+    pinned_seeds: BTreeMap<String, u64>,
+    progress: ProgressReporter,
+    rewrite_llm: LLMBox,
 ) -> Result<Image> {
-    let ImageDescription {
-        mut description,
-        caption,
-    } = rx_img_description.await?;
+    let description = rx_img_description
+        .wait_for(Option::is_some)
+        .await?
+        .clone()
+        .expect("waited for the description");
+    let seed = pinned_seed(&pinned_seeds, &description);
+    make_image(
+        description,
+        imgmod,
+        style,
+        cache,
+        seed,
+        progress,
+        rewrite_llm,
+    )
+    .await
+}
 
-    if let Some(style) = style {
-        description = format!(
-            "{} {} {}",
-            style.prefix.trim(),
-            description.trim(),
-            style.postfix.trim()
-        );
+/// The seed of the longest pinned name that the description or caption mentions, so a
+/// "Red Fox Inn" wins over a "Fox"
+fn pinned_seed(
+    pinned_seeds: &BTreeMap<String, u64>,
+    description: &ImageDescription,
+) -> Option<u64> {
+    let text = format!("{}\n{}", description.description, description.caption).to_lowercase();
+    pinned_seeds
+        .iter()
+        .filter(|(name, _)| !name.trim().is_empty() && text.contains(&name.trim().to_lowercase()))
+        .max_by_key(|(name, _)| name.trim().len())
+        .map(|(_, seed)| *seed)
+}
+
+/// When the image is moderated, `rewrite_llm` is asked for a tamer description, and the image
+/// is requested once more
+async fn make_image(
+    ImageDescription {
+        description: raw_description,
+        caption,
+    }: ImageDescription,
+    imgmod: ImgModBox,
+    style: Option<ModelStyle>,
+    cache: Option<ImageCache>,
+    seed: Option<u64>,
+    progress: ProgressReporter,
+    mut rewrite_llm: LLMBox,
+) -> Result<Image> {
+    let description = styled(&raw_description, style.as_ref());
+
+    // a pinned seed makes another image than a random one
+    let cache_key = match seed {
+        Some(seed) => ImageCache::key(
+            imgmod.provided_model(),
+            &format!("{description}\nseed {seed}"),
+        ),
+        None => ImageCache::key(imgmod.provided_model(), &description),
+    };
+    if let Some(cached) = cache.and_then(|cache| cache.get(cache_key)) {
+        debug!("Reusing image {} for the same prompt", cached.id);
+        return Ok(Image {
+            caption,
+            description,
+            cost: None,
+            bytes: vec![],
+            seed: cached.seed,
+            cache_key: Some(cache_key),
+            cached: Some(cached),
+            rewrite: None,
+        });
     }
 
-    let image_model::Image { data, cost } = imgmod.get_image(&description).await?;
+    let (description, rewrite, image) =
+        match imgmod.get_image(&description, seed, progress.clone()).await {
+            Err(err) if moderation::is_moderated(&err) => {
+                warn!("Asking the LLM for a tamer image description: {err}");
+                let rewrite = moderation::tame_description(&mut rewrite_llm, &raw_description)
+                    .await
+                    .map_err(|e| {
+                        err.wrap_err(format!("The image description couldn't be rewritten: {e}"))
+                    })?;
+                let description = styled(&rewrite.text, style.as_ref());
+                let image = imgmod
+                    .get_image(&description, seed, progress)
+                    .await
+                    .wrap_err("The rewritten image description was moderated, too")?;
+                (description, Some(Box::new(rewrite)), image)
+            }
+            res => (description, None, res?),
+        };
+    let image_model::Image { data, cost, seed } = image;
 
+    // the cache key stays the one of the original description, so it's not moderated again
     Ok(Image {
         caption,
         description,
         cost,
-        jpeg_bytes: data,
+        bytes: data,
+        seed,
+        cache_key: Some(cache_key),
+        cached: None,
+        rewrite,
     })
 }
 
+pub(crate) fn styled(description: &str, style: Option<&ModelStyle>) -> String {
+    match style {
+        Some(style) => format!(
+            "{} {} {}",
+            style.prefix.trim(),
+            description.trim(),
+            style.postfix.trim()
+        ),
+        None => description.into(),
+    }
+}
+
 // this is used a single time when pressing continue in the main menu
 // it's very short-lived, and I find this acceptable
 #[allow(clippy::large_enum_variant)]
@@ -564,91 +1135,122 @@ pub enum StartResultOrData {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameData {
+    /// see `migration`
+    pub schema_version: u32,
     pub world_description: WorldDescription,
     pub pc: String,
     pub summaries: Vec<Summary>,
     pub turn_data: Vec<TurnData>,
+    #[serde(default)]
+    pub costs: CostTracker,
+    /// The turn that is currently being generated. If it's still set when a game is loaded,
+    /// the app was closed during generation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_flight_turn: Option<InFlightTurn>,
+    /// the images in the archive by prompt, so the same prompt isn't paid for twice
+    #[serde(default)]
+    pub image_cache: ImageCache,
+    /// no images are made, and the LLM isn't asked for image descriptions
+    #[serde(default)]
+    pub text_only: bool,
+    /// how often actions fail, see [`Difficulty`]
+    #[serde(default)]
+    pub difficulty: Difficulty,
+    /// seeds by the name of a character or location. An image whose description mentions
+    /// the name is made with the seed, so they look alike.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pinned_seeds: BTreeMap<String, u64>,
+    /// The turns after which a snapshot was recorded. The save manager offers to continue a
+    /// save from each of them in a new save, while the turn is still part of it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<usize>,
+    /// the characters, locations and factions so far, see [`Codex`]
+    #[serde(default, skip_serializing_if = "Codex::is_empty")]
+    pub codex: Codex,
+    /// facts that always are part of the prompt, see [`world_state`]. When turns are removed,
+    /// it's restored to the state after the last remaining one, see [`TurnData::world_state`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub world_state: WorldState,
+    /// the name of the current branch, empty for the one the game started with. See
+    /// [`Branch`].
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub branch: String,
+    /// the other branches
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub branches: Vec<Branch>,
+    /// the latest title and recap of the story, see [`Game::mk_recap`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recap: Option<Recap>,
 }
 
-const MAX_WORDS: usize = 1000;
+pub const DEFAULT_TARGET_OUTPUT_WORDS: usize = 1000;
+pub const DEFAULT_MAX_TOKENS: usize = 5000;
 
 impl GameData {
-    pub fn construct_request(&self, input: &TurnInput, image_gen_extra_infos: &str) -> Request {
+    /// `history_turns` is the number of turns before the latest summary that are sent
+    /// verbatim anyway, so the story continues smoothly after it
+    pub fn construct_request(
+        &self,
+        input: &TurnInput,
+        image_gen_extra_infos: &str,
+        history_turns: usize,
+    ) -> Request {
         let player = &self.pc;
         let world_description = &self.world_description.main_description;
-        let pc_description = &self.world_description.pc_descriptions[&self.pc].description;
-        let last_summary = self.summaries.last();
-        let (summary, summary_turn) = match last_summary {
-            Some(Summary { content, bday }) => (content.as_str(), *bday),
-            None => ("", 0),
+        let target_words = self.world_description.target_output_words();
+        let pc = &self.world_description.pc_descriptions[&self.pc];
+        let pc_description = &pc.description;
+        let stats = pc.stats_text();
+        let (story_so_far, summary_turn) = match self.summaries.last() {
+            Some(last) => (summary::story_so_far(&self.summaries), last.bday),
+            None => (String::new(), 0),
+        };
+
+        let codex = self.codex.to_prompt();
+        let time = InGameTime::after(&self.turn_data).to_string();
+        // the GM's changes apply to the turn they are sent with
+        let mut state = self.world_state.clone();
+        world_state::apply(
+            &mut state,
+            &world_state::gm_directives(&input.gm_instruction),
+        );
+        let state = world_state::to_text(&state);
+        let tone = self.world_description.tone.to_prompt();
+        let difficulty = self.difficulty.to_prompt();
+        let locations = world_details::to_prompt(&self.world_description.locations);
+        let factions = world_details::to_prompt(&self.world_description.factions);
+        let npcs = world_details::to_prompt(&self.world_description.npcs);
+        let vars = PromptVariables {
+            images: !self.text_only,
+            codex: &codex,
+            world_state: &state,
+            time: &time,
+            stats: &stats,
+            tone: &tone,
+            difficulty,
+            locations: &locations,
+            factions: &factions,
+            npcs: &npcs,
+            ..PromptVariables::new(
+                player,
+                pc_description,
+                world_description,
+                image_gen_extra_infos,
+                target_words,
+                &story_so_far,
+                summary_turn,
+            )
         };
+        let system_message = self.world_description.render_system_prompt(&vars);
 
-        let system_message = indoc::formatdoc! {r#"
-           You are a Story-teller-game. In this world, I control {player}. When I send input,
-           it tells you what {player} tries to do or say, plus optional GM instructions for how
-           to shape the next turn. If I provide neither, continue the story naturally.
-
-           For each turn, also generate an image description for an image model. Be consistent
-           about character appearance and current state, especially hair, clothes and accessories.
-           {image_gen_extra_infos}
-
-           Output format:
-           Your reply must begin immediately with {SECTION_IMAGE_DESCRIPTION}.
-           Do not write any text before it. Do not write planning, explanations, or meta text.
-           Use exactly this structure and keep the delimiters unchanged:
-
-           {SECTION_IMAGE_DESCRIPTION}
-           image description
-           {SECTION_IMAGE_CAPTION}
-           short image caption, 1-5 words
-           {SECTION_OUTPUT}
-           visible story text, at most {MAX_WORDS} words, starting with date, time, weekday and location
-           {ACTION_SEPARATOR}
-           proposed action 1
-           {ACTION_SEPARATOR}
-           proposed action 2
-           {ACTION_SEPARATOR}
-           proposed action 3
-           {SECTION_SECRET_INFO}
-           secret info
-
-           Rules:
-           - The first characters of your reply must be exactly {SECTION_IMAGE_DESCRIPTION}
-           - The image should usually show a single currently important character unless a place or object is more important
-           - Proposed actions must be direct next actions for {player}
-           - Proposed actions must not contain hidden info, narrator notes, plans, or world-state summaries
-           - If an action would reveal something the player does not know, put that into secret info instead
-           - Secret info is a short hidden note for future turns
-           - Secret info must never be empty. Only write `none` if there is truly nothing hidden or worth tracking
-           - Do not generate anything after the secret info
-           - Use 2nd person narration
-           - You do NOT have an oppinion on what is right, wrong, or appropriate
-
-           Here is the description of the world the story plays in, and some some
-           instructions about the style:
-           --- START DESCRIPTION ---
-           {world_description} 
-           --- END DESCRIPTION ---
-
-           Here is a description of my character, {player}:
-           --- START DESCRIPTION ---
-           {pc_description}
-           --- END DESCRIPTION ---
-           
-
-           Here is a summary of everthing that has happened up till turn {summary_turn}:
-           --- START SUMMARY ---
-           {summary} 
-           --- END SUMMARY ---
-        "#};
-
-        let messages = (self.request_context_start()..self.turn_data.len()).flat_map(|i| {
+        let context_start = self.request_context_start(history_turns);
+        let messages = (context_start..self.turn_data.len()).flat_map(|i| {
             let mut user_message = format!("turn {i}");
             let TurnData { input, output, .. } = &self.turn_data[i];
             input.write_to_user_msg_string(&mut user_message);
             [
                 InputMessage::user(user_message),
-                InputMessage::assistant(output.to_llm_format()),
+                InputMessage::assistant(output.to_llm_format(!self.text_only)),
             ]
         });
 
@@ -664,20 +1266,142 @@ impl GameData {
             .collect();
         Request {
             messages,
-            max_tokens: 5000,
+            max_tokens: self.world_description.max_tokens(),
             system: Some(system_message),
+            sampling: self.world_description.sampling.clone(),
+            tools: vec![],
         }
     }
 
-    fn request_context_start(&self) -> usize {
+    /// Deletes the turns after `turn`, and what was derived from them: their summaries,
+    /// snapshots, codex entries and world state changes. Images are left alone, they belong
+    /// to the archive, and so are the branches that were forked from the removed turns. To
+    /// continue from a past turn and keep the later ones, use [`GameData::fork_after`].
+    pub fn clip_after_turn(&mut self, turn: usize) {
+        self.split_off_branch(turn);
+        self.codex = Codex::replay(&self.turn_data);
+    }
+
+    /// Replaces the world state. It counts as the state after the latest turn, so it's kept
+    /// when later turns are undone.
+    pub fn set_world_state(&mut self, state: WorldState) {
+        if let Some(td) = self.turn_data.last_mut() {
+            td.world_state = Some(state.clone());
+        }
+        self.world_state = state;
+    }
+
+    /// Bookmarks `turn`, with a label if it isn't blank. `None` removes the bookmark.
+    pub fn set_bookmark(&mut self, turn: usize, label: Option<String>) -> Result<()> {
+        let td = self
+            .turn_data
+            .get_mut(turn)
+            .ok_or_else(|| eyre!("There is no turn {}", turn + 1))?;
+        td.bookmarked = label.is_some();
+        td.bookmark_label = label
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty());
+        Ok(())
+    }
+
+    /// The recap, if it was written after the latest turn
+    pub fn current_recap(&self) -> Option<&Recap> {
+        self.recap
+            .as_ref()
+            .filter(|recap| recap.turns == self.turn_data.len())
+    }
+
+    /// The indices of the bookmarked turns, with their titles
+    pub fn bookmarks(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.turn_data
+            .iter()
+            .enumerate()
+            .filter(|(_, td)| td.bookmarked)
+            .map(|(i, td)| (i, td.bookmark_title()))
+    }
+
+    fn request_context_start(&self, history_turns: usize) -> usize {
         let Some(summary) = self.summaries.last() else {
             return 0;
         };
 
-        summary
-            .bday
-            .saturating_add(1)
-            .saturating_sub(TURNS_KEPT_AFTER_SUMMARY)
+        summary.bday.saturating_add(1).saturating_sub(history_turns)
+    }
+
+    /// Checks that the references within the data are intact, e.g. after it was edited by
+    /// hand. `n_images` is the number of images in the archive the data belongs to. The error
+    /// lists every problem that was found.
+    pub fn validate(&self, n_images: usize) -> Result<()> {
+        let mut problems = vec![];
+        if !self
+            .world_description
+            .pc_descriptions
+            .contains_key(&self.pc)
+        {
+            problems.push(format!("The world has no character {:?}", self.pc));
+        }
+        for (i, summary) in self.summaries.iter().enumerate() {
+            if summary.bday >= self.turn_data.len() {
+                problems.push(format!(
+                    "Summary {i} was created after turn {}, which doesn't exist",
+                    summary.bday
+                ));
+            }
+        }
+        let in_flight_image = self.in_flight_turn.iter().flat_map(|turn| &turn.image);
+        let images = self
+            .turn_data
+            .iter()
+            .enumerate()
+            .flat_map(|(i, td)| {
+                td.images
+                    .iter()
+                    .map(move |info| (format!("Turn {i}"), info))
+            })
+            .chain(self.branches.iter().flat_map(|b| {
+                b.turns.iter().enumerate().flat_map(move |(i, td)| {
+                    td.images.iter().map(move |info| {
+                        let turn = b.forked_after + 1 + i;
+                        (format!("Turn {turn} of branch {:?}", b.name), info)
+                    })
+                })
+            }))
+            .chain(in_flight_image.map(|info| ("The turn in flight".to_string(), info)));
+        for (owner, info) in images {
+            for id in [Some(info.id), info.original_id].into_iter().flatten() {
+                if id >= n_images {
+                    problems.push(format!(
+                        "{owner} shows image {id}, but the save has {n_images} images"
+                    ));
+                }
+            }
+        }
+        for (i, td) in self.turn_data.iter().enumerate() {
+            if let Some(summary) = td.summary_before_input
+                && summary >= self.summaries.len()
+            {
+                problems.push(format!(
+                    "Turn {i} follows summary {summary}, which doesn't exist"
+                ));
+            }
+        }
+        for &turn in &self.snapshots {
+            if turn >= self.turn_data.len() {
+                problems.push(format!(
+                    "There is a snapshot after turn {turn}, which doesn't exist"
+                ));
+            }
+        }
+        for branch in &self.branches {
+            if branch.forked_after >= self.turn_data.len() {
+                problems.push(format!(
+                    "Branch {:?} was forked after turn {}, which doesn't exist",
+                    branch.name, branch.forked_after
+                ));
+            }
+        }
+        ensure!(problems.is_empty(), "{}", problems.join("\n"));
+        Ok(())
     }
 }
 
@@ -686,6 +1410,8 @@ pub struct Summary {
     pub content: String,
     /// the turn after which it was created
     pub bday: usize,
+    #[serde(default)]
+    pub level: SummaryLevel,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -693,13 +1419,77 @@ pub struct TurnData {
     pub summary_before_input: Option<usize>,
     pub input: TurnInput,
     pub output: TurnOutput,
+    /// the last one is shown, see [`TurnData::select_image`]
     pub images: Vec<StoredImageInfo>,
+    /// marks an important moment of the story, see [`GameData::bookmarks`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bookmarked: bool,
+    /// shown instead of the image caption in the list of bookmarks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bookmark_label: Option<String>,
+    /// the world state after the turn, it's restored when the turns after it are removed.
+    /// `None` for turns from before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub world_state: Option<WorldState>,
+}
+
+impl TurnData {
+    /// Makes the image with the given archive id the one that is shown
+    pub fn select_image(&mut self, id: usize) -> Result<()> {
+        let idx = self
+            .images
+            .iter()
+            .position(|info| info.id == id)
+            .ok_or_else(|| eyre!("The turn has no image {id}"))?;
+        let info = self.images.remove(idx);
+        self.images.push(info);
+        Ok(())
+    }
+
+    /// The label of the bookmark, or the image caption if it has none. May be empty.
+    pub fn bookmark_title(&self) -> &str {
+        self.bookmark_label
+            .as_deref()
+            .unwrap_or(&self.output.image_caption)
+    }
+
+    /// Adds an image without replacing the one that is shown
+    pub fn add_image_variant(&mut self, info: StoredImageInfo) {
+        let idx = self.images.len().saturating_sub(1);
+        self.images.insert(idx, info);
+    }
+}
+
+/// What was received of a turn that isn't complete yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InFlightTurn {
+    pub input: TurnInput,
+    /// the visible output text received so far
+    pub partial_text: String,
+    /// the image is stored in the archive as soon as it's received
+    pub image: Option<StoredImageInfo>,
+}
+
+impl InFlightTurn {
+    pub fn new(input: TurnInput) -> Self {
+        Self {
+            input,
+            partial_text: String::new(),
+            image: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoredImageInfo {
     pub id: usize,
     pub caption: String,
+    /// the image as it was received, if it was stored in addition to the downscaled one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_id: Option<usize>,
+    /// to make a similar image, `None` if the model has no seeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -707,7 +1497,15 @@ pub struct Image {
     pub caption: String,
     pub description: String,
     pub cost: Option<f64>,
-    pub jpeg_bytes: Vec<u8>,
+    /// as the model sent it, in whatever format. Empty if the image is `cached`.
+    pub bytes: Vec<u8>,
+    pub seed: Option<u64>,
+    /// where the image is remembered in [`GameData::image_cache`], once it's stored
+    pub cache_key: Option<u64>,
+    /// the image is already in the archive
+    pub cached: Option<CachedImage>,
+    /// the LLM answer with a tamer description, if the first one was moderated
+    pub rewrite: Option<Box<OutputMessage>>,
 }
 
 #[derive(Debug, Clone)]
@@ -719,6 +1517,140 @@ pub struct ImageDescription {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::{self, LLMStream};
+
+    #[tokio::test]
+    async fn cancelling_ends_the_stream() {
+        let cancel = CancellationToken::new();
+        let stream = cancellable(
+            tokio_stream::iter([Ok("a".to_string())]).chain(tokio_stream::pending()),
+            cancel.clone(),
+        );
+        pin!(stream);
+        assert_eq!(stream.next().await.unwrap().unwrap(), "a");
+
+        cancel.cancel();
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(EngineError::from(err), EngineError::Cancelled));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingImageModel {
+        /// the descriptions that were asked for, with their seeds
+        requests: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl ImageModel for RecordingImageModel {
+        fn get_image<'a>(
+            &'a self,
+            description: &'a str,
+            seed: Option<u64>,
+            _progress: ProgressReporter,
+        ) -> Pin<Box<dyn Future<Output = Result<image_model::Image>> + Send + 'a>> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(format!("{description} / {seed:?}"));
+            Box::pin(async move {
+                Ok(image_model::Image {
+                    data: vec![1],
+                    cost: Some(0.01),
+                    seed: Some(seed.unwrap_or(1)),
+                })
+            })
+        }
+
+        fn download<'a>(
+            &'a self,
+            _url: &'a str,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+            Box::pin(async { Err(eyre!("nothing to download")) })
+        }
+
+        fn clone(&self) -> ImgModBox {
+            Box::new(Clone::clone(self))
+        }
+
+        fn provided_model(&self) -> image_model::ProvidedModel {
+            image_model::ProvidedModel::Flux2BLF
+        }
+    }
+
+    struct NoLLM;
+
+    impl LLM for NoLLM {
+        fn send_request_stream(&mut self, _req: Request) -> LLMStream<'_> {
+            Box::pin(tokio_stream::iter([Err(eyre!("no LLM in this test"))]))
+        }
+
+        fn clone(&self) -> LLMBox {
+            Box::new(NoLLM)
+        }
+
+        fn set_retry_policy(&mut self, _: llm::RetryPolicy) {}
+
+        fn provider(&self) -> llm::ModelProvider {
+            llm::ModelProvider::ANTHROPIC
+        }
+
+        fn set_timeouts(&mut self, _: llm::Timeouts) {}
+    }
+
+    #[test]
+    fn undoing_removes_the_last_turn_and_its_images() -> Result<(), EngineError> {
+        let tmpfile = tempfile::NamedTempFile::new()?;
+        let mut save =
+            ArchiveWorker::spawn(crate::save_archive::SaveArchive::create(tmpfile.path())?);
+        for i in 0..3 {
+            save.append_image(vec![i])?;
+        }
+        let data = crate::save_archive::tests::make_sample_game_data(3);
+        save.write_game_data(Arc::new(data.clone()))?;
+        let mut game = Game::load(Box::new(NoLLM), None, data, None);
+
+        game.undo_last_turn(&mut save)?;
+        assert_eq!(game.data.turn_data.len(), 2);
+        assert_eq!(save.read_game_data()?.turn_data.len(), 2);
+        assert_eq!(save.append_image(vec![9])?, 2);
+
+        game.undo_last_turn(&mut save)?;
+        assert!(game.undo_last_turn(&mut save).is_err());
+        assert_eq!(game.data.turn_data.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restyled_images_keep_the_seed() {
+        let mut data = crate::save_archive::tests::make_sample_game_data(3);
+        data.turn_data[0].images[0].seed = Some(7);
+        data.turn_data[1].images.clear();
+        let imgmod = RecordingImageModel::default();
+        let requests = imgmod.requests.clone();
+        let style = ModelStyle {
+            prefix: "watercolor".into(),
+            postfix: "soft light".into(),
+        };
+        let game = Game::load(Box::new(NoLLM), Some(Box::new(imgmod)), data, Some(style));
+
+        let mut turns = vec![];
+        for (turn, image) in game.restyle_images().unwrap() {
+            turns.push(turn);
+            assert_eq!(
+                image.await.unwrap().caption,
+                format!("image_description {turn}")
+            );
+        }
+        assert_eq!(turns, vec![0, 2]);
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests[..],
+            [
+                "watercolor image_description 0 soft light / Some(7)",
+                "watercolor image_description 2 soft light / None"
+            ]
+        );
+    }
 
     #[test]
     fn parses_streamed_image_description_prefix() {
@@ -752,38 +1684,313 @@ Night Watch
     #[test]
     fn request_context_starts_at_beginning_without_summary() {
         let data = GameData {
+            schema_version: migration::CURRENT_SCHEMA_VERSION,
             world_description: WorldDescription {
                 name: String::new(),
                 main_description: String::new(),
                 pc_descriptions: BTreeMap::new(),
                 init_action: String::new(),
+                sampling: Sampling::default(),
+                target_output_words: None,
+                max_tokens: None,
+                system_prompt_template: None,
+                summary_prompt: None,
+                history_turns: None,
+                preferred_llm: None,
+                preferred_image_model: None,
+                text_only: false,
+                content_rating: None,
+                tone: Tone::default(),
+                locations: vec![],
+                factions: vec![],
+                npcs: vec![],
+                script: None,
+                cover: None,
             },
             pc: String::new(),
             summaries: vec![],
             turn_data: vec![],
+            costs: CostTracker::default(),
+            in_flight_turn: None,
+            image_cache: ImageCache::default(),
+            text_only: false,
+            difficulty: Difficulty::default(),
+            pinned_seeds: BTreeMap::new(),
+            snapshots: vec![],
+            codex: Codex::default(),
+            world_state: WorldState::new(),
+            branch: String::new(),
+            branches: vec![],
+            recap: None,
         };
 
-        assert_eq!(data.request_context_start(), 0);
+        assert_eq!(data.request_context_start(DEFAULT_HISTORY_TURNS), 0);
     }
 
     #[test]
     fn request_context_keeps_two_turns_before_latest_summary() {
         let data = GameData {
+            schema_version: migration::CURRENT_SCHEMA_VERSION,
             world_description: WorldDescription {
                 name: String::new(),
                 main_description: String::new(),
                 pc_descriptions: BTreeMap::new(),
                 init_action: String::new(),
+                sampling: Sampling::default(),
+                target_output_words: None,
+                max_tokens: None,
+                system_prompt_template: None,
+                summary_prompt: None,
+                history_turns: None,
+                preferred_llm: None,
+                preferred_image_model: None,
+                text_only: false,
+                content_rating: None,
+                tone: Tone::default(),
+                locations: vec![],
+                factions: vec![],
+                npcs: vec![],
+                script: None,
+                cover: None,
             },
             pc: String::new(),
             summaries: vec![Summary {
                 content: String::new(),
                 bday: 9,
+                level: SummaryLevel::Chapter,
             }],
             turn_data: vec![],
+            costs: CostTracker::default(),
+            in_flight_turn: None,
+            image_cache: ImageCache::default(),
+            text_only: false,
+            difficulty: Difficulty::default(),
+            pinned_seeds: BTreeMap::new(),
+            snapshots: vec![],
+            codex: Codex::default(),
+            world_state: WorldState::new(),
+            branch: String::new(),
+            branches: vec![],
+            recap: None,
+        };
+
+        assert_eq!(data.request_context_start(DEFAULT_HISTORY_TURNS), 8);
+        assert_eq!(data.request_context_start(5), 5);
+        assert_eq!(data.request_context_start(20), 0);
+    }
+
+    #[test]
+    fn stats_survive_a_round_trip_through_text() {
+        let stats = PcDescription::parse_stats("Stealth: +2\n\n Strength:-1 \n").unwrap();
+        assert_eq!(
+            stats,
+            BTreeMap::from([("Stealth".into(), 2), ("Strength".into(), -1)])
+        );
+        let pc = PcDescription {
+            description: String::new(),
+            initial_action: String::new(),
+            stats,
+        };
+        assert_eq!(pc.stats_text(), "Stealth: +2\nStrength: -1");
+        assert!(PcDescription::parse_stats("Stealth").is_err());
+        assert!(PcDescription::parse_stats("Stealth: high").is_err());
+    }
+
+    #[test]
+    fn request_uses_the_output_length_of_the_world() {
+        let mut data = GameData {
+            schema_version: migration::CURRENT_SCHEMA_VERSION,
+            world_description: WorldDescription {
+                name: String::new(),
+                main_description: String::new(),
+                pc_descriptions: BTreeMap::from([(
+                    "Ann".into(),
+                    PcDescription {
+                        description: String::new(),
+                        initial_action: String::new(),
+                        stats: BTreeMap::new(),
+                    },
+                )]),
+                init_action: String::new(),
+                sampling: Sampling::default(),
+                target_output_words: None,
+                max_tokens: None,
+                system_prompt_template: None,
+                summary_prompt: None,
+                history_turns: None,
+                preferred_llm: None,
+                preferred_image_model: None,
+                text_only: false,
+                content_rating: None,
+                tone: Tone::default(),
+                locations: vec![],
+                factions: vec![],
+                npcs: vec![],
+                script: None,
+                cover: None,
+            },
+            pc: "Ann".into(),
+            summaries: vec![],
+            turn_data: vec![],
+            costs: CostTracker::default(),
+            in_flight_turn: None,
+            image_cache: ImageCache::default(),
+            text_only: false,
+            difficulty: Difficulty::default(),
+            pinned_seeds: BTreeMap::new(),
+            snapshots: vec![],
+            codex: Codex::default(),
+            world_state: WorldState::new(),
+            branch: String::new(),
+            branches: vec![],
+            recap: None,
+        };
+        let req = data.construct_request(&TurnInput::default(), "", DEFAULT_HISTORY_TURNS);
+        assert_eq!(req.max_tokens, DEFAULT_MAX_TOKENS);
+        assert!(req.system.unwrap().contains("about 1000 words"));
+
+        data.world_description.target_output_words = Some(250);
+        data.world_description.max_tokens = Some(1500);
+        let req = data.construct_request(&TurnInput::default(), "", DEFAULT_HISTORY_TURNS);
+        assert_eq!(req.max_tokens, 1500);
+        assert!(req.system.unwrap().contains("about 250 words"));
+    }
+
+    #[test]
+    fn variants_dont_replace_the_selected_image() {
+        let info = |id| StoredImageInfo {
+            id,
+            caption: String::new(),
+            original_id: None,
+            seed: None,
+        };
+        let mut turn = TurnData {
+            summary_before_input: None,
+            input: TurnInput::default(),
+            output: TurnOutput::from_parts(
+                String::new(),
+                String::new(),
+                String::new(),
+                None,
+                vec![],
+                0,
+                0,
+            ),
+            images: vec![],
+            bookmarked: false,
+            bookmark_label: None,
+            world_state: None,
         };
+        let ids = |turn: &TurnData| turn.images.iter().map(|i| i.id).collect::<Vec<_>>();
 
-        assert_eq!(data.request_context_start(), 8);
+        turn.add_image_variant(info(1));
+        turn.add_image_variant(info(2));
+        assert_eq!(ids(&turn), [2, 1]);
+
+        turn.select_image(2).unwrap();
+        assert_eq!(ids(&turn), [1, 2]);
+        turn.add_image_variant(info(3));
+        assert_eq!(ids(&turn), [1, 3, 2]);
+        assert!(turn.select_image(4).is_err());
+    }
+
+    #[test]
+    fn validation_finds_broken_references() {
+        let mut data = crate::save_archive::tests::make_sample_game_data(10);
+        assert!(data.validate(10).is_ok());
+
+        data.pc = "Bob".into();
+        data.turn_data[9].summary_before_input = Some(3);
+        data.snapshots.push(10);
+        let problems = data.validate(9).unwrap_err().to_string();
+        assert_eq!(problems.lines().count(), 4, "{problems}");
+        assert!(problems.contains("Turn 9 shows image 9"));
+    }
+
+    #[test]
+    fn clipping_drops_what_later_turns_added() {
+        let mut data = crate::save_archive::tests::make_sample_game_data(10);
+        data.snapshots = vec![4, 8];
+        data.clip_after_turn(8);
+        assert_eq!(data.summaries.len(), 1);
+        data.clip_after_turn(5);
+        assert_eq!(data.turn_data.len(), 6);
+        assert_eq!(data.snapshots, [4]);
+        assert!(data.summaries.is_empty());
+        assert!(data.validate(10).is_ok());
+    }
+
+    #[test]
+    fn clipping_restores_the_world_state() {
+        let mut data = crate::save_archive::tests::make_sample_game_data(3);
+        let state = |facts: &[(&str, &str)]| {
+            facts
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<WorldState>()
+        };
+        data.turn_data[1].world_state = Some(state(&[("bridge", "intact")]));
+        data.set_world_state(state(&[("bridge", "destroyed")]));
+        assert_eq!(
+            data.turn_data[2].world_state,
+            Some(data.world_state.clone())
+        );
+
+        data.clip_after_turn(1);
+        assert_eq!(data.world_state, state(&[("bridge", "intact")]));
+        // turns from before the state was recorded keep it as it is
+        data.clip_after_turn(0);
+        assert_eq!(data.world_state, state(&[("bridge", "intact")]));
+    }
+
+    #[test]
+    fn bookmarks_use_the_caption_without_label() {
+        let mut data = crate::save_archive::tests::make_sample_game_data(5);
+        data.set_bookmark(1, Some("  ".into())).unwrap();
+        data.set_bookmark(3, Some(" The duel ".into())).unwrap();
+        data.set_bookmark(4, Some("Gone".into())).unwrap();
+        data.set_bookmark(4, None).unwrap();
+        assert!(data.set_bookmark(5, None).is_err());
+        assert_eq!(
+            data.bookmarks().collect::<Vec<_>>(),
+            [
+                (1, data.turn_data[1].output.image_caption.as_str()),
+                (3, "The duel")
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_templates_get_the_rating_rule() {
+        let mut world = crate::save_archive::tests::make_sample_game_data(1).world_description;
+        world.system_prompt_template = Some("You narrate for {player}.".into());
+        world.content_rating = Some(ContentRating::Family);
+        let vars = PromptVariables::new("Kara", "a thief", "a city", "", 300, "", 0);
+        let prompt = world.render_system_prompt(&vars);
+        assert!(prompt.starts_with("You narrate for Kara."));
+        assert!(prompt.contains(ContentRating::Family.prompt_rule()));
+    }
+
+    #[test]
+    fn the_longest_pinned_name_wins() {
+        let pinned = BTreeMap::from([
+            ("Fox".to_string(), 1),
+            ("Red Fox Inn".to_string(), 2),
+            ("Kara".to_string(), 3),
+        ]);
+        let description = |description: &str, caption: &str| ImageDescription {
+            description: description.into(),
+            caption: caption.into(),
+        };
+        assert_eq!(
+            pinned_seed(&pinned, &description("The taproom of the red fox inn", "")),
+            Some(2)
+        );
+        assert_eq!(
+            pinned_seed(&pinned, &description("a woman in a cloak", "Kara")),
+            Some(3)
+        );
+        assert_eq!(pinned_seed(&pinned, &description("a harbor", "Dawn")), None);
     }
 }
 
@@ -815,10 +2022,128 @@ pub struct WorldDescription {
     pub main_description: String,
     pub pc_descriptions: BTreeMap<String, PcDescription>,
     pub init_action: String,
+    /// how wild the narration gets
+    #[serde(default, skip_serializing_if = "Sampling::is_default")]
+    pub sampling: Sampling,
+    /// how long the visible story text of a turn should be
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_output_words: Option<usize>,
+    /// the limit for the whole reply of the LLM, including the image description and the
+    /// secret info
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    /// replaces `system_prompt::DEFAULT_TEMPLATE`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt_template: Option<String>,
+    /// replaces `summary::DEFAULT_PROMPT`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary_prompt: Option<String>,
+    /// replaces the number of verbatim turns from the options, see
+    /// [`GameData::construct_request`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_turns: Option<usize>,
+    /// used instead of the LLM that is selected in the options, for games of this world
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_llm: Option<ProvidedModel>,
+    /// used instead of the image model that is selected in the options
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_image_model: Option<image_model::ProvidedModel>,
+    /// new games of this world are text-only, see [`GameData::text_only`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub text_only: bool,
+    /// `None` if the world wasn't rated, then neither the prompt nor the image model are
+    /// restricted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_rating: Option<ContentRating>,
+    /// how the stories should feel, see [`tone`]
+    #[serde(default, skip_serializing_if = "Tone::is_neutral")]
+    pub tone: Tone,
+    /// the important places, see [`world_details`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<WorldDetail>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub factions: Vec<WorldDetail>,
+    /// the characters besides the player character that matter from the start
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub npcs: Vec<WorldDetail>,
+    /// Rhai source with hooks for custom mechanics, see [`script`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover: Option<Cover>,
+}
+
+impl WorldDescription {
+    pub fn target_output_words(&self) -> usize {
+        self.target_output_words
+            .unwrap_or(DEFAULT_TARGET_OUTPUT_WORDS)
+    }
+
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS)
+    }
+
+    pub fn summary_prompt(&self) -> &str {
+        self.summary_prompt.as_deref().unwrap_or(summary::DEFAULT_PROMPT)
+    }
+
+    /// Uses the default template if the world's template is broken, since the game can't
+    /// continue without a system message. Templates are validated when a world is saved,
+    /// so this only happens to worlds that were edited by hand.
+    pub fn render_system_prompt(&self, vars: &PromptVariables) -> String {
+        let mut prompt = self.render_template(vars);
+        // after the template, so custom templates can't leave it out
+        if let Some(rating) = self.content_rating {
+            prompt += &format!("\n{}\n", rating.prompt_rule());
+        }
+        prompt
+    }
+
+    fn render_template(&self, vars: &PromptVariables) -> String {
+        if let Some(template) = &self.system_prompt_template {
+            match system_prompt::render(template, vars) {
+                Ok(prompt) => return prompt,
+                Err(e) => error!("Using the default prompt template: {e:#}"),
+            }
+        }
+        system_prompt::render(system_prompt::DEFAULT_TEMPLATE, vars)
+            .expect("the default prompt template is invalid")
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PcDescription {
     pub description: String,
     pub initial_action: String,
+    /// modifiers for skill checks by name, e.g. `Stealth: +2`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub stats: BTreeMap<String, i32>,
+}
+
+impl PcDescription {
+    /// One `name: modifier` line per stat, the format [`PcDescription::parse_stats`] reads
+    pub fn stats_text(&self) -> String {
+        self.stats
+            .iter()
+            .map(|(name, modifier)| format!("{name}: {modifier:+}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Reads one `name: modifier` line per stat, empty lines are skipped
+    pub fn parse_stats(src: &str) -> Result<BTreeMap<String, i32>> {
+        src.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let (name, modifier) = line
+                    .split_once(':')
+                    .ok_or_else(|| eyre!("Invalid stat: {line:?}, expected e.g. Stealth: +2"))?;
+                let modifier = modifier
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid modifier of {}", name.trim()))?;
+                Ok((name.trim().to_string(), modifier))
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,240 @@
+//! Branches keep the turns a game didn't continue with, so the story can return to them.
+//! The turns of [`GameData`] are the current branch. Every other branch shares the turns up to
+//! the one it was forked after with it, and holds the turns that followed there instead.
+//! Switching to a branch exchanges the turns after its fork, and the world state, which
+//! starts as it was after the fork turn in a new branch. A branch that was forked from
+//! turns the current branch doesn't have anymore gets a copy of them, so it stays complete.
+
+use std::{collections::BTreeMap, mem};
+
+use color_eyre::{
+    Result,
+    eyre::{bail, ensure},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{Codex, GameData, Summary, TurnData, WorldState};
+
+/// The name of the branch a game starts with
+pub const MAIN: &str = "Main";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Branch {
+    pub name: String,
+    /// the last turn it shares with the current branch
+    pub forked_after: usize,
+    /// the turns after `forked_after`
+    pub turns: Vec<TurnData>,
+    /// the summaries after the ones it shares with the current branch
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub summaries: Vec<Summary>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<usize>,
+    /// as it was after its last turn
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub world_state: WorldState,
+}
+
+impl Branch {
+    pub fn last_turn(&self) -> usize {
+        self.forked_after + self.turns.len()
+    }
+}
+
+impl GameData {
+    pub fn branch_name(&self) -> &str {
+        if self.branch.is_empty() {
+            MAIN
+        } else {
+            &self.branch
+        }
+    }
+
+    /// The turns of the current branch, followed by the ones of the other branches
+    pub fn all_turns(&self) -> impl Iterator<Item = &TurnData> {
+        self.turn_data
+            .iter()
+            .chain(self.branches.iter().flat_map(|b| &b.turns))
+    }
+
+    /// Continues the game after `turn` in a new branch called `name`. The turns after it are
+    /// kept in a branch with the name of the current one.
+    pub fn fork_after(&mut self, turn: usize, name: &str) -> Result<()> {
+        ensure!(
+            turn + 1 < self.turn_data.len(),
+            "There are no turns after turn {} to keep",
+            turn + 1
+        );
+        let name = self.check_new_branch_name(name)?;
+        let kept = self.split_off_branch(turn);
+        self.branches.push(kept);
+        self.branch = name;
+        self.codex = Codex::replay(&self.turn_data);
+        Ok(())
+    }
+
+    /// Continues the game with the branch at `index`. The turns of the current branch after
+    /// its fork are kept in a branch, unless there are none.
+    pub fn switch_branch(&mut self, index: usize) -> Result<()> {
+        ensure!(
+            self.in_flight_turn.is_none(),
+            "The branch can't be switched while a turn is generated"
+        );
+        ensure!(index < self.branches.len(), "There is no branch {index}");
+        let branch = self.branches.remove(index);
+        let kept = self.split_off_branch(branch.forked_after);
+        if !kept.turns.is_empty() {
+            self.branches.push(kept);
+        }
+        self.branch = branch.name;
+        self.turn_data.extend(branch.turns);
+        self.summaries.extend(branch.summaries);
+        self.snapshots.extend(branch.snapshots);
+        self.world_state = branch.world_state;
+        self.codex = Codex::replay(&self.turn_data);
+        Ok(())
+    }
+
+    /// Removes the turns after `turn` from the current branch, and returns them as a branch
+    /// with its name. Branches that were forked from the removed turns get a copy of the ones
+    /// they share.
+    pub(super) fn split_off_branch(&mut self, turn: usize) -> Branch {
+        let shared_summaries = |td: &TurnData| td.summary_before_input.map_or(0, |i| i + 1);
+        let kept_summaries = shared_summaries(&self.turn_data[turn]);
+        for branch in self.branches.iter_mut().filter(|b| b.forked_after > turn) {
+            let forked_after = branch.forked_after;
+            let summaries = kept_summaries..shared_summaries(&self.turn_data[forked_after]);
+            branch.turns.splice(
+                0..0,
+                self.turn_data[turn + 1..=forked_after].iter().cloned(),
+            );
+            branch
+                .summaries
+                .splice(0..0, self.summaries[summaries].iter().cloned());
+            branch.snapshots.extend(
+                self.snapshots
+                    .iter()
+                    .filter(|&&t| t > turn && t <= forked_after),
+            );
+            branch.snapshots.sort_unstable();
+            branch.forked_after = turn;
+        }
+        // it tells of turns that are gone
+        self.recap.take_if(|recap| recap.turns > turn + 1);
+        let (snapshots, later_snapshots): (Vec<_>, Vec<_>) = mem::take(&mut self.snapshots)
+            .into_iter()
+            .partition(|&t| t <= turn);
+        self.snapshots = snapshots;
+        let world_state = match &self.turn_data[turn].world_state {
+            Some(state) => mem::replace(&mut self.world_state, state.clone()),
+            // turns from before the state was recorded
+            None => self.world_state.clone(),
+        };
+        Branch {
+            name: self.branch_name().into(),
+            forked_after: turn,
+            turns: self.turn_data.split_off(turn + 1),
+            summaries: self.summaries.split_off(kept_summaries),
+            snapshots: later_snapshots,
+            world_state,
+        }
+    }
+
+    /// A name for a branch that continues after `turn`, which no other branch has. Going back
+    /// to a past turn continues in a branch with such a name, so the later turns are kept.
+    pub fn unused_branch_name(&self, turn: usize) -> String {
+        let base = format!("From turn {}", turn + 1);
+        std::iter::once(base.clone())
+            .chain((2..).map(|n| format!("{base} ({n})")))
+            .find(|name| self.check_new_branch_name(name).is_ok())
+            .expect("there are more names than branches")
+    }
+
+    fn check_new_branch_name(&self, name: &str) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() {
+            bail!("The name of the branch is empty");
+        }
+        let taken = std::iter::once(self.branch_name())
+            .chain(self.branches.iter().map(|b| b.name.as_str()))
+            .any(|other| other.eq_ignore_ascii_case(name));
+        ensure!(!taken, "There already is a branch called {name:?}");
+        Ok(name.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_archive::tests::make_sample_game_data;
+
+    fn actions(turns: &[TurnData]) -> Vec<&str> {
+        turns
+            .iter()
+            .map(|td| td.input.player_action.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn switching_back_restores_the_turns() {
+        let mut data = make_sample_game_data(10);
+        data.fork_after(3, "Other door").unwrap();
+        assert_eq!(data.turn_data.len(), 4);
+        assert_eq!(data.branch_name(), "Other door");
+        assert_eq!(data.branches[0].name, MAIN);
+        assert_eq!(data.branches[0].last_turn(), 9);
+        assert!(data.fork_after(1, "main").is_err());
+
+        let mut other = data.turn_data[3].clone();
+        other.input.player_action = "Open the other door".into();
+        data.turn_data.push(other);
+        data.switch_branch(0).unwrap();
+        assert_eq!(data.turn_data.len(), 10);
+        assert_eq!(data.summaries.len(), 1);
+        assert_eq!(data.branches[0].name, "Other door");
+        assert_eq!(actions(&data.branches[0].turns), ["Open the other door"]);
+        assert!(data.validate(10).is_ok());
+    }
+
+    #[test]
+    fn new_branches_start_with_the_world_state_of_their_fork() {
+        let mut data = make_sample_game_data(5);
+        let before = WorldState::from([("door".to_string(), "closed".to_string())]);
+        let after = WorldState::from([("door".to_string(), "opened".to_string())]);
+        data.turn_data[2].world_state = Some(before.clone());
+        data.set_world_state(after.clone());
+
+        data.fork_after(2, "Other door").unwrap();
+        assert_eq!(data.world_state, before);
+        assert_eq!(data.branches[0].world_state, after);
+        data.switch_branch(0).unwrap();
+        assert_eq!(data.world_state, after);
+    }
+
+    #[test]
+    fn branches_keep_the_turns_they_were_forked_from() {
+        let mut data = make_sample_game_data(10);
+        data.fork_after(6, "Late").unwrap();
+        data.clip_after_turn(2);
+        assert_eq!(data.turn_data.len(), 3);
+        let main = &data.branches[0];
+        assert_eq!((main.name.as_str(), main.forked_after), (MAIN, 2));
+        assert_eq!(main.last_turn(), 9);
+        assert_eq!(main.turns[0].input.player_action, "Do action 3");
+
+        data.switch_branch(0).unwrap();
+        assert_eq!(data.turn_data.len(), 10);
+        assert_eq!(data.summaries.len(), 1);
+        assert!(data.branches.is_empty());
+        assert!(data.validate(10).is_ok());
+    }
+
+    #[test]
+    fn generated_branch_names_are_unused() {
+        let mut data = make_sample_game_data(10);
+        let name = data.unused_branch_name(3);
+        assert_eq!(name, "From turn 4");
+        data.fork_after(3, &name).unwrap();
+        assert_eq!(data.unused_branch_name(3), "From turn 4 (2)");
+    }
+}
@@ -0,0 +1,74 @@
+//! The in-game time. The LLM reports how many minutes passed in a turn in the time section of
+//! its answer, and the time of a turn is the sum of all turns up to it, so it never has to be
+//! repaired when turns are removed.
+
+use std::fmt;
+
+use log::warn;
+
+use super::TurnData;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+/// Stories start in the morning of the first day
+const START: InGameTime = InGameTime(8 * 60);
+
+/// Minutes since midnight before the first day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct InGameTime(u64);
+
+impl InGameTime {
+    /// The time after the given turns
+    pub fn after(turns: &[TurnData]) -> Self {
+        let elapsed = turns
+            .iter()
+            .filter_map(|t| t.output.elapsed_minutes)
+            .map(u64::from)
+            .sum::<u64>();
+        Self(START.0 + elapsed)
+    }
+
+    /// counted from 1
+    pub fn day(self) -> u64 {
+        self.0 / MINUTES_PER_DAY + 1
+    }
+}
+
+impl fmt::Display for InGameTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes = self.0 % MINUTES_PER_DAY;
+        let (hours, minutes) = (minutes / 60, minutes % 60);
+        write!(f, "Day {}, {hours:02}:{minutes:02}", self.day())
+    }
+}
+
+/// Reads the time section of an answer, which should be a number of minutes. Models tend to
+/// add a unit anyway, so only the leading number counts. A missing number isn't worth
+/// failing the turn for, it's logged and the clock stands still.
+pub(super) fn parse_elapsed(src: &str) -> Option<u32> {
+    let src = src.trim();
+    let digits = src.find(|c: char| !c.is_ascii_digit()).unwrap_or(src.len());
+    let elapsed = src[..digits].parse().ok();
+    if elapsed.is_none() {
+        warn!("Ignoring the time section, it doesn't start with a number: {src}");
+    }
+    elapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_day_and_time() {
+        assert_eq!(START.to_string(), "Day 1, 08:00");
+        let later = InGameTime(START.0 + 17 * 60 + 5);
+        assert_eq!(later.to_string(), "Day 2, 01:05");
+    }
+
+    #[test]
+    fn parses_leading_minutes() {
+        assert_eq!(parse_elapsed(" 90\n"), Some(90));
+        assert_eq!(parse_elapsed("45 minutes"), Some(45));
+        assert_eq!(parse_elapsed("a while"), None);
+    }
+}
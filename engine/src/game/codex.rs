@@ -0,0 +1,176 @@
+//! The codex is a registry of the characters, locations and factions of a game. The LLM
+//! writes what it introduced or changed in a turn as JSON into the codex section of its
+//! answer, and the current entries are part of the system prompt, so they aren't forgotten
+//! when the turns that introduced them are summarized.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+use super::TurnData;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display, Serialize, Deserialize, EnumIter,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Character,
+    Location,
+    Faction,
+}
+
+/// What the LLM writes about an entry in a turn
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodexUpdate {
+    pub kind: EntryKind,
+    pub name: String,
+    /// the full description, not just what changed. Empty if it didn't change.
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodexEntry {
+    pub kind: EntryKind,
+    pub name: String,
+    pub description: String,
+    /// the turn that introduced it
+    pub first_turn: usize,
+    /// the turn that updated it last
+    pub last_turn: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Codex {
+    /// sorted by kind and name
+    entries: Vec<CodexEntry>,
+}
+
+impl Codex {
+    /// The codex as it was after the given turns
+    pub fn replay(turns: &[TurnData]) -> Self {
+        let mut codex = Self::default();
+        for (turn, turn_data) in turns.iter().enumerate() {
+            codex.apply(&turn_data.output.codex_updates, turn);
+        }
+        codex
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Sorted by kind and name
+    pub fn entries(&self) -> &[CodexEntry] {
+        &self.entries
+    }
+
+    /// Adds the entries that are new, and updates the known ones. Names are compared
+    /// case-insensitively, since the LLM isn't consistent about them.
+    pub fn apply(&mut self, updates: &[CodexUpdate], turn: usize) {
+        for update in updates {
+            let name = update.name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let description = update.description.trim();
+            let known = self
+                .entries
+                .iter_mut()
+                .find(|e| e.kind == update.kind && e.name.eq_ignore_ascii_case(name));
+            match known {
+                Some(entry) => {
+                    if !description.is_empty() {
+                        entry.description = description.into();
+                    }
+                    entry.last_turn = turn;
+                }
+                None => self.entries.push(CodexEntry {
+                    kind: update.kind,
+                    name: name.into(),
+                    description: description.into(),
+                    first_turn: turn,
+                    last_turn: turn,
+                }),
+            }
+        }
+        self.entries
+            .sort_by_cached_key(|e| (e.kind, e.name.to_lowercase()));
+    }
+
+    /// One line per entry, for the system prompt
+    pub(super) fn to_prompt(&self) -> String {
+        self.entries
+            .iter()
+            .map(|e| format!("- {} ({}): {}", e.name, e.kind, e.description))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Reads the codex section of an answer. A broken section isn't worth failing the turn, so
+/// it's logged and ignored.
+pub(super) fn parse_updates(src: &str) -> Vec<CodexUpdate> {
+    let src = src.trim();
+    // some models wrap JSON in a code block, no matter what they are told
+    let src = src
+        .strip_prefix("```json")
+        .or_else(|| src.strip_prefix("```"))
+        .and_then(|s| s.strip_suffix("```"))
+        .unwrap_or(src);
+    serde_json::from_str(src)
+        .inspect_err(|e| warn!("Ignoring the codex section, it's not valid: {e}\n{src}"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(kind: EntryKind, name: &str, description: &str) -> CodexUpdate {
+        CodexUpdate {
+            kind,
+            name: name.into(),
+            description: description.into(),
+        }
+    }
+
+    #[test]
+    fn updates_known_entries() {
+        let mut codex = Codex::default();
+        codex.apply(
+            &[
+                update(EntryKind::Location, "Harbor", "smells of fish"),
+                update(EntryKind::Character, "Mira", "a smuggler"),
+            ],
+            0,
+        );
+        codex.apply(
+            &[
+                update(EntryKind::Character, "mira", "a smuggler, owes you money"),
+                update(EntryKind::Location, "Harbor", ""),
+            ],
+            3,
+        );
+
+        let entries = codex.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "Mira");
+        assert_eq!(entries[0].description, "a smuggler, owes you money");
+        assert_eq!((entries[0].first_turn, entries[0].last_turn), (0, 3));
+        assert_eq!(entries[1].description, "smells of fish");
+    }
+
+    #[test]
+    fn parses_updates_in_code_blocks() {
+        let src = r#"
+```json
+[{"kind": "faction", "name": "Night Watch", "description": "guards the walls"}]
+```
+"#;
+        let expected = update(EntryKind::Faction, "Night Watch", "guards the walls");
+        assert_eq!(parse_updates(src), [expected]);
+        assert!(parse_updates("[]").is_empty());
+        assert!(parse_updates("none").is_empty());
+    }
+}
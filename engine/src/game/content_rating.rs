@@ -0,0 +1,84 @@
+//! How explicit the stories and images of a world may get. Shared worlds advertise it, and
+//! games hold the models to it: its rule is added to every system prompt, also to the ones
+//! of custom templates, and it caps the safety tolerance of the image model.
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+use crate::image_model::ProvidedModel;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display, Serialize, Deserialize, EnumIter,
+)]
+pub enum ContentRating {
+    Family,
+    Teen,
+    Mature,
+}
+
+impl ContentRating {
+    /// What players can expect, for the world list
+    pub fn description(self) -> &'static str {
+        match self {
+            ContentRating::Family => "suitable for all ages",
+            ContentRating::Teen => "some violence and mild language",
+            ContentRating::Mature => "violence, gore and adult themes",
+        }
+    }
+
+    pub fn prompt_rule(self) -> &'static str {
+        match self {
+            ContentRating::Family => {
+                "The story is rated for families. Keep the text and the image descriptions \
+                 suitable for children: no gore, no sexual content, no strong language, and \
+                 violence only without graphic details."
+            }
+            ContentRating::Teen => {
+                "The story is rated for teens. Violence and mild language are fine, but keep \
+                 out explicit sexual content and graphic gore, also from the image descriptions."
+            }
+            ContentRating::Mature => {
+                "The story is rated for adults. Mature themes, violence and strong language \
+                 are fine where the story calls for them."
+            }
+        }
+    }
+
+    /// The most permissive safety tolerance the rating allows for `model`, `None` if the
+    /// model has no such setting
+    pub fn max_safety_tolerance(self, model: ProvidedModel) -> Option<u8> {
+        let range = model.safety_tolerance_range()?;
+        let (strict, permissive) = (*range.start(), *range.end());
+        Some(match self {
+            ContentRating::Family => strict,
+            ContentRating::Teen => strict + (permissive - strict) / 2,
+            ContentRating::Mature => permissive,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    use super::*;
+
+    #[test]
+    fn stricter_ratings_cap_the_tolerance_lower() {
+        assert_eq!(
+            ContentRating::Teen.max_safety_tolerance(ProvidedModel::Flux2BLF),
+            Some(3)
+        );
+        assert_eq!(
+            ContentRating::Family.max_safety_tolerance(ProvidedModel::DallE3OpenAI),
+            None
+        );
+        for model in ProvidedModel::iter() {
+            let caps = ContentRating::iter()
+                .map(|r| r.max_safety_tolerance(model))
+                .collect::<Vec<_>>();
+            assert!(caps.is_sorted(), "{model}: {caps:?}");
+            assert_eq!(caps[2], model.safety_tolerance_range().map(|r| *r.end()));
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{collections::BTreeMap, ops::AddAssign};
+
+use serde::{Deserialize, Serialize};
+
+/// Costs by provider name, e.g. "Anthropic" or "Black Forest Labs"
+pub type ProviderCosts = BTreeMap<String, Cost>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cost {
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    pub images: usize,
+    /// only what the providers reported, which not all of them do
+    pub dollars: f64,
+}
+
+impl AddAssign for Cost {
+    fn add_assign(&mut self, rhs: Self) {
+        self.input_tokens += rhs.input_tokens;
+        self.output_tokens += rhs.output_tokens;
+        self.images += rhs.images;
+        self.dollars += rhs.dollars;
+    }
+}
+
+/// What each turn cost, by provider. A turn's costs include its image and the summary that
+/// was stored with it. Turns that were regenerated, or cancelled after their image arrived,
+/// add to the costs of the turn that replaced them, since the money was spent anyway.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CostTracker {
+    turns: BTreeMap<usize, ProviderCosts>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CostSummary {
+    pub total: ProviderCosts,
+    pub per_turn: BTreeMap<usize, ProviderCosts>,
+}
+
+impl CostTracker {
+    pub fn add(&mut self, turn: usize, provider: impl Into<String>, cost: Cost) {
+        if cost == Cost::default() {
+            return;
+        }
+        *self
+            .turns
+            .entry(turn)
+            .or_default()
+            .entry(provider.into())
+            .or_default() += cost;
+    }
+
+    pub fn turn(&self, turn: usize) -> Option<&ProviderCosts> {
+        self.turns.get(&turn)
+    }
+
+    pub fn total(&self) -> ProviderCosts {
+        let mut res = ProviderCosts::new();
+        for (provider, cost) in self.turns.values().flatten() {
+            *res.entry(provider.clone()).or_default() += *cost;
+        }
+        res
+    }
+
+    pub fn summary(&self) -> CostSummary {
+        CostSummary {
+            total: self.total(),
+            per_turn: self.turns.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn costs_add_up_per_provider() {
+        let mut tracker = CostTracker::default();
+        let tokens = |input_tokens, output_tokens| Cost {
+            input_tokens,
+            output_tokens,
+            ..Default::default()
+        };
+        let image = Cost {
+            images: 1,
+            dollars: 0.04,
+            ..Default::default()
+        };
+        tracker.add(0, "Anthropic", tokens(1000, 300));
+        tracker.add(0, "Black Forest Labs", image);
+        tracker.add(1, "Anthropic", tokens(1500, 200));
+        tracker.add(1, "Anthropic", tokens(800, 100));
+        tracker.add(1, "Black Forest Labs", image);
+        tracker.add(2, "Anthropic", Cost::default());
+
+        assert_eq!(tracker.turn(1).unwrap()["Anthropic"], tokens(2300, 300));
+        assert!(tracker.turn(2).is_none());
+        let total = tracker.total();
+        assert_eq!(total["Anthropic"], tokens(3300, 600));
+        assert_eq!(total["Black Forest Labs"].images, 2);
+        assert!((total["Black Forest Labs"].dollars - 0.08).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,43 @@
+//! How hard a game is. The difficulty is chosen when the game is started, it tells the
+//! narrator how often actions fail and how much failures cost, through the `difficulty`
+//! variable of the template, and it biases the dice of the [tools](crate::tools).
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Display, EnumIter)]
+#[serde(rename_all = "snake_case")]
+pub enum Difficulty {
+    Forgiving,
+    #[default]
+    Balanced,
+    Brutal,
+}
+
+impl Difficulty {
+    /// The instruction for the narrator, empty for `Balanced`
+    pub fn to_prompt(self) -> &'static str {
+        match self {
+            Difficulty::Forgiving => {
+                "Be forgiving: let reasonable actions succeed most of the time, and let \
+                 failures be setbacks that can be recovered from. Don't let the player \
+                 character die, unless the player insists."
+            }
+            Difficulty::Balanced => "",
+            Difficulty::Brutal => {
+                "Be brutal: risky actions fail often, and failures have lasting consequences, \
+                 like wounds, lost items or enemies that remember. Careless actions can get \
+                 the player character killed."
+            }
+        }
+    }
+
+    /// Added to every d20 that is rolled by the tools
+    pub fn roll_modifier(self) -> i64 {
+        match self {
+            Difficulty::Forgiving => 2,
+            Difficulty::Balanced => 0,
+            Difficulty::Brutal => -2,
+        }
+    }
+}
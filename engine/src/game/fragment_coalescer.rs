@@ -0,0 +1,119 @@
+//! LLMs stream tiny fragments, often hundreds per second. Each of them would cause a
+//! markdown reparse and relayout in the GUI, so they are collected and passed on in
+//! batches, either when a batch is large enough, or when its oldest fragment has
+//! waited long enough.
+
+use std::{mem, time::Duration};
+
+use async_stream::try_stream;
+use color_eyre::Result;
+use tokio::{
+    pin,
+    time::{Instant, timeout_at},
+};
+use tokio_stream::{Stream, StreamExt};
+
+pub const MAX_DELAY: Duration = Duration::from_millis(50);
+pub const MAX_BATCH_LEN: usize = 512;
+
+/// Concatenates consecutive fragments of `stream`. No fragment is held back longer than
+/// `max_delay`, and a batch is passed on as soon as it reaches `max_len` bytes.
+/// Text received before an error is passed on before the error.
+pub fn coalesce(
+    stream: impl Stream<Item = Result<String>> + Send,
+    max_delay: Duration,
+    max_len: usize,
+) -> impl Stream<Item = Result<String>> + Send {
+    try_stream! {
+        pin!(stream);
+        let mut batch = String::new();
+        let mut deadline = None;
+        loop {
+            let next = match deadline {
+                // `next` is cancel safe, so no fragment is lost on a timeout
+                Some(flush_at) => match timeout_at(flush_at, stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        deadline = None;
+                        yield mem::take(&mut batch);
+                        continue;
+                    }
+                },
+                None => stream.next().await,
+            };
+
+            let fragment = match next {
+                Some(Ok(fragment)) => fragment,
+                Some(Err(err)) => {
+                    if !batch.is_empty() {
+                        yield mem::take(&mut batch);
+                    }
+                    Err(err)?
+                }
+                None => break,
+            };
+
+            if batch.is_empty() {
+                deadline = Some(Instant::now() + max_delay);
+            }
+            batch.push_str(&fragment);
+            if batch.len() >= max_len {
+                yield mem::take(&mut batch);
+            }
+            if batch.is_empty() {
+                deadline = None;
+            }
+        }
+
+        if !batch.is_empty() {
+            yield batch;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    async fn collect(stream: impl Stream<Item = Result<String>> + Send) -> Vec<Result<String>> {
+        let stream = coalesce(stream, Duration::from_millis(20), 8);
+        pin!(stream);
+        let mut res = vec![];
+        while let Some(item) = stream.next().await {
+            res.push(item);
+        }
+        res
+    }
+
+    #[tokio::test]
+    async fn batches_by_length_and_time() {
+        let stream = try_stream! {
+            for fragment in ["a", "b", "cdefgh", "ij"] {
+                yield fragment.to_string();
+            }
+            sleep(Duration::from_millis(60)).await;
+            yield "k".to_string();
+        };
+        let batches: Vec<String> = collect(stream)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(batches, ["abcdefgh", "ij", "k"]);
+    }
+
+    #[tokio::test]
+    async fn text_before_an_error_is_kept() {
+        let stream = try_stream! {
+            yield "abc".to_string();
+            Err(eyre!("connection reset"))?;
+        };
+        let items = collect(stream).await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), "abc");
+        assert!(items[1].is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Images that were already made for the exact same prompt are taken from the archive
+//! instead of being paid for again. That happens when a turn is generated again, e.g. after
+//! loading the game from an earlier turn, and the LLM writes the same description.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::image_model::ProvidedModel;
+
+/// Where a cached image is in the archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedImage {
+    pub id: usize,
+    pub original_id: Option<usize>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImageCache {
+    entries: BTreeMap<u64, CachedImage>,
+}
+
+impl ImageCache {
+    /// The prompt includes the style, so a changed style makes a new image
+    pub fn key(model: ProvidedModel, prompt: &str) -> u64 {
+        // FNV-1a, since the keys are stored, and std's hasher may change between releases
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let model = format!("{model:?}");
+        for byte in model.bytes().chain([0]).chain(prompt.trim().bytes()) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        hash
+    }
+
+    pub fn get(&self, key: u64) -> Option<CachedImage> {
+        self.entries.get(&key).copied()
+    }
+
+    pub fn insert(&mut self, key: u64, image: CachedImage) {
+        self.entries.insert(key, image);
+    }
+
+    /// Forgets the images whose ids aren't below `n_images`, after the archive was clipped
+    pub fn retain_images_below(&mut self, n_images: usize) {
+        self.entries.retain(|_, image| {
+            image.id < n_images && image.original_id.is_none_or(|id| id < n_images)
+        });
+    }
+
+    /// Updates the ids after the archive was compacted. `new_ids` maps the old ids of the
+    /// kept images to their new ones, the images that aren't in it are forgotten.
+    pub fn remap_images(&mut self, new_ids: &BTreeMap<usize, usize>) {
+        self.entries.retain(|_, image| {
+            let Some(&id) = new_ids.get(&image.id) else {
+                return false;
+            };
+            let original_id = match image.original_id {
+                Some(old) => match new_ids.get(&old) {
+                    Some(&new) => Some(new),
+                    None => return false,
+                },
+                None => None,
+            };
+            image.id = id;
+            image.original_id = original_id;
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clipped_images_are_forgotten() {
+        let model = ProvidedModel::Flux2BLF;
+        let key = ImageCache::key(model, "a lighthouse at dusk");
+        assert_eq!(key, ImageCache::key(model, "a lighthouse at dusk\n"));
+        assert_ne!(
+            key,
+            ImageCache::key(ProvidedModel::Flux1Replicate, "a lighthouse at dusk")
+        );
+        assert_ne!(key, ImageCache::key(model, "a lighthouse at dawn"));
+
+        let mut cache = ImageCache::default();
+        let image = |id, original_id| CachedImage {
+            id,
+            original_id,
+            seed: None,
+        };
+        cache.insert(1, image(0, None));
+        cache.insert(2, image(1, Some(2)));
+        cache.insert(3, image(3, None));
+        cache.retain_images_below(2);
+        assert_eq!(cache.get(1), Some(image(0, None)));
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(3), None);
+    }
+}
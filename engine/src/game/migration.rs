@@ -0,0 +1,244 @@
+//! Upgrades serialized `GameData` from older layouts to the current one.
+//!
+//! Migrations operate on the raw JSON value, so they don't need copies of the old types.
+//! Saves without a `schema_version` field are version 0. To change the layout of `GameData`,
+//! bump `CURRENT_SCHEMA_VERSION` and append a function to `MIGRATIONS` that upgrades the
+//! previous version.
+//!
+//! A field that is added to `GameData` or one of its parts, like `TurnOutput` or
+//! `WorldDescription`, doesn't need a migration if it has `#[serde(default)]`, since older
+//! saves just lack it. Renamed, removed or restructured fields do.
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail, ensure, eyre},
+};
+use serde_json::{Map, Value, json};
+
+use super::GameData;
+
+pub const CURRENT_SCHEMA_VERSION: u32 = 2;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// `MIGRATIONS[i]` upgrades from version `i` to version `i + 1`
+const MIGRATIONS: [Migration; CURRENT_SCHEMA_VERSION as usize] = [v0_to_v1, v1_to_v2];
+
+/// Parses game data of any known schema version
+pub fn game_data_from_json(json: &str) -> Result<GameData> {
+    let value = serde_json::from_str(json).context("Game data is not valid JSON")?;
+    let value = migrate(value)?;
+    serde_json::from_value(value).context("Failed to deserialize game data")
+}
+
+pub fn migrate(mut value: Value) -> Result<Value> {
+    let data = value
+        .as_object_mut()
+        .ok_or_else(|| eyre!("Game data must be a JSON object"))?;
+    let version = match data.get("schema_version") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| eyre!("Invalid schema version: {v}"))? as u32,
+    };
+    ensure!(
+        version <= CURRENT_SCHEMA_VERSION,
+        "This save was created by a newer version of World Weaver (schema version {version}, \
+         supported: {CURRENT_SCHEMA_VERSION})"
+    );
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(data).with_context(|| format!("Migrating game data from version {from}"))?;
+        data.insert("schema_version".into(), json!(from + 1));
+    }
+    Ok(value)
+}
+
+/// Version 0 covers all saves before versioning was introduced. Those may have
+/// - `pc_descriptions` as plain strings instead of `PcDescription`s,
+/// - an enum as `TurnInput` instead of a struct with player action and gm instruction,
+/// - `image_ids` instead of `images` in the turn data.
+fn v0_to_v1(data: &mut Map<String, Value>) -> Result<()> {
+    if let Some(pcs) = data
+        .get_mut("world_description")
+        .and_then(|wd| wd.get_mut("pc_descriptions"))
+        .and_then(Value::as_object_mut)
+    {
+        for description in pcs.values_mut() {
+            if let Value::String(s) = description {
+                *description = json!({
+                    "description": std::mem::take(s),
+                    "initial_action": "",
+                });
+            }
+        }
+    }
+
+    let Some(turns) = data.get_mut("turn_data").and_then(Value::as_array_mut) else {
+        return Ok(());
+    };
+    for (i, turn) in turns.iter_mut().enumerate() {
+        let turn = turn
+            .as_object_mut()
+            .ok_or_else(|| eyre!("Turn {i} is not an object"))?;
+
+        if let Some(input) = turn.get_mut("input") {
+            migrate_turn_input(input).with_context(|| format!("Turn {i}"))?;
+        }
+
+        if !turn.contains_key("images")
+            && let Some(ids) = turn.remove("image_ids")
+        {
+            let caption = turn
+                .get("output")
+                .and_then(|o| o.get("image_caption"))
+                .cloned()
+                .unwrap_or_else(|| json!(""));
+            let ids = ids
+                .as_array()
+                .ok_or_else(|| eyre!("Turn {i}: image_ids is not a list"))?;
+            let images = ids
+                .iter()
+                .map(|id| json!({"id": id, "caption": caption}))
+                .collect();
+            turn.insert("images".into(), Value::Array(images));
+        }
+    }
+    Ok(())
+}
+
+/// Before there were tiers, every summary was written from the previous one and the latest
+/// turns, so each one covers the whole story like a campaign summary.
+fn v1_to_v2(data: &mut Map<String, Value>) -> Result<()> {
+    let Some(summaries) = data.get_mut("summaries").and_then(Value::as_array_mut) else {
+        return Ok(());
+    };
+    for (i, summary) in summaries.iter_mut().enumerate() {
+        summary
+            .as_object_mut()
+            .ok_or_else(|| eyre!("Summary {i} is not an object"))?
+            .insert("level".into(), json!("Campaign"));
+    }
+    Ok(())
+}
+
+fn migrate_turn_input(input: &mut Value) -> Result<()> {
+    let (player_action, gm_instruction) = match input {
+        Value::String(s) => (std::mem::take(s), String::new()),
+        Value::Object(obj) if obj.contains_key("player_action") => return Ok(()),
+        Value::Object(obj) if obj.len() == 1 => {
+            let (variant, content) = obj.iter().next().unwrap();
+            let content = content
+                .as_str()
+                .ok_or_else(|| eyre!("Unexpected turn input: {obj:?}"))?
+                .to_string();
+            match variant.as_str() {
+                "PlayerAction" => (content, String::new()),
+                "GmInstruction" | "GMInstruction" => (String::new(), content),
+                other => bail!("Unknown turn input variant: {other}"),
+            }
+        }
+        other => bail!("Unexpected turn input: {other}"),
+    };
+
+    *input = json!({
+        "player_action": player_action,
+        "gm_instruction": gm_instruction,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::SummaryLevel;
+
+    fn v0_save() -> Value {
+        json!({
+            "world_description": {
+                "name": "World",
+                "main_description": "A world",
+                "pc_descriptions": { "Alice": "A hero" },
+                "init_action": "Wake up",
+            },
+            "pc": "Alice",
+            "summaries": [],
+            "turn_data": [
+                {
+                    "summary_before_input": null,
+                    "input": { "PlayerAction": "look around" },
+                    "output": {
+                        "text": "You see a forest",
+                        "image_description": "a forest",
+                        "image_caption": "Forest",
+                        "secret_info": "none",
+                        "proposed_next_actions": ["a", "b", "c"],
+                        "input_tokens": 1,
+                        "output_tokens": 2,
+                    },
+                    "image_ids": [0],
+                },
+                {
+                    "summary_before_input": null,
+                    "input": { "GmInstruction": "make it rain" },
+                    "output": {
+                        "text": "It rains",
+                        "image_description": "rain",
+                        "image_caption": "Rain",
+                        "secret_info": "none",
+                        "proposed_next_actions": ["a", "b", "c"],
+                        "input_tokens": 1,
+                        "output_tokens": 2,
+                    },
+                    "images": [],
+                },
+            ],
+        })
+    }
+
+    #[test]
+    fn migrates_v0_save() {
+        let data = game_data_from_json(&v0_save().to_string()).unwrap();
+
+        assert_eq!(data.schema_version, CURRENT_SCHEMA_VERSION);
+        let alice = &data.world_description.pc_descriptions["Alice"];
+        assert_eq!(alice.description, "A hero");
+        assert_eq!(alice.initial_action, "");
+
+        let first = &data.turn_data[0];
+        assert_eq!(first.input.player_action, "look around");
+        assert_eq!(first.input.gm_instruction, "");
+        assert_eq!(first.images.len(), 1);
+        assert_eq!(first.images[0].id, 0);
+        assert_eq!(first.images[0].caption, "Forest");
+
+        let second = &data.turn_data[1];
+        assert_eq!(second.input.player_action, "");
+        assert_eq!(second.input.gm_instruction, "make it rain");
+        assert!(second.images.is_empty());
+    }
+
+    #[test]
+    fn old_summaries_become_campaign_summaries() {
+        let mut value = v0_save();
+        value["summaries"] = json!([{ "content": "It rained", "bday": 1 }]);
+        let data = game_data_from_json(&value.to_string()).unwrap();
+
+        assert_eq!(data.summaries[0].level, SummaryLevel::Campaign);
+    }
+
+    #[test]
+    fn current_version_is_left_alone() {
+        let mut value = migrate(v0_save()).unwrap();
+        let migrated = value.clone();
+        value = migrate(value).unwrap();
+        assert_eq!(value, migrated);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut value = v0_save();
+        value["schema_version"] = json!(CURRENT_SCHEMA_VERSION + 1);
+        assert!(migrate(value).is_err());
+    }
+}
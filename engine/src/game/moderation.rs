@@ -0,0 +1,79 @@
+//! Image models reject descriptions their content filter doesn't like, although the scene
+//! could often be shown with a tamer wording. Instead of failing, the LLM is asked to
+//! rewrite the description, and the image is requested once more.
+
+use color_eyre::{Report, Result};
+
+use crate::{
+    LLMBox,
+    error::EngineError,
+    llm::{InputMessage, OutputMessage, Request},
+};
+
+use super::repair::receive_message;
+
+const MAX_TOKENS: usize = 1000;
+
+pub(super) fn is_moderated(err: &Report) -> bool {
+    matches!(
+        err.downcast_ref::<EngineError>(),
+        Some(EngineError::Moderated { .. })
+    )
+}
+
+/// Asks the LLM for a version of `description` that passes the image moderation. The text
+/// of the returned message is the new description.
+pub(super) async fn tame_description(llm: &mut LLMBox, description: &str) -> Result<OutputMessage> {
+    let instruction = indoc::formatdoc! {"
+        The content filter of an image model rejected this image description:
+
+        {description}
+
+        Rewrite it so it passes the filter, while it still shows the same scene. Leave out or \
+        soften explicit sexual content, graphic violence and gore, and don't use the names of \
+        real people. Answer with nothing but the new description."
+    };
+    let req = Request {
+        system: None,
+        messages: vec![InputMessage::user(instruction)],
+        max_tokens: MAX_TOKENS,
+        sampling: Default::default(),
+        tools: vec![],
+    };
+    let mut answer = receive_message(llm, req).await?;
+    answer.text = answer.text.trim().to_string();
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::{ScriptedLLM, message};
+
+    #[tokio::test]
+    async fn the_description_is_rewritten() {
+        let script = ScriptedLLM::new([message("\na knight after the battle\n", 80, 20)]);
+        let mut llm: LLMBox = Box::new(script.clone());
+        let answer = tame_description(&mut llm, "a knight covered in gore")
+            .await
+            .unwrap();
+        assert_eq!(answer.text, "a knight after the battle");
+        assert_eq!((answer.input_tokens, answer.output_tokens), (80, 20));
+
+        let requests = script.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(
+            requests[0].messages[0]
+                .content
+                .text()
+                .contains("a knight covered in gore")
+        );
+
+        let moderated: Report = EngineError::Moderated {
+            message: "Request Moderated".into(),
+        }
+        .into();
+        assert!(is_moderated(&moderated.wrap_err("no image")));
+        assert!(!is_moderated(&color_eyre::eyre::eyre!("boom")));
+    }
+}
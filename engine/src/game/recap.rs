@@ -0,0 +1,109 @@
+//! A title for a game and a "previously on…" recap of its story, written by the summary LLM.
+//! The title names the save in the list of saves, and the recap reminds the player of the
+//! story when the game is loaded again. Both are part of the save's metadata.
+
+use color_eyre::{
+    Result,
+    eyre::{ensure, eyre},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{TurnData, repair::receive_message};
+use crate::{
+    LLMBox,
+    llm::{InputMessage, OutputMessage, Request},
+};
+
+const MAX_TOKENS: usize = 1000;
+
+/// The latest turns are sent as they are, the older ones are known from the summaries
+pub(super) const RECENT_TURNS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recap {
+    pub title: String,
+    pub text: String,
+    /// the number of turns the game had when it was written
+    pub turns: usize,
+}
+
+/// Asks the LLM for a title and a recap of the story. `story_so_far` is what the summaries
+/// contain, and `recent` are the turns after them. The returned message tells what it cost.
+pub(super) async fn write_recap(
+    mut llm: LLMBox,
+    story_so_far: &str,
+    recent: &[TurnData],
+    turns: usize,
+) -> Result<(Recap, OutputMessage)> {
+    let recent = recent
+        .iter()
+        .map(|t| {
+            format!(
+                "## player action\n{}\n## narration\n{}",
+                t.input.player_action, t.output.text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n---\n");
+    let instruction = indoc::formatdoc! {"
+        # The story so far
+
+        {story_so_far}
+
+        # The latest turns
+
+        {recent}
+
+        # Instructions
+
+        The player returns to this story after a break. Give the story a short title, and \
+        write a recap for the player, like the \"previously on\" of a TV series: one \
+        paragraph of at most 80 words, in the second person, that ends where the story \
+        stands now. Answer with the title on the first line, and the recap after it."
+    };
+    let req = Request {
+        system: None,
+        messages: vec![InputMessage::user(instruction)],
+        max_tokens: MAX_TOKENS,
+        sampling: Default::default(),
+        tools: vec![],
+    };
+    let answer = receive_message(&mut llm, req).await?;
+    let (title, text) = parse(&answer.text)?;
+    Ok((Recap { title, text, turns }, answer))
+}
+
+/// Splits the answer into the title and the recap
+fn parse(answer: &str) -> Result<(String, String)> {
+    let answer = answer.trim();
+    let (title, text) = answer
+        .split_once('\n')
+        .ok_or_else(|| eyre!("The recap has no title: {answer}"))?;
+    let title = title.trim().trim_start_matches('#').trim();
+    let title = title.strip_prefix("Title:").unwrap_or(title);
+    let title = title.trim().trim_matches(['*', '"']).trim();
+    let text = text.trim();
+    ensure!(
+        !title.is_empty() && !text.is_empty(),
+        "The recap is incomplete: {answer}"
+    );
+    Ok((title.to_string(), text.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_split_into_title_and_recap() {
+        let (title, text) = parse("# **The Drowned Crown**\n\nYou found the crown.\n").unwrap();
+        assert_eq!(title, "The Drowned Crown");
+        assert_eq!(text, "You found the crown.");
+
+        let (title, _) = parse("Title: \"Ashes\"\nYou ran.").unwrap();
+        assert_eq!(title, "Ashes");
+
+        assert!(parse("Only a title").is_err());
+        assert!(parse("Title:\n\nYou ran.").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Models occasionally forget a section marker, which used to end the turn with a parse
+//! error, although the story itself was fine. Instead, the model is shown its answer and
+//! asked to write it again in the expected format.
+
+use color_eyre::{Report, Result, eyre::eyre};
+use log::warn;
+use tokio_stream::StreamExt;
+
+use crate::{
+    LLMBox,
+    error::EngineError,
+    llm::{InputMessage, OutputMessage, Request, ResponseFragment},
+};
+
+use super::{
+    ACTION_SEPARATOR, SECTION_IMAGE_CAPTION, SECTION_IMAGE_DESCRIPTION, SECTION_OUTPUT,
+    SECTION_SECRET_INFO, TurnOutput,
+};
+
+/// How often the model is asked to fix its answer before the turn fails
+pub(super) const MAX_REPAIR_ATTEMPTS: usize = 2;
+
+pub(super) fn is_parse_failure(err: &Report) -> bool {
+    matches!(
+        err.downcast_ref::<EngineError>(),
+        Some(EngineError::ParseFailure { .. })
+    )
+}
+
+/// Asks the model to reformat `answer`, the reply to `req` that failed to parse with `err`.
+/// The returned output includes the tokens of the failed answer and of the repairs.
+/// `with_image` is false in text-only games.
+pub(super) async fn repair_output(
+    llm: &mut LLMBox,
+    req: &Request,
+    mut answer: OutputMessage,
+    mut err: Report,
+    with_image: bool,
+) -> Result<TurnOutput> {
+    let mut input_tokens = 0;
+    let mut output_tokens = 0;
+    for attempt in 1..=MAX_REPAIR_ATTEMPTS {
+        warn!("Asking the LLM to fix the format of its answer (attempt {attempt}): {err}");
+        input_tokens += answer.input_tokens;
+        output_tokens += answer.output_tokens;
+
+        let repair_req = repair_request(req, &answer.text, &err, with_image);
+        answer = receive_message(llm, repair_req).await?;
+        match TurnOutput::parse(answer.clone(), with_image) {
+            Ok(mut output) => {
+                output.input_tokens += input_tokens;
+                output.output_tokens += output_tokens;
+                return Ok(output);
+            }
+            Err(e) => err = e,
+        }
+    }
+    Err(err.wrap_err(format!(
+        "The answer couldn't be repaired in {MAX_REPAIR_ATTEMPTS} attempts"
+    )))
+}
+
+fn repair_request(req: &Request, answer: &str, err: &Report, with_image: bool) -> Request {
+    let image_sections = if with_image {
+        format!(
+            "{SECTION_IMAGE_DESCRIPTION}\n<the image description>\n{SECTION_IMAGE_CAPTION}\n<the caption>\n"
+        )
+    } else {
+        String::new()
+    };
+    let instruction = indoc::formatdoc! {"
+        Your last answer doesn't follow the required format: {err}
+
+        Write the same answer again, in this format:
+        {image_sections}{SECTION_OUTPUT}
+        <the story text>
+        {ACTION_SEPARATOR}
+        <proposed action>
+        {ACTION_SEPARATOR}
+        <proposed action>
+        {ACTION_SEPARATOR}
+        <proposed action>
+        {SECTION_SECRET_INFO}
+        <the secret info>
+
+        Keep the story text as it is, only add what is missing. Answer with nothing but the \
+        reformatted answer."
+    };
+    let mut messages = req.messages.clone();
+    messages.extend([
+        InputMessage::assistant(answer.into()),
+        InputMessage::user(instruction),
+    ]);
+    Request {
+        system: req.system.clone(),
+        messages,
+        max_tokens: req.max_tokens,
+        sampling: req.sampling.clone(),
+        // the story was written already, there's nothing to look up
+        tools: vec![],
+    }
+}
+
+pub(super) async fn receive_message(llm: &mut LLMBox, req: Request) -> Result<OutputMessage> {
+    let mut stream = llm.send_request_stream(req);
+    while let Some(fragment) = stream.try_next().await? {
+        if let ResponseFragment::MessageComplete(msg) = fragment {
+            return Ok(msg);
+        }
+    }
+    Err(eyre!("The response ended before it was complete"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::ScriptedLLM;
+
+    const VALID: &str = "[SECTION IMAGE DESCRIPTION]\nan alley\n[SECTION IMAGE CAPTION]\nThe Alley\n\
+        [SECTION OUTPUT]\nYou walk.\n[ACTION SEPARATOR]\na\n[ACTION SEPARATOR]\nb\n\
+        [ACTION SEPARATOR]\nc\n[SECTION SECRET INFO]\nnone";
+    const BROKEN: &str = "[SECTION IMAGE DESCRIPTION]\nan alley\nYou walk.";
+
+    fn message(text: &str) -> OutputMessage {
+        crate::llm::tests::message(text, 100, 10)
+    }
+
+    async fn repair(replies: Vec<&'static str>) -> (Result<TurnOutput>, Vec<Request>) {
+        let script = ScriptedLLM::new(replies.into_iter().map(message));
+        let mut llm: LLMBox = Box::new(script.clone());
+        let req = Request {
+            system: Some("Narrate".into()),
+            messages: vec![InputMessage::user("walk".into())],
+            max_tokens: 100,
+            sampling: Default::default(),
+            tools: vec![],
+        };
+        let err = TurnOutput::try_from(message(BROKEN)).unwrap_err();
+        let res = repair_output(&mut llm, &req, message(BROKEN), err, true).await;
+        (res, script.requests())
+    }
+
+    #[tokio::test]
+    async fn broken_answers_are_reformatted() {
+        let (res, requests) = repair(vec![BROKEN, VALID]).await;
+        let output = res.unwrap();
+        assert_eq!(output.text, "You walk.");
+        // the broken answers are paid for, too
+        assert_eq!((output.input_tokens, output.output_tokens), (300, 30));
+
+        assert_eq!(requests.len(), 2);
+        let messages = &requests[0].messages;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].content.text(), BROKEN);
+        assert!(
+            messages[2]
+                .content
+                .text()
+                .contains("no [SECTION IMAGE CAPTION]")
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let (res, requests) = repair(vec![BROKEN; MAX_REPAIR_ATTEMPTS]).await;
+        let err = res.unwrap_err();
+        assert!(is_parse_failure(&err), "{err:?}");
+        assert_eq!(requests.len(), MAX_REPAIR_ATTEMPTS);
+    }
+}
@@ -0,0 +1,203 @@
+//! Makes LLM output safe to render as markdown.
+//!
+//! The output is displayed while it's streaming in, and it's produced by a model that might
+//! have been instructed by the world description to do odd things. Therefore, before it's
+//! handed to a markdown renderer:
+//! - links and images are replaced by their text, so rendering never triggers a network fetch,
+//! - `<` is escaped, which neutralizes autolinks and inline HTML,
+//! - nesting of block quotes and indentation is capped,
+//! - the total size is capped,
+//! - our own section delimiters are escaped, in case they leak into the visible text.
+
+use super::{
+    ACTION_SEPARATOR, SECTION_IMAGE_CAPTION, SECTION_IMAGE_DESCRIPTION, SECTION_OUTPUT,
+    SECTION_SECRET_INFO,
+};
+
+/// Maximum number of nested block quotes
+const MAX_QUOTE_DEPTH: usize = 3;
+/// Maximum leading whitespace per line. Enough for three levels of nested lists.
+const MAX_INDENT: usize = 12;
+/// Maximum size of the sanitized text in bytes
+const MAX_DISPLAY_LEN: usize = 64 * 1024;
+
+const DELIMITERS: [&str; 5] = [
+    SECTION_IMAGE_DESCRIPTION,
+    SECTION_IMAGE_CAPTION,
+    SECTION_OUTPUT,
+    SECTION_SECRET_INFO,
+    ACTION_SEPARATOR,
+];
+
+/// Returns a version of `text` that is safe to pass to a markdown parser
+pub fn sanitize_markdown(text: &str) -> String {
+    let is_truncated = text.len() > MAX_DISPLAY_LEN;
+    let text = truncate(text, MAX_DISPLAY_LEN);
+    let mut res = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let line = limit_nesting(line);
+        if is_link_reference_definition(&line) {
+            continue;
+        }
+        res.push_str(&escape_delimiters(&strip_links(&line)));
+    }
+    if is_truncated { res + "\n\n…" } else { res }
+}
+
+fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+fn limit_nesting(line: &str) -> String {
+    let indent_len = line.len() - line.trim_start_matches([' ', '\t']).len();
+    let (indent, rest) = line.split_at(indent_len);
+    let indent = &indent[..indent.len().min(MAX_INDENT)];
+
+    let mut depth = 0;
+    let mut rest = rest;
+    let mut quotes = String::new();
+    while let Some(tail) = rest.strip_prefix('>') {
+        let tail = tail.strip_prefix(' ').unwrap_or(tail);
+        if depth < MAX_QUOTE_DEPTH {
+            quotes.push_str("> ");
+        }
+        depth += 1;
+        rest = tail;
+    }
+    format!("{indent}{quotes}{rest}")
+}
+
+/// Lines like `[ref]: https://example.com` would turn `[ref]` into a link
+fn is_link_reference_definition(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with('[')
+        && line
+            .find("]:")
+            .is_some_and(|i| !line[1..i].contains([']', '[']))
+}
+
+/// Replaces `[text](url)` and `![alt](url)` by their text, and escapes `<`.
+/// Inline code is left alone.
+fn strip_links(line: &str) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut in_code = false;
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            in_code = !in_code;
+        } else if !in_code {
+            if c == '<' {
+                res.push_str("\\<");
+                rest = &rest[1..];
+                continue;
+            }
+            let link_start = match c {
+                '!' if rest[1..].starts_with('[') => Some(2),
+                '[' => Some(1),
+                _ => None,
+            };
+            if let Some((text, tail)) = link_start.and_then(|i| split_link(&rest[i..])) {
+                res.push_str(text);
+                rest = tail;
+                continue;
+            }
+        }
+        res.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    res
+}
+
+/// `s` starts after the opening bracket. If it continues like a link, returns the link text,
+/// and what comes after the link
+fn split_link(s: &str) -> Option<(&str, &str)> {
+    let close = s.find(']')?;
+    let text = &s[..close];
+    if text.contains('[') {
+        return None;
+    }
+    let after = &s[close + 1..];
+    let url_end = if after.starts_with('(') {
+        after.find(')')?
+    } else if after.starts_with('[') {
+        // reference style link
+        after.find(']')?
+    } else {
+        return None;
+    };
+    Some((text, &after[url_end + 1..]))
+}
+
+fn escape_delimiters(line: &str) -> String {
+    let mut line = line.to_string();
+    for delimiter in DELIMITERS {
+        if line.contains(delimiter) {
+            let inner = &delimiter[1..delimiter.len() - 1];
+            line = line.replace(delimiter, &format!("\\[{inner}\\]"));
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_and_images_are_replaced_by_their_text() {
+        assert_eq!(
+            sanitize_markdown("Go [north](https://evil.example) now ![a map](http://x/y.png)."),
+            "Go north now a map."
+        );
+        assert_eq!(
+            sanitize_markdown("see [here][1]\n[1]: http://x\n"),
+            "see here\n"
+        );
+        assert_eq!(
+            sanitize_markdown("<https://x> and <img src=x>"),
+            "\\<https://x> and \\<img src=x>"
+        );
+        // not links
+        assert_eq!(
+            sanitize_markdown("[sic] and `[a](b)`"),
+            "[sic] and `[a](b)`"
+        );
+        assert_eq!(
+            sanitize_markdown("half a link [abc](htt"),
+            "half a link [abc](htt"
+        );
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        assert_eq!(sanitize_markdown(">>>>>> deep"), "> > > deep");
+        let deep_list = format!("{}- item", " ".repeat(40));
+        assert_eq!(
+            sanitize_markdown(&deep_list),
+            format!("{}- item", " ".repeat(MAX_INDENT))
+        );
+    }
+
+    #[test]
+    fn size_is_limited() {
+        let long = "ä".repeat(MAX_DISPLAY_LEN);
+        let sanitized = sanitize_markdown(&long);
+        assert!(sanitized.len() <= MAX_DISPLAY_LEN + 10);
+        assert!(sanitized.ends_with('…'));
+    }
+
+    #[test]
+    fn delimiters_are_escaped() {
+        assert_eq!(
+            sanitize_markdown(&format!("text {ACTION_SEPARATOR} more")),
+            "text \\[ACTION SEPARATOR\\] more"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! World scripts add mechanics like hunger counters or faction reputation, without changes to
+//! the engine. They are written in [Rhai](https://rhai.rs) and shipped with the world, see
+//! [`WorldDescription::script`]. A script can define these hooks, which are called with a map
+//! as `this`:
+//!
+//! - `on_before_request()`: before a turn is requested. `this.action` and
+//!   `this.gm_instruction` are what is sent, the turn keeps what the player typed.
+//! - `on_parse_output()`: when the answer of the LLM was parsed. `this.text`,
+//!   `this.secret_info` and `this.image_caption` replace the ones of the answer.
+//! - `on_turn_complete()`: when a turn was added to the game, with its `action`,
+//!   `gm_instruction`, `text` and `secret_info`.
+//!
+//! All of them also get `this.turn`, the number of the turn as the player sees it, and
+//! `this.state`, the [world state](super::world_state). Values that are whole numbers are
+//! passed as numbers, and setting a key to `()` removes it. Changes to the state are kept,
+//! except in `on_before_request`. A hook that fails is logged and skipped, so a broken script
+//! doesn't stop the game.
+
+use color_eyre::{Result, eyre::eyre};
+use log::{debug, error, info};
+use rhai::{AST, CallFnOptions, Dynamic, Engine, INT, Map, Scope};
+
+use super::{TurnData, TurnInput, TurnOutput, WorldDescription, WorldState, world_state};
+
+/// keeps endless loops in a shared world from freezing the game
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+/// keep a script from using up the memory, e.g. by doubling a string in a loop
+const MAX_STRING_SIZE: usize = 100_000;
+const MAX_ARRAY_SIZE: usize = 10_000;
+const MAX_MAP_SIZE: usize = 10_000;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn compile(src: &str) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(MAX_ARRAY_SIZE);
+        engine.set_max_map_size(MAX_MAP_SIZE);
+        engine.on_print(|s| info!("script: {s}"));
+        engine.on_debug(|s, _, pos| debug!("script at {pos}: {s}"));
+        let ast = engine
+            .compile(src)
+            .map_err(|e| eyre!("The script is invalid: {e}"))?;
+        Ok(Self { engine, ast })
+    }
+
+    /// The compiled script of `world`, `None` if it has none. A broken script is logged and
+    /// left out, worlds are validated when they are saved, so this only happens to worlds
+    /// that were edited by hand.
+    pub fn for_world(world: &WorldDescription) -> Option<Self> {
+        let src = world.script.as_deref()?;
+        Self::compile(src)
+            .inspect_err(|e| error!("Running {} without its script: {e:#}", world.name))
+            .ok()
+    }
+
+    /// What is sent for the turn with index `turn`
+    pub fn on_before_request(
+        &self,
+        turn: usize,
+        input: &TurnInput,
+        state: &WorldState,
+    ) -> TurnInput {
+        let mut this = hook_map(turn, state);
+        this.insert("action".into(), input.player_action.clone().into());
+        this.insert("gm_instruction".into(), input.gm_instruction.clone().into());
+        let Some(this) = self.call("on_before_request", this) else {
+            return input.clone();
+        };
+        TurnInput {
+            player_action: string_field(&this, "action")
+                .unwrap_or_else(|| input.player_action.clone()),
+            gm_instruction: string_field(&this, "gm_instruction")
+                .unwrap_or_else(|| input.gm_instruction.clone()),
+        }
+    }
+
+    /// Lets the script change the answer for the turn with index `turn`. Its changes of the
+    /// state are added to the world state updates of `output`.
+    pub fn on_parse_output(&self, turn: usize, output: &mut TurnOutput, state: &WorldState) {
+        let mut state = state.clone();
+        world_state::apply(&mut state, &output.world_state_updates);
+        let mut this = hook_map(turn, &state);
+        this.insert("text".into(), output.text.clone().into());
+        this.insert("secret_info".into(), output.secret_info.clone().into());
+        this.insert("image_caption".into(), output.image_caption.clone().into());
+        let Some(this) = self.call("on_parse_output", this) else {
+            return;
+        };
+        for (field, value) in [
+            ("text", &mut output.text),
+            ("secret_info", &mut output.secret_info),
+            ("image_caption", &mut output.image_caption),
+        ] {
+            if let Some(new) = string_field(&this, field) {
+                *value = new;
+            }
+        }
+        if let Some(new_state) = state_field(&this) {
+            output
+                .world_state_updates
+                .extend(world_state::diff(&state, &new_state));
+        }
+    }
+
+    /// Called after the turn with index `turn` was added, with the world state after it
+    pub fn on_turn_complete(&self, turn: usize, turn_data: &TurnData, state: &mut WorldState) {
+        let mut this = hook_map(turn, state);
+        let TurnData { input, output, .. } = turn_data;
+        this.insert("action".into(), input.player_action.clone().into());
+        this.insert("gm_instruction".into(), input.gm_instruction.clone().into());
+        this.insert("text".into(), output.text.clone().into());
+        this.insert("secret_info".into(), output.secret_info.clone().into());
+        if let Some(new_state) = self
+            .call("on_turn_complete", this)
+            .as_ref()
+            .and_then(state_field)
+        {
+            *state = new_state;
+        }
+    }
+
+    /// Calls `hook` if the script defines it, and returns what `this` became
+    fn call(&self, hook: &str, this: Map) -> Option<Map> {
+        let defined = self
+            .ast
+            .iter_functions()
+            .any(|f| f.name == hook && f.params.is_empty());
+        if !defined {
+            return None;
+        }
+        let mut this = Dynamic::from_map(this);
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut this);
+        if let Err(e) = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            hook,
+            (),
+        ) {
+            error!("The script's {hook} failed: {e}");
+            return None;
+        }
+        let this = this.try_cast::<Map>();
+        if this.is_none() {
+            error!("The script's {hook} replaced `this` with something that isn't a map");
+        }
+        this
+    }
+}
+
+fn hook_map(turn: usize, state: &WorldState) -> Map {
+    let state = state
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .parse::<INT>()
+                .map_or_else(|_| value.clone().into(), Dynamic::from_int);
+            (key.as_str().into(), value)
+        })
+        .collect::<Map>();
+    Map::from([
+        ("turn".into(), Dynamic::from_int(turn as INT + 1)),
+        ("state".into(), Dynamic::from_map(state)),
+    ])
+}
+
+fn string_field(this: &Map, field: &str) -> Option<String> {
+    this.get(field).map(Dynamic::to_string)
+}
+
+fn state_field(this: &Map) -> Option<WorldState> {
+    let state = this.get("state")?.clone().try_cast::<Map>()?;
+    Some(
+        state
+            .into_iter()
+            .filter(|(_, value)| !value.is_unit())
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_archive::tests::make_sample_game_data;
+
+    const HUNGER: &str = r#"
+        fn on_before_request() {
+            if this.state.hunger > 3 {
+                this.gm_instruction += "\nThe player is starving.";
+            }
+        }
+
+        fn on_parse_output() {
+            if this.text.contains("[ATE]") {
+                this.text.replace("[ATE]", "");
+                this.state.hunger = ();
+            }
+        }
+
+        fn on_turn_complete() {
+            this.state.hunger = (this.state.hunger ?? 0) + 1;
+        }
+    "#;
+
+    #[test]
+    fn hooks_change_the_turn_and_the_state() {
+        let script = Script::compile(HUNGER).unwrap();
+        let mut data = make_sample_game_data(1);
+        for turn in 0..4 {
+            script.on_turn_complete(turn, &data.turn_data[0], &mut data.world_state);
+        }
+        assert_eq!(data.world_state["hunger"], "4");
+
+        let input = TurnInput::player_action("Look around".into());
+        let sent = script.on_before_request(4, &input, &data.world_state);
+        assert_eq!(sent.player_action, "Look around");
+        assert!(sent.gm_instruction.ends_with("The player is starving."));
+
+        let mut output = data.turn_data[0].output.clone();
+        output.text = "You eat some bread.[ATE]".into();
+        script.on_parse_output(4, &mut output, &data.world_state);
+        assert_eq!(output.text, "You eat some bread.");
+        world_state::apply(&mut data.world_state, &output.world_state_updates);
+        assert!(!data.world_state.contains_key("hunger"));
+    }
+
+    #[test]
+    fn failing_hooks_change_nothing() {
+        assert!(Script::compile("fn on_turn_complete( {").is_err());
+        let script = Script::compile("fn on_before_request() { loop {} }").unwrap();
+        let input = TurnInput::player_action("Wait".into());
+        let sent = script.on_before_request(0, &input, &WorldState::new());
+        assert_eq!(sent.player_action, "Wait");
+
+        let script =
+            Script::compile(r#"fn on_before_request() { let s = "x"; loop { s += s; } }"#).unwrap();
+        let sent = script.on_before_request(0, &input, &WorldState::new());
+        assert_eq!(sent.player_action, "Wait");
+    }
+}
@@ -3,8 +3,11 @@
 
 pub struct StreamFinder {
     target: Vec<char>,
+    /// `failure[i]` is the length of the longest proper prefix of `target[..=i]`, that is
+    /// also a suffix of it. On a mismatch, this is how much of the partial match can be kept.
+    failure: Vec<usize>,
+    /// Number of chars of `target` that are currently matched. Those are held back.
     current_pos: usize,
-    stored_output: Vec<char>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -17,74 +20,50 @@ pub enum MatchResult {
     CheckedOutput(String),
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum ProcessCharOutcome {
-    PrefixMatch,
-    PrefixReMatch,
-    Mismatch,
-    FullMatch,
-}
-
 impl StreamFinder {
     pub fn new(target: &'static str) -> Self {
+        let target: Vec<char> = target.chars().collect();
+        assert!(!target.is_empty(), "StreamFinder needs a non-empty target");
         Self {
-            target: target.chars().collect(),
+            failure: failure_function(&target),
+            target,
             current_pos: 0,
-            stored_output: vec![],
         }
     }
 
-    fn process_char(&mut self, ch: char) -> ProcessCharOutcome {
-        if ch == self.target[self.current_pos] {
+    /// Advances the match by one char, and appends chars that can't be part of a match
+    /// anymore to `output`. Returns true on a full match.
+    fn process_char(&mut self, ch: char, output: &mut Vec<char>) -> bool {
+        let held_back = self.current_pos;
+        while self.current_pos > 0 && self.target[self.current_pos] != ch {
+            self.current_pos = self.failure[self.current_pos - 1];
+        }
+        // the held back text is target[..held_back]. Only its last current_pos chars
+        // are still part of a possible match, the rest is released.
+        output.extend_from_slice(&self.target[..held_back - self.current_pos]);
+
+        if self.target[self.current_pos] == ch {
             self.current_pos += 1;
-            if self.current_pos == self.target.len() {
-                ProcessCharOutcome::FullMatch
-            } else {
-                ProcessCharOutcome::PrefixMatch
-            }
-        } else if ch == self.target[0] {
-            self.current_pos = 1;
-            ProcessCharOutcome::PrefixReMatch
         } else {
-            self.current_pos = 0;
-            ProcessCharOutcome::Mismatch
+            output.push(ch);
         }
+        self.current_pos == self.target.len()
     }
 
     fn reset(&mut self) {
         self.current_pos = 0;
-        self.stored_output.clear();
     }
 
     pub fn process(&mut self, input: &str) -> MatchResult {
         let mut output_chars = vec![];
         let mut chars = input.chars();
         while let Some(ch) = chars.next() {
-            match self.process_char(ch) {
-                ProcessCharOutcome::Mismatch => {
-                    if self.stored_output.is_empty() {
-                        output_chars.push(ch);
-                    } else {
-                        output_chars.extend_from_slice(&self.stored_output);
-                        output_chars.push(ch);
-                        self.stored_output.clear();
-                    }
-                }
-                ProcessCharOutcome::PrefixMatch => {
-                    self.stored_output.push(ch);
-                }
-                ProcessCharOutcome::PrefixReMatch => {
-                    output_chars.extend_from_slice(&self.stored_output);
-                    self.stored_output.clear();
-                    self.stored_output.push(ch);
-                }
-                ProcessCharOutcome::FullMatch => {
-                    self.reset();
-                    return MatchResult::StopTokenMatched {
-                        pre_token_text: output_chars.into_iter().collect(),
-                        post_token_text: chars.collect(),
-                    };
-                }
+            if self.process_char(ch, &mut output_chars) {
+                self.reset();
+                return MatchResult::StopTokenMatched {
+                    pre_token_text: output_chars.into_iter().collect(),
+                    post_token_text: chars.collect(),
+                };
             }
         }
 
@@ -96,16 +75,106 @@ impl StreamFinder {
     }
 
     pub fn finish(&mut self) -> String {
-        let res = self.stored_output.iter().collect();
+        let res = self.target[..self.current_pos].iter().collect();
         self.reset();
         res
     }
 }
 
+fn failure_function(target: &[char]) -> Vec<usize> {
+    let mut failure = vec![0; target.len()];
+    let mut len = 0;
+    for i in 1..target.len() {
+        while len > 0 && target[i] != target[len] {
+            len = failure[len - 1];
+        }
+        if target[i] == target[len] {
+            len += 1;
+        }
+        failure[i] = len;
+    }
+    failure
+}
+
 #[cfg(test)]
 mod tests {
     use super::{MatchResult, StreamFinder};
 
+    /// Feeds `input` in chunks of `chunk_size` chars, until the token is found.
+    /// Returns all text before the token, and the text after it, if it was found.
+    fn run(target: &'static str, input: &str, chunk_size: usize) -> (String, Option<String>) {
+        let mut finder = StreamFinder::new(target);
+        let chars: Vec<char> = input.chars().collect();
+        let mut pre = String::new();
+        let mut chunks = chars.chunks(chunk_size);
+        while let Some(chunk) = chunks.next() {
+            match finder.process(&chunk.iter().collect::<String>()) {
+                MatchResult::Blocked => {}
+                MatchResult::CheckedOutput(s) => pre.push_str(&s),
+                MatchResult::StopTokenMatched {
+                    pre_token_text,
+                    mut post_token_text,
+                } => {
+                    pre.push_str(&pre_token_text);
+                    post_token_text.extend(chunks.flatten());
+                    return (pre, Some(post_token_text));
+                }
+            }
+        }
+        pre.push_str(&finder.finish());
+        (pre, None)
+    }
+
+    /// All strings of length up to `max_len` over `alphabet`
+    fn all_strings(alphabet: &[char], max_len: usize) -> Vec<String> {
+        let mut res = vec![String::new()];
+        let mut last = vec![String::new()];
+        for _ in 0..max_len {
+            last = last
+                .iter()
+                .flat_map(|s| alphabet.iter().map(move |c| format!("{s}{c}")))
+                .collect();
+            res.extend(last.iter().cloned());
+        }
+        res
+    }
+
+    fn check_exhaustively(targets: &[&'static str], alphabet: &[char], max_len: usize) {
+        for target in targets {
+            for input in all_strings(alphabet, max_len) {
+                let expected = match input.find(target) {
+                    Some(i) => (
+                        input[..i].to_string(),
+                        Some(input[i + target.len()..].to_string()),
+                    ),
+                    None => (input.clone(), None),
+                };
+                for chunk_size in 1..=3 {
+                    assert_eq!(
+                        run(target, &input, chunk_size),
+                        expected,
+                        "target: {target:?}, input: {input:?}, chunk size: {chunk_size}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_prefixes() {
+        assert_eq!(run("aab", "aaab", 1), ("a".into(), Some("".into())));
+        check_exhaustively(&["aab", "aba", "aaa", "abab", "abaab"], &['a', 'b'], 8);
+    }
+
+    #[test]
+    fn multi_byte_tokens() {
+        check_exhaustively(
+            &["ää😀", "😀ö😀", "[A]"],
+            &['ä', 'ö', '😀', '[', 'A', ']'],
+            5,
+        );
+    }
+
     #[test]
     fn test_stop_token_detection() {
         let mut matcher = StreamFinder::new("User:");
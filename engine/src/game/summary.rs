@@ -0,0 +1,248 @@
+//! Every few turns the story so far is summarized, and the summary replaces the older turns in
+//! the requests, see [`GameData::construct_request`]. A summary is requested together with the
+//! turn that follows the summarized ones, and it's born with that turn, so the turn itself is
+//! part of the next summary.
+//!
+//! The summaries have two tiers. A chapter summary covers the turns since the previous
+//! summary, and every few chapters, a campaign summary is written instead, which folds the
+//! previous campaign summary, the chapters since then and the latest turns into one. The
+//! requests contain the latest campaign summary and the chapters after it, see
+//! [`story_so_far`].
+
+use std::ops::Range;
+
+use color_eyre::{
+    Result,
+    eyre::{ensure, eyre},
+};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+use super::{GameData, Summary, TurnData, TurnInput};
+use crate::{
+    LLMBox,
+    llm::{InputMessage, OutputMessage, Request, ResponseFragment, Sampling},
+};
+
+/// The number of turns between two summaries, unless the game has its own
+pub const DEFAULT_INTERVAL: usize = 5;
+
+/// The instructions for the LLM, unless the world has its own
+pub const DEFAULT_PROMPT: &str = include_str!("summary_prompt.txt");
+
+/// A campaign summary is written instead of every this many'th chapter summary
+pub const CHAPTERS_PER_CAMPAIGN: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SummaryLevel {
+    /// the turns since the previous summary
+    #[default]
+    Chapter,
+    /// the whole story up to its birthday
+    Campaign,
+}
+
+/// The turns that are summarized before the next turn is added, `None` if no summary is due.
+/// An `interval` of 0 disables the summaries.
+pub(super) fn due_turns(data: &GameData, interval: usize) -> Option<Range<usize>> {
+    let since = data.summaries.last().map_or(0, |s| s.bday);
+    let now = data.turn_data.len();
+    (interval > 0 && now.saturating_sub(since) >= interval).then_some(since..now)
+}
+
+/// The level of the summary that is written next
+pub(super) fn next_level(summaries: &[Summary]) -> SummaryLevel {
+    let chapters = current_tiers(summaries)
+        .iter()
+        .filter(|s| s.level == SummaryLevel::Chapter)
+        .count();
+    if chapters + 1 >= CHAPTERS_PER_CAMPAIGN {
+        SummaryLevel::Campaign
+    } else {
+        SummaryLevel::Chapter
+    }
+}
+
+/// The latest campaign summary and the chapters after it
+fn current_tiers(summaries: &[Summary]) -> &[Summary] {
+    let start = summaries
+        .iter()
+        .rposition(|s| s.level == SummaryLevel::Campaign)
+        .unwrap_or(0);
+    &summaries[start..]
+}
+
+/// Everything the summaries know about the story, empty if there are none
+pub(super) fn story_so_far(summaries: &[Summary]) -> String {
+    let tiers = current_tiers(summaries);
+    let offset = summaries.len() - tiers.len();
+    tiers
+        .iter()
+        .enumerate()
+        .map(|(i, s)| match s.level {
+            SummaryLevel::Campaign => s.content.clone(),
+            SummaryLevel::Chapter => {
+                let start = (offset + i)
+                    .checked_sub(1)
+                    .map_or(0, |prev| summaries[prev].bday);
+                format!(
+                    "## Turns {start} to {}\n\n{}",
+                    s.bday.saturating_sub(1),
+                    s.content
+                )
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Asks the LLM for a summary of `turns` on the given level. `story_so_far` is what the
+/// summaries before it contain.
+pub(super) async fn summarize(
+    mut llm: LLMBox,
+    prompt: &str,
+    level: SummaryLevel,
+    story_so_far: &str,
+    turns: Vec<TurnData>,
+) -> Result<OutputMessage> {
+    let term_strs = turns
+        .iter()
+        .map(|t| {
+            let TurnInput {
+                player_action,
+                gm_instruction,
+            } = &t.input;
+            indoc::formatdoc! {
+                "## player action
+                {}
+                ## gm command
+                {}
+                ## assistant output
+                {}
+                ## secret info
+                {}", player_action,
+               gm_instruction,
+               t.output.text,
+               t.output.secret_info
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let instructions = match level {
+        SummaryLevel::Chapter => {
+            "Summarize only the provided turns. The old summary is context, don't repeat it"
+        }
+        SummaryLevel::Campaign => {
+            "Use the old summary (if it exists) and the provided turns to create a new summary"
+        }
+    };
+    let user_message = indoc::formatdoc! {r#"
+            # Last Summary
+
+            {story_so_far}
+
+            # Turns to summarize
+
+            {}
+
+            # Instructions
+
+            {instructions}
+        "#, term_strs.join("\n---\n")};
+
+    debug!("Sending summary request");
+    let mut stream = llm.send_request_stream(Request {
+        system: Some(prompt.into()),
+        messages: vec![InputMessage::user(user_message)],
+        max_tokens: 3000,
+        sampling: Sampling::default(),
+        tools: vec![],
+    });
+    let mut received_text = String::new();
+
+    let response = loop {
+        let fragment = match stream.try_next().await {
+            Ok(Some(fragment)) => fragment,
+            Ok(None) => {
+                error!(
+                    "Summary stream ended before message completion. Received text so far:\n{}",
+                    received_text
+                );
+                Err(eyre!("summary stream ended before message completion"))?
+            }
+            Err(err) => {
+                error!(
+                    "Summary stream failed before message completion. Received text so far:\n{}\nError: {err:?}",
+                    received_text
+                );
+                Err(err)?
+            }
+        };
+
+        match fragment {
+            ResponseFragment::TextDelta(text) => received_text.push_str(&text),
+            ResponseFragment::ThinkingDelta(_) => {}
+            ResponseFragment::MessageComplete(m) => break m,
+        }
+    };
+
+    ensure!(matches!(stream.try_next().await, Ok(None)));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_archive::tests::make_sample_game_data;
+
+    fn summary(bday: usize, level: SummaryLevel) -> Summary {
+        Summary {
+            content: format!("until {bday}"),
+            bday,
+            level,
+        }
+    }
+
+    #[test]
+    fn summaries_are_due_every_interval() {
+        let mut data = make_sample_game_data(4);
+        assert_eq!(due_turns(&data, 5), None);
+        assert_eq!(due_turns(&data, 4), Some(0..4));
+        assert_eq!(due_turns(&data, 0), None);
+
+        // the turn a summary was born with is part of the next one
+        data.summaries.push(summary(1, SummaryLevel::Chapter));
+        assert_eq!(due_turns(&data, 3), Some(1..4));
+        assert_eq!(due_turns(&data, 4), None);
+    }
+
+    #[test]
+    fn every_few_chapters_become_a_campaign_summary() {
+        let mut summaries = vec![];
+        for i in 1..CHAPTERS_PER_CAMPAIGN {
+            assert_eq!(next_level(&summaries), SummaryLevel::Chapter);
+            summaries.push(summary(i * 5, SummaryLevel::Chapter));
+        }
+        assert_eq!(next_level(&summaries), SummaryLevel::Campaign);
+        summaries.push(summary(100, SummaryLevel::Campaign));
+        assert_eq!(next_level(&summaries), SummaryLevel::Chapter);
+    }
+
+    #[test]
+    fn story_starts_at_the_latest_campaign_summary() {
+        assert_eq!(story_so_far(&[]), "");
+
+        let summaries = [
+            summary(5, SummaryLevel::Chapter),
+            summary(10, SummaryLevel::Campaign),
+            summary(15, SummaryLevel::Chapter),
+            summary(20, SummaryLevel::Chapter),
+        ];
+        assert_eq!(
+            story_so_far(&summaries),
+            "until 10\n\n## Turns 10 to 14\n\nuntil 15\n\n## Turns 15 to 19\n\nuntil 20"
+        );
+        assert_eq!(story_so_far(&summaries[..1]), "## Turns 0 to 4\n\nuntil 5");
+    }
+}
@@ -0,0 +1,254 @@
+//! The system message of a turn request is rendered from a template, so worlds can replace
+//! the instructions without touching the engine. Templates use the TinyTemplate syntax:
+//! `{player}` inserts a variable, `{{ if summary }}...{{ endif }}` is a condition, and
+//! literal braces have to be escaped as `\{`.
+
+use color_eyre::{Result, eyre::Context};
+use serde::Serialize;
+use tinytemplate::TinyTemplate;
+
+use super::{
+    ACTION_SEPARATOR, SECTION_CODEX, SECTION_IMAGE_CAPTION, SECTION_IMAGE_DESCRIPTION,
+    SECTION_OUTPUT, SECTION_SECRET_INFO, SECTION_TIME, SECTION_WORLD_STATE,
+};
+
+/// The template that is used unless the world has its own
+pub const DEFAULT_TEMPLATE: &str = include_str!("system_prompt.txt");
+
+const TEMPLATE_NAME: &str = "system";
+
+/// The variables that are available in a template
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptVariables<'a> {
+    /// the name of the player character
+    pub player: &'a str,
+    pub pc_description: &'a str,
+    pub world_description: &'a str,
+    /// whether the LLM writes image descriptions, false if the game is text-only
+    pub images: bool,
+    /// what the image model needs to be told in addition, may be empty
+    pub image_gen_extra_infos: &'a str,
+    pub target_words: usize,
+    /// the summaries of the story so far, empty if there are none yet
+    pub summary: &'a str,
+    /// the turn the latest summary was written after
+    pub summary_turn: usize,
+    /// the known characters, locations and factions, one per line. Empty if there are none.
+    pub codex: &'a str,
+    /// the stats of the player character, one per line. Empty if it has none.
+    pub stats: &'a str,
+    /// the facts of the world state, one `key = value` per line. Empty if there are none.
+    pub world_state: &'a str,
+    /// the in-game time when the turn starts, like "Day 2, 14:30"
+    pub time: &'a str,
+    /// the instructions of the world's tone knobs, one per line. Empty if all are neutral.
+    pub tone: &'a str,
+    /// how often actions fail and what failures cost. Empty for a balanced game.
+    pub difficulty: &'a str,
+    /// the world's locations, factions and key NPCs, one `- name: description` per line. Each
+    /// is empty if the world has none.
+    pub locations: &'a str,
+    pub factions: &'a str,
+    pub npcs: &'a str,
+    pub section_image_description: &'static str,
+    pub section_image_caption: &'static str,
+    pub section_output: &'static str,
+    pub action_separator: &'static str,
+    pub section_secret_info: &'static str,
+    pub section_time: &'static str,
+    pub section_codex: &'static str,
+    pub section_world_state: &'static str,
+}
+
+impl<'a> PromptVariables<'a> {
+    pub fn new(
+        player: &'a str,
+        pc_description: &'a str,
+        world_description: &'a str,
+        image_gen_extra_infos: &'a str,
+        target_words: usize,
+        summary: &'a str,
+        summary_turn: usize,
+    ) -> Self {
+        Self {
+            player,
+            pc_description,
+            world_description,
+            images: true,
+            image_gen_extra_infos,
+            target_words,
+            summary,
+            summary_turn,
+            codex: "",
+            stats: "",
+            world_state: "",
+            time: "Day 1, 08:00",
+            tone: "",
+            difficulty: "",
+            locations: "",
+            factions: "",
+            npcs: "",
+            section_image_description: SECTION_IMAGE_DESCRIPTION,
+            section_image_caption: SECTION_IMAGE_CAPTION,
+            section_output: SECTION_OUTPUT,
+            action_separator: ACTION_SEPARATOR,
+            section_secret_info: SECTION_SECRET_INFO,
+            section_time: SECTION_TIME,
+            section_codex: SECTION_CODEX,
+            section_world_state: SECTION_WORLD_STATE,
+        }
+    }
+
+    /// Values for all variables, to check templates before they are used
+    fn example() -> Self {
+        Self::new(
+            "Player",
+            "description",
+            "description",
+            "",
+            100,
+            "summary",
+            0,
+        )
+    }
+}
+
+pub fn render(template: &str, vars: &PromptVariables) -> Result<String> {
+    let mut tt = TinyTemplate::new();
+    // the output isn't HTML
+    tt.set_default_formatter(&tinytemplate::format_unescaped);
+    tt.add_template(TEMPLATE_NAME, template)
+        .wrap_err("Invalid prompt template")?;
+    tt.render(TEMPLATE_NAME, vars)
+        .wrap_err("Failed to render the prompt template")
+}
+
+/// Fails if `template` has a syntax error or uses an unknown variable
+pub fn validate(template: &str) -> Result<()> {
+    render(template, &PromptVariables::example()).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::Difficulty;
+
+    #[test]
+    fn default_template_uses_all_sections() {
+        let vars = PromptVariables::new("Kara", "a thief", "a city", "", 300, "", 0);
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        for section in [
+            SECTION_IMAGE_DESCRIPTION,
+            SECTION_IMAGE_CAPTION,
+            SECTION_OUTPUT,
+            ACTION_SEPARATOR,
+            SECTION_SECRET_INFO,
+            SECTION_CODEX,
+        ] {
+            assert!(prompt.contains(section), "{section}");
+        }
+        assert!(prompt.contains("I control Kara"));
+        assert!(prompt.contains("about 300 words"));
+    }
+
+    #[test]
+    fn text_only_prompts_dont_ask_for_images() {
+        let vars = PromptVariables {
+            images: false,
+            ..PromptVariables::new("Kara", "a thief", "a city", "", 300, "", 0)
+        };
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(!prompt.contains(SECTION_IMAGE_DESCRIPTION));
+        assert!(!prompt.contains(SECTION_IMAGE_CAPTION));
+        assert!(!prompt.contains("image"), "{prompt}");
+        assert!(prompt.contains(&format!("begin immediately with {SECTION_OUTPUT}")));
+    }
+
+    #[test]
+    fn codex_is_only_mentioned_if_there_are_entries() {
+        let vars = PromptVariables::new("Kara", "a thief", "a city", "", 300, "", 0);
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(!prompt.contains("START CODEX"));
+
+        let vars = PromptVariables {
+            codex: "- Mira (Character): a smuggler",
+            ..vars
+        };
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(prompt.contains("START CODEX ---\n- Mira (Character): a smuggler\n"));
+    }
+
+    #[test]
+    fn world_state_is_only_listed_if_it_has_facts() {
+        let vars = PromptVariables::new("Kara", "a thief", "a city", "", 300, "", 0);
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(!prompt.contains("START WORLD STATE"));
+
+        let vars = PromptVariables {
+            world_state: "bridge = destroyed",
+            ..vars
+        };
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(prompt.contains("START WORLD STATE ---\nbridge = destroyed\n"));
+    }
+
+    #[test]
+    fn tone_is_only_listed_if_a_knob_is_turned() {
+        let vars = PromptVariables::new("Kara", "a thief", "a city", "", 300, "", 0);
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(!prompt.contains("START TONE"));
+
+        let vars = PromptVariables {
+            tone: "Keep humor rare.",
+            ..vars
+        };
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(prompt.contains("START TONE ---\nKeep humor rare.\n"));
+    }
+
+    #[test]
+    fn difficulty_is_only_listed_if_it_isnt_balanced() {
+        let vars = PromptVariables::new("Kara", "a thief", "a city", "", 300, "", 0);
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(!prompt.contains("START DIFFICULTY"));
+
+        let vars = PromptVariables {
+            difficulty: Difficulty::Brutal.to_prompt(),
+            ..vars
+        };
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(prompt.contains("START DIFFICULTY ---\nBe brutal"));
+    }
+
+    #[test]
+    fn world_details_get_their_own_sections() {
+        let vars = PromptVariables::new("Kara", "a thief", "a city", "", 300, "", 0);
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(!prompt.contains("START LOCATIONS"));
+        assert!(!prompt.contains("START FACTIONS"));
+
+        let vars = PromptVariables {
+            locations: "- The Docks: where the smugglers meet",
+            npcs: "- Mira: a smuggler",
+            ..vars
+        };
+        let prompt = render(DEFAULT_TEMPLATE, &vars).unwrap();
+        assert!(prompt.contains("START LOCATIONS ---\n- The Docks: where the smugglers meet\n"));
+        assert!(!prompt.contains("START FACTIONS"));
+        assert!(prompt.contains("START NPCS ---\n- Mira: a smuggler\n"));
+    }
+
+    #[test]
+    fn custom_templates_are_checked() {
+        assert!(validate("You narrate for {player}. \\{braces} are fine.").is_ok());
+        assert!(validate("{unknown_variable}").is_err());
+        assert!(validate("{{ if summary }}unterminated").is_err());
+
+        let vars = PromptVariables::new("Kara", "", "", "", 300, "", 0);
+        let prompt = render(
+            "{{ if summary }}Summary: {summary}{{ endif }}Go, {player}!",
+            &vars,
+        );
+        assert_eq!(prompt.unwrap(), "Go, Kara!");
+    }
+}
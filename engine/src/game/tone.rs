@@ -0,0 +1,140 @@
+//! Knobs for the tone of a world's stories. Each is a level from 0 to [`MAX_LEVEL`], and
+//! every level but the neutral one adds an instruction to the system prompt, through the
+//! `tone` variable of the template. They can be changed during a game.
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter, IntoEnumIterator};
+
+pub const MAX_LEVEL: u8 = 4;
+/// adds nothing to the prompt
+pub const NEUTRAL: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tone {
+    pub darkness: u8,
+    pub humor: u8,
+    pub pacing: u8,
+    pub descriptiveness: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumIter)]
+pub enum Knob {
+    Darkness,
+    Humor,
+    Pacing,
+    Descriptiveness,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            darkness: NEUTRAL,
+            humor: NEUTRAL,
+            pacing: NEUTRAL,
+            descriptiveness: NEUTRAL,
+        }
+    }
+}
+
+impl Tone {
+    pub fn get(&self, knob: Knob) -> u8 {
+        match knob {
+            Knob::Darkness => self.darkness,
+            Knob::Humor => self.humor,
+            Knob::Pacing => self.pacing,
+            Knob::Descriptiveness => self.descriptiveness,
+        }
+    }
+
+    pub fn set(&mut self, knob: Knob, level: u8) {
+        let level = level.min(MAX_LEVEL);
+        match knob {
+            Knob::Darkness => self.darkness = level,
+            Knob::Humor => self.humor = level,
+            Knob::Pacing => self.pacing = level,
+            Knob::Descriptiveness => self.descriptiveness = level,
+        }
+    }
+
+    pub fn is_neutral(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The instructions of the knobs that aren't neutral, one per line
+    pub fn to_prompt(&self) -> String {
+        Knob::iter()
+            .map(|knob| knob.phrase(self.get(knob)))
+            .filter(|phrase| !phrase.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Knob {
+    /// What the lowest and the highest level mean, for the sliders
+    pub fn ends(self) -> (&'static str, &'static str) {
+        match self {
+            Knob::Darkness => ("light", "grim"),
+            Knob::Humor => ("serious", "comedic"),
+            Knob::Pacing => ("slow", "fast"),
+            Knob::Descriptiveness => ("sparse", "lavish"),
+        }
+    }
+
+    fn phrase(self, level: u8) -> &'static str {
+        let phrases = match self {
+            Knob::Darkness => [
+                "Keep the story light and hopeful, without grim or disturbing events.",
+                "Lean towards a lighter tone.",
+                "",
+                "Lean towards a darker tone.",
+                "Make the story dark and grim. Hope is rare, and victories come at a cost.",
+            ],
+            Knob::Humor => [
+                "Play everything straight, without jokes.",
+                "Keep humor rare.",
+                "",
+                "Let humor come up often, in dialogue and in situations.",
+                "Make the story comedic, with lots of jokes and absurd situations.",
+            ],
+            Knob::Pacing => [
+                "Take the story slowly. Linger on scenes, conversations and small details, \
+                 and let events unfold over several turns.",
+                "Let scenes breathe before moving on.",
+                "",
+                "Keep the story moving, and skip uneventful stretches.",
+                "Make the story fast-paced. Every turn moves the plot forward, and scenes \
+                 change quickly.",
+            ],
+            Knob::Descriptiveness => [
+                "Describe sparsely, only what matters for the action.",
+                "Keep descriptions short.",
+                "",
+                "Describe places, people and the atmosphere in detail.",
+                "Describe lavishly, with rich sensory details in every scene.",
+            ],
+        };
+        phrases[usize::from(level.min(MAX_LEVEL))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_turned_knobs_are_in_the_prompt() {
+        let mut tone = Tone::default();
+        assert!(tone.is_neutral());
+        assert_eq!(tone.to_prompt(), "");
+
+        tone.set(Knob::Darkness, 4);
+        tone.set(Knob::Pacing, 9);
+        assert_eq!(tone.pacing, MAX_LEVEL);
+        let prompt = tone.to_prompt();
+        assert_eq!(prompt.lines().count(), 2);
+        assert!(prompt.starts_with("Make the story dark and grim."));
+        assert!(prompt.ends_with("scenes change quickly."));
+    }
+}
@@ -1,18 +1,20 @@
 //! `TurnOutput` is the structured form of a fully generated turn.
 //! This module also contains the parser and serializer for the custom output format.
 
-use color_eyre::eyre::eyre;
 use log::{error, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     N_PROPOSED_OPTIONS,
+    error::EngineError,
     llm::OutputMessage,
 };
 
 use super::{
-    ACTION_SEPARATOR, SECTION_IMAGE_CAPTION, SECTION_IMAGE_DESCRIPTION, SECTION_OUTPUT,
-    SECTION_SECRET_INFO,
+    ACTION_SEPARATOR, SECTION_CODEX, SECTION_IMAGE_CAPTION, SECTION_IMAGE_DESCRIPTION,
+    SECTION_OUTPUT, SECTION_SECRET_INFO, SECTION_TIME, SECTION_WORLD_STATE, clock,
+    codex::{self, CodexUpdate},
+    world_state::{self, WorldStateUpdate},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,9 +23,21 @@ pub struct TurnOutput {
     pub image_description: String,
     pub image_caption: String,
     pub secret_info: String,
+    /// how many in-game minutes passed in the turn, `None` if the LLM didn't say
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_minutes: Option<u32>,
     pub proposed_next_actions: [String; N_PROPOSED_OPTIONS],
     pub input_tokens: usize,
     pub output_tokens: usize,
+    /// what the turn introduced or changed in the codex
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub codex_updates: Vec<CodexUpdate>,
+    /// the changes of the world state the LLM asked for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub world_state_updates: Vec<WorldStateUpdate>,
+    /// the results of the dice rolls and skill checks the LLM asked for, see `tools`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rolls: Vec<String>,
 }
 
 impl TurnOutput {
@@ -54,24 +68,31 @@ impl TurnOutput {
                     .to_string(),
                 "none",
             ),
+            elapsed_minutes: None,
             proposed_next_actions: actions[..N_PROPOSED_OPTIONS].to_vec().try_into().unwrap(),
             input_tokens,
             output_tokens,
+            codex_updates: vec![],
+            world_state_updates: vec![],
+            rolls: vec![],
         }
     }
 
-    pub fn to_llm_format(&self) -> String {
+    /// `with_image` is false in text-only games, whose prompt doesn't mention the image sections
+    pub fn to_llm_format(&self, with_image: bool) -> String {
         let mut output = String::new();
 
         output.push('\n');
-        output.push_str(SECTION_IMAGE_DESCRIPTION);
-        output.push('\n');
-        output.push_str(&self.image_description);
-        output.push('\n');
-        output.push_str(SECTION_IMAGE_CAPTION);
-        output.push('\n');
-        output.push_str(&self.image_caption);
-        output.push('\n');
+        if with_image {
+            output.push_str(SECTION_IMAGE_DESCRIPTION);
+            output.push('\n');
+            output.push_str(&self.image_description);
+            output.push('\n');
+            output.push_str(SECTION_IMAGE_CAPTION);
+            output.push('\n');
+            output.push_str(&self.image_caption);
+            output.push('\n');
+        }
         output.push_str(SECTION_OUTPUT);
         output.push('\n');
 
@@ -85,39 +106,87 @@ impl TurnOutput {
         output.push_str(SECTION_SECRET_INFO);
         output.push('\n');
         output.push_str(&self.secret_info);
+        if let Some(minutes) = self.elapsed_minutes {
+            output.push('\n');
+            output.push_str(SECTION_TIME);
+            output.push('\n');
+            output.push_str(&minutes.to_string());
+        }
+        if !self.codex_updates.is_empty() {
+            output.push('\n');
+            output.push_str(SECTION_CODEX);
+            output.push('\n');
+            output.push_str(&serde_json::to_string(&self.codex_updates).unwrap());
+        }
+        if !self.world_state_updates.is_empty() {
+            output.push('\n');
+            output.push_str(SECTION_WORLD_STATE);
+            for update in &self.world_state_updates {
+                output.push('\n');
+                output.push_str(&update.key);
+                output.push_str(" = ");
+                output.push_str(update.value.as_deref().unwrap_or_default());
+            }
+        }
 
         output
     }
-}
-
-impl TryFrom<OutputMessage> for TurnOutput {
-    type Error = color_eyre::Report;
 
-    fn try_from(value: OutputMessage) -> std::result::Result<Self, Self::Error> {
-        let Some((_, tail)) = split_once_any(&value.text, &[SECTION_IMAGE_DESCRIPTION]) else {
-            let err = eyre!("no {SECTION_IMAGE_DESCRIPTION} in output");
-            error!("Failed to parse LLM message:\n{}\nParse error: {err:?}", value.text);
-            return Err(err);
-        };
-        let Some((image_description, tail)) =
-            split_once_any(tail, &[SECTION_IMAGE_CAPTION])
-        else {
-            let err = eyre!("no {SECTION_IMAGE_CAPTION} in output");
-            error!("Failed to parse LLM message:\n{}\nParse error: {err:?}", value.text);
-            return Err(err);
+    /// Without `with_image`, the answer starts with the output section, and the image
+    /// description and caption stay empty
+    pub fn parse(value: OutputMessage, with_image: bool) -> color_eyre::Result<Self> {
+        let (image_description, tail) = if with_image {
+            let Some((_, tail)) = split_once_any(&value.text, &[SECTION_IMAGE_DESCRIPTION]) else {
+                let err = EngineError::ParseFailure {
+                    message: format!("no {SECTION_IMAGE_DESCRIPTION} in output"),
+                };
+                error!("Failed to parse LLM message:\n{}\nParse error: {err}", value.text);
+                return Err(err.into());
+            };
+            let Some((image_description, tail)) =
+                split_once_any(tail, &[SECTION_IMAGE_CAPTION])
+            else {
+                let err = EngineError::ParseFailure {
+                    message: format!("no {SECTION_IMAGE_CAPTION} in output"),
+                };
+                error!("Failed to parse LLM message:\n{}\nParse error: {err}", value.text);
+                return Err(err.into());
+            };
+            (image_description, trim_leading_markers(tail, &[SECTION_IMAGE_CAPTION]))
+        } else {
+            ("", value.text.as_str())
         };
-        let tail = trim_leading_markers(tail, &[SECTION_IMAGE_CAPTION]);
 
         let Some((image_caption, tail)) = split_once_any(tail, &[SECTION_OUTPUT]) else {
-            let err = eyre!("no {SECTION_OUTPUT} in output");
-            error!("Failed to parse LLM message:\n{}\nParse error: {err:?}", value.text);
-            return Err(err);
+            let err = EngineError::ParseFailure {
+                message: format!("no {SECTION_OUTPUT} in output"),
+            };
+            error!("Failed to parse LLM message:\n{}\nParse error: {err}", value.text);
+            return Err(err.into());
         };
+        // without image, it's whatever the model wrote before the output
+        let image_caption = if with_image { image_caption } else { "" };
 
         let Some((output, tail)) = split_once_any(tail, &[ACTION_SEPARATOR]) else {
-            let err = eyre!("No {ACTION_SEPARATOR} in output");
-            error!("Failed to parse LLM message:\n{}\nParse error: {err:?}", value.text);
-            return Err(err);
+            let err = EngineError::ParseFailure {
+                message: format!("No {ACTION_SEPARATOR} in output"),
+            };
+            error!("Failed to parse LLM message:\n{}\nParse error: {err}", value.text);
+            return Err(err.into());
+        };
+
+        // the world state is the last section, so it's split off first
+        let (tail, world_state_updates) = match split_once_any(tail, &[SECTION_WORLD_STATE]) {
+            Some((tail, updates)) => (tail, world_state::parse(updates)),
+            None => (tail, vec![]),
+        };
+        let (tail, codex_updates) = match split_once_any(tail, &[SECTION_CODEX]) {
+            Some((tail, codex)) => (tail, codex::parse_updates(codex)),
+            None => (tail, vec![]),
+        };
+        let (tail, elapsed_minutes) = match split_once_any(tail, &[SECTION_TIME]) {
+            Some((tail, time)) => (tail, clock::parse_elapsed(time)),
+            None => (tail, None),
         };
 
         let (action_text, secret) = if let Some((action_text, secret)) =
@@ -140,15 +209,28 @@ impl TryFrom<OutputMessage> for TurnOutput {
             );
         }
 
-        Ok(TurnOutput::from_parts(
-            image_description.into(),
-            image_caption.into(),
-            output.into(),
-            secret,
-            proposed_next_actions,
-            value.input_tokens,
-            value.output_tokens,
-        ))
+        Ok(TurnOutput {
+            elapsed_minutes,
+            codex_updates,
+            world_state_updates,
+            ..TurnOutput::from_parts(
+                image_description.into(),
+                image_caption.into(),
+                output.into(),
+                secret,
+                proposed_next_actions,
+                value.input_tokens,
+                value.output_tokens,
+            )
+        })
+    }
+}
+
+impl TryFrom<OutputMessage> for TurnOutput {
+    type Error = color_eyre::Report;
+
+    fn try_from(value: OutputMessage) -> std::result::Result<Self, Self::Error> {
+        TurnOutput::parse(value, true)
     }
 }
 
@@ -202,6 +284,8 @@ The watcher is armed.
             text: raw.into(),
             input_tokens: 12,
             output_tokens: 34,
+            tool_calls: vec![],
+            thinking: vec![],
         })
         .unwrap();
 
@@ -235,6 +319,8 @@ You step into the alley.
             text: raw.into(),
             input_tokens: 12,
             output_tokens: 34,
+            tool_calls: vec![],
+            thinking: vec![],
         })
         .unwrap();
 
@@ -273,10 +359,82 @@ The watcher is armed.
             text: raw.into(),
             input_tokens: 12,
             output_tokens: 34,
+            tool_calls: vec![],
+            thinking: vec![],
         })
         .unwrap();
 
         assert_eq!(parsed.image_caption, "Night Watch");
         assert_eq!(parsed.secret_info, "The watcher is armed.");
     }
+
+    #[test]
+    fn parses_codex_after_secret_info() {
+        let raw = r#"
+[SECTION OUTPUT]
+You step into the alley.
+[ACTION SEPARATOR]
+Move closer.
+[SECTION SECRET INFO]
+The watcher is armed.
+[SECTION TIME]
+15
+[SECTION CODEX]
+[{"kind": "character", "name": "The watcher", "description": "armed, hides in the alley"}]
+[SECTION WORLD STATE]
+alley lamp = broken
+"#;
+        let parsed = TurnOutput::parse(
+            OutputMessage {
+                text: raw.into(),
+                input_tokens: 12,
+                output_tokens: 34,
+                tool_calls: vec![],
+                thinking: vec![],
+            },
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(parsed.secret_info, "The watcher is armed.");
+        assert_eq!(parsed.elapsed_minutes, Some(15));
+        assert_eq!(parsed.codex_updates.len(), 1);
+        assert_eq!(parsed.codex_updates[0].name, "The watcher");
+        assert_eq!(parsed.world_state_updates.len(), 1);
+        assert_eq!(parsed.world_state_updates[0].key, "alley lamp");
+        let llm_format = parsed.to_llm_format(false);
+        assert!(llm_format.contains(SECTION_CODEX));
+        assert!(llm_format.contains(&format!("{SECTION_TIME}\n15\n")));
+        assert!(llm_format.contains(&format!("{SECTION_WORLD_STATE}\nalley lamp = broken")));
+    }
+
+    #[test]
+    fn parses_text_only_output() {
+        let raw = r#"
+[SECTION OUTPUT]
+You step into the alley.
+[ACTION SEPARATOR]
+Move closer.
+[ACTION SEPARATOR]
+Hide behind crates.
+[ACTION SEPARATOR]
+Call out softly.
+[SECTION SECRET INFO]
+The watcher is armed.
+"#;
+        let message = OutputMessage {
+            text: raw.into(),
+            input_tokens: 12,
+            output_tokens: 34,
+            tool_calls: vec![],
+            thinking: vec![],
+        };
+        assert!(TurnOutput::try_from(message.clone()).is_err());
+
+        let parsed = TurnOutput::parse(message, false).unwrap();
+        assert_eq!(parsed.image_description, "");
+        assert_eq!(parsed.image_caption, "");
+        assert_eq!(parsed.text, "You step into the alley.");
+        assert!(!parsed.to_llm_format(false).contains(SECTION_IMAGE_DESCRIPTION));
+    }
 }
@@ -17,6 +17,8 @@ use super::{
 };
 
 pub(super) struct TurnStreamProcessor {
+    /// false in text-only games, where the answer starts with the output section
+    with_image: bool,
     mode: SendToLLMState,
     soi_finder: StreamFinder,
     eoi_finder: StreamFinder,
@@ -29,6 +31,8 @@ pub(super) struct TurnStreamProcessor {
     tail_text: String,
 }
 
+// there is only one `TurnComplete` per turn, so it isn't worth boxing
+#[allow(clippy::large_enum_variant)]
 pub(super) enum ProcessorEvent {
     VisibleText(String),
     ImageDescriptionReady(ImageDescription),
@@ -36,9 +40,16 @@ pub(super) enum ProcessorEvent {
 }
 
 impl TurnStreamProcessor {
-    pub(super) fn new() -> Self {
+    pub(super) fn new(with_image: bool) -> Self {
         Self {
-            mode: SendToLLMState::LookingForStartOfImageDescription,
+            with_image,
+            // without image, everything up to the output section is discarded, like the
+            // image description
+            mode: if with_image {
+                SendToLLMState::LookingForStartOfImageDescription
+            } else {
+                SendToLLMState::ParsingImageDescription
+            },
             soi_finder: StreamFinder::new(SECTION_IMAGE_DESCRIPTION),
             eoi_finder: StreamFinder::new(SECTION_OUTPUT),
             eoo_finder: StreamFinder::new(ACTION_SEPARATOR),
@@ -57,6 +68,7 @@ impl TurnStreamProcessor {
                 self.received_text.push_str(&f);
                 self.push_text_delta(f)
             }
+            ResponseFragment::ThinkingDelta(_) => Ok(vec![]),
             ResponseFragment::MessageComplete(m) => self.finish_message(m),
         }
     }
@@ -129,7 +141,7 @@ impl TurnStreamProcessor {
     }
 
     fn finish_message(&mut self, message: OutputMessage) -> Result<Vec<ProcessorEvent>> {
-        let output = TurnOutput::try_from(message).context("parse output")?;
+        let output = TurnOutput::parse(message, self.with_image).context("parse output")?;
         Ok(vec![ProcessorEvent::TurnComplete(output)])
     }
 
@@ -168,6 +180,13 @@ impl TurnStreamProcessor {
             } => {
                 self.image_description.push_str(&pre_token_text);
                 self.mode = SendToLLMState::StreamingOutputText;
+                if !self.with_image {
+                    self.image_info = Some(ImageDescription {
+                        description: String::new(),
+                        caption: String::new(),
+                    });
+                    return Ok(post_token_text);
+                }
                 let description = parse_image_description(&self.image_description)
                     .inspect_err(|e| {
                         error!(
@@ -225,7 +244,7 @@ mod tests {
 
     #[test]
     fn emits_image_description_when_caption_marker_arrives() {
-        let mut processor = TurnStreamProcessor::new();
+        let mut processor = TurnStreamProcessor::new(true);
         let events = processor
             .push(text_delta(
                 "preface [SECTION IMAGE DESCRIPTION]\nportrait details\n[SECTION IMAGE CAPTION]\nNight Watch\n[SECTION OUTPUT]",
@@ -242,7 +261,7 @@ mod tests {
 
     #[test]
     fn emits_visible_text_after_caption_marker() {
-        let mut processor = TurnStreamProcessor::new();
+        let mut processor = TurnStreamProcessor::new(true);
         let events = processor
             .push(text_delta(
                 "[SECTION IMAGE DESCRIPTION]\nportrait details\n[SECTION IMAGE CAPTION]\nNight Watch\n[SECTION OUTPUT]\nVisible intro",
@@ -258,7 +277,7 @@ mod tests {
 
     #[test]
     fn ignores_text_after_output_stop_until_completion() {
-        let mut processor = TurnStreamProcessor::new();
+        let mut processor = TurnStreamProcessor::new(true);
         let _ = processor
             .push(text_delta(
                 "[SECTION IMAGE DESCRIPTION]\nportrait\n[SECTION IMAGE CAPTION]\nNight Watch\n[SECTION OUTPUT]\nShown text[ACTION SEPARATOR]\na1",
@@ -270,6 +289,8 @@ mod tests {
                 text: "[SECTION IMAGE DESCRIPTION]\nportrait\n[SECTION IMAGE CAPTION]\nNight Watch\n[SECTION OUTPUT]\nShown text[ACTION SEPARATOR]a1[ACTION SEPARATOR]a2[ACTION SEPARATOR]a3[SECTION SECRET INFO]\nsecret".into(),
                 input_tokens: 1,
                 output_tokens: 1,
+                tool_calls: vec![],
+                thinking: vec![],
             }))
             .unwrap();
 
@@ -279,7 +300,7 @@ mod tests {
 
     #[test]
     fn builds_partial_output_when_stream_dies_after_visible_text() {
-        let mut processor = TurnStreamProcessor::new();
+        let mut processor = TurnStreamProcessor::new(true);
         processor
             .push(text_delta(
                 "[SECTION IMAGE DESCRIPTION]\nportrait details\n[SECTION IMAGE CAPTION]\nNight Watch\n[SECTION OUTPUT]\nVisible intro",
@@ -304,7 +325,7 @@ mod tests {
 
     #[test]
     fn builds_partial_output_after_output_stop_with_missing_tail() {
-        let mut processor = TurnStreamProcessor::new();
+        let mut processor = TurnStreamProcessor::new(true);
         processor
             .push(text_delta(
                 "[SECTION IMAGE DESCRIPTION]\nportrait details\n[SECTION IMAGE CAPTION]\nNight Watch\n[SECTION OUTPUT]\nVisible intro\n[ACTION SEPARATOR]\n",
@@ -317,4 +338,22 @@ mod tests {
         assert_eq!(output.secret_info, "none");
         assert_eq!(output.proposed_next_actions[0], "missing");
     }
+
+    #[test]
+    fn text_only_output_is_streamed_without_image_description() {
+        let mut processor = TurnStreamProcessor::new(false);
+        let events = processor
+            .push(text_delta("preface\n[SECTION OUTPUT]\nVisible intro"))
+            .unwrap();
+
+        assert_eq!(events.len(), 1);
+        let ProcessorEvent::VisibleText(text) = &events[0] else {
+            panic!("expected visible text event");
+        };
+        assert_eq!(text, "\nVisible intro");
+
+        let output = processor.finish_incomplete().unwrap();
+        assert_eq!(output.image_description, "");
+        assert_eq!(output.text, "Visible intro");
+    }
 }
@@ -0,0 +1,52 @@
+//! The locations, factions and key NPCs of a world. They are described apart from the main
+//! description, so each list gets its own section of the system prompt, and worlds with many
+//! of them stay readable.
+
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+use super::WorldDescription;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorldDetail {
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display, EnumIter)]
+pub enum DetailKind {
+    Locations,
+    Factions,
+    #[strum(to_string = "Key NPCs")]
+    Npcs,
+}
+
+impl DetailKind {
+    /// Names the fields of the kind in the markdown format of worlds
+    pub fn key(self) -> &'static str {
+        match self {
+            DetailKind::Locations => "location",
+            DetailKind::Factions => "faction",
+            DetailKind::Npcs => "npc",
+        }
+    }
+}
+
+impl WorldDescription {
+    pub fn details(&self, kind: DetailKind) -> &[WorldDetail] {
+        match kind {
+            DetailKind::Locations => &self.locations,
+            DetailKind::Factions => &self.factions,
+            DetailKind::Npcs => &self.npcs,
+        }
+    }
+}
+
+/// One `- name: description` line per detail, empty if there are none
+pub fn to_prompt(details: &[WorldDetail]) -> String {
+    details
+        .iter()
+        .map(|d| format!("- {}: {}", d.name.trim(), d.description.trim()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,146 @@
+//! The world state holds facts like "the bridge is destroyed" as key/value pairs. It's part
+//! of every system prompt, so facts don't fall out of the history window with the turn that
+//! established them. The LLM changes it through the world state section of its answer, the
+//! GM through `/set key = value` and `/unset key` lines in the GM instruction, and it can be
+//! edited by hand.
+
+use std::collections::BTreeMap;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+pub type WorldState = BTreeMap<String, String>;
+
+const GM_SET: &str = "/set ";
+const GM_UNSET: &str = "/unset ";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorldStateUpdate {
+    pub key: String,
+    /// `None` removes the key
+    pub value: Option<String>,
+}
+
+pub fn apply(state: &mut WorldState, updates: &[WorldStateUpdate]) {
+    for WorldStateUpdate { key, value } in updates {
+        match value {
+            Some(value) => state.insert(key.clone(), value.clone()),
+            None => state.remove(key),
+        };
+    }
+}
+
+/// The updates that turn `old` into `new`
+pub fn diff(old: &WorldState, new: &WorldState) -> Vec<WorldStateUpdate> {
+    let removed = old
+        .keys()
+        .filter(|key| !new.contains_key(*key))
+        .map(|key| WorldStateUpdate {
+            key: key.clone(),
+            value: None,
+        });
+    let changed = new
+        .iter()
+        .filter(|(key, value)| old.get(*key) != Some(*value))
+        .map(|(key, value)| WorldStateUpdate {
+            key: key.clone(),
+            value: Some(value.clone()),
+        });
+    removed.chain(changed).collect()
+}
+
+/// One `key = value` line per entry, the format [`parse`] reads
+pub fn to_text(state: &WorldState) -> String {
+    state
+        .iter()
+        .map(|(key, value)| format!("{key} = {value}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Reads `key = value` lines, a line without value removes the key. The LLM isn't always
+/// careful, so broken lines are logged and skipped.
+pub fn parse(src: &str) -> Vec<WorldStateUpdate> {
+    src.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && *line != "none")
+        .filter_map(|line| {
+            let update = parse_line(line);
+            if update.is_none() {
+                warn!("Ignoring invalid world state line: {line:?}");
+            }
+            update
+        })
+        .collect()
+}
+
+/// The `/set` and `/unset` lines of a GM instruction
+pub fn gm_directives(instruction: &str) -> Vec<WorldStateUpdate> {
+    instruction
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            if let Some(assignment) = line.strip_prefix(GM_SET) {
+                parse_line(assignment)
+            } else {
+                let key = line.strip_prefix(GM_UNSET)?.trim();
+                (!key.is_empty()).then(|| WorldStateUpdate {
+                    key: key.into(),
+                    value: None,
+                })
+            }
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<WorldStateUpdate> {
+    let (key, value) = line.trim_start_matches("- ").split_once('=')?;
+    let key = key.trim();
+    if key.is_empty() {
+        return None;
+    }
+    let value = value.trim();
+    Some(WorldStateUpdate {
+        key: key.into(),
+        value: (!value.is_empty()).then(|| value.into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_set_and_remove_keys() {
+        let mut state = WorldState::from([("gate".into(), "open".into())]);
+        apply(
+            &mut state,
+            &parse("- bridge = destroyed\ngate =\nno assignment\n"),
+        );
+        assert_eq!(
+            state,
+            WorldState::from([("bridge".into(), "destroyed".into())])
+        );
+        assert_eq!(to_text(&state), "bridge = destroyed");
+        assert!(parse("none").is_empty());
+    }
+
+    #[test]
+    fn reads_gm_directives() {
+        let instruction = "Let it rain.\n/set weather = rain\n /unset bridge\n/set broken";
+        let updates = gm_directives(instruction);
+        assert_eq!(
+            updates,
+            [
+                WorldStateUpdate {
+                    key: "weather".into(),
+                    value: Some("rain".into()),
+                },
+                WorldStateUpdate {
+                    key: "bridge".into(),
+                    value: None,
+                },
+            ]
+        );
+    }
+}
@@ -1,17 +1,40 @@
-use std::{fmt::Display, pin::Pin};
+use std::{fmt::Display, ops::RangeInclusive, pin::Pin, sync::Arc};
 
-use color_eyre::Result;
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use color_eyre::{Result, eyre::bail};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{Value, json};
 use strum::{Display, EnumIter};
+use tokio::sync::mpsc;
+
+pub mod download;
+pub use download::ImageDownloadError;
+
+pub mod fallback;
+pub use fallback::FallbackImageModel;
 
 pub mod flux2;
 pub use flux2::Flux2;
 
+pub mod local;
+pub use local::{LocalApi, LocalSettings};
+
+pub mod manual;
+pub use manual::ManualImageModel;
+
+pub mod openai;
+pub use openai::OpenAIImageModel;
+
 pub mod pruna;
 
+pub mod queue;
+pub use queue::QueueSettings;
+
 pub mod replicate;
 
+pub mod storage;
+pub use storage::{ImageFormat, PreparedImage, StorageSettings};
+
 use crate::ImgModBox;
 
 #[derive(
@@ -26,6 +49,8 @@ use crate::ImgModBox;
     Eq,
     EnumIter,
     Default,
+    PartialOrd,
+    Ord,
 )]
 
 pub enum ProvidedModel {
@@ -34,6 +59,10 @@ pub enum ProvidedModel {
     Flux2Replicate,
     #[default]
     PImagePruna,
+    StableDiffusionLocal,
+    GptImage1OpenAI,
+    DallE3OpenAI,
+    ManualImage,
 }
 
 impl Display for ProvidedModel {
@@ -61,6 +90,13 @@ pub enum Model {
     Flux1,
     Flux2,
     PImage,
+    StableDiffusion,
+    #[strum(to_string = "gpt-image-1")]
+    GptImage1,
+    #[strum(to_string = "DALL·E 3")]
+    DallE3,
+    #[strum(to_string = "Own image files")]
+    OwnImages,
 }
 
 impl Model {
@@ -79,6 +115,27 @@ impl Model {
                 "
             }
             Self::PImage => "",
+            Self::StableDiffusion => {
+                r"
+                    The image model is Stable Diffusion. Keep the input short and concrete, everything after
+                    77 tokens will be clipped.
+                "
+            }
+            Self::GptImage1 => {
+                r"
+                    The image model is gpt-image-1. It follows long, detailed descriptions well. Avoid
+                    explicit sexual content and graphic violence, the image moderation rejects it.
+                "
+            }
+            Self::DallE3 => {
+                r"
+                    The image model is DALL-E 3. Keep the input below 4000 characters. Avoid explicit
+                    sexual content, graphic violence and the names of real people, the image moderation
+                    rejects them.
+                "
+            }
+            // the player reads the description when picking the file
+            Self::OwnImages => "",
         }
     }
 }
@@ -103,45 +160,68 @@ pub enum ModelProvider {
     BFL,
     Replicate,
     Pruna,
+    /// runs on the user's machine, needs no API key
+    Local,
+    OpenAI,
+    /// the player picks the files, nothing is sent anywhere
+    Manual,
+}
+
+impl ModelProvider {
+    pub fn needs_api_key(&self) -> bool {
+        !matches!(self, Self::Local | Self::Manual)
+    }
 }
 
 impl ProvidedModel {
+    /// `StableDiffusionLocal` is made with the default settings, use [`LocalSettings::make`]
+    /// to configure it.
     pub fn make(&self, key: String) -> ImgModBox {
+        self.make_with_safety_tolerance(key, self.default_safety_tolerance().unwrap_or(0))
+    }
+
+    /// Like [`ProvidedModel::make`], with a tolerance from [`ProvidedModel::safety_tolerance_range`].
+    /// It's clamped into the range, and ignored by models without one.
+    pub fn make_with_safety_tolerance(&self, key: String, safety_tolerance: u8) -> ImgModBox {
+        let tolerance = self.safety_tolerance_range().map_or(0, |range| {
+            safety_tolerance.clamp(*range.start(), *range.end())
+        });
         match self {
             ProvidedModel::Flux1Replicate => Box::new(replicate::ReplicateImageModel::new(
                 "https://api.replicate.com/v1/predictions".into(),
                 *self,
                 key,
-                Some("8cf067a09fbd627c5597781951e1a6988e3b69f6ef712b4948d3d2b5361569ad".into()),
-                |prompt| {
+                Some(replicate::catalog::FLUX1_VERSION.into()),
+                |prompt, seed| {
                     json!({
                         "prompt": prompt,
                         "width": 832,
                         "height": 1216,
                         "steps": 25,
                         "cfg_scale": 3,
-                        "seed": -1,
+                        "seed": seed,
                     })
                 },
             )),
-            ProvidedModel::Flux2BLF => Box::new(Flux2::new(key)),
+            ProvidedModel::Flux2BLF => Box::new(Flux2::new(key, tolerance)),
             ProvidedModel::Flux2Replicate => Box::new(replicate::ReplicateImageModel::new(
                 "https://api.replicate.com/v1/models/black-forest-labs/flux-2-pro/predictions"
                     .into(),
                 *self,
                 key,
                 None,
-                |prompt| {
+                move |prompt, seed| {
                     json!({
                         "width": 832,
                         "height": 1216,
                         "prompt": prompt,
+                        "seed": seed,
                         "resolution": "1 MP",
                         "aspect_ratio": "9:16",
                         "input_images": [],
                         "output_format": "jpg",
                         "output_quality": 80,
-                        "safety_tolerance": 5
+                        "safety_tolerance": tolerance
                     })
                 },
             )),
@@ -150,16 +230,46 @@ impl ProvidedModel {
                 *self,
                 "p-image".into(),
                 key,
-                |prompt| {
+                move |prompt, seed| {
                     json!({
                         "prompt": prompt,
+                        "seed": seed,
                         "aspect_ratio": "custom",
                         "width": 832,
                         "height": 1216,
-                        "disable_safety_checker": true
+                        "disable_safety_checker": tolerance > 0
                     })
                 },
             )),
+            ProvidedModel::StableDiffusionLocal => LocalSettings::default().make(),
+            ProvidedModel::GptImage1OpenAI | ProvidedModel::DallE3OpenAI => {
+                Box::new(OpenAIImageModel::new(*self, key, tolerance))
+            }
+            ProvidedModel::ManualImage => Box::new(ManualImageModel::new()),
+        }
+    }
+
+    /// How permissive the moderation of the model can be set, higher is more permissive.
+    /// `None` if the model has no such setting.
+    pub fn safety_tolerance_range(&self) -> Option<RangeInclusive<u8>> {
+        match self {
+            ProvidedModel::Flux2BLF => Some(0..=6),
+            ProvidedModel::Flux2Replicate => Some(1..=5),
+            // 1 disables the safety checker
+            ProvidedModel::PImagePruna => Some(0..=1),
+            // 0 is the `auto` moderation, 1 the `low` one
+            ProvidedModel::GptImage1OpenAI => Some(0..=1),
+            ProvidedModel::Flux1Replicate
+            | ProvidedModel::StableDiffusionLocal
+            | ProvidedModel::DallE3OpenAI
+            | ProvidedModel::ManualImage => None,
+        }
+    }
+
+    pub fn default_safety_tolerance(&self) -> Option<u8> {
+        match self {
+            ProvidedModel::Flux2BLF | ProvidedModel::Flux2Replicate => Some(5),
+            _ => self.safety_tolerance_range().map(|range| *range.end()),
         }
     }
 
@@ -169,6 +279,10 @@ impl ProvidedModel {
             ProvidedModel::Flux2Replicate => ModelProvider::Replicate,
             ProvidedModel::Flux2BLF => ModelProvider::BFL,
             ProvidedModel::PImagePruna => ModelProvider::Pruna,
+            ProvidedModel::StableDiffusionLocal => ModelProvider::Local,
+            ProvidedModel::GptImage1OpenAI => ModelProvider::OpenAI,
+            ProvidedModel::DallE3OpenAI => ModelProvider::OpenAI,
+            ProvidedModel::ManualImage => ModelProvider::Manual,
         }
     }
 
@@ -178,6 +292,10 @@ impl ProvidedModel {
             ProvidedModel::Flux2BLF => Model::Flux2,
             ProvidedModel::Flux2Replicate => Model::Flux2,
             ProvidedModel::PImagePruna => Model::PImage,
+            ProvidedModel::StableDiffusionLocal => Model::StableDiffusion,
+            ProvidedModel::GptImage1OpenAI => Model::GptImage1,
+            ProvidedModel::DallE3OpenAI => Model::DallE3,
+            ProvidedModel::ManualImage => Model::OwnImages,
         }
     }
 }
@@ -185,14 +303,106 @@ impl ProvidedModel {
 pub struct Image {
     pub data: Vec<u8>,
     pub cost: Option<f64>,
+    /// `None` if the model has no seeds
+    pub seed: Option<u64>,
+}
+
+/// Makes the model specific input of a prediction from the prompt and the seed
+pub(crate) type InputBuilder = Arc<dyn Fn(&str, u64) -> serde_json::Value + Send + Sync>;
+
+/// A seed for models that make one up themselves otherwise. It's kept below `u32::MAX`, since
+/// not every API accepts larger ones.
+pub(crate) fn random_seed() -> u64 {
+    fastrand::u32(..).into()
+}
+
+/// How far along an image is, as far as the provider tells
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageProgress {
+    /// as the provider calls it, e.g. `Pending` or `processing`
+    pub status: String,
+    /// between 0 and 1
+    pub fraction: Option<f32>,
+    /// the unfinished image, encoded like the finished one
+    pub preview: Option<Vec<u8>>,
+}
+
+impl ImageProgress {
+    pub fn status(status: impl Into<String>) -> Self {
+        Self {
+            status: status.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Where an image model reports its progress. The default one drops the reports.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressReporter(Option<mpsc::UnboundedSender<ImageProgress>>);
+
+impl ProgressReporter {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<ImageProgress>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(Some(tx)), rx)
+    }
+
+    pub fn report(&self, progress: ImageProgress) {
+        if let Some(tx) = &self.0 {
+            _ = tx.send(progress);
+        }
+    }
+}
+
+/// Providers report the progress either from 0 to 1, or in percent
+fn progress_fraction(value: &Value) -> Option<f32> {
+    let x = value.as_f64()?;
+    let x = if x > 1.0 { x / 100.0 } else { x };
+    Some(x.clamp(0.0, 1.0) as f32)
+}
+
+/// Previews are sent base64 encoded, sometimes as data URL
+fn decode_preview(value: &Value) -> Option<Vec<u8>> {
+    let encoded = value.as_str()?;
+    let encoded = encoded
+        .split_once(";base64,")
+        .map_or(encoded, |(_, data)| data);
+    BASE64_STANDARD.decode(encoded).ok()
 }
 
 pub trait ImageModel {
+    /// The same description and `seed` give the same image again. Without a seed, a random one
+    /// is used, and returned with the image. Models without seeds ignore it.
+    /// Models that can tell how far along the image is report it to `progress`.
     fn get_image<'a>(
         &'a self,
         description: &'a str,
+        seed: Option<u64>,
+        progress: ProgressReporter,
     ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>>;
 
+    /// Fetches a finished generation again. Used to retry after an `ImageDownloadError`
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>>;
+
+    /// Whether the model can change an existing image, see [`ImageModel::edit_image`]
+    fn can_edit(&self) -> bool {
+        false
+    }
+
+    /// Changes `original` as the `instruction` says, e.g. "remove the second moon", and keeps
+    /// the rest of the image as it is. Models that can't edit return an error.
+    fn edit_image<'a>(
+        &'a self,
+        _original: &'a [u8],
+        _instruction: &'a str,
+        _progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        let model = self.provided_model();
+        Box::pin(async move { bail!("{model} can't edit images") })
+    }
+
     fn clone(&self) -> Box<dyn ImageModel + Send + 'static>;
     fn provided_model(&self) -> ProvidedModel;
 }
@@ -202,3 +412,38 @@ pub struct ModelStyle {
     pub prefix: String,
     pub postfix: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    use super::*;
+
+    #[test]
+    fn default_tolerances_are_in_range() {
+        for model in ProvidedModel::iter() {
+            match (
+                model.safety_tolerance_range(),
+                model.default_safety_tolerance(),
+            ) {
+                (Some(range), Some(default)) => assert!(range.contains(&default), "{model}"),
+                (None, None) => {}
+                _ => panic!("{model} has a range without a default, or the other way around"),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_progress_and_previews() {
+        assert_eq!(progress_fraction(&json!(0.25)), Some(0.25));
+        assert_eq!(progress_fraction(&json!(50)), Some(0.5));
+        assert_eq!(progress_fraction(&json!("50%")), None);
+
+        assert_eq!(decode_preview(&json!("aW1hZ2U=")).unwrap(), b"image");
+        assert_eq!(
+            decode_preview(&json!("data:image/jpeg;base64,aW1hZ2U=")).unwrap(),
+            b"image"
+        );
+        assert_eq!(decode_preview(&json!({"url": "https://example.com"})), None);
+    }
+}
@@ -0,0 +1,102 @@
+//! Downloads generated images. When this code runs, the generation has already been
+//! paid for, so failed downloads are retried, and the bytes are only accepted if they decode.
+
+use std::time::Duration;
+
+use color_eyre::{Result, eyre::eyre};
+use log::warn;
+use reqwest::Client;
+use tokio::time::sleep;
+
+const MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The generation succeeded, but the result couldn't be fetched. The URL usually stays valid
+/// for a while, so the download can be retried via `ImageModel::download` without
+/// generating the image again.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to download the generated image from {url} after {attempts} attempts: {reason}")]
+pub struct ImageDownloadError {
+    pub url: String,
+    pub attempts: u32,
+    pub reason: String,
+}
+
+/// Fetches `url` with the given extra headers, and retries with exponential backoff
+/// until the response is a decodable image.
+pub async fn download_image(
+    client: &Client,
+    url: &str,
+    headers: &[(&str, &str)],
+) -> Result<Vec<u8>, ImageDownloadError> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let err = match try_download(client, url, headers).await {
+            Ok(bytes) => return Ok(bytes),
+            Err(err) => err,
+        };
+
+        if attempt == MAX_ATTEMPTS {
+            return Err(ImageDownloadError {
+                url: url.into(),
+                attempts: attempt,
+                reason: format!("{err:#}"),
+            });
+        }
+
+        warn!("Image download attempt {attempt} failed, retrying in {backoff:?}: {err:#}");
+        sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+async fn try_download(client: &Client, url: &str, headers: &[(&str, &str)]) -> Result<Vec<u8>> {
+    let mut request = client.get(url);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let bytes = request
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?
+        .to_vec();
+    verify_image(&bytes)?;
+    Ok(bytes)
+}
+
+/// Makes sure the bytes are a complete image in a supported format
+pub fn verify_image(bytes: &[u8]) -> Result<()> {
+    image::load_from_memory(bytes).map_err(|e| eyre!("Received invalid image data: {e}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn png_bytes() -> Vec<u8> {
+        let img = image::RgbImage::from_pixel(4, 4, image::Rgb([200, 30, 30]));
+        let mut bytes = vec![];
+        img.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn accepts_complete_images() {
+        verify_image(&png_bytes()).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_images() {
+        let bytes = png_bytes();
+        assert!(verify_image(&bytes[..bytes.len() / 2]).is_err());
+        assert!(verify_image(b"<html>502 Bad Gateway</html>").is_err());
+    }
+}
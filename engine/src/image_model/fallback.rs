@@ -0,0 +1,185 @@
+//! A turn shouldn't end without an image because one provider is down, or its content filter
+//! is stricter than another one's. [`FallbackImageModel`] asks a second model when the first
+//! one fails.
+
+use std::{future::Future, pin::Pin};
+
+use color_eyre::Result;
+use log::warn;
+
+use crate::ImgModBox;
+
+use super::{Image, ImageModel, ProgressReporter, ProvidedModel};
+
+pub struct FallbackImageModel {
+    primary: ImgModBox,
+    fallback: ImgModBox,
+}
+
+impl FallbackImageModel {
+    pub fn new(primary: ImgModBox, fallback: ImgModBox) -> Self {
+        Self { primary, fallback }
+    }
+}
+
+impl ImageModel for FallbackImageModel {
+    fn get_image<'a>(
+        &'a self,
+        description: &'a str,
+        seed: Option<u64>,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        // the boxed models aren't `Sync`, so the future can't hold on to `self`
+        let (primary, fallback) = (self.primary.clone(), self.fallback.clone());
+        Box::pin(async move {
+            let primary_err = match primary.get_image(description, seed, progress.clone()).await {
+                Ok(image) => return Ok(image),
+                Err(e) => e,
+            };
+            let primary = primary.provided_model();
+            let fallback_model = fallback.provided_model();
+            warn!("{primary} failed, asking {fallback_model} for the image: {primary_err:#}");
+            // the seed of one model means nothing to another one
+            fallback
+                .get_image(description, None, progress)
+                .await
+                .map_err(|e| {
+                    e.wrap_err(format!(
+                        "{primary} failed with: {primary_err:#}\nThe fallback {fallback_model} failed, too"
+                    ))
+                })
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        // the url may be from either model
+        let (primary, fallback) = (self.primary.clone(), self.fallback.clone());
+        Box::pin(async move {
+            match primary.download(url).await {
+                Ok(bytes) => Ok(bytes),
+                Err(_) => fallback.download(url).await,
+            }
+        })
+    }
+
+    fn can_edit(&self) -> bool {
+        self.primary.can_edit() || self.fallback.can_edit()
+    }
+
+    /// Edits are asked for explicitly, so only the first model that can edit is asked
+    fn edit_image<'a>(
+        &'a self,
+        original: &'a [u8],
+        instruction: &'a str,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        if self.primary.can_edit() {
+            self.primary.edit_image(original, instruction, progress)
+        } else {
+            self.fallback.edit_image(original, instruction, progress)
+        }
+    }
+
+    fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
+        Box::new(Self::new(self.primary.clone(), self.fallback.clone()))
+    }
+
+    /// The primary model. Images of the fallback are attributed to it, too.
+    fn provided_model(&self) -> ProvidedModel {
+        self.primary.provided_model()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+    use crate::error::EngineError;
+
+    #[derive(Clone)]
+    struct FixedModel {
+        model: ProvidedModel,
+        moderated: bool,
+    }
+
+    impl ImageModel for FixedModel {
+        fn get_image<'a>(
+            &'a self,
+            _description: &'a str,
+            seed: Option<u64>,
+            _progress: ProgressReporter,
+        ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+            Box::pin(async move {
+                if self.moderated {
+                    return Err(EngineError::Moderated {
+                        message: format!("{}", self.model),
+                    }
+                    .into());
+                }
+                Ok(Image {
+                    data: vec![self.model as u8],
+                    cost: None,
+                    seed,
+                })
+            })
+        }
+
+        fn download<'a>(
+            &'a self,
+            _url: &'a str,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+            Box::pin(async { Err(eyre!("nothing to download")) })
+        }
+
+        fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
+            Box::new(Clone::clone(self))
+        }
+
+        fn provided_model(&self) -> ProvidedModel {
+            self.model
+        }
+    }
+
+    fn fallback_model(primary_moderated: bool, fallback_moderated: bool) -> FallbackImageModel {
+        FallbackImageModel::new(
+            Box::new(FixedModel {
+                model: ProvidedModel::Flux2BLF,
+                moderated: primary_moderated,
+            }),
+            Box::new(FixedModel {
+                model: ProvidedModel::PImagePruna,
+                moderated: fallback_moderated,
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn the_fallback_is_asked_when_the_primary_fails() {
+        let image = |model: FallbackImageModel| async move {
+            model
+                .get_image("a lighthouse", Some(7), ProgressReporter::default())
+                .await
+        };
+
+        let primary = image(fallback_model(false, true)).await.unwrap();
+        assert_eq!(primary.data, vec![ProvidedModel::Flux2BLF as u8]);
+        assert_eq!(primary.seed, Some(7));
+
+        let fallback = image(fallback_model(true, false)).await.unwrap();
+        assert_eq!(fallback.data, vec![ProvidedModel::PImagePruna as u8]);
+        assert_eq!(fallback.seed, None);
+
+        // the error of the fallback is kept, so a moderation can still be recognized
+        let Err(err) = image(fallback_model(true, true)).await else {
+            panic!("both models failed");
+        };
+        assert!(matches!(
+            err.downcast_ref::<EngineError>(),
+            Some(EngineError::Moderated { .. })
+        ));
+    }
+}
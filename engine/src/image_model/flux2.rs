@@ -1,50 +1,107 @@
-use std::pin::Pin;
+use std::{pin::Pin, sync::Arc};
 
 use color_eyre::{Result, eyre::Context};
 use log::debug;
 
-use crate::image_model::{Image, ImageModel};
+use crate::{
+    image_model::{Image, ImageModel, ProgressReporter, download::download_image},
+    rate_limit::{self, RateLimiter},
+};
 
-use super::ProvidedModel;
+use super::{ModelProvider, ProvidedModel};
 
 pub mod flux2_api;
 
 #[derive(Clone)]
 pub struct Flux2 {
     api_key: String,
+    /// from 0, the strictest, to 6
+    safety_tolerance: u8,
     client: reqwest::Client,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 impl Flux2 {
-    pub fn new(api_key: String) -> Self {
+    pub fn new(api_key: String, safety_tolerance: u8) -> Self {
         Self {
             api_key,
+            safety_tolerance,
             client: reqwest::Client::new(),
+            rate_limiter: rate_limit::shared(ModelProvider::BFL),
         }
     }
 }
 
-impl ImageModel for Flux2 {
-    fn get_image<'a>(
+impl Flux2 {
+    /// A new image, or an edit of `input_image`
+    fn generate<'a>(
         &'a self,
-        description: &'a str,
+        prompt: &'a str,
+        seed: Option<u64>,
+        input_image: Option<&'a [u8]>,
+        progress: ProgressReporter,
     ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
-        let resp_fut = flux2_api::query(description, &self.api_key, &self.client);
+        let resp_fut = flux2_api::query(
+            prompt,
+            seed,
+            input_image,
+            self.safety_tolerance,
+            &self.api_key,
+            &self.client,
+            &self.rate_limiter,
+        );
 
         Box::pin(async move {
             let response = resp_fut.await?;
             let cost = response.cost;
             debug!("Query response: {response:#?}");
-            let data =
-                flux2_api::poll_and_fetch(&response.polling_url, &self.api_key, &self.client)
-                    .await
-                    .with_context(|| format!("Image description:\n{description}"))?;
+            let (data, seed) = flux2_api::poll_and_fetch(
+                &response.polling_url,
+                &self.api_key,
+                &self.client,
+                &self.rate_limiter,
+                &progress,
+            )
+            .await
+            .with_context(|| format!("Image description:\n{prompt}"))?;
             Ok(Image {
                 data,
                 cost: Some(cost),
+                seed: Some(seed),
             })
         })
     }
+}
+
+impl ImageModel for Flux2 {
+    fn get_image<'a>(
+        &'a self,
+        description: &'a str,
+        seed: Option<u64>,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        self.generate(description, seed, None, progress)
+    }
+
+    fn can_edit(&self) -> bool {
+        true
+    }
+
+    fn edit_image<'a>(
+        &'a self,
+        original: &'a [u8],
+        instruction: &'a str,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        self.generate(instruction, None, Some(original), progress)
+    }
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move { Ok(download_image(&self.client, url, &[]).await?) })
+    }
 
     fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
         Box::new(Clone::clone(self))
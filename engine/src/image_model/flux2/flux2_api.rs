@@ -1,12 +1,22 @@
+use base64::{Engine as _, prelude::BASE64_STANDARD};
 use color_eyre::{
     Result,
-    eyre::{bail, ensure, eyre},
+    eyre::{bail, eyre},
 };
 use serde::Deserialize;
 use serde_json::Value;
 use std::time::Duration;
 use tokio::time::sleep;
 
+use crate::{
+    error::EngineError,
+    image_model::{
+        ImageProgress, ProgressReporter, decode_preview, download::download_image,
+        progress_fraction,
+    },
+    rate_limit::RateLimiter,
+};
+
 #[derive(Debug, Deserialize)]
 pub struct StartResponse {
     pub id: String,
@@ -34,16 +44,21 @@ pub struct PollResult {
     pub sample: String, // URL to the generated image
 }
 
-/// Starts a FLUX.2 Pro text-to-image job and returns the StartResponse
-pub async fn query(prompt: &str, api_key: &str, client: &reqwest::Client) -> Result<StartResponse> {
-    let payload = serde_json::json!({
-        "prompt": prompt,
-        "model": "flux-2-pro",
-        "width": 832,
-        "height": 1216,
-        "safety_tolerance": 5,
-    });
+/// Starts a FLUX.2 Pro job and returns the StartResponse. With an `input_image`, the prompt
+/// says how to change it, otherwise a new image is made.
+/// Without a seed, BFL picks one, and reports it in the [`PollResult`].
+pub async fn query(
+    prompt: &str,
+    seed: Option<u64>,
+    input_image: Option<&[u8]>,
+    safety_tolerance: u8,
+    api_key: &str,
+    client: &reqwest::Client,
+    rate_limiter: &RateLimiter,
+) -> Result<StartResponse> {
+    let payload = payload(prompt, seed, input_image, safety_tolerance);
 
+    rate_limiter.acquire().await;
     let resp = client
         .post("https://api.bfl.ai/v1/flux-2-pro")
         .header("accept", "application/json")
@@ -56,23 +71,49 @@ pub async fn query(prompt: &str, api_key: &str, client: &reqwest::Client) -> Res
     let status = resp.status();
     let text = resp.text().await?;
 
-    ensure!(
-        status.is_success(),
-        "Failed to start Flux2 job: {} - {}",
-        status,
-        text
-    );
+    if !status.is_success() {
+        return Err(EngineError::from_status("BFL", status, &text).into());
+    }
 
     Ok(serde_json::from_str(&text)?)
 }
 
-/// Polls a FLUX.2 Pro job until it's ready, then fetches the resulting image bytes
+fn payload(
+    prompt: &str,
+    seed: Option<u64>,
+    input_image: Option<&[u8]>,
+    safety_tolerance: u8,
+) -> Value {
+    let mut payload = serde_json::json!({
+        "prompt": prompt,
+        "model": "flux-2-pro",
+        "safety_tolerance": safety_tolerance,
+    });
+    match input_image {
+        // without a size, the edited image keeps the one of the input
+        Some(image) => payload["input_image"] = BASE64_STANDARD.encode(image).into(),
+        None => {
+            payload["width"] = 832.into();
+            payload["height"] = 1216.into();
+        }
+    }
+    if let Some(seed) = seed {
+        payload["seed"] = seed.into();
+    }
+    payload
+}
+
+/// Polls a FLUX.2 Pro job until it's ready, then fetches the resulting image bytes.
+/// Returns them with the seed of the image.
 pub async fn poll_and_fetch(
     polling_url: &str,
     api_key: &str,
     client: &reqwest::Client,
-) -> Result<Vec<u8>> {
+    rate_limiter: &RateLimiter,
+    progress: &ProgressReporter,
+) -> Result<(Vec<u8>, u64)> {
     loop {
+        rate_limiter.acquire().await;
         let resp = client
             .get(polling_url)
             .header("accept", "application/json")
@@ -83,25 +124,51 @@ pub async fn poll_and_fetch(
         if !resp.status().is_success() {
             let status = resp.status();
             let body = resp.text().await?;
-            bail!("Flux2 poll failed {}: {}", status, body);
+            return Err(EngineError::from_status("BFL", status, &body).into());
         }
 
         let poll: PollResponse = resp.json().await?;
+        progress.report(ImageProgress {
+            status: poll.status.clone(),
+            fraction: poll.progress.as_ref().and_then(progress_fraction),
+            preview: poll.preview.as_ref().and_then(decode_preview),
+        });
 
         match poll.status.as_str() {
             "Ready" => {
-                let url = &poll
+                let result = poll
                     .result
                     .as_ref()
-                    .ok_or(eyre!("Missing result field:\n{poll:#?}"))?
-                    .sample;
-                let resp = client.get(url).send().await?;
-                let bytes = resp.bytes().await?.to_vec();
-                return Ok(bytes);
+                    .ok_or(eyre!("Missing result field:\n{poll:#?}"))?;
+                let data = download_image(client, &result.sample, &[]).await?;
+                return Ok((data, result.seed));
+            }
+            "Request Moderated" => {
+                return Err(EngineError::Moderated {
+                    message: "Flux2 refused to generate the image".into(),
+                }
+                .into());
             }
-            "Request Moderated" => bail!("Request moderated"),
             "Error" => bail!("Flux2 job failed:\n{poll:#?}"),
             _ => sleep(Duration::from_secs(1)).await,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_send_the_image_instead_of_a_size() {
+        let new = payload("a lighthouse", Some(3), None, 2);
+        assert_eq!(new["width"], 832);
+        assert_eq!(new["seed"], 3);
+        assert!(new.get("input_image").is_none());
+
+        let edit = payload("make it night", None, Some(b"image"), 2);
+        assert_eq!(edit["input_image"], "aW1hZ2U=");
+        assert!(edit.get("width").is_none());
+        assert!(edit.get("seed").is_none());
+    }
+}
@@ -0,0 +1,429 @@
+//! Stable Diffusion on the user's own machine, via the HTTP API of Automatic1111 (or one of
+//! its forks, like Forge) or ComfyUI. The images are free then, and nothing leaves the machine.
+
+use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use color_eyre::{Result, eyre::eyre};
+use log::debug;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use strum::{Display, EnumIter};
+use tokio::{pin, select, time::sleep};
+
+use crate::{
+    ImgModBox,
+    error::EngineError,
+    rate_limit::{self, RateLimiter},
+};
+
+use super::{
+    Image, ImageModel, ImageProgress, ModelProvider, ProgressReporter, ProvidedModel,
+    download::download_image, random_seed,
+};
+
+const WIDTH: u32 = 832;
+const HEIGHT: u32 = 1216;
+/// ComfyUI has no default sampler, this is the one of its example workflow
+const COMFY_DEFAULT_SAMPLER: &str = "euler";
+
+#[derive(Debug, Clone, Copy, Default, Display, Serialize, Deserialize, PartialEq, Eq, EnumIter)]
+pub enum LocalApi {
+    #[default]
+    Automatic1111,
+    ComfyUI,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct LocalSettings {
+    pub api: LocalApi,
+    /// e.g. `http://127.0.0.1:7860` for Automatic1111, or `http://127.0.0.1:8188` for ComfyUI
+    pub url: String,
+    /// the file name of the checkpoint. ComfyUI needs one, Automatic1111 uses the loaded
+    /// checkpoint if it's empty
+    pub checkpoint: String,
+    /// the name as the API knows it, e.g. `Euler a` for Automatic1111 or `euler_ancestral`
+    /// for ComfyUI. Empty means the default.
+    pub sampler: String,
+    pub steps: u32,
+}
+
+impl Default for LocalSettings {
+    fn default() -> Self {
+        Self {
+            api: LocalApi::Automatic1111,
+            url: "http://127.0.0.1:7860".into(),
+            checkpoint: String::new(),
+            sampler: String::new(),
+            steps: 25,
+        }
+    }
+}
+
+impl LocalSettings {
+    pub fn make(&self) -> ImgModBox {
+        Box::new(LocalImageModel {
+            settings: self.clone(),
+            client: Client::new(),
+            rate_limiter: rate_limit::shared(ModelProvider::Local),
+        })
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}/{path}", self.url.trim().trim_end_matches('/'))
+    }
+
+    fn a1111_request(&self, prompt: &str, seed: u64) -> Value {
+        let mut req = json!({
+            "prompt": prompt,
+            "seed": seed,
+            "steps": self.steps,
+            "width": WIDTH,
+            "height": HEIGHT,
+        });
+        if !self.sampler.trim().is_empty() {
+            req["sampler_name"] = self.sampler.trim().into();
+        }
+        if !self.checkpoint.trim().is_empty() {
+            req["override_settings"] = json!({ "sd_model_checkpoint": self.checkpoint.trim() });
+        }
+        req
+    }
+
+    /// A plain text to image workflow, like ComfyUI's default one
+    fn comfy_workflow(&self, prompt: &str, seed: u64) -> Value {
+        let sampler = match self.sampler.trim() {
+            "" => COMFY_DEFAULT_SAMPLER,
+            sampler => sampler,
+        };
+        json!({
+            "checkpoint": {
+                "class_type": "CheckpointLoaderSimple",
+                "inputs": { "ckpt_name": self.checkpoint.trim() },
+            },
+            "latent": {
+                "class_type": "EmptyLatentImage",
+                "inputs": { "width": WIDTH, "height": HEIGHT, "batch_size": 1 },
+            },
+            "positive": {
+                "class_type": "CLIPTextEncode",
+                "inputs": { "text": prompt, "clip": ["checkpoint", 1] },
+            },
+            "negative": {
+                "class_type": "CLIPTextEncode",
+                "inputs": { "text": "", "clip": ["checkpoint", 1] },
+            },
+            "sampler": {
+                "class_type": "KSampler",
+                "inputs": {
+                    "seed": seed,
+                    "steps": self.steps,
+                    "cfg": 7,
+                    "sampler_name": sampler,
+                    "scheduler": "normal",
+                    "denoise": 1,
+                    "model": ["checkpoint", 0],
+                    "positive": ["positive", 0],
+                    "negative": ["negative", 0],
+                    "latent_image": ["latent", 0],
+                },
+            },
+            "decode": {
+                "class_type": "VAEDecode",
+                "inputs": { "samples": ["sampler", 0], "vae": ["checkpoint", 2] },
+            },
+            "save": {
+                "class_type": "SaveImage",
+                "inputs": { "filename_prefix": "world_weaver", "images": ["decode", 0] },
+            },
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct LocalImageModel {
+    settings: LocalSettings,
+    client: Client,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+#[derive(Debug, Deserialize)]
+struct A1111Response {
+    images: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct A1111Progress {
+    /// from 0 to 1
+    progress: f32,
+    /// base64 encoded, once the first steps are done
+    current_image: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComfyQueued {
+    prompt_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComfyHistoryEntry {
+    #[serde(default)]
+    outputs: BTreeMap<String, ComfyOutput>,
+    status: ComfyStatus,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComfyOutput {
+    #[serde(default)]
+    images: Vec<ComfyImage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComfyImage {
+    filename: String,
+    subfolder: String,
+    #[serde(rename = "type")]
+    folder_type: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComfyStatus {
+    status_str: String,
+    completed: bool,
+}
+
+impl LocalImageModel {
+    async fn post<T: DeserializeOwned>(&self, path: &str, body: &Value) -> Result<T> {
+        self.rate_limiter.acquire().await;
+        let res = self
+            .client
+            .post(self.settings.endpoint(path))
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| self.unreachable(e))?;
+        let status = res.status();
+        let body = res.text().await?;
+        if !status.is_success() {
+            return Err(EngineError::from_status(&self.provider_name(), status, &body).into());
+        }
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    async fn get_a1111_image(
+        &self,
+        description: &str,
+        seed: u64,
+        progress: &ProgressReporter,
+    ) -> Result<Vec<u8>> {
+        let body = self.settings.a1111_request(description, seed);
+        let request = self.post::<A1111Response>("sdapi/v1/txt2img", &body);
+        pin!(request);
+        // txt2img only answers when it's done, the progress is asked for in the meantime
+        let res = loop {
+            select! {
+                res = &mut request => break res?,
+                _ = sleep(Duration::from_secs(1)) => {
+                    match self.get_a1111_progress().await {
+                        Ok(p) => progress.report(p),
+                        Err(e) => debug!("Couldn't get the progress from Automatic1111: {e}"),
+                    }
+                }
+            }
+        };
+        let image = res
+            .images
+            .first()
+            .ok_or_else(|| eyre!("Automatic1111 answered without an image"))?;
+        Ok(BASE64_STANDARD.decode(image)?)
+    }
+
+    async fn get_a1111_progress(&self) -> Result<ImageProgress> {
+        self.rate_limiter.acquire().await;
+        let res: A1111Progress = self
+            .client
+            .get(self.settings.endpoint("sdapi/v1/progress"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(ImageProgress {
+            status: "generating".into(),
+            fraction: Some(res.progress.clamp(0.0, 1.0)),
+            preview: res
+                .current_image
+                .and_then(|image| BASE64_STANDARD.decode(image).ok()),
+        })
+    }
+
+    async fn get_comfy_image(
+        &self,
+        description: &str,
+        seed: u64,
+        progress: &ProgressReporter,
+    ) -> Result<Vec<u8>> {
+        let queued: ComfyQueued = self
+            .post(
+                "prompt",
+                &json!({ "prompt": self.settings.comfy_workflow(description, seed) }),
+            )
+            .await?;
+        // the history only tells when it's done
+        progress.report(ImageProgress::status("queued"));
+
+        let history_url = self
+            .settings
+            .endpoint(&format!("history/{}", queued.prompt_id));
+        let image = loop {
+            sleep(Duration::from_secs(1)).await;
+            self.rate_limiter.acquire().await;
+            let history: BTreeMap<String, ComfyHistoryEntry> = self
+                .client
+                .get(&history_url)
+                .send()
+                .await
+                .map_err(|e| self.unreachable(e))?
+                .error_for_status()?
+                .json()
+                .await?;
+            // the prompt only shows up in the history once it's done
+            if let Some(entry) = history.into_values().next() {
+                break finished_image(entry)?;
+            }
+        };
+        let url = self.view_url(&image)?;
+        Ok(download_image(&self.client, &url, &[]).await?)
+    }
+
+    fn view_url(&self, image: &ComfyImage) -> Result<String> {
+        let url = Url::parse_with_params(
+            &self.settings.endpoint("view"),
+            [
+                ("filename", &image.filename),
+                ("subfolder", &image.subfolder),
+                ("type", &image.folder_type),
+            ],
+        )?;
+        Ok(url.into())
+    }
+
+    fn provider_name(&self) -> String {
+        self.settings.api.to_string()
+    }
+
+    /// Connection errors usually mean the server isn't running, which is worth saying
+    fn unreachable(&self, err: reqwest::Error) -> color_eyre::Report {
+        eyre!(
+            "{} isn't reachable at {}, is it running? {err}",
+            self.provider_name(),
+            self.settings.url
+        )
+    }
+}
+
+fn finished_image(entry: ComfyHistoryEntry) -> Result<ComfyImage> {
+    if !entry.status.completed {
+        return Err(eyre!(
+            "ComfyUI couldn't run the workflow: {}",
+            entry.status.status_str
+        ));
+    }
+    entry
+        .outputs
+        .into_values()
+        .flat_map(|output| output.images)
+        .next()
+        .ok_or_else(|| eyre!("The ComfyUI workflow finished without an image"))
+}
+
+impl ImageModel for LocalImageModel {
+    fn get_image<'a>(
+        &'a self,
+        description: &'a str,
+        seed: Option<u64>,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        Box::pin(async move {
+            let seed = seed.unwrap_or_else(random_seed);
+            let data = match self.settings.api {
+                LocalApi::Automatic1111 => {
+                    self.get_a1111_image(description, seed, &progress).await?
+                }
+                LocalApi::ComfyUI => self.get_comfy_image(description, seed, &progress).await?,
+            };
+            Ok(Image {
+                data,
+                cost: Some(0.0),
+                seed: Some(seed),
+            })
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move { Ok(download_image(&self.client, url, &[]).await?) })
+    }
+
+    fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn provided_model(&self) -> ProvidedModel {
+        ProvidedModel::StableDiffusionLocal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_image_of_a_comfy_workflow() {
+        let history = r#"{"outputs":{"save":{"images":[{"filename":"world_weaver_00001_.png",
+            "subfolder":"","type":"output"}]}},"status":{"status_str":"success","completed":true}}"#;
+        let image = finished_image(serde_json::from_str(history).unwrap()).unwrap();
+        let model = LocalImageModel {
+            settings: LocalSettings {
+                api: LocalApi::ComfyUI,
+                url: "http://127.0.0.1:8188/".into(),
+                ..Default::default()
+            },
+            client: Client::new(),
+            rate_limiter: rate_limit::shared(ModelProvider::Local),
+        };
+        assert_eq!(
+            model.view_url(&image).unwrap(),
+            "http://127.0.0.1:8188/view?filename=world_weaver_00001_.png&subfolder=&type=output"
+        );
+
+        let failed = r#"{"outputs":{},"status":{"status_str":"error","completed":false}}"#;
+        assert!(finished_image(serde_json::from_str(failed).unwrap()).is_err());
+    }
+
+    #[test]
+    fn only_set_options_are_sent_to_automatic1111() {
+        let settings = LocalSettings::default();
+        let req = settings.a1111_request("a lighthouse", 7);
+        assert_eq!(req["steps"], 25);
+        assert_eq!(req["seed"], 7);
+        assert!(req.get("sampler_name").is_none());
+        assert!(req.get("override_settings").is_none());
+
+        let settings = LocalSettings {
+            sampler: "Euler a".into(),
+            checkpoint: "dreamshaper_8.safetensors".into(),
+            ..settings
+        };
+        let req = settings.a1111_request("a lighthouse", 7);
+        assert_eq!(req["sampler_name"], "Euler a");
+        assert_eq!(
+            req["override_settings"]["sd_model_checkpoint"],
+            "dreamshaper_8.safetensors"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Not every image has to be paid for. [`ManualImageModel`] doesn't call an API, it asks the
+//! player for an image file instead, e.g. for prepared art of a custom campaign. The GUI
+//! receives the requests via [`requests`], and answers each of them with
+//! [`ManualRequest::supply`] or [`ManualRequest::decline`].
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, LazyLock},
+};
+
+use async_stream::stream;
+use color_eyre::{
+    Result,
+    eyre::{bail, ensure, eyre},
+};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio_stream::Stream;
+
+use super::{Image, ImageFormat, ImageModel, ImageProgress, ProgressReporter, ProvidedModel};
+
+/// An image the player is asked for. Dropping it counts as declining.
+#[derive(Debug)]
+pub struct ManualRequest {
+    /// what the image should show, as the LLM described it
+    pub description: String,
+    reply: oneshot::Sender<Option<Vec<u8>>>,
+}
+
+impl ManualRequest {
+    pub fn supply(self, bytes: Vec<u8>) {
+        // the turn may be gone already, then nobody waits for the image
+        let _ = self.reply.send(Some(bytes));
+    }
+
+    pub fn decline(self) {
+        let _ = self.reply.send(None);
+    }
+}
+
+struct Channel {
+    sender: mpsc::UnboundedSender<ManualRequest>,
+    receiver: Arc<Mutex<mpsc::UnboundedReceiver<ManualRequest>>>,
+}
+
+static CHANNEL: LazyLock<Channel> = LazyLock::new(|| {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    Channel {
+        sender,
+        receiver: Arc::new(Mutex::new(receiver)),
+    }
+});
+
+/// Only one file is asked for at a time, so the player never sees two file pickers at once
+static ASKING: LazyLock<Mutex<()>> = LazyLock::new(Default::default);
+
+/// Yields the requests of every [`ManualImageModel`]. There should be only one consumer, a
+/// second one waits until the first one is dropped.
+pub fn requests() -> impl Stream<Item = ManualRequest> + Send + use<> {
+    let receiver = CHANNEL.receiver.clone();
+    stream! {
+        let mut receiver = receiver.lock().await;
+        while let Some(request) = receiver.recv().await {
+            yield request;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ManualImageModel;
+
+impl ManualImageModel {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl ImageModel for ManualImageModel {
+    fn get_image<'a>(
+        &'a self,
+        description: &'a str,
+        _seed: Option<u64>,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        Box::pin(async move {
+            let _asking = ASKING.lock().await;
+            progress.report(ImageProgress::status("waiting for a file"));
+            let (reply, answer) = oneshot::channel();
+            CHANNEL
+                .sender
+                .send(ManualRequest {
+                    description: description.to_string(),
+                    reply,
+                })
+                .map_err(|_| eyre!("Nothing asks for image files"))?;
+            let data = answer
+                .await
+                .ok()
+                .flatten()
+                .ok_or(eyre!("No image file was picked"))?;
+            ensure!(
+                ImageFormat::detect(&data).is_some(),
+                "The picked file is no JPEG, PNG or WebP image"
+            );
+            Ok(Image {
+                data,
+                cost: None,
+                seed: None,
+            })
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move { bail!("Picked images aren't downloaded: {url}") })
+    }
+
+    fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn provided_model(&self) -> ProvidedModel {
+        ProvidedModel::ManualImage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn the_picked_file_becomes_the_image() {
+        let mut png = vec![];
+        image::RgbImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let mut requests = Box::pin(requests());
+        let ask = |description: &'static str| {
+            tokio::spawn(async move {
+                ManualImageModel::new()
+                    .get_image(description, Some(3), ProgressReporter::default())
+                    .await
+            })
+        };
+
+        let image = ask("a lighthouse");
+        let request = requests.next().await.unwrap();
+        assert_eq!(request.description, "a lighthouse");
+        request.supply(png.clone());
+        let image = image.await.unwrap().unwrap();
+        assert_eq!(image.data, png);
+        assert_eq!(image.seed, None);
+
+        let declined = ask("a harbor");
+        requests.next().await.unwrap().decline();
+        assert!(declined.await.unwrap().is_err());
+
+        let not_an_image = ask("a ship");
+        requests.next().await.unwrap().supply(vec![1, 2, 3]);
+        assert!(not_an_image.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! OpenAI's image API. It answers with the image itself, base64 encoded, so there's nothing
+//! to poll or download.
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use color_eyre::{Result, eyre::eyre};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::{
+    error::EngineError,
+    rate_limit::{self, RateLimiter},
+};
+
+use super::{Image, ImageModel, ModelProvider, ProgressReporter, ProvidedModel};
+
+const URL: &str = "https://api.openai.com/v1/images/generations";
+
+/// gpt-image-1 is billed by tokens, in dollars per million
+const GPT_IMAGE_TEXT_INPUT_PRICE: f64 = 5.0;
+const GPT_IMAGE_IMAGE_INPUT_PRICE: f64 = 10.0;
+const GPT_IMAGE_OUTPUT_PRICE: f64 = 40.0;
+/// DALL·E 3 is billed per image, this is the standard quality in portrait format
+const DALL_E_3_PRICE: f64 = 0.08;
+
+#[derive(Clone)]
+pub struct OpenAIImageModel {
+    model: ProvidedModel,
+    api_key: String,
+    /// 1 sets gpt-image-1's moderation to `low`, DALL·E 3 has no such setting
+    safety_tolerance: u8,
+    client: Client,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    data: Vec<ImageData>,
+    /// only gpt-image-1 reports it
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageData {
+    b64_json: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Usage {
+    output_tokens: usize,
+    #[serde(default)]
+    input_tokens_details: InputTokensDetails,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct InputTokensDetails {
+    text_tokens: usize,
+    image_tokens: usize,
+}
+
+impl OpenAIImageModel {
+    pub fn new(model: ProvidedModel, api_key: String, safety_tolerance: u8) -> Self {
+        Self {
+            model,
+            api_key,
+            safety_tolerance,
+            client: Client::new(),
+            rate_limiter: rate_limit::shared(ModelProvider::OpenAI),
+        }
+    }
+
+    fn request_body(&self, prompt: &str) -> Value {
+        match self.model {
+            ProvidedModel::DallE3OpenAI => json!({
+                "model": "dall-e-3",
+                "prompt": prompt,
+                "size": "1024x1792",
+                "quality": "standard",
+                "response_format": "b64_json",
+            }),
+            _ => json!({
+                "model": "gpt-image-1",
+                "prompt": prompt,
+                "size": "1024x1536",
+                "quality": "medium",
+                "output_format": "jpeg",
+                "moderation": if self.safety_tolerance > 0 { "low" } else { "auto" },
+            }),
+        }
+    }
+
+    fn cost(&self, usage: Option<&Usage>) -> Option<f64> {
+        match self.model {
+            ProvidedModel::DallE3OpenAI => Some(DALL_E_3_PRICE),
+            _ => usage.map(|usage| {
+                let details = &usage.input_tokens_details;
+                (details.text_tokens as f64 * GPT_IMAGE_TEXT_INPUT_PRICE
+                    + details.image_tokens as f64 * GPT_IMAGE_IMAGE_INPUT_PRICE
+                    + usage.output_tokens as f64 * GPT_IMAGE_OUTPUT_PRICE)
+                    / 1_000_000.0
+            }),
+        }
+    }
+
+    fn parse_response(&self, body: &str) -> Result<Image> {
+        let response: Response = serde_json::from_str(body)?;
+        let image = response
+            .data
+            .first()
+            .ok_or_else(|| eyre!("OpenAI answered without an image"))?;
+        Ok(Image {
+            data: BASE64_STANDARD.decode(&image.b64_json)?,
+            cost: self.cost(response.usage.as_ref()),
+            seed: None,
+        })
+    }
+}
+
+impl ImageModel for OpenAIImageModel {
+    fn get_image<'a>(
+        &'a self,
+        description: &'a str,
+        // neither model has seeds
+        _seed: Option<u64>,
+        // the image comes with the response, there's nothing in between
+        _progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        Box::pin(async move {
+            self.rate_limiter.acquire().await;
+            let res = self
+                .client
+                .post(URL)
+                .bearer_auth(&self.api_key)
+                .json(&self.request_body(description))
+                .send()
+                .await?;
+            let status = res.status();
+            let body = res.text().await?;
+            if status == StatusCode::BAD_REQUEST && body.contains("moderation_blocked") {
+                return Err(EngineError::Moderated { message: body }.into());
+            }
+            if !status.is_success() {
+                return Err(EngineError::from_status("OpenAI", status, &body).into());
+            }
+            self.parse_response(&body)
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        _url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async {
+            Err(eyre!(
+                "OpenAI sends the images with the response, there is nothing to download"
+            ))
+        })
+    }
+
+    fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
+        Box::new(Clone::clone(self))
+    }
+
+    fn provided_model(&self) -> ProvidedModel {
+        self.model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_are_decoded_and_priced() {
+        let gpt_image = OpenAIImageModel::new(ProvidedModel::GptImage1OpenAI, "key".into(), 1);
+        let body = r#"{"created":1,"data":[{"b64_json":"aW1hZ2U="}],"usage":{"total_tokens":1100,
+            "input_tokens":100,"output_tokens":1000,
+            "input_tokens_details":{"text_tokens":100,"image_tokens":0}}}"#;
+        let image = gpt_image.parse_response(body).unwrap();
+        assert_eq!(image.data, b"image");
+        let cost = image.cost.unwrap();
+        assert!((cost - 0.0405).abs() < 1e-9, "{cost}");
+
+        let dall_e = OpenAIImageModel::new(ProvidedModel::DallE3OpenAI, "key".into(), 0);
+        let body = r#"{"created":1,"data":[{"b64_json":"aW1hZ2U=","revised_prompt":"an image"}]}"#;
+        assert_eq!(
+            dall_e.parse_response(body).unwrap().cost,
+            Some(DALL_E_3_PRICE)
+        );
+    }
+}
@@ -1,16 +1,20 @@
 use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
 
-use color_eyre::{
-    Result,
-    eyre::{ensure, eyre},
-};
+use color_eyre::{Result, eyre::eyre};
 use reqwest::Client;
 use serde::Deserialize;
 use tokio::time::sleep;
 
-use crate::{ImageModel, image_model::ProvidedModel};
+use crate::{
+    ImageModel,
+    error::EngineError,
+    image_model::ProvidedModel,
+    rate_limit::{self, RateLimiter},
+};
 
-use super::Image;
+use super::{
+    Image, ImageProgress, InputBuilder, ProgressReporter, download::download_image, random_seed,
+};
 
 #[derive(Clone)]
 pub struct PrunaImageModel {
@@ -19,7 +23,8 @@ pub struct PrunaImageModel {
     model_id: String,
     client: Client,
     api_key: String,
-    input_builder: Arc<dyn Fn(&str) -> serde_json::Value + Send + Sync>,
+    input_builder: InputBuilder,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 impl PrunaImageModel {
@@ -28,7 +33,7 @@ impl PrunaImageModel {
         model: ProvidedModel,
         model_id: String,
         api_key: String,
-        input_builder: impl Fn(&str) -> serde_json::Value + Send + Sync + 'static,
+        input_builder: impl Fn(&str, u64) -> serde_json::Value + Send + Sync + 'static,
     ) -> Self {
         Self {
             url,
@@ -37,6 +42,7 @@ impl PrunaImageModel {
             client: Client::new(),
             api_key,
             input_builder: Arc::new(input_builder),
+            rate_limiter: rate_limit::shared(model.provider()),
         }
     }
 }
@@ -66,12 +72,16 @@ impl ImageModel for PrunaImageModel {
     fn get_image<'a>(
         &'a self,
         description: &'a str,
+        seed: Option<u64>,
+        progress: ProgressReporter,
     ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
         Box::pin(async move {
+            let seed = seed.unwrap_or_else(random_seed);
             let req_body = serde_json::json!({
-                "input": (self.input_builder)(description),
+                "input": (self.input_builder)(description, seed),
             });
 
+            self.rate_limiter.acquire().await;
             let create_resp = self
                 .client
                 .post(&self.url)
@@ -84,10 +94,9 @@ impl ImageModel for PrunaImageModel {
 
             let status = create_resp.status();
             let body = create_resp.text().await?;
-            ensure!(
-                status.is_success(),
-                "Pruna prediction request error: {status} - {body}"
-            );
+            if !status.is_success() {
+                return Err(EngineError::from_status("Pruna", status, &body).into());
+            }
 
             if let Ok(sync_resp) = serde_json::from_str::<SyncPredictionResponse>(&body)
             {
@@ -97,7 +106,7 @@ impl ImageModel for PrunaImageModel {
                             .generation_url
                             .ok_or_else(|| eyre!("Pruna sync response missing generation_url"))?;
                         let data = fetch_image_bytes(&self.client, &self.api_key, &url).await?;
-                        return Ok(Image { data, cost: None });
+                        return Ok(Image { data, cost: None, seed: Some(seed) });
                     }
                     "failed" | "canceled" => {
                         return Err(eyre!(
@@ -117,6 +126,7 @@ impl ImageModel for PrunaImageModel {
             let prediction = serde_json::from_str::<AsyncPredictionResponse>(&body)?;
 
             loop {
+                self.rate_limiter.acquire().await;
                 let resp = self
                     .client
                     .get(&prediction.get_url)
@@ -126,6 +136,8 @@ impl ImageModel for PrunaImageModel {
                     .error_for_status()?
                     .json::<PredictionStatusResponse>()
                     .await?;
+                // Pruna only tells the status
+                progress.report(ImageProgress::status(&resp.status));
 
                 match resp.status.as_str() {
                     "succeeded" => {
@@ -133,7 +145,7 @@ impl ImageModel for PrunaImageModel {
                             .generation_url
                             .ok_or_else(|| eyre!("Pruna prediction succeeded without generation_url"))?;
                         let data = fetch_image_bytes(&self.client, &self.api_key, &url).await?;
-                        return Ok(Image { data, cost: None });
+                        return Ok(Image { data, cost: None, seed: Some(seed) });
                     }
                     "failed" | "canceled" => {
                         return Err(eyre!(
@@ -154,6 +166,13 @@ impl ImageModel for PrunaImageModel {
         })
     }
 
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(fetch_image_bytes(&self.client, &self.api_key, url))
+    }
+
     fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
         Box::new(Clone::clone(self))
     }
@@ -170,13 +189,5 @@ async fn fetch_image_bytes(client: &Client, api_key: &str, url: &str) -> Result<
         format!("https://api.pruna.ai{url}")
     };
 
-    Ok(client
-        .get(url)
-        .header("apikey", api_key)
-        .send()
-        .await?
-        .error_for_status()?
-        .bytes()
-        .await?
-        .to_vec())
+    Ok(download_image(client, &url, &[("apikey", api_key)]).await?)
 }
@@ -0,0 +1,398 @@
+//! The image of the next turn, its variants, regenerations and edits are all requested
+//! independently, and without coordination they would stampede the provider. Therefore every
+//! image is a job of one process wide [`ImageQueue`], which runs only a few of them at a time,
+//! in the order they were requested, and retries those that fail with a transient error.
+//! The GUI follows the jobs via [`ImageQueue::updates`].
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use async_stream::stream;
+use color_eyre::{Report, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::sleep};
+use tokio_stream::Stream;
+
+use crate::{ImgModBox, error::EngineError, llm::RetryPolicy};
+
+use super::{Image, ImageModel, ProgressReporter, ProvidedModel};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct QueueSettings {
+    /// how many images are made at the same time
+    pub max_concurrent: usize,
+    pub retry: RetryPolicy,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 2,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Waiting,
+    Running {
+        attempt: u32,
+    },
+    /// the attempt failed, and the next one starts after a delay
+    Retrying {
+        attempt: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobStatus {
+    pub id: u64,
+    pub model: ProvidedModel,
+    pub state: JobState,
+}
+
+#[derive(Debug)]
+pub struct ImageQueue {
+    settings: Mutex<QueueSettings>,
+    /// the jobs that aren't finished, in the order they were requested
+    jobs: watch::Sender<Vec<JobStatus>>,
+    next_id: Mutex<u64>,
+}
+
+static QUEUE: LazyLock<Arc<ImageQueue>> =
+    LazyLock::new(|| Arc::new(ImageQueue::new(QueueSettings::default())));
+
+/// Returns the queue that is shared by all image models
+pub fn shared() -> Arc<ImageQueue> {
+    QUEUE.clone()
+}
+
+/// Changes the settings of the shared queue. Running jobs keep running, even if there are
+/// more of them than the new limit allows.
+pub fn configure(settings: QueueSettings) {
+    shared().set_settings(settings);
+}
+
+/// Returns an image model that behaves like `model`, but whose images are jobs of the
+/// shared queue
+pub fn queued(model: ImgModBox) -> ImgModBox {
+    Box::new(Queued {
+        inner: model,
+        queue: shared(),
+    })
+}
+
+impl ImageQueue {
+    pub fn new(settings: QueueSettings) -> Self {
+        Self {
+            settings: Mutex::new(settings),
+            jobs: watch::Sender::new(vec![]),
+            next_id: Mutex::new(0),
+        }
+    }
+
+    pub fn set_settings(&self, settings: QueueSettings) {
+        *self.settings.lock().unwrap() = settings;
+        // waiting jobs check whether they may start now
+        self.jobs.send_modify(|_| {});
+    }
+
+    pub fn jobs(&self) -> Vec<JobStatus> {
+        self.jobs.borrow().clone()
+    }
+
+    /// Yields the unfinished jobs now, and again whenever they changed
+    pub fn updates(&self) -> impl Stream<Item = Vec<JobStatus>> + Send + use<> {
+        let mut jobs = self.jobs.subscribe();
+        stream! {
+            loop {
+                let current = jobs.borrow_and_update().clone();
+                yield current;
+                if jobs.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Waits for a free slot, then calls `attempt` until it succeeds, fails with an error that
+    /// isn't transient, or the attempts of the retry policy are used up. The slot is kept
+    /// during the delay before a retry.
+    pub async fn run<T, Fut>(
+        &self,
+        model: ProvidedModel,
+        mut attempt: impl FnMut() -> Fut,
+    ) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let job = self.add(model);
+        job.wait_for_slot().await;
+        let retry = self.settings.lock().unwrap().retry;
+        let mut backoff = Duration::from_millis(retry.initial_backoff_ms);
+        let mut n = 1;
+        loop {
+            match attempt().await {
+                Err(err) if n < retry.max_attempts && is_retryable(&err) => {
+                    warn!("{model} failed in attempt {n}, retrying in {backoff:?}: {err:#}");
+                    job.set_state(JobState::Retrying { attempt: n });
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    n += 1;
+                    job.set_state(JobState::Running { attempt: n });
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn add(&self, model: ProvidedModel) -> Job<'_> {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id - 1
+        };
+        self.jobs.send_modify(|jobs| {
+            jobs.push(JobStatus {
+                id,
+                model,
+                state: JobState::Waiting,
+            })
+        });
+        Job { queue: self, id }
+    }
+}
+
+/// Removes the job from the queue when it's done, or dropped while it's waiting
+struct Job<'a> {
+    queue: &'a ImageQueue,
+    id: u64,
+}
+
+impl Job<'_> {
+    async fn wait_for_slot(&self) {
+        let mut changes = self.queue.jobs.subscribe();
+        while !self.try_start() {
+            // the sender lives as long as the queue
+            let _ = changes.changed().await;
+        }
+    }
+
+    /// Starts the job if there's a free slot and no job that was requested earlier is waiting
+    fn try_start(&self) -> bool {
+        let max_concurrent = self.queue.settings.lock().unwrap().max_concurrent.max(1);
+        self.queue.jobs.send_if_modified(|jobs| {
+            let active = jobs
+                .iter()
+                .filter(|job| job.state != JobState::Waiting)
+                .count();
+            let next = jobs.iter_mut().find(|job| job.state == JobState::Waiting);
+            match next {
+                Some(job) if job.id == self.id && active < max_concurrent => {
+                    job.state = JobState::Running { attempt: 1 };
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    fn set_state(&self, state: JobState) {
+        self.queue.jobs.send_modify(|jobs| {
+            if let Some(job) = jobs.iter_mut().find(|job| job.id == self.id) {
+                job.state = state;
+            }
+        });
+    }
+}
+
+impl Drop for Job<'_> {
+    fn drop(&mut self) {
+        self.queue
+            .jobs
+            .send_modify(|jobs| jobs.retain(|job| job.id != self.id));
+    }
+}
+
+/// Failed downloads aren't retried, the image was made and paid for already. It can be
+/// downloaded again instead, see [`ImageModel::download`].
+fn is_retryable(err: &Report) -> bool {
+    err.downcast_ref::<EngineError>()
+        .is_some_and(|err| err.is_transient() && !matches!(err, EngineError::ImageDownload(_)))
+}
+
+struct Queued {
+    inner: ImgModBox,
+    queue: Arc<ImageQueue>,
+}
+
+impl ImageModel for Queued {
+    fn get_image<'a>(
+        &'a self,
+        description: &'a str,
+        seed: Option<u64>,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        // the boxed model isn't `Sync`, so the future can't hold on to `self`
+        let (inner, queue) = (self.inner.clone(), self.queue.clone());
+        Box::pin(async move {
+            queue
+                .run(inner.provided_model(), move || {
+                    let (inner, progress) = (inner.clone(), progress.clone());
+                    async move { inner.get_image(description, seed, progress).await }
+                })
+                .await
+        })
+    }
+
+    fn can_edit(&self) -> bool {
+        self.inner.can_edit()
+    }
+
+    fn edit_image<'a>(
+        &'a self,
+        original: &'a [u8],
+        instruction: &'a str,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        let (inner, queue) = (self.inner.clone(), self.queue.clone());
+        Box::pin(async move {
+            queue
+                .run(inner.provided_model(), move || {
+                    let (inner, progress) = (inner.clone(), progress.clone());
+                    async move { inner.edit_image(original, instruction, progress).await }
+                })
+                .await
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        self.inner.download(url)
+    }
+
+    fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
+        Box::new(Self {
+            inner: self.inner.clone(),
+            queue: self.queue.clone(),
+        })
+    }
+
+    fn provided_model(&self) -> ProvidedModel {
+        self.inner.provided_model()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use color_eyre::eyre::eyre;
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    const SETTINGS: QueueSettings = QueueSettings {
+        max_concurrent: 1,
+        retry: RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 1,
+        },
+    };
+
+    fn overloaded() -> Report {
+        EngineError::Overloaded {
+            provider: "BFL".into(),
+            message: "busy".into(),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let queue = ImageQueue::new(SETTINGS);
+        let attempts = AtomicU32::new(0);
+        let res = queue
+            .run(ProvidedModel::Flux2BLF, || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(overloaded()),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(res.unwrap(), 1);
+
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<()> = queue
+            .run(ProvidedModel::Flux2BLF, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(overloaded())
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        attempts.store(0, Ordering::SeqCst);
+        let res: Result<()> = queue
+            .run(ProvidedModel::Flux2BLF, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(eyre!("invalid prompt"))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert!(queue.jobs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn jobs_wait_for_a_free_slot() {
+        let queue = Arc::new(ImageQueue::new(SETTINGS));
+        let (release, released) = oneshot::channel::<()>();
+        let mut released = Some(released);
+        let first = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                queue
+                    .run(ProvidedModel::Flux2BLF, || {
+                        // it doesn't fail, so there's only one attempt
+                        let released = released.take().unwrap();
+                        async move { Ok(released.await.is_ok()) }
+                    })
+                    .await
+            }
+        });
+        while queue.jobs().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let second = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                queue
+                    .run(ProvidedModel::PImagePruna, || async { Ok(()) })
+                    .await
+            }
+        });
+        while queue.jobs().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        let states: Vec<_> = queue.jobs().into_iter().map(|job| job.state).collect();
+        assert_eq!(
+            states,
+            vec![JobState::Running { attempt: 1 }, JobState::Waiting]
+        );
+
+        release.send(()).unwrap();
+        assert!(first.await.unwrap().unwrap());
+        second.await.unwrap().unwrap();
+        assert!(queue.jobs().is_empty());
+    }
+}
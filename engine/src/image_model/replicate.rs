@@ -1,17 +1,23 @@
 use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
 
-use color_eyre::{
-    Result,
-    eyre::{ensure, eyre},
-};
+use color_eyre::{Result, eyre::eyre};
 use reqwest::Client;
 use serde::Deserialize;
 use serde_json::json;
 use tokio::time::sleep;
 
-use crate::{ImageModel, image_model::ProvidedModel};
+use crate::{
+    ImageModel,
+    error::EngineError,
+    image_model::ProvidedModel,
+    rate_limit::{self, RateLimiter},
+};
+
+use super::{
+    Image, ImageProgress, InputBuilder, ProgressReporter, download::download_image, random_seed,
+};
 
-use super::Image;
+pub mod catalog;
 
 #[derive(Clone)]
 pub struct ReplicateImageModel {
@@ -20,7 +26,8 @@ pub struct ReplicateImageModel {
     client: Client,
     api_key: String,
     version: Option<String>,
-    input_builder: Arc<dyn Fn(&str) -> serde_json::Value + Send + Sync>,
+    input_builder: InputBuilder,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 impl ReplicateImageModel {
@@ -29,7 +36,7 @@ impl ReplicateImageModel {
         model: ProvidedModel,
         api_key: String,
         version: Option<String>,
-        input_builder: impl Fn(&str) -> serde_json::Value + Send + Sync + 'static,
+        input_builder: impl Fn(&str, u64) -> serde_json::Value + Send + Sync + 'static,
     ) -> Self {
         Self {
             url,
@@ -38,6 +45,7 @@ impl ReplicateImageModel {
             api_key,
             version,
             input_builder: Arc::new(input_builder),
+            rate_limiter: rate_limit::shared(model.provider()),
         }
     }
 }
@@ -46,25 +54,44 @@ impl ReplicateImageModel {
 struct PredictionResponse {
     status: String,
     output: Option<serde_json::Value>,
+    /// the output of the model, with progress bars
+    #[serde(default)]
+    logs: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl PredictionResponse {
+    /// Whether the safety checker of the model rejected the prompt or the image
+    fn is_moderated(&self) -> bool {
+        self.error.as_deref().is_some_and(|error| {
+            let error = error.to_lowercase();
+            error.contains("nsfw") || error.contains("sensitive")
+        })
+    }
 }
 
 impl ImageModel for ReplicateImageModel {
     fn get_image<'a>(
         &'a self,
         description: &'a str,
+        seed: Option<u64>,
+        progress: ProgressReporter,
     ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
         Box::pin(async move {
             // 1. Create prediction
+            let seed = seed.unwrap_or_else(random_seed);
             let req_body = if let Some(v) = &self.version {
                 json!({
                     "version": v,
-                    "input": (self.input_builder)(description),
+                    "input": (self.input_builder)(description, seed),
                 })
             } else {
                 json!({
-                    "input": (self.input_builder)(description),
+                    "input": (self.input_builder)(description, seed),
                 })
             };
+            self.rate_limiter.acquire().await;
             let create_resp = self
                 .client
                 .post(&self.url)
@@ -75,10 +102,9 @@ impl ImageModel for ReplicateImageModel {
 
             let status = create_resp.status();
             let body = create_resp.text().await?;
-            ensure!(
-                status.is_success(),
-                "Prediciton Request error: {status} - {body}"
-            );
+            if !status.is_success() {
+                return Err(EngineError::from_status("Replicate", status, &body).into());
+            }
 
             let prediction_infos = serde_json::from_str::<serde_json::Value>(&body)?;
 
@@ -89,6 +115,7 @@ impl ImageModel for ReplicateImageModel {
 
             // 2. Poll until finished
             loop {
+                self.rate_limiter.acquire().await;
                 let resp = self
                     .client
                     .get(&prediction_url)
@@ -98,6 +125,11 @@ impl ImageModel for ReplicateImageModel {
                     .error_for_status()?
                     .json::<PredictionResponse>()
                     .await?;
+                progress.report(ImageProgress {
+                    status: resp.status.clone(),
+                    fraction: resp.logs.as_deref().and_then(progress_from_logs),
+                    preview: None,
+                });
 
                 match resp.status.as_str() {
                     "succeeded" => {
@@ -105,20 +137,19 @@ impl ImageModel for ReplicateImageModel {
                             resp.output.as_ref().ok_or(eyre!("No output image"))?,
                         )?;
                         // 3. Download image
-                        let bytes = self
-                            .client
-                            .get(url)
-                            .send()
-                            .await?
-                            .error_for_status()?
-                            .bytes()
-                            .await?;
-
+                        let data = download_image(&self.client, url, &[]).await?;
                         return Ok(Image {
-                            data: bytes.to_vec(),
+                            data,
                             cost: None,
+                            seed: Some(seed),
                         });
                     }
+                    "failed" if resp.is_moderated() => {
+                        return Err(EngineError::Moderated {
+                            message: resp.error.unwrap_or_default(),
+                        }
+                        .into());
+                    }
                     "failed" | "canceled" => {
                         return Err(eyre!("Replicate prediction failed:\n{resp:#?}"));
                     }
@@ -130,6 +161,13 @@ impl ImageModel for ReplicateImageModel {
         })
     }
 
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move { Ok(download_image(&self.client, url, &[]).await?) })
+    }
+
     fn clone(&self) -> Box<dyn ImageModel + Send + 'static> {
         Box::new(Clone::clone(self))
     }
@@ -139,6 +177,16 @@ impl ImageModel for ReplicateImageModel {
     }
 }
 
+/// The latest percentage of the progress bars in the logs, like
+/// ` 50%|█████     | 14/28 [00:02<00:02,  6.1it/s]`
+fn progress_from_logs(logs: &str) -> Option<f32> {
+    logs.rsplit(['\n', '\r']).find_map(|line| {
+        let (head, _) = line.split_once('%')?;
+        let percent: f32 = head.trim().rsplit(' ').next()?.parse().ok()?;
+        Some((percent / 100.0).clamp(0.0, 1.0))
+    })
+}
+
 fn extract_image_url(output: &serde_json::Value) -> Result<&str> {
     match output {
         serde_json::Value::String(url) => Ok(url),
@@ -152,3 +200,27 @@ fn extract_image_url(output: &serde_json::Value) -> Result<&str> {
         other => Err(eyre!("Unsupported output format: {other:#?}")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_progress_from_the_logs() {
+        let logs = "Using seed: 7\n  0%|          | 0/28 [00:00<?, ?it/s]\r 50%|█████     | 14/28 [00:02<00:02,  6.1it/s]\r";
+        assert_eq!(progress_from_logs(logs), Some(0.5));
+        assert_eq!(progress_from_logs("Using seed: 7\n"), None);
+    }
+
+    #[test]
+    fn nsfw_failures_are_moderated() {
+        let resp: PredictionResponse = serde_json::from_str(
+            r#"{"status":"failed","output":null,"error":"NSFW content detected. Try running it again, or try a different prompt."}"#,
+        )
+        .unwrap();
+        assert!(resp.is_moderated());
+        let resp: PredictionResponse =
+            serde_json::from_str(r#"{"status":"failed","error":"CUDA out of memory"}"#).unwrap();
+        assert!(!resp.is_moderated());
+    }
+}
@@ -0,0 +1,184 @@
+//! Replicate hosts far more text-to-image models than World Weaver knows about. The catalog
+//! lists the ones of Replicate's text-to-image collection and their versions, so any of them
+//! can be selected instead of the built-in Flux1 version.
+
+use std::fmt;
+
+use color_eyre::Result;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    ImgModBox,
+    error::EngineError,
+    image_model::{ModelProvider, ProvidedModel},
+    rate_limit,
+};
+
+use super::ReplicateImageModel;
+
+const API: &str = "https://api.replicate.com/v1";
+
+/// The version that [`ProvidedModel::Flux1Replicate`] uses, unless another model is selected
+pub const FLUX1_VERSION: &str = "8cf067a09fbd627c5597781951e1a6988e3b69f6ef712b4948d3d2b5361569ad";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CatalogModel {
+    pub owner: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// official models have no versions, they always run the latest one
+    #[serde(default)]
+    pub latest_version: Option<ModelVersion>,
+}
+
+impl CatalogModel {
+    /// `owner/name`, as the API expects it
+    pub fn full_name(&self) -> String {
+        format!("{}/{}", self.owner, self.name)
+    }
+}
+
+impl fmt::Display for CatalogModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.owner, self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ModelVersion {
+    pub id: String,
+    pub created_at: String,
+}
+
+impl fmt::Display for ModelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let date = self.created_at.split('T').next().unwrap_or_default();
+        write!(f, "{} ({date})", short_id(&self.id))
+    }
+}
+
+/// A model of the catalog that is used for [`ProvidedModel::Flux1Replicate`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Selection {
+    /// `owner/name`
+    pub model: String,
+    /// `None` runs the latest version, which is the only way to run official models
+    pub version: Option<String>,
+}
+
+impl Selection {
+    /// Its inputs are the ones that most text-to-image models share, Replicate ignores
+    /// those a model doesn't know.
+    pub fn make(&self, key: String) -> ImgModBox {
+        let url = match &self.version {
+            Some(_) => format!("{API}/predictions"),
+            None => format!("{API}/models/{}/predictions", self.model),
+        };
+        Box::new(ReplicateImageModel::new(
+            url,
+            ProvidedModel::Flux1Replicate,
+            key,
+            self.version.clone(),
+            |prompt, seed| {
+                json!({
+                    "prompt": prompt,
+                    "seed": seed,
+                    "width": 832,
+                    "height": 1216,
+                })
+            },
+        ))
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} ({})", self.model, short_id(version)),
+            None => write!(f, "{} (latest)", self.model),
+        }
+    }
+}
+
+fn short_id(id: &str) -> &str {
+    id.get(..12).unwrap_or(id)
+}
+
+#[derive(Debug, Deserialize)]
+struct Collection {
+    models: Vec<CatalogModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VersionPage {
+    results: Vec<ModelVersion>,
+}
+
+/// The models of Replicate's text-to-image collection
+pub async fn text_to_image_models(api_key: &str) -> Result<Vec<CatalogModel>> {
+    let body = get(api_key, &format!("{API}/collections/text-to-image")).await?;
+    Ok(serde_json::from_str::<Collection>(&body)?.models)
+}
+
+/// The versions of the model `owner/name`, the newest first. Only the first page is fetched,
+/// older versions are rarely wanted.
+pub async fn versions(api_key: &str, model: &str) -> Result<Vec<ModelVersion>> {
+    let body = get(api_key, &format!("{API}/models/{model}/versions")).await?;
+    Ok(serde_json::from_str::<VersionPage>(&body)?.results)
+}
+
+async fn get(api_key: &str, url: &str) -> Result<String> {
+    rate_limit::shared(ModelProvider::Replicate).acquire().await;
+    let resp = Client::new().get(url).bearer_auth(api_key).send().await?;
+    let status = resp.status();
+    let body = resp.text().await?;
+    if !status.is_success() {
+        return Err(EngineError::from_status("Replicate", status, &body).into());
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_models_and_versions() {
+        let collection: Collection = serde_json::from_str(
+            r#"{"name":"Text to image","slug":"text-to-image","models":[
+                {"url":"https://replicate.com/stability-ai/sdxl","owner":"stability-ai",
+                 "name":"sdxl","description":"A text-to-image model",
+                 "latest_version":{"id":"7762fd07cf82c948538e41f63f77d685e02b063e37e496e96eefd46c929f9bdc",
+                 "created_at":"2023-11-01T12:00:00.000Z","cog_version":"0.8.6"}},
+                {"owner":"black-forest-labs","name":"flux-2-pro","latest_version":null}]}"#,
+        )
+        .unwrap();
+        let [sdxl, flux] = &collection.models[..] else {
+            panic!("two models were listed");
+        };
+        assert_eq!(sdxl.full_name(), "stability-ai/sdxl");
+        assert_eq!(
+            sdxl.latest_version.as_ref().unwrap().to_string(),
+            "7762fd07cf82 (2023-11-01)"
+        );
+        assert_eq!(flux.latest_version, None);
+
+        let page: VersionPage = serde_json::from_str(
+            r#"{"next":null,"previous":null,"results":[{"id":"abc","created_at":"2024-01-02T03:04:05Z"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(page.results[0].id, "abc");
+
+        let selection = Selection {
+            model: flux.full_name(),
+            version: None,
+        };
+        assert_eq!(
+            selection.to_string(),
+            "black-forest-labs/flux-2-pro (latest)"
+        );
+    }
+}
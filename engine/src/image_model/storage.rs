@@ -0,0 +1,203 @@
+//! Image models return images at full resolution, often as barely compressed JPEGs, which
+//! makes save archives grow quickly. Therefore images are downscaled and re-encoded
+//! before they are stored. Some models answer with PNGs or WebPs instead, so the format is
+//! detected from the bytes.
+
+use std::io::Cursor;
+
+use color_eyre::{Result, eyre::eyre};
+use image::{
+    DynamicImage,
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+#[derive(Debug, Clone, Copy, Display, Serialize, Deserialize, PartialEq, Eq, Hash, EnumIter)]
+pub enum ImageFormat {
+    #[strum(to_string = "JPEG")]
+    Jpeg,
+    #[strum(to_string = "PNG")]
+    Png,
+    /// always lossless when it's written
+    #[strum(to_string = "WebP")]
+    WebP,
+}
+
+impl ImageFormat {
+    /// `None` if the bytes aren't an image in one of the supported formats
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        match image::guess_format(bytes).ok()? {
+            image::ImageFormat::Jpeg => Some(Self::Jpeg),
+            image::ImageFormat::Png => Some(Self::Png),
+            image::ImageFormat::WebP => Some(Self::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::WebP => "image/webp",
+        }
+    }
+}
+
+/// The bytes that are stored for an image
+#[derive(Debug, Clone)]
+pub struct PreparedImage {
+    pub bytes: Vec<u8>,
+    pub format: ImageFormat,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct StorageSettings {
+    /// Images with a longer side are downscaled to this size
+    pub max_dimension: u32,
+    /// The format images are stored in, `None` keeps the one they were received in
+    pub format: Option<ImageFormat>,
+    /// JPEG quality from 1 to 100, the other formats are lossless
+    pub jpeg_quality: u8,
+    /// Additionally store the images as they were received
+    pub store_originals: bool,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            max_dimension: 1024,
+            format: Some(ImageFormat::Jpeg),
+            jpeg_quality: 85,
+            store_originals: false,
+        }
+    }
+}
+
+impl StorageSettings {
+    /// Returns the bytes that should be stored for the received image. If re-encoding in
+    /// the same format doesn't make an image smaller, it's stored as it is.
+    pub fn prepare(&self, bytes: &[u8]) -> Result<PreparedImage> {
+        let received_format =
+            ImageFormat::detect(bytes).ok_or_else(|| eyre!("Unsupported image format"))?;
+        let img =
+            image::load_from_memory(bytes).map_err(|e| eyre!("Failed to decode image: {e}"))?;
+        let max_side = self.max_dimension.max(1);
+        let is_too_large = img.width() > max_side || img.height() > max_side;
+        let img = if is_too_large {
+            img.resize(max_side, max_side, FilterType::Lanczos3)
+        } else {
+            img
+        };
+
+        let format = self.format.unwrap_or(received_format);
+        let res = self.encode(img, format)?;
+        if !is_too_large && format == received_format && res.len() >= bytes.len() {
+            Ok(PreparedImage {
+                bytes: bytes.to_vec(),
+                format,
+            })
+        } else {
+            Ok(PreparedImage { bytes: res, format })
+        }
+    }
+
+    fn encode(&self, img: DynamicImage, format: ImageFormat) -> Result<Vec<u8>> {
+        let mut res = vec![];
+        let writer = Cursor::new(&mut res);
+        match format {
+            ImageFormat::Jpeg => {
+                JpegEncoder::new_with_quality(writer, self.jpeg_quality.clamp(1, 100))
+                    .encode_image(&img.into_rgb8())
+            }
+            ImageFormat::Png => img.into_rgb8().write_with_encoder(PngEncoder::new(writer)),
+            ImageFormat::WebP => img
+                .into_rgb8()
+                .write_with_encoder(WebPEncoder::new_lossless(writer)),
+        }
+        .map_err(|e| eyre!("Failed to encode image: {e}"))?;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg_bytes(width: u32, height: u32, quality: u8) -> Vec<u8> {
+        let img = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+        });
+        let mut bytes = vec![];
+        JpegEncoder::new_with_quality(Cursor::new(&mut bytes), quality)
+            .encode_image(&img)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn large_images_are_downscaled() {
+        let original = jpeg_bytes(1216, 832, 100);
+        let settings = StorageSettings {
+            max_dimension: 608,
+            ..Default::default()
+        };
+        let stored = settings.prepare(&original).unwrap();
+        assert_eq!(stored.format, ImageFormat::Jpeg);
+        let img = image::load_from_memory(&stored.bytes).unwrap();
+        assert_eq!((img.width(), img.height()), (608, 416));
+        assert!(stored.bytes.len() < original.len());
+    }
+
+    #[test]
+    fn images_never_grow() {
+        let original = jpeg_bytes(64, 64, 20);
+        let settings = StorageSettings {
+            jpeg_quality: 100,
+            ..Default::default()
+        };
+        assert_eq!(settings.prepare(&original).unwrap().bytes, original);
+    }
+
+    #[test]
+    fn images_are_converted_to_the_configured_format() {
+        let original = jpeg_bytes(64, 48, 90);
+        assert_eq!(ImageFormat::detect(&original), Some(ImageFormat::Jpeg));
+        assert_eq!(ImageFormat::detect(b"not an image"), None);
+
+        let settings = StorageSettings {
+            format: Some(ImageFormat::Png),
+            ..Default::default()
+        };
+        let png = settings.prepare(&original).unwrap();
+        assert_eq!(png.format, ImageFormat::Png);
+        assert_eq!(ImageFormat::detect(&png.bytes), Some(ImageFormat::Png));
+
+        // the PNG is larger, but it's kept as it is, as its format is kept
+        let settings = StorageSettings {
+            format: None,
+            ..Default::default()
+        };
+        let kept = settings.prepare(&png.bytes).unwrap();
+        assert_eq!((kept.format, kept.bytes), (ImageFormat::Png, png.bytes));
+
+        let settings = StorageSettings {
+            format: Some(ImageFormat::WebP),
+            ..Default::default()
+        };
+        let webp = settings.prepare(&original).unwrap();
+        assert_eq!(ImageFormat::detect(&webp.bytes), Some(ImageFormat::WebP));
+        let img = image::load_from_memory(&webp.bytes).unwrap();
+        assert_eq!((img.width(), img.height()), (64, 48));
+    }
+}
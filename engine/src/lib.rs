@@ -4,8 +4,18 @@ pub type LLMBox = Box<dyn LLM + Send>;
 pub type ImgModBox = Box<dyn ImageModel + Send>;
 pub const N_PROPOSED_OPTIONS: usize = 3;
 
+pub mod archive_worker;
+pub mod audit_log;
+pub mod error;
+pub mod export;
 pub mod game;
 pub mod image_model;
 pub mod llm;
+pub mod rate_limit;
+pub mod replay;
 pub mod save_archive;
+pub mod thumbnail;
+pub mod token_estimate;
+pub mod tools;
+pub mod world_cover;
 pub mod world_markdown;
@@ -1,7 +1,6 @@
 use std::pin::Pin;
 
 use serde::{Deserialize, Serialize};
-use strum::{Display, EnumIter};
 use tokio_stream::Stream;
 
 use color_eyre::Result;
@@ -9,6 +8,21 @@ use color_eyre::Result;
 pub trait LLM {
     fn send_request_stream(&mut self, req: Request) -> LLMStream<'_>;
     fn clone(&self) -> Box<dyn LLM + Send + 'static>;
+    /// Determines how requests that fail with a transient error are retried
+    fn set_retry_policy(&mut self, policy: RetryPolicy);
+    fn provider(&self) -> ModelProvider;
+    /// Whether `Request::tools` are offered to the model. If not, they are ignored.
+    fn supports_tools(&self) -> bool {
+        false
+    }
+    /// Lets the model think before it answers, using at most `budget` tokens for that.
+    /// Models that can't think ignore it.
+    fn set_thinking_budget(&mut self, _budget: Option<u32>) {}
+    /// Sends the requests via a batch API, which is cheaper, but can take minutes. Only the
+    /// complete message is yielded then. Providers without such an API ignore it.
+    fn set_batched(&mut self, _batched: bool) {}
+    /// Determines how long the provider may take to start and continue its responses
+    fn set_timeouts(&mut self, timeouts: Timeouts);
 }
 
 pub type LLMStream<'a> = Pin<Box<dyn Stream<Item = Result<ResponseFragment>> + Send + 'a>>;
@@ -16,33 +30,139 @@ pub type LLMStream<'a> = Pin<Box<dyn Stream<Item = Result<ResponseFragment>> + S
 #[derive(Debug)]
 pub enum ResponseFragment {
     TextDelta(String),
+    /// a piece of the reasoning that precedes the answer, it's not part of the answer
+    ThinkingDelta(String),
     MessageComplete(OutputMessage),
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request {
     pub system: Option<String>,
     pub messages: Vec<InputMessage>,
     pub max_tokens: usize,
+    pub sampling: Sampling,
+    /// tools the model may call instead of answering directly
+    pub tools: Vec<ToolSpec>,
+}
+
+/// Describes a tool to the model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    /// a JSON schema of the tool's input
+    pub input_schema: serde_json::Value,
+}
+
+/// A tool call requested by the model. It's answered with a `ContentBlock::ToolResult`
+/// in the next user message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: serde_json::Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// How the LLM picks the next token. Unset values are left to the provider.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sampling {
+    /// higher values make the output more random
+    pub temperature: Option<f32>,
+    /// only the most likely tokens, whose probabilities add up to this, are considered
+    pub top_p: Option<f32>,
+    /// the output ends as soon as one of these is generated
+    pub stop_sequences: Vec<String>,
+}
+
+impl Sampling {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputMessage {
     pub role: Role,
-    pub content: String,
+    pub content: Content,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    /// only needed for tool calls and their results
+    Blocks(Vec<ContentBlock>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
+    /// the signature lets the provider check that the thinking wasn't modified
+    Thinking {
+        thinking: String,
+        signature: String,
+    },
+    /// thinking that was flagged by the provider's safety systems, and is encrypted
+    RedactedThinking {
+        data: String,
+    },
+}
+
+impl Content {
+    /// The text of the message, without tool calls and results
+    pub fn text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Blocks(blocks) => blocks
+                .iter()
+                .filter_map(|b| match b {
+                    ContentBlock::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Content::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Content::Text(text.into())
+    }
 }
 
 impl InputMessage {
     pub(crate) fn user(user_message: String) -> InputMessage {
         Self {
             role: Role::User,
-            content: user_message,
+            content: user_message.into(),
         }
     }
 
     pub(crate) fn assistant(assistant_message: String) -> InputMessage {
         Self {
             role: Role::Assistant,
-            content: assistant_message,
+            content: assistant_message.into(),
         }
     }
 }
@@ -52,101 +172,154 @@ pub struct OutputMessage {
     pub input_tokens: usize,
     pub output_tokens: usize,
     pub text: String,
+    /// if this isn't empty, the model waits for the results before it continues
+    pub tool_calls: Vec<ToolCall>,
+    /// the `Thinking` and `RedactedThinking` blocks of the answer. They must be sent back
+    /// along with the results of tool calls.
+    pub thinking: Vec<ContentBlock>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Role {
     User,
     Assistant,
 }
 
-#[derive(
-    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, EnumIter, Display,
-)]
-pub enum ModelProvider {
-    Anthropic,
-    Openrouter,
+/// A server with an OpenAI-compatible chat completions API, e.g. LM Studio, vLLM or the
+/// llama.cpp server
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CustomEndpoint {
+    /// e.g. `http://localhost:1234/v1`, the completions path is appended if it's missing
+    pub url: String,
+    pub model: String,
 }
 
-#[derive(
-    Debug,
-    Clone,
-    Copy,
-    clap::ValueEnum,
-    Serialize,
-    Deserialize,
-    Default,
-    EnumIter,
-    Display,
-    PartialEq,
-    Eq,
-)]
-pub enum ProvidedModel {
-    #[strum(to_string = "Claude Sonette - latest (Anthropic)")]
-    ClaudeSonette,
+impl CustomEndpoint {
+    pub fn make(&self, api_key: String) -> LLMBox {
+        Box::new(OpenAIChat::new(
+            ModelProvider::CUSTOM_ENDPOINT,
+            api_key,
+            self.completions_url(),
+            self.model.trim(),
+        ))
+    }
 
-    #[strum(to_string = "Claude Sonette 4.5 (Anthropic)")]
-    ClaudeSonette45,
+    fn completions_url(&self) -> String {
+        let url = self.url.trim().trim_end_matches('/');
+        if url.ends_with("/chat/completions") {
+            url.to_string()
+        } else {
+            format!("{url}/chat/completions")
+        }
+    }
+}
 
-    #[strum(to_string = "Claude Haiku - latest (Anthropic)")]
-    ClaudeHaiku,
+mod claude;
+pub use claude::Claude;
 
-    #[strum(to_string = "Aion-2.0 (openrouter.ai)")]
-    Aion2Openr,
+use crate::LLMBox;
 
-    #[strum(to_string = "Kimmi K2.5 (openrouter.ai)")]
-    Flex,
+mod open_ai_chat;
+pub use open_ai_chat::OpenAIChat;
 
-    #[default]
-    #[strum(to_string = "GLM 5 (openrouter.ai)")]
-    Glm5,
-}
+mod registry;
+pub use registry::{
+    Api, MODELS, ModelEntry, ModelProvider, PROVIDERS, ProvidedModel, ProviderEntry,
+};
 
-impl ProvidedModel {
-    pub fn make(self, api_key: String) -> LLMBox {
-        match self {
-            ProvidedModel::ClaudeSonette => {
-                Box::new(Claude::new(api_key, "claude-sonnet-4-6".into()))
-            }
-            ProvidedModel::ClaudeSonette45 => {
-                Box::new(Claude::new(api_key, "claude-sonnet-4-5".into()))
+mod retry;
+pub use retry::RetryPolicy;
+
+mod sse;
+
+mod timeout;
+pub use timeout::Timeouts;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// Answers with the given messages in order, and records the requests. Clones share both.
+    #[derive(Clone, Default)]
+    pub(crate) struct ScriptedLLM {
+        replies: Arc<Mutex<VecDeque<OutputMessage>>>,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl ScriptedLLM {
+        pub(crate) fn new(replies: impl IntoIterator<Item = OutputMessage>) -> Self {
+            Self {
+                replies: Arc::new(Mutex::new(replies.into_iter().collect())),
+                requests: Arc::default(),
             }
-            ProvidedModel::ClaudeHaiku => Box::new(Claude::new(api_key, "claude-haiku-4-5".into())),
-            ProvidedModel::Aion2Openr => Box::new(OpenAIChat::new(
-                api_key,
-                "https://openrouter.ai/api/v1/chat/completions",
-                "aion-labs/aion-2.0",
-            )),
-            ProvidedModel::Flex => Box::new(OpenAIChat::new(
-                api_key,
-                "https://openrouter.ai/api/v1/chat/completions",
-                "moonshotai/kimi-k2.5",
-            )),
-            ProvidedModel::Glm5 => Box::new(OpenAIChat::new(
-                api_key,
-                "https://openrouter.ai/api/v1/chat/completions",
-                "z-ai/glm-5",
-            )),
         }
-    }
 
-    pub fn provider(self) -> ModelProvider {
-        match self {
-            ProvidedModel::ClaudeSonette => ModelProvider::Anthropic,
-            ProvidedModel::ClaudeSonette45 => ModelProvider::Anthropic,
-            ProvidedModel::ClaudeHaiku => ModelProvider::Anthropic,
-            ProvidedModel::Aion2Openr => ModelProvider::Openrouter,
-            ProvidedModel::Flex => ModelProvider::Openrouter,
-            ProvidedModel::Glm5 => ModelProvider::Openrouter,
+        /// The requests that were sent so far
+        pub(crate) fn requests(&self) -> Vec<Request> {
+            self.requests.lock().unwrap().clone()
         }
     }
-}
 
-mod claude;
-pub use claude::Claude;
+    impl LLM for ScriptedLLM {
+        fn send_request_stream(&mut self, req: Request) -> LLMStream<'_> {
+            self.requests.lock().unwrap().push(req);
+            let msg = self
+                .replies
+                .lock()
+                .unwrap()
+                .pop_front()
+                .expect("the script has no reply left");
+            Box::pin(tokio_stream::iter([
+                Ok(ResponseFragment::TextDelta(msg.text.clone())),
+                Ok(ResponseFragment::MessageComplete(msg)),
+            ]))
+        }
 
-use crate::LLMBox;
+        fn clone(&self) -> LLMBox {
+            Box::new(Clone::clone(self))
+        }
 
-mod open_ai_chat;
-pub use open_ai_chat::OpenAIChat;
+        fn set_retry_policy(&mut self, _: RetryPolicy) {}
+
+        fn provider(&self) -> ModelProvider {
+            ModelProvider::ANTHROPIC
+        }
+
+        fn set_timeouts(&mut self, _: Timeouts) {}
+    }
+
+    /// A reply of the model, with the given token counts
+    pub(crate) fn message(text: &str, input_tokens: usize, output_tokens: usize) -> OutputMessage {
+        OutputMessage {
+            input_tokens,
+            output_tokens,
+            text: text.into(),
+            tool_calls: vec![],
+            thinking: vec![],
+        }
+    }
+
+    #[test]
+    fn completions_path_is_appended_once() {
+        let endpoint = |url: &str| CustomEndpoint {
+            url: url.into(),
+            model: "qwen3".into(),
+        };
+        for url in [
+            "http://localhost:1234/v1",
+            "http://localhost:1234/v1/",
+            " http://localhost:1234/v1/chat/completions",
+        ] {
+            assert_eq!(
+                endpoint(url).completions_url(),
+                "http://localhost:1234/v1/chat/completions"
+            );
+        }
+    }
+}
@@ -1,4 +1,11 @@
-use crate::llm::LLMStream;
+use std::sync::Arc;
+
+use async_stream::try_stream;
+
+use crate::{
+    llm::{LLMStream, ModelProvider, ResponseFragment, RetryPolicy, Timeouts, retry},
+    rate_limit::{self, RateLimiter},
+};
 
 use super::{LLM, Request};
 
@@ -9,6 +16,11 @@ pub struct Claude {
     pub api_key: String,
     pub model: String,
     pub client: reqwest::Client,
+    rate_limiter: Arc<RateLimiter>,
+    retry_policy: RetryPolicy,
+    thinking_budget: Option<u32>,
+    timeouts: Timeouts,
+    batched: bool,
 }
 
 impl Claude {
@@ -17,6 +29,11 @@ impl Claude {
             api_key,
             model,
             client: reqwest::Client::new(),
+            rate_limiter: rate_limit::shared(ModelProvider::ANTHROPIC),
+            retry_policy: RetryPolicy::default(),
+            thinking_budget: None,
+            timeouts: Timeouts::default(),
+            batched: false,
         }
     }
 }
@@ -27,23 +44,85 @@ impl LLM for Claude {
             system,
             messages,
             max_tokens,
+            sampling,
+            tools,
         } = req;
 
+        let mut data = claude_api::RequestBody {
+            model: self.model.clone(),
+            system,
+            messages,
+            max_tokens,
+            stream: true,
+            temperature: sampling.temperature,
+            top_p: sampling.top_p,
+            stop_sequences: sampling.stop_sequences,
+            tools,
+            thinking: None,
+        };
+        if let Some(budget) = self.thinking_budget {
+            // the thinking counts towards max_tokens, and the API rejects thinking requests
+            // that change the sampling
+            data.max_tokens += budget as usize;
+            data.temperature = None;
+            data.top_p = None;
+            data.thinking = Some(claude_api::ThinkingConfig::enabled(budget));
+        }
         let claude_req = claude_api::Request {
             api_key: self.api_key.clone(),
-            data: claude_api::RequestBody {
-                model: self.model.clone(),
-                system,
-                messages,
-                max_tokens,
-                stream: true,
-            },
+            data,
         };
 
-        Box::pin(claude_api::send_request_stream(claude_req, &self.client))
+        let client = &self.client;
+        let rate_limiter = &*self.rate_limiter;
+        let timeouts = self.timeouts;
+        let policy = self.retry_policy;
+        let stream = move |claude_req: claude_api::Request| {
+            retry::with_retries(policy, move || {
+                claude_api::send_request_stream(claude_req.clone(), client, rate_limiter, timeouts)
+            })
+        };
+        if self.batched {
+            return Box::pin(try_stream! {
+                match claude_api::send_batch_request(&claude_req, client, timeouts, policy).await? {
+                    Some(msg) => yield ResponseFragment::MessageComplete(msg),
+                    // the batch took too long
+                    None => {
+                        for await fragment in stream(claude_req) {
+                            yield fragment?;
+                        }
+                    }
+                }
+            });
+        }
+        Box::pin(stream(claude_req))
     }
 
     fn clone(&self) -> Box<dyn LLM + Send + 'static> {
         Box::new(Clone::clone(self))
     }
+
+    fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    fn provider(&self) -> ModelProvider {
+        ModelProvider::ANTHROPIC
+    }
+
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    fn set_thinking_budget(&mut self, budget: Option<u32>) {
+        self.thinking_budget = budget;
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    fn set_batched(&mut self, batched: bool) {
+        self.batched = batched;
+    }
 }
@@ -1,26 +1,35 @@
-use std::time::Duration;
+use std::mem;
 
 use async_stream::try_stream;
 use color_eyre::{Result, eyre::eyre};
 use log::info;
 use reqwest::header::{self, HeaderValue};
 use serde::{Deserialize, Serialize};
-use tokio_stream::Stream;
+use tokio_stream::{Stream, StreamExt};
 
+mod batch;
+pub use batch::send_batch_request;
 mod error;
 pub use error::ClaudeApiError;
 
-use crate::llm::{InputMessage, OutputMessage, ResponseFragment};
+use crate::{
+    error::EngineError,
+    llm::{
+        ContentBlock, InputMessage, ModelProvider, OutputMessage, ResponseFragment, Timeouts,
+        ToolCall, ToolSpec,
+    },
+    rate_limit::RateLimiter,
+};
 
 mod sse_parser;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request {
     pub api_key: String,
     pub data: RequestBody,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestBody {
     pub model: String,
     pub messages: Vec<InputMessage>,
@@ -30,37 +39,77 @@ pub struct RequestBody {
 
     pub max_tokens: usize,
     pub stream: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolSpec>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<ThinkingConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThinkingConfig {
+    #[serde(rename = "type")]
+    pub thinking_type: String,
+    /// must be at least 1024
+    pub budget_tokens: u32,
+}
+
+impl ThinkingConfig {
+    pub fn enabled(budget_tokens: u32) -> Self {
+        Self {
+            thinking_type: "enabled".into(),
+            budget_tokens,
+        }
+    }
 }
 
 pub fn send_request_stream(
     mut req: Request,
     client: &reqwest::Client,
+    rate_limiter: &RateLimiter,
+    timeouts: Timeouts,
 ) -> impl Stream<Item = Result<ResponseFragment>> {
     try_stream! {
         req.data.stream = true;
+        rate_limiter.acquire().await;
         let request =client
             .post("https://api.anthropic.com/v1/messages")
-            .timeout(Duration::from_secs(60*3))
             .json(&req.data)
             .header("x-api-key", &req.api_key)
             .header("anthropic-version", HeaderValue::from_static("2023-06-01"))
             .header(header::ACCEPT, HeaderValue::from_static("text/event-stream"));
 
-        let res = request
-            .send()
-            .await?;
+        let res = timeouts
+            .response(ModelProvider::ANTHROPIC, request.send())
+            .await??;
 
          if !res.status().is_success() {
             let status = res.status();
             let body = res.text().await.unwrap_or_default();
-            Err(eyre!("Anthropic error {}: {}", status, body))?;
+            Err(EngineError::from_status("Anthropic", status, &body))?;
         } else {
-            let stream = res.bytes_stream();
+            let mut stream = Box::pin(res.bytes_stream());
 
             let mut parser = sse_parser::Parser::default();
             let mut input_tokens = 0;
             let mut output_tokens = 0;
             let mut text = String::new();
+            let mut tool_calls = vec![];
+            // the tool call whose input is being received, and its input so far
+            let mut pending_tool_call: Option<(ToolCall, String)> = None;
+            let mut thinking_blocks = vec![];
+            // the thinking block that is being received
+            let mut pending_thinking: Option<(String, String)> = None;
             let mut first_msg_complete = false;
 
             let mut process_event = |ev| -> Result<Option<ResponseFragment>> {
@@ -80,6 +129,32 @@ pub fn send_request_stream(
                     }
 
                     ContentBlockStart(block) => {
+                        if block.content_block.block_type == "tool_use" {
+                            let block = block.content_block;
+                            let call = ToolCall {
+                                id: block.id.ok_or(eyre!("tool_use block without id"))?,
+                                name: block.name.ok_or(eyre!("tool_use block without name"))?,
+                                input: serde_json::Value::Null,
+                            };
+                            pending_tool_call = Some((call, String::new()));
+                            return Ok(None);
+                        }
+                        match block.content_block.block_type.as_str() {
+                            "thinking" => {
+                                let block = block.content_block;
+                                pending_thinking = Some((block.thinking.clone(), block.signature));
+                                if !block.thinking.is_empty() {
+                                    return Ok(Some(ResponseFragment::ThinkingDelta(block.thinking)));
+                                }
+                                return Ok(None);
+                            }
+                            "redacted_thinking" => {
+                                let data = block.content_block.data.ok_or(eyre!("redacted_thinking block without data"))?;
+                                thinking_blocks.push(ContentBlock::RedactedThinking { data });
+                                return Ok(None);
+                            }
+                            _ => {}
+                        }
                         if block.content_block.block_type != "text" {
                             Err(eyre!("unexpected block type: {}", block.content_block.block_type))?;
                         }
@@ -91,6 +166,21 @@ pub fn send_request_stream(
                     }
 
                     ContentBlockDelta(delta) => {
+                        if delta.delta.delta_type == "input_json_delta" {
+                            let (_, input) = pending_tool_call.as_mut().ok_or(eyre!("input_json_delta outside of a tool_use block"))?;
+                            input.push_str(&delta.delta.partial_json);
+                            return Ok(None);
+                        }
+                        if delta.delta.delta_type == "thinking_delta" {
+                            let (thinking, _) = pending_thinking.as_mut().ok_or(eyre!("thinking_delta outside of a thinking block"))?;
+                            thinking.push_str(&delta.delta.thinking);
+                            return Ok(Some(ResponseFragment::ThinkingDelta(delta.delta.thinking)));
+                        }
+                        if delta.delta.delta_type == "signature_delta" {
+                            let (_, signature) = pending_thinking.as_mut().ok_or(eyre!("signature_delta outside of a thinking block"))?;
+                            signature.push_str(&delta.delta.signature);
+                            return Ok(None);
+                        }
                         if delta.delta.delta_type != "text_delta" {
                             Err(eyre!("unexpected delta type: {}", delta.delta.delta_type))?;
                         }
@@ -103,16 +193,31 @@ pub fn send_request_stream(
                         output_tokens += delta.usage.output_tokens.ok_or(eyre!("MessageDelta missing output tokens"))?;
                     }
 
-                    ContentBlockStop(_) | Ping=> {
+                    ContentBlockStop(_) => {
+                        if let Some((mut call, input)) = pending_tool_call.take() {
+                            // a tool without parameters gets an empty input
+                            call.input = if input.trim().is_empty() {
+                                serde_json::json!({})
+                            } else {
+                                serde_json::from_str(&input).map_err(|e| eyre!("Invalid input for tool {}: {e}", call.name))?
+                            };
+                            tool_calls.push(call);
+                        }
+                        if let Some((thinking, signature)) = pending_thinking.take() {
+                            thinking_blocks.push(ContentBlock::Thinking { thinking, signature });
+                        }
+                    }
+
+                    Ping => {
                     }
 
                     MessageStop => {
                         first_msg_complete = true;
-                        return Ok(Some(ResponseFragment::MessageComplete(OutputMessage { input_tokens, output_tokens, text: text.clone() })))
+                        return Ok(Some(ResponseFragment::MessageComplete(OutputMessage { input_tokens, output_tokens, text: text.clone(), tool_calls: mem::take(&mut tool_calls), thinking: mem::take(&mut thinking_blocks) })))
                     }
 
                     Error(err) => {
-                        Err(err)?;
+                        Err(EngineError::from(err))?;
                     }
 
                     Unknown(raw_event) => {
@@ -123,7 +228,7 @@ pub fn send_request_stream(
                 Ok(None)
             };
 
-            for await chunk in stream {
+            while let Some(chunk) = timeouts.next_chunk(ModelProvider::ANTHROPIC, stream.next()).await? {
                 for ev in parser.process(chunk?)? {
                     if let Some(fragment) = process_event(ev)? {
                         yield fragment;
@@ -167,6 +272,11 @@ mod test {
             ],
             max_tokens: 200,
             stream: false,
+            temperature: None,
+            top_p: None,
+            stop_sequences: vec![],
+            tools: vec![],
+            thinking: None,
         };
 
         let expect = expect![[
@@ -0,0 +1,234 @@
+//! The Message Batches API processes requests in the background, for half the price. It can
+//! take minutes until a batch is done, so it's only used for requests nobody waits for.
+//! Transient errors are retried per call, so the batch that was created is kept.
+
+use std::time::Duration;
+
+use color_eyre::{Result, eyre::eyre};
+use log::{debug, warn};
+use reqwest::header::HeaderValue;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Instant, sleep};
+
+use super::{ClaudeApiError, Request, RequestBody};
+use crate::{
+    error::EngineError,
+    llm::{ModelProvider, OutputMessage, RetryPolicy, Timeouts, retry::call_with_retries},
+};
+
+const BATCHES_URL: &str = "https://api.anthropic.com/v1/messages/batches";
+/// Batches are rarely done within a few seconds, so there's no point in asking more often
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+/// Most batches end within an hour. One that takes longer is canceled, and the request is sent
+/// the usual way instead.
+const MAX_WAIT: Duration = Duration::from_secs(60 * 60);
+const CUSTOM_ID: &str = "request";
+
+#[derive(Serialize)]
+struct CreateBatch<'a> {
+    requests: [BatchRequest<'a>; 1],
+}
+
+#[derive(Serialize)]
+struct BatchRequest<'a> {
+    custom_id: &'static str,
+    params: &'a RequestBody,
+}
+
+#[derive(Debug, Deserialize)]
+struct Batch {
+    id: String,
+    processing_status: String,
+    results_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResult {
+    result: ResultKind,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResultKind {
+    Succeeded { message: Message },
+    Errored { error: ErrorResponse },
+    Canceled,
+    Expired,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    content: Vec<ContentBlock>,
+    usage: Usage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    block_type: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Usage {
+    input_tokens: usize,
+    output_tokens: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorDetails,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetails {
+    #[serde(rename = "type")]
+    error_type: String,
+    message: String,
+}
+
+/// Sends `req` as a batch of one, and returns the message once the batch is done. Each call of
+/// the API is retried on its own, so a failed poll doesn't create another batch. `None` if the
+/// batch didn't end within [`MAX_WAIT`], it's canceled then.
+pub async fn send_batch_request(
+    req: &Request,
+    client: &reqwest::Client,
+    timeouts: Timeouts,
+    policy: RetryPolicy,
+) -> Result<Option<OutputMessage>> {
+    // batches can't be streamed
+    let mut params = req.data.clone();
+    params.stream = false;
+    let body = CreateBatch {
+        requests: [BatchRequest {
+            custom_id: CUSTOM_ID,
+            params: &params,
+        }],
+    };
+    let api_key = &req.api_key;
+    // after a timeout the batch might have been created anyway, so only refusals are retried
+    let is_refusal = |err: &EngineError| {
+        matches!(
+            err,
+            EngineError::RateLimited { .. } | EngineError::Overloaded { .. }
+        )
+    };
+    let mut batch: Batch = call_with_retries(policy, is_refusal, || {
+        send(client.post(BATCHES_URL).json(&body), api_key, timeouts)
+    })
+    .await?;
+    debug!("Created batch {}", batch.id);
+
+    let url = format!("{BATCHES_URL}/{}", batch.id);
+    let deadline = Instant::now() + MAX_WAIT;
+    while batch.processing_status != "ended" {
+        if Instant::now() >= deadline {
+            warn!(
+                "The batch {} didn't end within {MAX_WAIT:?}, canceling it",
+                batch.id
+            );
+            let canceled: Result<Batch> =
+                send(client.post(format!("{url}/cancel")), api_key, timeouts).await;
+            if let Err(err) = canceled {
+                warn!("Failed to cancel the batch {}: {err:#}", batch.id);
+            }
+            return Ok(None);
+        }
+        sleep(POLL_INTERVAL).await;
+        batch = call_with_retries(policy, EngineError::is_transient, || {
+            send(client.get(&url), api_key, timeouts)
+        })
+        .await?;
+    }
+
+    let results_url = batch
+        .results_url
+        .ok_or_else(|| eyre!("The batch {} ended without results", batch.id))?;
+    let results = call_with_retries(policy, EngineError::is_transient, || {
+        send_raw(client.get(&results_url), api_key, timeouts)
+    })
+    .await?;
+    parse_results(&results).map(Some)
+}
+
+async fn send<T: for<'de> Deserialize<'de>>(
+    request: reqwest::RequestBuilder,
+    api_key: &str,
+    timeouts: Timeouts,
+) -> Result<T> {
+    let body = send_raw(request, api_key, timeouts).await?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+async fn send_raw(
+    request: reqwest::RequestBuilder,
+    api_key: &str,
+    timeouts: Timeouts,
+) -> Result<String> {
+    let request = request
+        .header("x-api-key", api_key)
+        .header("anthropic-version", HeaderValue::from_static("2023-06-01"))
+        .send();
+    let res = timeouts
+        .response(ModelProvider::ANTHROPIC, request)
+        .await??;
+    let status = res.status();
+    let body = res.text().await?;
+    if !status.is_success() {
+        Err(EngineError::from_status("Anthropic", status, &body))?;
+    }
+    Ok(body)
+}
+
+/// The results are a JSON lines file, with one line per request of the batch
+fn parse_results(results: &str) -> Result<OutputMessage> {
+    let line = results
+        .lines()
+        .find(|l| !l.trim().is_empty())
+        .ok_or_else(|| eyre!("The batch results are empty"))?;
+    let result: BatchResult = serde_json::from_str(line)?;
+    match result.result {
+        ResultKind::Succeeded { message } => Ok(OutputMessage {
+            input_tokens: message.usage.input_tokens,
+            output_tokens: message.usage.output_tokens,
+            text: message
+                .content
+                .into_iter()
+                .filter(|block| block.block_type == "text")
+                .map(|block| block.text)
+                .collect(),
+            tool_calls: vec![],
+            thinking: vec![],
+        }),
+        ResultKind::Errored { error } => {
+            let err = ClaudeApiError::from_type(&error.error.error_type, error.error.message);
+            Err(EngineError::from(err).into())
+        }
+        ResultKind::Canceled => Err(eyre!("The batch was canceled")),
+        ResultKind::Expired => Err(eyre!("The batch expired before it was processed")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_results() {
+        let results = concat!(
+            r#"{"custom_id":"request","result":{"type":"succeeded","message":{"id":"msg_1","#,
+            r#""type":"message","role":"assistant","content":[{"type":"thinking","thinking":"hm"},"#,
+            r#"{"type":"text","text":"The summary"}],"usage":{"input_tokens":900,"output_tokens":40}}}}"#,
+            "\n",
+        );
+        let msg = parse_results(results).unwrap();
+        assert_eq!(msg.text, "The summary");
+        assert_eq!((msg.input_tokens, msg.output_tokens), (900, 40));
+
+        let errored = r#"{"custom_id":"request","result":{"type":"errored","error":{"type":"error","error":{"type":"overloaded_error","message":"busy"}}}}"#;
+        let err = EngineError::from(parse_results(errored).unwrap_err());
+        assert!(err.is_transient(), "{err}");
+        assert!(parse_results(r#"{"custom_id":"request","result":{"type":"expired"}}"#).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::error::EngineError;
+
 /// Errors returned by the Claude / Anthropic API
 #[derive(Debug, Error)]
 pub enum ClaudeApiError {
@@ -52,3 +54,19 @@ impl ClaudeApiError {
         }
     }
 }
+
+impl From<ClaudeApiError> for EngineError {
+    fn from(value: ClaudeApiError) -> Self {
+        let provider = "Anthropic".to_string();
+        match value {
+            ClaudeApiError::Authentication { message } | ClaudeApiError::Permission { message } => {
+                Self::ApiAuth { provider, message }
+            }
+            ClaudeApiError::RateLimit { message } => Self::RateLimited { provider, message },
+            ClaudeApiError::Overloaded { message } | ClaudeApiError::Api { message } => {
+                Self::Overloaded { provider, message }
+            }
+            other => Self::Other(other.into()),
+        }
+    }
+}
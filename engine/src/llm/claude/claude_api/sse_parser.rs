@@ -2,19 +2,11 @@
 /// this module holds types related to API responses. I don't want warnings
 /// just cause the fields are unused
 use bytes::Bytes;
-use color_eyre::{Result, eyre::bail};
+use color_eyre::Result;
 use serde::Deserialize;
 
 use super::ClaudeApiError;
-
-#[derive(Debug)]
-pub struct RawEvent {
-    /// Value from `event:` (e.g. "content_block_delta", "ping")
-    pub event_type: Option<String>,
-
-    /// Concatenated `data:` payload (may contain newlines)
-    pub data: String,
-}
+use crate::llm::sse::{self, RawEvent};
 
 #[derive(Debug)]
 pub enum Event {
@@ -58,7 +50,18 @@ pub struct ContentBlockStart {
 pub struct ContentBlock {
     #[serde(rename = "type")]
     pub block_type: String,
+    #[serde(default)]
     pub text: String,
+    /// only set for `tool_use` blocks
+    pub id: Option<String>,
+    /// only set for `tool_use` blocks
+    pub name: Option<String>,
+    #[serde(default)]
+    pub thinking: String,
+    #[serde(default)]
+    pub signature: String,
+    /// only set for `redacted_thinking` blocks
+    pub data: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -71,7 +74,17 @@ pub struct ContentBlockDelta {
 pub struct TextDelta {
     #[serde(rename = "type")]
     pub delta_type: String,
+    #[serde(default)]
     pub text: String,
+    /// a piece of the input of a tool call, for `input_json_delta`s
+    #[serde(default)]
+    pub partial_json: String,
+    /// for `thinking_delta`s
+    #[serde(default)]
+    pub thinking: String,
+    /// for `signature_delta`s
+    #[serde(default)]
+    pub signature: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -109,67 +122,27 @@ pub struct InnerError {
     pub message: String,
 }
 
+/// Turns the raw events of `sse::Parser` into typed events
 #[derive(Default)]
 pub struct Parser {
-    bytes: Vec<u8>,
-    index: usize,
+    inner: sse::Parser,
 }
 
 impl Parser {
     /// Feed a chunk into the parser, returning all complete events
     pub fn process(&mut self, chunk: Bytes) -> Result<Vec<Event>> {
-        self.bytes.extend_from_slice(&chunk);
-        let mut events = vec![];
-
-        loop {
-            // search for \n\n in unprocessed buffer
-            let unprocessed = &self.bytes[self.index..];
-            if let Some(pos) = unprocessed.windows(2).position(|w| w == b"\n\n") {
-                let event_bytes = &unprocessed[..pos];
-                let event = Self::parse_sse_event(event_bytes)?;
-                events.push(Event::from_raw_event(event));
-                self.index += pos + 2; // advance past the \n\n
-            } else {
-                break;
-            }
-        }
-
-        Ok(events)
+        Ok(self
+            .inner
+            .process(&chunk)?
+            .into_iter()
+            .map(Event::from_raw_event)
+            .collect())
     }
 
     /// Parse any remaining bytes in the buffer as a final event (even without \n\n)
     pub fn parse_remaining(&mut self) -> Option<Event> {
-        if self.index < self.bytes.len() {
-            let remaining = &self.bytes[self.index..];
-            let event = Self::parse_sse_event(remaining).ok()?;
-            self.index = self.bytes.len();
-            Some(Event::from_raw_event(event))
-        } else {
-            None
-        }
-    }
-
-    /// Parses a single raw SSE event from the buffer.
-    /// Returns (RawEvent, number of bytes consumed) if successful.
-    fn parse_sse_event(buf: &[u8]) -> Result<RawEvent> {
-        let text = std::str::from_utf8(buf)?;
-        let mut event_type = None;
-        let mut data = String::new();
-
-        for line in text.lines() {
-            if let Some(prefix) = line.strip_prefix("event: ") {
-                event_type = Some(prefix.to_string());
-            } else if let Some(prefix) = line.strip_prefix("data: ") {
-                if !data.is_empty() {
-                    data.push('\n');
-                }
-                data.push_str(prefix);
-            } else {
-                bail!("Unexpected line while parsing SSE event {line}\n\nEvent:\n{text}");
-            }
-        }
-
-        Ok(RawEvent { event_type, data })
+        let event = self.inner.parse_remaining().ok()??;
+        Some(Event::from_raw_event(event))
     }
 }
 
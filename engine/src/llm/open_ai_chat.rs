@@ -1,11 +1,24 @@
 use async_stream::try_stream;
-use color_eyre::eyre::{Context, eyre};
-use log::{debug, error};
+use color_eyre::{
+    Result,
+    eyre::{Context, eyre},
+};
+use log::{debug, error, warn};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 
-use super::{LLM, LLMStream, OutputMessage, Request, ResponseFragment, Role};
+use std::sync::Arc;
+
+use super::{
+    LLM, LLMStream, ModelProvider, OutputMessage, Request, ResponseFragment, RetryPolicy, Role,
+    Timeouts, retry,
+    sse::{self, RawEvent},
+};
+use crate::{
+    error::EngineError,
+    rate_limit::{self, RateLimiter},
+};
 
 #[derive(Debug, Clone)]
 pub struct OpenAIChat {
@@ -14,11 +27,21 @@ pub struct OpenAIChat {
     base_url: String,
     model: String,
     provider_order: Vec<String>,
+    provider: ModelProvider,
+    rate_limiter: Arc<RateLimiter>,
+    retry_policy: RetryPolicy,
+    timeouts: Timeouts,
 }
 
 impl OpenAIChat {
-    pub fn new(api_key: String, base_url: impl Into<String>, model: impl Into<String>) -> Self {
+    pub fn new(
+        provider: ModelProvider,
+        api_key: String,
+        base_url: impl Into<String>,
+        model: impl Into<String>,
+    ) -> Self {
         Self::new_with_provider_order(
+            provider,
             api_key,
             base_url,
             model,
@@ -27,6 +50,7 @@ impl OpenAIChat {
     }
 
     pub fn new_with_provider_order<I, S>(
+        provider: ModelProvider,
         api_key: String,
         base_url: impl Into<String>,
         model: impl Into<String>,
@@ -42,72 +66,86 @@ impl OpenAIChat {
             base_url: base_url.into(),
             model: model.into(),
             provider_order: provider_order.into_iter().map(Into::into).collect(),
+            provider,
+            rate_limiter: rate_limit::shared(provider),
+            retry_policy: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
         }
     }
-}
 
-impl LLM for OpenAIChat {
-    fn send_request_stream(&mut self, req: Request) -> LLMStream<'_> {
+    fn request_body(&self, req: Request) -> OpenAIChatRequest {
+        let mut messages = Vec::new();
+
+        if let Some(system) = req.system {
+            messages.push(OpenAIMessage {
+                role: "system",
+                content: system,
+            });
+        }
+
+        for msg in req.messages {
+            messages.push(OpenAIMessage {
+                role: match msg.role {
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                },
+                content: msg.content.text(),
+            });
+        }
+
+        OpenAIChatRequest {
+            model: self.model.clone(),
+            messages,
+            // max_tokens: req.max_tokens,
+            stream: true,
+            stream_options: StreamOptions {
+                include_usage: true,
+            },
+            provider: OpenRouterProvider::from_order(self.provider_order.clone()),
+            temperature: req.sampling.temperature,
+            top_p: req.sampling.top_p,
+            stop: req.sampling.stop_sequences,
+        }
+    }
+
+    /// Sends the request once
+    fn stream_response(
+        &self,
+        body: OpenAIChatRequest,
+    ) -> impl Stream<Item = Result<ResponseFragment>> + Send + 'static {
         let client = self.client.clone();
         let api_key = self.api_key.clone();
         let url = self.base_url.clone();
-        let model = self.model.clone();
-        let provider_order = self.provider_order.clone();
-
-        Box::pin(try_stream! {
-            // Build messages
-            let mut messages = Vec::new();
-
-            if let Some(system) = req.system {
-                messages.push(OpenAIMessage {
-                    role: "system",
-                    content: system,
-                });
-            }
-
-            for msg in req.messages {
-                messages.push(OpenAIMessage {
-                    role: match msg.role {
-                        Role::User => "user",
-                        Role::Assistant => "assistant",
-                    },
-                    content: msg.content,
-                });
-            }
+        let model = body.model.clone();
+        let provider = self.provider;
+        let rate_limiter = self.rate_limiter.clone();
+        let timeouts = self.timeouts;
 
-            let body = OpenAIChatRequest {
-                model: model.clone(),
-                messages,
-                // max_tokens: req.max_tokens,
-                stream: true,
-                provider: OpenRouterProvider::from_order(provider_order),
-            };
-
-            let res = client
+        try_stream! {
+            rate_limiter.acquire().await;
+            let request = client
                 .post(&url)
                 .bearer_auth(api_key)
                 .json(&body)
-                .send()
-                .await.context("initial response")?;
+                .send();
+            let res = timeouts
+                .response(provider, request)
+                .await?
+                .context("initial response")?;
 
 
              if !res.status().is_success() {
                 let status = res.status();
                 let body = res.text().await.unwrap_or_default();
-                Err(eyre!("OpenAI error {}: {}", status, body))?;
+                Err(EngineError::from_status(&provider.to_string(), status, &body))?;
             } else {
                 debug!("Openai response:\n{res:#?}");
                 let mut stream = res.bytes_stream();
-
-                let mut full_text = String::new();
-                let mut input_tokens = 0usize;
-                let mut output_tokens = 0usize;
+                let mut decoder = StreamDecoder::default();
                 let mut chunk_count = 0usize;
-                let mut data_line_count = 0usize;
                 let mut last_chunk_preview = None::<String>;
-                let mut last_data_line = None::<String>;
 
-                while let Some(chunk) = stream.next().await {
+                while let Some(chunk) = timeouts.next_chunk(provider, stream.next()).await? {
                     let chunk = match chunk {
                         Ok(chunk) => chunk,
                         Err(err) => {
@@ -117,53 +155,45 @@ impl LLM for OpenAIChat {
                     };
 
                     chunk_count += 1;
-                    let text = std::str::from_utf8(&chunk).context("chunk to utf-8")?;
-                    last_chunk_preview = Some(text.chars().take(300).collect());
-
-                    for line in text.lines() {
-                        let Some(data) = line.trim().strip_prefix("data: ") else {
-                            continue;
-                        };
-                        data_line_count += 1;
-                        last_data_line = Some(data.chars().take(300).collect());
-
-                        if data == "[DONE]" {
-                            yield ResponseFragment::MessageComplete(OutputMessage {
-                                input_tokens,
-                                output_tokens,
-                                text: full_text.clone(),
-                            });
-                            return;
-                        }
-
-                        let event: OpenAIStreamChunk = serde_json::from_str(data).context("parsing stream chunk")?;
-
-                        if let Some(choice) = event.choices.first()
-                            && let Some(content) = &choice.delta.content
-                        {
-                            output_tokens += 1; // token estimate; provider may differ
-                            full_text.push_str(content);
-                            yield ResponseFragment::TextDelta(content.clone());
-                        }
+                    last_chunk_preview = Some(String::from_utf8_lossy(&chunk).chars().take(300).collect());
 
-                        if let Some(usage) = event.usage {
-                            input_tokens = usage.prompt_tokens;
-                            output_tokens = usage.completion_tokens;
-                        }
+                    for fragment in decoder.feed(&chunk)? {
+                        yield fragment;
+                    }
+                    if decoder.done {
+                        return;
                     }
                 }
 
+                for fragment in decoder.finish()? {
+                    yield fragment;
+                }
+                if decoder.done {
+                    return;
+                }
+
                 error!(
-                    "OpenAI stream ended without [DONE]. model={model}, url={url}, chunks={chunk_count}, data_lines={data_line_count}, text_len={}, input_tokens={}, output_tokens={}, last_chunk_preview={:?}, last_data_line={:?}",
-                    full_text.len(),
-                    input_tokens,
-                    output_tokens,
+                    "OpenAI stream ended without [DONE]. model={model}, url={url}, chunks={chunk_count}, data_lines={}, text_len={}, input_tokens={}, output_tokens={}, last_chunk_preview={:?}, last_data_line={:?}",
+                    decoder.data_line_count,
+                    decoder.full_text.len(),
+                    decoder.usage.as_ref().map_or(0, |u| u.prompt_tokens),
+                    decoder.output_tokens(),
                     last_chunk_preview,
-                    last_data_line,
+                    decoder.last_data_line,
                 );
                 Err(eyre!("OpenAI stream ended without [DONE]"))?;
             }
-        })
+        }
+    }
+}
+
+impl LLM for OpenAIChat {
+    fn send_request_stream(&mut self, req: Request) -> LLMStream<'_> {
+        let body = self.request_body(req);
+        let this = &*self;
+        Box::pin(retry::with_retries(self.retry_policy, move || {
+            this.stream_response(body.clone())
+        }))
     }
 
     fn clone(&self) -> Box<dyn LLM + Send + 'static> {
@@ -173,25 +203,144 @@ impl LLM for OpenAIChat {
             base_url: self.base_url.clone(),
             model: self.model.clone(),
             provider_order: self.provider_order.clone(),
+            provider: self.provider,
+            rate_limiter: self.rate_limiter.clone(),
+            retry_policy: self.retry_policy,
+            timeouts: self.timeouts,
         })
     }
+
+    fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    fn provider(&self) -> ModelProvider {
+        self.provider
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+}
+
+/// Turns the raw bytes of a response stream into response fragments
+#[derive(Default)]
+struct StreamDecoder {
+    parser: sse::Parser,
+    full_text: String,
+    /// what the provider reported in the last chunk, if it did
+    usage: Option<OpenAIUsage>,
+    n_deltas: usize,
+    /// set once `[DONE]` was received. Everything after that is ignored.
+    done: bool,
+    data_line_count: usize,
+    last_data_line: Option<String>,
+}
+
+impl StreamDecoder {
+    fn feed(&mut self, chunk: &[u8]) -> Result<Vec<ResponseFragment>> {
+        let events = self.parser.process(chunk)?;
+        self.handle_events(events)
+    }
+
+    /// Must be called when the stream ended, in case the last event wasn't terminated
+    fn finish(&mut self) -> Result<Vec<ResponseFragment>> {
+        let events = self.parser.parse_remaining()?;
+        self.handle_events(events)
+    }
+
+    /// The reported number, or an estimate if the provider didn't send the usage
+    fn output_tokens(&self) -> usize {
+        self.usage
+            .as_ref()
+            .map_or(self.n_deltas, |u| u.completion_tokens)
+    }
+
+    fn handle_events(
+        &mut self,
+        events: impl IntoIterator<Item = RawEvent>,
+    ) -> Result<Vec<ResponseFragment>> {
+        let mut fragments = vec![];
+        for event in events {
+            if self.done {
+                break;
+            }
+            if let Some(fragment) = self.handle_event(event)? {
+                fragments.push(fragment);
+            }
+        }
+        Ok(fragments)
+    }
+
+    fn handle_event(&mut self, event: RawEvent) -> Result<Option<ResponseFragment>> {
+        let data = event.data.trim();
+        if data.is_empty() {
+            return Ok(None);
+        }
+        self.data_line_count += 1;
+        self.last_data_line = Some(data.chars().take(300).collect());
+
+        if data == "[DONE]" {
+            self.done = true;
+            if self.usage.is_none() {
+                warn!("The response didn't include the token usage, it is estimated");
+            }
+            return Ok(Some(ResponseFragment::MessageComplete(OutputMessage {
+                input_tokens: self.usage.as_ref().map_or(0, |u| u.prompt_tokens),
+                output_tokens: self.output_tokens(),
+                text: self.full_text.clone(),
+                tool_calls: vec![],
+                thinking: vec![],
+            })));
+        }
+
+        let event: OpenAIStreamChunk =
+            serde_json::from_str(data).context("parsing stream chunk")?;
+
+        if event.usage.is_some() {
+            self.usage = event.usage;
+        }
+
+        if let Some(choice) = event.choices.first()
+            && let Some(content) = &choice.delta.content
+        {
+            self.n_deltas += 1;
+            self.full_text.push_str(content);
+            return Ok(Some(ResponseFragment::TextDelta(content.clone())));
+        }
+
+        Ok(None)
+    }
 }
 
 //
 // ===== OpenAI wire types =====
 //
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct OpenAIChatRequest {
     model: String,
     messages: Vec<OpenAIMessage>,
     // max_tokens: usize,
     stream: bool,
+    stream_options: StreamOptions,
     #[serde(skip_serializing_if = "Option::is_none")]
     provider: Option<OpenRouterProvider>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
+struct StreamOptions {
+    /// makes the provider send a last chunk with the usage and no choices
+    include_usage: bool,
+}
+
+#[derive(Serialize, Clone)]
 struct OpenRouterProvider {
     order: Vec<String>,
 }
@@ -206,7 +355,7 @@ impl OpenRouterProvider {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct OpenAIMessage {
     role: &'static str,
     content: String,
@@ -214,6 +363,7 @@ struct OpenAIMessage {
 
 #[derive(Deserialize)]
 struct OpenAIStreamChunk {
+    #[serde(default)]
     choices: Vec<OpenAIStreamChoice>,
     #[serde(default)]
     usage: Option<OpenAIUsage>,
@@ -235,3 +385,99 @@ struct OpenAIUsage {
     prompt_tokens: usize,
     completion_tokens: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM: &str = concat!(
+        ": OPENROUTER PROCESSING\n\n",
+        "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"Hällo\"}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\", wörld 🌍\"}}]}\r\n\r\n",
+        "data: {\"choices\":[{\"delta\":{}}],\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":5}}\n\n",
+        "data: [DONE]",
+    );
+
+    fn decode(chunks: impl IntoIterator<Item = &'static [u8]>) -> Vec<ResponseFragment> {
+        let mut decoder = StreamDecoder::default();
+        let mut fragments = vec![];
+        for chunk in chunks {
+            fragments.extend(decoder.feed(chunk).unwrap());
+        }
+        fragments.extend(decoder.finish().unwrap());
+        assert!(decoder.done);
+        fragments
+    }
+
+    fn text_and_usage(fragments: &[ResponseFragment]) -> (String, Option<(usize, usize, String)>) {
+        let mut text = String::new();
+        let mut complete = None;
+        for fragment in fragments {
+            match fragment {
+                ResponseFragment::TextDelta(delta) => text.push_str(delta),
+                ResponseFragment::ThinkingDelta(_) => panic!("OpenAI models don't think"),
+                ResponseFragment::MessageComplete(msg) => {
+                    assert!(complete.is_none(), "more than one MessageComplete");
+                    complete = Some((msg.input_tokens, msg.output_tokens, msg.text.clone()));
+                }
+            }
+        }
+        (text, complete)
+    }
+
+    #[test]
+    fn decodes_every_chunk_split() {
+        let expected_text = "Hällo, wörld 🌍".to_string();
+        for chunk_size in 1..=STREAM.len() {
+            let fragments = decode(STREAM.as_bytes().chunks(chunk_size));
+            let (text, complete) = text_and_usage(&fragments);
+            assert_eq!(text, expected_text, "chunk size {chunk_size}");
+            assert_eq!(
+                complete,
+                Some((12, 5, expected_text.clone())),
+                "chunk size {chunk_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_every_two_way_split() {
+        let bytes = STREAM.as_bytes();
+        for split in 0..=bytes.len() {
+            let fragments = decode([&bytes[..split], &bytes[split..]]);
+            let (text, complete) = text_and_usage(&fragments);
+            assert_eq!(text, "Hällo, wörld 🌍", "split at {split}");
+            assert!(complete.is_some(), "split at {split}");
+        }
+    }
+
+    #[test]
+    fn usage_comes_from_the_last_chunk() {
+        let fragments = decode([concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}],\"usage\":null}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":40,\"completion_tokens\":2}}\n\n",
+            "data: [DONE]\n\n",
+        )
+        .as_bytes()]);
+        assert_eq!(text_and_usage(&fragments).1, Some((40, 2, "Hi".into())));
+
+        // without it, each delta counts as a token
+        let fragments = decode([concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\" there\"}}]}\n\n",
+            "data: [DONE]\n\n",
+        )
+        .as_bytes()]);
+        assert_eq!(text_and_usage(&fragments).1, Some((0, 2, "Hi there".into())));
+    }
+
+    #[test]
+    fn ignores_everything_after_done() {
+        let fragments = decode([
+            b"data: [DONE]\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"x\"}}]}\n\n".as_slice(),
+        ]);
+        assert_eq!(fragments.len(), 1);
+        assert!(matches!(fragments[0], ResponseFragment::MessageComplete(_)));
+    }
+}
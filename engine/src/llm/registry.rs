@@ -0,0 +1,420 @@
+//! The providers and models that can be selected. Most providers offer an OpenAI-compatible
+//! API, so adding one only takes an entry in [`PROVIDERS`] and one in [`MODELS`].
+//!
+//! Providers and models are identified by their ids, which are serialized like the variants
+//! of the enums they replaced, so existing configs stay valid.
+
+use std::{fmt, str::FromStr, sync::LazyLock};
+
+use clap::builder::PossibleValue;
+use color_eyre::eyre::{Report, eyre};
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, DeserializeSeed, EnumAccess, VariantAccess, Visitor},
+};
+
+use super::{Claude, OpenAIChat};
+use crate::LLMBox;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Api {
+    Anthropic,
+    /// the chat completions endpoint at `url`
+    OpenAIChat {
+        url: &'static str,
+    },
+    /// an OpenAI-compatible server, whose URL is configured by the user
+    CustomEndpoint,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProviderEntry {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub api: Api,
+    /// stays below the documented limit of the lowest paid tier
+    pub requests_per_minute: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModelEntry {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub provider: ModelProvider,
+    /// `None` if the user enters the model id, see [`ProvidedModel::make`]
+    pub model_id: Option<&'static str>,
+    /// how many tokens a request may have, `None` if it depends on the model id or isn't known
+    pub context_tokens: Option<usize>,
+}
+
+pub static PROVIDERS: &[ProviderEntry] = &[
+    ProviderEntry {
+        id: "Anthropic",
+        name: "Anthropic",
+        api: Api::Anthropic,
+        requests_per_minute: 50,
+    },
+    ProviderEntry {
+        id: "Openrouter",
+        name: "Openrouter",
+        api: Api::OpenAIChat {
+            url: "https://openrouter.ai/api/v1/chat/completions",
+        },
+        requests_per_minute: 60,
+    },
+    ProviderEntry {
+        id: "CustomEndpoint",
+        name: "Custom endpoint",
+        api: Api::CustomEndpoint,
+        // usually a local server, that doesn't limit anything
+        requests_per_minute: 600,
+    },
+    ProviderEntry {
+        id: "Mistral",
+        name: "Mistral",
+        api: Api::OpenAIChat {
+            url: "https://api.mistral.ai/v1/chat/completions",
+        },
+        requests_per_minute: 60,
+    },
+    ProviderEntry {
+        id: "DeepSeek",
+        name: "DeepSeek",
+        api: Api::OpenAIChat {
+            url: "https://api.deepseek.com/chat/completions",
+        },
+        requests_per_minute: 60,
+    },
+    ProviderEntry {
+        id: "Xai",
+        name: "xAI",
+        api: Api::OpenAIChat {
+            url: "https://api.x.ai/v1/chat/completions",
+        },
+        requests_per_minute: 60,
+    },
+];
+
+pub static MODELS: &[ModelEntry] = &[
+    ModelEntry {
+        id: "ClaudeSonette",
+        name: "Claude Sonette - latest (Anthropic)",
+        provider: ModelProvider::ANTHROPIC,
+        model_id: Some("claude-sonnet-4-6"),
+        context_tokens: Some(200_000),
+    },
+    ModelEntry {
+        id: "ClaudeSonette45",
+        name: "Claude Sonette 4.5 (Anthropic)",
+        provider: ModelProvider::ANTHROPIC,
+        model_id: Some("claude-sonnet-4-5"),
+        context_tokens: Some(200_000),
+    },
+    ModelEntry {
+        id: "ClaudeHaiku",
+        name: "Claude Haiku - latest (Anthropic)",
+        provider: ModelProvider::ANTHROPIC,
+        model_id: Some("claude-haiku-4-5"),
+        context_tokens: Some(200_000),
+    },
+    ModelEntry {
+        id: "Aion2Openr",
+        name: "Aion-2.0 (openrouter.ai)",
+        provider: ModelProvider::OPENROUTER,
+        model_id: Some("aion-labs/aion-2.0"),
+        context_tokens: None,
+    },
+    ModelEntry {
+        id: "Flex",
+        name: "Kimmi K2.5 (openrouter.ai)",
+        provider: ModelProvider::OPENROUTER,
+        model_id: Some("moonshotai/kimi-k2.5"),
+        context_tokens: Some(262_144),
+    },
+    ModelEntry {
+        id: "Glm5",
+        name: "GLM 5 (openrouter.ai)",
+        provider: ModelProvider::OPENROUTER,
+        model_id: Some("z-ai/glm-5"),
+        context_tokens: None,
+    },
+    ModelEntry {
+        id: "OpenrouterCustom",
+        name: "Other model (openrouter.ai)",
+        provider: ModelProvider::OPENROUTER,
+        model_id: None,
+        context_tokens: None,
+    },
+    ModelEntry {
+        id: "MistralLarge",
+        name: "Mistral Large (Mistral)",
+        provider: ModelProvider("Mistral"),
+        model_id: Some("mistral-large-latest"),
+        context_tokens: Some(128_000),
+    },
+    ModelEntry {
+        id: "DeepSeekChat",
+        name: "DeepSeek V3 (DeepSeek)",
+        provider: ModelProvider("DeepSeek"),
+        model_id: Some("deepseek-chat"),
+        context_tokens: Some(128_000),
+    },
+    ModelEntry {
+        id: "Grok4",
+        name: "Grok 4 (xAI)",
+        provider: ModelProvider("Xai"),
+        model_id: Some("grok-4"),
+        context_tokens: Some(256_000),
+    },
+];
+
+/// The id of an entry of [`PROVIDERS`]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ModelProvider(&'static str);
+
+/// The id of an entry of [`MODELS`]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProvidedModel(&'static str);
+
+impl ModelProvider {
+    pub const ANTHROPIC: Self = Self("Anthropic");
+    pub const OPENROUTER: Self = Self("Openrouter");
+    pub const CUSTOM_ENDPOINT: Self = Self("CustomEndpoint");
+
+    pub fn iter() -> impl Iterator<Item = Self> {
+        PROVIDERS.iter().map(|p| Self(p.id))
+    }
+
+    pub fn id(self) -> &'static str {
+        self.0
+    }
+
+    pub fn entry(self) -> &'static ProviderEntry {
+        PROVIDERS
+            .iter()
+            .find(|p| p.id == self.0)
+            .expect("provider ids are only created from the registry")
+    }
+}
+
+impl ProvidedModel {
+    pub fn iter() -> impl Iterator<Item = Self> {
+        MODELS.iter().map(|m| Self(m.id))
+    }
+
+    pub fn id(self) -> &'static str {
+        self.0
+    }
+
+    pub fn entry(self) -> &'static ModelEntry {
+        MODELS
+            .iter()
+            .find(|m| m.id == self.0)
+            .expect("model ids are only created from the registry")
+    }
+
+    pub fn provider(self) -> ModelProvider {
+        self.entry().provider
+    }
+
+    /// Whether the user has to enter the model id, which is passed to `make`
+    pub fn needs_model_id(self) -> bool {
+        self.entry().model_id.is_none()
+    }
+
+    pub fn context_tokens(self) -> Option<usize> {
+        self.entry().context_tokens
+    }
+
+    /// `custom_model_id` is only used by models that need it, e.g.
+    /// `meta-llama/llama-3.3-70b-instruct`
+    pub fn make(self, api_key: String, custom_model_id: &str) -> LLMBox {
+        let entry = self.entry();
+        let model_id = entry.model_id.unwrap_or(custom_model_id);
+        match entry.provider.entry().api {
+            Api::Anthropic => Box::new(Claude::new(api_key, model_id.into())),
+            Api::OpenAIChat { url } => {
+                Box::new(OpenAIChat::new(entry.provider, api_key, url, model_id))
+            }
+            Api::CustomEndpoint => unreachable!("custom endpoints are made by `CustomEndpoint`"),
+        }
+    }
+}
+
+impl Default for ProvidedModel {
+    fn default() -> Self {
+        Self("Glm5")
+    }
+}
+
+impl fmt::Debug for ModelProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl fmt::Debug for ProvidedModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl fmt::Display for ModelProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.entry().name)
+    }
+}
+
+impl fmt::Display for ProvidedModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.entry().name)
+    }
+}
+
+impl FromStr for ProvidedModel {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::iter()
+            .find(|m| m.0.eq_ignore_ascii_case(s))
+            .ok_or_else(|| eyre!("Unknown model: {s}"))
+    }
+}
+
+impl clap::ValueEnum for ProvidedModel {
+    fn value_variants<'a>() -> &'a [Self] {
+        static ALL: LazyLock<Vec<ProvidedModel>> =
+            LazyLock::new(|| ProvidedModel::iter().collect());
+        &ALL
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(PossibleValue::new(self.0).help(self.entry().name))
+    }
+}
+
+static PROVIDER_IDS: LazyLock<Vec<&'static str>> =
+    LazyLock::new(|| PROVIDERS.iter().map(|p| p.id).collect());
+static MODEL_IDS: LazyLock<Vec<&'static str>> =
+    LazyLock::new(|| MODELS.iter().map(|m| m.id).collect());
+
+impl Serialize for ModelProvider {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_id(serializer, "ModelProvider", &PROVIDER_IDS, self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for ModelProvider {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_id(deserializer, "ModelProvider", &PROVIDER_IDS).map(Self)
+    }
+}
+
+impl Serialize for ProvidedModel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_id(serializer, "ProvidedModel", &MODEL_IDS, self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProvidedModel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_id(deserializer, "ProvidedModel", &MODEL_IDS).map(Self)
+    }
+}
+
+fn serialize_id<S: Serializer>(
+    serializer: S,
+    name: &'static str,
+    ids: &'static [&'static str],
+    id: &'static str,
+) -> Result<S::Ok, S::Error> {
+    let index = ids.iter().position(|i| *i == id).unwrap_or_default();
+    serializer.serialize_unit_variant(name, index as u32, id)
+}
+
+fn deserialize_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    name: &'static str,
+    ids: &'static [&'static str],
+) -> Result<&'static str, D::Error> {
+    struct IdVisitor(&'static [&'static str]);
+
+    impl IdVisitor {
+        fn find<E: de::Error>(&self, id: &str) -> Result<&'static str, E> {
+            self.0
+                .iter()
+                .find(|i| **i == id)
+                .copied()
+                .ok_or_else(|| E::unknown_variant(id, self.0))
+        }
+    }
+
+    impl<'de> Visitor<'de> for IdVisitor {
+        type Value = &'static str;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "one of {:?}", self.0)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            self.find(v)
+        }
+
+        fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+            let (id, variant) = data.variant_seed(self)?;
+            variant.unit_variant()?;
+            Ok(id)
+        }
+    }
+
+    // RON writes variants as bare identifiers, which it only reads as such
+    impl<'de> DeserializeSeed<'de> for IdVisitor {
+        type Value = &'static str;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_identifier(self)
+        }
+    }
+
+    deserializer.deserialize_enum(name, ids, IdVisitor(ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_unique_and_models_have_providers() {
+        for (i, id) in PROVIDER_IDS.iter().enumerate() {
+            assert!(!PROVIDER_IDS[i + 1..].contains(id), "{id}");
+        }
+        for (i, id) in MODEL_IDS.iter().enumerate() {
+            assert!(!MODEL_IDS[i + 1..].contains(id), "{id}");
+        }
+        for model in ProvidedModel::iter() {
+            assert_ne!(
+                model.provider().entry().api,
+                Api::CustomEndpoint,
+                "{model:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn serialized_like_the_former_enums() {
+        let model = ProvidedModel::from_str("claudehaiku").unwrap();
+        assert_eq!(ron::to_string(&model).unwrap(), "ClaudeHaiku");
+        assert_eq!(serde_json::to_string(&model).unwrap(), r#""ClaudeHaiku""#);
+
+        let config = "{Anthropic:\"key\",CustomEndpoint:\"\"}";
+        let tokens: std::collections::BTreeMap<ModelProvider, String> =
+            ron::from_str(config).unwrap();
+        assert_eq!(tokens[&ModelProvider::ANTHROPIC], "key");
+        assert!(ron::from_str::<ModelProvider>("Unknown").is_err());
+        let provider: ModelProvider = serde_json::from_str(r#""Xai""#).unwrap();
+        assert_eq!(provider.to_string(), "xAI");
+    }
+}
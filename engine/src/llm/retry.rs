@@ -0,0 +1,236 @@
+//! Providers regularly answer with 429 or 529 when they are busy, which would end the turn
+//! with an error, although sending the same request a few seconds later usually works.
+//! Therefore requests that fail with a transient error are sent again, with exponential
+//! backoff.
+//!
+//! A request is only retried if none of its text was passed on yet, since the receiver
+//! can't take back fragments it already displayed.
+
+use std::{future::Future, time::Duration};
+
+use async_stream::try_stream;
+use color_eyre::{Report, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{pin, time::sleep};
+use tokio_stream::{Stream, StreamExt};
+
+use super::ResponseFragment;
+use crate::error::EngineError;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// including the first one, so 1 disables retrying
+    pub max_attempts: u32,
+    /// the delay before the first retry, it doubles with each further one
+    pub initial_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff_ms: 2000,
+        }
+    }
+}
+
+/// Passes on the stream returned by `send`, and calls `send` again if the stream fails with
+/// a transient error before yielding anything.
+pub fn with_retries<'a, S>(
+    policy: RetryPolicy,
+    mut send: impl FnMut() -> S + Send + 'a,
+) -> impl Stream<Item = Result<ResponseFragment>> + Send + 'a
+where
+    S: Stream<Item = Result<ResponseFragment>> + Send + 'a,
+{
+    try_stream! {
+        let mut backoff = Duration::from_millis(policy.initial_backoff_ms);
+        let mut attempt = 1;
+        loop {
+            let stream = send();
+            pin!(stream);
+            let mut received_any = false;
+            let mut failure = None;
+            while let Some(item) = stream.next().await {
+                match item {
+                    Ok(fragment) => {
+                        received_any = true;
+                        yield fragment;
+                    }
+                    Err(err) if !received_any && attempt < policy.max_attempts && is_transient(&err) => {
+                        failure = Some(err);
+                        break;
+                    }
+                    Err(err) => Err(err)?,
+                }
+            }
+
+            let Some(err) = failure else {
+                return;
+            };
+            warn!("Request attempt {attempt} failed, retrying in {backoff:?}: {err:#}");
+            sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// Calls `call` again while it fails with an error for which `retry` is true, with the same
+/// backoff as [`with_retries`]. For requests whose answer isn't streamed.
+pub async fn call_with_retries<T, F>(
+    policy: RetryPolicy,
+    retry: impl Fn(&EngineError) -> bool,
+    mut call: impl FnMut() -> F,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let mut backoff = Duration::from_millis(policy.initial_backoff_ms);
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Err(err)
+                if attempt < policy.max_attempts
+                    && err.downcast_ref::<EngineError>().is_some_and(&retry) =>
+            {
+                warn!("Request attempt {attempt} failed, retrying in {backoff:?}: {err:#}");
+                sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+fn is_transient(err: &Report) -> bool {
+    err.downcast_ref::<EngineError>()
+        .is_some_and(EngineError::is_transient)
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+    use crate::llm::OutputMessage;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        initial_backoff_ms: 1,
+    };
+
+    fn overloaded() -> Report {
+        EngineError::Overloaded {
+            provider: "Anthropic".into(),
+            message: "Overloaded".into(),
+        }
+        .into()
+    }
+
+    fn text(fragment: &ResponseFragment) -> &str {
+        match fragment {
+            ResponseFragment::TextDelta(t) | ResponseFragment::ThinkingDelta(t) => t,
+            ResponseFragment::MessageComplete(msg) => &msg.text,
+        }
+    }
+
+    /// Returns the texts of the fragments, or the error, and the number of attempts
+    async fn run(
+        mut attempt: impl FnMut(u32) -> Vec<Result<ResponseFragment>> + Send,
+    ) -> (Result<Vec<String>>, u32) {
+        let mut n_attempts = 0;
+        let mut stream = Box::pin(with_retries(POLICY, || {
+            n_attempts += 1;
+            tokio_stream::iter(attempt(n_attempts))
+        }));
+        let mut res = vec![];
+        let mut err = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(fragment) => res.push(text(&fragment).to_string()),
+                Err(e) => err = Some(e),
+            }
+        }
+        drop(stream);
+        (err.map_or(Ok(res), Err), n_attempts)
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let (res, n_attempts) = run(|n| {
+            if n < 3 {
+                vec![Err(overloaded())]
+            } else {
+                vec![
+                    Ok(ResponseFragment::TextDelta("Hi".into())),
+                    Ok(ResponseFragment::MessageComplete(OutputMessage {
+                        input_tokens: 1,
+                        output_tokens: 1,
+                        text: "Hi".into(),
+                        tool_calls: vec![],
+                        thinking: vec![],
+                    })),
+                ]
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), ["Hi", "Hi"]);
+        assert_eq!(n_attempts, 3);
+
+        let (res, n_attempts) = run(|_| vec![Err(overloaded())]).await;
+        assert!(res.is_err());
+        assert_eq!(n_attempts, POLICY.max_attempts);
+    }
+
+    #[tokio::test]
+    async fn no_retry_after_text_or_on_other_errors() {
+        let (res, n_attempts) = run(|_| {
+            vec![
+                Ok(ResponseFragment::TextDelta("Hi".into())),
+                Err(overloaded()),
+            ]
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(n_attempts, 1);
+
+        let (res, n_attempts) = run(|_| vec![Err(eyre!("invalid response"))]).await;
+        assert!(res.is_err());
+        assert_eq!(n_attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn calls_are_retried_while_the_error_is_accepted() {
+        let mut n_attempts = 0;
+        let res = call_with_retries(POLICY, EngineError::is_transient, || {
+            n_attempts += 1;
+            let attempt = n_attempts;
+            async move {
+                if attempt < 2 {
+                    Err(overloaded())
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 2);
+
+        n_attempts = 0;
+        let res: Result<()> = call_with_retries(
+            POLICY,
+            |_| false,
+            || {
+                n_attempts += 1;
+                async { Err(overloaded()) }
+            },
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(n_attempts, 1);
+    }
+}
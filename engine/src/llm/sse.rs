@@ -0,0 +1,141 @@
+//! A buffered parser for server-sent events, as used by all streaming LLM APIs.
+//!
+//! Network chunks don't respect line or event boundaries, so bytes are buffered until
+//! a line is complete, and lines are collected until a blank line ends the event.
+
+use color_eyre::Result;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RawEvent {
+    /// Value from `event:` (e.g. "content_block_delta", "ping")
+    pub event_type: Option<String>,
+
+    /// Concatenated `data:` payload (may contain newlines)
+    pub data: String,
+}
+
+#[derive(Default)]
+pub struct Parser {
+    /// bytes of the current, incomplete line
+    line: Vec<u8>,
+    event_type: Option<String>,
+    data: Option<String>,
+}
+
+impl Parser {
+    /// Feed a chunk into the parser, returning all complete events
+    pub fn process(&mut self, chunk: &[u8]) -> Result<Vec<RawEvent>> {
+        let mut events = vec![];
+        let mut rest = chunk;
+        while let Some(pos) = rest.iter().position(|b| *b == b'\n') {
+            self.line.extend_from_slice(&rest[..pos]);
+            rest = &rest[pos + 1..];
+
+            let mut line = std::mem::take(&mut self.line);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if let Some(event) = self.process_line(&line)? {
+                events.push(event);
+            }
+        }
+        self.line.extend_from_slice(rest);
+        Ok(events)
+    }
+
+    /// Treats the end of the stream as the end of the current line and event.
+    pub fn parse_remaining(&mut self) -> Result<Option<RawEvent>> {
+        let line = std::mem::take(&mut self.line);
+        let line_event = if line.is_empty() {
+            None
+        } else {
+            self.process_line(&line)?
+        };
+        Ok(line_event.or_else(|| self.take_event()))
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Result<Option<RawEvent>> {
+        if line.is_empty() {
+            return Ok(self.take_event());
+        }
+
+        let line = std::str::from_utf8(line)?;
+        if line.starts_with(':') {
+            // comment, e.g. keep-alives like ": OPENROUTER PROCESSING"
+            return Ok(None);
+        }
+
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => self.event_type = Some(value.to_string()),
+            "data" => {
+                let data = self.data.get_or_insert_default();
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(value);
+            }
+            // "id", "retry" and unknown fields are ignored, as the spec demands
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn take_event(&mut self) -> Option<RawEvent> {
+        if self.event_type.is_none() && self.data.is_none() {
+            return None;
+        }
+
+        Some(RawEvent {
+            event_type: self.event_type.take(),
+            data: self.data.take().unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_in_chunks(input: &[u8], chunk_size: usize) -> Vec<RawEvent> {
+        let mut parser = Parser::default();
+        let mut events = vec![];
+        for chunk in input.chunks(chunk_size) {
+            events.extend(parser.process(chunk).unwrap());
+        }
+        events.extend(parser.parse_remaining().unwrap());
+        events
+    }
+
+    fn ev(event_type: Option<&str>, data: &str) -> RawEvent {
+        RawEvent {
+            event_type: event_type.map(Into::into),
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn chunk_boundaries_dont_matter() {
+        let input = "event: a\ndata: {\"x\": \"ä€😀\"}\n\n: comment\n\ndata: line1\ndata:line2\r\n\r\ndata: [DONE]";
+        let expected = vec![
+            ev(Some("a"), "{\"x\": \"ä€😀\"}"),
+            ev(None, "line1\nline2"),
+            ev(None, "[DONE]"),
+        ];
+
+        for chunk_size in 1..=input.len() {
+            assert_eq!(
+                parse_in_chunks(input.as_bytes(), chunk_size),
+                expected,
+                "chunk size: {chunk_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_blank_line_does_not_duplicate_event() {
+        assert_eq!(parse_in_chunks(b"data: x\n\n", 3), vec![ev(None, "x")]);
+        assert_eq!(parse_in_chunks(b"data: x\n", 3), vec![ev(None, "x")]);
+    }
+}
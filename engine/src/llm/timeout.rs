@@ -0,0 +1,86 @@
+//! A request that hangs would block the turn forever, so the backends give up when the
+//! provider takes too long to answer, or stops sending in the middle of a response.
+//!
+//! There's no limit for the whole response, since thinking or long turns can legitimately take
+//! minutes. While a provider is busy it usually sends keep-alive pings, which reset the idle
+//! timer, so only streams that really stalled run into it.
+
+use std::{future::Future, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::timeout;
+
+use crate::{error::EngineError, llm::ModelProvider};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct Timeouts {
+    /// until the provider starts its response
+    pub response_secs: u64,
+    /// the longest gap between two chunks of a response
+    pub idle_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            response_secs: 120,
+            idle_secs: 60,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Waits for the response to start
+    pub(crate) async fn response<T>(
+        &self,
+        provider: ModelProvider,
+        fut: impl Future<Output = T>,
+    ) -> Result<T, EngineError> {
+        timeout(Duration::from_secs(self.response_secs), fut)
+            .await
+            .map_err(|_| EngineError::Timeout {
+                provider: provider.to_string(),
+                message: format!("no response within {}s", self.response_secs),
+            })
+    }
+
+    /// Waits for the next chunk of a response
+    pub(crate) async fn next_chunk<T>(
+        &self,
+        provider: ModelProvider,
+        fut: impl Future<Output = T>,
+    ) -> Result<T, EngineError> {
+        timeout(Duration::from_secs(self.idle_secs), fut)
+            .await
+            .map_err(|_| EngineError::Timeout {
+                provider: provider.to_string(),
+                message: format!("the response stalled for {}s", self.idle_secs),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn stalls_become_timeout_errors() {
+        let timeouts = Timeouts {
+            response_secs: 1,
+            idle_secs: 0,
+        };
+        let provider = ModelProvider::ANTHROPIC;
+        assert_eq!(timeouts.response(provider, async { 1 }).await.unwrap(), 1);
+
+        let err = timeouts
+            .next_chunk(provider, pending::<()>())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EngineError::Timeout { .. }));
+        assert!(err.is_transient());
+        assert!(err.to_string().contains("stalled for 0s"), "{err}");
+    }
+}
@@ -0,0 +1,361 @@
+//! Process wide rate limiting for outbound API calls.
+//!
+//! The GUI clones LLMs and image models freely, and a summary request, an image poll loop
+//! and the next turn can all be in flight at the same time. If every instance throttled
+//! itself, they would still add up to more requests than a provider accepts. Therefore
+//! there is exactly one `RateLimiter` per provider, which is obtained via [`shared`], and
+//! every request to that provider has to call [`RateLimiter::acquire`] first.
+//!
+//! Providers also limit the tokens per minute. Those are only known once the response is
+//! complete, so they are enforced by [`limit_tokens`], which wraps an LLM.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use async_stream::stream;
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use tokio::time::{Instant, sleep_until};
+use tokio_stream::StreamExt;
+
+use crate::{
+    LLMBox, image_model,
+    llm::{self, LLM, LLMStream, Request, ResponseFragment, RetryPolicy},
+    token_estimate,
+};
+
+/// Identifies a provider, no matter whether it serves text or images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LimitKey {
+    Llm(llm::ModelProvider),
+    Image(image_model::ModelProvider),
+}
+
+impl From<llm::ModelProvider> for LimitKey {
+    fn from(value: llm::ModelProvider) -> Self {
+        Self::Llm(value)
+    }
+}
+
+impl From<image_model::ModelProvider> for LimitKey {
+    fn from(value: image_model::ModelProvider) -> Self {
+        Self::Image(value)
+    }
+}
+
+impl fmt::Display for LimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitKey::Llm(provider) => provider.fmt(f),
+            LimitKey::Image(provider) => provider.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimits {
+    pub requests_per_minute: u32,
+    /// input and output tokens together, `None` means no limit
+    #[serde(default)]
+    pub tokens_per_minute: Option<u32>,
+}
+
+impl LimitKey {
+    /// All LLM and image providers
+    pub fn iter() -> impl Iterator<Item = LimitKey> {
+        llm::ModelProvider::iter()
+            .map(LimitKey::from)
+            .chain(image_model::ModelProvider::iter().map(LimitKey::from))
+    }
+
+    pub fn default_limits(self) -> RateLimits {
+        RateLimits {
+            requests_per_minute: self.default_requests_per_minute(),
+            tokens_per_minute: None,
+        }
+    }
+
+    /// Conservative defaults, that stay below the documented limits of the lowest paid tier.
+    /// The ones of the LLM providers are part of the registry. Image providers are polled
+    /// every 500ms - 1s while a job runs, so their limits must leave room for a few
+    /// concurrent jobs.
+    pub fn default_requests_per_minute(self) -> u32 {
+        match self {
+            LimitKey::Llm(provider) => provider.entry().requests_per_minute,
+            LimitKey::Image(image_model::ModelProvider::BFL) => 240,
+            LimitKey::Image(image_model::ModelProvider::Replicate) => 600,
+            LimitKey::Image(image_model::ModelProvider::Pruna) => 240,
+            // only protects against polling too eagerly, the server queues the jobs anyway
+            LimitKey::Image(image_model::ModelProvider::Local) => 600,
+            // OpenAI's images aren't polled, and the lowest tier allows only a few per minute
+            LimitKey::Image(image_model::ModelProvider::OpenAI) => 5,
+            // nothing is sent, the player is asked for a file
+            LimitKey::Image(image_model::ModelProvider::Manual) => 600,
+        }
+    }
+}
+
+static LIMITERS: LazyLock<Mutex<BTreeMap<LimitKey, Arc<RateLimiter>>>> =
+    LazyLock::new(Default::default);
+
+/// Returns the limiter that is shared by everything talking to the given provider.
+pub fn shared(key: impl Into<LimitKey>) -> Arc<RateLimiter> {
+    let key = key.into();
+    LIMITERS
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| Arc::new(RateLimiter::with_limits(key.default_limits())))
+        .clone()
+}
+
+/// Changes the limits of the shared limiter of `key`. Requests that are already waiting keep
+/// the slot they got.
+pub fn configure(key: LimitKey, limits: RateLimits) {
+    shared(key).set_limits(limits);
+}
+
+/// Returns an LLM that behaves like `llm`, but waits before a request if the tokens of the
+/// last minute would exceed the limit of its provider.
+pub fn limit_tokens(llm: LLMBox) -> LLMBox {
+    let limiter = shared(llm.provider());
+    Box::new(TokenLimited {
+        inner: llm,
+        limiter,
+    })
+}
+
+/// A GCRA (leaky bucket) limiter for the requests. It allows short bursts, but on average
+/// never more than `requests_per_minute`. The tokens are limited with a sliding window.
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests: Mutex<Gcra>,
+    tokens: Mutex<TokenWindow>,
+}
+
+#[derive(Debug)]
+struct Gcra {
+    interval: Duration,
+    burst_tolerance: Duration,
+    /// The theoretical arrival time of the next request.
+    tat: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct TokenWindow {
+    per_minute: Option<u32>,
+    /// when tokens were used, and how many, during the last minute
+    used: VecDeque<(Instant, usize)>,
+}
+
+const BURST_SIZE: u32 = 5;
+const WINDOW: Duration = Duration::from_secs(60);
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32) -> Self {
+        Self::with_limits(RateLimits {
+            requests_per_minute,
+            tokens_per_minute: None,
+        })
+    }
+
+    pub fn with_limits(limits: RateLimits) -> Self {
+        let limiter = Self {
+            requests: Mutex::new(Gcra {
+                interval: Duration::ZERO,
+                burst_tolerance: Duration::ZERO,
+                tat: None,
+            }),
+            tokens: Mutex::default(),
+        };
+        limiter.set_limits(limits);
+        limiter
+    }
+
+    pub fn set_limits(&self, limits: RateLimits) {
+        let interval = WINDOW / limits.requests_per_minute.max(1);
+        let mut requests = self.requests.lock().unwrap();
+        requests.interval = interval;
+        requests.burst_tolerance = interval * (BURST_SIZE - 1);
+        self.tokens.lock().unwrap().per_minute = limits.tokens_per_minute;
+    }
+
+    /// Waits until the next request may be sent.
+    pub async fn acquire(&self) {
+        let slot = self.reserve(Instant::now());
+        sleep_until(slot).await;
+    }
+
+    /// Books the next free slot and returns when it starts.
+    fn reserve(&self, now: Instant) -> Instant {
+        let mut requests = self.requests.lock().unwrap();
+        let current = requests.tat.map_or(now, |tat| tat.max(now));
+        let start = current
+            .checked_sub(requests.burst_tolerance)
+            .map_or(now, |earliest| earliest.max(now));
+        requests.tat = Some(current + requests.interval);
+        start
+    }
+
+    /// Waits until `n` more tokens fit into the limit, and books them. A request that is
+    /// larger than the limit on its own is sent once the window is empty.
+    pub async fn acquire_tokens(&self, n: usize) {
+        while let Err(retry_at) = self.try_reserve_tokens(Instant::now(), n) {
+            sleep_until(retry_at).await;
+        }
+    }
+
+    /// Books `n` tokens, or returns when to try again
+    fn try_reserve_tokens(&self, now: Instant, n: usize) -> Result<(), Instant> {
+        let mut tokens = self.tokens.lock().unwrap();
+        while tokens
+            .used
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= WINDOW)
+        {
+            tokens.used.pop_front();
+        }
+        if let Some(limit) = tokens.per_minute {
+            let used: usize = tokens.used.iter().map(|(_, n)| n).sum();
+            if let Some((oldest, _)) = tokens.used.front()
+                && used + n > limit as usize
+            {
+                return Err(*oldest + WINDOW);
+            }
+        }
+        tokens.used.push_back((now, n));
+        Ok(())
+    }
+
+    /// Books tokens that were used without asking first, e.g. the output of a request
+    pub fn record_tokens(&self, n: usize) {
+        if n > 0 {
+            self.tokens
+                .lock()
+                .unwrap()
+                .used
+                .push_back((Instant::now(), n));
+        }
+    }
+}
+
+struct TokenLimited {
+    inner: LLMBox,
+    limiter: Arc<RateLimiter>,
+}
+
+impl LLM for TokenLimited {
+    fn send_request_stream(&mut self, req: Request) -> LLMStream<'_> {
+        let limiter = self.limiter.clone();
+        let estimate = token_estimate::estimate_request(&req);
+        let inner = &mut self.inner;
+        Box::pin(stream! {
+            limiter.acquire_tokens(estimate).await;
+            let mut stream = inner.send_request_stream(req);
+            while let Some(item) = stream.next().await {
+                if let Ok(ResponseFragment::MessageComplete(msg)) = &item {
+                    // the input was booked with its estimate already
+                    let used = msg.input_tokens + msg.output_tokens;
+                    limiter.record_tokens(used.saturating_sub(estimate));
+                }
+                yield item;
+            }
+        })
+    }
+
+    fn clone(&self) -> LLMBox {
+        Box::new(TokenLimited {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+        })
+    }
+
+    fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.inner.set_retry_policy(policy);
+    }
+
+    fn provider(&self) -> llm::ModelProvider {
+        self.inner.provider()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.inner.supports_tools()
+    }
+
+    fn set_thinking_budget(&mut self, budget: Option<u32>) {
+        self.inner.set_thinking_budget(budget);
+    }
+
+    fn set_timeouts(&mut self, timeouts: llm::Timeouts) {
+        self.inner.set_timeouts(timeouts);
+    }
+
+    fn set_batched(&mut self, batched: bool) {
+        self.inner.set_batched(batched);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_spaces_requests() {
+        let limiter = RateLimiter::new(60);
+        let now = Instant::now();
+
+        for _ in 0..BURST_SIZE {
+            assert_eq!(limiter.reserve(now), now);
+        }
+        assert_eq!(limiter.reserve(now), now + Duration::from_secs(1));
+        assert_eq!(limiter.reserve(now), now + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn idle_time_refills_the_bucket() {
+        let limiter = RateLimiter::new(60);
+        let now = Instant::now();
+
+        for _ in 0..BURST_SIZE + 2 {
+            limiter.reserve(now);
+        }
+
+        let later = now + Duration::from_secs(60);
+        for _ in 0..BURST_SIZE {
+            assert_eq!(limiter.reserve(later), later);
+        }
+        assert!(limiter.reserve(later) > later);
+    }
+
+    #[test]
+    fn tokens_wait_for_the_window_to_move() {
+        let limiter = RateLimiter::with_limits(RateLimits {
+            requests_per_minute: 60,
+            tokens_per_minute: Some(1000),
+        });
+        let now = Instant::now();
+
+        assert!(limiter.try_reserve_tokens(now, 600).is_ok());
+        assert!(limiter.try_reserve_tokens(now, 300).is_ok());
+        assert_eq!(limiter.try_reserve_tokens(now, 200), Err(now + WINDOW));
+        assert!(limiter.try_reserve_tokens(now + WINDOW, 200).is_ok());
+
+        // a request larger than the limit still goes through when nothing else is running
+        let later = now + WINDOW * 3;
+        assert!(limiter.try_reserve_tokens(later, 5000).is_ok());
+        assert!(limiter.try_reserve_tokens(later, 1).is_err());
+    }
+
+    #[test]
+    fn limiters_are_shared_per_provider() {
+        let a = shared(llm::ModelProvider::ANTHROPIC);
+        let b = shared(llm::ModelProvider::ANTHROPIC);
+        let c = shared(image_model::ModelProvider::Replicate);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &c));
+    }
+}
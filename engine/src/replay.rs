@@ -0,0 +1,748 @@
+//! Records what the LLM and the image model answered, so a game can be driven through the
+//! same answers again.
+//!
+//! Bugs in the parsing of streamed answers, or in what happens after them, often depend on
+//! how the answer was split into fragments, and can't be reproduced by asking the model
+//! again. A [`Recorder`] wraps the models and keeps every fragment as it arrived. From the
+//! recording, a [`Replay`] makes models that answer with the same fragments, without any
+//! network access, which can be used in tests. A game records itself if its
+//! [`recorder`](crate::game::Game::recorder) is set, and `admin_cli replay` plays the save
+//! again from the recording.
+//!
+//! Answers are matched to requests by the request, so it doesn't matter in which order the
+//! turn pipeline sends them. A request that doesn't match exactly, e.g. because it contains
+//! the result of a dice roll, gets the oldest answer that wasn't used yet.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use async_stream::stream;
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use color_eyre::{
+    Report, Result,
+    eyre::{bail, eyre},
+};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+use crate::{
+    ImgModBox, LLMBox,
+    error::EngineError,
+    image_model::{Image, ImageModel, ProgressReporter, ProvidedModel},
+    llm::{
+        ContentBlock, LLM, LLMStream, ModelProvider, OutputMessage, Request, ResponseFragment,
+        RetryPolicy, Timeouts, ToolCall,
+    },
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Recorded {
+    Llm {
+        request: Request,
+        fragments: Vec<Fragment>,
+    },
+    Image {
+        description: String,
+        seed: Option<u64>,
+        result: Outcome<RecordedImage>,
+    },
+    Edit {
+        instruction: String,
+        result: Outcome<RecordedImage>,
+    },
+}
+
+/// A [`ResponseFragment`], or the error the stream yielded instead
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fragment {
+    Text(String),
+    Thinking(String),
+    Complete {
+        input_tokens: usize,
+        output_tokens: usize,
+        text: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ToolCall>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        thinking: Vec<ContentBlock>,
+    },
+    Error(Outcome<()>),
+}
+
+/// Errors are kept as their message. Moderation is kept apart, since the game reacts to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome<T> {
+    Ok(T),
+    Moderated(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedImage {
+    /// base64 encoded
+    pub data: String,
+    pub cost: Option<f64>,
+    pub seed: Option<u64>,
+}
+
+impl<T> Outcome<T> {
+    fn new<S>(result: &Result<S>, record: impl FnOnce(&S) -> T) -> Self {
+        match result {
+            Ok(x) => Self::Ok(record(x)),
+            Err(e) => Self::failure(e),
+        }
+    }
+
+    fn failure(err: &Report) -> Self {
+        match err.downcast_ref::<EngineError>() {
+            Some(EngineError::Moderated { message }) => Self::Moderated(message.clone()),
+            _ => Self::Failed(format!("{err:#}")),
+        }
+    }
+
+    fn into_result(self) -> Result<T> {
+        match self {
+            Self::Ok(x) => Ok(x),
+            Self::Moderated(message) => Err(EngineError::Moderated { message }.into()),
+            Self::Failed(message) => Err(eyre!(message)),
+        }
+    }
+}
+
+impl Fragment {
+    fn new(item: &Result<ResponseFragment>) -> Self {
+        match item {
+            Ok(ResponseFragment::TextDelta(text)) => Self::Text(text.clone()),
+            Ok(ResponseFragment::ThinkingDelta(text)) => Self::Thinking(text.clone()),
+            Ok(ResponseFragment::MessageComplete(msg)) => Self::Complete {
+                input_tokens: msg.input_tokens,
+                output_tokens: msg.output_tokens,
+                text: msg.text.clone(),
+                tool_calls: msg.tool_calls.clone(),
+                thinking: msg.thinking.clone(),
+            },
+            Err(e) => Self::Error(Outcome::failure(e)),
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        matches!(self, Self::Complete { .. } | Self::Error(_))
+    }
+}
+
+impl From<Fragment> for Result<ResponseFragment> {
+    fn from(fragment: Fragment) -> Self {
+        Ok(match fragment {
+            Fragment::Text(text) => ResponseFragment::TextDelta(text),
+            Fragment::Thinking(text) => ResponseFragment::ThinkingDelta(text),
+            Fragment::Complete {
+                input_tokens,
+                output_tokens,
+                text,
+                tool_calls,
+                thinking,
+            } => ResponseFragment::MessageComplete(OutputMessage {
+                input_tokens,
+                output_tokens,
+                text,
+                tool_calls,
+                thinking,
+            }),
+            Fragment::Error(outcome) => {
+                outcome.into_result()?;
+                bail!("A recorded error was replayed as success")
+            }
+        })
+    }
+}
+
+impl RecordedImage {
+    fn new(image: &Image) -> Self {
+        Self {
+            data: BASE64_STANDARD.encode(&image.data),
+            cost: image.cost,
+            seed: image.seed,
+        }
+    }
+
+    fn into_image(self) -> Result<Image> {
+        Ok(Image {
+            data: BASE64_STANDARD.decode(self.data)?,
+            cost: self.cost,
+            seed: self.seed,
+        })
+    }
+}
+
+/// Keeps everything the wrapped models answered, and writes it to a JSON lines file, if it
+/// has one
+#[derive(Debug, Default)]
+pub struct Recorder {
+    file: Option<Mutex<File>>,
+    entries: Mutex<Vec<Recorded>>,
+}
+
+impl Recorder {
+    /// The recording that belongs to the save archive at `save_path`
+    pub fn path_for(save_path: &Path) -> PathBuf {
+        let mut name = save_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".recording.jsonl");
+        save_path.with_file_name(name)
+    }
+
+    /// Appends to the file at `path`, and creates it if it doesn't exist
+    pub fn open(path: &Path) -> Result<Self, EngineError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+            entries: Mutex::default(),
+        })
+    }
+
+    pub fn entries(&self) -> Vec<Recorded> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    pub fn read(path: &Path) -> Result<Vec<Recorded>, EngineError> {
+        BufReader::new(File::open(path)?)
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+            .map(|line| serde_json::from_str(&line?).map_err(|e| EngineError::Other(e.into())))
+            .collect()
+    }
+
+    /// Returns an LLM that behaves like `llm`, and records all its answers
+    pub fn wrap_llm(self: &Arc<Self>, llm: LLMBox) -> LLMBox {
+        Box::new(RecordingLLM {
+            inner: llm,
+            recorder: self.clone(),
+        })
+    }
+
+    /// Returns an image model that behaves like `imgmod`, and records all its images
+    pub fn wrap_image_model(self: &Arc<Self>, imgmod: ImgModBox) -> ImgModBox {
+        Box::new(RecordingImageModel {
+            inner: imgmod,
+            recorder: self.clone(),
+        })
+    }
+
+    /// A failing file mustn't end the turn, the entry is kept in memory anyway
+    fn push(&self, entry: Recorded) {
+        if let Some(file) = &self.file {
+            let written = serde_json::to_vec(&entry)
+                .map_err(Report::from)
+                .and_then(|mut line| {
+                    line.push(b'\n');
+                    Ok(file.lock().unwrap().write_all(&line)?)
+                });
+            if let Err(e) = written {
+                error!("Failed to write the recording: {e}");
+            }
+        }
+        self.entries.lock().unwrap().push(entry);
+    }
+}
+
+struct RecordingLLM {
+    inner: LLMBox,
+    recorder: Arc<Recorder>,
+}
+
+impl LLM for RecordingLLM {
+    fn send_request_stream(&mut self, req: Request) -> LLMStream<'_> {
+        let recorder = self.recorder.clone();
+        let request = req.clone();
+        let mut inner = self.inner.send_request_stream(req);
+
+        Box::pin(stream! {
+            let mut fragments = vec![];
+            let mut recorded = false;
+            while let Some(item) = inner.next().await {
+                let fragment = Fragment::new(&item);
+                let is_terminal = fragment.is_terminal();
+                fragments.push(fragment);
+                // the caller may drop the stream once the message is complete
+                if !recorded && is_terminal {
+                    recorder.push(Recorded::Llm {
+                        request: request.clone(),
+                        fragments: fragments.clone(),
+                    });
+                    recorded = true;
+                }
+                yield item;
+            }
+            if !recorded {
+                recorder.push(Recorded::Llm { request, fragments });
+            }
+        })
+    }
+
+    fn clone(&self) -> LLMBox {
+        Box::new(RecordingLLM {
+            inner: self.inner.clone(),
+            recorder: self.recorder.clone(),
+        })
+    }
+
+    fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.inner.set_retry_policy(policy);
+    }
+
+    fn provider(&self) -> ModelProvider {
+        self.inner.provider()
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.inner.supports_tools()
+    }
+
+    fn set_thinking_budget(&mut self, budget: Option<u32>) {
+        self.inner.set_thinking_budget(budget);
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.inner.set_timeouts(timeouts);
+    }
+
+    fn set_batched(&mut self, batched: bool) {
+        self.inner.set_batched(batched);
+    }
+}
+
+struct RecordingImageModel {
+    inner: ImgModBox,
+    recorder: Arc<Recorder>,
+}
+
+impl ImageModel for RecordingImageModel {
+    fn get_image<'a>(
+        &'a self,
+        description: &'a str,
+        seed: Option<u64>,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        // `self` isn't `Sync`, so only the future and the recorder go into the block
+        let image = self.inner.get_image(description, seed, progress);
+        let recorder = self.recorder.clone();
+        Box::pin(async move {
+            let image = image.await;
+            recorder.push(Recorded::Image {
+                description: description.into(),
+                seed,
+                result: Outcome::new(&image, RecordedImage::new),
+            });
+            image
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        self.inner.download(url)
+    }
+
+    fn can_edit(&self) -> bool {
+        self.inner.can_edit()
+    }
+
+    fn edit_image<'a>(
+        &'a self,
+        original: &'a [u8],
+        instruction: &'a str,
+        progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        let image = self.inner.edit_image(original, instruction, progress);
+        let recorder = self.recorder.clone();
+        Box::pin(async move {
+            let image = image.await;
+            recorder.push(Recorded::Edit {
+                instruction: instruction.into(),
+                result: Outcome::new(&image, RecordedImage::new),
+            });
+            image
+        })
+    }
+
+    fn clone(&self) -> ImgModBox {
+        Box::new(RecordingImageModel {
+            inner: self.inner.clone(),
+            recorder: self.recorder.clone(),
+        })
+    }
+
+    fn provided_model(&self) -> ProvidedModel {
+        self.inner.provided_model()
+    }
+}
+
+/// The answers of a recording. The models made from it share them, and every answer is only
+/// given once.
+#[derive(Debug, Clone)]
+pub struct Replay(Arc<Mutex<Answers>>);
+
+#[derive(Debug)]
+struct Answers {
+    /// keyed by the request as JSON, since requests can't be compared
+    llm: Queue<serde_json::Value, Vec<Fragment>>,
+    images: Queue<(String, Option<u64>), Outcome<RecordedImage>>,
+    edits: Queue<String, Outcome<RecordedImage>>,
+    offers_tools: bool,
+}
+
+/// The answers of one kind, in the order they were recorded. Used ones are `None`.
+#[derive(Debug)]
+struct Queue<K, V>(Vec<Option<(K, V)>>);
+
+impl<K: PartialEq, V> Queue<K, V> {
+    fn take(&mut self, key: &K, what: &str) -> Result<V> {
+        let i = match self
+            .0
+            .iter()
+            .position(|e| e.as_ref().is_some_and(|(k, _)| k == key))
+        {
+            Some(i) => i,
+            None => {
+                let i = self
+                    .0
+                    .iter()
+                    .position(Option::is_some)
+                    .ok_or_else(|| eyre!("The recording has no {what} left"))?;
+                warn!("Replaying {what} {i}, which was recorded for another request");
+                i
+            }
+        };
+        Ok(self.0[i].take().expect("only unused answers are found").1)
+    }
+
+    fn remaining(&self) -> usize {
+        self.0.iter().flatten().count()
+    }
+}
+
+impl Replay {
+    pub fn new(recording: Vec<Recorded>) -> Self {
+        let mut answers = Answers {
+            llm: Queue(vec![]),
+            images: Queue(vec![]),
+            edits: Queue(vec![]),
+            offers_tools: false,
+        };
+        for entry in recording {
+            match entry {
+                Recorded::Llm { request, fragments } => {
+                    answers.offers_tools |= !request.tools.is_empty();
+                    let key = serde_json::to_value(&request).unwrap_or_default();
+                    answers.llm.0.push(Some((key, fragments)));
+                }
+                Recorded::Image {
+                    description,
+                    seed,
+                    result,
+                } => answers.images.0.push(Some(((description, seed), result))),
+                Recorded::Edit {
+                    instruction,
+                    result,
+                } => answers.edits.0.push(Some((instruction, result))),
+            }
+        }
+        Self(Arc::new(Mutex::new(answers)))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, EngineError> {
+        Ok(Self::new(Recorder::read(path)?))
+    }
+
+    /// How many answers weren't given yet
+    pub fn remaining(&self) -> usize {
+        let answers = self.0.lock().unwrap();
+        answers.llm.remaining() + answers.images.remaining() + answers.edits.remaining()
+    }
+
+    /// An LLM that answers with the recorded fragments. The recording doesn't know the
+    /// provider, so it's passed in. It supports tools if the recorded requests offered some.
+    pub fn llm(&self, provider: ModelProvider) -> LLMBox {
+        Box::new(ReplayLLM {
+            replay: self.clone(),
+            provider,
+        })
+    }
+
+    /// An image model that answers with the recorded images. It can edit images if the
+    /// recording has edits.
+    pub fn image_model(&self, model: ProvidedModel) -> ImgModBox {
+        Box::new(ReplayImageModel {
+            replay: self.clone(),
+            model,
+        })
+    }
+}
+
+pub struct ReplayLLM {
+    replay: Replay,
+    provider: ModelProvider,
+}
+
+impl LLM for ReplayLLM {
+    fn send_request_stream(&mut self, req: Request) -> LLMStream<'_> {
+        let key = serde_json::to_value(&req).unwrap_or_default();
+        let answer = self.replay.0.lock().unwrap().llm.take(&key, "LLM answer");
+        let fragments: Vec<Result<ResponseFragment>> = match answer {
+            Ok(fragments) => fragments.into_iter().map(Into::into).collect(),
+            Err(e) => vec![Err(e)],
+        };
+        Box::pin(tokio_stream::iter(fragments))
+    }
+
+    fn clone(&self) -> LLMBox {
+        self.replay.llm(self.provider)
+    }
+
+    fn set_retry_policy(&mut self, _: RetryPolicy) {}
+
+    fn provider(&self) -> ModelProvider {
+        self.provider
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.replay.0.lock().unwrap().offers_tools
+    }
+
+    fn set_timeouts(&mut self, _: Timeouts) {}
+}
+
+pub struct ReplayImageModel {
+    replay: Replay,
+    model: ProvidedModel,
+}
+
+impl ImageModel for ReplayImageModel {
+    fn get_image<'a>(
+        &'a self,
+        description: &'a str,
+        seed: Option<u64>,
+        _progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        let key = (description.to_string(), seed);
+        let answer = self.replay.0.lock().unwrap().images.take(&key, "image");
+        Box::pin(async move { answer?.into_result()?.into_image() })
+    }
+
+    fn download<'a>(
+        &'a self,
+        _url: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async { bail!("Downloads aren't recorded") })
+    }
+
+    fn can_edit(&self) -> bool {
+        !self.replay.0.lock().unwrap().edits.0.is_empty()
+    }
+
+    fn edit_image<'a>(
+        &'a self,
+        _original: &'a [u8],
+        instruction: &'a str,
+        _progress: ProgressReporter,
+    ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+        let key = instruction.to_string();
+        let answer = self.replay.0.lock().unwrap().edits.take(&key, "image edit");
+        Box::pin(async move { answer?.into_result()?.into_image() })
+    }
+
+    fn clone(&self) -> ImgModBox {
+        self.replay.image_model(self.model)
+    }
+
+    fn provided_model(&self) -> ProvidedModel {
+        self.model
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        game::{self, Game, TurnInput, TurnOutput},
+        save_archive::tests::make_sample_game_data,
+    };
+
+    const ANSWER: &str = "[SECTION IMAGE DESCRIPTION]\nan alley\n[SECTION IMAGE CAPTION]\n\
+        The Alley\n[SECTION OUTPUT]\nYou walk into the alley.\n[ACTION SEPARATOR]\na\n\
+        [ACTION SEPARATOR]\nb\n[ACTION SEPARATOR]\nc\n[SECTION SECRET INFO]\nnone";
+
+    /// Sends [`ANSWER`] in small pieces, with the markers split between them
+    #[derive(Clone)]
+    struct Chunked;
+
+    impl LLM for Chunked {
+        fn send_request_stream(&mut self, _: Request) -> LLMStream<'_> {
+            let chars = ANSWER.chars().collect::<Vec<_>>();
+            let deltas = chars
+                .chunks(7)
+                .map(|c| Ok(ResponseFragment::TextDelta(String::from_iter(c))));
+            let complete = Ok(ResponseFragment::MessageComplete(OutputMessage {
+                input_tokens: 100,
+                output_tokens: 40,
+                text: ANSWER.into(),
+                tool_calls: vec![],
+                thinking: vec![],
+            }));
+            Box::pin(tokio_stream::iter(
+                deltas.chain([complete]).collect::<Vec<_>>(),
+            ))
+        }
+
+        fn clone(&self) -> LLMBox {
+            Box::new(Chunked)
+        }
+
+        fn set_retry_policy(&mut self, _: RetryPolicy) {}
+
+        fn provider(&self) -> ModelProvider {
+            ModelProvider::ANTHROPIC
+        }
+
+        fn set_timeouts(&mut self, _: Timeouts) {}
+    }
+
+    #[derive(Clone)]
+    struct SeededImages;
+
+    impl ImageModel for SeededImages {
+        fn get_image<'a>(
+            &'a self,
+            _description: &'a str,
+            seed: Option<u64>,
+            _progress: ProgressReporter,
+        ) -> Pin<Box<dyn Future<Output = Result<Image>> + Send + 'a>> {
+            let seed = seed.unwrap_or_else(|| fastrand::u64(..));
+            Box::pin(async move {
+                Ok(Image {
+                    data: seed.to_le_bytes().to_vec(),
+                    cost: Some(0.01),
+                    seed: Some(seed),
+                })
+            })
+        }
+
+        fn download<'a>(
+            &'a self,
+            _url: &'a str,
+        ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+            Box::pin(async { bail!("nothing to download") })
+        }
+
+        fn clone(&self) -> ImgModBox {
+            Box::new(SeededImages)
+        }
+
+        fn provided_model(&self) -> ProvidedModel {
+            ProvidedModel::Flux2BLF
+        }
+    }
+
+    async fn play_turn(llm: LLMBox, imgmod: ImgModBox) -> (String, TurnOutput, game::Image) {
+        play_game_turn(Game::load(
+            llm,
+            Some(imgmod),
+            make_sample_game_data(3),
+            None,
+        ))
+        .await
+    }
+
+    async fn play_game_turn(game: Game) -> (String, TurnOutput, game::Image) {
+        let res = game.send_to_llm(TurnInput::player_action("Go on".into()));
+        let text = res
+            .text_stream
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap()
+            .concat();
+        let image = res.image.unwrap().await.unwrap();
+        (text, res.round_output.await.unwrap(), image)
+    }
+
+    #[tokio::test]
+    async fn replays_a_turn_exactly() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("recording.jsonl");
+        let recorder = Arc::new(Recorder::open(&path)?);
+        let recorded = play_turn(
+            recorder.wrap_llm(Box::new(Chunked)),
+            recorder.wrap_image_model(Box::new(SeededImages)),
+        )
+        .await;
+        assert_eq!(recorder.entries().len(), 2);
+
+        let replay = Replay::load(&path)?;
+        let replayed = play_turn(
+            replay.llm(ModelProvider::ANTHROPIC),
+            replay.image_model(ProvidedModel::Flux2BLF),
+        )
+        .await;
+        assert_eq!(replay.remaining(), 0);
+        assert_eq!(replayed.0, recorded.0);
+        assert_eq!(
+            serde_json::to_value(&replayed.1).unwrap(),
+            serde_json::to_value(&recorded.1).unwrap()
+        );
+        assert_eq!(replayed.1.text, "You walk into the alley.");
+        assert_eq!(
+            (replayed.2.bytes, replayed.2.seed),
+            (recorded.2.bytes, recorded.2.seed)
+        );
+
+        let mut llm = replay.llm(ModelProvider::ANTHROPIC);
+        let stream = llm.send_request_stream(Request {
+            system: None,
+            messages: vec![],
+            max_tokens: 10,
+            sampling: Default::default(),
+            tools: vec![],
+        });
+        assert!(stream.collect::<Vec<_>>().await[0].is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn games_record_through_their_recorder() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let save_path = dir.path().join("game.wwsave");
+        let path = Recorder::path_for(&save_path);
+        assert!(path.ends_with("game.wwsave.recording.jsonl"));
+
+        let mut game = Game::load(
+            Box::new(Chunked),
+            Some(Box::new(SeededImages)),
+            make_sample_game_data(3),
+            None,
+        );
+        game.recorder = Some(Arc::new(Recorder::open(&path)?));
+        let recorded = play_game_turn(game).await;
+        assert_eq!(Recorder::read(&path)?.len(), 2);
+
+        let replay = Replay::load(&path)?;
+        let replayed = play_turn(
+            replay.llm(ModelProvider::ANTHROPIC),
+            replay.image_model(ProvidedModel::Flux2BLF),
+        )
+        .await;
+        assert_eq!(replay.remaining(), 0);
+        assert_eq!(replayed.0, recorded.0);
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
 //! # SaveArchive Module
 //!
 //! This module provides a custom archive format for storing a game's state (`GameData`)
-//! together with associated turn images in a single file. The format is designed for
+//! together with associated turn images, and the transcripts of the LLM requests of each turn, in
+//! a single file. The format is designed for
 //! efficient appending of images while keeping the game data easily readable and writable.
 //!
 //! ## File Layout
@@ -10,81 +11,340 @@
 //!
 //! ```text
 //! +----------------------+
-//! | Header               |  fixed-size `SaveHeader`
+//! | Header               |  fixed-size `SaveHeader`, little endian
 //! +----------------------+
-//! | GameData JSON region |  Fixed-size (or growable) space for JSON-serialized `GameData`
+//! | GameData JSON region |  Fixed-size (or growable) space for zlib compressed, JSON-serialized `GameData`
 //! +----------------------+
-//! | Image Data Chunks    |  Arbitrary-length sequence of image bytes, appended as needed
+//! | Image Data Chunks    |  Arbitrary-length sequence of image bytes, their thumbnails and zlib compressed transcripts,
+//! |                      |  appended as needed
 //! +----------------------+
-//! | Image Index          |  Serialized `BTreeMap<ImageId, (offset, length)>` pointing to image chunks
+//! | Image Index          |  Serialized `Vec<IndexEntry>` with the offset, length, format and compression of each image,
+//! |                      |  followed by the `IndexEntry` of each transcript by turn, of each thumbnail by image,
+//! |                      |  and of each transcript of the other branches by branch and turn
+//! +----------------------+
+//! | Metadata             |  JSON-serialized `SaveMetadata`, what lists of saves show
 //! +----------------------+
 //! ```
 //!
 //! ## Key Features
 //! - The JSON region for `GameData` is pre-allocated and can grow if necessary by rewriting the file.
 //! - Each appended image is stored sequentially in the file. The index at the end allows random access to any image by its `ImageId`.
+//! - Images in formats that aren't compressed already are stored compressed, if that makes them noticeably smaller.
+//! - Each image is stored with a small JPEG thumbnail, see [`SaveArchive::read_thumbnail`].
+//! - Archives can be encrypted with a password. Then the game data and each image are encrypted with AES-256-GCM,
+//!   using a key that is derived from the password with Argon2. The header and the index stay readable.
+//! - Archives of older versions are read as they are, and upgraded to the current version before the first write.
 //! - The header is updated whenever the JSON region or index changes, keeping the archive consistent.
+//! - The header, the game data, the index, the metadata and each image have a CRC32 checksum, so damaged
+//!   saves are recognized when they are read, see [`EngineError::ChecksumMismatch`].
+//! - Every change is written to a journal next to the archive first, so a change that a crash interrupted is
+//!   completed or discarded when the archive is opened again, see [`journal`].
+//! - Optionally, the archive is copied before its game data is written, see [`backup`].
+//! - Archives can be exported to a zip file of plain files and imported from one, see [`bundle`].
+//! - Damaged archives can be examined, and what is intact of them salvaged, see [`doctor`].
+//! - Whenever the game data is written, the metadata is updated, so saves can be listed without reading their game
+//!   data, see [`SaveArchive::read_metadata`].
 //! - Supports reading and writing of both `GameData` and images via `read_game_data`, `write_game_data`, `append_image`, and `read_image`.
 
-use color_eyre::{
-    Result,
-    eyre::{ensure, eyre},
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng, rand_core::RngCore},
 };
-use log::debug;
+use argon2::Argon2;
+use color_eyre::eyre::eyre;
+use flate2::{Compression, Crc, read::ZlibDecoder, write::ZlibEncoder};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
 use serde_binary::binary_stream::Endian;
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    mem::transmute,
-    path::Path,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    audit_log::AuditEntry,
+    error::EngineError,
+    game::{GameData, StoredImageInfo, migration},
+    image_model::ImageFormat,
+    thumbnail,
 };
 
-use crate::game::GameData;
+pub mod backup;
+pub mod bundle;
+pub mod doctor;
+mod journal;
+mod metadata;
+
+pub use backup::BackupSettings;
+use journal::Transaction;
+use metadata::PlayClock;
+pub use metadata::SaveMetadata;
 
 const MAGIC: &[u8; 8] = b"WOWEAVER";
+/// Version 1 archives have no image formats in the index. Version 2 archives have no flags in
+/// the header, and nothing in them is compressed. Version 3 archives can't be encrypted.
+/// Version 4 archives have no metadata, version 5 archives no checksums, version 6 archives no
+/// transcripts, version 7 archives no thumbnails, and version 8 archives no transcripts of other
+/// branches.
+const VERSION: u64 = 9;
+/// The header of version 1 and 2 archives, which ends before the flags
+const V2_HEADER_SIZE: usize = 7 * size_of::<u64>();
+/// The header of version 3 archives, which ends before the salt
+const V3_HEADER_SIZE: usize = V2_HEADER_SIZE + size_of::<u64>();
+/// the game data region is zlib compressed
+const FLAG_COMPRESSED_GAME_DATA: u64 = 1;
+/// the game data and the images are encrypted, see [`Cipher`]
+const FLAG_ENCRYPTED: u64 = 2;
+const SALT_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const V4_HEADER_SIZE: usize = V3_HEADER_SIZE + SALT_SIZE + KEY_SIZE;
+const V5_HEADER_SIZE: usize = V4_HEADER_SIZE + size_of::<u64>();
+const HEADER_SIZE: usize = V5_HEADER_SIZE + 4 * size_of::<u64>();
+/// large enough that serializing the game data doesn't call the compressor thousands of times
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
 
 #[derive(Debug)]
 pub struct SaveArchive {
     file: File,
+    path: PathBuf,
+    journal: PathBuf,
+    backups: BackupSettings,
     header: SaveHeader,
-    /// (offset, length)
-    image_index: Vec<(u64, u64)>,
+    image_index: Vec<IndexEntry>,
+    /// the JSON-serialized [`AuditEntry`]s of each turn, by turn
+    transcripts: BTreeMap<usize, IndexEntry>,
+    /// the JPEG thumbnails, by image id. Images from before version 8, and ones that couldn't
+    /// be decoded, have none.
+    thumbnails: BTreeMap<usize, IndexEntry>,
+    /// the transcripts of the turns of the other branches, by branch name and turn, see
+    /// [`crate::game::Branch`]
+    branch_transcripts: BTreeMap<String, BTreeMap<usize, IndexEntry>>,
+    /// `Some` if the archive is encrypted
+    cipher: Option<Cipher>,
+    /// `None` until the game data is written, in archives before version 5
+    metadata: Option<SaveMetadata>,
+    clock: PlayClock,
+    /// see [`SaveArchive::open_read_only`]
+    read_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct IndexEntry {
+    offset: u64,
+    /// of the stored bytes, which are compressed if `compressed` is set
+    length: u64,
+    /// `None` if it wasn't recorded, or the bytes aren't a known image format
+    format: Option<ImageFormat>,
+    compressed: bool,
+    /// of the stored bytes, `None` for images from before version 6
+    checksum: Option<u32>,
+}
+
+/// How versions 3 to 5 stored the entries of the index
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct IndexEntryV5 {
+    offset: u64,
+    length: u64,
+    format: Option<ImageFormat>,
+    compressed: bool,
+}
+
+/// How version 2 stored the entries of the index
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct IndexEntryV2 {
+    offset: u64,
+    length: u64,
+    format: Option<ImageFormat>,
+}
+
+/// The entries of the stored images, and of the transcripts and thumbnails
+struct Index {
+    images: Vec<IndexEntry>,
+    /// by turn
+    transcripts: BTreeMap<usize, IndexEntry>,
+    /// by image id
+    thumbnails: BTreeMap<usize, IndexEntry>,
+    /// by branch name and turn
+    branch_transcripts: BTreeMap<String, BTreeMap<usize, IndexEntry>>,
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-#[repr(C)]
+impl Index {
+    /// Parses the index as an archive of `version` stores it
+    fn decode(version: u64, bytes: &[u8]) -> Result<Self, EngineError> {
+        let invalid_index = |e| EngineError::archive_corrupt(format!("Invalid image index: {e}"));
+        let mut transcripts = BTreeMap::new();
+        let mut thumbnails = BTreeMap::new();
+        let mut branch_transcripts = BTreeMap::new();
+        let images = if bytes.is_empty() {
+            vec![]
+        } else if version < 2 {
+            let index: Vec<(u64, u64)> =
+                serde_binary::from_slice(bytes, Endian::Little).map_err(invalid_index)?;
+            index
+                .into_iter()
+                .map(|(offset, length)| IndexEntry {
+                    offset,
+                    length,
+                    format: None,
+                    compressed: false,
+                    checksum: None,
+                })
+                .collect()
+        } else if version < 3 {
+            let index: Vec<IndexEntryV2> =
+                serde_binary::from_slice(bytes, Endian::Little).map_err(invalid_index)?;
+            index
+                .into_iter()
+                .map(|entry| IndexEntry {
+                    offset: entry.offset,
+                    length: entry.length,
+                    format: entry.format,
+                    compressed: false,
+                    checksum: None,
+                })
+                .collect()
+        } else if version < 6 {
+            let index: Vec<IndexEntryV5> =
+                serde_binary::from_slice(bytes, Endian::Little).map_err(invalid_index)?;
+            index
+                .into_iter()
+                .map(|entry| IndexEntry {
+                    offset: entry.offset,
+                    length: entry.length,
+                    format: entry.format,
+                    compressed: entry.compressed,
+                    checksum: None,
+                })
+                .collect()
+        } else if version < 7 {
+            serde_binary::from_slice(bytes, Endian::Little).map_err(invalid_index)?
+        } else if version < 8 {
+            let (image_index, by_turn) =
+                serde_binary::from_slice(bytes, Endian::Little).map_err(invalid_index)?;
+            transcripts = by_turn;
+            image_index
+        } else if version < 9 {
+            let (image_index, by_turn, by_image) =
+                serde_binary::from_slice(bytes, Endian::Little).map_err(invalid_index)?;
+            transcripts = by_turn;
+            thumbnails = by_image;
+            image_index
+        } else {
+            let (image_index, by_turn, by_image, by_branch) =
+                serde_binary::from_slice(bytes, Endian::Little).map_err(invalid_index)?;
+            transcripts = by_turn;
+            thumbnails = by_image;
+            branch_transcripts = by_branch;
+            image_index
+        };
+        Ok(Self {
+            images,
+            transcripts,
+            thumbnails,
+            branch_transcripts,
+        })
+    }
+}
+
+/// Its fields are stored one after the other, in the order they are declared. See
+/// [`SaveHeader::encode`].
+#[derive(Debug, Clone, Copy)]
 pub struct SaveHeader {
     /// marker for files
     magic: [u8; 8],
-    /// 8bytes so there is no padding
     version: u64,
     game_data_region_size: u64,
     game_data_size: u64,
     game_data_region_offset: u64,
     index_offset: u64,
     index_size: u64,
+    /// see `FLAG_COMPRESSED_GAME_DATA` and `FLAG_ENCRYPTED`. Not present before version 3.
+    flags: u64,
+    /// of the key derivation, random for each encrypted archive. Not present before version 4,
+    /// like `key_check`.
+    salt: [u8; SALT_SIZE],
+    /// derived from the password together with the key, so a wrong password is recognized
+    /// without decrypting anything
+    key_check: [u8; KEY_SIZE],
+    /// the metadata follows the index. Not present before version 5.
+    metadata_size: u64,
+    /// CRC32s of the stored bytes of the sections. Not present before version 6, like
+    /// `header_checksum`.
+    game_data_checksum: u64,
+    index_checksum: u64,
+    metadata_checksum: u64,
+    /// of the fields before it
+    header_checksum: u64,
+}
+
+/// What [`SaveArchive::compact`] removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Compaction {
+    pub removed_images: usize,
+    pub freed_bytes: u64,
 }
 
 impl SaveArchive {
     pub const DEFAULT_GAME_DATA_SIZE: u64 = 20 * 1024 * 1024; // 20 MB
-    pub const HEADER_SIZE: u64 = size_of::<SaveHeader>() as u64;
+    pub const HEADER_SIZE: u64 = HEADER_SIZE as u64;
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, EngineError> {
+        Self::create_inner(path.as_ref(), None)
+    }
+
+    /// Creates an archive whose game data and images can only be read with the password
+    pub fn create_encrypted<P: AsRef<Path>>(path: P, password: &str) -> Result<Self, EngineError> {
+        Self::create_inner(path.as_ref(), Some(password))
+    }
 
-    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+    fn create_inner(path: &Path, password: Option<&str>) -> Result<Self, EngineError> {
+        // truncated only once it's locked, another instance might still use the file
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .truncate(true)
-            .open(&path)?;
+            .truncate(false)
+            .open(path)?;
+        lock(&file, path)?;
+        file.set_len(0)?;
+        // a journal of a previous file of the same name doesn't belong to this one
+        let journal = journal::path_for(path);
+        if journal.exists() {
+            fs::remove_file(&journal)?;
+        }
 
-        let header = SaveHeader {
+        let mut header = SaveHeader {
             magic: *MAGIC,
-            version: 1,
+            version: VERSION,
             game_data_region_size: Self::DEFAULT_GAME_DATA_SIZE,
             game_data_region_offset: Self::HEADER_SIZE,
             index_offset: Self::HEADER_SIZE + Self::DEFAULT_GAME_DATA_SIZE,
             index_size: 0,
             game_data_size: 0,
+            flags: FLAG_COMPRESSED_GAME_DATA,
+            salt: [0; SALT_SIZE],
+            key_check: [0; KEY_SIZE],
+            metadata_size: 0,
+            game_data_checksum: 0,
+            index_checksum: 0,
+            metadata_checksum: 0,
+            header_checksum: 0,
+        };
+        let cipher = match password {
+            Some(password) => {
+                OsRng.fill_bytes(&mut header.salt);
+                let (cipher, key_check) = Cipher::derive(password, &header.salt)?;
+                header.flags |= FLAG_ENCRYPTED;
+                header.key_check = key_check;
+                Some(cipher)
+            }
+            None => None,
         };
 
         file.set_len(header.index_offset)?;
@@ -92,182 +352,1052 @@ impl SaveArchive {
 
         Ok(Self {
             file,
+            path: path.to_path_buf(),
+            journal,
+            backups: BackupSettings::default(),
             header,
             image_index: vec![],
+            transcripts: BTreeMap::new(),
+            thumbnails: BTreeMap::new(),
+            branch_transcripts: BTreeMap::new(),
+            cipher,
+            metadata: None,
+            clock: PlayClock::start(),
+            read_only: false,
         })
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    /// Fails with [`EngineError::PasswordRequired`] if the archive is encrypted, and with
+    /// [`EngineError::ArchiveLocked`] if another instance has it open for writing
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, EngineError> {
+        Self::open_inner(path.as_ref(), None, false)
+    }
+
+    /// Opens an encrypted archive, or any other one, then the password is ignored
+    pub fn open_with_password<P: AsRef<Path>>(
+        path: P,
+        password: &str,
+    ) -> Result<Self, EngineError> {
+        Self::open_inner(path.as_ref(), Some(password), false)
+    }
+
+    /// Opens an archive for reading, e.g. to list or export it, even while another instance
+    /// writes it. Such an archive isn't locked, and every write fails. An interrupted change
+    /// isn't completed, that's left to the next open for writing.
+    pub fn open_read_only<P: AsRef<Path>>(
+        path: P,
+        password: Option<&str>,
+    ) -> Result<Self, EngineError> {
+        Self::open_inner(path.as_ref(), password, true)
+    }
+
+    fn open_inner(
+        path: &Path,
+        password: Option<&str>,
+        read_only: bool,
+    ) -> Result<Self, EngineError> {
+        let mut file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let journal = journal::path_for(path);
+        if !read_only {
+            lock(&file, path)?;
+            journal::recover(&mut file, &journal)?;
+        }
+        file.seek(SeekFrom::Start(0))?;
         let header = read_header(&mut file)?;
         debug!("Read header:\n{header:#?}");
-        ensure!(&header.magic == MAGIC, "Invalid save file");
+        let cipher = Cipher::for_header(&header, password)?;
 
         let mut index_bytes = vec![0u8; header.index_size as usize];
         file.seek(SeekFrom::Start(header.index_offset))?;
         file.read_exact(&mut index_bytes)?;
-        let image_index: Vec<(u64, u64)> = if header.index_size > 0 {
-            serde_binary::from_slice(&index_bytes, Endian::Little)?
-        } else {
-            vec![]
-        };
-
-        Ok(Self {
+        if header.version >= 6 {
+            verify(
+                &index_bytes,
+                header.index_checksum,
+                "the image index",
+                header.index_offset,
+            )?;
+        }
+        let Index {
+            images: image_index,
+            transcripts,
+            thumbnails,
+            branch_transcripts,
+        } = Index::decode(header.version, &index_bytes)?;
+
+        let mut archive = Self {
             file,
+            path: path.to_path_buf(),
+            journal,
+            backups: BackupSettings::default(),
             header,
             image_index,
-        })
+            transcripts,
+            thumbnails,
+            branch_transcripts,
+            cipher,
+            metadata: None,
+            clock: PlayClock::start(),
+            read_only,
+        };
+        // the metadata can be made from the game data again, so it's not worth failing for
+        archive.metadata = archive
+            .read_stored_metadata()
+            .inspect_err(|e| warn!("Ignoring the metadata of {path:?}: {e}"))
+            .ok()
+            .flatten();
+        Ok(archive)
     }
 
-    pub fn write_game_data(&mut self, data: &GameData) -> Result<()> {
-        let serde_str = serde_json::to_string(data)?;
-        let json_bytes = serde_str.as_bytes();
-        ensure!(
-            (json_bytes.len() as u64) < self.header.game_data_region_size,
-            "The json region in the save archive is not large enough, it needs to be grown"
+    fn read_stored_metadata(&mut self) -> Result<Option<SaveMetadata>, EngineError> {
+        if self.header.metadata_size == 0 {
+            return Ok(None);
+        }
+        let offset = self.header.index_offset + self.header.index_size;
+        let stored = self.read_stored(IndexEntry {
+            offset,
+            length: self.header.metadata_size,
+            format: None,
+            compressed: false,
+            checksum: None,
+        })?;
+        if self.header.version >= 6 {
+            verify(
+                &stored,
+                self.header.metadata_checksum,
+                "the metadata",
+                offset,
+            )?;
+        }
+        let json = self.unseal(stored, "the metadata")?;
+        serde_json::from_slice(&json).map_err(EngineError::archive_corrupt)
+    }
+
+    /// What lists of saves show. Archives of older versions don't have it until their game
+    /// data is written again, then it's made from the game data.
+    pub fn read_metadata(&mut self) -> Result<SaveMetadata, EngineError> {
+        if let Some(metadata) = &self.metadata {
+            return Ok(metadata.clone());
+        }
+        let data = self.read_game_data()?;
+        let last_played = self.file.metadata()?.modified()?;
+        Ok(SaveMetadata::of(&data, Default::default(), last_played))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    fn ensure_writable(&self) -> Result<(), EngineError> {
+        if self.read_only {
+            return Err(EngineError::Other(eyre!(
+                "{:?} was opened read-only",
+                self.path
+            )));
+        }
+        Ok(())
+    }
+
+    /// Backups are made before the game data is written. By default there are none.
+    pub fn set_backups(&mut self, settings: BackupSettings) {
+        self.backups = settings;
+    }
+
+    /// Serializes, compresses and maybe encrypts `data` in memory, and writes it into the JSON
+    /// region.
+    /// The region isn't touched if the data doesn't fit.
+    pub fn write_game_data(&mut self, data: &GameData) -> Result<(), EngineError> {
+        self.ensure_writable()?;
+        backup::rotate(&self.path, &self.backups)?;
+        self.upgrade()?;
+        let mut transaction = Transaction::default();
+        self.stage_game_data(&mut transaction, data)?;
+        self.commit(transaction)
+    }
+
+    /// Adds writing `data` into the JSON region to the transaction
+    fn stage_game_data(
+        &mut self,
+        transaction: &mut Transaction,
+        data: &GameData,
+    ) -> Result<(), EngineError> {
+        let mut writer = BufWriter::with_capacity(
+            WRITE_BUFFER_SIZE,
+            ZlibEncoder::new(vec![], Compression::fast()),
         );
+        serde_json::to_writer(&mut writer, data).map_err(|e| EngineError::Other(e.into()))?;
+        let compressed = writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        let stored = self.seal(Cow::Owned(compressed))?;
+        if stored.len() as u64 >= self.header.game_data_region_size {
+            return Err(EngineError::Other(eyre!(
+                "The json region in the save archive is not large enough, it needs to be grown"
+            )));
+        }
 
-        self.file
-            .seek(SeekFrom::Start(self.header.game_data_region_offset))?;
-        self.file.write_all(json_bytes)?;
+        self.header.game_data_size = stored.len() as u64;
+        self.header.game_data_checksum = checksum(&stored).into();
+        transaction.write(self.header.game_data_region_offset, stored.into_owned());
+
+        let playtime = self
+            .metadata
+            .as_ref()
+            .map(|m| m.playtime)
+            .unwrap_or_default();
+        self.metadata = Some(SaveMetadata::of(
+            data,
+            playtime + self.clock.lap(),
+            SystemTime::now(),
+        ));
+        self.stage_metadata(transaction)
+    }
 
-        self.header.game_data_size = json_bytes.len() as u64;
-        write_header(&mut self.file, &self.header)?;
+    /// Adds writing the metadata behind the index to the transaction
+    fn stage_metadata(&mut self, transaction: &mut Transaction) -> Result<(), EngineError> {
+        let offset = self.header.index_offset + self.header.index_size;
+        transaction.set_len(offset);
+        let Some(metadata) = &self.metadata else {
+            self.header.metadata_size = 0;
+            return Ok(());
+        };
+        let json = serde_json::to_vec(metadata).map_err(|e| EngineError::Other(e.into()))?;
+        let stored = self.seal(Cow::Owned(json))?;
+        self.header.metadata_size = stored.len() as u64;
+        self.header.metadata_checksum = checksum(&stored).into();
+        transaction.write(offset, stored.into_owned());
+        Ok(())
+    }
 
+    /// Adds writing the index, and the metadata that follows it, to the transaction
+    fn stage_index(&mut self, transaction: &mut Transaction) -> Result<(), EngineError> {
+        let serialized_index = self.serialize_index()?;
+        self.header.index_size = serialized_index.len() as u64;
+        self.header.index_checksum = checksum(&serialized_index).into();
+        transaction.set_len(self.header.index_offset);
+        transaction.write(self.header.index_offset, serialized_index);
+        self.stage_metadata(transaction)
+    }
+
+    /// Applies the transaction, together with the header
+    fn commit(&mut self, mut transaction: Transaction) -> Result<(), EngineError> {
+        transaction.write(0, self.header.encode());
+        transaction.commit(&mut self.file, &self.journal)?;
         Ok(())
     }
 
-    pub fn append_image(&mut self, image_bytes: &[u8]) -> Result<usize> {
-        let offset = self.header.index_offset;
-        let length = image_bytes.len() as u64;
-        self.file.set_len(offset)?;
-        self.file.seek(SeekFrom::End(0))?;
-        self.file.write_all(image_bytes)?;
+    /// The format of the image is detected from its bytes, and recorded in the index. A
+    /// thumbnail is stored with it, unless the bytes can't be decoded.
+    pub fn append_image(&mut self, image_bytes: &[u8]) -> Result<usize, EngineError> {
+        self.ensure_writable()?;
+        self.upgrade()?;
+        let format = ImageFormat::detect(image_bytes);
+        let compressed = compress_image(image_bytes, format)?;
+        let is_compressed = compressed.is_some();
+        let stored = self.seal(compressed.map_or(Cow::Borrowed(image_bytes), Cow::Owned))?;
+        let mut transaction = Transaction::default();
+        let entry = IndexEntry {
+            format,
+            compressed: is_compressed,
+            ..self.stage_chunk(&mut transaction, stored.into_owned())
+        };
 
         let id = self.image_index.len();
-        self.image_index.push((offset, length));
-        self.header.index_offset += length;
-        let serialized_index = serde_binary::to_vec(&self.image_index, Endian::Little)?;
-        self.file.write_all(&serialized_index)?;
-        self.header.index_size = serialized_index.len() as u64;
-        write_header(&mut self.file, &self.header)?;
+        self.image_index.push(entry);
+        match thumbnail::encode_thumbnail(image_bytes) {
+            Ok(jpeg) => {
+                let stored = self.seal(Cow::Owned(jpeg))?;
+                let entry = IndexEntry {
+                    format: Some(ImageFormat::Jpeg),
+                    ..self.stage_chunk(&mut transaction, stored.into_owned())
+                };
+                self.thumbnails.insert(id, entry);
+            }
+            Err(e) => warn!("Storing image {id} without a thumbnail: {e:#}"),
+        }
+        self.stage_index(&mut transaction)?;
+        self.commit(transaction)?;
 
         Ok(id)
     }
 
-    pub fn read_game_data(&mut self) -> Result<GameData> {
-        ensure!(self.header.game_data_size > 0, "No game data");
+    /// Adds writing `stored` behind the last chunk to the transaction, and returns its entry
+    /// for the index
+    fn stage_chunk(&mut self, transaction: &mut Transaction, stored: Vec<u8>) -> IndexEntry {
+        let entry = IndexEntry {
+            offset: self.header.index_offset,
+            length: stored.len() as u64,
+            format: None,
+            compressed: false,
+            checksum: Some(checksum(&stored)),
+        };
+        transaction.write(entry.offset, stored);
+        self.header.index_offset += entry.length;
+        entry
+    }
+
+    /// Stores the requests and responses of a turn. If the turn has a transcript already, e.g.
+    /// because it was regenerated, the entries are added to it.
+    pub fn append_transcript(
+        &mut self,
+        turn: usize,
+        entries: &[AuditEntry],
+    ) -> Result<(), EngineError> {
+        self.ensure_writable()?;
+        self.upgrade()?;
+        let mut transcript = self.read_transcript(turn)?.unwrap_or_default();
+        transcript.extend_from_slice(entries);
+        let mut transaction = Transaction::default();
+        let entry = self.stage_transcript(&mut transaction, &transcript)?;
+        // the previous transcript of the turn stays until the archive is compacted
+        self.transcripts.insert(turn, entry);
+        self.stage_index(&mut transaction)?;
+        self.commit(transaction)
+    }
+
+    /// Stores the transcript of `turn` of the branch `branch`, which isn't the current one
+    fn write_branch_transcript(
+        &mut self,
+        branch: &str,
+        turn: usize,
+        transcript: &[AuditEntry],
+    ) -> Result<(), EngineError> {
+        self.ensure_writable()?;
+        self.upgrade()?;
+        let mut transaction = Transaction::default();
+        let entry = self.stage_transcript(&mut transaction, transcript)?;
+        self.branch_transcripts
+            .entry(branch.to_string())
+            .or_default()
+            .insert(turn, entry);
+        self.stage_index(&mut transaction)?;
+        self.commit(transaction)
+    }
+
+    fn stage_transcript(
+        &mut self,
+        transaction: &mut Transaction,
+        transcript: &[AuditEntry],
+    ) -> Result<IndexEntry, EngineError> {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        serde_json::to_writer(&mut encoder, transcript)
+            .map_err(|e| EngineError::Other(e.into()))?;
+        let stored = self.seal(Cow::Owned(encoder.finish()?))?;
+        Ok(IndexEntry {
+            compressed: true,
+            ..self.stage_chunk(transaction, stored.into_owned())
+        })
+    }
+
+    /// The JPEG thumbnail of the image, see [`crate::thumbnail::STORED_THUMBNAIL_SIZE`]. `None`
+    /// if none was stored, then the image has to be downscaled by the caller.
+    pub fn read_thumbnail(&mut self, id: usize) -> Result<Option<Vec<u8>>, EngineError> {
+        let Some(entry) = self.thumbnails.get(&id).copied() else {
+            return Ok(None);
+        };
+        let what = format!("the thumbnail of image {id}");
+        let stored = self.read_stored(entry)?;
+        if let Some(expected) = entry.checksum {
+            verify(&stored, expected.into(), &what, entry.offset)?;
+        }
+        Ok(Some(self.unseal(stored, &what)?))
+    }
+
+    /// `None` if no transcript of the turn was stored, e.g. because it was played before
+    /// transcripts were
+    pub fn read_transcript(&mut self, turn: usize) -> Result<Option<Vec<AuditEntry>>, EngineError> {
+        let Some(entry) = self.transcripts.get(&turn).copied() else {
+            return Ok(None);
+        };
+        let what = format!("the transcript of turn {turn}");
+        self.read_transcript_entry(entry, &what).map(Some)
+    }
+
+    fn read_transcript_entry(
+        &mut self,
+        entry: IndexEntry,
+        what: &str,
+    ) -> Result<Vec<AuditEntry>, EngineError> {
+        let stored = self.read_stored(entry)?;
+        if let Some(expected) = entry.checksum {
+            verify(&stored, expected.into(), what, entry.offset)?;
+        }
+        let compressed = self.unseal(stored, what)?;
+        serde_json::from_reader(ZlibDecoder::new(&compressed[..]))
+            .map_err(EngineError::archive_corrupt)
+    }
+
+    /// The turns that have a transcript
+    pub fn transcript_turns(&self) -> impl Iterator<Item = usize> + '_ {
+        self.transcripts.keys().copied()
+    }
+
+    pub fn n_images(&self) -> usize {
+        self.image_index.len()
+    }
+
+    /// `None` if the format wasn't recorded, which is the case in older archives
+    pub fn image_format(&self, id: usize) -> Option<ImageFormat> {
+        self.image_index.get(id)?.format
+    }
+
+    fn serialize_index(&self) -> Result<Vec<u8>, EngineError> {
+        serde_binary::to_vec(
+            &(
+                &self.image_index,
+                &self.transcripts,
+                &self.thumbnails,
+                &self.branch_transcripts,
+            ),
+            Endian::Little,
+        )
+        .map_err(|e| EngineError::Other(e.into()))
+    }
+
+    /// Brings an archive of an older version to the current one. That happens before the
+    /// first write, so merely reading an old save doesn't change it.
+    fn upgrade(&mut self) -> Result<(), EngineError> {
+        if self.header.version >= VERSION {
+            return Ok(());
+        }
+        let data = match self.header.game_data_size {
+            0 => None,
+            _ => Some(self.read_game_data()?),
+        };
+        // the header grew, and the game data region starts right after it
+        let growth = Self::HEADER_SIZE.saturating_sub(self.header.game_data_region_offset);
+        self.header.game_data_region_offset += growth;
+        self.header.game_data_region_size -= growth;
+        self.header.version = VERSION;
+        self.header.flags |= FLAG_COMPRESSED_GAME_DATA;
+
+        let mut transaction = Transaction::default();
+        self.stage_index(&mut transaction)?;
+        if let Some(data) = data {
+            self.stage_game_data(&mut transaction, &data)?;
+        }
+        self.commit(transaction)
+    }
+
+    pub fn read_game_data(&mut self) -> Result<GameData, EngineError> {
+        if self.header.game_data_size == 0 {
+            return Err(EngineError::archive_corrupt("No game data"));
+        }
         self.file
             .seek(SeekFrom::Start(self.header.game_data_region_offset))?;
         let mut buf = vec![0u8; self.header.game_data_size as usize];
         self.file.read_exact(&mut buf)?;
-
-        let data: GameData = serde_json::from_str(std::str::from_utf8(&buf)?)?;
-        Ok(data)
+        if self.header.version >= 6 {
+            verify(
+                &buf,
+                self.header.game_data_checksum,
+                "the game data",
+                self.header.game_data_region_offset,
+            )?;
+        }
+        let buf = self.unseal(buf, "the game data")?;
+
+        let json = if self.header.flags & FLAG_COMPRESSED_GAME_DATA != 0 {
+            let mut json = String::new();
+            ZlibDecoder::new(&buf[..])
+                .read_to_string(&mut json)
+                .map_err(EngineError::archive_corrupt)?;
+            json
+        } else {
+            String::from_utf8(buf).map_err(EngineError::archive_corrupt)?
+        };
+        migration::game_data_from_json(&json)
+            .map_err(|e| EngineError::archive_corrupt(format!("{e:#}")))
     }
 
-    pub fn read_image(&mut self, id: usize) -> Result<Vec<u8>> {
-        let (offset, length) = self
+    pub fn read_image(&mut self, id: usize) -> Result<Vec<u8>, EngineError> {
+        let entry = *self
             .image_index
             .get(id)
-            .ok_or_else(|| eyre!("Image ID not found"))?;
+            .ok_or_else(|| EngineError::archive_corrupt(format!("Image ID not found: {id}")))?;
+
+        let stored = self.read_stored(entry)?;
+        if let Some(expected) = entry.checksum {
+            verify(
+                &stored,
+                expected.into(),
+                &format!("image {id}"),
+                entry.offset,
+            )?;
+        }
+        let stored = self.unseal(stored, &format!("image {id}"))?;
+        if !entry.compressed {
+            return Ok(stored);
+        }
+        let mut buf = vec![];
+        ZlibDecoder::new(&stored[..])
+            .read_to_end(&mut buf)
+            .map_err(EngineError::archive_corrupt)?;
+        Ok(buf)
+    }
+
+    /// Encrypts `bytes` if the archive is encrypted
+    fn seal<'a>(&self, bytes: Cow<'a, [u8]>) -> Result<Cow<'a, [u8]>, EngineError> {
+        match &self.cipher {
+            Some(cipher) => Ok(Cow::Owned(cipher.encrypt(&bytes)?)),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Decrypts `bytes` if the archive is encrypted. The password was checked when the archive
+    /// was opened, so bytes that can't be decrypted are corrupt.
+    fn unseal(&self, bytes: Vec<u8>, what: &str) -> Result<Vec<u8>, EngineError> {
+        match &self.cipher {
+            Some(cipher) => cipher
+                .decrypt(&bytes)
+                .ok_or_else(|| EngineError::archive_corrupt(format!("Can't decrypt {what}"))),
+            None => Ok(bytes),
+        }
+    }
 
-        self.file.seek(SeekFrom::Start(*offset))?;
-        let mut buf = vec![0u8; *length as usize];
+    /// The bytes of an image as they are in the file
+    fn read_stored(&mut self, entry: IndexEntry) -> Result<Vec<u8>, EngineError> {
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        let mut buf = vec![0u8; entry.length as usize];
         self.file.read_exact(&mut buf)?;
         Ok(buf)
     }
 
-    pub fn clip_after_turn(&mut self, turn: usize) -> Result<()> {
+    pub fn clip_after_turn(&mut self, turn: usize) -> Result<(), EngineError> {
+        self.ensure_writable()?;
+        backup::rotate(&self.path, &self.backups)?;
+        self.upgrade()?;
         let mut gd = self.read_game_data()?;
-        ensure!(turn < gd.turn_data.len(), "Invalid turn: {turn}");
-        gd.turn_data = gd.turn_data[..=turn].to_vec();
-
-        let latest_turn = gd.turn_data.last().unwrap();
-        let latest_summary_idx = latest_turn.summary_before_input;
-        if let Some(i) = latest_summary_idx {
-            gd.summaries = gd.summaries[..=i].to_vec();
-        } else {
-            gd.summaries.clear();
+        if turn >= gd.turn_data.len() {
+            return Err(EngineError::Other(eyre!("Invalid turn: {turn}")));
         }
+        let forks = forks(&gd);
+        gd.clip_after_turn(turn);
 
         let latest_image = gd
-            .turn_data
-            .iter()
-            .flat_map(|td| td.images.iter().map(|i| i.id))
+            .all_turns()
+            .flat_map(|td| &td.images)
+            .flat_map(|i| [Some(i.id), i.original_id])
+            .flatten()
             .max();
 
-        ensure!(
-            latest_image.is_none_or(|i| i < self.image_index.len()),
-            "Image index out of sync with game data"
-        );
+        if latest_image.is_some_and(|i| i >= self.image_index.len()) {
+            return Err(EngineError::archive_corrupt(
+                "Image index out of sync with game data",
+            ));
+        }
+
+        self.split_off_transcripts(&forks, turn);
+        gd.image_cache
+            .retain_images_below(latest_image.map_or(0, |i| i + 1));
 
-        match latest_image {
-            Some(i) => {
-                let (offset, length) = self.image_index[i];
-                self.header.index_offset = offset + length;
-                self.image_index = self.image_index[..=i].to_vec();
+        self.image_index.truncate(latest_image.map_or(0, |i| i + 1));
+        let n_images = self.image_index.len();
+        self.thumbnails.retain(|&id, _| id < n_images);
+        // the transcripts of the kept turns can be behind the removed images
+        self.header.index_offset = self
+            .image_index
+            .iter()
+            .chain(self.thumbnails.values())
+            .chain(self.transcripts.values())
+            .chain(self.branch_transcripts.values().flat_map(|t| t.values()))
+            .map(|entry| entry.offset + entry.length)
+            .max()
+            .unwrap_or(self.header.game_data_region_offset + self.header.game_data_region_size);
+
+        let mut transaction = Transaction::default();
+        self.stage_index(&mut transaction)?;
+        self.stage_game_data(&mut transaction, &gd)?;
+        self.commit(transaction)
+    }
+
+    /// Removes the images that no turn refers to, e.g. the ones of clipped turns, or
+    /// images that were only in the image cache, and the transcripts that were replaced. The
+    /// kept images are renumbered in their previous order, and the game data is updated to
+    /// the new ids.
+    ///
+    /// Like [`SaveArchive::clip_after_turn`], this happens in place. The images that move are
+    /// held in memory and written to the journal until the compaction is committed.
+    pub fn compact(&mut self) -> Result<Compaction, EngineError> {
+        self.ensure_writable()?;
+        backup::rotate(&self.path, &self.backups)?;
+        self.upgrade()?;
+        let mut gd = self.read_game_data()?;
+        let referenced: BTreeSet<usize> = stored_images(&mut gd)
+            .flat_map(|info| [Some(info.id), info.original_id])
+            .flatten()
+            .collect();
+        if referenced
+            .last()
+            .is_some_and(|&id| id >= self.image_index.len())
+        {
+            return Err(EngineError::archive_corrupt(
+                "Image index out of sync with game data",
+            ));
+        }
+
+        let old_end = self.header.index_offset;
+        let mut offset = self.header.game_data_region_offset + self.header.game_data_region_size;
+        let mut image_index = Vec::with_capacity(referenced.len());
+        let mut new_ids = BTreeMap::new();
+        let mut transaction = Transaction::default();
+        for &old_id in &referenced {
+            let entry = self.image_index[old_id];
+            if entry.offset != offset {
+                transaction.write(offset, self.read_stored(entry)?);
+            }
+            new_ids.insert(old_id, image_index.len());
+            image_index.push(IndexEntry { offset, ..entry });
+            offset += entry.length;
+        }
+        // the thumbnails and the transcripts follow the images
+        let mut thumbnails = BTreeMap::new();
+        for (old_id, entry) in self.thumbnails.clone() {
+            let Some(&new_id) = new_ids.get(&old_id) else {
+                continue;
+            };
+            if entry.offset != offset {
+                transaction.write(offset, self.read_stored(entry)?);
+            }
+            thumbnails.insert(new_id, IndexEntry { offset, ..entry });
+            offset += entry.length;
+        }
+        let mut transcripts = BTreeMap::new();
+        for (turn, entry) in self.transcripts.clone() {
+            if entry.offset != offset {
+                transaction.write(offset, self.read_stored(entry)?);
             }
-            None => {
-                self.header.index_offset = Self::HEADER_SIZE + Self::DEFAULT_GAME_DATA_SIZE;
-                self.image_index.clear();
+            transcripts.insert(turn, IndexEntry { offset, ..entry });
+            offset += entry.length;
+        }
+        let mut branch_transcripts = BTreeMap::new();
+        for branch in &gd.branches {
+            let Some(by_turn) = self.branch_transcripts.get(&branch.name).cloned() else {
+                continue;
+            };
+            let mut moved = BTreeMap::new();
+            for (turn, entry) in by_turn {
+                if entry.offset != offset {
+                    transaction.write(offset, self.read_stored(entry)?);
+                }
+                moved.insert(turn, IndexEntry { offset, ..entry });
+                offset += entry.length;
             }
+            branch_transcripts.insert(branch.name.clone(), moved);
         }
 
-        let serialized_index = serde_binary::to_vec(&self.image_index, Endian::Little)?;
-        self.file.set_len(self.header.index_offset)?;
-        self.file.seek(SeekFrom::End(0))?;
-        self.file.write_all(&serialized_index)?;
-        self.header.index_size = serialized_index.len() as u64;
+        for info in stored_images(&mut gd) {
+            info.id = new_ids[&info.id];
+            info.original_id = info.original_id.map(|id| new_ids[&id]);
+        }
+        gd.image_cache.remap_images(&new_ids);
 
-        // this will also write the updated header
-        self.write_game_data(&gd)
+        let compaction = Compaction {
+            removed_images: self.image_index.len() - image_index.len(),
+            freed_bytes: old_end - offset,
+        };
+        self.image_index = image_index;
+        self.thumbnails = thumbnails;
+        self.transcripts = transcripts;
+        self.branch_transcripts = branch_transcripts;
+        self.header.index_offset = offset;
+        self.stage_index(&mut transaction)?;
+        self.stage_game_data(&mut transaction, &gd)?;
+        self.commit(transaction)?;
+        Ok(compaction)
     }
 
     /// writes the current archive to another file.
-    pub fn write_to(&mut self, path: &Path) -> Result<()> {
+    pub fn write_to(&mut self, path: &Path) -> Result<(), EngineError> {
+        self.copy_to(path)?;
+        Ok(())
+    }
+
+    /// Writes the archive to `path`, and returns the copy, locked and open for writing
+    fn copy_to(&mut self, path: &Path) -> Result<File, EngineError> {
         self.file.seek(SeekFrom::Start(0))?;
 
         let mut dst = OpenOptions::new()
+            .read(true)
             .write(true)
             .create(true)
-            .truncate(true)
+            .truncate(false)
             .open(path)?;
+        lock(&dst, path)?;
+        dst.set_len(0)?;
+
+        io::copy(&mut self.file, &mut dst)?;
+        Ok(dst)
+    }
 
-        std::io::copy(&mut self.file, &mut dst)?;
+    /// Copies the archive to `path`, and continues with the copy. An encrypted archive keeps
+    /// its password. A read-only archive can be written again afterwards.
+    pub fn save_as(&mut self, path: &Path) -> Result<(), EngineError> {
+        self.file = self.copy_to(path)?;
+        self.path = path.to_path_buf();
+        self.journal = journal::path_for(path);
+        self.read_only = false;
         Ok(())
     }
+
+    /// Writes a copy of the archive to `path` that ends after `turn`, like
+    /// [`SaveArchive::clip_after_turn`] would. This archive stays as it is.
+    pub fn fork_clipped(&mut self, path: &Path, turn: usize) -> Result<(), EngineError> {
+        let file = self.copy_to(path)?;
+        let journal = journal::path_for(path);
+        if journal.exists() {
+            fs::remove_file(&journal)?;
+        }
+        let mut fork = Self {
+            file,
+            path: path.to_path_buf(),
+            journal,
+            backups: BackupSettings::default(),
+            header: self.header,
+            image_index: self.image_index.clone(),
+            transcripts: self.transcripts.clone(),
+            thumbnails: self.thumbnails.clone(),
+            branch_transcripts: self.branch_transcripts.clone(),
+            cipher: self.cipher.clone(),
+            metadata: self.metadata.clone(),
+            clock: PlayClock::start(),
+            read_only: false,
+        };
+        fork.clip_after_turn(turn)
+    }
+
+    /// Continues the game after `turn` in a new branch, see [`GameData::fork_after`]. The
+    /// transcripts of the later turns are kept with them.
+    pub fn fork_after(&mut self, turn: usize, name: &str) -> Result<(), EngineError> {
+        self.ensure_writable()?;
+        backup::rotate(&self.path, &self.backups)?;
+        self.upgrade()?;
+        let mut gd = self.read_game_data()?;
+        let forks = forks(&gd);
+        let current = gd.branch_name().to_string();
+        gd.fork_after(turn, name)?;
+
+        let later = self.split_off_transcripts(&forks, turn);
+        self.branch_transcripts.insert(current, later);
+        let mut transaction = Transaction::default();
+        self.stage_index(&mut transaction)?;
+        self.stage_game_data(&mut transaction, &gd)?;
+        self.commit(transaction)
+    }
+
+    /// Continues the game with the branch at `index`, see [`GameData::switch_branch`]
+    pub fn switch_branch(&mut self, index: usize) -> Result<(), EngineError> {
+        self.ensure_writable()?;
+        backup::rotate(&self.path, &self.backups)?;
+        self.upgrade()?;
+        let mut gd = self.read_game_data()?;
+        let forks = forks(&gd);
+        let current = gd.branch_name().to_string();
+        gd.switch_branch(index)?;
+
+        let (name, forked_after) = forks[index].clone();
+        let later = self.split_off_transcripts(&forks, forked_after);
+        if gd.branches.iter().any(|b| b.name == current) {
+            self.branch_transcripts.insert(current, later);
+        }
+        let restored = self.branch_transcripts.remove(&name).unwrap_or_default();
+        self.transcripts.extend(restored);
+        let mut transaction = Transaction::default();
+        self.stage_index(&mut transaction)?;
+        self.stage_game_data(&mut transaction, &gd)?;
+        self.commit(transaction)
+    }
+
+    /// Removes the transcripts after `turn` from the current branch, like
+    /// [`GameData::clip_after_turn`] does with the turns, and returns them. `forks` are the
+    /// names of the other branches, with the turns they were forked after before the turns
+    /// were removed. The ones that were forked from removed turns get a copy of their
+    /// transcripts.
+    fn split_off_transcripts(
+        &mut self,
+        forks: &[(String, usize)],
+        turn: usize,
+    ) -> BTreeMap<usize, IndexEntry> {
+        for (name, forked_after) in forks.iter().filter(|(_, f)| *f > turn) {
+            let shared = self
+                .transcripts
+                .range(turn + 1..=*forked_after)
+                .map(|(&t, &entry)| (t, entry));
+            self.branch_transcripts
+                .entry(name.clone())
+                .or_default()
+                .extend(shared);
+        }
+        self.transcripts.split_off(&(turn + 1))
+    }
+}
+
+/// The names of the other branches, with the turns they were forked after
+fn forks(gd: &GameData) -> Vec<(String, usize)> {
+    gd.branches
+        .iter()
+        .map(|b| (b.name.clone(), b.forked_after))
+        .collect()
+}
+
+/// Every image of the game data that refers to the archive
+fn stored_images(gd: &mut GameData) -> impl Iterator<Item = &mut StoredImageInfo> {
+    let in_flight = gd
+        .in_flight_turn
+        .iter_mut()
+        .flat_map(|turn| turn.image.as_mut());
+    let branches = gd.branches.iter_mut().flat_map(|b| &mut b.turns);
+    gd.turn_data
+        .iter_mut()
+        .chain(branches)
+        .flat_map(|td| td.images.iter_mut())
+        .chain(in_flight)
+}
+
+/// The header of older versions is shorter, their game data region starts where it ends
+fn header_size(version: u64) -> usize {
+    match version {
+        ..3 => V2_HEADER_SIZE,
+        3 => V3_HEADER_SIZE,
+        4 => V4_HEADER_SIZE,
+        5 => V5_HEADER_SIZE,
+        _ => HEADER_SIZE,
+    }
+}
+
+impl SaveHeader {
+    /// The fields that its version has, in little endian
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend(self.magic);
+        for field in [
+            self.version,
+            self.game_data_region_size,
+            self.game_data_size,
+            self.game_data_region_offset,
+            self.index_offset,
+            self.index_size,
+            self.flags,
+        ] {
+            bytes.extend(field.to_le_bytes());
+        }
+        bytes.extend(self.salt);
+        bytes.extend(self.key_check);
+        for field in [
+            self.metadata_size,
+            self.game_data_checksum,
+            self.index_checksum,
+            self.metadata_checksum,
+        ] {
+            bytes.extend(field.to_le_bytes());
+        }
+        bytes.extend(u64::from(checksum(&bytes)).to_le_bytes());
+        bytes.truncate(header_size(self.version));
+        bytes
+    }
+
+    /// Headers before version 6 have no checksum, they always pass
+    fn verify_checksum(&self) -> Result<(), EngineError> {
+        if self.version < 6 {
+            return Ok(());
+        }
+        let bytes = self.encode();
+        let (fields, _) = bytes.split_last_chunk::<8>().unwrap();
+        verify(fields, self.header_checksum, "the header", 0)
+    }
+
+    /// The fields that older versions don't have are zeroed in `bytes`
+    fn decode(bytes: &[u8; HEADER_SIZE]) -> Self {
+        let mut fields = Fields(bytes);
+        Self {
+            magic: fields.array(),
+            version: fields.u64(),
+            game_data_region_size: fields.u64(),
+            game_data_size: fields.u64(),
+            game_data_region_offset: fields.u64(),
+            index_offset: fields.u64(),
+            index_size: fields.u64(),
+            flags: fields.u64(),
+            salt: fields.array(),
+            key_check: fields.array(),
+            metadata_size: fields.u64(),
+            game_data_checksum: fields.u64(),
+            index_checksum: fields.u64(),
+            metadata_checksum: fields.u64(),
+            header_checksum: fields.u64(),
+        }
+    }
+}
+
+/// Reads the fields of a header one after the other
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let (field, rest) = self
+            .0
+            .split_first_chunk()
+            .expect("the fields fit into the header");
+        self.0 = rest;
+        *field
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array())
+    }
+}
+
+/// Fails if the file isn't a save, or one of a newer version
+fn read_header(file: &mut File) -> Result<SaveHeader, EngineError> {
+    let header = read_unverified_header(file)?;
+    header.verify_checksum()?;
+    Ok(header)
+}
+
+/// Reads the header like [`read_header`], but doesn't compare it with its checksum
+fn read_unverified_header(file: &mut File) -> Result<SaveHeader, EngineError> {
+    let mut buf = [0u8; HEADER_SIZE];
+    file.read_exact(&mut buf[..V2_HEADER_SIZE])?;
+    if &buf[..MAGIC.len()] != MAGIC {
+        return Err(EngineError::archive_corrupt("Not a World Weaver save file"));
+    }
+    let version = Fields(&buf[MAGIC.len()..]).u64();
+    if version > VERSION {
+        return Err(EngineError::ArchiveTooNew { version });
+    }
+    file.read_exact(&mut buf[V2_HEADER_SIZE..header_size(version)])?;
+    Ok(SaveHeader::decode(&buf))
+}
+
+/// Takes the advisory lock of an archive that is opened for writing, so two instances, e.g.
+/// the game and the admin CLI, don't overwrite each other's changes. The lock is released
+/// when the file is closed.
+fn lock(file: &File, path: &Path) -> Result<(), EngineError> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(EngineError::ArchiveLocked),
+        // e.g. some network file systems
+        Err(TryLockError::Error(e)) if e.kind() == io::ErrorKind::Unsupported => {
+            warn!("{path:?} can't be locked, it's not protected against other instances");
+            Ok(())
+        }
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// The CRC32 of `bytes`
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
 }
 
-fn read_header(file: &mut File) -> Result<SaveHeader> {
-    let mut res = SaveHeader::default();
-    let buf: &mut [u8; size_of::<SaveHeader>()] = unsafe { transmute(&mut res) };
-    file.read_exact(buf)?;
-    Ok(res)
+fn verify(bytes: &[u8], expected: u64, section: &str, offset: u64) -> Result<(), EngineError> {
+    if u64::from(checksum(bytes)) == expected {
+        return Ok(());
+    }
+    Err(EngineError::ChecksumMismatch {
+        section: section.to_string(),
+        offset,
+    })
 }
 
-fn write_header(file: &mut File, header: &SaveHeader) -> Result<()> {
-    let buf: &[u8; size_of::<SaveHeader>()] = unsafe { transmute(header) };
+fn write_header(file: &mut File, header: &SaveHeader) -> Result<(), EngineError> {
     file.seek(SeekFrom::Start(0))?;
-    file.write_all(buf)?;
+    file.write_all(&header.encode())?;
     Ok(())
 }
 
+/// The key of an encrypted archive. Each piece of game data or image is encrypted on its
+/// own, with a random nonce that is stored in front of it.
+#[derive(Clone)]
+struct Cipher(Aes256Gcm);
+
+impl Cipher {
+    /// `None` if the archive isn't encrypted
+    fn for_header(
+        header: &SaveHeader,
+        password: Option<&str>,
+    ) -> Result<Option<Self>, EngineError> {
+        if header.flags & FLAG_ENCRYPTED == 0 {
+            return Ok(None);
+        }
+        let password = password.ok_or(EngineError::PasswordRequired)?;
+        let (cipher, key_check) = Self::derive(password, &header.salt)?;
+        if key_check != header.key_check {
+            return Err(EngineError::WrongPassword);
+        }
+        Ok(Some(cipher))
+    }
+
+    /// Returns the cipher, and the value that is stored to check the password
+    fn derive(
+        password: &str,
+        salt: &[u8; SALT_SIZE],
+    ) -> Result<(Self, [u8; KEY_SIZE]), EngineError> {
+        let mut derived = [0u8; 2 * KEY_SIZE];
+        Argon2::default()
+            .hash_password_into(password.as_bytes(), salt, &mut derived)
+            .map_err(|e| EngineError::Other(eyre!("Failed to derive the key: {e}")))?;
+        let (key, key_check) = derived.split_at(KEY_SIZE);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        Ok((Self(cipher), key_check.try_into().unwrap()))
+    }
+
+    fn encrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, EngineError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .0
+            .encrypt(&nonce, bytes)
+            .map_err(|e| EngineError::Other(eyre!("Encryption failed: {e}")))?;
+        Ok([&nonce[..], &encrypted].concat())
+    }
+
+    /// `None` if the bytes weren't encrypted with this key, or were changed since
+    fn decrypt(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        if bytes.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, encrypted) = bytes.split_at(NONCE_SIZE);
+        self.0.decrypt(Nonce::from_slice(nonce), encrypted).ok()
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+/// JPEGs and WebPs are compressed already, other images are compressed if that makes them
+/// at least a tenth smaller. Returns `None` if the image should be stored as it is.
+fn compress_image(
+    bytes: &[u8],
+    format: Option<ImageFormat>,
+) -> Result<Option<Vec<u8>>, EngineError> {
+    if matches!(format, Some(ImageFormat::Jpeg | ImageFormat::WebP)) {
+        return Ok(None);
+    }
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(bytes)?;
+    let compressed = encoder.finish()?;
+    Ok((compressed.len() < bytes.len() / 10 * 9).then_some(compressed))
+}
+
 #[cfg(test)]
-mod tests {
-    use crate::game::{PcDescription, StoredImageInfo};
+pub(crate) mod tests {
+    use crate::game::{ImageCache, InFlightTurn, PcDescription, StoredImageInfo, TurnInput};
 
     use super::*;
     use std::collections::BTreeMap;
     use tempfile::NamedTempFile;
 
-    fn make_sample_game_data(turns: usize) -> GameData {
+    pub(crate) fn make_sample_game_data(turns: usize) -> GameData {
         let mut pc_descriptions = BTreeMap::new();
         pc_descriptions.insert(
             "Alice".to_string(),
             PcDescription {
                 description: "A brave warrior".to_string(),
                 initial_action: "".into(),
+                stats: BTreeMap::new(),
             },
         );
 
@@ -276,6 +1406,22 @@ mod tests {
             pc_descriptions,
             init_action: "Look around".to_string(),
             name: "World name".into(),
+            sampling: Default::default(),
+            target_output_words: None,
+            max_tokens: None,
+            system_prompt_template: None,
+            summary_prompt: None,
+            history_turns: None,
+            preferred_llm: None,
+            preferred_image_model: None,
+            text_only: false,
+            content_rating: None,
+            tone: Default::default(),
+            locations: vec![],
+            factions: vec![],
+            npcs: vec![],
+            script: None,
+            cover: None,
         };
 
         let mut summaries = vec![];
@@ -283,6 +1429,7 @@ mod tests {
             summaries.push(crate::game::Summary {
                 content: format!("Summary at turn {}", i * 8),
                 bday: i * 8,
+                level: crate::game::SummaryLevel::Chapter,
             });
         }
 
@@ -295,6 +1442,7 @@ mod tests {
             let output = crate::game::TurnOutput {
                 text: format!("Result of action {}", i),
                 secret_info: format!("Secret info {}", i),
+                elapsed_minutes: None,
                 proposed_next_actions: [
                     format!("Action A{}", i),
                     format!("Action B{}", i),
@@ -304,6 +1452,9 @@ mod tests {
                 output_tokens: 10,
                 image_description: format!("image_description {i}"),
                 image_caption: format!("image_description {i}"),
+                codex_updates: vec![],
+                world_state_updates: vec![],
+                rolls: vec![],
             };
             turn_data.push(crate::game::TurnData {
                 summary_before_input: if i < 8 {
@@ -316,20 +1467,38 @@ mod tests {
                 images: vec![StoredImageInfo {
                     id: i,
                     caption: format!("caption {i}"),
+                    original_id: None,
+                    seed: None,
                 }],
+                bookmarked: false,
+                bookmark_label: None,
+                world_state: None,
             });
         }
 
         GameData {
+            schema_version: migration::CURRENT_SCHEMA_VERSION,
             world_description,
             pc: "Alice".to_string(),
             summaries,
             turn_data,
+            costs: Default::default(),
+            in_flight_turn: None,
+            image_cache: ImageCache::default(),
+            text_only: false,
+            difficulty: Default::default(),
+            pinned_seeds: BTreeMap::new(),
+            snapshots: vec![],
+            codex: crate::game::Codex::default(),
+            world_state: crate::game::WorldState::new(),
+            branch: String::new(),
+            branches: vec![],
+            recap: None,
         }
     }
 
     #[test]
-    fn create_and_write_game_data() -> Result<()> {
+    fn create_and_write_game_data() -> Result<(), EngineError> {
         let tmpfile = NamedTempFile::new()?;
         let mut archive = SaveArchive::create(tmpfile.path())?;
 
@@ -344,7 +1513,50 @@ mod tests {
     }
 
     #[test]
-    fn append_and_read_image() -> Result<()> {
+    fn data_that_doesnt_fit_leaves_the_archive_intact() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create(tmpfile.path())?;
+        archive.write_game_data(&make_sample_game_data(2))?;
+
+        archive.header.game_data_region_size = archive.header.game_data_size * 4;
+        assert!(archive.write_game_data(&make_sample_game_data(40)).is_err());
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn in_flight_turn_survives_reopening() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        {
+            let mut archive = SaveArchive::create(tmpfile.path())?;
+            let id = archive.append_image(&[1, 2, 3])?;
+            let mut game_data = make_sample_game_data(2);
+            game_data.in_flight_turn = Some(InFlightTurn {
+                input: TurnInput::player_action("open the door".into()),
+                partial_text: "The door creaks".into(),
+                image: Some(StoredImageInfo {
+                    id,
+                    caption: "Door".into(),
+                    original_id: None,
+                    seed: None,
+                }),
+            });
+            archive.write_game_data(&game_data)?;
+        }
+
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        let in_flight = archive.read_game_data()?.in_flight_turn.unwrap();
+        assert_eq!(in_flight.input.player_action, "open the door");
+        assert_eq!(in_flight.partial_text, "The door creaks");
+        assert_eq!(
+            archive.read_image(in_flight.image.unwrap().id)?,
+            vec![1, 2, 3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn append_and_read_image() -> Result<(), EngineError> {
         let tmpfile = NamedTempFile::new()?;
         let mut archive = SaveArchive::create(tmpfile.path())?;
 
@@ -359,12 +1571,13 @@ mod tests {
 
         assert_eq!(img1, read1);
         assert_eq!(img2, read2);
+        assert!(!journal::path_for(tmpfile.path()).exists());
 
         Ok(())
     }
 
     #[test]
-    fn reopen_archive() -> Result<()> {
+    fn reopen_archive() -> Result<(), EngineError> {
         let tmpfile = NamedTempFile::new()?;
         let path = tmpfile.path().to_path_buf();
 
@@ -392,7 +1605,7 @@ mod tests {
     }
 
     #[test]
-    fn image_not_found() -> Result<()> {
+    fn image_not_found() -> Result<(), EngineError> {
         let tmpfile = NamedTempFile::new()?;
         let mut archive = SaveArchive::create(tmpfile.path())?;
 
@@ -403,7 +1616,7 @@ mod tests {
     }
 
     #[test]
-    fn clip_after_turn_truncates_turns_summaries_and_images() -> Result<()> {
+    fn clip_after_turn_truncates_turns_summaries_and_images() -> Result<(), EngineError> {
         let tmpfile = NamedTempFile::new()?;
         let path = tmpfile.path();
 
@@ -419,7 +1632,7 @@ mod tests {
 
             // Append one image per turn (ids 0..total_turns-1)
             for i in 0..total_turns {
-                archive.append_image(&vec![i as u8; 4])?;
+                archive.append_image(&[i as u8; 4])?;
             }
         }
 
@@ -461,7 +1674,28 @@ mod tests {
     }
 
     #[test]
-    fn write_to_copies_entire_archive() -> Result<()> {
+    fn writers_lock_the_archive() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create(tmpfile.path())?;
+        archive.write_game_data(&make_sample_game_data(1))?;
+        assert!(matches!(
+            SaveArchive::open(tmpfile.path()),
+            Err(EngineError::ArchiveLocked)
+        ));
+
+        let mut reader = SaveArchive::open_read_only(tmpfile.path(), None)?;
+        assert_eq!(reader.read_game_data()?.turn_data.len(), 1);
+        assert!(reader.write_game_data(&make_sample_game_data(2)).is_err());
+        assert!(reader.append_image(&[1, 2, 3]).is_err());
+
+        drop(archive);
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        archive.write_game_data(&make_sample_game_data(2))?;
+        Ok(())
+    }
+
+    #[test]
+    fn write_to_copies_entire_archive() -> Result<(), EngineError> {
         use tempfile::NamedTempFile;
 
         let src_file = NamedTempFile::new()?;
@@ -474,11 +1708,11 @@ mod tests {
             archive.write_game_data(&game_data)?;
 
             for i in 0..10 {
-                archive.append_image(&vec![i as u8; 8])?;
+                archive.append_image(&[i as u8; 8])?;
             }
 
             // Copy archive
-            archive.write_to(&dst_file.path().to_path_buf())?;
+            archive.write_to(dst_file.path())?;
         }
 
         // Open copied archive and validate contents
@@ -495,4 +1729,512 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn save_as_continues_with_the_copy() -> Result<(), EngineError> {
+        let original = NamedTempFile::new()?;
+        let copy = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create_encrypted(original.path(), "pw")?;
+        archive.write_game_data(&make_sample_game_data(2))?;
+        archive.save_as(copy.path())?;
+        archive.append_image(&[1, 2, 3])?;
+
+        assert_eq!(
+            SaveArchive::open_with_password(original.path(), "pw")?.n_images(),
+            0
+        );
+        drop(archive);
+        let mut copied = SaveArchive::open_with_password(copy.path(), "pw")?;
+        assert_eq!(copied.read_image(0)?, vec![1, 2, 3]);
+        assert_eq!(copied.read_game_data()?.turn_data.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_is_read_without_the_game_data() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        {
+            let mut archive = SaveArchive::create(tmpfile.path())?;
+            assert!(archive.read_metadata().is_err());
+            archive.append_image(&[1])?;
+            let mut data = make_sample_game_data(3);
+            data.recap = Some(crate::game::Recap {
+                title: "The Long Night".into(),
+                text: "You were lost.".into(),
+                turns: 3,
+            });
+            archive.write_game_data(&data)?;
+            // the metadata follows the index, which moves with each image
+            archive.append_image(&[2])?;
+        }
+
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        let metadata = archive.read_metadata()?;
+        assert_eq!(metadata.world_name, "World name");
+        assert_eq!(metadata.character, "Alice");
+        assert_eq!(metadata.turns, 3);
+        assert_eq!(metadata.title.as_deref(), Some("The Long Night"));
+        assert!(metadata.thumbnail.is_some());
+        assert!(metadata.last_played <= SystemTime::now());
+
+        // it's stored, not made from the game data
+        archive.header.game_data_size = 0;
+        assert_eq!(archive.read_metadata()?, metadata);
+        assert_eq!(archive.read_image(1)?, vec![2]);
+
+        // older archives don't have it, then it's made from the game data
+        let old = NamedTempFile::new()?;
+        write_old_archive(old.path(), 2, &make_sample_game_data(2), &[])?;
+        assert_eq!(SaveArchive::open(old.path())?.read_metadata()?.turns, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn forks_are_clipped_and_leave_the_original() -> Result<(), EngineError> {
+        let original = NamedTempFile::new()?;
+        let fork = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create_encrypted(original.path(), "pw")?;
+        let mut gd = make_sample_game_data(3);
+        for (i, td) in gd.turn_data.iter_mut().enumerate() {
+            td.images[0].id = archive.append_image(&[i as u8])?;
+        }
+        gd.snapshots = vec![0, 2];
+        archive.write_game_data(&gd)?;
+
+        archive.fork_clipped(fork.path(), 1)?;
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 3);
+        assert_eq!(archive.read_image(2)?, vec![2]);
+
+        let mut forked = SaveArchive::open_with_password(fork.path(), "pw")?;
+        assert_eq!(forked.read_game_data()?.turn_data.len(), 2);
+        assert_eq!(forked.read_metadata()?.turns, 2);
+        assert_eq!(forked.read_metadata()?.snapshots, [0]);
+        assert_eq!(forked.n_images(), 2);
+        assert!(archive.fork_clipped(fork.path(), 5).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_sections_are_recognized() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let (image_offset, index_offset) = {
+            let mut archive = SaveArchive::create(tmpfile.path())?;
+            archive.write_game_data(&make_sample_game_data(2))?;
+            archive.append_image(&[0xFF, 0xD8, 0xFF, 1, 2, 3])?;
+            (archive.image_index[0].offset, archive.header.index_offset)
+        };
+        let intact = std::fs::read(tmpfile.path())?;
+        let damaged_at = |offset: u64| -> Result<SaveArchive, EngineError> {
+            let mut bytes = intact.clone();
+            bytes[offset as usize] ^= 1;
+            std::fs::write(tmpfile.path(), bytes)?;
+            SaveArchive::open(tmpfile.path())
+        };
+        let is_mismatch = |res: Result<_, EngineError>, expected: &str| matches!(res, Err(EngineError::ChecksumMismatch { section, .. }) if section == expected);
+
+        assert!(is_mismatch(damaged_at(20).map(|_| ()), "the header"));
+        assert!(is_mismatch(
+            damaged_at(index_offset).map(|_| ()),
+            "the image index"
+        ));
+        let mut archive = damaged_at(SaveArchive::HEADER_SIZE + 2)?;
+        assert!(is_mismatch(
+            archive.read_game_data().map(|_| ()),
+            "the game data"
+        ));
+        drop(archive);
+        let mut archive = damaged_at(image_offset + 4)?;
+        assert!(is_mismatch(archive.read_image(0).map(|_| ()), "image 0"));
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 2);
+        Ok(())
+    }
+
+    fn transcript_entry(response: &str) -> AuditEntry {
+        AuditEntry {
+            sent_at_ms: 0,
+            duration_ms: 10,
+            purpose: crate::audit_log::RequestPurpose::Turn,
+            request: crate::llm::Request {
+                system: Some("You are the GM".into()),
+                messages: vec![],
+                max_tokens: 100,
+                sampling: Default::default(),
+                tools: vec![],
+            },
+            response: response.into(),
+            tool_calls: vec![],
+            thinking: String::new(),
+            input_tokens: 1,
+            output_tokens: 2,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn images_are_stored_with_thumbnails() -> Result<(), EngineError> {
+        use crate::thumbnail::tests::jpeg_bytes;
+
+        let tmpfile = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create(tmpfile.path())?;
+        archive.append_image(&jpeg_bytes(600, 400))?;
+        // can't be decoded, so it has no thumbnail
+        archive.append_image(&[1, 2, 3])?;
+        archive.append_image(&jpeg_bytes(300, 900))?;
+        let mut data = make_sample_game_data(3);
+        data.turn_data[1].images[0].id = 2;
+        archive.write_game_data(&data)?;
+        assert_eq!(archive.read_thumbnail(1)?, None);
+
+        archive.compact()?;
+        drop(archive);
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        let size = |bytes: Option<Vec<u8>>| {
+            let img = image::load_from_memory(&bytes.unwrap()).unwrap();
+            (img.width(), img.height())
+        };
+        assert_eq!(size(archive.read_thumbnail(0)?), (256, 171));
+        assert_eq!(size(archive.read_thumbnail(1)?), (85, 256));
+
+        archive.clip_after_turn(0)?;
+        assert_eq!(archive.read_thumbnail(1)?, None);
+        assert!(archive.read_thumbnail(0)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn transcripts_are_stored_by_turn() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create(tmpfile.path())?;
+        // turn i shows image i
+        archive.write_game_data(&make_sample_game_data(3))?;
+        archive.append_image(&[0])?;
+        archive.append_transcript(0, &[transcript_entry("first")])?;
+        archive.append_image(&[1])?;
+        archive.append_transcript(1, &[transcript_entry("second")])?;
+        archive.append_image(&[2])?;
+        archive.append_image(&[3])?;
+        archive.append_transcript(2, &[transcript_entry("third")])?;
+        archive.append_transcript(1, &[transcript_entry("regenerated")])?;
+
+        drop(archive);
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        let responses = |transcript: Option<Vec<AuditEntry>>| -> Vec<String> {
+            transcript
+                .unwrap()
+                .into_iter()
+                .map(|e| e.response)
+                .collect()
+        };
+        assert_eq!(
+            responses(archive.read_transcript(1)?),
+            ["second", "regenerated"]
+        );
+        assert_eq!(archive.read_transcript(5)?.map(|t| t.len()), None);
+
+        archive.compact()?;
+        assert_eq!(archive.n_images(), 3);
+        assert_eq!(responses(archive.read_transcript(0)?), ["first"]);
+        assert_eq!(
+            responses(archive.read_transcript(1)?),
+            ["second", "regenerated"]
+        );
+
+        archive.clip_after_turn(1)?;
+        assert_eq!(archive.transcript_turns().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(archive.append_image(&[9])?, 2);
+        drop(archive);
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        assert_eq!(responses(archive.read_transcript(0)?), ["first"]);
+        assert_eq!(archive.read_image(1)?, vec![1]);
+        assert_eq!(archive.read_image(2)?, vec![9]);
+        Ok(())
+    }
+
+    #[test]
+    fn branches_keep_their_transcripts() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create(tmpfile.path())?;
+        archive.write_game_data(&make_sample_game_data(3))?;
+        for turn in 0..3 {
+            archive.append_image(&[turn as u8])?;
+            archive.append_transcript(turn, &[transcript_entry(&format!("turn {turn}"))])?;
+        }
+        archive.fork_after(0, "Other door")?;
+        assert_eq!(archive.transcript_turns().collect::<Vec<_>>(), [0]);
+        // the images of the other branch are still referenced
+        archive.compact()?;
+        assert_eq!(archive.n_images(), 3);
+
+        drop(archive);
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        archive.switch_branch(0)?;
+        let data = archive.read_game_data()?;
+        assert_eq!((data.turn_data.len(), data.branches.len()), (3, 0));
+        assert_eq!(archive.transcript_turns().collect::<Vec<_>>(), [0, 1, 2]);
+        let transcript = archive.read_transcript(2)?.unwrap();
+        assert_eq!(transcript[0].response, "turn 2");
+        Ok(())
+    }
+
+    #[test]
+    fn image_formats_are_recorded() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let mut png = vec![];
+        image::RgbImage::new(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        {
+            let mut archive = SaveArchive::create(tmpfile.path())?;
+            archive.write_game_data(&make_sample_game_data(1))?;
+            archive.append_image(&png)?;
+            archive.append_image(&[1, 2, 3])?;
+        }
+
+        let archive = SaveArchive::open(tmpfile.path())?;
+        assert_eq!(archive.image_format(0), Some(ImageFormat::Png));
+        assert_eq!(archive.image_format(1), None);
+        Ok(())
+    }
+
+    /// What the given older version wrote: a header without flags, uncompressed game data,
+    /// and the images after a small game data region
+    fn write_old_archive(
+        path: &Path,
+        version: u64,
+        data: &GameData,
+        images: &[&[u8]],
+    ) -> Result<(), EngineError> {
+        let json = serde_json::to_vec(data).unwrap();
+        let region_offset = V2_HEADER_SIZE as u64;
+        let region_size = 2 * json.len() as u64;
+        let mut image_bytes = vec![];
+        let mut entries = vec![];
+        for image in images {
+            entries.push(IndexEntryV2 {
+                offset: region_offset + region_size + image_bytes.len() as u64,
+                length: image.len() as u64,
+                format: ImageFormat::detect(image),
+            });
+            image_bytes.extend_from_slice(image);
+        }
+        let index = if version < 2 {
+            let entries: Vec<_> = entries.iter().map(|e| (e.offset, e.length)).collect();
+            serde_binary::to_vec(&entries, Endian::Little)
+        } else {
+            serde_binary::to_vec(&entries, Endian::Little)
+        }
+        .unwrap();
+
+        let header = [
+            u64::from_le_bytes(*MAGIC),
+            version,
+            region_size,
+            json.len() as u64,
+            region_offset,
+            region_offset + region_size + image_bytes.len() as u64,
+            index.len() as u64,
+        ];
+        let mut bytes: Vec<u8> = header.iter().flat_map(|x| x.to_le_bytes()).collect();
+        bytes.extend(json);
+        bytes.resize((region_offset + region_size) as usize, 0);
+        bytes.extend(image_bytes);
+        bytes.extend(index);
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    #[test]
+    fn version_1_indices_are_read() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        write_old_archive(
+            tmpfile.path(),
+            1,
+            &make_sample_game_data(1),
+            &[&[1, 2], &[3, 4, 5]],
+        )?;
+
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        assert_eq!(archive.read_image(1)?, vec![3, 4, 5]);
+        assert_eq!(archive.image_format(1), None);
+        archive.append_image(&[6])?;
+        assert_eq!(archive.header.version, VERSION);
+
+        drop(archive);
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        assert_eq!(archive.read_image(2)?, vec![6]);
+        assert_eq!(
+            archive.header.game_data_region_offset,
+            SaveArchive::HEADER_SIZE
+        );
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn headers_are_little_endian_and_checked() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let archive = SaveArchive::create(tmpfile.path())?;
+        let bytes = std::fs::read(tmpfile.path())?;
+        assert_eq!(&bytes[..8], MAGIC);
+        assert_eq!(bytes[8..16], VERSION.to_le_bytes());
+        assert_eq!(
+            bytes[56..64],
+            archive.header.flags.to_le_bytes(),
+            "the flags follow the fields of version 2"
+        );
+        let header = SaveHeader::decode(&bytes[..HEADER_SIZE].try_into().unwrap());
+        assert_eq!(header.encode(), bytes[..HEADER_SIZE]);
+
+        drop(archive);
+        let mut newer = bytes.clone();
+        newer[8..16].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        std::fs::write(tmpfile.path(), newer)?;
+        assert!(matches!(
+            SaveArchive::open(tmpfile.path()),
+            Err(EngineError::ArchiveTooNew { .. })
+        ));
+
+        std::fs::write(tmpfile.path(), [b'?'; HEADER_SIZE])?;
+        assert!(matches!(
+            SaveArchive::open(tmpfile.path()),
+            Err(EngineError::ArchiveCorrupt { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn version_2_archives_are_upgraded_on_the_first_write() -> Result<(), EngineError> {
+        let mut png = vec![];
+        image::RgbImage::new(2, 2)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let tmpfile = NamedTempFile::new()?;
+        write_old_archive(tmpfile.path(), 2, &make_sample_game_data(3), &[&png])?;
+
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 3);
+        assert_eq!(archive.image_format(0), Some(ImageFormat::Png));
+        assert_eq!(archive.header.version, 2);
+        archive.write_game_data(&make_sample_game_data(4))?;
+
+        drop(archive);
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        assert_eq!(archive.header.version, VERSION);
+        assert_eq!(
+            archive.header.game_data_region_offset,
+            SaveArchive::HEADER_SIZE
+        );
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 4);
+        assert_eq!(archive.read_image(0)?, png);
+        Ok(())
+    }
+
+    #[test]
+    fn game_data_and_uncompressed_images_are_compressed() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create(tmpfile.path())?;
+        let data = make_sample_game_data(50);
+        archive.write_game_data(&data)?;
+        let json_size = serde_json::to_vec(&data).unwrap().len() as u64;
+        assert!(archive.header.game_data_size < json_size / 4);
+
+        let raw = vec![7u8; 4096];
+        archive.append_image(&raw)?;
+        assert!(archive.image_index[0].compressed);
+        assert!(archive.image_index[0].length < 1024);
+        // the bytes start like a JPEG, but aren't one
+        let jpeg_like = [&[0xFF, 0xD8, 0xFF][..], &[0; 4096]].concat();
+        archive.append_image(&jpeg_like)?;
+        assert!(!archive.image_index[1].compressed);
+
+        drop(archive);
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        assert_eq!(archive.read_image(0)?, raw);
+        assert_eq!(archive.read_image(1)?, jpeg_like);
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 50);
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_archives_need_the_password() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        // the bytes start like a JPEG, so they aren't compressed
+        let image = [&[0xFF, 0xD8, 0xFF][..], b"a secret image"].concat();
+        {
+            let mut archive = SaveArchive::create_encrypted(tmpfile.path(), "hunter2")?;
+            archive.write_game_data(&make_sample_game_data(3))?;
+            archive.append_image(&image)?;
+        }
+        let bytes = std::fs::read(tmpfile.path())?;
+        assert!(!bytes.windows(image.len()).any(|w| w == image));
+
+        assert!(matches!(
+            SaveArchive::open(tmpfile.path()),
+            Err(EngineError::PasswordRequired)
+        ));
+        assert!(matches!(
+            SaveArchive::open_with_password(tmpfile.path(), "hunter3"),
+            Err(EngineError::WrongPassword)
+        ));
+        let mut archive = SaveArchive::open_with_password(tmpfile.path(), "hunter2")?;
+        assert!(archive.is_encrypted());
+        assert_eq!(archive.read_game_data()?.turn_data.len(), 3);
+        assert_eq!(archive.read_image(0)?, image);
+        archive.append_image(&[1, 2, 3])?;
+        assert_eq!(archive.read_image(1)?, vec![1, 2, 3]);
+
+        let other = NamedTempFile::new()?;
+        SaveArchive::create(other.path())?;
+        assert!(!SaveArchive::open_with_password(other.path(), "unused")?.is_encrypted());
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_keeps_only_referenced_images() -> Result<(), EngineError> {
+        let tmpfile = NamedTempFile::new()?;
+        {
+            let mut archive = SaveArchive::create(tmpfile.path())?;
+            for i in 0..6 {
+                archive.append_image(&[i; 4])?;
+            }
+            let mut gd = make_sample_game_data(3);
+            gd.turn_data[1].images[0].id = 2;
+            gd.turn_data[1].images[0].original_id = Some(3);
+            gd.turn_data[2].images[0].id = 5;
+            let cached = |id, original_id| crate::game::CachedImage {
+                id,
+                original_id,
+                seed: None,
+            };
+            gd.image_cache.insert(1, cached(2, Some(3)));
+            gd.image_cache.insert(2, cached(4, None));
+            archive.write_game_data(&gd)?;
+
+            let compaction = archive.compact()?;
+            assert_eq!(
+                compaction,
+                Compaction {
+                    removed_images: 2,
+                    freed_bytes: 8,
+                }
+            );
+            assert_eq!(archive.append_image(&[9])?, 4);
+        }
+
+        let mut archive = SaveArchive::open(tmpfile.path())?;
+        let gd = archive.read_game_data()?;
+        let ids: Vec<_> = gd
+            .turn_data
+            .iter()
+            .map(|td| (td.images[0].id, td.images[0].original_id))
+            .collect();
+        assert_eq!(ids, vec![(0, None), (1, Some(2)), (3, None)]);
+        for (id, bytes) in [(0, [0; 4]), (1, [2; 4]), (2, [3; 4]), (3, [5; 4])] {
+            assert_eq!(archive.read_image(id)?, bytes);
+        }
+        assert_eq!(archive.read_image(4)?, vec![9]);
+        assert_eq!(gd.image_cache.get(1).map(|image| image.id), Some(1));
+        assert_eq!(gd.image_cache.get(2), None);
+        Ok(())
+    }
 }
@@ -0,0 +1,131 @@
+//! Copies of an archive from before it was changed, so a damaged save, or a change that the
+//! player regrets, can be rolled back. The newest copy of `save.wwsave` is `save.bak.1`, the
+//! older ones have higher numbers.
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct BackupSettings {
+    /// how many backups are kept, 0 disables them
+    pub keep: usize,
+    /// a backup is only made if the newest one is at least this old, so the writes while a
+    /// turn streams in don't replace every backup with nearly the same state
+    pub min_interval_minutes: u64,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        Self {
+            keep: 0,
+            min_interval_minutes: 10,
+        }
+    }
+}
+
+/// The path of the `n`th newest backup, counted from 1
+pub fn path_for(archive_path: &Path, n: usize) -> PathBuf {
+    archive_path.with_extension(format!("bak.{n}"))
+}
+
+/// The backups that exist, the newest first, with the time they were made
+pub fn list(archive_path: &Path) -> Vec<(PathBuf, SystemTime)> {
+    (1..)
+        .map(|n| path_for(archive_path, n))
+        .map_while(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            Some((path, modified))
+        })
+        .collect()
+}
+
+/// Copies the archive to the newest backup, after the other backups were moved one number up
+pub(super) fn rotate(archive_path: &Path, settings: &BackupSettings) -> io::Result<()> {
+    if settings.keep == 0 {
+        return Ok(());
+    }
+    let newest = path_for(archive_path, 1);
+    let min_interval = Duration::from_secs(settings.min_interval_minutes * 60);
+    let is_recent = fs::metadata(&newest)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age < min_interval));
+    if is_recent {
+        return Ok(());
+    }
+
+    // the ones beyond `keep` are left from when more were kept
+    let mut n = settings.keep;
+    while path_for(archive_path, n).exists() {
+        fs::remove_file(path_for(archive_path, n))?;
+        n += 1;
+    }
+    for n in (1..settings.keep).rev() {
+        let path = path_for(archive_path, n);
+        if path.exists() {
+            fs::rename(path, path_for(archive_path, n + 1))?;
+        }
+    }
+    fs::copy(archive_path, &newest)?;
+    // some platforms copy the modification time, but the backup's age is when it was made
+    File::options()
+        .write(true)
+        .open(&newest)?
+        .set_modified(SystemTime::now())
+}
+
+/// Replaces the archive with its `n`th newest backup. The current state of the archive is
+/// lost, unless it was backed up.
+pub fn restore(archive_path: &Path, n: usize) -> io::Result<()> {
+    fs::copy(path_for(archive_path, n), archive_path)?;
+    // an interrupted change of the replaced archive doesn't apply to the backup
+    let journal = super::journal::path_for(archive_path);
+    if journal.exists() {
+        fs::remove_file(journal)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn backups_rotate_and_are_limited() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let archive = dir.path().join("save.wwsave");
+        let settings = BackupSettings {
+            keep: 2,
+            min_interval_minutes: 0,
+        };
+        for content in ["first", "second", "third"] {
+            fs::write(&archive, content)?;
+            rotate(&archive, &settings)?;
+        }
+        let backups = list(&archive);
+        assert_eq!(backups.len(), 2);
+        assert_eq!(fs::read_to_string(path_for(&archive, 1))?, "third");
+        assert_eq!(fs::read_to_string(path_for(&archive, 2))?, "second");
+
+        // the newest backup is too recent for another one
+        fs::write(&archive, "fourth")?;
+        let settings = BackupSettings {
+            keep: 1,
+            min_interval_minutes: 10,
+        };
+        rotate(&archive, &settings)?;
+        assert_eq!(fs::read_to_string(path_for(&archive, 1))?, "third");
+
+        restore(&archive, 2)?;
+        assert_eq!(fs::read_to_string(&archive)?, "second");
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! A save as a zip file of plain files, so it can be shared, or inspected with standard
+//! tools. The bundle contains [`GAME_DATA_NAME`], and the images in [`IMAGES_DIR`], named
+//! like [`crate::export::export_images`] names them.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use super::SaveArchive;
+use crate::{error::EngineError, export, game::migration};
+
+pub const GAME_DATA_NAME: &str = "game_data.json";
+pub const IMAGES_DIR: &str = "images/";
+
+impl SaveArchive {
+    /// Writes the game data and every image to a zip file at `path`. Encrypted archives are
+    /// exported decrypted.
+    pub fn export_bundle(&mut self, path: &Path) -> Result<(), EngineError> {
+        let mut zip = ZipWriter::new(File::create(path)?);
+        let json = serde_json::to_vec_pretty(&self.read_game_data()?)
+            .map_err(|e| EngineError::Other(e.into()))?;
+        zip.start_file(GAME_DATA_NAME, SimpleFileOptions::default())
+            .map_err(zip_error)?;
+        zip.write_all(&json)?;
+
+        // the images are compressed already
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        export::write_images(self, |file, bytes| {
+            zip.start_file(format!("{IMAGES_DIR}{file}"), stored)?;
+            zip.write_all(bytes)?;
+            Ok(())
+        })?;
+        zip.finish().map_err(zip_error)?;
+        Ok(())
+    }
+
+    /// Creates an archive at `path` from a bundle that [`SaveArchive::export_bundle`] wrote
+    pub fn import_bundle(bundle: &Path, path: &Path) -> Result<Self, EngineError> {
+        let mut zip = ZipArchive::new(File::open(bundle)?).map_err(zip_error)?;
+        let mut json = String::new();
+        zip.by_name(GAME_DATA_NAME)
+            .map_err(zip_error)?
+            .read_to_string(&mut json)?;
+        let data = migration::game_data_from_json(&json)
+            .map_err(|e| EngineError::archive_corrupt(format!("{e:#}")))?;
+
+        // the images are numbered by their ids, starting at 1
+        let mut images = vec![];
+        for i in 0..zip.len() {
+            let name = zip.name_for_index(i).unwrap_or_default();
+            let number = name
+                .strip_prefix(IMAGES_DIR)
+                .and_then(|file| file.split_once('.'))
+                .and_then(|(stem, _)| stem.parse::<usize>().ok());
+            if let Some(number) = number {
+                images.push((number, i));
+            }
+        }
+        images.sort();
+        let mut archive = Self::create(path)?;
+        for (expected, (number, i)) in (1..).zip(images) {
+            if number != expected {
+                return Err(EngineError::archive_corrupt(format!(
+                    "The bundle has no image {expected}"
+                )));
+            }
+            let mut bytes = vec![];
+            zip.by_index(i)
+                .map_err(zip_error)?
+                .read_to_end(&mut bytes)?;
+            archive.append_image(&bytes)?;
+        }
+        archive.write_game_data(&data)?;
+        Ok(archive)
+    }
+}
+
+fn zip_error(e: zip::result::ZipError) -> EngineError {
+    EngineError::Other(e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::*;
+    use crate::save_archive::tests::make_sample_game_data;
+
+    #[test]
+    fn bundles_contain_the_whole_save() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let original = NamedTempFile::new()?;
+        let mut archive = SaveArchive::create_encrypted(original.path(), "pw")?;
+        let mut data = make_sample_game_data(2);
+        for i in 0..11u8 {
+            archive.append_image(&[i; 3])?;
+        }
+        data.turn_data[1].images[0].id = 10;
+        archive.write_game_data(&data)?;
+
+        let bundle = dir.path().join("save.zip");
+        archive.export_bundle(&bundle)?;
+        let mut zip = ZipArchive::new(File::open(&bundle)?).map_err(zip_error)?;
+        assert!(zip.by_name("images/0011.bin").is_ok());
+        assert!(zip.by_name("images/manifest.json").is_ok());
+
+        let mut imported = SaveArchive::import_bundle(&bundle, &dir.path().join("copy.wwsave"))?;
+        assert!(!imported.is_encrypted());
+        assert_eq!(imported.n_images(), 11);
+        assert_eq!(imported.read_image(10)?, vec![10; 3]);
+        let imported_data = imported.read_game_data()?;
+        assert_eq!(imported_data.turn_data.len(), 2);
+        assert_eq!(imported_data.turn_data[1].images[0].id, 10);
+        Ok(())
+    }
+}
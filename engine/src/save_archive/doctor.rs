@@ -0,0 +1,611 @@
+//! Examines a save for damage, and salvages what is intact of a damaged one. The images are
+//! kept up to the first damaged one, and the game data is clipped to the last turn whose
+//! images are all kept. Branches are kept whole or not at all.
+//!
+//! If the index is unreadable, it's rebuilt by scanning the chunks behind the game data
+//! region for the bytes of images. That only works for unencrypted archives, the chunks of
+//! encrypted ones can't be told apart. Transcripts that are found like that are dropped,
+//! since their turns aren't known.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Cursor, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+};
+
+use flate2::read::ZlibDecoder;
+
+use super::{
+    Cipher, Index, IndexEntry, SaveArchive, SaveHeader, backup::BackupSettings, checksum,
+    header_size, journal, lock, metadata::PlayClock, read_unverified_header, verify,
+};
+use crate::{
+    error::EngineError,
+    game::{Codex, GameData, TurnData},
+    image_model::ImageFormat,
+    thumbnail::STORED_THUMBNAIL_SIZE,
+};
+
+/// What [`examine`] found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Checkup {
+    /// what is damaged, empty if the save is intact
+    pub problems: Vec<String>,
+    /// how many images the index lists, or the scan found
+    pub n_images: usize,
+    /// how many images are intact, counted from the first
+    pub intact_images: usize,
+    /// `None` if the game data can't be read
+    pub turns: Option<usize>,
+    /// how many turns a repair keeps, the ones whose images are all intact
+    pub kept_turns: usize,
+    /// the index was unreadable, and the images were found by scanning the file
+    pub index_rebuilt: bool,
+}
+
+impl Checkup {
+    pub fn is_intact(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks every section of the save at `path`, without changing it. Fails if not even the
+/// header and the game data region can be found. The password is only needed for encrypted
+/// saves.
+pub fn examine(path: &Path, password: Option<&str>) -> Result<Checkup, EngineError> {
+    Ok(salvage(path, password)?.checkup)
+}
+
+/// Replaces a damaged save with what is intact of it. The damaged save is kept as
+/// `<name>.damaged`. Intact saves aren't touched.
+pub fn repair(path: &Path, password: Option<&str>) -> Result<Checkup, EngineError> {
+    // an interrupted change is completed first, like opening the save would
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    lock(&file, path)?;
+    journal::recover(&mut file, &journal::path_for(path))?;
+
+    let Salvage {
+        mut archive,
+        data,
+        checkup,
+    } = salvage(path, password)?;
+    if checkup.is_intact() {
+        return Ok(checkup);
+    }
+    let mut data = data.ok_or_else(|| {
+        EngineError::archive_corrupt("The game data is unreadable, only a backup can restore it")
+    })?;
+    clip(&mut data, checkup.kept_turns, checkup.intact_images);
+
+    let repaired_path = path.with_extension("repaired");
+    let mut repaired = match password {
+        Some(password) if archive.is_encrypted() => {
+            SaveArchive::create_encrypted(&repaired_path, password)?
+        }
+        _ => SaveArchive::create(&repaired_path)?,
+    };
+    for id in 0..archive.n_images() {
+        repaired.append_image(&archive.read_image(id)?)?;
+    }
+    let turns: Vec<_> = archive.transcript_turns().collect();
+    for turn in turns.into_iter().filter(|&t| t < checkup.kept_turns) {
+        if let Some(transcript) = archive.read_transcript(turn)? {
+            repaired.append_transcript(turn, &transcript)?;
+        }
+    }
+    // the kept branches were forked from kept turns, so all of their transcripts are theirs
+    for (branch, by_turn) in archive.branch_transcripts.clone() {
+        if !data.branches.iter().any(|b| b.name == branch) {
+            continue;
+        }
+        for (turn, entry) in by_turn {
+            let what = format!("the transcript of turn {} of branch {branch:?}", turn + 1);
+            let transcript = archive.read_transcript_entry(entry, &what)?;
+            repaired.write_branch_transcript(&branch, turn, &transcript)?;
+        }
+    }
+    // keeps the playtime
+    repaired.metadata = archive.read_stored_metadata().ok().flatten();
+    repaired.write_game_data(&data)?;
+
+    drop((repaired, archive, file));
+    fs::rename(path, path.with_extension("damaged"))?;
+    fs::rename(&repaired_path, path)?;
+    Ok(checkup)
+}
+
+/// An archive with the intact images and transcripts, and what was found
+struct Salvage {
+    archive: SaveArchive,
+    data: Option<GameData>,
+    checkup: Checkup,
+}
+
+fn salvage(path: &Path, password: Option<&str>) -> Result<Salvage, EngineError> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut problems = vec![];
+    if len < header_size(1) as u64 {
+        return Err(EngineError::archive_corrupt(
+            "The file is too short to be a save",
+        ));
+    }
+    let header = read_unverified_header(&mut file)?;
+    if let Err(e) = header.verify_checksum() {
+        problems.push(e.to_string());
+    }
+    let region_end = header.game_data_region_offset + header.game_data_region_size;
+    if header.game_data_region_offset < header_size(header.version) as u64
+        || region_end > len
+        || header.game_data_size > header.game_data_region_size
+    {
+        return Err(EngineError::archive_corrupt(
+            "The header is damaged, the game data can't be found",
+        ));
+    }
+    let cipher = Cipher::for_header(&header, password)?;
+
+    let index_end = header.index_offset + header.index_size;
+    let index_is_inside = header.index_offset >= region_end && index_end <= len;
+    let chunks_end = if index_is_inside {
+        header.index_offset
+    } else {
+        problems.push("The index is outside of the file".into());
+        len
+    };
+    let index = if index_is_inside {
+        read_index(&mut file, &header)
+            .inspect_err(|e| problems.push(e.to_string()))
+            .ok()
+    } else {
+        None
+    };
+    let index_rebuilt = index.is_none();
+    let index = match index {
+        Some(index) => index,
+        None if cipher.is_some() => {
+            problems.push("The images of encrypted saves can't be found without the index".into());
+            Index {
+                images: vec![],
+                transcripts: BTreeMap::new(),
+                thumbnails: BTreeMap::new(),
+                branch_transcripts: BTreeMap::new(),
+            }
+        }
+        None => {
+            let mut chunks = vec![0; (chunks_end - region_end) as usize];
+            file.seek(SeekFrom::Start(region_end))?;
+            file.read_exact(&mut chunks)?;
+            scan(&chunks, region_end, header.version)
+        }
+    };
+
+    let mut archive = SaveArchive {
+        file,
+        path: path.to_path_buf(),
+        journal: journal::path_for(path),
+        backups: BackupSettings::default(),
+        header,
+        image_index: index.images,
+        transcripts: index.transcripts,
+        thumbnails: index.thumbnails,
+        branch_transcripts: index.branch_transcripts,
+        cipher,
+        metadata: None,
+        clock: PlayClock::start(),
+        read_only: true,
+    };
+    let n_images = archive.n_images();
+    let chunks = region_end..chunks_end;
+    let mut intact_images = 0;
+    while intact_images < n_images {
+        let id = intact_images;
+        let entry = archive.image_index[id];
+        if let Err(e) = check_chunk(&mut archive, entry, &chunks, |a| a.read_image(id)) {
+            problems.push(format!(
+                "Image {id} is damaged, {e}. It and the {} images after it are lost.",
+                n_images - id - 1
+            ));
+            break;
+        }
+        intact_images += 1;
+    }
+    archive.image_index.truncate(intact_images);
+    archive.thumbnails.retain(|&id, _| id < intact_images);
+    for (turn, entry) in archive.transcripts.clone() {
+        if let Err(e) = check_chunk(&mut archive, entry, &chunks, |a| a.read_transcript(turn)) {
+            problems.push(format!(
+                "The transcript of turn {} is damaged, {e}",
+                turn + 1
+            ));
+            archive.transcripts.remove(&turn);
+        }
+    }
+    for (branch, by_turn) in archive.branch_transcripts.clone() {
+        for (turn, entry) in by_turn {
+            let what = format!("the transcript of turn {} of branch {branch:?}", turn + 1);
+            if let Err(e) = check_chunk(&mut archive, entry, &chunks, |a| {
+                a.read_transcript_entry(entry, &what)
+            }) {
+                problems.push(format!(
+                    "The transcript of turn {} of branch {branch:?} is damaged, {e}",
+                    turn + 1
+                ));
+                if let Some(by_turn) = archive.branch_transcripts.get_mut(&branch) {
+                    by_turn.remove(&turn);
+                }
+            }
+        }
+    }
+    if index_is_inside && let Err(e) = archive.read_stored_metadata() {
+        problems.push(format!("The metadata is damaged: {e}"));
+    }
+
+    let data = archive
+        .read_game_data()
+        .inspect_err(|e| problems.push(format!("The game data is unreadable: {e}")))
+        .ok();
+    let turns = data.as_ref().map(|data| data.turn_data.len());
+    let kept_turns = data.as_ref().map_or(0, |data| {
+        data.turn_data
+            .iter()
+            .take_while(|td| shows_intact_images(td, intact_images))
+            .count()
+    });
+    if let Some(turns) = turns
+        && kept_turns < turns
+    {
+        problems.push(format!(
+            "Turns {} to {turns} show lost images, they are lost too",
+            kept_turns + 1
+        ));
+    }
+    for branch in data.iter().flat_map(|data| &data.branches) {
+        // the branches that were forked from lost turns would get a copy of them
+        if branch.forked_after >= kept_turns
+            || !branch
+                .turns
+                .iter()
+                .all(|td| shows_intact_images(td, intact_images))
+        {
+            problems.push(format!(
+                "The branch {:?} shows lost images, it is lost too",
+                branch.name
+            ));
+        }
+    }
+
+    Ok(Salvage {
+        archive,
+        data,
+        checkup: Checkup {
+            problems,
+            n_images,
+            intact_images,
+            turns,
+            kept_turns,
+            index_rebuilt,
+        },
+    })
+}
+
+/// Reads the chunk of `entry` with `read`, after checking that it's where the chunks are
+fn check_chunk<T>(
+    archive: &mut SaveArchive,
+    entry: IndexEntry,
+    chunks: &Range<u64>,
+    read: impl FnOnce(&mut SaveArchive) -> Result<T, EngineError>,
+) -> Result<(), String> {
+    if entry.offset < chunks.start || entry.offset + entry.length > chunks.end {
+        return Err("it's outside of the file".into());
+    }
+    read(archive).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn read_index(file: &mut File, header: &SaveHeader) -> Result<Index, EngineError> {
+    let mut bytes = vec![0; header.index_size as usize];
+    file.seek(SeekFrom::Start(header.index_offset))?;
+    file.read_exact(&mut bytes)?;
+    if header.version >= 6 {
+        verify(
+            &bytes,
+            header.index_checksum,
+            "the image index",
+            header.index_offset,
+        )?;
+    }
+    Index::decode(header.version, &bytes)
+}
+
+fn shows_intact_images(td: &TurnData, n_images: usize) -> bool {
+    td.images
+        .iter()
+        .all(|info| info.id < n_images && info.original_id.is_none_or(|id| id < n_images))
+}
+
+/// Drops the turns from `kept_turns` on, and whatever refers to images from `n_images` on
+fn clip(data: &mut GameData, kept_turns: usize, n_images: usize) {
+    let turns_are_lost = kept_turns < data.turn_data.len();
+    if kept_turns == 0 {
+        // no branch can be kept without the first turn
+        data.turn_data.clear();
+        data.summaries.clear();
+        data.snapshots.clear();
+        data.branches.clear();
+        data.codex = Codex::default();
+        data.recap = None;
+    } else if turns_are_lost {
+        // branches that were forked from the lost turns get a copy of them
+        data.clip_after_turn(kept_turns - 1);
+    }
+    data.branches
+        .retain(|b| b.turns.iter().all(|td| shows_intact_images(td, n_images)));
+    data.image_cache.retain_images_below(n_images);
+    let in_flight_is_lost = data
+        .in_flight_turn
+        .as_ref()
+        .and_then(|turn| turn.image.as_ref())
+        .is_some_and(|info| info.id >= n_images);
+    if in_flight_is_lost || turns_are_lost {
+        data.in_flight_turn = None;
+    }
+}
+
+/// Finds the images in `chunks`, which start at `offset` in the file, by the formats of their
+/// bytes. Scanning stops at the first chunk that isn't an image or a zlib stream.
+fn scan(chunks: &[u8], offset: u64, version: u64) -> Index {
+    let mut index = Index {
+        images: vec![],
+        transcripts: BTreeMap::new(),
+        thumbnails: BTreeMap::new(),
+        branch_transcripts: BTreeMap::new(),
+    };
+    let mut pos = 0;
+    let mut follows_image = false;
+    while pos < chunks.len() {
+        let rest = &chunks[pos..];
+        let (length, content, compressed) = match image_length(rest) {
+            Some(length) => (length, rest[..length].to_vec(), false),
+            None => match inflate(rest) {
+                Some((length, content)) => (length, content, true),
+                None => break,
+            },
+        };
+        let entry = IndexEntry {
+            offset: offset + pos as u64,
+            length: length as u64,
+            format: ImageFormat::detect(&content),
+            compressed,
+            checksum: Some(checksum(&rest[..length])),
+        };
+        pos += length;
+        // transcripts are the only other zlib streams
+        if entry.format.is_none() {
+            follows_image = false;
+            continue;
+        }
+        // since version 8, each image is followed by its thumbnail
+        if version >= 8 && follows_image && is_thumbnail(&content) {
+            index.thumbnails.insert(index.images.len() - 1, entry);
+            follows_image = false;
+            continue;
+        }
+        index.images.push(entry);
+        follows_image = true;
+    }
+    index
+}
+
+fn is_thumbnail(bytes: &[u8]) -> bool {
+    let dimensions = image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    matches!(ImageFormat::detect(bytes), Some(ImageFormat::Jpeg))
+        && dimensions.is_some_and(|(w, h)| w.max(h) <= STORED_THUMBNAIL_SIZE)
+}
+
+/// The length of the image that `bytes` starts with, if it's a JPEG, PNG or WebP
+fn image_length(bytes: &[u8]) -> Option<usize> {
+    match ImageFormat::detect(bytes)? {
+        ImageFormat::Jpeg => jpeg_length(bytes),
+        ImageFormat::Png => png_length(bytes),
+        ImageFormat::WebP => {
+            let size = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+            let length = 8 + size as usize;
+            (length <= bytes.len()).then_some(length)
+        }
+    }
+}
+
+/// Follows the segments up to the end marker
+fn jpeg_length(bytes: &[u8]) -> Option<usize> {
+    let mut pos = 2;
+    loop {
+        if *bytes.get(pos)? != 0xFF {
+            return None;
+        }
+        match *bytes.get(pos + 1)? {
+            // padding
+            0xFF => pos += 1,
+            0xD9 => return Some(pos + 2),
+            // markers without a length
+            0x01 | 0xD0..=0xD7 => pos += 2,
+            marker => {
+                let length = u16::from_be_bytes([*bytes.get(pos + 2)?, *bytes.get(pos + 3)?]);
+                pos += 2 + length as usize;
+                // the compressed data follows the start of scan, in which 0xFF is followed by 0
+                // or a restart marker
+                if marker == 0xDA {
+                    loop {
+                        let ff = pos + bytes.get(pos..)?.iter().position(|&b| b == 0xFF)?;
+                        match *bytes.get(ff + 1)? {
+                            0x00 | 0xD0..=0xD7 => pos = ff + 2,
+                            _ => {
+                                pos = ff;
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Follows the chunks up to the end chunk
+fn png_length(bytes: &[u8]) -> Option<usize> {
+    let mut pos = 8;
+    loop {
+        let length = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?);
+        let kind = bytes.get(pos + 4..pos + 8)?;
+        pos += 12 + length as usize;
+        if kind == b"IEND" {
+            return (pos <= bytes.len()).then_some(pos);
+        }
+    }
+}
+
+/// The length and the content of the zlib stream that `bytes` starts with
+fn inflate(bytes: &[u8]) -> Option<(usize, Vec<u8>)> {
+    let (&cmf, &flg) = (bytes.first()?, bytes.get(1)?);
+    if cmf & 0x0F != 8 || u16::from_be_bytes([cmf, flg]) % 31 != 0 {
+        return None;
+    }
+    let mut decoder = ZlibDecoder::new(bytes);
+    let mut content = vec![];
+    decoder.read_to_end(&mut content).ok()?;
+    Some((decoder.total_in() as usize, content))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{save_archive::tests::make_sample_game_data, thumbnail::tests::jpeg_bytes};
+
+    fn png_bytes() -> Vec<u8> {
+        let img = image::RgbImage::from_pixel(300, 300, image::Rgb([1, 2, 3]));
+        let mut bytes = vec![];
+        img.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    /// A save with a JPEG, a PNG and another JPEG, each shown by one turn
+    fn make_save(path: &Path) -> Result<(), EngineError> {
+        let mut archive = SaveArchive::create(path)?;
+        archive.append_image(&jpeg_bytes(400, 300))?;
+        archive.append_image(&png_bytes())?;
+        archive.append_image(&jpeg_bytes(300, 400))?;
+        archive.append_transcript(0, &[])?;
+        archive.write_game_data(&make_sample_game_data(3))?;
+        Ok(())
+    }
+
+    fn damage(path: &Path, offset: u64) -> Result<(), EngineError> {
+        let mut bytes = fs::read(path)?;
+        bytes[offset as usize] ^= 0xFF;
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    #[test]
+    fn intact_saves_have_no_problems() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("save.wwsave");
+        make_save(&path)?;
+        let checkup = repair(&path, None)?;
+        assert!(checkup.is_intact(), "{:?}", checkup.problems);
+        assert_eq!(checkup.intact_images, 3);
+        assert_eq!(checkup.kept_turns, 3);
+        assert!(!path.with_extension("damaged").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn saves_are_clipped_before_the_first_damaged_image() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("save.wwsave");
+        make_save(&path)?;
+        let second = SaveArchive::open(&path)?.image_index[1];
+        damage(&path, second.offset + second.length / 2)?;
+
+        let checkup = examine(&path, None)?;
+        assert_eq!(checkup.intact_images, 1);
+        assert_eq!(checkup.turns, Some(3));
+        assert_eq!(checkup.kept_turns, 1);
+        assert_eq!(repair(&path, None)?, checkup);
+
+        let mut repaired = SaveArchive::open(&path)?;
+        assert_eq!(repaired.n_images(), 1);
+        assert_eq!(repaired.read_game_data()?.turn_data.len(), 1);
+        assert!(repaired.read_transcript(0)?.is_some());
+        assert!(path.with_extension("damaged").exists());
+        assert!(examine(&path, None)?.is_intact());
+        Ok(())
+    }
+
+    #[test]
+    fn repairs_keep_intact_branches() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("save.wwsave");
+        make_save(&path)?;
+        let transcript = {
+            let mut archive = SaveArchive::open(&path)?;
+            archive.append_transcript(1, &[])?;
+            archive.fork_after(0, "Other door")?;
+            archive.transcripts[&0]
+        };
+        damage(&path, transcript.offset + transcript.length / 2)?;
+
+        let checkup = repair(&path, None)?;
+        assert_eq!(checkup.problems.len(), 1, "{:?}", checkup.problems);
+        assert_eq!(checkup.kept_turns, 1);
+
+        let mut repaired = SaveArchive::open(&path)?;
+        let data = repaired.read_game_data()?;
+        assert_eq!(data.branches.len(), 1);
+        assert_eq!(data.branches[0].turns.len(), 2);
+        assert!(repaired.read_transcript(0)?.is_none());
+        repaired.switch_branch(0)?;
+        assert!(repaired.read_transcript(1)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_indices_are_rebuilt_by_scanning() -> Result<(), EngineError> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("save.wwsave");
+        make_save(&path)?;
+        let (images, thumbnails, index_offset) = {
+            let mut archive = SaveArchive::open(&path)?;
+            let images = (0..3)
+                .map(|id| archive.read_image(id))
+                .collect::<Result<Vec<_>, _>>()?;
+            (
+                images,
+                archive.thumbnails.len(),
+                archive.header.index_offset,
+            )
+        };
+        assert_eq!(thumbnails, 3);
+        damage(&path, index_offset + 1)?;
+        assert!(SaveArchive::open(&path).is_err());
+
+        let checkup = repair(&path, None)?;
+        assert!(checkup.index_rebuilt);
+        assert_eq!(checkup.n_images, 3);
+        assert_eq!(checkup.kept_turns, 3);
+
+        let mut repaired = SaveArchive::open(&path)?;
+        for (id, image) in images.iter().enumerate() {
+            assert_eq!(&repaired.read_image(id)?, image);
+        }
+        assert_eq!(repaired.read_game_data()?.turn_data.len(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! Changing an archive takes several writes, e.g. an appended image, the index behind it and
+//! the header. If the game crashes in between, the header might point at an index that isn't
+//! there. Therefore the changes are collected in a [`Transaction`] and written to a journal
+//! next to the archive first. Only then is the archive changed, and the journal removed.
+//!
+//! When an archive is opened and its journal exists, the changes were interrupted. If the
+//! journal is complete, they are applied again, otherwise the archive wasn't touched yet and
+//! the journal is discarded. Either way the archive is in the last consistent state.
+
+use std::{
+    fs::{self, File},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::Crc;
+use log::warn;
+
+const MAGIC: &[u8; 8] = b"WWJOURNL";
+const WRITE: u8 = 0;
+const SET_LEN: u8 = 1;
+
+/// Where the journal of the archive at `archive_path` is written
+pub(super) fn path_for(archive_path: &Path) -> PathBuf {
+    let mut name = archive_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".journal");
+    archive_path.with_file_name(name)
+}
+
+/// Changes of an archive file that are applied all or nothing, in the order they were added
+#[derive(Debug, Default)]
+pub(super) struct Transaction {
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+    Write { offset: u64, bytes: Vec<u8> },
+    SetLen(u64),
+}
+
+impl Transaction {
+    pub fn write(&mut self, offset: u64, bytes: Vec<u8>) {
+        self.changes.push(Change::Write { offset, bytes });
+    }
+
+    pub fn set_len(&mut self, len: u64) {
+        self.changes.push(Change::SetLen(len));
+    }
+
+    /// Writes the journal, applies the changes to `file`, and removes the journal again
+    pub fn commit(self, file: &mut File, journal_path: &Path) -> io::Result<()> {
+        let mut journal = File::create(journal_path)?;
+        journal.write_all(&self.encode())?;
+        journal.sync_all()?;
+        sync_parent_dir(journal_path);
+        self.apply(file)?;
+        fs::remove_file(journal_path)
+    }
+
+    /// Every change is to absolute offsets, so applying a transaction again after it was
+    /// applied partially gives the same result
+    fn apply(&self, file: &mut File) -> io::Result<()> {
+        for change in &self.changes {
+            match change {
+                Change::Write { offset, bytes } => {
+                    file.seek(SeekFrom::Start(*offset))?;
+                    file.write_all(bytes)?;
+                }
+                Change::SetLen(len) => file.set_len(*len)?,
+            }
+        }
+        file.sync_data()
+    }
+
+    /// The changes, followed by a checksum, so an incomplete journal is recognized
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for change in &self.changes {
+            match change {
+                Change::Write {
+                    offset,
+                    bytes: data,
+                } => {
+                    bytes.push(WRITE);
+                    bytes.extend(offset.to_le_bytes());
+                    bytes.extend((data.len() as u64).to_le_bytes());
+                    bytes.extend(data);
+                }
+                Change::SetLen(len) => {
+                    bytes.push(SET_LEN);
+                    bytes.extend(len.to_le_bytes());
+                }
+            }
+        }
+        let mut crc = Crc::new();
+        crc.update(&bytes);
+        bytes.extend(crc.sum().to_le_bytes());
+        bytes
+    }
+
+    /// `None` if the journal is incomplete or damaged
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (content, checksum) = bytes.split_last_chunk::<4>()?;
+        let mut crc = Crc::new();
+        crc.update(content);
+        if crc.sum() != u32::from_le_bytes(*checksum) {
+            return None;
+        }
+        let mut rest = content.strip_prefix(MAGIC)?;
+        let mut changes = vec![];
+        while let Some((&kind, tail)) = rest.split_first() {
+            let (number, tail) = tail.split_first_chunk::<8>()?;
+            let number = u64::from_le_bytes(*number);
+            rest = match kind {
+                WRITE => {
+                    let (len, tail) = tail.split_first_chunk::<8>()?;
+                    let (data, tail) =
+                        tail.split_at_checked(usize::try_from(u64::from_le_bytes(*len)).ok()?)?;
+                    changes.push(Change::Write {
+                        offset: number,
+                        bytes: data.to_vec(),
+                    });
+                    tail
+                }
+                SET_LEN => {
+                    changes.push(Change::SetLen(number));
+                    tail
+                }
+                _ => return None,
+            };
+        }
+        Some(Self { changes })
+    }
+}
+
+/// Completes the changes of an interrupted commit, if there are any
+pub(super) fn recover(file: &mut File, journal_path: &Path) -> io::Result<()> {
+    let bytes = match fs::read(journal_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    match Transaction::decode(&bytes) {
+        Some(transaction) => {
+            warn!("Completing the interrupted changes of {journal_path:?}");
+            transaction.apply(file)?;
+        }
+        None => warn!("Discarding the incomplete journal {journal_path:?}"),
+    }
+    fs::remove_file(journal_path)
+}
+
+/// Makes sure the journal is found after a crash. Directories can't be synced on every
+/// platform, so this is done where it's possible.
+fn sync_parent_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn contents(file: &mut File) -> Vec<u8> {
+        let mut bytes = vec![];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn interrupted_commits_are_completed_or_discarded() -> io::Result<()> {
+        let archive = NamedTempFile::new()?;
+        let journal_path = path_for(archive.path());
+        let mut file = archive.reopen()?;
+        file.write_all(b"0123456789")?;
+
+        let mut transaction = Transaction::default();
+        transaction.set_len(4);
+        transaction.write(6, b"ab".to_vec());
+        let journal = transaction.encode();
+        assert_eq!(
+            Transaction::decode(&journal).unwrap().changes,
+            transaction.changes
+        );
+
+        // the crash happened before the journal was complete
+        fs::write(&journal_path, &journal[..journal.len() - 1])?;
+        recover(&mut file, &journal_path)?;
+        assert_eq!(contents(&mut file), b"0123456789");
+        assert!(!journal_path.exists());
+
+        // the crash happened while the changes were applied
+        fs::write(&journal_path, &journal)?;
+        file.set_len(4)?;
+        recover(&mut file, &journal_path)?;
+        assert_eq!(contents(&mut file), b"0123\0\0ab");
+        assert!(!journal_path.exists());
+
+        transaction.commit(&mut file, &journal_path)?;
+        assert_eq!(contents(&mut file), b"0123\0\0ab");
+        assert!(!journal_path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! What lists of saves show, stored behind the image index. It's small, so listing saves
+//! doesn't read, decompress and parse the game data of each.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{game::GameData, world_cover::Cover};
+
+/// The time between two writes counts as played up to this, longer gaps are breaks
+const IDLE_LIMIT: Duration = Duration::from_secs(20 * 60);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaveMetadata {
+    pub world_name: String,
+    pub character: String,
+    pub turns: usize,
+    pub playtime: Duration,
+    pub last_played: SystemTime,
+    /// the id of the latest image, which represents the save
+    pub thumbnail: Option<usize>,
+    pub cover: Option<Cover>,
+    /// see [`GameData::snapshots`]
+    #[serde(default)]
+    pub snapshots: Vec<usize>,
+    /// the title of the latest recap, see [`GameData::recap`]
+    #[serde(default)]
+    pub title: Option<String>,
+    /// the latest "previously on…" recap of the story
+    #[serde(default)]
+    pub recap: Option<String>,
+}
+
+impl SaveMetadata {
+    pub fn of(data: &GameData, playtime: Duration, last_played: SystemTime) -> Self {
+        Self {
+            world_name: data.world_description.name.clone(),
+            character: data.pc.clone(),
+            turns: data.turn_data.len(),
+            playtime,
+            last_played,
+            thumbnail: data
+                .turn_data
+                .iter()
+                .flat_map(|td| &td.images)
+                .last()
+                .map(|info| info.id),
+            cover: data.world_description.cover.clone(),
+            snapshots: data.snapshots.clone(),
+            title: data.recap.as_ref().map(|recap| recap.title.clone()),
+            recap: data.recap.as_ref().map(|recap| recap.text.clone()),
+        }
+    }
+}
+
+/// Measures the playtime of an open archive by the writes of the game data
+#[derive(Debug)]
+pub(super) struct PlayClock {
+    last_write: Instant,
+}
+
+impl PlayClock {
+    pub fn start() -> Self {
+        Self {
+            last_write: Instant::now(),
+        }
+    }
+
+    /// The time that was played since the last write
+    pub fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let played = now.duration_since(self.last_write).min(IDLE_LIMIT);
+        self.last_write = now;
+        played
+    }
+}
@@ -0,0 +1,200 @@
+//! Downscaled versions of stored images.
+//!
+//! Stored images are full resolution JPEGs, PNGs or WebPs. Decoding one every time a turn is displayed is
+//! noticeably slow when browsing a long campaign, so images are decoded once, downscaled,
+//! and kept as raw RGBA pixels in a [`ThumbnailCache`]. Small JPEGs of each image are also
+//! stored in the save, see [`encode_thumbnail`], so views of many small images don't need to
+//! decode the originals.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Cursor,
+};
+
+use color_eyre::{Result, eyre::eyre};
+use image::{DynamicImage, codecs::jpeg::JpegEncoder, imageops::FilterType};
+
+/// The longer side of the thumbnails that are stored in saves
+pub const STORED_THUMBNAIL_SIZE: u32 = 256;
+const STORED_THUMBNAIL_QUALITY: u8 = 80;
+
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    /// 8 bit RGBA, row by row
+    pub rgba: Vec<u8>,
+}
+
+/// Decodes `bytes` and scales the image down so its longer side is at most `max_side`.
+/// Smaller images keep their size.
+pub fn make_thumbnail(bytes: &[u8], max_side: u32) -> Result<Thumbnail> {
+    let img = downscale(bytes, max_side)?;
+    Ok(Thumbnail {
+        width: img.width(),
+        height: img.height(),
+        rgba: img.into_rgba8().into_raw(),
+    })
+}
+
+/// Makes the JPEG of at most [`STORED_THUMBNAIL_SIZE`] pixels that is stored next to an
+/// image in the save
+pub fn encode_thumbnail(bytes: &[u8]) -> Result<Vec<u8>> {
+    // JPEGs have no alpha channel
+    let img = downscale(bytes, STORED_THUMBNAIL_SIZE)?.into_rgb8();
+    let mut jpeg = vec![];
+    img.write_with_encoder(JpegEncoder::new_with_quality(
+        &mut Cursor::new(&mut jpeg),
+        STORED_THUMBNAIL_QUALITY,
+    ))
+    .map_err(|e| eyre!("Failed to encode the thumbnail: {e}"))?;
+    Ok(jpeg)
+}
+
+fn downscale(bytes: &[u8], max_side: u32) -> Result<DynamicImage> {
+    let img = image::load_from_memory(bytes).map_err(|e| eyre!("Failed to decode image: {e}"))?;
+    if img.width() > max_side || img.height() > max_side {
+        Ok(img.resize(max_side, max_side, FilterType::Triangle))
+    } else {
+        Ok(img)
+    }
+}
+
+/// Thumbnails of one save archive, keyed by image id. When the cache is full, the least
+/// recently used thumbnail is dropped.
+#[derive(Debug)]
+pub struct ThumbnailCache {
+    max_side: u32,
+    capacity: usize,
+    thumbnails: HashMap<usize, Thumbnail>,
+    /// image ids, least recently used first
+    usage: VecDeque<usize>,
+}
+
+impl ThumbnailCache {
+    pub fn new(max_side: u32, capacity: usize) -> Self {
+        Self {
+            max_side,
+            capacity,
+            thumbnails: HashMap::new(),
+            usage: VecDeque::new(),
+        }
+    }
+
+    /// Returns the thumbnail for `id`. On a cache miss, the image is obtained from
+    /// `read_image` and downscaled.
+    pub fn get_or_load<E>(
+        &mut self,
+        id: usize,
+        read_image: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<&Thumbnail>
+    where
+        color_eyre::Report: From<E>,
+    {
+        if self.thumbnails.contains_key(&id) {
+            self.touch(id);
+        } else {
+            let thumbnail = make_thumbnail(&read_image()?, self.max_side)?;
+            self.insert(id, thumbnail);
+        }
+        Ok(&self.thumbnails[&id])
+    }
+
+    /// Adds the thumbnail of an image whose bytes are at hand anyway, e.g. a new one
+    pub fn insert_from_bytes(&mut self, id: usize, bytes: &[u8]) -> Result<&Thumbnail> {
+        let thumbnail = make_thumbnail(bytes, self.max_side)?;
+        self.insert(id, thumbnail);
+        Ok(&self.thumbnails[&id])
+    }
+
+    /// Adds a thumbnail that was made elsewhere, e.g. in a background task. It must have
+    /// been made with [`ThumbnailCache::max_side`].
+    pub fn insert_thumbnail(&mut self, id: usize, thumbnail: Thumbnail) {
+        self.insert(id, thumbnail);
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.thumbnails.contains_key(&id)
+    }
+
+    pub fn max_side(&self) -> u32 {
+        self.max_side
+    }
+
+    /// Drops all thumbnails, e.g. because image ids were reused after clipping the archive
+    pub fn clear(&mut self) {
+        self.thumbnails.clear();
+        self.usage.clear();
+    }
+
+    fn insert(&mut self, id: usize, thumbnail: Thumbnail) {
+        if self.thumbnails.insert(id, thumbnail).is_some() {
+            self.touch(id);
+            return;
+        }
+        self.usage.push_back(id);
+        while self.usage.len() > self.capacity {
+            if let Some(evicted) = self.usage.pop_front() {
+                self.thumbnails.remove(&evicted);
+            }
+        }
+    }
+
+    fn touch(&mut self, id: usize) {
+        if let Some(pos) = self.usage.iter().position(|x| *x == id) {
+            self.usage.remove(pos);
+        }
+        self.usage.push_back(id);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn jpeg_bytes(width: u32, height: u32) -> Vec<u8> {
+        let img = image::RgbImage::from_pixel(width, height, image::Rgb([10, 120, 200]));
+        let mut bytes = vec![];
+        img.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Jpeg)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn keeps_the_aspect_ratio() {
+        let thumb = make_thumbnail(&jpeg_bytes(1216, 832), 304).unwrap();
+        assert_eq!((thumb.width, thumb.height), (304, 208));
+        assert_eq!(thumb.rgba.len(), 304 * 208 * 4);
+
+        let small = make_thumbnail(&jpeg_bytes(40, 20), 304).unwrap();
+        assert_eq!((small.width, small.height), (40, 20));
+
+        let stored = encode_thumbnail(&jpeg_bytes(1216, 832)).unwrap();
+        let decoded = image::load_from_memory(&stored).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (256, 175));
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let bytes = jpeg_bytes(16, 16);
+        let mut cache = ThumbnailCache::new(8, 2);
+        let mut loads = 0;
+        let mut get = |cache: &mut ThumbnailCache, id| {
+            cache
+                .get_or_load(id, || {
+                    loads += 1;
+                    color_eyre::eyre::Ok(bytes.clone())
+                })
+                .unwrap();
+        };
+
+        get(&mut cache, 1);
+        get(&mut cache, 2);
+        get(&mut cache, 1);
+        // evicts 2, since 1 was used more recently
+        get(&mut cache, 3);
+        get(&mut cache, 1);
+        get(&mut cache, 2);
+        assert_eq!(loads, 4);
+    }
+}
@@ -0,0 +1,126 @@
+//! Estimates how many tokens a request will take, before it's sent.
+//!
+//! Only some providers publish their tokenizers, and they differ between models anyway, so
+//! the text is counted with OpenAI's `cl100k_base` BPE as a proxy. Modern tokenizers of the
+//! other providers come within a few percent of it for English prose, which is good enough
+//! to keep requests within a budget.
+
+use std::sync::LazyLock;
+
+use log::warn;
+use tiktoken_rs::CoreBPE;
+
+use crate::llm::{Content, ContentBlock, Request};
+
+static BPE: LazyLock<CoreBPE> = LazyLock::new(|| {
+    tiktoken_rs::cl100k_base().expect("the cl100k_base ranks are part of the binary")
+});
+
+/// Opaque data, like redacted thinking, isn't tokenized, it takes about a token per this
+/// many characters
+const CHARS_PER_TOKEN: usize = 4;
+/// The role and the delimiters of each message
+const MESSAGE_OVERHEAD: usize = 4;
+
+/// The number of tokens of `text`, in `cl100k_base`
+pub fn estimate_tokens(text: &str) -> usize {
+    BPE.encode_ordinary(text).len()
+}
+
+/// The estimated size of the prompt of `req`, without the reply
+pub fn estimate_request(req: &Request) -> usize {
+    let system = req.system.as_deref().map_or(0, estimate_tokens);
+    let messages: usize = req
+        .messages
+        .iter()
+        .map(|msg| MESSAGE_OVERHEAD + estimate_content(&msg.content))
+        .sum();
+    let tools: usize = req
+        .tools
+        .iter()
+        .map(|spec| estimate_tokens(&serde_json::to_string(spec).unwrap_or_default()))
+        .sum();
+    system + messages + tools
+}
+
+fn estimate_content(content: &Content) -> usize {
+    match content {
+        Content::Text(text) => estimate_tokens(text),
+        Content::Blocks(blocks) => blocks
+            .iter()
+            .map(|block| match block {
+                ContentBlock::Text { text } => estimate_tokens(text),
+                ContentBlock::ToolUse { name, input, .. } => {
+                    estimate_tokens(name) + estimate_tokens(&input.to_string())
+                }
+                ContentBlock::ToolResult { content, .. } => estimate_tokens(content),
+                ContentBlock::Thinking { thinking, .. } => estimate_tokens(thinking),
+                ContentBlock::RedactedThinking { data } => data.len() / CHARS_PER_TOKEN,
+            })
+            .sum(),
+    }
+}
+
+/// Removes the oldest user/assistant pairs of messages until the prompt of `req` fits into
+/// `budget` tokens. The latest message is always kept, even if the prompt doesn't fit then.
+/// Returns the number of removed pairs.
+pub fn trim_to_budget(req: &mut Request, budget: usize) -> usize {
+    let mut estimate = estimate_request(req);
+    let mut n_removed = 0;
+    while estimate > budget && req.messages.len() > 2 {
+        for msg in req.messages.drain(..2) {
+            estimate -= MESSAGE_OVERHEAD + estimate_content(&msg.content);
+        }
+        n_removed += 1;
+    }
+    if estimate > budget {
+        warn!("The prompt takes about {estimate} tokens, more than the budget of {budget}");
+    }
+    n_removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::InputMessage;
+
+    #[test]
+    fn text_is_counted_with_the_tokenizer() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello world"), 2);
+        assert_eq!(estimate_tokens("You step into the alley."), 6);
+        // rare words take several tokens each
+        let estimate = estimate_tokens("Characterization of the extraordinarily unremarkable.");
+        assert!(estimate > 6, "{estimate}");
+    }
+
+    #[test]
+    fn oldest_turns_are_removed_first() {
+        let turn = |i: usize| {
+            [
+                InputMessage::user(format!("turn {i}: {}", "walk ".repeat(100))),
+                InputMessage::assistant(format!("You walk. {}", "far ".repeat(100))),
+            ]
+        };
+        let mut req = Request {
+            system: Some("Narrate".into()),
+            messages: (0..3)
+                .flat_map(turn)
+                .chain([InputMessage::user("rest".into())])
+                .collect(),
+            max_tokens: 100,
+            sampling: Default::default(),
+            tools: vec![],
+        };
+        let full = estimate_request(&req);
+
+        assert_eq!(trim_to_budget(&mut req, full), 0);
+        assert_eq!(trim_to_budget(&mut req, full - 1), 1);
+        assert_eq!(req.messages.len(), 5);
+        assert!(req.messages[0].content.text().starts_with("turn 1"));
+
+        assert_eq!(trim_to_budget(&mut req, 0), 2);
+        assert_eq!(req.messages.len(), 1);
+        assert_eq!(req.messages[0].content.text(), "rest");
+    }
+}
@@ -0,0 +1,631 @@
+//! Tools the narrating LLM can call while it resolves the player's action.
+//!
+//! Asked whether an action succeeds, models mostly decide in the player's favour, and the
+//! "random" events they invent are the same few every time. With the tools, the outcome of
+//! uncertain actions comes from real dice rolls, and random tables are actually random.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use async_stream::try_stream;
+use color_eyre::{
+    Result,
+    eyre::{bail, ensure, eyre},
+};
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{pin, sync::mpsc};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    LLMBox,
+    llm::{
+        Content, ContentBlock, InputMessage, OutputMessage, Request, ResponseFragment, Role,
+        ToolCall, ToolSpec,
+    },
+};
+
+/// Appended to the system message if the tools are offered
+pub const INSTRUCTIONS: &str = indoc::indoc! {"
+
+    Tools:
+    - When the outcome of an action is uncertain, call roll_dice and narrate what the result
+      implies instead of deciding it yourself. Usually roll 1d20: higher is better, and 10 or
+      more succeeds at an ordinary task. Adjust the difficulty to the situation
+    - Call pick_from_table when something should be picked at random, e.g. the weather, an
+      encounter or loot. List all sensible options as entries
+    - Call the tools before you write your reply, which starts after their results
+"};
+
+/// Appended to the system message if `skill_check` is offered too
+pub const SKILL_CHECK_INSTRUCTIONS: &str = indoc::indoc! {"
+    - If one of the player character's stats applies to an uncertain action, call skill_check
+      with it instead of roll_dice
+"};
+
+/// A model that keeps calling tools after this many rounds is considered stuck
+const MAX_TOOL_ROUNDS: usize = 8;
+
+pub trait Tool: Send + Sync {
+    fn spec(&self) -> ToolSpec;
+    /// Returns the text the model receives as result
+    fn call(&self, input: serde_json::Value) -> Result<String>;
+}
+
+/// The tools that are offered to the model, by name
+pub struct ToolRegistry {
+    tools: BTreeMap<String, Box<dyn Tool>>,
+}
+
+impl Default for ToolRegistry {
+    /// Contains `roll_dice` and `pick_from_table`, with unbiased dice
+    fn default() -> Self {
+        let mut res = Self::empty();
+        res.register(RollDice::default());
+        res.register(PickFromTable);
+        res
+    }
+}
+
+impl ToolRegistry {
+    pub fn empty() -> Self {
+        Self {
+            tools: BTreeMap::new(),
+        }
+    }
+
+    /// Replaces a tool with the same name
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.tools.insert(tool.spec().name, Box::new(tool));
+    }
+
+    /// The default tools, and `skill_check` if the player character has stats. `bias` is
+    /// added to every d20, see [`Difficulty::roll_modifier`](crate::game::Difficulty).
+    pub fn for_stats(stats: &BTreeMap<String, i32>, bias: i64) -> Self {
+        let mut res = Self::default();
+        res.register(RollDice { bias });
+        if !stats.is_empty() {
+            res.register(SkillCheck {
+                stats: stats.clone(),
+                bias,
+            });
+        }
+        res
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.contains_key(name)
+    }
+
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.tools.values().map(|t| t.spec()).collect()
+    }
+
+    /// Runs the tool and returns the block that reports the result to the model. Failures
+    /// are reported to the model too, so it can correct its input.
+    pub fn answer(&self, call: &ToolCall) -> ContentBlock {
+        let res = match self.tools.get(&call.name) {
+            Some(tool) => tool.call(call.input.clone()),
+            None => Err(eyre!("There is no tool named {}", call.name)),
+        };
+        debug!("Tool call {} with {}: {res:?}", call.name, call.input);
+        let (content, is_error) = match res {
+            Ok(content) => (content, false),
+            Err(e) => {
+                warn!("Tool call {} failed: {e}", call.name);
+                (format!("Error: {e}"), true)
+            }
+        };
+        ContentBlock::ToolResult {
+            tool_use_id: call.id.clone(),
+            content,
+            is_error,
+        }
+    }
+}
+
+/// Sends `req`, and answers the tool calls of the model until it completes its reply. The
+/// text of all rounds is passed on as one message, whose token counts are the sums of all
+/// rounds. The results of the successful calls are sent to `tx_results`, so they can be
+/// shown to the player.
+pub fn run_with_tools(
+    mut llm: LLMBox,
+    mut req: Request,
+    tools: Arc<ToolRegistry>,
+    tx_results: mpsc::UnboundedSender<String>,
+) -> impl Stream<Item = Result<ResponseFragment>> + Send + 'static {
+    try_stream! {
+        let mut text = String::new();
+        let mut thinking = vec![];
+        let mut input_tokens = 0;
+        let mut output_tokens = 0;
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let msg = {
+                let stream = llm.send_request_stream(req.clone());
+                pin!(stream);
+                let mut complete = None;
+                while let Some(fragment) = stream.try_next().await? {
+                    match fragment {
+                        ResponseFragment::TextDelta(_) | ResponseFragment::ThinkingDelta(_) => yield fragment,
+                        ResponseFragment::MessageComplete(msg) => {
+                            complete = Some(msg);
+                            break;
+                        }
+                    }
+                }
+                complete.ok_or(eyre!("stream ended before message completion"))?
+            };
+
+            text.push_str(&msg.text);
+            thinking.extend(msg.thinking.iter().cloned());
+            input_tokens += msg.input_tokens;
+            output_tokens += msg.output_tokens;
+            if msg.tool_calls.is_empty() {
+                yield ResponseFragment::MessageComplete(OutputMessage {
+                    input_tokens,
+                    output_tokens,
+                    text,
+                    tool_calls: vec![],
+                    thinking,
+                });
+                return;
+            }
+
+            let results: Vec<_> = msg.tool_calls.iter().map(|call| tools.answer(call)).collect();
+            for result in &results {
+                if let ContentBlock::ToolResult { content, is_error: false, .. } = result {
+                    _ = tx_results.send(content.clone());
+                }
+            }
+            // the thinking has to precede the calls, or the provider rejects them
+            let mut calls = msg.thinking;
+            if !msg.text.is_empty() {
+                calls.push(ContentBlock::Text { text: msg.text });
+            }
+            calls.extend(msg.tool_calls.into_iter().map(|call| ContentBlock::ToolUse {
+                id: call.id,
+                name: call.name,
+                input: call.input,
+            }));
+            req.messages.extend([
+                InputMessage {
+                    role: Role::Assistant,
+                    content: Content::Blocks(calls),
+                },
+                InputMessage {
+                    role: Role::User,
+                    content: Content::Blocks(results),
+                },
+            ]);
+        }
+        Err(eyre!("The LLM was still calling tools after {MAX_TOOL_ROUNDS} rounds"))?;
+    }
+}
+
+/// Rolls dice given in the usual notation, e.g. `2d6+1`
+#[derive(Default)]
+pub struct RollDice {
+    /// added to rolls of a single d20, the ones that decide whether actions succeed
+    pub bias: i64,
+}
+
+#[derive(Deserialize)]
+struct RollDiceInput {
+    dice: String,
+}
+
+impl Tool for RollDice {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "roll_dice".into(),
+            description: "Rolls dice and returns the individual results and their total".into(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "dice": {
+                        "type": "string",
+                        "description": "The dice in common notation, e.g. 1d20, 2d6+1 or 1d100"
+                    },
+                    "reason": {
+                        "type": "string",
+                        "description": "What the roll decides"
+                    }
+                },
+                "required": ["dice"]
+            }),
+        }
+    }
+
+    fn call(&self, input: serde_json::Value) -> Result<String> {
+        let RollDiceInput { dice } = serde_json::from_value(input)?;
+        let roll = DiceRoll::parse(&dice)?;
+        let rolls = (0..roll.count)
+            .map(|_| fastrand::u32(1..=roll.sides))
+            .collect::<Vec<_>>();
+        let bias = if roll.count == 1 && roll.sides == 20 {
+            self.bias
+        } else {
+            0
+        };
+        let total = rolls.iter().map(|r| *r as i64).sum::<i64>() + roll.modifier + bias;
+
+        let mut res = rolls
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" + ");
+        if roll.modifier != 0 {
+            let sign = if roll.modifier < 0 { '-' } else { '+' };
+            res.push_str(&format!(" {sign} {}", roll.modifier.abs()));
+        }
+        res.push_str(&format_bias(bias));
+        Ok(format!("{}: {res} = {total}", dice.trim()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct DiceRoll {
+    count: u32,
+    sides: u32,
+    modifier: i64,
+}
+
+impl DiceRoll {
+    fn parse(src: &str) -> Result<Self> {
+        let src: String = src.chars().filter(|c| !c.is_whitespace()).collect();
+        let src = src.to_lowercase();
+        let Some((count, rest)) = src.split_once('d') else {
+            bail!("Invalid dice: {src}, expected e.g. 2d6+1");
+        };
+        let (sides, modifier) = match rest.find(['+', '-']) {
+            Some(idx) => (&rest[..idx], rest[idx..].trim_start_matches('+')),
+            None => (rest, "0"),
+        };
+
+        let invalid = |_| eyre!("Invalid dice: {src}, expected e.g. 2d6+1");
+        let count = if count.is_empty() {
+            1
+        } else {
+            count.parse().map_err(invalid)?
+        };
+        let res = Self {
+            count,
+            sides: sides.parse().map_err(invalid)?,
+            modifier: modifier.parse().map_err(invalid)?,
+        };
+        ensure!(
+            (1..=100).contains(&res.count),
+            "At most 100 dice can be rolled at once"
+        );
+        ensure!(res.sides >= 2, "Dice need at least 2 sides");
+        Ok(res)
+    }
+}
+
+/// Rolls 1d20 plus the modifier of one of the player character's stats against a difficulty
+pub struct SkillCheck {
+    /// the modifiers by stat name
+    pub stats: BTreeMap<String, i32>,
+    /// added to every check, like [`RollDice::bias`]
+    pub bias: i64,
+}
+
+#[derive(Deserialize)]
+struct SkillCheckInput {
+    stat: String,
+    difficulty: i64,
+}
+
+impl Tool for SkillCheck {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "skill_check".into(),
+            description: "Rolls 1d20 plus the modifier of one of the player character's stats, \
+                          and tells whether it reaches the difficulty"
+                .into(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "stat": {
+                        "type": "string",
+                        "enum": self.stats.keys().collect::<Vec<_>>()
+                    },
+                    "difficulty": {
+                        "type": "integer",
+                        "description": "The total that succeeds, 10 for an ordinary task"
+                    },
+                    "reason": {
+                        "type": "string",
+                        "description": "What the check decides"
+                    }
+                },
+                "required": ["stat", "difficulty"]
+            }),
+        }
+    }
+
+    fn call(&self, input: serde_json::Value) -> Result<String> {
+        let SkillCheckInput { stat, difficulty } = serde_json::from_value(input)?;
+        let Some((stat, modifier)) = self
+            .stats
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(stat.trim()))
+        else {
+            let known = self.stats.keys().cloned().collect::<Vec<_>>().join(", ");
+            bail!("There is no stat {stat}, the stats are: {known}");
+        };
+        let roll = fastrand::i64(1..=20);
+        let total = roll + *modifier as i64 + self.bias;
+        let outcome = if total >= difficulty {
+            "success"
+        } else {
+            "failure"
+        };
+        Ok(format!(
+            "{stat} check against {difficulty}: {roll} {} {}{} = {total}, {outcome}",
+            if *modifier < 0 { '-' } else { '+' },
+            modifier.abs(),
+            format_bias(self.bias)
+        ))
+    }
+}
+
+/// Names the bias of a roll in its result, so the model doesn't mistake it for a wrong sum.
+/// Empty if there is none.
+fn format_bias(bias: i64) -> String {
+    match bias {
+        0 => String::new(),
+        bias if bias < 0 => format!(" - {} for the difficulty", -bias),
+        _ => format!(" + {bias} for the difficulty"),
+    }
+}
+
+/// Picks a random entry of a table that the model provides
+pub struct PickFromTable;
+
+#[derive(Deserialize)]
+struct PickFromTableInput {
+    entries: Vec<String>,
+    #[serde(default)]
+    weights: Option<Vec<f64>>,
+}
+
+impl Tool for PickFromTable {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "pick_from_table".into(),
+            description: "Picks a random entry from a table".into(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "entries": {
+                        "type": "array",
+                        "items": { "type": "string" },
+                        "minItems": 1
+                    },
+                    "weights": {
+                        "type": "array",
+                        "items": { "type": "number" },
+                        "description": "Relative probabilities of the entries. All entries are equally likely if this is left out"
+                    }
+                },
+                "required": ["entries"]
+            }),
+        }
+    }
+
+    fn call(&self, input: serde_json::Value) -> Result<String> {
+        let PickFromTableInput { entries, weights } = serde_json::from_value(input)?;
+        ensure!(!entries.is_empty(), "The table has no entries");
+        let weights = weights.unwrap_or_else(|| vec![1.0; entries.len()]);
+        ensure!(
+            weights.len() == entries.len(),
+            "There must be one weight per entry"
+        );
+        ensure!(
+            weights.iter().all(|w| w.is_finite() && *w >= 0.0),
+            "Weights can't be negative"
+        );
+        let total: f64 = weights.iter().sum();
+        ensure!(total > 0.0, "At least one weight must be positive");
+
+        let mut threshold = fastrand::f64() * total;
+        let idx = weights
+            .iter()
+            .position(|w| {
+                threshold -= w;
+                threshold < 0.0
+            })
+            // rounding might leave a tiny rest
+            .unwrap_or_else(|| weights.iter().rposition(|w| *w > 0.0).unwrap());
+        Ok(format!("Picked: {}", entries[idx]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::{ScriptedLLM, message};
+
+    fn reply(text: &str, tool_calls: Vec<ToolCall>) -> OutputMessage {
+        OutputMessage {
+            tool_calls,
+            ..message(text, 10, 1)
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_results_are_sent_back_until_the_reply_is_complete() {
+        let call = ToolCall {
+            id: "call_1".into(),
+            name: "roll_dice".into(),
+            input: json!({ "dice": "1d20" }),
+        };
+        let thinking = ContentBlock::Thinking {
+            thinking: "The wall is slippery, that needs a roll.".into(),
+            signature: "sig".into(),
+        };
+        let mut first = reply("", vec![call]);
+        first.thinking.push(thinking);
+        let llm = ScriptedLLM::new([first, reply("You climb the wall.", vec![])]);
+        let req = Request {
+            system: None,
+            messages: vec![InputMessage::user("climb the wall".into())],
+            max_tokens: 100,
+            sampling: Default::default(),
+            tools: ToolRegistry::default().specs(),
+        };
+
+        let (tx_results, mut rx_results) = mpsc::unbounded_channel();
+        let stream = run_with_tools(
+            Box::new(Clone::clone(&llm)),
+            req,
+            Arc::default(),
+            tx_results,
+        );
+        pin!(stream);
+        let mut complete = None;
+        while let Some(fragment) = stream.try_next().await.unwrap() {
+            if let ResponseFragment::MessageComplete(msg) = fragment {
+                complete = Some(msg);
+            }
+        }
+        let complete = complete.unwrap();
+        assert_eq!(complete.text, "You climb the wall.");
+        assert_eq!(complete.input_tokens, 20);
+        assert_eq!(complete.thinking.len(), 1);
+        assert!(rx_results.try_recv().unwrap().starts_with("1d20: "));
+
+        let requests = llm.requests();
+        assert_eq!(requests.len(), 2);
+        let [_, assistant, results] = &requests[1].messages[..] else {
+            panic!("expected the tool call and its result to be appended");
+        };
+        assert!(matches!(
+            &assistant.content,
+            Content::Blocks(blocks)
+                if matches!(&blocks[..], [ContentBlock::Thinking { .. }, ContentBlock::ToolUse { .. }])
+        ));
+        assert!(matches!(
+            &results.content,
+            Content::Blocks(blocks)
+                if matches!(&blocks[..], [ContentBlock::ToolResult { is_error: false, .. }])
+        ));
+    }
+
+    #[test]
+    fn parses_dice_notation() {
+        let roll = |src| DiceRoll::parse(src).unwrap();
+        assert_eq!(
+            roll("2d6+1"),
+            DiceRoll {
+                count: 2,
+                sides: 6,
+                modifier: 1
+            }
+        );
+        assert_eq!(
+            roll(" D20 - 2"),
+            DiceRoll {
+                count: 1,
+                sides: 20,
+                modifier: -2
+            }
+        );
+        for invalid in ["", "d", "2d", "2x6", "0d6", "1d1", "1d6+x"] {
+            assert!(DiceRoll::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn rolls_stay_in_range() {
+        fastrand::seed(7);
+        for _ in 0..100 {
+            let res = RollDice::default()
+                .call(json!({ "dice": "3d4-1" }))
+                .unwrap();
+            let total: i64 = res.rsplit(' ').next().unwrap().parse().unwrap();
+            assert!((2..=11).contains(&total), "{res}");
+        }
+    }
+
+    #[test]
+    fn skill_checks_add_the_modifier() {
+        fastrand::seed(7);
+        let check = SkillCheck {
+            stats: BTreeMap::from([("Stealth".into(), -2)]),
+            bias: 0,
+        };
+        for _ in 0..100 {
+            let res = check
+                .call(json!({ "stat": "stealth", "difficulty": 10 }))
+                .unwrap();
+            assert!(res.starts_with("Stealth check against 10: "), "{res}");
+            let (sum, outcome) = res.rsplit_once(" = ").unwrap().1.split_once(", ").unwrap();
+            let total: i64 = sum.parse().unwrap();
+            assert!((-1..=18).contains(&total), "{res}");
+            assert_eq!(outcome, if total >= 10 { "success" } else { "failure" });
+        }
+        let unknown = json!({ "stat": "Magic", "difficulty": 10 });
+        assert!(check.call(unknown).is_err());
+    }
+
+    #[test]
+    fn the_difficulty_biases_d20_rolls() {
+        fastrand::seed(7);
+        let dice = RollDice { bias: -2 };
+        for _ in 0..100 {
+            let res = dice.call(json!({ "dice": "1d20" })).unwrap();
+            assert!(res.contains(" - 2 for the difficulty = "), "{res}");
+            let total: i64 = res.rsplit(' ').next().unwrap().parse().unwrap();
+            assert!((-1..=18).contains(&total), "{res}");
+
+            let res = dice.call(json!({ "dice": "2d6" })).unwrap();
+            assert!(!res.contains("difficulty"), "{res}");
+        }
+
+        let check = SkillCheck {
+            stats: BTreeMap::from([("Stealth".into(), 1)]),
+            bias: 2,
+        };
+        let res = check
+            .call(json!({ "stat": "Stealth", "difficulty": 30 }))
+            .unwrap();
+        assert!(res.contains(" + 1 + 2 for the difficulty = "), "{res}");
+    }
+
+    #[test]
+    fn entries_without_weight_are_never_picked() {
+        fastrand::seed(7);
+        for _ in 0..100 {
+            let res = PickFromTable
+                .call(json!({ "entries": ["rain", "sun", "fog"], "weights": [1, 0, 2] }))
+                .unwrap();
+            assert_ne!(res, "Picked: sun");
+        }
+        assert!(PickFromTable.call(json!({ "entries": [] })).is_err());
+    }
+
+    #[test]
+    fn unknown_tools_are_reported_to_the_model() {
+        let call = ToolCall {
+            id: "call_1".into(),
+            name: "summon_dragon".into(),
+            input: json!({}),
+        };
+        let ContentBlock::ToolResult {
+            tool_use_id,
+            is_error,
+            ..
+        } = ToolRegistry::default().answer(&call)
+        else {
+            panic!("expected a tool result");
+        };
+        assert_eq!(tool_use_id, "call_1");
+        assert!(is_error);
+    }
+}
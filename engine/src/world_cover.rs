@@ -0,0 +1,123 @@
+//! The cover image of a world, shown where worlds and saves are listed. It's part of the
+//! world description, and thereby of world files and saves, so it's stored small.
+
+use std::fmt;
+
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use color_eyre::{Result, eyre::eyre};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    ImgModBox,
+    game::WorldDescription,
+    image_model::{ImageFormat, ModelStyle, ProgressReporter, StorageSettings},
+};
+
+const STORAGE: StorageSettings = StorageSettings {
+    max_dimension: 512,
+    format: Some(ImageFormat::Jpeg),
+    jpeg_quality: 80,
+    store_originals: false,
+};
+
+/// A JPEG, serialized base64 encoded
+#[derive(Clone, PartialEq, Eq)]
+pub struct Cover(Vec<u8>);
+
+impl Cover {
+    /// Downscales and re-encodes any image the image models or the player provide
+    pub fn from_image(bytes: &[u8]) -> Result<Self> {
+        Ok(Self(STORAGE.prepare(bytes)?.bytes))
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// A markdown image with a data url, so world files show their cover in markdown viewers
+    pub fn to_markdown(&self) -> String {
+        format!(
+            "![Cover](data:image/jpeg;base64,{})",
+            BASE64_STANDARD.encode(&self.0)
+        )
+    }
+
+    pub fn from_markdown(src: &str) -> Result<Self> {
+        let data = src
+            .trim()
+            .strip_prefix("![Cover](")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .and_then(|url| url.split_once(";base64,"))
+            .map(|(_, data)| data)
+            .ok_or(eyre!("The cover is no markdown image with a data url"))?;
+        Ok(Self(BASE64_STANDARD.decode(data)?))
+    }
+}
+
+impl fmt::Debug for Cover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cover({} bytes)", self.0.len())
+    }
+}
+
+impl Serialize for Cover {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Cover {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64_STANDARD
+            .decode(encoded)
+            .map(Self)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// What the image model is asked for
+pub fn cover_prompt(world: &WorldDescription) -> String {
+    format!(
+        "Cover art for a story set in the world \"{}\". No text or lettering. The world: {}",
+        world.name.trim(),
+        world.main_description.trim()
+    )
+}
+
+/// Asks `model` for a cover of the world, in the given style
+pub async fn generate(
+    world: &WorldDescription,
+    model: ImgModBox,
+    style: Option<ModelStyle>,
+) -> Result<Cover> {
+    let prompt = crate::game::styled(&cover_prompt(world), style.as_ref());
+    let image = model
+        .get_image(&prompt, None, ProgressReporter::default())
+        .await?;
+    Cover::from_image(&image.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn covers_are_small_jpegs_that_survive_markdown_and_ron() {
+        let mut png = vec![];
+        image::RgbImage::new(1024, 768)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let cover = Cover::from_image(&png).unwrap();
+        assert_eq!(ImageFormat::detect(cover.bytes()), Some(ImageFormat::Jpeg));
+        let img = image::load_from_memory(cover.bytes()).unwrap();
+        assert_eq!((img.width(), img.height()), (512, 384));
+
+        assert_eq!(Cover::from_markdown(&cover.to_markdown()).unwrap(), cover);
+        let ron = ron::to_string(&cover).unwrap();
+        assert_eq!(ron::from_str::<Cover>(&ron).unwrap(), cover);
+        assert!(Cover::from_markdown("no image").is_err());
+    }
+}
@@ -2,10 +2,16 @@
 
 use std::{collections::BTreeMap, fmt::Write};
 
-use color_eyre::Result;
+use color_eyre::{Result, eyre::eyre};
 use log::warn;
+use serde::de::DeserializeOwned;
+use strum::IntoEnumIterator;
 
-use crate::game::{PcDescription, WorldDescription};
+use crate::{
+    game::{DetailKind, PcDescription, Tone, WorldDescription, WorldDetail, tone::Knob},
+    llm::Sampling,
+    world_cover::Cover,
+};
 
 const WORLD_MARKDOWN_FORMAT_VERSION: u32 = 1;
 
@@ -19,18 +25,119 @@ pub fn world_to_markdown(world: &WorldDescription) -> String {
     writeln!(out, "\n# Initial Action\n").unwrap();
     write_block_field(&mut out, "world.initial_action", &world.init_action);
 
+    if !world.sampling.is_default() {
+        writeln!(out, "\n# Sampling\n").unwrap();
+        let Sampling {
+            temperature,
+            top_p,
+            stop_sequences,
+        } = &world.sampling;
+        if let Some(temperature) = temperature {
+            write_inline_field(&mut out, "world.temperature", temperature);
+        }
+        if let Some(top_p) = top_p {
+            write_inline_field(&mut out, "world.top_p", top_p);
+        }
+        if !stop_sequences.is_empty() {
+            write_block_field(&mut out, "world.stop_sequences", &stop_sequences.join("\n"));
+        }
+    }
+
+    if let Some(cover) = &world.cover {
+        writeln!(out, "\n# Cover\n").unwrap();
+        write_block_field(&mut out, "world.cover", &cover.to_markdown());
+    }
+
+    if world.target_output_words.is_some() || world.max_tokens.is_some() {
+        writeln!(out, "\n# Output Length\n").unwrap();
+        if let Some(words) = world.target_output_words {
+            write_inline_field(&mut out, "world.target_output_words", words);
+        }
+        if let Some(max_tokens) = world.max_tokens {
+            write_inline_field(&mut out, "world.max_tokens", max_tokens);
+        }
+    }
+
+    if world.preferred_llm.is_some() || world.preferred_image_model.is_some() || world.text_only
+    {
+        writeln!(out, "\n# Models\n").unwrap();
+        // written like in the config, the names that are shown in the GUI aren't unique
+        if let Some(model) = world.preferred_llm {
+            write_inline_field(&mut out, "world.preferred_llm", format!("{model:?}"));
+        }
+        if let Some(model) = world.preferred_image_model {
+            write_inline_field(&mut out, "world.preferred_image_model", format!("{model:?}"));
+        }
+        if world.text_only {
+            write_inline_field(&mut out, "world.text_only", true);
+        }
+    }
+
+    if !world.tone.is_neutral() {
+        writeln!(out, "\n# Tone\n").unwrap();
+        for knob in Knob::iter() {
+            let key = format!("world.tone.{}", knob.to_string().to_lowercase());
+            write_inline_field(&mut out, &key, world.tone.get(knob));
+        }
+    }
+
+    if let Some(rating) = world.content_rating {
+        writeln!(out, "\n# Content Rating\n").unwrap();
+        write_inline_field(&mut out, "world.content_rating", format!("{rating:?}"));
+    }
+
+    if let Some(template) = &world.system_prompt_template {
+        writeln!(out, "\n# System Prompt\n").unwrap();
+        write_block_field(&mut out, "world.system_prompt_template", template);
+    }
+
+    if let Some(prompt) = &world.summary_prompt {
+        writeln!(out, "\n# Summary Prompt\n").unwrap();
+        write_block_field(&mut out, "world.summary_prompt", prompt);
+    }
+
+    if let Some(turns) = world.history_turns {
+        writeln!(out, "\n# History\n").unwrap();
+        write_inline_field(&mut out, "world.history_turns", turns);
+    }
+
+    if let Some(script) = &world.script {
+        writeln!(out, "\n# Script\n").unwrap();
+        write_block_field(&mut out, "world.script", script);
+    }
+
+    for kind in DetailKind::iter() {
+        let details = world.details(kind);
+        if details.is_empty() {
+            continue;
+        }
+        writeln!(out, "\n# {kind}").unwrap();
+        let key = kind.key();
+        for detail in details {
+            writeln!(out, "\n## {}", detail.name).unwrap();
+            write_heading_field(&mut out, &format!("{key}.name"));
+            write_block_start(&mut out, &key.to_uppercase());
+            write_block_field(&mut out, &format!("{key}.description"), &detail.description);
+            write_block_end(&mut out, &key.to_uppercase());
+        }
+    }
+
     if !world.pc_descriptions.is_empty() {
         writeln!(out, "\n# Characters").unwrap();
 
         for (name, character) in &world.pc_descriptions {
             writeln!(out, "\n## {name}").unwrap();
             write_heading_field(&mut out, "character.name");
-            write_character_start(&mut out);
+            write_block_start(&mut out, "CHARACTER");
             writeln!(out, "\n### Description\n").unwrap();
             write_block_field(&mut out, "character.description", &character.description);
             writeln!(out, "\n### Initial Action\n").unwrap();
             write_block_field(&mut out, "character.initial_action", &character.initial_action);
-            write_character_end(&mut out);
+            if !character.stats.is_empty() {
+                writeln!(out, "\n### Stats\n").unwrap();
+                write_block_field(&mut out, "character.stats", &character.stats_text());
+            }
+            write_block_end(&mut out, "CHARACTER");
         }
     }
 
@@ -41,48 +148,135 @@ pub fn world_from_markdown(src: &str) -> Result<WorldDescription> {
     let name = first_heading_field(src, "world.name", 1);
     let main_description = first_field(src, "world.description");
     let init_action = first_field(src, "world.initial_action");
+    let system_prompt_template = Some(first_field(src, "world.system_prompt_template"))
+        .filter(|t| !t.trim().is_empty());
+    let summary_prompt =
+        Some(first_field(src, "world.summary_prompt")).filter(|p| !p.trim().is_empty());
+    let script = Some(first_field(src, "world.script")).filter(|s| !s.trim().is_empty());
 
     let mut pc_descriptions = BTreeMap::new();
 
-    for section in collect_character_blocks(src) {
+    for section in collect_blocks(src, "CHARACTER") {
         let character_name = first_heading_field(section, "character.name", 2);
         if !character_name.is_empty() {
             let description = first_field(section, "character.description");
             let initial_action = first_field(section, "character.initial_action");
+            let stats = PcDescription::parse_stats(&first_field(section, "character.stats"))?;
             pc_descriptions.insert(
                 character_name,
                 PcDescription {
                     description,
                     initial_action,
+                    stats,
                 },
             );
         }
     }
 
+    let sampling = Sampling {
+        temperature: parse_optional_field(src, "world.temperature")?,
+        top_p: parse_optional_field(src, "world.top_p")?,
+        stop_sequences: first_field(src, "world.stop_sequences")
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect(),
+    };
+
     Ok(WorldDescription {
         name,
         main_description,
         pc_descriptions,
         init_action,
+        sampling,
+        target_output_words: parse_optional_field(src, "world.target_output_words")?,
+        max_tokens: parse_optional_field(src, "world.max_tokens")?,
+        system_prompt_template,
+        summary_prompt,
+        history_turns: parse_optional_field(src, "world.history_turns")?,
+        preferred_llm: parse_optional_enum_field(src, "world.preferred_llm")?,
+        preferred_image_model: parse_optional_enum_field(src, "world.preferred_image_model")?,
+        text_only: parse_optional_field(src, "world.text_only")?.unwrap_or(false),
+        content_rating: parse_optional_enum_field(src, "world.content_rating")?,
+        tone: parse_tone(src)?,
+        locations: parse_details(src, DetailKind::Locations),
+        factions: parse_details(src, DetailKind::Factions),
+        npcs: parse_details(src, DetailKind::Npcs),
+        script,
+        cover: Some(first_field(src, "world.cover"))
+            .filter(|c| !c.trim().is_empty())
+            .map(|c| Cover::from_markdown(&c))
+            .transpose()?,
     })
 }
 
+/// Knobs without a field are neutral
+fn parse_tone(src: &str) -> Result<Tone> {
+    let mut tone = Tone::default();
+    for knob in Knob::iter() {
+        let key = format!("world.tone.{}", knob.to_string().to_lowercase());
+        if let Some(level) = parse_optional_field(src, &key)? {
+            tone.set(knob, level);
+        }
+    }
+    Ok(tone)
+}
+
+/// Details without a name are skipped
+fn parse_details(src: &str, kind: DetailKind) -> Vec<WorldDetail> {
+    let key = kind.key();
+    collect_blocks(src, &key.to_uppercase())
+        .into_iter()
+        .map(|block| WorldDetail {
+            name: first_heading_field(block, &format!("{key}.name"), 2),
+            description: first_field(block, &format!("{key}.description")),
+        })
+        .filter(|detail| !detail.name.is_empty())
+        .collect()
+}
+
+fn parse_optional_field<T: std::str::FromStr>(src: &str, key: &str) -> Result<Option<T>> {
+    let value = first_field(src, key);
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    value
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|_| eyre!("Invalid value for {key}: {value}"))
+}
+
+fn parse_optional_enum_field<T: DeserializeOwned>(src: &str, key: &str) -> Result<Option<T>> {
+    let value = first_field(src, key);
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    ron::from_str(value.trim())
+        .map(Some)
+        .map_err(|_| eyre!("Invalid value for {key}: {value}"))
+}
+
 fn write_heading_field(out: &mut String, key: &str) {
     writeln!(out, "<!-- WW:HEADING {key} -->").unwrap();
 }
 
+fn write_inline_field(out: &mut String, key: &str, value: impl std::fmt::Display) {
+    writeln!(out, "<!-- WW:FIELD {key} = {value} -->").unwrap();
+}
+
 fn write_block_field(out: &mut String, key: &str, value: &str) {
     writeln!(out, "<!-- WW:FIELD {key} -->").unwrap();
     writeln!(out, "{value}").unwrap();
     writeln!(out, "<!-- /WW:FIELD {key} -->").unwrap();
 }
 
-fn write_character_start(out: &mut String) {
-    writeln!(out, "<!-- WW:CHARACTER -->").unwrap();
+fn write_block_start(out: &mut String, tag: &str) {
+    writeln!(out, "<!-- WW:{tag} -->").unwrap();
 }
 
-fn write_character_end(out: &mut String) {
-    writeln!(out, "<!-- /WW:CHARACTER -->").unwrap();
+fn write_block_end(out: &mut String, tag: &str) {
+    writeln!(out, "<!-- /WW:{tag} -->").unwrap();
 }
 
 fn collect_fields(src: &str, key: &str) -> Vec<String> {
@@ -167,21 +361,21 @@ fn first_heading_field(src: &str, key: &str, level: usize) -> String {
     }
 }
 
-fn collect_character_blocks(src: &str) -> Vec<&str> {
-    let start_marker = "<!-- WW:CHARACTER -->";
-    let end_marker = "<!-- /WW:CHARACTER -->";
+fn collect_blocks<'a>(src: &'a str, tag: &str) -> Vec<&'a str> {
+    let start_marker = format!("<!-- WW:{tag} -->");
+    let end_marker = format!("<!-- /WW:{tag} -->");
 
     let mut blocks = Vec::new();
     let mut cursor = src;
 
     loop {
-        let Some(start_idx) = cursor.find(start_marker) else {
+        let Some(start_idx) = cursor.find(&start_marker) else {
             return blocks;
         };
         let after_start = &cursor[start_idx + start_marker.len()..];
 
         if let Some(content) = after_start.strip_prefix('\n') {
-            if let Some(end_idx) = content.find(end_marker) {
+            if let Some(end_idx) = content.find(&end_marker) {
                 blocks.push(&cursor[..start_idx + start_marker.len() + 1 + end_idx]);
                 cursor = &content[end_idx + end_marker.len()..];
             } else {
@@ -227,9 +421,15 @@ fn parse_inline_field_header(header: &str, key: &str) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::game::{ContentRating, tone};
 
     #[test]
     fn world_markdown_roundtrip() {
+        let mut png = vec![];
+        image::RgbImage::new(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let cover = Cover::from_image(&png).unwrap();
         let world = WorldDescription {
             name: "Cyber Runner".into(),
             main_description: "Intro\n# heading inside content\n## another one".into(),
@@ -239,6 +439,7 @@ mod tests {
                     PcDescription {
                         description: "desc\n# inner heading".into(),
                         initial_action: "go".into(),
+                        stats: BTreeMap::from([("Hacking".into(), 3), ("Charm".into(), -1)]),
                     },
                 ),
                 (
@@ -246,10 +447,47 @@ mod tests {
                     PcDescription {
                         description: "other desc".into(),
                         initial_action: "wait\n".into(),
+                        stats: BTreeMap::new(),
                     },
                 ),
             ]),
             init_action: "start\nwith newline".into(),
+            sampling: Sampling {
+                temperature: Some(1.2),
+                top_p: None,
+                stop_sequences: vec!["THE END".into(), "###".into()],
+            },
+            target_output_words: Some(400),
+            max_tokens: None,
+            system_prompt_template: Some("You narrate for {player}.\n# Not a heading".into()),
+            summary_prompt: Some("Summarize it.".into()),
+            history_turns: Some(4),
+            preferred_llm: Some("ClaudeHaiku".parse().unwrap()),
+            preferred_image_model: None,
+            text_only: true,
+            content_rating: Some(ContentRating::Teen),
+            tone: Tone {
+                humor: 0,
+                pacing: tone::MAX_LEVEL,
+                ..Default::default()
+            },
+            locations: vec![WorldDetail {
+                name: "The Sprawl".into(),
+                description: "endless\n## towers".into(),
+            }],
+            factions: vec![],
+            npcs: vec![
+                WorldDetail {
+                    name: "Mira".into(),
+                    description: "a fixer".into(),
+                },
+                WorldDetail {
+                    name: "Vex".into(),
+                    description: "a rival runner".into(),
+                },
+            ],
+            script: Some("fn on_turn_complete() {\n    this.state.turns += 1;\n}".into()),
+            cover: Some(cover),
         };
 
         let markdown = world_to_markdown(&world);
@@ -259,11 +497,28 @@ mod tests {
         assert_eq!(parsed.main_description, world.main_description);
         assert_eq!(parsed.init_action, world.init_action);
         assert_eq!(parsed.pc_descriptions.len(), world.pc_descriptions.len());
+        assert_eq!(parsed.sampling, world.sampling);
+        assert_eq!(parsed.target_output_words, Some(400));
+        assert_eq!(parsed.max_tokens, None);
+        assert_eq!(parsed.system_prompt_template, world.system_prompt_template);
+        assert_eq!(parsed.summary_prompt, world.summary_prompt);
+        assert_eq!(parsed.history_turns, Some(4));
+        assert_eq!(parsed.preferred_llm, world.preferred_llm);
+        assert_eq!(parsed.preferred_image_model, None);
+        assert!(parsed.text_only);
+        assert_eq!(parsed.content_rating, Some(ContentRating::Teen));
+        assert_eq!(parsed.tone, world.tone);
+        assert_eq!(parsed.script, world.script);
+        assert_eq!(parsed.locations, world.locations);
+        assert!(parsed.factions.is_empty());
+        assert_eq!(parsed.npcs, world.npcs);
+        assert_eq!(parsed.cover, world.cover);
 
         for (name, expected) in &world.pc_descriptions {
             let actual = parsed.pc_descriptions.get(name).unwrap();
             assert_eq!(actual.description, expected.description);
             assert_eq!(actual.initial_action, expected.initial_action);
+            assert_eq!(actual.stats, expected.stats);
         }
     }
 
@@ -277,9 +532,26 @@ mod tests {
                 PcDescription {
                     description: "desc".into(),
                     initial_action: "Start".into(),
+                    stats: BTreeMap::new(),
                 },
             )]),
             init_action: "Start".into(),
+            sampling: Sampling::default(),
+            target_output_words: None,
+            max_tokens: None,
+            system_prompt_template: None,
+            summary_prompt: None,
+            history_turns: None,
+            preferred_llm: None,
+            preferred_image_model: None,
+            text_only: false,
+            content_rating: None,
+            tone: Default::default(),
+            locations: vec![],
+            factions: vec![],
+            npcs: vec![],
+            script: None,
+            cover: None,
         };
 
         let markdown = world_to_markdown(&world);
@@ -291,6 +563,9 @@ mod tests {
         assert!(markdown.contains("<!-- WW:HEADING character.name -->"));
         assert!(markdown.contains("<!-- WW:FIELD world.initial_action -->"));
         assert!(markdown.contains("<!-- WW:FIELD world.description -->"));
+        assert!(!markdown.contains("# Sampling"));
+        assert!(!markdown.contains("# Output Length"));
+        assert!(!markdown.contains("# System Prompt"));
     }
 
     #[test]
@@ -1,4 +1,9 @@
-use std::{collections::BTreeMap, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use color_eyre::{
     Result,
@@ -6,17 +11,25 @@ use color_eyre::{
 };
 use engine::{
     ImgModBox, LLMBox,
-    game::Game,
-    image_model::{self, Model, ModelStyle},
+    audit_log::AuditLog,
+    game::{DEFAULT_HISTORY_TURNS, Game, WorldDescription, summary},
+    image_model::{
+        self, Model, ModelStyle, StorageSettings,
+        queue::{self, JobStatus},
+        replicate::catalog,
+    },
     llm::{self},
-    save_archive::SaveArchive,
+    rate_limit::{self, LimitKey, RateLimits},
+    replay::Recorder,
+    save_archive::{BackupSettings, SaveArchive},
 };
 use iced::Task;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
 
 use crate::{
-    load_active_game_save_path,
+    load_active_game_save_path, remember_save, save_active_game_save_path, saves_dir,
     context::game_context::GameContext,
     message::{ContextMessage, Message},
 };
@@ -26,11 +39,21 @@ pub mod game_context;
 pub struct Context {
     pub game: Option<game_context::GameContext>,
     pub config: Config,
+    /// whether the user was already told that the game runs without images
+    pub text_only_warning_shown: bool,
+    /// the images that are being made or wait for their turn, of any game
+    pub image_jobs: Vec<JobStatus>,
 }
 
 impl Context {
     pub fn from_config(config: Config) -> Self {
-        Self { game: None, config }
+        config.apply_rate_limits();
+        Self {
+            game: None,
+            config,
+            text_only_warning_shown: false,
+            image_jobs: vec![],
+        }
     }
 
     pub fn update(&mut self, message: ContextMessage) -> Result<Task<Message>> {
@@ -48,23 +71,136 @@ impl Context {
             save_path.exists(),
             "No game running. Please start a new one via the New Game flow"
         );
-        self.load_game_from_path(&save_path)
+        self.load_game_from_path(&save_path, None)
     }
 
-    pub fn load_game_from_path(&mut self, save_path: &Path) -> Result<&Game> {
+    /// The password is only needed for encrypted saves
+    pub fn load_game_from_path(
+        &mut self,
+        save_path: &Path,
+        password: Option<&str>,
+    ) -> Result<&Game> {
         self.game = None;
         debug!("Loading save: {save_path:?}");
-        let mut archive = SaveArchive::open(save_path)?;
+        let mut archive = match password {
+            Some(password) => SaveArchive::open_with_password(save_path, password)?,
+            None => SaveArchive::open(save_path)?,
+        };
+        archive.set_backups(self.config.backups);
         let game_data = archive.read_game_data()?;
-        let game = Game::load(
-            self.config.get_llm()?,
-            self.config.get_image_model()?,
+        let config = self.config.for_world(&game_data.world_description);
+        let mut game = Game::load(
+            config.get_llm()?,
+            config.get_image_model(),
             game_data,
-            self.config.active_style().cloned(),
+            config.active_style().cloned(),
         );
-        self.game = Some(GameContext::try_new(game, archive)?);
+        game.summary_llm = self.config.get_summary_llm()?;
+        game.context_budget = self.config.context_budget;
+        game.context_window = config.context_window();
+        game.history_turns = self.config.history_turns();
+        game.summary_interval = self.config.summary_interval();
+        game.batch_summaries = self.config.batch_summaries;
+        game.extra_image_variants = self.config.extra_image_variants;
+        game.snapshot_every = self.config.snapshot_every;
+        game.audit_log = Some(Arc::new(AuditLog::open(&AuditLog::path_for(save_path))?));
+        // a recording that doesn't start with the first turn can't be replayed
+        let recording = Recorder::path_for(save_path);
+        if self.config.record_sessions && recording.exists() {
+            game.recorder = Some(Arc::new(Recorder::open(&recording)?));
+        }
+        self.game = Some(GameContext::try_new(game, archive, self.config.image_storage)?);
         Ok(&self.game.as_ref().unwrap().game)
     }
+
+    /// Copies the running game to `name` in the saves dir, and continues with the copy. The
+    /// original save keeps the state it has now.
+    pub fn save_game_as(&mut self, name: &str) -> Result<PathBuf> {
+        let gctx = self.game.as_mut().ok_or(eyre!("There is no running game"))?;
+        let path = named_save_path(name)?;
+        gctx.save.save_as(path.clone())?;
+        let audit_path = copy_audit_log(&path)?;
+        gctx.game.audit_log = Some(Arc::new(AuditLog::open(&audit_path)?));
+        if gctx.game.recorder.is_some()
+            && let Some(original) = load_active_game_save_path()?
+        {
+            let recording = Recorder::path_for(&path);
+            fs::copy(Recorder::path_for(&original), &recording)?;
+            gctx.game.recorder = Some(Arc::new(Recorder::open(&recording)?));
+        }
+
+        remember_save(&path)?;
+        save_active_game_save_path(&path)?;
+        Ok(path)
+    }
+
+    /// Writes the game up to the turn that is viewed in the past to `name` in the saves dir.
+    /// The running game continues unchanged.
+    pub fn branch_from_current_past(&mut self, name: &str) -> Result<PathBuf> {
+        let gctx = self.game.as_mut().ok_or(eyre!("There is no running game"))?;
+        let path = named_save_path(name)?;
+        gctx.fork_from_current_past(&path)?;
+        copy_audit_log(&path)?;
+        remember_save(&path)?;
+        Ok(path)
+    }
+
+    /// Writes the save at `parent` up to its snapshot after `turn` to `name` in the saves dir,
+    /// and loads the new save. The password is only needed for encrypted saves, the new save
+    /// keeps it.
+    pub fn load_snapshot(
+        &mut self,
+        parent: &Path,
+        turn: usize,
+        name: &str,
+        password: Option<&str>,
+    ) -> Result<PathBuf> {
+        let path = named_save_path(name)?;
+        // the parent might be the running game, which holds the lock
+        SaveArchive::open_read_only(parent, password)?.fork_clipped(&path, turn)?;
+        copy_audit_log_of(parent, &path)?;
+        remember_save(&path)?;
+        self.load_game_from_path(&path, password)?;
+        save_active_game_save_path(&path)?;
+        Ok(path)
+    }
+}
+
+/// Where a save called `name` is written in the saves dir. Fails if there is one already.
+fn named_save_path(name: &str) -> Result<PathBuf> {
+    let dir = saves_dir()?;
+    fs::create_dir_all(&dir)?;
+    save_path_in(&dir, name)
+}
+
+/// Where a save called `name` is written in `dir`. Fails if there is one already.
+pub fn save_path_in(dir: &Path, name: &str) -> Result<PathBuf> {
+    let name = name.trim();
+    ensure!(!name.is_empty(), "Please enter a name for the save");
+    ensure!(
+        !name.contains(['/', '\\']) && name != "." && name != "..",
+        "The name of a save can't contain slashes"
+    );
+    let path = dir.join(format!("{name}.wwsave"));
+    ensure!(!path.exists(), "There is a save called {name} already");
+    Ok(path)
+}
+
+/// The history of a copy of the active save starts with the one of the original. Returns the
+/// path of the copy's audit log.
+fn copy_audit_log(copy: &Path) -> Result<PathBuf> {
+    match load_active_game_save_path()? {
+        Some(original) => copy_audit_log_of(&original, copy),
+        None => Ok(AuditLog::path_for(copy)),
+    }
+}
+
+pub fn copy_audit_log_of(original: &Path, copy: &Path) -> Result<PathBuf> {
+    let audit_path = AuditLog::path_for(copy);
+    if AuditLog::path_for(original).exists() {
+        fs::copy(AuditLog::path_for(original), &audit_path)?;
+    }
+    Ok(audit_path)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -75,6 +211,73 @@ pub struct Config {
     pub llm_tokens: BTreeMap<llm::ModelProvider, String>,
     pub active_model_style: BTreeMap<image_model::Model, String>,
     pub styles: BTreeMap<StyleKey, ModelStyle>,
+    #[serde(default)]
+    pub image_storage: StorageSettings,
+    /// used when `current_llm` is `OpenrouterCustom`
+    #[serde(default)]
+    pub openrouter_model_id: String,
+    /// if it's set, it's used instead of `current_llm`
+    #[serde(default)]
+    pub custom_openai_endpoint: Option<llm::CustomEndpoint>,
+    /// how LLM requests are retried when the provider is overloaded
+    #[serde(default)]
+    pub llm_retry: llm::RetryPolicy,
+    /// writes the summaries, `None` means `current_llm` does it
+    #[serde(default)]
+    pub summary_llm: Option<llm::ProvidedModel>,
+    /// how many tokens the LLM that narrates may think before each turn, `None` disables
+    /// thinking
+    #[serde(default)]
+    pub thinking_budget: Option<u32>,
+    /// the estimated number of tokens a turn's prompt may take, `None` means no limit
+    #[serde(default)]
+    pub context_budget: Option<usize>,
+    /// how many summarized turns are still sent verbatim, unless the world sets it. `None`
+    /// means `game::DEFAULT_HISTORY_TURNS`.
+    #[serde(default)]
+    pub history_turns: Option<usize>,
+    /// replaces the default limits of the providers
+    #[serde(default)]
+    pub rate_limits: BTreeMap<LimitKey, RateLimits>,
+    /// replaces the default timeouts of the LLM providers
+    #[serde(default)]
+    pub timeouts: BTreeMap<llm::ModelProvider, llm::Timeouts>,
+    /// summaries are written via the cheaper batch API, in the background
+    #[serde(default)]
+    pub batch_summaries: bool,
+    /// games that are started are recorded next to their save, so `admin_cli replay` can
+    /// play them again with the same answers
+    #[serde(default)]
+    pub record_sessions: bool,
+    /// how many images are made per turn in addition to the main one
+    #[serde(default)]
+    pub extra_image_variants: usize,
+    /// used when `current_img_model` is `StableDiffusionLocal`
+    #[serde(default)]
+    pub local_image_model: image_model::LocalSettings,
+    /// replaces the default safety tolerance of the image models that have one
+    #[serde(default)]
+    pub safety_tolerance: BTreeMap<image_model::ProvidedModel, u8>,
+    /// asked for the image when `current_img_model` fails
+    #[serde(default)]
+    pub fallback_img_model: Option<image_model::ProvidedModel>,
+
+    #[serde(default)]
+    pub image_queue: image_model::QueueSettings,
+
+    /// used instead of the built-in version of `Flux1Replicate`
+    #[serde(default)]
+    pub replicate_model: Option<catalog::Selection>,
+    /// copies of the save that are made before it's written
+    #[serde(default)]
+    pub backups: BackupSettings,
+    /// every how many turns a snapshot is recorded, 0 records none
+    #[serde(default)]
+    pub snapshot_every: usize,
+    /// every how many turns the story is summarized, 0 never. `None` means
+    /// `summary::DEFAULT_INTERVAL`.
+    #[serde(default)]
+    pub summary_interval: Option<usize>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -84,22 +287,150 @@ pub struct StyleKey {
 }
 
 impl Config {
+    /// The config with the models that `world` prefers, and the safety tolerances its
+    /// content rating allows
+    pub fn for_world(&self, world: &WorldDescription) -> Config {
+        let mut config = self.clone();
+        if let Some(model) = world.preferred_llm {
+            config.current_llm = model;
+            // it would be used instead of the model
+            config.custom_openai_endpoint = None;
+        }
+        if let Some(model) = world.preferred_image_model {
+            config.current_img_model = model;
+        }
+        if let Some(rating) = world.content_rating {
+            for model in image_model::ProvidedModel::iter() {
+                if let (Some(tolerance), Some(max)) = (
+                    config.safety_tolerance(model),
+                    rating.max_safety_tolerance(model),
+                ) {
+                    config.safety_tolerance.insert(model, tolerance.min(max));
+                }
+            }
+        }
+        config
+    }
+
     pub fn get_llm(&self) -> Result<LLMBox> {
-        let model = self.current_llm;
+        if let Some(endpoint) = &self.custom_openai_endpoint {
+            ensure!(
+                !endpoint.url.trim().is_empty(),
+                "No URL for the custom endpoint is set in the options"
+            );
+            // local servers usually don't need a key
+            let key = self
+                .llm_tokens
+                .get(&llm::ModelProvider::CUSTOM_ENDPOINT)
+                .cloned()
+                .unwrap_or_default();
+            let mut llm = endpoint.make(key);
+            llm.set_retry_policy(self.llm_retry);
+            llm.set_timeouts(self.timeouts(llm::ModelProvider::CUSTOM_ENDPOINT));
+            return Ok(rate_limit::limit_tokens(llm));
+        }
+        let mut llm = self.make_llm(self.current_llm)?;
+        llm.set_thinking_budget(self.thinking_budget);
+        Ok(llm)
+    }
+
+    /// Returns `None` if the summaries are written by the LLM that narrates
+    pub fn get_summary_llm(&self) -> Result<Option<LLMBox>> {
+        self.summary_llm
+            .map(|model| self.make_llm(model))
+            .transpose()
+    }
+
+    fn make_llm(&self, model: llm::ProvidedModel) -> Result<LLMBox> {
         let key = self
             .llm_tokens
             .get(&model.provider())
             .ok_or(eyre!("No token for {model:?}"))?;
-        Ok(model.make(key.clone()))
+        let model_id = self.openrouter_model_id.trim();
+        ensure!(
+            !model.needs_model_id() || !model_id.is_empty(),
+            "No model id for openrouter.ai is set in the options"
+        );
+        let mut llm = model.make(key.clone(), model_id);
+        llm.set_retry_policy(self.llm_retry);
+        llm.set_timeouts(self.timeouts(model.provider()));
+        Ok(rate_limit::limit_tokens(llm))
     }
 
-    pub fn get_image_model(&self) -> Result<ImgModBox> {
-        let model = self.current_img_model;
-        let key = self
-            .img_model_tokens
-            .get(&model.provider())
-            .ok_or(eyre!("No token for {model}"))?;
-        Ok(model.make(key.clone()))
+    pub fn rate_limits(&self, key: LimitKey) -> RateLimits {
+        self.rate_limits
+            .get(&key)
+            .copied()
+            .unwrap_or_else(|| key.default_limits())
+    }
+
+    pub fn timeouts(&self, provider: llm::ModelProvider) -> llm::Timeouts {
+        self.timeouts.get(&provider).copied().unwrap_or_default()
+    }
+
+    pub fn summary_interval(&self) -> usize {
+        self.summary_interval.unwrap_or(summary::DEFAULT_INTERVAL)
+    }
+
+    pub fn history_turns(&self) -> usize {
+        self.history_turns.unwrap_or(DEFAULT_HISTORY_TURNS)
+    }
+
+    /// The context window of the LLM that narrates, if it's known
+    pub fn context_window(&self) -> Option<usize> {
+        if self.custom_openai_endpoint.is_some() {
+            return None;
+        }
+        self.current_llm.context_tokens()
+    }
+
+    /// `None` if the model has no safety tolerance
+    pub fn safety_tolerance(&self, model: image_model::ProvidedModel) -> Option<u8> {
+        self.safety_tolerance
+            .get(&model)
+            .copied()
+            .or_else(|| model.default_safety_tolerance())
+    }
+
+    /// Configures the limiters that are shared by all requests to a provider, and the queue
+    /// of the images
+    pub fn apply_rate_limits(&self) {
+        for key in LimitKey::iter() {
+            rate_limit::configure(key, self.rate_limits(key));
+        }
+        queue::configure(self.image_queue);
+    }
+
+    /// Returns `None` if there is no token for the selected model. Games are text-only then.
+    /// A fallback model without a token is left out.
+    pub fn get_image_model(&self) -> Option<ImgModBox> {
+        let primary = self.make_image_model(self.current_img_model)?;
+        let fallback = self
+            .fallback_img_model
+            .filter(|model| *model != self.current_img_model)
+            .and_then(|model| self.make_image_model(model));
+        let model: ImgModBox = match fallback {
+            Some(fallback) => Box::new(image_model::FallbackImageModel::new(primary, fallback)),
+            None => primary,
+        };
+        Some(queue::queued(model))
+    }
+
+    fn make_image_model(&self, model: image_model::ProvidedModel) -> Option<ImgModBox> {
+        if model == image_model::ProvidedModel::StableDiffusionLocal {
+            return Some(self.local_image_model.make());
+        }
+        if !model.provider().needs_api_key() {
+            return Some(model.make(String::new()));
+        }
+        let key = self.img_model_tokens.get(&model.provider())?;
+        if model == image_model::ProvidedModel::Flux1Replicate
+            && let Some(selection) = &self.replicate_model
+        {
+            return Some(selection.make(key.clone()));
+        }
+        let tolerance = self.safety_tolerance(model).unwrap_or_default();
+        Some(model.make_with_safety_tolerance(key.clone(), tolerance))
     }
 
     pub fn active_style_for_mut(&mut self, model: Model) -> Option<&mut image_model::ModelStyle> {
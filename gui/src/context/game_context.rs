@@ -1,39 +1,102 @@
+use std::{mem, path::Path, sync::Arc, time::Duration};
+
 use color_eyre::{
-    Result,
-    eyre::{bail, eyre},
+    Report, Result,
+    eyre::{WrapErr as _, bail, ensure, eyre},
 };
-use iced::{Task, advanced::image::Handle as ImgHandle, widget::markdown};
-use log::{debug, warn};
+use iced::{Task, advanced::image::Handle as ImgHandle, task, widget::markdown};
+use log::{debug, error, warn};
 
 use crate::{
     TryIntoExt,
-    message::{ContextMessage, Message, ui_messages::Playing as PlayingMessage},
+    message::{ContextMessage, Message, UiMessage, ui_messages::Playing as PlayingMessage},
 };
 use engine::{
+    audit_log::AuditEntry,
     game::{
-        AdvanceResult, Game, StartResultOrData, StoredImageInfo, TurnInput, WorldDescription,
+        AdvanceResult, CachedImage, CancellationToken, Game, Image, InFlightTurn, InGameTime,
+        StartResultOrData, StoredImageInfo, Tone, TurnInput, WorldDescription, WorldState,
+        sanitize_markdown, world_state,
     },
-    save_archive::SaveArchive,
+    error::EngineError,
+    image_model::StorageSettings,
+    llm::OutputMessage,
+    archive_worker::ArchiveWorker,
+    save_archive::{Compaction, SaveArchive},
+    thumbnail::{Thumbnail, ThumbnailCache, make_thumbnail},
 };
 
+mod markdown_cache;
 mod pending_turn;
 mod state;
+mod turn_cursor;
 
-use pending_turn::{FinalizingTurn, PendingTurn, Resolution};
+use markdown_cache::MarkdownCache;
+use pending_turn::{FinalizingTurn, PendingTurn, Resolution, SummaryState};
 pub use pending_turn::ImageState;
 pub use state::{Complete, InThePast, SubState};
+pub use turn_cursor::TurnCursor;
 
 pub struct GameContext {
     pub game: Game,
-    pub save: SaveArchive,
+    pub save: ArchiveWorker,
     pub sub_state: SubState,
     pub current_generation: usize,
     pub output_scroll_y: f32,
-    pub output_markdown: Vec<markdown::Item>,
+    /// the output of the turn that is being received
+    streaming_markdown: Vec<markdown::Item>,
     pub output_text: String,
+    /// what the GM reasoned before it wrote the latest turn, if thinking is enabled
+    pub thoughts: String,
     pub image_data: Option<ImageData>,
+    /// The image of the latest turn was generated, but couldn't be downloaded from this URL
+    pub failed_image_download: Option<String>,
+    /// whether the user was asked what to do with an interrupted turn
+    pub interrupted_turn_prompted: bool,
+    /// whether the recap was offered since the game was loaded
+    pub recap_prompted: bool,
+    /// length of the in-flight turn's text when it was last written to the archive
+    in_flight_persisted_len: usize,
+    /// stops the pending turn
+    turn_cancel: Option<CancellationToken>,
+    /// allows to stop waiting for the image of the pending turn
+    image_job: Option<task::Handle>,
+    /// the summary that is written in the background, it's aborted with the game
+    summary_job: Option<task::Handle>,
+    /// the recap that is written in the background, see [`GameContext::refresh_recap`]
+    recap_job: Option<task::Handle>,
+    /// the image of the pending turn takes much longer than usual
+    pub image_stuck: bool,
+    pub image_progress: Option<ImageProgressData>,
+    /// another image for a completed turn is being made
+    pub regenerating_image: bool,
+    /// the image variants that are still being made, with the turn they belong to
+    variant_jobs: Vec<(usize, task::Handle)>,
+    /// variants that arrived before their turn was complete, they're already in the archive
+    pending_variants: Vec<StoredImageInfo>,
+    /// the images of the displayed turn, if there is more than one, by archive id
+    pub image_variants: Vec<(usize, ImgHandle)>,
+    /// how received images are stored
+    pub image_storage: StorageSettings,
+    /// downscaled images for the sidebar
+    thumbnails: ThumbnailCache,
+    /// the parsed outputs of the completed turns around the viewed one
+    markdown: MarkdownCache,
 }
 
+/// While a turn is streaming, its text is written to the archive whenever it grew by this
+/// many bytes
+const IN_FLIGHT_PERSIST_INTERVAL: usize = 1024;
+
+/// Images usually take well below a minute. After this, the user is offered to continue
+/// without the image.
+const IMAGE_WATCHDOG_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The sidebar is never wider than this, so larger images don't need to be kept
+const SIDEBAR_IMAGE_SIZE: u32 = 832;
+/// Enough to browse back and forth through the recent turns without decoding again
+const THUMBNAIL_CACHE_CAPACITY: usize = 32;
+
 pub struct ImageData {
     pub handle: ImgHandle,
     pub caption: String,
@@ -42,15 +105,30 @@ pub struct ImageData {
     pub is_current: bool,
 }
 
+/// What the image model told about the image of the pending turn
+pub struct ImageProgressData {
+    pub status: String,
+    /// between 0 and 1
+    pub fraction: Option<f32>,
+    pub preview: Option<ImgHandle>,
+}
+
 impl GameContext {
-    pub fn try_new(game: Game, mut save: SaveArchive) -> Result<Self> {
+    pub fn try_new(
+        game: Game,
+        archive: SaveArchive,
+        image_storage: StorageSettings,
+    ) -> Result<Self> {
+        let mut save = ArchiveWorker::spawn(archive);
+        let mut thumbnails = ThumbnailCache::new(SIDEBAR_IMAGE_SIZE, THUMBNAIL_CACHE_CAPACITY);
         if let Some(td) = game.data.turn_data.last().cloned() {
-            let output_markdown = markdown::parse(&td.output.text).collect();
+            let mut markdown = MarkdownCache::default();
+            markdown.get_or_parse(game.data.turn_data.len() - 1, &td.output.text);
             let image_data = game
                 .get_latest_image_info()
                 .map(|info| {
                     color_eyre::eyre::Ok(ImageData {
-                        handle: ImgHandle::from_bytes(save.read_image(info.id)?),
+                        handle: sidebar_image(&mut thumbnails, &mut save, info.id)?,
                         caption: info.caption.clone(),
                         is_current: true,
                     })
@@ -61,22 +139,58 @@ impl GameContext {
                 game,
                 save,
                 sub_state: Complete { turn_data: td }.into(),
-                output_markdown,
+                streaming_markdown: vec![],
                 image_data,
                 output_text,
                 current_generation: 0,
                 output_scroll_y: 0.0,
+                failed_image_download: None,
+                interrupted_turn_prompted: false,
+                recap_prompted: false,
+                in_flight_persisted_len: 0,
+                turn_cancel: None,
+                thoughts: String::new(),
+                image_job: None,
+                summary_job: None,
+                recap_job: None,
+                image_stuck: false,
+                image_progress: None,
+                regenerating_image: false,
+                variant_jobs: vec![],
+                pending_variants: vec![],
+                image_variants: vec![],
+                image_storage,
+                thumbnails,
+                markdown,
             })
         } else {
             Ok(Self {
                 game,
                 save,
                 sub_state: SubState::Uninit,
-                output_markdown: vec![],
+                streaming_markdown: vec![],
                 image_data: None,
                 output_text: String::new(),
                 current_generation: 0,
                 output_scroll_y: 0.0,
+                failed_image_download: None,
+                interrupted_turn_prompted: false,
+                recap_prompted: false,
+                in_flight_persisted_len: 0,
+                turn_cancel: None,
+                thoughts: String::new(),
+                image_job: None,
+                summary_job: None,
+                recap_job: None,
+                image_stuck: false,
+                image_progress: None,
+                regenerating_image: false,
+                variant_jobs: vec![],
+                pending_variants: vec![],
+                image_variants: vec![],
+                image_storage,
+                thumbnails,
+                markdown: MarkdownCache::default(),
             })
         }
     }
@@ -93,53 +207,41 @@ impl GameContext {
                     return Ok(Task::none());
                 }
 
-                let Ok(output) = $invar else {
-                    self.current_generation += 1;
-                    let turn = self.current_turn();
-                    if turn > 0 {
-                        self.load_completed_turn(self.current_turn() - 1)?;
+                match $invar {
+                    Ok(output) => output,
+                    Err(err) => {
+                        self.abandon_turn()?;
+                        // the error is kept intact, so `Gui::update` can react to its kind
+                        return Err(Report::new(err).wrap_err(indoc::indoc! {"
+                            There was an error with the LLM response or the image model.
+                            This can happen. Try again.
+                            If it repeats, try doing something else, if you're on Flux2, try Flux1."}));
                     }
-                    bail!(indoc::formatdoc! {"
-                        There was an error with the LLM response or the image model.
-                        This can happen. Try again.
-                        If it repeats, try doing something else, if you're on Flux2, try Flux1.
-                        Details:
-                        {:?}", $invar});
-                };
-                output
+                }
             }}
         }
 
         match message {
             Init => match self.game.start_or_get_last_output() {
-                StartResultOrData::StartResult(
-                    AdvanceResult {
-                        text_stream,
-                        round_output,
-                        image,
-                    },
-                    input,
-                ) => {
-                    let generation = self.current_generation;
-                    let output_fut = Task::perform(round_output, move |res| {
-                        OutputComplete(generation, res).into()
-                    });
-                    let image_fut =
-                        Task::perform(image, move |res| ImageReady(generation, res).into());
-                    let stream_task = Task::run(text_stream, move |res| {
-                        NewTextFragment(generation, res).into()
-                    });
-                    self.sub_state = PendingTurn::new(input).into();
-                    Ok(Task::batch([output_fut, stream_task, image_fut]))
+                StartResultOrData::StartResult(advance_result, input) => {
+                    // if the first turn was interrupted, its image can be reused
+                    let in_flight = self.game.data_mut().in_flight_turn.take();
+                    let in_flight = in_flight.unwrap_or_else(|| InFlightTurn::new(input));
+                    self.begin_turn(in_flight, advance_result)
                 }
                 StartResultOrData::Data(turn_data) => {
-                    self.output_markdown = markdown::parse(&turn_data.output.text).collect();
+                    let turn = self.game.data.turn_data.len() - 1;
+                    self.markdown.get_or_parse(turn, &turn_data.output.text);
                     self.image_data = turn_data
                         .images
                         .first()
                         .map(|info| {
                             color_eyre::eyre::Ok(ImageData {
-                                handle: ImgHandle::from_bytes(self.save.read_image(info.id)?),
+                                handle: sidebar_image(
+                                    &mut self.thumbnails,
+                                    &mut self.save,
+                                    info.id,
+                                )?,
                                 caption: info.caption.clone(),
                                 is_current: true,
                             })
@@ -155,51 +257,98 @@ impl GameContext {
                 let output = unpack_received_msg!(turn_output, generation);
 
                 self.output_text = output.text.clone();
-                self.output_markdown = markdown::parse(&self.output_text).collect();
+                self.streaming_markdown = parse_output(&self.output_text);
 
                 let pending_turn: PendingTurn = self.sub_state.take().try_into_ex()?;
-                self.apply_resolution(pending_turn.finish_output(output))
+                // the summary only depends on the completed turns, so it's generated while
+                // the image is still rendering
+                let summary_task = self.request_summary();
+                let task = self.apply_resolution(pending_turn.finish_output(output))?;
+                Ok(Task::batch([summary_task, task]))
             }
 
             SummaryFinished(generation, message) => {
                 debug!("Received SummaryFinished for generation {generation}");
-                let summary_msg = unpack_received_msg!(message, generation);
-                let FinalizingTurn {
-                    input,
-                    output,
-                    image,
-                } = self.sub_state.take().try_into_ex()?;
-
-                let images = if let Some(image) = image {
-                    let id = self.save.append_image(&image.jpeg_bytes)?;
-                    vec![StoredImageInfo {
-                        id,
-                        caption: image.caption,
-                    }]
-                } else {
-                    vec![]
-                };
-                self.game.update(
-                    input,
-                    output.clone(),
-                    images,
-                    summary_msg.map(|s| s.text),
-                )?;
-                self.save.write_game_data(&self.game.data)?;
-                self.sub_state = Complete {
-                    turn_data: self.game.data.turn_data.last().unwrap().clone(),
+                let summary = unpack_received_msg!(message, generation);
+                match self.sub_state.take() {
+                    SubState::WaitingForOutput(pending_turn) => {
+                        debug!("Summary is ready, the image is still pending");
+                        self.sub_state = pending_turn.finish_summary(summary).into();
+                        Ok(Task::none())
+                    }
+                    SubState::WaitingForSummary(turn) => self.complete_turn(turn, summary),
+                    other => {
+                        self.sub_state = other;
+                        bail!("Received a summary while being: {:?}", self.sub_state)
+                    }
                 }
-                .into();
-                self.current_generation += 1;
-                debug!("Turn finalized for generation {generation}, sending ClearActionEditors");
-                Ok(Task::done(PlayingMessage::ClearActionEditors.into()))
+            }
+
+            RecapFinished(show, res) => {
+                self.recap_job = None;
+                match res {
+                    Ok((recap, message)) => {
+                        self.game.set_recap(recap, message);
+                        self.save.write_game_data(self.game.data.clone())?;
+                        if show {
+                            return Ok(Task::done(UiMessage::ShowRecap.into()));
+                        }
+                    }
+                    // the save keeps its previous title
+                    Err(e) => error!("Writing the recap failed: {e}"),
+                }
+                Ok(Task::none())
+            }
+
+            BackgroundSummaryFinished(bday, res) => {
+                self.summary_job = None;
+                match res {
+                    Ok(Some(summary)) => {
+                        debug!("Received the background summary for turn {bday}");
+                        self.game.add_summary(summary, bday);
+                        self.save.write_game_data(self.game.data.clone())?;
+                    }
+                    Ok(None) => {}
+                    // the summary is requested again after the next turn
+                    Err(e) => error!("Writing the summary in the background failed: {e}"),
+                }
+                Ok(Task::none())
+            }
+
+            ImageProgressed(generation, progress) => {
+                if generation == self.current_generation && self.image_job.is_some() {
+                    self.image_progress = Some(ImageProgressData {
+                        status: progress.status,
+                        fraction: progress.fraction,
+                        preview: progress.preview.map(ImgHandle::from_bytes),
+                    });
+                }
+                Ok(Task::none())
+            }
+
+            NewThoughtFragment(generation, t) => {
+                if generation == self.current_generation {
+                    self.thoughts.push_str(&t);
+                }
+                Ok(Task::none())
             }
 
             NewTextFragment(generation, t) => {
                 let t = unpack_received_msg!(t, generation);
                 self.sub_state.stream_buffer_mut()?.push_str(&t);
                 self.output_text.push_str(&t);
-                self.output_markdown = markdown::parse(&self.output_text).collect();
+                self.streaming_markdown = parse_output(&self.output_text);
+
+                // the game data is shared with the archive worker while it's written, so it's
+                // only touched when it's written again
+                let unsaved = self.output_text.len() - self.in_flight_persisted_len;
+                if unsaved >= IN_FLIGHT_PERSIST_INTERVAL
+                    && let Some(in_flight) = &mut self.game.data_mut().in_flight_turn
+                {
+                    in_flight.partial_text.clone_from(&self.output_text);
+                    self.in_flight_persisted_len = self.output_text.len();
+                    self.save.write_game_data(self.game.data.clone())?;
+                }
                 Ok(Task::none())
             }
 
@@ -207,10 +356,17 @@ impl GameContext {
                 if generation < self.current_generation {
                     return Ok(Task::none());
                 }
+                self.image_job = None;
+                self.image_stuck = false;
+                self.image_progress = None;
                 let Ok(img) = image else {
                     if let Some(img_data) = &mut self.image_data {
                         img_data.is_current = false;
                     }
+                    self.failed_image_download = match &image {
+                        Err(EngineError::ImageDownload(e)) => Some(e.url.clone()),
+                        _ => None,
+                    };
                     warn!(
                         "{}",
                         indoc::formatdoc! {
@@ -227,15 +383,372 @@ impl GameContext {
                 };
                 let pending_turn: PendingTurn = self.sub_state.take().try_into_ex()?;
 
+                // the image is stored right away, so it isn't lost if the app is closed
+                // before the turn is complete
+                let info = self.store_image(self.game.current_turn(), &img)?;
+                if let Some(in_flight) = &mut self.game.data_mut().in_flight_turn {
+                    in_flight.image = Some(info.clone());
+                }
+                self.save.write_game_data(self.game.data.clone())?;
+
+                let handle = if img.cached.is_some() {
+                    sidebar_image(&mut self.thumbnails, &mut self.save, info.id)?
+                } else {
+                    to_handle(self.thumbnails.insert_from_bytes(info.id, &img.bytes)?)
+                };
                 self.image_data = Some(ImageData {
-                    handle: ImgHandle::from_bytes(img.jpeg_bytes.clone()),
-                    caption: img.caption.clone(),
+                    handle,
+                    caption: img.caption,
                     is_current: true,
                 });
 
-                self.apply_resolution(pending_turn.finish_image(img))
+                self.apply_resolution(pending_turn.finish_image(info))
+            }
+
+            PrefetchedImage(id, thumbnail) => {
+                match thumbnail {
+                    Ok(thumbnail) => self.thumbnails.insert_thumbnail(id, thumbnail),
+                    // it will be loaded again when it's needed
+                    Err(e) => debug!("Failed to prefetch image {id}: {e:#}"),
+                }
+                Ok(Task::none())
+            }
+
+            PrefetchedMarkdown(turn, text, markdown) => {
+                self.markdown.insert(turn, &text, markdown);
+                Ok(Task::none())
+            }
+
+            ImageWatchdog(generation) => {
+                let still_pending = matches!(
+                    &self.sub_state,
+                    SubState::WaitingForOutput(PendingTurn {
+                        image: ImageState::Pending,
+                        ..
+                    })
+                );
+                if generation == self.current_generation && still_pending {
+                    warn!("The image wasn't received after {IMAGE_WATCHDOG_TIMEOUT:?}");
+                    self.image_stuck = true;
+                }
+                Ok(Task::none())
+            }
+
+            ImageVariantReady(turn, image) => {
+                let Some(idx) = self.variant_jobs.iter().position(|(t, _)| *t == turn) else {
+                    // the turn was abandoned
+                    return Ok(Task::none());
+                };
+                self.variant_jobs.remove(idx);
+                match image {
+                    Ok(image) => self.add_image_variant(turn, image)?,
+                    Err(e) => warn!("Failed to make an image variant for turn {turn}: {e:?}"),
+                }
+                Ok(Task::none())
             }
+
+            ExtraImageReady(turn, image) => {
+                self.regenerating_image = false;
+                let image = image.wrap_err("Failed to get the image")?;
+                self.add_image_to_turn(turn, image)?;
+                if turn + 1 == self.game.current_turn() {
+                    self.failed_image_download = None;
+                }
+                Ok(Task::none())
+            }
+        }
+    }
+
+    /// Appends a received image of `turn` to the archive, according to the storage settings,
+    /// and records its cost. Images from the cache are in the archive already, and free.
+    fn store_image(&mut self, turn: usize, image: &Image) -> Result<StoredImageInfo> {
+        if let Some(cached) = image.cached {
+            return Ok(StoredImageInfo {
+                id: cached.id,
+                caption: image.caption.clone(),
+                original_id: cached.original_id,
+                seed: cached.seed,
+            });
+        }
+        let bytes = self.image_storage.prepare(&image.bytes)?.bytes;
+        let original_id = if self.image_storage.store_originals {
+            Some(self.save.append_image(image.bytes.clone())?)
+        } else {
+            None
+        };
+        let id = self.save.append_image(bytes)?;
+        self.game.record_image(turn, image);
+        if let Some(key) = image.cache_key {
+            self.game
+                .data_mut()
+                .image_cache
+                .insert(
+                    key,
+                    CachedImage {
+                        id,
+                        original_id,
+                        seed: image.seed,
+                    },
+                );
+        }
+        Ok(StoredImageInfo {
+            id,
+            caption: image.caption.clone(),
+            original_id,
+            seed: image.seed,
+        })
+    }
+
+    /// Stores an image that was generated after the turn was completed
+    fn add_image_to_turn(&mut self, turn: usize, image: Image) -> Result<()> {
+        let info = self.store_image(turn, &image)?;
+        self.game
+            .data_mut()
+            .turn_data
+            .get_mut(turn)
+            .ok_or_else(|| eyre!("Invalid turn: {turn}"))?
+            .images
+            .push(info);
+        self.save.write_game_data(self.game.data.clone())?;
+
+        if self.turn_cursor().map(TurnCursor::viewed) == Some(turn) {
+            self.load_completed_turn(turn)?;
+        }
+        Ok(())
+    }
+
+    /// Stores an additional image of `turn`, which may still be pending
+    fn add_image_variant(&mut self, turn: usize, image: Image) -> Result<()> {
+        let info = self.store_image(turn, &image)?;
+        match self.game.data_mut().turn_data.get_mut(turn) {
+            Some(turn_data) => turn_data.add_image_variant(info),
+            None => self.pending_variants.push(info),
+        }
+        self.save.write_game_data(self.game.data.clone())?;
+
+        if self.turn_cursor().map(TurnCursor::viewed) == Some(turn) {
+            self.load_completed_turn(turn)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the image variants of `first_turn` and the turns after it
+    fn drop_image_variants_from(&mut self, first_turn: usize) {
+        self.variant_jobs.retain(|(turn, _)| *turn < first_turn);
+        self.pending_variants.clear();
+    }
+
+    /// Shows the image with the given archive id for the displayed turn
+    pub fn select_image(&mut self, id: usize) -> Result<()> {
+        let turn = self
+            .turn_cursor()
+            .map(TurnCursor::viewed)
+            .ok_or_else(|| eyre!("No completed turn is displayed"))?;
+        self.game
+            .data_mut()
+            .turn_data
+            .get_mut(turn)
+            .ok_or_else(|| eyre!("Invalid turn: {turn}"))?
+            .select_image(id)?;
+        self.save.write_game_data(self.game.data.clone())?;
+        self.load_completed_turn(turn)
+    }
+
+    /// Bookmarks the displayed turn, or removes its bookmark if `label` is `None`
+    pub fn set_bookmark(&mut self, label: Option<String>) -> Result<()> {
+        let turn = self
+            .turn_cursor()
+            .map(TurnCursor::viewed)
+            .ok_or_else(|| eyre!("No completed turn is displayed"))?;
+        self.game.data_mut().set_bookmark(turn, label)?;
+        self.save.write_game_data(self.game.data.clone())?;
+        self.load_completed_turn(turn)
+    }
+
+    /// Images that mention `name` are made with `seed` from now on
+    pub fn pin_seed(&mut self, name: String, seed: u64) -> Result<()> {
+        let name = name.trim();
+        ensure!(!name.is_empty(), "The name of the character or location is empty");
+        self.game.data_mut().pinned_seeds.insert(name.into(), seed);
+        self.save.write_game_data(self.game.data.clone())?;
+        Ok(())
+    }
+
+    pub fn unpin_seed(&mut self, name: &str) -> Result<()> {
+        self.game.data_mut().pinned_seeds.remove(name);
+        self.save.write_game_data(self.game.data.clone())?;
+        Ok(())
+    }
+
+    /// Replaces the world state with the `key = value` lines of `text`
+    pub fn set_world_state(&mut self, text: &str) -> Result<()> {
+        let invalid = text
+            .lines()
+            .find(|l| !l.trim().is_empty() && !l.contains('='));
+        if let Some(line) = invalid {
+            bail!("Expected a \"key = value\" line, but got: {line}");
+        }
+        let mut state = WorldState::new();
+        world_state::apply(&mut state, &world_state::parse(text));
+        self.game.data_mut().set_world_state(state);
+        self.save.write_game_data(self.game.data.clone())?;
+        Ok(())
+    }
+
+    /// Applies from the next turn on
+    pub fn set_tone(&mut self, tone: Tone) -> Result<()> {
+        self.game.data_mut().world_description.tone = tone;
+        self.save.write_game_data(self.game.data.clone())?;
+        Ok(())
+    }
+
+    /// Asks for a new title and recap in the background, unless the latest one is current.
+    /// With `show`, the recap is shown when it's there.
+    pub fn refresh_recap(&mut self, show: bool) -> Task<Message> {
+        if self.game.data.turn_data.is_empty() {
+            return Task::none();
+        }
+        if self.game.data.current_recap().is_some() {
+            return if show {
+                Task::done(UiMessage::ShowRecap.into())
+            } else {
+                Task::none()
+            };
+        }
+        let (task, handle) = Task::perform(self.game.mk_recap(), move |res| {
+            ContextMessage::RecapFinished(show, res).into()
+        })
+        .abortable();
+        self.recap_job = Some(handle.abort_on_drop());
+        task
+    }
+
+    /// Takes effect with the next turn
+    pub fn set_text_only(&mut self, text_only: bool) -> Result<()> {
+        self.game.data_mut().text_only = text_only;
+        self.save.write_game_data(self.game.data.clone())?;
+        Ok(())
+    }
+
+    pub fn retry_image_download(&self) -> Result<Task<Message>> {
+        let url = self
+            .failed_image_download
+            .clone()
+            .ok_or_else(|| eyre!("There is no failed image download"))?;
+        let turn = self
+            .game
+            .current_turn()
+            .checked_sub(1)
+            .ok_or_else(|| eyre!("No completed turn"))?;
+        let output = &self.game.data.turn_data[turn].output;
+        let caption = output.image_caption.clone();
+        let description = output.image_description.clone();
+        let imgmod = self
+            .game
+            .imgmod
+            .as_deref()
+            .ok_or_else(|| eyre!("There is no image model configured"))?
+            .clone();
+
+        Ok(Task::perform(
+            async move {
+                let bytes = imgmod.download(&url).await.map_err(EngineError::from)?;
+                Ok(Image {
+                    caption,
+                    description,
+                    cost: None,
+                    bytes,
+                    // the download doesn't tell
+                    seed: None,
+                    cache_key: None,
+                    cached: None,
+                    rewrite: None,
+                })
+            },
+            move |res| ContextMessage::ExtraImageReady(turn, res).into(),
+        ))
+    }
+
+    /// Makes another image for the displayed turn, from its image description. It's added to
+    /// the images of the turn, so the old ones stay in the archive. With a `seed`, it looks
+    /// like the image that was made with it.
+    pub fn regenerate_image(&mut self, seed: Option<u64>) -> Result<Task<Message>> {
+        let turn = self
+            .turn_cursor()
+            .map(TurnCursor::viewed)
+            .ok_or_else(|| eyre!("No completed turn is displayed"))?;
+        let image = self.game.regenerate_image(turn, seed)?;
+        self.regenerating_image = true;
+        Ok(Task::perform(
+            async move { image.await.map_err(EngineError::from) },
+            move |res| ContextMessage::ExtraImageReady(turn, res).into(),
+        ))
+    }
+
+    /// Changes the displayed image of the displayed turn as the `instruction` says. The edit
+    /// is added to the images of the turn, so the unedited one stays available.
+    pub fn edit_image(&mut self, instruction: String) -> Result<Task<Message>> {
+        let turn = self
+            .turn_cursor()
+            .map(TurnCursor::viewed)
+            .ok_or_else(|| eyre!("No completed turn is displayed"))?;
+        // the last image of a turn is the displayed one
+        let info = self
+            .game
+            .data
+            .turn_data
+            .get(turn)
+            .and_then(|turn_data| turn_data.images.last())
+            .ok_or_else(|| eyre!("The turn has no image to edit"))?;
+        // the original has more detail than the downscaled image, if it was kept
+        let original = self.save.read_image(info.original_id.unwrap_or(info.id))?;
+        let image = self.game.edit_image(turn, original, instruction)?;
+        self.regenerating_image = true;
+        Ok(Task::perform(
+            async move { image.await.map_err(EngineError::from) },
+            move |res| ContextMessage::ExtraImageReady(turn, res).into(),
+        ))
+    }
+
+    /// Makes a new image for every turn with the current style, see [`Game::restyle_images`].
+    /// They're added as variants, so the displayed images stay until another one is selected.
+    pub fn restyle_images(&mut self) -> Result<Task<Message>> {
+        let jobs = self.game.restyle_images()?;
+        let mut tasks = vec![];
+        for (turn, image) in jobs {
+            let (task, handle) = Task::perform(
+                async move { image.await.map_err(EngineError::from) },
+                move |res| ContextMessage::ImageVariantReady(turn, res).into(),
+            )
+            .abortable();
+            self.variant_jobs.push((turn, handle.abort_on_drop()));
+            tasks.push(task);
+        }
+        Ok(Task::batch(tasks))
+    }
+
+    /// Removes the images no turn refers to from the save, see [`SaveArchive::compact`].
+    /// The images are renumbered, so nothing may be generated meanwhile.
+    pub fn compact_save(&mut self) -> Result<Compaction> {
+        let viewed_turn = match &self.sub_state {
+            SubState::Uninit => None,
+            SubState::Complete(_) | SubState::InThePast(_) => Some(self.current_turn() - 1),
+            SubState::WaitingForOutput(_) | SubState::WaitingForSummary(_) => {
+                bail!("The save can't be compacted while a turn is generated")
+            }
+        };
+        ensure!(
+            !self.regenerating_image && self.variant_jobs.is_empty(),
+            "The save can't be compacted while images are generated"
+        );
+        let compaction = self.save.compact()?;
+        self.thumbnails.clear();
+        self.pending_variants.clear();
+        self.game.data = Arc::new(self.save.read_game_data()?);
+        if let Some(turn) = viewed_turn {
+            self.load_completed_turn(turn)?;
         }
+        Ok(compaction)
     }
 
     /// turn semantics are as follows:
@@ -253,6 +766,12 @@ impl GameContext {
         }
     }
 
+    /// The in-game time after the shown turn
+    pub fn in_game_time(&self) -> InGameTime {
+        let turns = &self.game.data.turn_data;
+        InGameTime::after(&turns[..self.current_turn().min(turns.len())])
+    }
+
     /// loading completed turn n actually means loading turn n+1, but this way it's less confusing
     pub fn load_completed_turn(&mut self, target_turn: usize) -> Result<()> {
         let turn_data = self
@@ -266,14 +785,25 @@ impl GameContext {
             .get_latest_image_info_for_turn(target_turn)
             .map(|info| {
                 color_eyre::eyre::Ok(ImageData {
-                    handle: ImgHandle::from_bytes(self.save.read_image(info.id)?),
+                    handle: sidebar_image(&mut self.thumbnails, &mut self.save, info.id)?,
                     caption: info.caption.clone(),
-                    is_current: turn_data.images.first().map(|i| i.id) == Some(info.id),
+                    is_current: turn_data.images.iter().any(|i| i.id == info.id),
                 })
             })
             .transpose()?;
+        self.image_variants = if turn_data.images.len() > 1 {
+            let mut ids: Vec<_> = turn_data.images.iter().map(|info| info.id).collect();
+            // the order of the images changes when one is selected
+            ids.sort_unstable();
+            ids.into_iter()
+                .map(|id| Ok((id, sidebar_image(&mut self.thumbnails, &mut self.save, id)?)))
+                .collect::<Result<_>>()?
+        } else {
+            vec![]
+        };
         self.output_text = turn_data.output.text.clone();
-        self.output_markdown = markdown::parse(&turn_data.output.text).collect();
+        self.markdown.get_or_parse(target_turn, &turn_data.output.text);
+        self.markdown.evict_far_from(target_turn);
 
         // this looks wrong but is right. If we load the completed turn 0, the displayed output
         // is the ouput of turn 0, but that means we're actually in turn 1
@@ -299,12 +829,12 @@ impl GameContext {
                 completed_turn,
             }) => {
                 data.output.secret_info = val.clone();
-                self.game.data.turn_data[*completed_turn].output.secret_info = val;
+                self.game.data_mut().turn_data[*completed_turn].output.secret_info = val;
             }
             SubState::Complete(Complete { turn_data }) => {
                 turn_data.output.secret_info = val.clone();
                 self.game
-                    .data
+                    .data_mut()
                     .turn_data
                     .last_mut()
                     .unwrap()
@@ -314,7 +844,7 @@ impl GameContext {
             other => bail!("Invalid substate when seeing UpdateHiddenInfo: {other:#?}",),
         }
 
-        self.save.write_game_data(&self.game.data)?;
+        self.save.write_game_data(self.game.data.clone())?;
         Ok(())
     }
 
@@ -325,32 +855,49 @@ impl GameContext {
                 completed_turn,
             }) => {
                 data.output.text = val.clone();
-                self.game.data.turn_data[*completed_turn].output.text = val.clone();
+                self.game.data_mut().turn_data[*completed_turn].output.text = val.clone();
             }
             SubState::Complete(Complete { turn_data }) => {
                 turn_data.output.text = val.clone();
-                self.game.data.turn_data.last_mut().unwrap().output.text = val.clone();
+                self.game.data_mut().turn_data.last_mut().unwrap().output.text = val.clone();
             }
             other => bail!("Invalid substate when seeing UpdateHiddenInfo: {other:#?}",),
         }
 
         self.output_text = val;
-        self.output_markdown = markdown::parse(&self.output_text).collect();
-        self.save.write_game_data(&self.game.data)?;
+        if let Some(cursor) = self.turn_cursor() {
+            self.markdown.get_or_parse(cursor.viewed(), &self.output_text);
+        }
+        self.save.write_game_data(self.game.data.clone())?;
         Ok(())
     }
 
-    fn request_summary(&mut self, turn: FinalizingTurn) -> Result<Task<Message>> {
+    fn request_summary(&mut self) -> Task<Message> {
         debug!(
             "Requesting summary for generation {}",
             self.current_generation
         );
-        self.sub_state = turn.into();
-        let fut = self.game.mk_summary_if_neccessary();
         let generation = self.current_generation;
-        Ok(Task::perform(fut, move |res| {
+        if self.game.batch_summaries {
+            // the turn completes without the summary, it's added whenever it arrives
+            let no_summary =
+                Task::done(ContextMessage::SummaryFinished(generation, Ok(None)).into());
+            if self.summary_job.is_some() {
+                return no_summary;
+            }
+            let bday = self.game.current_turn();
+            let (summary_task, handle) =
+                Task::perform(self.game.mk_summary_if_neccessary(), move |res| {
+                    ContextMessage::BackgroundSummaryFinished(bday, res).into()
+                })
+                .abortable();
+            self.summary_job = Some(handle.abort_on_drop());
+            return Task::batch([no_summary, summary_task]);
+        }
+        let fut = self.game.mk_summary_if_neccessary();
+        Task::perform(fut, move |res| {
             ContextMessage::SummaryFinished(generation, res).into()
-        }))
+        })
     }
 
     fn apply_resolution(&mut self, resolution: Resolution) -> Result<Task<Message>> {
@@ -360,77 +907,433 @@ impl GameContext {
                 self.sub_state = turn.into();
                 Ok(Task::none())
             }
-            Resolution::Finalizing(turn) => {
-                debug!("Turn has output and image result, moving to summary/finalization");
-                self.request_summary(turn)
+            Resolution::Finalizing(turn) => match turn.summary.clone() {
+                SummaryState::Ready(summary) => self.complete_turn(turn, summary),
+                SummaryState::Pending => {
+                    debug!("Turn has output and image result, waiting for the summary");
+                    self.sub_state = turn.into();
+                    Ok(Task::none())
+                }
+            },
+        }
+    }
+
+    fn complete_turn(
+        &mut self,
+        turn: FinalizingTurn,
+        summary: Option<OutputMessage>,
+    ) -> Result<Task<Message>> {
+        let FinalizingTurn {
+            input,
+            output,
+            image,
+            ..
+        } = turn;
+
+        self.turn_cancel = None;
+        self.game
+            .update(input, output.clone(), image.into_iter().collect(), summary)?;
+        for info in mem::take(&mut self.pending_variants) {
+            let turn_data = self.game.data_mut().turn_data.last_mut().unwrap();
+            turn_data.add_image_variant(info);
+        }
+        self.save.write_game_data(self.game.data.clone())?;
+        let turn = self.game.data.turn_data.len() - 1;
+        if let Some(log) = &self.game.audit_log {
+            let entries = log.take_pending();
+            if !entries.is_empty() {
+                self.save.append_transcript(turn, entries)?;
             }
         }
+        let markdown = mem::take(&mut self.streaming_markdown);
+        self.markdown.insert(turn, &output.text, markdown);
+        self.markdown.evict_far_from(turn);
+        self.sub_state = Complete {
+            turn_data: self.game.data.turn_data.last().unwrap().clone(),
+        }
+        .into();
+        debug!(
+            "Turn finalized for generation {}, sending ClearActionEditors",
+            self.current_generation
+        );
+        self.current_generation += 1;
+        Ok(Task::done(PlayingMessage::ClearActionEditors.into()))
     }
 
-    pub fn generate_new_turn(&mut self, input: TurnInput) -> Task<Message> {
-        self.output_markdown.clear();
+    pub fn generate_new_turn(&mut self, input: TurnInput) -> Result<Task<Message>> {
+        let advance_result = self.game.send_to_llm(input.clone());
+        self.begin_turn(InFlightTurn::new(input), advance_result)
+    }
+
+    /// Starts receiving a turn, and records it as in-flight turn in the archive
+    fn begin_turn(
+        &mut self,
+        mut in_flight: InFlightTurn,
+        mut advance_result: AdvanceResult,
+    ) -> Result<Task<Message>> {
+        self.failed_image_download = None;
+        self.image_variants.clear();
+        self.streaming_markdown.clear();
         self.output_text.clear();
+        self.thoughts.clear();
+
+        let image_state = if let Some(info) = &in_flight.image {
+            // an interrupted turn is resumed, and the image was already paid for
+            advance_result.image = None;
+            advance_result.image_variants.clear();
+            self.image_data = Some(ImageData {
+                handle: sidebar_image(&mut self.thumbnails, &mut self.save, info.id)?,
+                caption: info.caption.clone(),
+                is_current: true,
+            });
+            ImageState::Ready(info.clone())
+        } else if advance_result.image.is_some() {
+            ImageState::Pending
+        } else {
+            ImageState::Skipped
+        };
+
+        in_flight.partial_text.clear();
+        self.sub_state = PendingTurn::new(in_flight.input.clone(), image_state).into();
+        self.game.data_mut().in_flight_turn = Some(in_flight);
+        self.in_flight_persisted_len = 0;
+        self.save.write_game_data(self.game.data.clone())?;
+        Ok(self.advance_tasks(advance_result))
+    }
+
+    /// The last session ended while a turn was being generated. An interrupted first turn
+    /// isn't reported, since it's generated again on `Init` anyway.
+    pub fn has_interrupted_turn(&self) -> bool {
+        self.game.data.in_flight_turn.is_some()
+            && !self.game.is_empty()
+            && !matches!(
+                self.sub_state,
+                SubState::WaitingForOutput(_) | SubState::WaitingForSummary(_)
+            )
+    }
+
+    /// Generates the interrupted turn again. If its image was received, it's reused.
+    pub fn resume_interrupted_turn(&mut self) -> Result<Task<Message>> {
+        ensure!(self.has_interrupted_turn(), "There is no interrupted turn");
+        let in_flight = self.game.data_mut().in_flight_turn.take().unwrap();
+        let advance_result = self.game.send_to_llm(in_flight.input.clone());
+        self.begin_turn(in_flight, advance_result)
+    }
+
+    /// Stops waiting for the image of the pending turn, which then completes without one
+    pub fn cancel_image(&mut self) -> Result<Task<Message>> {
+        let job = self
+            .image_job
+            .take()
+            .ok_or_else(|| eyre!("No image is being generated"))?;
+        job.abort();
+        self.image_stuck = false;
+        self.image_progress = None;
+        if let Some(img_data) = &mut self.image_data {
+            img_data.is_current = false;
+        }
+        let pending_turn: PendingTurn = self.sub_state.take().try_into_ex()?;
+        self.apply_resolution(pending_turn.fail_image())
+    }
+
+    /// Whether the turn that is being received can be stopped, which requires a completed
+    /// turn that can be shown instead
+    pub fn can_cancel_turn(&self) -> bool {
+        matches!(self.sub_state, SubState::WaitingForOutput(_)) && self.current_turn() > 0
+    }
+
+    /// Stops the turn that is being received, and shows the latest completed one again
+    pub fn cancel_turn(&mut self) -> Result<()> {
+        ensure!(self.can_cancel_turn(), "There is no turn that can be cancelled");
+        debug!("Cancelling generation {}", self.current_generation);
+        self.abandon_turn()
+    }
+
+    /// Forgets the pending turn, and shows the latest completed one again
+    fn abandon_turn(&mut self) -> Result<()> {
+        self.current_generation += 1;
+        if let Some(cancel) = self.turn_cancel.take() {
+            cancel.cancel();
+        }
+        if let Some(job) = self.image_job.take() {
+            job.abort();
+        }
+        self.image_stuck = false;
+        self.image_progress = None;
+        self.thoughts.clear();
+        // the requests of the abandoned turn mustn't end up in the transcript of the next one
+        if let Some(log) = &self.game.audit_log {
+            log.take_pending();
+        }
+        self.drop_image_variants_from(self.game.data.turn_data.len());
+        self.game.data_mut().in_flight_turn = None;
+        self.save.write_game_data(self.game.data.clone())?;
+        let turn = self.current_turn();
+        if turn > 0 {
+            self.load_completed_turn(turn - 1)?;
+        }
+        Ok(())
+    }
+
+    /// Completes the interrupted turn with the text and image that were received
+    pub fn finalize_interrupted_turn(&mut self) -> Result<()> {
+        ensure!(self.has_interrupted_turn(), "There is no interrupted turn");
+        self.game.finalize_in_flight_turn()?;
+        self.save.write_game_data(self.game.data.clone())?;
+        self.load_completed_turn(self.game.current_turn() - 1)
+    }
+
+    /// Forwards the results of a turn to the context, tagged with the generation
+    fn advance_tasks(&mut self, advance_result: AdvanceResult) -> Task<Message> {
+        let generation = self.current_generation;
         let AdvanceResult {
             text_stream,
+            thought_stream,
+            image_progress,
             round_output,
             image,
-        } = self.game.send_to_llm(input.clone());
-        self.sub_state = PendingTurn::new(input).into();
-        let generation = self.current_generation;
-        Task::batch([
+            image_variants,
+            cancel,
+        } = advance_result;
+        self.turn_cancel = Some(cancel);
+        let mut tasks = vec![
             Task::perform(round_output, move |x| {
                 ContextMessage::OutputComplete(generation, x).into()
             }),
-            Task::perform(image, move |x| {
-                ContextMessage::ImageReady(generation, x).into()
-            }),
             Task::run(text_stream, move |x| {
                 ContextMessage::NewTextFragment(generation, x).into()
             }),
-        ])
+            Task::run(thought_stream, move |x| {
+                ContextMessage::NewThoughtFragment(generation, x).into()
+            }),
+        ];
+        let turn = self.game.data.turn_data.len();
+        for variant in image_variants {
+            let (variant_task, handle) = Task::perform(variant, move |x| {
+                ContextMessage::ImageVariantReady(turn, x).into()
+            })
+            .abortable();
+            self.variant_jobs.push((turn, handle.abort_on_drop()));
+            tasks.push(variant_task);
+        }
+        if let Some(image) = image {
+            let (image_task, handle) = Task::perform(image, move |x| {
+                ContextMessage::ImageReady(generation, x).into()
+            })
+            .abortable();
+            if let Some(old_job) = self.image_job.replace(handle) {
+                old_job.abort();
+            }
+            self.image_stuck = false;
+            self.image_progress = None;
+            tasks.extend([
+                image_task,
+                Task::run(image_progress, move |x| {
+                    ContextMessage::ImageProgressed(generation, x).into()
+                }),
+                Task::perform(tokio::time::sleep(IMAGE_WATCHDOG_TIMEOUT), move |_| {
+                    ContextMessage::ImageWatchdog(generation).into()
+                }),
+            ]);
+        }
+        Task::batch(tasks)
     }
 
-    pub fn load_prev_turn(&mut self) -> Result<()> {
-        let target_turn = match &self.sub_state {
-            SubState::Complete(_) => self.game.current_turn() - 2,
-            SubState::InThePast(InThePast {
-                completed_turn: turn,
-                ..
-            }) => *turn - 1,
-            other => bail!(
-                "PrevTurnButtonPressed but Substate is not Complete or InThePast: {:?}",
-                other
-            ),
+    /// The position of the displayed turn. It's `None` while there is no completed turn
+    /// on screen, e.g. while a turn is being generated.
+    pub fn turn_cursor(&self) -> Option<TurnCursor> {
+        let n_completed = self.game.data.turn_data.len();
+        match &self.sub_state {
+            SubState::Complete(_) => TurnCursor::new(n_completed.checked_sub(1)?, n_completed),
+            SubState::InThePast(InThePast { completed_turn, .. }) => {
+                TurnCursor::new(*completed_turn, n_completed)
+            }
+            _ => None,
+        }
+    }
+
+    fn move_cursor(&mut self, f: impl FnOnce(TurnCursor) -> Option<TurnCursor>) -> Result<()> {
+        let cursor = self
+            .turn_cursor()
+            .ok_or_else(|| eyre!("Can't navigate turns while being: {:?}", self.sub_state))?;
+        let target = f(cursor).ok_or_else(|| eyre!("There is no such turn"))?;
+        self.load_completed_turn(target.viewed())
+    }
+
+    /// Moves the cursor, and loads the neighbours of the new turn in the background
+    fn browse(
+        &mut self,
+        f: impl FnOnce(TurnCursor) -> Option<TurnCursor>,
+    ) -> Result<Task<Message>> {
+        self.move_cursor(f)?;
+        self.prefetch_neighbours()
+    }
+
+    pub fn load_prev_turn(&mut self) -> Result<Task<Message>> {
+        self.browse(TurnCursor::prev)
+    }
+
+    pub fn load_next_turn(&mut self) -> Result<Task<Message>> {
+        self.browse(TurnCursor::next)
+    }
+
+    pub fn load_latest_turn(&mut self) -> Result<Task<Message>> {
+        self.browse(|c| Some(c.latest()))
+    }
+
+    /// `turn` is the turn number as displayed, which starts at 1
+    pub fn goto_turn(&mut self, turn: usize) -> Result<Task<Message>> {
+        self.browse(|c| c.goto(turn.checked_sub(1)?))
+    }
+
+    /// Decodes the images and parses the outputs of the turns before and after the viewed one,
+    /// so browsing doesn't wait for disk and decoder
+    fn prefetch_neighbours(&mut self) -> Result<Task<Message>> {
+        let Some(cursor) = self.turn_cursor() else {
+            return Ok(Task::none());
         };
-        self.load_completed_turn(target_turn)
+        let mut tasks = vec![];
+        for neighbour in [cursor.prev(), cursor.next()].into_iter().flatten() {
+            let turn = neighbour.viewed();
+            let text = &self.game.data.turn_data[turn].output.text;
+            if !self.markdown.contains(turn, text) {
+                let text = text.clone();
+                tasks.push(Task::perform(
+                    async move {
+                        let markdown = parse_output(&text);
+                        (text, markdown)
+                    },
+                    move |(text, markdown)| {
+                        ContextMessage::PrefetchedMarkdown(turn, text, markdown).into()
+                    },
+                ));
+            }
+
+            let Some(id) = self
+                .game
+                .get_latest_image_info_for_turn(turn)
+                .map(|info| info.id)
+            else {
+                continue;
+            };
+            if self.thumbnails.contains(id) {
+                continue;
+            }
+            let bytes = self.save.read_image_later(id)?;
+            let max_side = self.thumbnails.max_side();
+            tasks.push(Task::perform(
+                async move { Ok(make_thumbnail(&bytes.await?, max_side)?) },
+                move |thumbnail| ContextMessage::PrefetchedImage(id, thumbnail).into(),
+            ));
+        }
+        Ok(Task::batch(tasks))
     }
 
-    pub fn load_next_turn(&mut self) -> Result<()> {
-        let target_turn = match &self.sub_state {
-            SubState::InThePast(InThePast {
-                completed_turn: turn,
-                ..
-            }) => *turn + 1,
-            other => bail!(
-                "PrevTurnButtonPressed but Substate is not Complete or InThePast: {:?}",
-                other
+    /// Writes a copy of the save that ends with the turn that is viewed
+    pub fn fork_from_current_past(&mut self, path: &Path) -> Result<()> {
+        let SubState::InThePast(InThePast { completed_turn, .. }) = &self.sub_state else {
+            bail!("Only a past turn can be branched from");
+        };
+        self.save.fork_clipped(path.to_path_buf(), *completed_turn)?;
+        Ok(())
+    }
+
+    /// Continues the game after the viewed turn in a new branch. The later turns are kept
+    /// in the branch that was current, see `engine::game::Branch`.
+    pub fn continue_in_new_branch(&mut self, name: String) -> Result<()> {
+        let SubState::InThePast(InThePast { completed_turn, .. }) = &self.sub_state else {
+            bail!("A new branch can only start at a past turn");
+        };
+        let turn = *completed_turn;
+        self.save.fork_after(turn, name)?;
+        self.reload_branches(turn)
+    }
+
+    /// Continues the game with another branch, see `engine::game::Branch`
+    pub fn switch_branch(&mut self, index: usize) -> Result<()> {
+        ensure!(
+            matches!(
+                self.sub_state,
+                SubState::Complete(_) | SubState::InThePast(_)
             ),
+            "The branch can't be switched while a turn is generated"
+        );
+        let forked_after = self
+            .game
+            .data
+            .branches
+            .get(index)
+            .ok_or_else(|| eyre!("There is no branch {index}"))?
+            .forked_after;
+        self.save.switch_branch(index)?;
+        self.reload_branches(forked_after)
+    }
+
+    /// Reads the game data again after the turns after `turn` were exchanged with another
+    /// branch, and shows the latest turn
+    fn reload_branches(&mut self, turn: usize) -> Result<()> {
+        self.markdown.clear();
+        self.failed_image_download = None;
+        self.game.data = Arc::new(self.save.read_game_data()?);
+        self.drop_image_variants_from(turn + 1);
+        self.load_completed_turn(self.game.data.turn_data.len() - 1)
+    }
+
+    /// The requests and responses of the viewed turn, as markdown. `None` if none were
+    /// stored, e.g. because the turn was played before transcripts were.
+    pub fn transcript_for_current_turn(&mut self) -> Result<Option<String>> {
+        let Some(turn) = self.current_turn().checked_sub(1) else {
+            return Ok(None);
         };
-        self.load_completed_turn(target_turn)
+        let transcript = self.save.read_transcript(turn)?;
+        Ok(transcript.map(|entries| {
+            entries
+                .iter()
+                .map(AuditEntry::to_markdown)
+                .collect::<Vec<_>>()
+                .join("\n")
+        }))
     }
 
+    /// Continues the game after the viewed turn in a new branch with a generated name. The
+    /// later turns are kept in the branch that was current, see `engine::game::Branch`.
     pub fn load_from_current_past(&mut self) -> Result<()> {
         let InThePast {
             completed_turn,
             data,
         } = self.sub_state.take().try_into_ex()?;
 
-        self.save.clip_after_turn(completed_turn)?;
-        self.game.data = self.save.read_game_data()?;
+        let name = self.game.data.unused_branch_name(completed_turn);
+        self.save.fork_after(completed_turn, name)?;
+        self.markdown.clear();
+        self.markdown.get_or_parse(completed_turn, &data.output.text);
+        self.failed_image_download = None;
+        self.game.data = Arc::new(self.save.read_game_data()?);
+        self.drop_image_variants_from(completed_turn + 1);
         self.sub_state = Complete { turn_data: data }.into();
         Ok(())
     }
 
+    /// Removes the latest turn, together with its images, and shows the one before it
+    pub fn undo_last_turn(&mut self) -> Result<()> {
+        ensure!(
+            matches!(self.sub_state, SubState::Complete(_)),
+            "Only the latest turn can be undone"
+        );
+        self.game.undo_last_turn(&mut self.save)?;
+        let turn = self.game.data.turn_data.len() - 1;
+        self.thumbnails.clear();
+        self.markdown.clear();
+        self.failed_image_download = None;
+        self.drop_image_variants_from(turn + 1);
+        self.load_completed_turn(turn)
+    }
+
+    /// The stored image with the given id, in the size of the sidebar
+    pub fn image_handle(&mut self, id: usize) -> Result<ImgHandle> {
+        sidebar_image(&mut self.thumbnails, &mut self.save, id)
+    }
+
     pub fn hidden_info(&self) -> Result<&str> {
         Ok(match &self.sub_state {
             SubState::InThePast(InThePast { data, .. }) => &data.output.secret_info,
@@ -447,6 +1350,15 @@ impl GameContext {
         })
     }
 
+    /// The dice rolls and skill checks of the shown turn
+    pub fn rolls(&self) -> &[String] {
+        match &self.sub_state {
+            SubState::InThePast(InThePast { data, .. }) => &data.output.rolls,
+            SubState::Complete(Complete { turn_data }) => &turn_data.output.rolls,
+            _ => &[],
+        }
+    }
+
     fn summary_idx_for_current_turn(&self) -> Result<Option<usize>> {
         let turn = self.current_turn();
         if turn < self.game.current_turn() {
@@ -472,12 +1384,12 @@ impl GameContext {
             .summary_idx_for_current_turn()?
             .ok_or(eyre!("No summary is available for this turn"))?;
         self.game
-            .data
+            .data_mut()
             .summaries
             .get_mut(summary_idx)
             .ok_or(eyre!("Invalid summary index: {summary_idx}"))?
             .content = val;
-        self.save.write_game_data(&self.game.data)?;
+        self.save.write_game_data(self.game.data.clone())?;
         Ok(())
     }
 
@@ -491,13 +1403,15 @@ impl GameContext {
         })
     }
 
+    /// Generates the latest turn again, changed as `s` says. The previous version is kept in
+    /// a branch.
     pub fn regenerate_turn(&mut self, s: String) -> Result<Task<Message>> {
         let last_turn = self.sub_state.turn_data()?;
         let last_output = last_turn.output.text.clone();
         let last_input = last_turn.input.player_action.clone();
-        self.load_prev_turn()?;
+        self.move_cursor(TurnCursor::prev)?;
         self.load_from_current_past()?;
-        Ok(self.generate_new_turn(TurnInput {
+        self.generate_new_turn(TurnInput {
             player_action: last_input,
             gm_instruction: indoc::formatdoc!(
                 "
@@ -510,16 +1424,63 @@ impl GameContext {
                         Use that as base for what should happen, but modify it like this:
                         {s}"
             ),
-        }))
+        })
+    }
+
+    /// Replaces the player action of the viewed past turn, and generates it again in a new
+    /// branch. The turns from it on are kept in the branch that was current.
+    pub fn replay_from_current_past(&mut self, player_action: String) -> Result<Task<Message>> {
+        let SubState::InThePast(InThePast {
+            completed_turn,
+            data,
+        }) = &self.sub_state
+        else {
+            bail!("Only a past turn can be replayed");
+        };
+        ensure!(*completed_turn > 0, "The first turn can't be replayed");
+        let input = TurnInput {
+            player_action,
+            gm_instruction: data.input.gm_instruction.clone(),
+        };
+        self.move_cursor(TurnCursor::prev)?;
+        self.load_from_current_past()?;
+        self.generate_new_turn(input)
     }
 
     pub(crate) fn upate_world_description(&mut self, world: WorldDescription) -> Result<()> {
-        self.game.data.world_description = world;
-        self.save.write_game_data(&self.game.data)?;
+        self.game.data_mut().world_description = world;
+        self.save.write_game_data(self.game.data.clone())?;
         Ok(())
     }
 
+    /// The parsed output that is displayed, either of a completed turn, or of the turn that
+    /// is being received
+    pub fn output_markdown(&self) -> &[markdown::Item] {
+        match self.turn_cursor() {
+            Some(cursor) => self.markdown.get(cursor.viewed()).unwrap_or_default(),
+            None => &self.streaming_markdown,
+        }
+    }
+
     pub fn set_output_scroll_y(&mut self, y: f32) {
         self.output_scroll_y = y.clamp(0.0, 1.0);
     }
 }
+
+/// LLM output is sanitized before it's rendered
+fn parse_output(text: &str) -> Vec<markdown::Item> {
+    markdown::parse(&sanitize_markdown(text)).collect()
+}
+
+/// Decodes a stored image for the sidebar, or takes it from the cache
+fn sidebar_image(
+    thumbnails: &mut ThumbnailCache,
+    save: &mut ArchiveWorker,
+    id: usize,
+) -> Result<ImgHandle> {
+    Ok(to_handle(thumbnails.get_or_load(id, || save.read_image(id))?))
+}
+
+pub fn to_handle(thumbnail: &Thumbnail) -> ImgHandle {
+    ImgHandle::from_rgba(thumbnail.width, thumbnail.height, thumbnail.rgba.clone())
+}
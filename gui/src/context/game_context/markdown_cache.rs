@@ -0,0 +1,73 @@
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use iced::widget::markdown;
+
+use super::parse_output;
+
+/// Turns further away from the viewed one than this are evicted
+const KEEP_RADIUS: usize = 2;
+
+/// The parsed outputs of completed turns, keyed like `GameData::turn_data`. An output is
+/// parsed when it's viewed or prefetched for the first time, and only the turns around the
+/// viewed one are kept.
+#[derive(Debug, Default)]
+pub struct MarkdownCache {
+    entries: BTreeMap<usize, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// identifies the text the items were parsed from, outputs can be edited
+    fingerprint: u64,
+    items: Vec<markdown::Item>,
+}
+
+impl MarkdownCache {
+    /// Returns the parsed output of `turn`, parsing `text` if it wasn't cached yet
+    pub fn get_or_parse(&mut self, turn: usize, text: &str) -> &[markdown::Item] {
+        if !self.contains(turn, text) {
+            self.insert(turn, text, parse_output(text));
+        }
+        &self.entries[&turn].items
+    }
+
+    pub fn get(&self, turn: usize) -> Option<&[markdown::Item]> {
+        self.entries.get(&turn).map(|e| e.items.as_slice())
+    }
+
+    /// Whether the cached items of `turn` were parsed from `text`
+    pub fn contains(&self, turn: usize, text: &str) -> bool {
+        self.entries
+            .get(&turn)
+            .is_some_and(|e| e.fingerprint == fingerprint(text))
+    }
+
+    /// Adds items that were parsed elsewhere, e.g. while the output was streamed
+    pub fn insert(&mut self, turn: usize, text: &str, items: Vec<markdown::Item>) {
+        self.entries.insert(
+            turn,
+            Entry {
+                fingerprint: fingerprint(text),
+                items,
+            },
+        );
+    }
+
+    pub fn evict_far_from(&mut self, turn: usize) {
+        self.entries
+            .retain(|cached, _| cached.abs_diff(turn) <= KEEP_RADIUS);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+fn fingerprint(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
@@ -1,4 +1,7 @@
-use engine::game::{Image, TurnInput, TurnOutput};
+use engine::{
+    game::{StoredImageInfo, TurnInput, TurnOutput},
+    llm::OutputMessage,
+};
 
 #[derive(Debug, Clone)]
 pub struct PendingTurn {
@@ -6,21 +9,37 @@ pub struct PendingTurn {
     pub input: TurnInput,
     pub output: Option<TurnOutput>,
     pub image: ImageState,
+    pub summary: SummaryState,
 }
 
+/// A turn whose output and image are received
 #[derive(Debug, Clone)]
 pub struct FinalizingTurn {
     pub input: TurnInput,
     pub output: TurnOutput,
-    pub image: Option<Image>,
+    pub image: Option<StoredImageInfo>,
+    pub summary: SummaryState,
 }
 
 #[derive(Debug, Default, Clone)]
 pub enum ImageState {
     #[default]
     Pending,
-    Ready(Image),
+    /// the image is already stored in the archive
+    Ready(StoredImageInfo),
     Failed,
+    /// There is no image model, the turn is text-only
+    Skipped,
+}
+
+/// The summary is requested as soon as the output is complete, so it's generated while the
+/// image is still rendering
+#[derive(Debug, Default, Clone)]
+pub enum SummaryState {
+    #[default]
+    Pending,
+    /// `None` if no new summary was necessary
+    Ready(Option<OutputMessage>),
 }
 
 pub enum Resolution {
@@ -29,12 +48,13 @@ pub enum Resolution {
 }
 
 impl PendingTurn {
-    pub fn new(input: TurnInput) -> Self {
+    pub fn new(input: TurnInput, image: ImageState) -> Self {
         Self {
             stream_buffer: String::new(),
             input,
             output: None,
-            image: ImageState::Pending,
+            image,
+            summary: SummaryState::Pending,
         }
     }
 
@@ -48,11 +68,13 @@ impl PendingTurn {
                 input: self.input,
                 output,
                 image: Some(image),
+                summary: self.summary,
             }),
-            ImageState::Failed => Resolution::Finalizing(FinalizingTurn {
+            ImageState::Failed | ImageState::Skipped => Resolution::Finalizing(FinalizingTurn {
                 input: self.input,
                 output,
                 image: None,
+                summary: self.summary,
             }),
             ImageState::Pending => Resolution::Pending(Self {
                 output: Some(output),
@@ -61,12 +83,13 @@ impl PendingTurn {
         }
     }
 
-    pub fn finish_image(self, image: Image) -> Resolution {
+    pub fn finish_image(self, image: StoredImageInfo) -> Resolution {
         match self.output {
             Some(output) => Resolution::Finalizing(FinalizingTurn {
                 input: self.input,
                 output,
                 image: Some(image),
+                summary: self.summary,
             }),
             None => Resolution::Pending(Self {
                 image: ImageState::Ready(image),
@@ -81,6 +104,7 @@ impl PendingTurn {
                 input: self.input,
                 output,
                 image: None,
+                summary: self.summary,
             }),
             None => Resolution::Pending(Self {
                 image: ImageState::Failed,
@@ -88,4 +112,12 @@ impl PendingTurn {
             }),
         }
     }
+
+    /// Stores the summary until the image is received
+    pub fn finish_summary(self, summary: Option<OutputMessage>) -> Self {
+        Self {
+            summary: SummaryState::Ready(summary),
+            ..self
+        }
+    }
 }
@@ -0,0 +1,51 @@
+/// Points at the completed turn whose output is displayed. Completed turns are indexed like
+/// `GameData::turn_data`, so the displayed turn number is `viewed() + 1`.
+/// All navigation goes through this type, so the bounds are checked in one place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurnCursor {
+    viewed: usize,
+    latest: usize,
+}
+
+impl TurnCursor {
+    /// Returns `None` if `viewed` is not a completed turn
+    pub fn new(viewed: usize, n_completed_turns: usize) -> Option<Self> {
+        let latest = n_completed_turns.checked_sub(1)?;
+        (viewed <= latest).then_some(Self { viewed, latest })
+    }
+
+    pub fn viewed(self) -> usize {
+        self.viewed
+    }
+
+    pub fn is_latest(self) -> bool {
+        self.viewed == self.latest
+    }
+
+    pub fn has_prev(self) -> bool {
+        self.viewed > 0
+    }
+
+    pub fn has_next(self) -> bool {
+        self.viewed < self.latest
+    }
+
+    pub fn prev(self) -> Option<Self> {
+        self.goto(self.viewed.checked_sub(1)?)
+    }
+
+    pub fn next(self) -> Option<Self> {
+        self.goto(self.viewed + 1)
+    }
+
+    pub fn goto(self, completed_turn: usize) -> Option<Self> {
+        Self::new(completed_turn, self.latest + 1)
+    }
+
+    pub fn latest(self) -> Self {
+        Self {
+            viewed: self.latest,
+            ..self
+        }
+    }
+}
@@ -4,16 +4,24 @@ use std::{
 };
 
 use color_eyre::{
-    Result,
+    Report, Result,
     eyre::{WrapErr as _, eyre},
 };
+use engine::{
+    error::EngineError,
+    image_model::{
+        manual::{self, ManualRequest},
+        queue,
+    },
+};
 use iced::{
-    Element, Font, Length, Task, Theme,
+    Element, Font, Length, Subscription, Task, Theme,
     font::{self},
+    futures::{Stream, StreamExt},
     padding,
     widget::{Id, container, operation, scrollable, text},
 };
-use log::debug;
+use log::{debug, warn};
 use serde::{Serialize, de::DeserializeOwned};
 
 use crate::{
@@ -22,7 +30,6 @@ use crate::{
     state::{Modal, State, StateExt, options_menu::OptionsMenu},
 };
 
-pub mod cli;
 pub mod context;
 pub mod message;
 pub mod state;
@@ -63,7 +70,7 @@ impl Gui {
         match self.try_update(message) {
             Ok(task) => task,
             Err(e) => {
-                self.state = Modal::message(self.state.clone(), "Error", format!("{e:?}")).boxed();
+                self.state = error_dialog(self.state.clone(), &e);
                 Task::none()
             }
         }
@@ -72,6 +79,26 @@ impl Gui {
     fn try_update(&mut self, message: Message) -> Result<Task<Message>> {
         match message {
             Message::Ui(ui_message) => {
+                if matches!(ui_message, message::UiMessage::OpenOptions) {
+                    self.state = OptionsMenu::new(&self.ctx.config)?.boxed();
+                    return Ok(Task::none());
+                }
+                if matches!(ui_message, message::UiMessage::ShowRecap) {
+                    if let Some(recap) = self
+                        .ctx
+                        .game
+                        .as_ref()
+                        .and_then(|g| g.game.data.recap.as_ref())
+                    {
+                        self.state = Modal::message(
+                            self.state.clone(),
+                            format!("Previously on {}", recap.title),
+                            &recap.text,
+                        )
+                        .boxed();
+                    }
+                    return Ok(Task::none());
+                }
                 if matches!(
                     ui_message,
                     message::UiMessage::Playing(message::ui_messages::Playing::ClearActionEditors)
@@ -80,6 +107,23 @@ impl Gui {
                     debug!("Dropping ClearActionEditors because active state is not Playing");
                     return Ok(Task::none());
                 }
+                if matches!(
+                    ui_message,
+                    message::UiMessage::Playing(
+                        message::ui_messages::Playing::RefreshPromptEstimate
+                    )
+                ) && !self.state.is_playing()
+                {
+                    return Ok(Task::none());
+                }
+                if matches!(
+                    ui_message,
+                    message::UiMessage::Gallery(message::ui_messages::Gallery::ThumbnailLoaded(..))
+                ) && !self.state.is_gallery()
+                {
+                    debug!("Dropping a thumbnail because the gallery was closed");
+                    return Ok(Task::none());
+                }
                 if matches!(
                     ui_message,
                     message::UiMessage::Playing(message::ui_messages::Playing::ClearActionEditors)
@@ -92,7 +136,21 @@ impl Gui {
                     .map(|t| t.map(Message::from))
                     .unwrap_or(Task::none());
                 if let Some(new_state) = cmd.transition {
-                    self.state = new_state;
+                    let enters_game = new_state.is_playing();
+                    self.state = self.with_notices(new_state);
+                    if enters_game {
+                        // the turns or the settings may have changed in the meantime
+                        task = task.chain(Task::done(
+                            message::ui_messages::Playing::RefreshPromptEstimate.into(),
+                        ));
+                    }
+                    if enters_game
+                        && let Some(gctx) = &mut self.ctx.game
+                        && !gctx.recap_prompted
+                    {
+                        gctx.recap_prompted = true;
+                        task = Task::batch([task, gctx.refresh_recap(true)]);
+                    }
                     // Keep Playing's output scroll position stable across state transitions.
                     // In iced, restoring scroll position is done via widget operations/tasks,
                     // so we centralize it here instead of scattering restore calls in states.
@@ -109,7 +167,67 @@ impl Gui {
                 Ok(task)
             }
             Message::Context(context_message) => self.ctx.update(context_message),
+            Message::ImageJobs(jobs) => {
+                self.ctx.image_jobs = jobs;
+                Ok(Task::none())
+            }
+            Message::ManualImage(request) => Ok(pick_image_file(request)),
+        }
+    }
+
+    pub fn subscription(&self) -> Subscription<Message> {
+        Subscription::batch([
+            Subscription::run(image_jobs),
+            Subscription::run(manual_image_requests),
+        ])
+    }
+
+    /// When entering the Playing state, some things need the user's attention once
+    fn with_notices(&mut self, mut state: Box<dyn State>) -> Box<dyn State> {
+        let Some(gctx) = &mut self.ctx.game else {
+            return state;
+        };
+        if !state.is_playing() {
+            return state;
+        }
+
+        if gctx.has_interrupted_turn() && !gctx.interrupted_turn_prompted {
+            gctx.interrupted_turn_prompted = true;
+            state = Modal::confirm(
+                state,
+                indoc::indoc! {"
+                    World Weaver was closed while the last turn was generated.
+                    Do you want to generate it again? Choose \"No\" to keep what was received
+                    so far. A received image is kept either way."
+                },
+                Some(message::ui_messages::Playing::ResumeInterruptedTurn.into()),
+                Some(message::ui_messages::Playing::FinalizeInterruptedTurn.into()),
+            )
+            .boxed();
         }
+
+        // The notice for the interrupted turn is below this one, so its answer reaches
+        // the Playing state
+        if gctx.game.imgmod.is_none() && !self.ctx.text_only_warning_shown {
+            self.ctx.text_only_warning_shown = true;
+            warn!("No token for the selected image model, playing without images");
+            state = Self::text_only_notice(state);
+        }
+        state
+    }
+
+    /// The first time a game is played without an image model, explain why there are no images
+    fn text_only_notice(state: Box<dyn State>) -> Box<dyn State> {
+        Modal::message(
+            state,
+            "No Images",
+            indoc::indoc! {"
+                There is no API-key for the selected image model, so the game will be text-only.
+                You can add a key in the options at any time, images will be generated from the
+                next turn on."
+            },
+        )
+        .boxed()
     }
 
     pub fn view(&self) -> Element<'_, message::Message> {
@@ -121,8 +239,103 @@ impl Gui {
     }
 }
 
-pub const CLAUDE_MODEL: &str = "claude-sonnet-4-5";
-pub const PERSISTENT_INFO_NAME: &str = "persisted_info";
+/// The jobs of the shared image queue, whenever they change
+fn image_jobs() -> impl Stream<Item = Message> {
+    queue::shared().updates().map(Message::ImageJobs)
+}
+
+fn manual_image_requests() -> impl Stream<Item = Message> {
+    manual::requests().map(Message::ManualImage)
+}
+
+/// Lets the player pick the file for an image of the manual image model. The dialog title
+/// shows the start of the description, the rest would be cut off anyway.
+fn pick_image_file(request: ManualRequest) -> Task<Message> {
+    let mut description: String = request.description.chars().take(100).collect();
+    if description.len() < request.description.len() {
+        description.push('…');
+    }
+    Task::future(async move {
+        let file = rfd::AsyncFileDialog::new()
+            .set_title(format!("Pick an image: {description}"))
+            .add_filter("Images", &["jpg", "jpeg", "png", "webp"])
+            .pick_file()
+            .await;
+        match file {
+            Some(file) => request.supply(file.read().await),
+            None => request.decline(),
+        }
+    })
+    .discard()
+}
+
+/// Shows an error. Errors that the engine classified come with advice on what to do about them,
+/// everything else is shown as is.
+fn error_dialog(parent: Box<dyn State>, e: &Report) -> Box<dyn State> {
+    let Some(engine_error) = e.downcast_ref::<EngineError>() else {
+        return Modal::message(parent, "Error", format!("{e:?}")).boxed();
+    };
+
+    let hint = match engine_error {
+        EngineError::ApiAuth { provider, message } => {
+            return Modal::confirm(
+                parent,
+                indoc::formatdoc! {"
+                    {provider} did not accept your API key. Do you want to open the options to check it?
+
+                    Details: {message}"
+                },
+                Some(message::UiMessage::OpenOptions),
+                None,
+            )
+            .boxed();
+        }
+        EngineError::RateLimited { .. } | EngineError::Overloaded { .. } => {
+            "The provider is busy right now. Please wait a moment and try again."
+        }
+        EngineError::Timeout { .. } => {
+            "The provider took too long to answer. You can try again, or raise the timeouts \
+             in the options."
+        }
+        EngineError::Moderated { .. } => {
+            "The content filter of the image model refused the request, even after the \
+             image description was rewritten. You can try again, raise the safety tolerance \
+             in the options, or try a different image model."
+        }
+        EngineError::ParseFailure { .. } => {
+            "The LLM did not answer in the expected format. This happens occasionally, \
+             regenerating the turn usually helps."
+        }
+        EngineError::ArchiveCorrupt { .. } => {
+            "The save file is damaged. If you have a copy of it, try loading that instead."
+        }
+        EngineError::ChecksumMismatch { .. } => {
+            "The save file is damaged. If backups are enabled in the options, you can restore \
+             one with the admin CLI."
+        }
+        EngineError::ArchiveTooNew { .. } => {
+            "The save was made by a newer version of World Weaver. Please update to load it."
+        }
+        EngineError::PasswordRequired | EngineError::WrongPassword => {
+            "The save is encrypted. Open it via Saves, which asks for its password."
+        }
+        EngineError::ArchiveLocked => {
+            "Another World Weaver window, or the admin CLI, has this save open. Close it there \
+             first."
+        }
+        EngineError::ImageDownload(_) => {
+            "The image was generated, but could not be downloaded. You can retry the download \
+             from the sidebar."
+        }
+        EngineError::Cancelled => {
+            return Modal::message(parent, "Cancelled", "The turn was cancelled.").boxed();
+        }
+        EngineError::Io(_) | EngineError::Other(_) => {
+            return Modal::message(parent, "Error", format!("{e:?}")).boxed();
+        }
+    };
+    Modal::message(parent, "Error", format!("{hint}\n\nDetails: {e:#}")).boxed()
+}
 
 pub fn playing_output_scroll_id() -> Id {
     Id::new("playing-output-scroll")
@@ -155,6 +368,11 @@ pub fn styles_dir() -> Result<PathBuf> {
     Ok(data_dir()?.join("styles"))
 }
 
+/// Where saves that were named via "Save as" are written
+pub fn saves_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join("saves"))
+}
+
 pub fn config_path() -> Result<PathBuf> {
     Ok(dirs::config_local_dir()
         .ok_or(eyre!("Couldn't get config dir"))?
@@ -214,6 +432,16 @@ pub fn save_remembered_saves(saves: &[PathBuf]) -> Result<()> {
     save_ron_file(&path, &saves)
 }
 
+/// Adds the save to the ones the save manager lists, if it isn't there already
+pub fn remember_save(path: &Path) -> Result<()> {
+    let mut saves = load_remembered_saves()?;
+    if !saves.iter().any(|save| save == path) {
+        saves.push(path.to_path_buf());
+        save_remembered_saves(&saves)?;
+    }
+    Ok(())
+}
+
 pub fn load_active_game_save_path() -> Result<Option<PathBuf>> {
     let path = active_game_save_path_ref_path()?;
     if !path.exists() {
@@ -17,6 +17,7 @@ pub fn main() -> Result<()> {
         Gui::update,
         Gui::view,
     )
+    .subscription(Gui::subscription)
     .run()?;
     Ok(())
 }
@@ -1,23 +1,54 @@
-use color_eyre::Result;
 use derive_more::{From, TryInto};
 use engine::{
+    error::EngineError,
     game::{self, TurnOutput},
+    image_model::{
+        self,
+        manual::ManualRequest,
+        queue::JobStatus,
+        replicate::catalog::{CatalogModel, ModelVersion},
+    },
     llm,
+    thumbnail::Thumbnail,
+    world_cover::Cover,
 };
+use iced::widget::markdown;
 
 #[derive(Debug, From, TryInto)]
 pub enum Message {
     Ui(UiMessage),
     Context(ContextMessage),
+    /// the image queue changed
+    ImageJobs(Vec<JobStatus>),
+    /// the manual image model asks for a file
+    ManualImage(ManualRequest),
 }
 
 #[derive(Debug)]
 pub enum ContextMessage {
-    OutputComplete(usize, Result<TurnOutput>),
-    SummaryFinished(usize, Result<Option<llm::OutputMessage>>),
-    NewTextFragment(usize, Result<String>),
+    OutputComplete(usize, Result<TurnOutput, EngineError>),
+    SummaryFinished(usize, Result<Option<llm::OutputMessage>, EngineError>),
+    /// a summary that was written in the background, for the turn with the given index
+    BackgroundSummaryFinished(usize, Result<Option<llm::OutputMessage>, EngineError>),
+    /// a new title and recap, which is shown if the flag is set
+    RecapFinished(bool, Result<(game::Recap, llm::OutputMessage), EngineError>),
+    NewTextFragment(usize, Result<String, EngineError>),
+    NewThoughtFragment(usize, String),
     Init,
-    ImageReady(usize, Result<game::Image>),
+    ImageReady(usize, Result<game::Image, EngineError>),
+    /// an additional image for the completed turn with the given index
+    ExtraImageReady(usize, Result<game::Image, EngineError>),
+    /// an additional variant of the image of the turn with the given index, which may still
+    /// be pending
+    ImageVariantReady(usize, Result<game::Image, EngineError>),
+    /// the image of the given generation takes much longer than usual, if it's still pending
+    ImageWatchdog(usize),
+    /// what the image model told about the image of the given generation
+    ImageProgressed(usize, image_model::ImageProgress),
+    /// the sidebar image with the given id, loaded in advance
+    PrefetchedImage(usize, Result<Thumbnail, EngineError>),
+    /// the output of the completed turn with the given index, and its parsed markdown
+    PrefetchedMarkdown(usize, String, Vec<markdown::Item>),
 }
 
 #[derive(Debug, Clone, From, TryInto)]
@@ -30,16 +61,29 @@ pub enum UiMessage {
     WorldMenu(ui_messages::WorldMenu),
     WorldEditor(ui_messages::WorldEditor),
     InputDialog(ui_messages::InputDialog),
+    GmTools(ui_messages::GmTools),
     StartNewGame(ui_messages::StartNewGame),
-    LoadMenu(ui_messages::LoadMenu),
+    SaveManager(ui_messages::SaveManager),
     OptionsMenu(ui_messages::OptionsMenu),
+    Gallery(ui_messages::Gallery),
+    Codex(ui_messages::Codex),
+    Timelines(ui_messages::Timelines),
+    Bookmarks(ui_messages::Bookmarks),
+    /// Opens the options menu from any state, e.g. from an error dialog
+    OpenOptions,
+    /// Shows the recap of the running game over any state
+    ShowRecap,
 }
 
 pub mod ui_messages {
     use super::*;
 
-    use engine::image_model::{self, Model};
+    use engine::{
+        image_model::{self, Model},
+        rate_limit::LimitKey,
+    };
     use iced::widget::text_editor;
+    use std::path::PathBuf;
 
     macro_rules! ui_enums {
         ($($pub:vis enum $name:ident { $( $variant:ident $( ( $($body:tt)* ) )? ),+ $(,)? })+) => {
@@ -63,6 +107,7 @@ pub mod ui_messages {
             UpdateActionText(text_editor::Action),
             UpdateGMInstructionText(text_editor::Action),
             ClearActionEditors,
+            RefreshPromptEstimate,
             ProposedActionButtonPressed(String),
             Submit,
             PrevTurnButtonPressed,
@@ -75,10 +120,21 @@ pub mod ui_messages {
             OutputScrolled(f32),
             LoadGameFromCurrentPastButtonPressed,
             ConfirmLoadGameFromCurrentPast,
+            UndoTurnPressed,
+            ConfirmUndoTurn,
+            NewTimelinePressed,
+            NewTimeline(String),
+            BookmarkPressed,
+            Bookmark(String),
+            EditAndReplayPressed,
+            EditAndReplay(String),
+            BranchPressed,
+            Branch(String),
             ShowHiddenText,
             UpdateHiddenInfo(String),
             ShowImageDescription,
             ShowSummary,
+            ShowTranscript,
             UpdateSummary(String),
             CopyInputToClipboard,
             RegenerateButtonPressed,
@@ -86,6 +142,32 @@ pub mod ui_messages {
             ToMainMenu,
             EditOutputPressed,
             EditOutputSubmitted(String),
+            RetryImageDownload,
+            RegenerateImage,
+            RegenerateImageWithSeed(u64),
+            EditImagePressed,
+            EditImage(String),
+            PinSeedPressed(u64),
+            PinSeed(String, u64),
+            UnpinSeed(String),
+            SelectImage(usize),
+            TextOnlyToggled(bool),
+            OpenGallery,
+            OpenCodex,
+            OpenTimelines,
+            OpenBookmarks,
+            ShowWorldState,
+            OpenGmTools,
+            UpdateWorldState(String),
+            SaveAsPressed,
+            SaveAs(String),
+            ExportStoryPressed,
+            ExportStory(PathBuf, bool),
+            CancelImage,
+            CancelTurn,
+            ToggleThoughts,
+            ResumeInterruptedTurn,
+            FinalizeInterruptedTurn,
         }
 
         pub enum MessageDialog {
@@ -110,13 +192,22 @@ pub mod ui_messages {
             Edit(String),
         }
 
+        pub enum GmTools {
+            ToneChanged(game::tone::Knob, u8),
+            Reset,
+            Save,
+            Cancel,
+        }
+
         pub enum MainMenu {
             Continue,
             RestartCurrentWorld,
             WorldsMenu,
             Options,
-            Load,
+            Saves,
             EditActiveWorld,
+            SaveAsPressed,
+            SaveAs(String),
         }
 
         pub enum WorldMenu {
@@ -138,34 +229,150 @@ pub mod ui_messages {
             ConfirmCharacterNameEdit,
             UpdateCharacter(String, text_editor::Action),
             UpdateCharacterInitAction(String, text_editor::Action),
+            UpdateCharacterStats(String, text_editor::Action),
             DescriptionUpdate(text_editor::Action),
             InitActionUpdate(text_editor::Action),
+            TemperatureUpdate(String),
+            TopPUpdate(String),
+            StopSequencesUpdate(text_editor::Action),
+            TargetWordsUpdate(String),
+            MaxTokensUpdate(String),
+            SystemPromptUpdate(text_editor::Action),
+            UseDefaultSystemPrompt,
+            SummaryPromptUpdate(text_editor::Action),
+            UseDefaultSummaryPrompt,
+            HistoryTurnsUpdate(String),
+            SelectTab(Option<game::DetailKind>),
+            AddDetail(game::DetailKind),
+            RemoveDetail(game::DetailKind, usize),
+            DetailNameUpdate(game::DetailKind, usize, String),
+            DetailDescriptionUpdate(game::DetailKind, usize, text_editor::Action),
+            ScriptUpdate(text_editor::Action),
+            SelectPreferredLLM(Option<llm::ProvidedModel>),
+            SelectPreferredImageModel(Option<image_model::ProvidedModel>),
+            TextOnlyToggled(bool),
+            SelectContentRating(Option<game::ContentRating>),
+            ToneChanged(game::tone::Knob, u8),
+            GenerateCover,
+            CoverGenerated(Result<Cover, String>),
+            ImportCover,
+            RemoveCover,
             NameUpdate(String),
             Button(String),
         }
 
         pub enum StartNewGame {
-            Selected(String)
+            Selected(String),
+            DifficultySelected(game::Difficulty),
+            EncryptToggled(bool),
+            PasswordChanged(String),
+            PasswordRepeated(String),
+        }
+
+        pub enum Gallery {
+            Back,
+            ThumbnailLoaded(usize, Option<Thumbnail>),
+            Zoom(usize),
+            CloseZoom,
+            JumpToTurn(usize),
+            ExportImages,
+            RestylePressed,
+            Restyle,
+            CompactPressed,
+            Compact,
+        }
+
+        pub enum Codex {
+            Back,
+            SelectKind(Option<game::EntryKind>),
+            SearchChanged(String),
+            JumpToTurn(usize),
+        }
+
+        pub enum Timelines {
+            Back,
+            Switch(usize),
+        }
+
+        pub enum Bookmarks {
+            Back,
+            JumpToTurn(usize),
         }
 
-        pub enum LoadMenu {
+        pub enum SaveManager {
             Back,
             OpenSave,
             ForgetSave(usize),
             LoadSave(usize),
+            LoadWithPassword(PathBuf, String),
+            ExportSave(usize),
+            ExportWithPassword(PathBuf, String),
+            ImportBundle,
+            LoadSnapshot(PathBuf, usize),
+            SnapshotNamed(PathBuf, usize, String),
+            SnapshotWithPassword(PathBuf, usize, String, String),
+            RenameSave(usize),
+            Rename(PathBuf, String),
+            DuplicateSave(usize),
+            Duplicate(PathBuf, String),
+            DeleteSave(usize),
+            Delete(PathBuf),
+            CompactSave(usize),
+            CompactWithPassword(PathBuf, String),
         }
 
         pub enum OptionsMenu {
             ImgModelTokenChanged(image_model::ModelProvider, String),
             LLMTokenChanged(llm::ModelProvider, String),
             SelectImageModel(image_model::ProvidedModel),
+            SelectLocalApi(image_model::LocalApi),
+            LocalUrlChanged(String),
+            LocalCheckpointChanged(String),
+            LocalSamplerChanged(String),
+            LocalStepsChanged(String),
             SelectLLM(llm::ProvidedModel),
+            SelectSummaryLLM(Option<llm::ProvidedModel>),
+            SelectFallbackImageModel(Option<image_model::ProvidedModel>),
+            LoadReplicateModels,
+            ReplicateModelsLoaded(Result<Vec<CatalogModel>, String>),
+            SelectReplicateModel(CatalogModel),
+            ReplicateVersionsLoaded(String, Result<Vec<ModelVersion>, String>),
+            SelectReplicateVersion(ModelVersion),
+            ResetReplicateModel,
+            OpenrouterModelIdChanged(String),
+            CustomEndpointToggled(bool),
+            CustomEndpointUrlChanged(String),
+            CustomEndpointModelChanged(String),
+            MaxAttemptsChanged(String),
+            InitialBackoffChanged(String),
+            ThinkingBudgetChanged(String),
+            ContextBudgetChanged(String),
+            SummaryIntervalChanged(String),
+            HistoryTurnsChanged(String),
+            BatchSummariesToggled(bool),
+            RecordSessionsToggled(bool),
+            ImageVariantsChanged(String),
+            ImageConcurrencyChanged(String),
+            ImageMaxAttemptsChanged(String),
+            ImageInitialBackoffChanged(String),
+            RequestsPerMinuteChanged(LimitKey, String),
+            TokensPerMinuteChanged(LimitKey, String),
+            ResponseTimeoutChanged(llm::ModelProvider, String),
+            IdleTimeoutChanged(llm::ModelProvider, String),
+            SafetyToleranceChanged(image_model::ProvidedModel, String),
             SelectStyle(usize),
             UnselectStyle(image_model::Model),
             EditStylePrefix(usize, text_editor::Action),
             EditStylePostfix(usize,text_editor::Action),
             NewStyle(Model, String),
             AddModelStyleButton(Model),
+            MaxImageDimensionChanged(String),
+            JpegQualityChanged(String),
+            SelectImageFormat(Option<image_model::ImageFormat>),
+            StoreOriginalsToggled(bool),
+            BackupsKeepChanged(String),
+            BackupsIntervalChanged(String),
+            SnapshotEveryChanged(String),
             Ok,
         }
     }
@@ -17,8 +17,20 @@ pub use world_menu::WorldMenu;
 pub mod world_editor;
 pub use world_editor::WorldEditor;
 
-pub mod load_menu;
+pub mod gallery;
+pub use gallery::Gallery;
+
+pub mod codex;
+pub use codex::Codex;
+
+pub mod timelines;
+pub use timelines::Timelines;
+
+pub mod bookmarks;
+pub use bookmarks::Bookmarks;
+
 pub mod options_menu;
+pub mod save_manager;
 pub mod start_new_game;
 
 use crate::{
@@ -33,6 +45,9 @@ pub trait State: fmt::Debug {
     fn is_playing(&self) -> bool {
         false
     }
+    fn is_gallery(&self) -> bool {
+        false
+    }
 }
 
 pub trait StateExt: State + Sized + 'static {
@@ -59,6 +74,10 @@ impl<T: std::ops::DerefMut<Target = dyn State> + fmt::Debug> State for T {
     fn is_playing(&self) -> bool {
         self.deref().is_playing()
     }
+
+    fn is_gallery(&self) -> bool {
+        self.deref().is_gallery()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -0,0 +1,101 @@
+//! The bookmarked turns of the running game, to jump back to the important moments of the
+//! story. Turns are bookmarked from the playing view.
+
+use color_eyre::{Result, eyre::eyre};
+use iced::{
+    Element, Length,
+    widget::{button, column, row, rule, scrollable, space, text},
+};
+
+use crate::{
+    TryIntoExt, bold_text,
+    context::{Context, game_context::GameContext},
+    message::{UiMessage, ui_messages::Bookmarks as MyMessage},
+    state::{State, StateCommand, cmd},
+    top_level_container,
+};
+
+#[derive(Debug)]
+pub struct Bookmarks {
+    /// returned to when a turn is chosen
+    parent: Box<dyn State>,
+    bookmarks: Vec<(usize, String)>,
+}
+
+impl Bookmarks {
+    pub fn new(parent: Box<dyn State>, ctx: &GameContext) -> Self {
+        Self {
+            parent,
+            bookmarks: ctx
+                .game
+                .data
+                .bookmarks()
+                .map(|(turn, title)| (turn, title.to_string()))
+                .collect(),
+        }
+    }
+
+    fn view_bookmark((turn, title): &(usize, String)) -> Element<'_, UiMessage> {
+        column![
+            row![
+                bold_text(format!("Turn {}", turn + 1)).size(20),
+                text(title),
+                space::horizontal(),
+                button("Go").on_press(MyMessage::JumpToTurn(*turn).into()),
+            ]
+            .spacing(10)
+            .align_y(iced::alignment::Vertical::Center),
+            rule::horizontal(1),
+        ]
+        .spacing(5)
+        .into()
+    }
+}
+
+impl State for Bookmarks {
+    fn update(&mut self, event: UiMessage, ctx: &mut Context) -> Result<StateCommand> {
+        let ctx = ctx.game.as_mut().ok_or(eyre!("There is no running game"))?;
+        use MyMessage::*;
+        match event.try_into_ex()? {
+            Back => cmd::transition(self.parent.clone()),
+            JumpToTurn(turn) => {
+                let prefetch = ctx.goto_turn(turn + 1)?;
+                cmd::transition_with_task(self.parent.clone(), prefetch)
+            }
+        }
+    }
+
+    fn view<'a>(&'a self, _ctx: &'a Context) -> Element<'a, UiMessage> {
+        let bookmarks: Element<'_, UiMessage> = if self.bookmarks.is_empty() {
+            text("There are no bookmarks yet. Press ☆ below the story to bookmark a turn.").into()
+        } else {
+            scrollable(column(self.bookmarks.iter().map(Self::view_bookmark)).spacing(15))
+                .height(Length::Fill)
+                .into()
+        };
+
+        top_level_container(
+            column![
+                row![
+                    button("Back").on_press(MyMessage::Back.into()),
+                    space::horizontal(),
+                    bold_text("Bookmarks").size(32),
+                    space::horizontal(),
+                ]
+                .align_y(iced::alignment::Vertical::Center),
+                bookmarks,
+            ]
+            .spacing(20)
+            .width(Length::Fill)
+            .height(Length::Fill),
+        )
+        .into()
+    }
+
+    fn clone(&self) -> Box<dyn State> {
+        Box::new(Self {
+            parent: self.parent.clone(),
+            bookmarks: self.bookmarks.clone(),
+        })
+    }
+}
@@ -0,0 +1,149 @@
+//! The characters, locations and factions the LLM registered in the running game, see
+//! `engine::game::Codex`.
+
+use color_eyre::{Result, eyre::eyre};
+use engine::game::{CodexEntry, EntryKind};
+use iced::{
+    Element, Length,
+    widget::{button, column, radio, row, rule, scrollable, space, text, text_input},
+};
+use strum::IntoEnumIterator;
+
+use crate::{
+    TryIntoExt, bold_text,
+    context::{Context, game_context::GameContext},
+    message::{UiMessage, ui_messages::Codex as MyMessage},
+    state::{State, StateCommand, cmd},
+    top_level_container,
+};
+
+#[derive(Debug)]
+pub struct Codex {
+    /// returned to when the codex is closed
+    parent: Box<dyn State>,
+    entries: Vec<CodexEntry>,
+    /// `None` shows all kinds
+    kind: Option<EntryKind>,
+    search: String,
+}
+
+impl Codex {
+    pub fn new(parent: Box<dyn State>, ctx: &GameContext) -> Self {
+        Self {
+            parent,
+            entries: ctx.game.data.codex.entries().to_vec(),
+            kind: None,
+            search: String::new(),
+        }
+    }
+
+    fn visible_entries(&self) -> impl Iterator<Item = &CodexEntry> {
+        let search = self.search.trim().to_lowercase();
+        self.entries.iter().filter(move |e| {
+            self.kind.is_none_or(|k| k == e.kind)
+                && (e.name.to_lowercase().contains(&search)
+                    || e.description.to_lowercase().contains(&search))
+        })
+    }
+
+    fn view_entry(entry: &CodexEntry) -> Element<'_, UiMessage> {
+        column![
+            row![
+                bold_text(&entry.name).size(20),
+                text(format!("({})", entry.kind)),
+                space::horizontal(),
+                button(text(format!("Introduced in turn {}", entry.first_turn + 1)))
+                    .style(button::text)
+                    .on_press(MyMessage::JumpToTurn(entry.first_turn).into()),
+                button(text(format!("Updated in turn {}", entry.last_turn + 1)))
+                    .style(button::text)
+                    .on_press(MyMessage::JumpToTurn(entry.last_turn).into()),
+            ]
+            .spacing(10)
+            .align_y(iced::alignment::Vertical::Center),
+            text(&entry.description),
+            rule::horizontal(1),
+        ]
+        .spacing(5)
+        .into()
+    }
+}
+
+impl State for Codex {
+    fn update(&mut self, event: UiMessage, ctx: &mut Context) -> Result<StateCommand> {
+        let ctx = ctx.game.as_mut().ok_or(eyre!("There is no running game"))?;
+        use MyMessage::*;
+        match event.try_into_ex()? {
+            Back => cmd::transition(self.parent.clone()),
+            SelectKind(kind) => {
+                self.kind = kind;
+                cmd::none()
+            }
+            SearchChanged(search) => {
+                self.search = search;
+                cmd::none()
+            }
+            JumpToTurn(turn) => {
+                // the context counts turns from 1, like the turn selection
+                let prefetch = ctx.goto_turn(turn + 1)?;
+                cmd::transition_with_task(self.parent.clone(), prefetch)
+            }
+        }
+    }
+
+    fn view<'a>(&'a self, _ctx: &'a Context) -> Element<'a, UiMessage> {
+        let kinds = row(
+            [radio("All", None, Some(self.kind), |k| MyMessage::SelectKind(k).into()).into()]
+                .into_iter()
+                .chain(EntryKind::iter().map(|k| {
+                    radio(format!("{k}s"), Some(k), Some(self.kind), |k| {
+                        MyMessage::SelectKind(k).into()
+                    })
+                    .into()
+                })),
+        )
+        .spacing(20);
+        let entries: Element<'_, UiMessage> = if self.entries.is_empty() {
+            text("The codex is still empty. The storyteller adds to it as the story goes on.")
+                .into()
+        } else {
+            scrollable(column(self.visible_entries().map(Self::view_entry)).spacing(15))
+                .height(Length::Fill)
+                .into()
+        };
+
+        top_level_container(
+            column![
+                row![
+                    button("Back").on_press(MyMessage::Back.into()),
+                    space::horizontal(),
+                    bold_text("Codex").size(32),
+                    space::horizontal(),
+                ]
+                .align_y(iced::alignment::Vertical::Center),
+                row![
+                    kinds,
+                    space::horizontal(),
+                    text_input("Search", &self.search)
+                        .on_input(|s| MyMessage::SearchChanged(s).into())
+                        .width(300),
+                ]
+                .align_y(iced::alignment::Vertical::Center),
+                entries,
+            ]
+            .spacing(20)
+            .width(Length::Fill)
+            .height(Length::Fill),
+        )
+        .into()
+    }
+
+    fn clone(&self) -> Box<dyn State> {
+        Box::new(Self {
+            parent: self.parent.clone(),
+            entries: self.entries.clone(),
+            kind: self.kind,
+            search: self.search.clone(),
+        })
+    }
+}
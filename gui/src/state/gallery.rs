@@ -0,0 +1,270 @@
+//! Every image of the running game in a grid. The thumbnails are decoded in the background,
+//! a few at a time, so opening the gallery of a long campaign doesn't block.
+
+use color_eyre::{Result, eyre::eyre};
+use engine::thumbnail::{Thumbnail, make_thumbnail};
+use iced::{
+    Element, Length, Task,
+    advanced::image::Handle as ImgHandle,
+    alignment::Horizontal,
+    widget::{button, column, container, image, row, scrollable, space, text},
+};
+use log::warn;
+
+use crate::{
+    TryIntoExt, bold_text,
+    context::{
+        Context,
+        game_context::{GameContext, to_handle},
+    },
+    message::{Message, UiMessage, ui_messages::Gallery as MyMessage},
+    state::{Modal, State, StateCommand, cmd},
+    top_level_container,
+};
+
+const THUMBNAIL_SIZE: u32 = 192;
+const COLUMNS: usize = 5;
+/// how many thumbnails are decoded at the same time
+const MAX_LOADING: usize = 4;
+
+#[derive(Debug)]
+pub struct Gallery {
+    /// returned to when the gallery is closed
+    parent: Box<dyn State>,
+    entries: Vec<Entry>,
+    /// the index of the next entry whose thumbnail is loaded
+    next_to_load: usize,
+    zoomed: Option<(usize, ImgHandle)>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    turn: usize,
+    id: usize,
+    caption: String,
+    thumbnail: Option<ImgHandle>,
+}
+
+impl Gallery {
+    pub fn new(parent: Box<dyn State>, ctx: &mut GameContext) -> Result<(Self, Task<Message>)> {
+        let entries = ctx
+            .game
+            .data
+            .turn_data
+            .iter()
+            .enumerate()
+            .flat_map(|(turn, turn_data)| {
+                turn_data.images.iter().map(move |info| Entry {
+                    turn,
+                    id: info.id,
+                    caption: info.caption.clone(),
+                    thumbnail: None,
+                })
+            })
+            .collect();
+        let mut gallery = Self {
+            parent,
+            entries,
+            next_to_load: 0,
+            zoomed: None,
+        };
+        let tasks = (0..MAX_LOADING)
+            .map(|_| gallery.load_next(ctx))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((gallery, Task::batch(tasks)))
+    }
+
+    fn load_next(&mut self, ctx: &mut GameContext) -> Result<Task<Message>> {
+        let Some(entry) = self.entries.get(self.next_to_load) else {
+            return Ok(Task::none());
+        };
+        let i = self.next_to_load;
+        self.next_to_load += 1;
+        let bytes = ctx.save.read_thumbnail_later(entry.id)?;
+        Ok(Task::perform(
+            async move { make_thumbnail(&bytes.await?, THUMBNAIL_SIZE) },
+            move |thumbnail: Result<Thumbnail>| {
+                let thumbnail = thumbnail
+                    .inspect_err(|e| warn!("Failed to load the thumbnail of image {i}: {e:#}"))
+                    .ok();
+                MyMessage::ThumbnailLoaded(i, thumbnail).into()
+            },
+        ))
+    }
+
+    fn view_grid(&self) -> Element<'_, UiMessage> {
+        if self.entries.is_empty() {
+            return text("There are no images yet.").into();
+        }
+        let rows = self.entries.chunks(COLUMNS).enumerate().map(|(r, chunk)| {
+            row(chunk.iter().enumerate().map(|(c, entry)| {
+                let i = r * COLUMNS + c;
+                let thumbnail: Element<'_, UiMessage> = match &entry.thumbnail {
+                    Some(handle) => image(handle).width(THUMBNAIL_SIZE as f32).into(),
+                    None => container(text("…")).center(THUMBNAIL_SIZE as f32).into(),
+                };
+                column![
+                    button(thumbnail)
+                        .style(button::text)
+                        .on_press(MyMessage::Zoom(i).into()),
+                    text(format!("Turn {}", entry.turn + 1)).size(14),
+                ]
+                .align_x(Horizontal::Center)
+                .width(THUMBNAIL_SIZE as f32)
+                .into()
+            }))
+            .spacing(10)
+            .into()
+        });
+        scrollable(column(rows).spacing(10).width(Length::Fill))
+            .height(Length::Fill)
+            .into()
+    }
+
+    fn view_zoomed<'a>(&'a self, i: usize, handle: &'a ImgHandle) -> Element<'a, UiMessage> {
+        let entry = &self.entries[i];
+        column![
+            image(handle).height(Length::Fill),
+            text(&entry.caption),
+            row![
+                button("Back to the gallery").on_press(MyMessage::CloseZoom.into()),
+                button(text(format!("Go to turn {}", entry.turn + 1)))
+                    .on_press(MyMessage::JumpToTurn(entry.turn).into()),
+            ]
+            .spacing(10),
+        ]
+        .align_x(Horizontal::Center)
+        .spacing(10)
+        .into()
+    }
+}
+
+impl State for Gallery {
+    fn update(&mut self, event: UiMessage, ctx: &mut Context) -> Result<StateCommand> {
+        let ctx = ctx.game.as_mut().ok_or(eyre!("There is no running game"))?;
+        use MyMessage::*;
+        match event.try_into_ex()? {
+            ThumbnailLoaded(i, thumbnail) => {
+                if let Some(entry) = self.entries.get_mut(i) {
+                    entry.thumbnail = thumbnail.as_ref().map(to_handle);
+                }
+                cmd::task(self.load_next(ctx)?)
+            }
+            Zoom(i) => {
+                let entry = self.entries.get(i).ok_or(eyre!("Invalid image: {i}"))?;
+                self.zoomed = Some((i, ctx.image_handle(entry.id)?));
+                cmd::none()
+            }
+            CloseZoom => {
+                self.zoomed = None;
+                cmd::none()
+            }
+            JumpToTurn(turn) => {
+                // the context counts turns from 1, like the turn selection
+                let prefetch = ctx.goto_turn(turn + 1)?;
+                cmd::transition_with_task(self.parent.clone(), prefetch)
+            }
+            Back => cmd::transition(self.parent.clone()),
+            ExportImages => {
+                let Some(dir) = rfd::FileDialog::new().pick_folder() else {
+                    return cmd::none();
+                };
+                let n_images = ctx.save.export_images(dir.clone())?;
+                cmd::transition(Modal::message(
+                    State::clone(self),
+                    "Images exported",
+                    format!(
+                        "Wrote {n_images} images and their captions to {}",
+                        dir.display()
+                    ),
+                ))
+            }
+            RestylePressed => {
+                let n_turns = ctx
+                    .game
+                    .data
+                    .turn_data
+                    .iter()
+                    .filter(|turn_data| !turn_data.images.is_empty())
+                    .count();
+                cmd::transition(Modal::confirm(
+                    State::clone(self),
+                    format!(
+                        "Make a new image in the current style for each of the {n_turns} turns \
+                         with images? Each one is paid for. They are added as variants, so the \
+                         current images stay displayed until you select a new one."
+                    ),
+                    Some(MyMessage::Restyle.into()),
+                    None,
+                ))
+            }
+            Restyle => cmd::task(ctx.restyle_images()?),
+            CompactPressed => cmd::transition(Modal::confirm(
+                State::clone(self),
+                "Remove the images that no turn shows anymore from the save, e.g. the ones of \
+                 turns that were discarded? This can't be undone.",
+                Some(MyMessage::Compact.into()),
+                None,
+            )),
+            Compact => {
+                let compaction = ctx.compact_save()?;
+                // the ids of the images changed
+                let (gallery, task) = Gallery::new(self.parent.clone(), ctx)?;
+                cmd::transition_with_task(
+                    Modal::message(
+                        Box::new(gallery),
+                        "Save compacted",
+                        format!(
+                            "Removed {} images, {:.1} MB were freed.",
+                            compaction.removed_images,
+                            compaction.freed_bytes as f64 / 1e6
+                        ),
+                    ),
+                    task,
+                )
+            }
+        }
+    }
+
+    fn view<'a>(&'a self, _ctx: &'a Context) -> Element<'a, UiMessage> {
+        let content = match &self.zoomed {
+            Some((i, handle)) => self.view_zoomed(*i, handle),
+            None => self.view_grid(),
+        };
+        top_level_container(
+            column![
+                row![
+                    button("Back").on_press(MyMessage::Back.into()),
+                    space::horizontal(),
+                    bold_text("Gallery").size(32),
+                    space::horizontal(),
+                    row![
+                        button("Restyle all...").on_press(MyMessage::RestylePressed.into()),
+                        button("Compact save...").on_press(MyMessage::CompactPressed.into()),
+                        button("Export images...").on_press(MyMessage::ExportImages.into()),
+                    ]
+                    .spacing(10),
+                ]
+                .align_y(iced::alignment::Vertical::Center),
+                content,
+            ]
+            .spacing(20)
+            .width(Length::Fill)
+            .height(Length::Fill),
+        )
+        .into()
+    }
+
+    fn clone(&self) -> Box<dyn State> {
+        Box::new(Self {
+            parent: self.parent.clone(),
+            entries: self.entries.clone(),
+            next_to_load: self.next_to_load,
+            zoomed: self.zoomed.clone(),
+        })
+    }
+
+    fn is_gallery(&self) -> bool {
+        true
+    }
+}
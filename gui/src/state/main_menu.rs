@@ -1,7 +1,7 @@
 use color_eyre::Result;
-use engine::save_archive::SaveArchive;
+use engine::{error::EngineError, save_archive::SaveArchive};
 use iced::{
-    Length,
+    Length, Task,
     alignment::Horizontal,
     widget::{button, column, container},
 };
@@ -12,7 +12,9 @@ use crate::{
     elem_list,
     message::{UiMessage, ui_messages::MainMenu as MyMessage},
     state::{
-        self, Playing, StateCommand, WorldEditor, cmd, load_menu::LoadMenu, options_menu::OptionsMenu,
+        self, Modal, Playing, StateCommand, WorldEditor, cmd,
+        options_menu::OptionsMenu,
+        save_manager::{SaveManager, needs_password},
     },
 };
 
@@ -26,9 +28,11 @@ impl MainMenu {
         Ok(MainMenu {
             active_game_exists: load_active_game_save_path()?
                 .map(|path| {
-                    SaveArchive::open(&path)
-                        .and_then(|mut archive| archive.read_game_data().map(|_| ()))
-                        .is_ok()
+                    // encrypted saves can only be read with the password, which is asked for
+                    // on Continue
+                    let res = SaveArchive::open_read_only(&path, None)
+                        .and_then(|mut archive| archive.read_game_data().map(|_| ()));
+                    matches!(res, Ok(()) | Err(EngineError::PasswordRequired))
                 })
                 .unwrap_or(false),
         })
@@ -44,8 +48,15 @@ impl State for MainMenu {
         use MyMessage::*;
         match msg {
             Continue => {
-                if ctx.game.is_none() {
-                    ctx.load_game()?;
+                if ctx.game.is_none()
+                    && let Err(e) = ctx.load_game()
+                {
+                    return match load_active_game_save_path()? {
+                        Some(path) if needs_password(&e) => {
+                            SaveManager::try_new()?.load(ctx, path, None)
+                        }
+                        _ => Err(e),
+                    };
                 }
                 cmd::transition(Playing::new())
             }
@@ -58,7 +69,7 @@ impl State for MainMenu {
                 cmd::transition(state::start_new_game::StartNewGame::new(world))
             }
             WorldsMenu => cmd::transition(state::WorldMenu::try_new()?),
-            Load => cmd::transition(LoadMenu::try_new()?),
+            Saves => cmd::transition(SaveManager::try_new()?),
             Options => cmd::transition(OptionsMenu::new(&ctx.config)?),
             EditActiveWorld => {
                 let world = if let Some(gctx) = &ctx.game {
@@ -69,10 +80,24 @@ impl State for MainMenu {
 
                 cmd::transition(WorldEditor::edit_running_world(world))
             }
+            SaveAsPressed => cmd::transition(Modal::input(
+                State::clone(self),
+                "Save the game as",
+                "Name",
+                |name| Task::done(MyMessage::SaveAs(name).into()),
+            )),
+            SaveAs(name) => {
+                let path = ctx.save_game_as(&name)?;
+                cmd::transition(Modal::message(
+                    State::clone(self),
+                    "Game saved",
+                    format!("The game continues in {}", path.display()),
+                ))
+            }
         }
     }
 
-    fn view<'a>(&'a self, _ctx: &'a Context) -> iced::Element<'a, crate::message::UiMessage> {
+    fn view<'a>(&'a self, ctx: &'a Context) -> iced::Element<'a, crate::message::UiMessage> {
         let button_w = 200;
         let mut buttons = vec![];
         if self.active_game_exists {
@@ -88,13 +113,21 @@ impl State for MainMenu {
                     .width(button_w),
             ]);
         }
+        if ctx.game.is_some() {
+            buttons.push(
+                button("Save as...")
+                    .on_press(MyMessage::SaveAsPressed.into())
+                    .width(button_w)
+                    .into(),
+            );
+        }
 
         buttons.extend(elem_list![
             button("New Game / Worlds")
                 .on_press(MyMessage::WorldsMenu.into())
                 .width(button_w),
-            button("Load Game")
-                .on_press(MyMessage::Load.into())
+            button("Saves")
+                .on_press(MyMessage::Saves.into())
                 .width(button_w),
             button("Options")
                 .on_press(MyMessage::Options.into())
@@ -20,6 +20,7 @@ use crate::{
 
 pub mod confirm;
 pub mod edit;
+pub mod gm_tools;
 pub mod input;
 pub mod message;
 
@@ -75,6 +76,11 @@ where
     ) -> Self {
         Self::new(parent, InputDialog::new(title, placeholder, ok_msg))
     }
+
+    /// Like [`Modal::input`], but what is typed isn't shown
+    pub fn password(parent: Box<dyn State>, title: impl Into<String>, ok_msg: F) -> Self {
+        Self::new(parent, InputDialog::new(title, "Password", ok_msg).secure())
+    }
 }
 
 /// Constructs a Modal wrapping an EditorModal
@@ -0,0 +1,97 @@
+use color_eyre::{Result, eyre::eyre};
+use engine::game::{
+    Tone,
+    tone::{self, Knob},
+};
+use iced::{
+    Element, Task,
+    widget::{button, column, row, slider, space, text},
+};
+use strum::IntoEnumIterator;
+
+use crate::{
+    context::Context,
+    message::{UiMessage, ui_messages::GmTools as MyMessage},
+    state::modal::{Dialog, DialogResult, modal_outer_container},
+};
+
+/// Changes the tone of the running game's world, which applies from the next turn on
+#[derive(Debug, Clone)]
+pub struct GmToolsDialog {
+    tone: Tone,
+}
+
+impl GmToolsDialog {
+    pub fn new(tone: Tone) -> Self {
+        Self { tone }
+    }
+}
+
+impl Dialog for GmToolsDialog {
+    fn update(&mut self, event: UiMessage, ctx: &mut Context) -> Result<DialogResult> {
+        use MyMessage::*;
+        let Ok(msg) = TryInto::<MyMessage>::try_into(event) else {
+            return Ok(DialogResult::Stay);
+        };
+        match msg {
+            ToneChanged(knob, level) => {
+                self.tone.set(knob, level);
+                Ok(DialogResult::Stay)
+            }
+            Reset => {
+                self.tone = Tone::default();
+                Ok(DialogResult::Stay)
+            }
+            Save => {
+                let gctx = ctx.game.as_mut().ok_or(eyre!("There is no running game"))?;
+                gctx.set_tone(self.tone)?;
+                Ok(DialogResult::Close(Task::none()))
+            }
+            Cancel => Ok(DialogResult::Close(Task::none())),
+        }
+    }
+
+    fn view<'a>(&'a self, _ctx: &'a Context) -> Element<'a, UiMessage> {
+        let content = column![
+            text("GM Tools").size(20),
+            text("The tone of the story, from the next turn on. It's saved with the world of this game."),
+            tone_sliders(&self.tone, |knob, level| {
+                MyMessage::ToneChanged(knob, level).into()
+            }),
+            row![
+                button("Reset").on_press(MyMessage::Reset.into()),
+                space::horizontal(),
+                button("Cancel").on_press(MyMessage::Cancel.into()),
+                button("Ok").on_press(MyMessage::Save.into()),
+            ]
+            .spacing(10)
+        ]
+        .spacing(10)
+        .padding(20);
+
+        modal_outer_container(content).into()
+    }
+}
+
+/// A slider per knob, between the names of its ends. Also used by the world editor.
+pub fn tone_sliders<'a>(
+    tone: &Tone,
+    on_change: impl Fn(Knob, u8) -> UiMessage + Clone + 'a,
+) -> Element<'a, UiMessage> {
+    column(Knob::iter().map(|knob| {
+        let (low, high) = knob.ends();
+        let on_change = on_change.clone();
+        row![
+            text(knob.to_string()).width(150),
+            text(low).width(60),
+            slider(0..=tone::MAX_LEVEL, tone.get(knob), move |level| {
+                on_change(knob, level)
+            }),
+            text(high).width(60),
+        ]
+        .spacing(10)
+        .into()
+    }))
+    .spacing(10)
+    .into()
+}
@@ -18,6 +18,8 @@ pub struct InputDialog<F> {
     title: String,
     input: String,
     placeholder: String,
+    /// hides what is typed, for passwords
+    secure: bool,
     on_save: F,
 }
 
@@ -40,9 +42,17 @@ where
             title: title.into(),
             input: String::new(),
             placeholder: placeholder.into(),
+            secure: false,
             on_save,
         }
     }
+
+    pub fn secure(self) -> Self {
+        Self {
+            secure: true,
+            ..self
+        }
+    }
 }
 
 impl<F> Dialog for InputDialog<F>
@@ -72,6 +82,7 @@ where
         let content = column![
             text(&self.title).size(20),
             text_input(&self.placeholder, &self.input)
+                .secure(self.secure)
                 .on_submit(MyMessage::Save.into())
                 .on_input(|a| MyMessage::Edit(a).into()),
             row![
@@ -3,7 +3,10 @@ use std::collections::BTreeMap;
 use color_eyre::{Result, eyre::eyre};
 use iced::{
     Color, Length, Task, padding,
-    widget::{button, column, container, radio, row, scrollable, space, text, text_editor, text_input},
+    widget::{
+        button, checkbox, column, container, pick_list, radio, row, scrollable, space, text,
+        text_editor, text_input,
+    },
 };
 use strum::IntoEnumIterator;
 
@@ -11,13 +14,18 @@ use crate::{
     TryIntoExt, bold_default_font, bold_text,
     context::{Config, StyleKey},
     elem_list,
-    message::ui_messages::OptionsMenu as MyMessage,
+    message::{UiMessage, ui_messages::OptionsMenu as MyMessage},
     save_config,
     state::{MainMenu, Modal, State, cmd},
 };
 use engine::{
-    image_model::{self, Model, ModelStyle},
+    game::{DEFAULT_HISTORY_TURNS, summary},
+    image_model::{
+        self, Model, ModelStyle,
+        replicate::catalog::{self, CatalogModel, ModelVersion},
+    },
     llm,
+    rate_limit::LimitKey,
 };
 
 #[derive(Debug, Clone, Default)]
@@ -29,8 +37,48 @@ struct StyleEntry {
 #[derive(Debug, Clone)]
 pub struct OptionsMenu {
     styles: BTreeMap<(Model, String), StyleEntry>,
+    /// the text inputs for the image storage, they are applied when they're valid
+    max_image_dimension: String,
+    jpeg_quality: String,
+    /// the text inputs for the retry policy, they are applied when they're valid
+    max_attempts: String,
+    initial_backoff_ms: String,
+    /// empty if thinking is disabled
+    thinking_budget: String,
+    /// empty if there is no limit
+    context_budget: String,
+    /// empty for the default interval
+    summary_interval: String,
+    /// empty for the default number of turns
+    history_turns: String,
+    /// the text inputs for the requests and tokens per minute of each provider
+    rate_limits: BTreeMap<LimitKey, (String, String)>,
+    /// the text inputs for the response and idle timeout of each LLM provider
+    timeouts: BTreeMap<llm::ModelProvider, (String, String)>,
+    /// the text input for the steps of the local image model
+    local_steps: String,
+    /// the text input for the number of images per turn
+    image_variants: String,
+    /// the text inputs for the image models that have a safety tolerance
+    safety_tolerances: BTreeMap<image_model::ProvidedModel, String>,
+    /// the text inputs for the image queue
+    image_concurrency: String,
+    image_max_attempts: String,
+    image_initial_backoff_ms: String,
+    /// the text inputs for the backups of the save
+    backups_keep: String,
+    backups_interval: String,
+    snapshot_every: String,
+    /// the models of Replicate's catalog and the versions of the selected one, once loaded
+    replicate_models: Vec<CatalogModel>,
+    replicate_versions: Vec<ModelVersion>,
 }
 
+/// The smallest budget Anthropic accepts
+const MIN_THINKING_BUDGET: u32 = 1024;
+/// Every variant is paid for, so there's a limit to what a typo can cost
+const MAX_IMAGE_VARIANTS: usize = 4;
+
 impl OptionsMenu {
     pub fn new(config: &Config) -> Result<Self> {
         let styles = config
@@ -46,7 +94,64 @@ impl OptionsMenu {
                 )
             })
             .collect();
-        Ok(Self { styles })
+        Ok(Self {
+            styles,
+            max_image_dimension: config.image_storage.max_dimension.to_string(),
+            jpeg_quality: config.image_storage.jpeg_quality.to_string(),
+            max_attempts: config.llm_retry.max_attempts.to_string(),
+            initial_backoff_ms: config.llm_retry.initial_backoff_ms.to_string(),
+            thinking_budget: config
+                .thinking_budget
+                .map(|b| b.to_string())
+                .unwrap_or_default(),
+            context_budget: config
+                .context_budget
+                .map(|b| b.to_string())
+                .unwrap_or_default(),
+            summary_interval: config
+                .summary_interval
+                .map(|n| n.to_string())
+                .unwrap_or_default(),
+            history_turns: config
+                .history_turns
+                .map(|n| n.to_string())
+                .unwrap_or_default(),
+            rate_limits: LimitKey::iter()
+                .map(|key| {
+                    let limits = config.rate_limits(key);
+                    let tokens = limits
+                        .tokens_per_minute
+                        .map(|t| t.to_string())
+                        .unwrap_or_default();
+                    (key, (limits.requests_per_minute.to_string(), tokens))
+                })
+                .collect(),
+            timeouts: llm::ModelProvider::iter()
+                .map(|provider| {
+                    let timeouts = config.timeouts(provider);
+                    (
+                        provider,
+                        (
+                            timeouts.response_secs.to_string(),
+                            timeouts.idle_secs.to_string(),
+                        ),
+                    )
+                })
+                .collect(),
+            local_steps: config.local_image_model.steps.to_string(),
+            image_variants: (config.extra_image_variants + 1).to_string(),
+            safety_tolerances: image_model::ProvidedModel::iter()
+                .filter_map(|model| Some((model, config.safety_tolerance(model)?.to_string())))
+                .collect(),
+            image_concurrency: config.image_queue.max_concurrent.to_string(),
+            image_max_attempts: config.image_queue.retry.max_attempts.to_string(),
+            image_initial_backoff_ms: config.image_queue.retry.initial_backoff_ms.to_string(),
+            backups_keep: config.backups.keep.to_string(),
+            backups_interval: config.backups.min_interval_minutes.to_string(),
+            snapshot_every: config.snapshot_every.to_string(),
+            replicate_models: vec![],
+            replicate_versions: vec![],
+        })
     }
 
     fn get_style_enty(&mut self, i: usize) -> Result<(Model, &String, &mut StyleEntry)> {
@@ -80,6 +185,29 @@ impl State for OptionsMenu {
                 ctx.config.current_img_model = model;
                 cmd::none()
             }
+            SelectLocalApi(api) => {
+                ctx.config.local_image_model.api = api;
+                cmd::none()
+            }
+            LocalUrlChanged(url) => {
+                ctx.config.local_image_model.url = url;
+                cmd::none()
+            }
+            LocalCheckpointChanged(checkpoint) => {
+                ctx.config.local_image_model.checkpoint = checkpoint;
+                cmd::none()
+            }
+            LocalSamplerChanged(sampler) => {
+                ctx.config.local_image_model.sampler = sampler;
+                cmd::none()
+            }
+            LocalStepsChanged(val) => {
+                if let Some(steps) = val.trim().parse().ok().filter(|s| *s > 0) {
+                    ctx.config.local_image_model.steps = steps;
+                }
+                self.local_steps = val;
+                cmd::none()
+            }
             SelectStyle(idx) => {
                 let (model, name, _) = self.get_style_enty(idx)?;
                 ctx.config.active_model_style.insert(model, name.clone());
@@ -117,10 +245,22 @@ impl State for OptionsMenu {
             }
             Ok => {
                 save_config(&ctx.config)?;
+                ctx.config.apply_rate_limits();
                 if let Some(gctx) = &mut ctx.game {
-                    gctx.game.imgmod = ctx.config.get_image_model()?;
-                    gctx.game.img_style = ctx.config.active_style().cloned();
-                    gctx.game.llm = ctx.config.get_llm()?;
+                    let config = ctx.config.for_world(&gctx.game.data.world_description);
+                    gctx.game.imgmod = config.get_image_model();
+                    gctx.game.img_style = config.active_style().cloned();
+                    gctx.game.llm = config.get_llm()?;
+                    gctx.game.summary_llm = ctx.config.get_summary_llm()?;
+                    gctx.game.context_budget = ctx.config.context_budget;
+                    gctx.game.context_window = config.context_window();
+                    gctx.game.history_turns = ctx.config.history_turns();
+                    gctx.game.summary_interval = ctx.config.summary_interval();
+                    gctx.game.batch_summaries = ctx.config.batch_summaries;
+                    gctx.game.extra_image_variants = ctx.config.extra_image_variants;
+                    gctx.game.snapshot_every = ctx.config.snapshot_every;
+                    gctx.image_storage = ctx.config.image_storage;
+                    gctx.save.set_backups(ctx.config.backups)?;
                 }
                 cmd::transition(MainMenu::try_new()?)
             }
@@ -138,6 +278,285 @@ impl State for OptionsMenu {
                 ctx.config.current_llm = provided_model;
                 cmd::none()
             }
+            SelectFallbackImageModel(model) => {
+                ctx.config.fallback_img_model = model;
+                cmd::none()
+            }
+            SelectSummaryLLM(model) => {
+                ctx.config.summary_llm = model;
+                cmd::none()
+            }
+            OpenrouterModelIdChanged(id) => {
+                ctx.config.openrouter_model_id = id;
+                cmd::none()
+            }
+            CustomEndpointToggled(enabled) => {
+                ctx.config.custom_openai_endpoint = enabled.then(Default::default);
+                cmd::none()
+            }
+            CustomEndpointUrlChanged(url) => {
+                if let Some(endpoint) = &mut ctx.config.custom_openai_endpoint {
+                    endpoint.url = url;
+                }
+                cmd::none()
+            }
+            CustomEndpointModelChanged(model) => {
+                if let Some(endpoint) = &mut ctx.config.custom_openai_endpoint {
+                    endpoint.model = model;
+                }
+                cmd::none()
+            }
+            MaxImageDimensionChanged(val) => {
+                if let Some(dim) = val.parse().ok().filter(|d| *d > 0) {
+                    ctx.config.image_storage.max_dimension = dim;
+                }
+                self.max_image_dimension = val;
+                cmd::none()
+            }
+            SelectImageFormat(format) => {
+                ctx.config.image_storage.format = format;
+                cmd::none()
+            }
+            BackupsKeepChanged(val) => {
+                if let Result::Ok(n) = val.trim().parse() {
+                    ctx.config.backups.keep = n;
+                }
+                self.backups_keep = val;
+                cmd::none()
+            }
+            BackupsIntervalChanged(val) => {
+                if let Result::Ok(minutes) = val.trim().parse() {
+                    ctx.config.backups.min_interval_minutes = minutes;
+                }
+                self.backups_interval = val;
+                cmd::none()
+            }
+            SnapshotEveryChanged(val) => {
+                if let Result::Ok(n) = val.trim().parse() {
+                    ctx.config.snapshot_every = n;
+                }
+                self.snapshot_every = val;
+                cmd::none()
+            }
+            JpegQualityChanged(val) => {
+                if let Some(quality) = val.parse().ok().filter(|q| (1..=100).contains(q)) {
+                    ctx.config.image_storage.jpeg_quality = quality;
+                }
+                self.jpeg_quality = val;
+                cmd::none()
+            }
+            MaxAttemptsChanged(val) => {
+                if let Some(n) = val.parse().ok().filter(|n| *n > 0) {
+                    ctx.config.llm_retry.max_attempts = n;
+                }
+                self.max_attempts = val;
+                cmd::none()
+            }
+            InitialBackoffChanged(val) => {
+                if let Result::Ok(ms) = val.parse() {
+                    ctx.config.llm_retry.initial_backoff_ms = ms;
+                }
+                self.initial_backoff_ms = val;
+                cmd::none()
+            }
+            ThinkingBudgetChanged(val) => {
+                if val.trim().is_empty() {
+                    ctx.config.thinking_budget = None;
+                } else if let Some(budget) =
+                    val.trim().parse().ok().filter(|b| *b >= MIN_THINKING_BUDGET)
+                {
+                    ctx.config.thinking_budget = Some(budget);
+                }
+                self.thinking_budget = val;
+                cmd::none()
+            }
+            ContextBudgetChanged(val) => {
+                if val.trim().is_empty() {
+                    ctx.config.context_budget = None;
+                } else if let Some(budget) = val.trim().parse().ok().filter(|b| *b > 0) {
+                    ctx.config.context_budget = Some(budget);
+                }
+                self.context_budget = val;
+                cmd::none()
+            }
+            SummaryIntervalChanged(val) => {
+                if val.trim().is_empty() {
+                    ctx.config.summary_interval = None;
+                } else if let Result::Ok(n) = val.trim().parse() {
+                    ctx.config.summary_interval = Some(n);
+                }
+                self.summary_interval = val;
+                cmd::none()
+            }
+            HistoryTurnsChanged(val) => {
+                if val.trim().is_empty() {
+                    ctx.config.history_turns = None;
+                } else if let Result::Ok(n) = val.trim().parse() {
+                    ctx.config.history_turns = Some(n);
+                }
+                self.history_turns = val;
+                cmd::none()
+            }
+            RequestsPerMinuteChanged(key, val) => {
+                if let Some(n) = val.trim().parse().ok().filter(|n| *n > 0) {
+                    let mut limits = ctx.config.rate_limits(key);
+                    limits.requests_per_minute = n;
+                    ctx.config.rate_limits.insert(key, limits);
+                }
+                self.rate_limits.entry(key).or_default().0 = val;
+                cmd::none()
+            }
+            TokensPerMinuteChanged(key, val) => {
+                let mut limits = ctx.config.rate_limits(key);
+                if val.trim().is_empty() {
+                    limits.tokens_per_minute = None;
+                    ctx.config.rate_limits.insert(key, limits);
+                } else if let Some(n) = val.trim().parse().ok().filter(|n| *n > 0) {
+                    limits.tokens_per_minute = Some(n);
+                    ctx.config.rate_limits.insert(key, limits);
+                }
+                self.rate_limits.entry(key).or_default().1 = val;
+                cmd::none()
+            }
+            ResponseTimeoutChanged(provider, val) => {
+                if let Some(secs) = val.trim().parse().ok().filter(|s| *s > 0) {
+                    let mut timeouts = ctx.config.timeouts(provider);
+                    timeouts.response_secs = secs;
+                    ctx.config.timeouts.insert(provider, timeouts);
+                }
+                self.timeouts.entry(provider).or_default().0 = val;
+                cmd::none()
+            }
+            IdleTimeoutChanged(provider, val) => {
+                if let Some(secs) = val.trim().parse().ok().filter(|s| *s > 0) {
+                    let mut timeouts = ctx.config.timeouts(provider);
+                    timeouts.idle_secs = secs;
+                    ctx.config.timeouts.insert(provider, timeouts);
+                }
+                self.timeouts.entry(provider).or_default().1 = val;
+                cmd::none()
+            }
+            SafetyToleranceChanged(model, val) => {
+                if let Some(range) = model.safety_tolerance_range()
+                    && let Some(tolerance) = val.trim().parse().ok().filter(|t| range.contains(t))
+                {
+                    ctx.config.safety_tolerance.insert(model, tolerance);
+                }
+                self.safety_tolerances.insert(model, val);
+                cmd::none()
+            }
+            ImageVariantsChanged(val) => {
+                if let Some(n) = val.trim().parse().ok().filter(|n| (1..=MAX_IMAGE_VARIANTS).contains(n)) {
+                    ctx.config.extra_image_variants = n - 1;
+                }
+                self.image_variants = val;
+                cmd::none()
+            }
+            ImageConcurrencyChanged(val) => {
+                if let Some(n) = val.trim().parse().ok().filter(|n| *n > 0) {
+                    ctx.config.image_queue.max_concurrent = n;
+                }
+                self.image_concurrency = val;
+                cmd::none()
+            }
+            ImageMaxAttemptsChanged(val) => {
+                if let Some(n) = val.trim().parse().ok().filter(|n| *n > 0) {
+                    ctx.config.image_queue.retry.max_attempts = n;
+                }
+                self.image_max_attempts = val;
+                cmd::none()
+            }
+            ImageInitialBackoffChanged(val) => {
+                if let Result::Ok(ms) = val.trim().parse() {
+                    ctx.config.image_queue.retry.initial_backoff_ms = ms;
+                }
+                self.image_initial_backoff_ms = val;
+                cmd::none()
+            }
+            LoadReplicateModels => {
+                let key = replicate_key(&ctx.config)?;
+                cmd::task(Task::perform(
+                    async move {
+                        catalog::text_to_image_models(&key)
+                            .await
+                            .map_err(|e| format!("{e:#}"))
+                    },
+                    |res| -> UiMessage { MyMessage::ReplicateModelsLoaded(res).into() },
+                ))
+            }
+            ReplicateModelsLoaded(res) => match res {
+                Result::Ok(models) => {
+                    self.replicate_models = models;
+                    cmd::none()
+                }
+                Err(e) => cmd::transition(Modal::message(
+                    State::clone(self),
+                    "Failed to load the Replicate models",
+                    e,
+                )),
+            },
+            SelectReplicateModel(model) => {
+                let name = model.full_name();
+                ctx.config.replicate_model = Some(catalog::Selection {
+                    model: name.clone(),
+                    version: model.latest_version.as_ref().map(|v| v.id.clone()),
+                });
+                self.replicate_versions.clear();
+                // official models have no versions to choose from
+                if model.latest_version.is_none() {
+                    return cmd::none();
+                }
+                let key = replicate_key(&ctx.config)?;
+                cmd::task(Task::perform(
+                    async move {
+                        let versions = catalog::versions(&key, &name).await;
+                        (name, versions.map_err(|e| format!("{e:#}")))
+                    },
+                    |(name, res)| -> UiMessage {
+                        MyMessage::ReplicateVersionsLoaded(name, res).into()
+                    },
+                ))
+            }
+            ReplicateVersionsLoaded(name, res) => {
+                // another model may have been selected in the meantime
+                if ctx.config.replicate_model.as_ref().map(|s| &s.model) != Some(&name) {
+                    return cmd::none();
+                }
+                match res {
+                    Result::Ok(versions) => {
+                        self.replicate_versions = versions;
+                        cmd::none()
+                    }
+                    Err(e) => cmd::transition(Modal::message(
+                        State::clone(self),
+                        "Failed to load the versions",
+                        e,
+                    )),
+                }
+            }
+            SelectReplicateVersion(version) => {
+                if let Some(selection) = &mut ctx.config.replicate_model {
+                    selection.version = Some(version.id);
+                }
+                cmd::none()
+            }
+            ResetReplicateModel => {
+                ctx.config.replicate_model = None;
+                self.replicate_versions.clear();
+                cmd::none()
+            }
+            BatchSummariesToggled(val) => {
+                ctx.config.batch_summaries = val;
+                cmd::none()
+            }
+            RecordSessionsToggled(val) => {
+                ctx.config.record_sessions = val;
+                cmd::none()
+            }
+            StoreOriginalsToggled(val) => {
+                ctx.config.image_storage.store_originals = val;
+                cmd::none()
+            }
         }
     }
 
@@ -178,6 +597,156 @@ impl State for OptionsMenu {
                 .into()
             }))
             .spacing(10),
+            row![
+                text("OpenRouter model id").width(200),
+                text_input(
+                    "e.g. meta-llama/llama-3.3-70b-instruct",
+                    &ctx.config.openrouter_model_id
+                )
+                .on_input(|s| MyMessage::OpenrouterModelIdChanged(s).into())
+            ]
+            .spacing(10),
+            checkbox(ctx.config.custom_openai_endpoint.is_some())
+                .label("Use an OpenAI-compatible server instead (LM Studio, vLLM, llama.cpp, ...)")
+                .on_toggle(|b| MyMessage::CustomEndpointToggled(b).into()),
+        ]);
+
+        if let Some(endpoint) = &ctx.config.custom_openai_endpoint {
+            items.extend(elem_list![
+                row![
+                    text("URL").width(200),
+                    text_input("http://localhost:1234/v1", &endpoint.url)
+                        .on_input(|s| MyMessage::CustomEndpointUrlChanged(s).into())
+                ]
+                .spacing(10),
+                row![
+                    text("Model name").width(200),
+                    text_input("e.g. qwen3-30b-a3b", &endpoint.model)
+                        .on_input(|s| MyMessage::CustomEndpointModelChanged(s).into())
+                ]
+                .spacing(10),
+            ]);
+        }
+
+        items.extend(elem_list![
+            space().height(20),
+            bold_text("Summary LLM").size(22),
+            text("Every few turns the story so far is summarized, which a cheaper model can do. \
+                  The summary replaces the older turns in the prompt, without one the prompt keeps growing."),
+            row![
+                text("Summarize every N turns (0 = never)").width(200),
+                text_input(&summary::DEFAULT_INTERVAL.to_string(), &self.summary_interval)
+                    .on_input(|s| MyMessage::SummaryIntervalChanged(s).into())
+            ]
+            .spacing(10),
+            radio(
+                "Same as the active LLM",
+                None,
+                Some(ctx.config.summary_llm),
+                |m| MyMessage::SelectSummaryLLM(m).into()
+            ),
+            column(llm::ProvidedModel::iter().map(|m| {
+                radio(format!("{m}"), Some(m), Some(ctx.config.summary_llm), |m| {
+                    MyMessage::SelectSummaryLLM(m).into()
+                })
+                .into()
+            }))
+            .spacing(10),
+            checkbox(ctx.config.batch_summaries)
+                .label("Write the summaries in the background via the batch API, which costs half as much (Anthropic only)")
+                .on_toggle(|b| MyMessage::BatchSummariesToggled(b).into()),
+            space().height(20),
+            bold_text("Thinking").size(22),
+            text(format!(
+                "Claude can plan each turn before it writes it, which makes turns slower and more expensive. \
+                 Leave it empty to disable thinking, otherwise it must be at least {MIN_THINKING_BUDGET}."
+            )),
+            row![
+                text("Thinking budget in tokens").width(200),
+                text_input("disabled", &self.thinking_budget)
+                    .on_input(|s| MyMessage::ThinkingBudgetChanged(s).into())
+            ]
+            .spacing(10),
+            space().height(20),
+            bold_text("Context Budget").size(22),
+            text("If the prompt of a turn would be larger, the oldest turns since the last summary are left out. \
+                  Leave it empty for no limit."),
+            row![
+                text("Max. prompt size in tokens").width(200),
+                text_input("no limit", &self.context_budget)
+                    .on_input(|s| MyMessage::ContextBudgetChanged(s).into())
+            ]
+            .spacing(10),
+            text("The last turns a summary covers are still sent as they are, so the story continues smoothly. \
+                  Worlds can set their own number."),
+            row![
+                text("Verbatim turns").width(200),
+                text_input(&DEFAULT_HISTORY_TURNS.to_string(), &self.history_turns)
+                    .on_input(|s| MyMessage::HistoryTurnsChanged(s).into())
+            ]
+            .spacing(10),
+            space().height(20),
+            text("When the provider is overloaded, requests are sent again after a delay that doubles each time."),
+            row![
+                text("Max. attempts").width(200),
+                text_input("4", &self.max_attempts)
+                    .on_input(|s| MyMessage::MaxAttemptsChanged(s).into())
+            ]
+            .spacing(10),
+            row![
+                text("First delay in ms").width(200),
+                text_input("2000", &self.initial_backoff_ms)
+                    .on_input(|s| MyMessage::InitialBackoffChanged(s).into())
+            ]
+            .spacing(10),
+            space().height(20),
+            bold_text("Rate Limits").size(22),
+            text("Requests are delayed so they stay below these limits. Empty token limits mean no limit."),
+            row![
+                text("Provider").width(200),
+                text("Requests per minute").width(Length::Fill),
+                text("Tokens per minute").width(Length::Fill),
+            ]
+            .spacing(10),
+            column(self.rate_limits.iter().map(|(&key, (requests, tokens))| {
+                // images aren't billed by tokens
+                let tokens_input: iced::Element<'_, _> = match key {
+                    LimitKey::Llm(_) => text_input("no limit", tokens)
+                        .on_input(move |s| MyMessage::TokensPerMinuteChanged(key, s).into())
+                        .into(),
+                    LimitKey::Image(_) => space::horizontal().into(),
+                };
+                row![
+                    text(key.to_string()).width(200),
+                    text_input("", requests)
+                        .on_input(move |s| MyMessage::RequestsPerMinuteChanged(key, s).into()),
+                    tokens_input,
+                ]
+                .spacing(10)
+                .into()
+            }))
+            .spacing(10),
+            space().height(20),
+            bold_text("Timeouts").size(22),
+            text("A request fails if the provider doesn't start to answer in time, or stops sending in the middle of a response."),
+            row![
+                text("Provider").width(200),
+                text("Response in seconds").width(Length::Fill),
+                text("Max. pause in seconds").width(Length::Fill),
+            ]
+            .spacing(10),
+            column(self.timeouts.iter().map(|(&provider, (response, idle))| {
+                row![
+                    text(provider.to_string()).width(200),
+                    text_input("", response)
+                        .on_input(move |s| MyMessage::ResponseTimeoutChanged(provider, s).into()),
+                    text_input("", idle)
+                        .on_input(move |s| MyMessage::IdleTimeoutChanged(provider, s).into()),
+                ]
+                .spacing(10)
+                .into()
+            }))
+            .spacing(10),
             space().height(20),
             bold_text("Active Image Model").size(22),
             column(image_model::ProvidedModel::iter().map(|m| {
@@ -187,11 +756,170 @@ impl State for OptionsMenu {
                 .into()
             }))
             .spacing(10),
+            text(format!("Each turn can get up to {MAX_IMAGE_VARIANTS} images to choose from. They are made at the same time, as far as the image queue allows, and each one is paid for.")),
+            row![
+                text("Images per turn").width(200),
+                text_input("1", &self.image_variants)
+                    .on_input(|s| MyMessage::ImageVariantsChanged(s).into())
+            ]
+            .spacing(10),
+            space().height(20),
+            bold_text("Image Queue").size(22),
+            text("Only this many images are made at the same time, the others wait. When the provider is overloaded, an image is requested again after a delay that doubles each time."),
+            row![
+                text("Images at the same time").width(200),
+                text_input("2", &self.image_concurrency)
+                    .on_input(|s| MyMessage::ImageConcurrencyChanged(s).into())
+            ]
+            .spacing(10),
+            row![
+                text("Max. attempts").width(200),
+                text_input("4", &self.image_max_attempts)
+                    .on_input(|s| MyMessage::ImageMaxAttemptsChanged(s).into())
+            ]
+            .spacing(10),
+            row![
+                text("First delay in ms").width(200),
+                text_input("2000", &self.image_initial_backoff_ms)
+                    .on_input(|s| MyMessage::ImageInitialBackoffChanged(s).into())
+            ]
+            .spacing(10),
+            space().height(20),
+            bold_text("Fallback Image Model").size(22),
+            text("Asked for the image when the active image model fails, e.g. because it's down or refuses the description."),
+            radio(
+                "No fallback",
+                None,
+                Some(ctx.config.fallback_img_model),
+                |m| MyMessage::SelectFallbackImageModel(m).into()
+            ),
+            column(image_model::ProvidedModel::iter().map(|m| {
+                radio(format!("{m}"), Some(m), Some(ctx.config.fallback_img_model), |m| {
+                    MyMessage::SelectFallbackImageModel(m).into()
+                })
+                .into()
+            }))
+            .spacing(10),
+            space().height(20),
+            bold_text("Safety Tolerance").size(22),
+            text("How much the content filters of the image models let through, higher is more permissive. \
+                  When an image is refused anyway, the LLM rewrites its description once, and it's tried again."),
+            column(self.safety_tolerances.iter().map(|(&model, tolerance)| {
+                let range = model.safety_tolerance_range().expect("only models with a range are listed");
+                row![
+                    text(model.to_string()).width(200),
+                    text_input("", tolerance)
+                        .on_input(move |s| MyMessage::SafetyToleranceChanged(model, s).into()),
+                    text(format!("{} to {}", range.start(), range.end())).width(100),
+                ]
+                .spacing(10)
+                .into()
+            }))
+            .spacing(10),
+        ]);
+
+        if ctx.config.current_img_model == image_model::ProvidedModel::StableDiffusionLocal {
+            let local = &ctx.config.local_image_model;
+            items.extend(elem_list![
+                space().height(20),
+                bold_text("Local Stable Diffusion").size(22),
+                text("Images are made by an Automatic1111 or ComfyUI server on this machine."),
+                row(image_model::LocalApi::iter().map(|api| {
+                    radio(format!("{api}"), api, Some(local.api), |api| {
+                        MyMessage::SelectLocalApi(api).into()
+                    })
+                    .into()
+                }))
+                .spacing(20),
+                row![
+                    text("URL").width(200),
+                    text_input("http://127.0.0.1:7860", &local.url)
+                        .on_input(|s| MyMessage::LocalUrlChanged(s).into())
+                ]
+                .spacing(10),
+                row![
+                    text("Checkpoint").width(200),
+                    text_input("e.g. sd_xl_base_1.0.safetensors", &local.checkpoint)
+                        .on_input(|s| MyMessage::LocalCheckpointChanged(s).into())
+                ]
+                .spacing(10),
+                row![
+                    text("Sampler").width(200),
+                    text_input("the default of the server", &local.sampler)
+                        .on_input(|s| MyMessage::LocalSamplerChanged(s).into())
+                ]
+                .spacing(10),
+                row![
+                    text("Steps").width(200),
+                    text_input("", &self.local_steps)
+                        .on_input(|s| MyMessage::LocalStepsChanged(s).into())
+                ]
+                .spacing(10),
+            ]);
+        }
+
+        if ctx.config.current_img_model == image_model::ProvidedModel::Flux1Replicate {
+            let selection = ctx.config.replicate_model.as_ref();
+            let current = match selection {
+                Some(selection) => format!("Images are made by {selection}."),
+                None => "Images are made by the built-in Flux1 version.".to_string(),
+            };
+            items.extend(elem_list![
+                space().height(20),
+                bold_text("Replicate Model").size(22),
+                text("Any model of Replicate's text-to-image collection can be used instead of Flux1."),
+                text(current),
+                row![
+                    button("Load models").on_press(MyMessage::LoadReplicateModels.into()),
+                    button("Use Flux1").on_press(MyMessage::ResetReplicateModel.into()),
+                ]
+                .spacing(10),
+            ]);
+            if !self.replicate_models.is_empty() {
+                let selected = selection.and_then(|selection| {
+                    self.replicate_models
+                        .iter()
+                        .find(|m| m.full_name() == selection.model)
+                        .cloned()
+                });
+                items.push(
+                    row![
+                        text("Model").width(200),
+                        pick_list(self.replicate_models.as_slice(), selected, |m| {
+                            MyMessage::SelectReplicateModel(m).into()
+                        })
+                    ]
+                    .spacing(10)
+                    .into(),
+                );
+            }
+            if !self.replicate_versions.is_empty() {
+                let selected = selection.and_then(|selection| {
+                    self.replicate_versions
+                        .iter()
+                        .find(|v| selection.version.as_ref() == Some(&v.id))
+                        .cloned()
+                });
+                items.push(
+                    row![
+                        text("Version").width(200),
+                        pick_list(self.replicate_versions.as_slice(), selected, |v| {
+                            MyMessage::SelectReplicateVersion(v).into()
+                        })
+                    ]
+                    .spacing(10)
+                    .into(),
+                );
+            }
+        }
+
+        items.extend(elem_list![
             space().height(20),
             bold_text("Image Model API Keys").size(22)
         ]);
 
-        for provider in image_model::ModelProvider::iter() {
+        for provider in image_model::ModelProvider::iter().filter(|p| p.needs_api_key())
+        {
             let value = ctx
                 .config
                 .img_model_tokens
@@ -208,6 +936,69 @@ impl State for OptionsMenu {
             );
         }
 
+        items.extend(elem_list![
+            space().height(20),
+            bold_text("Image Storage").size(22),
+            text("Generated images are downscaled and re-encoded before they are saved."),
+            row(
+                [radio(
+                    "Keep the received format",
+                    None,
+                    Some(ctx.config.image_storage.format),
+                    |f| MyMessage::SelectImageFormat(f).into()
+                )
+                .into()]
+                .into_iter()
+                .chain(image_model::ImageFormat::iter().map(|f| {
+                    radio(f.to_string(), Some(f), Some(ctx.config.image_storage.format), |f| {
+                        MyMessage::SelectImageFormat(f).into()
+                    })
+                    .into()
+                }))
+            )
+            .spacing(20),
+            row![
+                text("Max. size in pixels").width(200),
+                text_input("1024", &self.max_image_dimension)
+                    .on_input(|s| MyMessage::MaxImageDimensionChanged(s).into())
+            ]
+            .spacing(10),
+            row![
+                text("JPEG quality (1 - 100)").width(200),
+                text_input("85", &self.jpeg_quality)
+                    .on_input(|s| MyMessage::JpegQualityChanged(s).into())
+            ]
+            .spacing(10),
+            checkbox(ctx.config.image_storage.store_originals)
+                .label("Also keep the original images (makes saves much larger)")
+                .on_toggle(|b| MyMessage::StoreOriginalsToggled(b).into()),
+            space().height(20),
+            bold_text("Backups").size(22),
+            text("The save is copied before it's written, to save.bak.1, save.bak.2 and so on, the newest first. A copy is only made if the newest one is older than the interval, so one turn doesn't replace every backup."),
+            row![
+                text("Backups to keep (0 = none)").width(200),
+                text_input("0", &self.backups_keep)
+                    .on_input(|s| MyMessage::BackupsKeepChanged(s).into())
+            ]
+            .spacing(10),
+            row![
+                text("Interval in minutes").width(200),
+                text_input("10", &self.backups_interval)
+                    .on_input(|s| MyMessage::BackupsIntervalChanged(s).into())
+            ]
+            .spacing(10),
+            text("Snapshots mark turns of the save. The save manager lists them under the save, and continues the game from one in a new save, as long as the save wasn't loaded from an earlier turn."),
+            row![
+                text("Snapshot every N turns (0 = none)").width(200),
+                text_input("0", &self.snapshot_every)
+                    .on_input(|s| MyMessage::SnapshotEveryChanged(s).into())
+            ]
+            .spacing(10),
+            checkbox(ctx.config.record_sessions)
+                .label("Record what the models answer in new games, next to the save, so admin_cli can replay them when a bug is reported")
+                .on_toggle(|b| MyMessage::RecordSessionsToggled(b).into()),
+        ]);
+
         items.push(space().height(30).into());
         items.push(bold_text("Image Styles").size(22).into());
         items.push(space().height(10).into());
@@ -297,3 +1088,13 @@ impl State for OptionsMenu {
         Box::new(Clone::clone(self))
     }
 }
+
+/// The catalog is fetched with the Replicate key of the image models
+fn replicate_key(config: &Config) -> Result<String> {
+    config
+        .img_model_tokens
+        .get(&image_model::ModelProvider::Replicate)
+        .filter(|key| !key.trim().is_empty())
+        .cloned()
+        .ok_or(eyre!("There is no API key for Replicate"))
+}
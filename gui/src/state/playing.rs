@@ -1,15 +1,16 @@
-use color_eyre::{
-    Result,
-    eyre::{ensure, eyre},
+use color_eyre::{Result, eyre::eyre};
+use engine::{
+    export::StoryFormat,
+    game::{PromptEstimate, TurnInput, TurnOutput, world_state},
+    image_model::queue::JobState,
 };
-use engine::game::{TurnInput, TurnOutput};
 use iced::{
     Color, Element, Length, Task, Theme,
     alignment::{Horizontal, Vertical},
     padding,
     widget::{
-        self, Button, Column, Container, button, container, markdown, operation, row, scrollable,
-        space,
+        self, Button, Column, Container, button, container, markdown, operation, progress_bar, row,
+        scrollable, space,
         text_editor::{self, Edit},
         text_input,
     },
@@ -18,11 +19,16 @@ use log::debug;
 
 use crate::{
     ElemHelper, State, TryIntoExt,
-    context::game_context::{Complete, GameContext as Context, ImageData, InThePast, SubState},
+    context::game_context::{
+        Complete, GameContext as Context, ImageData, ImageProgressData, SubState, TurnCursor,
+    },
     elem_list, italic_text,
     message::{Message, UiMessage, ui_messages::Playing as MyMessage},
     playing_output_scroll_id,
-    state::{MainMenu, Modal, StateCommand, cmd, modal::confirm::ConfirmDialog},
+    state::{
+        Bookmarks, Codex, Gallery, MainMenu, Modal, StateCommand, Timelines, cmd,
+        modal::{confirm::ConfirmDialog, gm_tools::GmToolsDialog},
+    },
 };
 
 #[derive(Debug, Clone)]
@@ -30,6 +36,11 @@ pub struct Playing {
     goto_turn_input: Option<usize>,
     action_text_content: text_editor::Content,
     gm_instruction_text_content: text_editor::Content,
+    /// whether the thoughts of the GM are expanded
+    show_thoughts: bool,
+    /// the size of the next turn's prompt. It's only computed when the input, the turns or
+    /// the settings change, since it builds the whole request.
+    prompt_estimate: Option<PromptEstimate>,
 }
 
 enum EditorId {
@@ -49,6 +60,8 @@ impl Playing {
             goto_turn_input: None,
             action_text_content: text_editor::Content::default(),
             gm_instruction_text_content: text_editor::Content::default(),
+            show_thoughts: false,
+            prompt_estimate: None,
         }
     }
 
@@ -61,18 +74,34 @@ impl Playing {
         &mut self,
         action: text_editor::Action,
         editor: EditorId,
+        ctx: &Context,
     ) -> Result<StateCommand, color_eyre::eyre::Error> {
         if let text_editor::Action::Edit(Edit::Enter) = action {
             cmd::task(Task::done(MyMessage::Submit))
         } else {
+            let is_edit = action.is_edit();
             match editor {
                 EditorId::PlayerAction => self.action_text_content.perform(action),
                 EditorId::GMInstruction => self.gm_instruction_text_content.perform(action),
             }
+            if is_edit {
+                self.refresh_prompt_estimate(ctx);
+            }
             cmd::none()
         }
     }
 
+    fn refresh_prompt_estimate(&mut self, ctx: &Context) {
+        self.prompt_estimate = Some(ctx.game.estimate_prompt(&self.turn_input()));
+    }
+
+    fn turn_input(&self) -> TurnInput {
+        TurnInput {
+            player_action: self.action_text_content.text(),
+            gm_instruction: self.gm_instruction_text_content.text(),
+        }
+    }
+
     fn goto_turn_string(&self) -> String {
         self.goto_turn_input
             .as_ref()
@@ -85,22 +114,29 @@ impl State for Playing {
     fn update(
         &mut self,
         message: UiMessage,
-        ctx: &mut crate::context::Context,
+        context: &mut crate::context::Context,
     ) -> color_eyre::eyre::Result<StateCommand> {
-        let ctx = ctx
+        let ctx = context
             .game
             .as_mut()
             .ok_or(eyre!("No game in context while being in playing state"))?;
 
         use MyMessage::*;
         match message.try_into_ex()? {
-            UpdateActionText(action) => self.update_editor_content(action, EditorId::PlayerAction),
+            UpdateActionText(action) => {
+                self.update_editor_content(action, EditorId::PlayerAction, ctx)
+            }
             UpdateGMInstructionText(action) => {
-                self.update_editor_content(action, EditorId::GMInstruction)
+                self.update_editor_content(action, EditorId::GMInstruction, ctx)
             }
             ClearActionEditors => {
                 debug!("Handling ClearActionEditors in Playing state");
                 self.reset_action_editors();
+                self.refresh_prompt_estimate(ctx);
+                cmd::none()
+            }
+            RefreshPromptEstimate => {
+                self.refresh_prompt_estimate(ctx);
                 cmd::none()
             }
             ProposedActionButtonPressed(s) => {
@@ -108,42 +144,27 @@ impl State for Playing {
                     cmd::task(Task::done(Submit))
                 } else {
                     self.action_text_content = text_editor::Content::with_text(&s);
+                    self.refresh_prompt_estimate(ctx);
                     cmd::none()
                 }
             }
-            Submit => {
-                let input = TurnInput {
-                    player_action: self.action_text_content.text(),
-                    gm_instruction: self.gm_instruction_text_content.text(),
-                };
-                cmd::task(ctx.generate_new_turn(input))
-            }
-            PrevTurnButtonPressed => {
-                ctx.load_prev_turn()?;
-                cmd::none()
-            }
-            NextTurnButtonPressed => {
-                ctx.load_next_turn()?;
-                cmd::none()
-            }
+            Submit => cmd::task(ctx.generate_new_turn(self.turn_input())?),
+            PrevTurnButtonPressed => cmd::task(ctx.load_prev_turn()?),
+            NextTurnButtonPressed => cmd::task(ctx.load_next_turn()?),
             UpdateTurnInput(inp) => {
                 self.goto_turn_input = inp.parse().ok();
                 cmd::none()
             }
             GotoTurnPressed => {
                 if let Some(target) = self.goto_turn_input {
-                    ensure!(
-                        (1..=ctx.game.current_turn()).contains(&target),
-                        "Invalid turn number"
-                    );
-                    ctx.load_completed_turn(target - 1)?;
+                    let prefetch = ctx
+                        .goto_turn(target)
+                        .map_err(|_| eyre!("Invalid turn number: {target}"))?;
+                    return cmd::task(prefetch);
                 }
                 cmd::none()
             }
-            GoToCurrentTurn => {
-                ctx.load_completed_turn(ctx.game.current_turn() - 1)?;
-                cmd::none()
-            }
+            GoToCurrentTurn => cmd::task(ctx.load_latest_turn()?),
             ScrollOutputToTop => cmd::task(operation::snap_to::<Message>(
                 playing_output_scroll_id(),
                 operation::RelativeOffset::START,
@@ -159,7 +180,7 @@ impl State for Playing {
             LoadGameFromCurrentPastButtonPressed => cmd::transition(Modal::new(
                 State::clone(self),
                 ConfirmDialog::new(
-                    "Do you really want to load the Game from here?\nThis will delete all unsafed progress.",
+                    "Do you really want to load the Game from here?\nThe later turns are kept in a timeline of their own.",
                     Some(ConfirmLoadGameFromCurrentPast.into()),
                     None,
                 ),
@@ -169,6 +190,74 @@ impl State for Playing {
                 self.reset_action_editors();
                 cmd::none()
             }
+            UndoTurnPressed => cmd::transition(Modal::new(
+                State::clone(self),
+                ConfirmDialog::new(
+                    "Do you really want to undo the last turn?\nIts text and images will be deleted.",
+                    Some(ConfirmUndoTurn.into()),
+                    None,
+                ),
+            )),
+            ConfirmUndoTurn => {
+                ctx.undo_last_turn()?;
+                self.reset_action_editors();
+                cmd::none()
+            }
+            NewTimelinePressed => cmd::transition(Modal::input(
+                State::clone(self),
+                "Continue from here in a new timeline, and keep the later turns. Name it",
+                "Name",
+                |name| Task::done(MyMessage::NewTimeline(name).into()),
+            )),
+            NewTimeline(name) => {
+                ctx.continue_in_new_branch(name)?;
+                self.reset_action_editors();
+                cmd::none()
+            }
+            BookmarkPressed => {
+                if ctx.sub_state.turn_data()?.bookmarked {
+                    ctx.set_bookmark(None)?;
+                    return cmd::none();
+                }
+                cmd::transition(Modal::input(
+                    State::clone(self),
+                    "Bookmark this turn. Without a label, it's listed with its image caption",
+                    "Label (optional)",
+                    |label| Task::done(MyMessage::Bookmark(label).into()),
+                ))
+            }
+            Bookmark(label) => {
+                ctx.set_bookmark(Some(label))?;
+                cmd::none()
+            }
+            EditAndReplayPressed => cmd::transition(Modal::edit(
+                State::clone(self),
+                "Edit the action and replay from here.\nThe later turns are kept in a timeline of their own.",
+                ctx.input()?.player_action.clone(),
+                |s| Task::done(EditAndReplay(s).into()),
+            )),
+            EditAndReplay(action) => {
+                self.reset_action_editors();
+                cmd::task(ctx.replay_from_current_past(action)?)
+            }
+            BranchPressed => cmd::transition(Modal::input(
+                State::clone(self),
+                "Save the game up to this turn as",
+                "Name",
+                |name| Task::done(MyMessage::Branch(name).into()),
+            )),
+            Branch(name) => {
+                let path = context.branch_from_current_past(&name)?;
+                cmd::transition(Modal::message(
+                    State::clone(self),
+                    "Branch saved",
+                    format!(
+                        "The game up to this turn was saved to {}. You can continue it via \
+                         \"Saves\", this game is unchanged.",
+                        path.display()
+                    ),
+                ))
+            }
             ShowHiddenText => {
                 let hidden_info = ctx.hidden_info()?;
                 cmd::transition(Modal::edit(
@@ -202,6 +291,16 @@ impl State for Playing {
                     Task::done(MyMessage::UpdateSummary(s).into())
                 }))
             }
+            ShowTranscript => {
+                let transcript = ctx.transcript_for_current_turn()?;
+                cmd::transition(Modal::message(
+                    State::clone(self),
+                    "Transcript",
+                    transcript
+                        .as_deref()
+                        .unwrap_or("No transcript was stored for this turn."),
+                ))
+            }
             UpdateSummary(s) => {
                 ctx.update_summary_for_current_turn(s)?;
                 cmd::none()
@@ -222,7 +321,82 @@ impl State for Playing {
                 self.reset_action_editors();
                 cmd::task(ctx.regenerate_turn(s)?)
             }
-            ToMainMenu => cmd::transition(MainMenu::try_new()?),
+            // the title in the list of saves should tell where the story stands
+            ToMainMenu => {
+                cmd::transition_with_task(MainMenu::try_new()?, ctx.refresh_recap(false))
+            }
+            OpenGallery => {
+                let (gallery, load_thumbnails) = Gallery::new(State::clone(self), ctx)?;
+                cmd::transition_with_task(gallery, load_thumbnails)
+            }
+            OpenCodex => cmd::transition(Codex::new(State::clone(self), ctx)),
+            OpenTimelines => cmd::transition(Timelines::new(State::clone(self), ctx)),
+            OpenBookmarks => cmd::transition(Bookmarks::new(State::clone(self), ctx)),
+            ShowWorldState => cmd::transition(Modal::edit(
+                State::clone(self),
+                "World State (one \"key = value\" per line)",
+                world_state::to_text(&ctx.game.data.world_state),
+                |s| Task::done(UpdateWorldState(s).into()),
+            )),
+            UpdateWorldState(s) => {
+                ctx.set_world_state(&s)?;
+                cmd::none()
+            }
+            OpenGmTools => cmd::transition(Modal::new(
+                State::clone(self),
+                GmToolsDialog::new(ctx.game.data.world_description.tone),
+            )),
+            SaveAsPressed => cmd::transition(Modal::input(
+                State::clone(self),
+                "Save the game as",
+                "Name",
+                |name| Task::done(MyMessage::SaveAs(name).into()),
+            )),
+            SaveAs(name) => {
+                // written to the new save, which the game continues in
+                let refresh_recap = ctx.refresh_recap(false);
+                let path = context.save_game_as(&name)?;
+                cmd::transition_with_task(
+                    Modal::message(
+                        State::clone(self),
+                        "Game saved",
+                        format!("The game continues in {}", path.display()),
+                    ),
+                    refresh_recap,
+                )
+            }
+            ExportStoryPressed => {
+                let Some(path) = rfd::FileDialog::new()
+                    .set_file_name(format!("{}.epub", ctx.game.data.world_description.name))
+                    .add_filter("EPUB", &["epub"])
+                    .add_filter("HTML", &["html"])
+                    .add_filter("Markdown", &["md"])
+                    .save_file()
+                else {
+                    return cmd::none();
+                };
+                if ctx.game.data.turn_data.iter().all(|t| t.images.is_empty()) {
+                    return cmd::task(Task::done(ExportStory(path, false)));
+                }
+                cmd::transition(Modal::confirm(
+                    State::clone(self),
+                    "Include the images in the story?",
+                    Some(ExportStory(path.clone(), true).into()),
+                    Some(ExportStory(path, false).into()),
+                ))
+            }
+            ExportStory(path, with_images) => {
+                let format = StoryFormat::from_path(&path).ok_or(eyre!(
+                    "Stories can be exported as .epub, .html or .md files, not as {}",
+                    path.display()
+                ))?;
+                let n_turns = ctx.save.export_story(path.clone(), format, with_images)?;
+                cmd::transition(Modal::message(
+                    State::clone(self),
+                    "Story exported",
+                    format!("Wrote {n_turns} turns to {}", path.display()),
+                ))
+            }
             EditOutputPressed => cmd::transition(Modal::edit(
                 State::clone(self),
                 "Edit Output",
@@ -233,15 +407,63 @@ impl State for Playing {
                 ctx.update_output(s)?;
                 cmd::none()
             }
+            RetryImageDownload => cmd::task(ctx.retry_image_download()?),
+            RegenerateImage => cmd::task(ctx.regenerate_image(None)?),
+            RegenerateImageWithSeed(seed) => cmd::task(ctx.regenerate_image(Some(seed))?),
+            EditImagePressed => cmd::transition(Modal::input(
+                State::clone(self),
+                "Describe the fix",
+                "e.g. give the innkeeper a beard",
+                |instruction| Task::done(MyMessage::EditImage(instruction).into()),
+            )),
+            EditImage(instruction) => cmd::task(ctx.edit_image(instruction)?),
+            PinSeedPressed(seed) => cmd::transition(Modal::input(
+                State::clone(self),
+                "Pin the seed for images that mention",
+                "a character or location",
+                move |name| Task::done(MyMessage::PinSeed(name, seed).into()),
+            )),
+            PinSeed(name, seed) => {
+                ctx.pin_seed(name, seed)?;
+                cmd::none()
+            }
+            UnpinSeed(name) => {
+                ctx.unpin_seed(&name)?;
+                cmd::none()
+            }
+            SelectImage(id) => {
+                ctx.select_image(id)?;
+                cmd::none()
+            }
+            TextOnlyToggled(text_only) => {
+                ctx.set_text_only(text_only)?;
+                cmd::none()
+            }
+            CancelImage => cmd::task(ctx.cancel_image()?),
+            CancelTurn => {
+                ctx.cancel_turn()?;
+                cmd::none()
+            }
+            ToggleThoughts => {
+                self.show_thoughts = !self.show_thoughts;
+                cmd::none()
+            }
+            ResumeInterruptedTurn => cmd::task(ctx.resume_interrupted_turn()?),
+            FinalizeInterruptedTurn => {
+                ctx.finalize_interrupted_turn()?;
+                cmd::none()
+            }
         }
     }
 
     fn view<'a>(&'a self, ctx: &'a crate::context::Context) -> iced::Element<'a, UiMessage> {
+        let image_jobs = &ctx.image_jobs;
         let ctx = ctx
             .game
             .as_ref()
             .expect("No game in context while being in playing state");
 
+        let text_only = ctx.game.data.text_only;
         let mut sidebar = Column::new();
         if let Some(ImageData {
             handle,
@@ -266,6 +488,109 @@ impl State for Playing {
                 },
             ]);
         };
+        if ctx.failed_image_download.is_some() && matches!(ctx.sub_state, SubState::Complete(_)) {
+            sidebar = sidebar.extend(elem_list![
+                widget::text("The image was generated, but the download failed."),
+                button("Retry download").on_press(MyMessage::RetryImageDownload.into()),
+            ]);
+        }
+
+        if let Ok(turn_data) = ctx.sub_state.turn_data()
+            && !ctx.image_variants.is_empty()
+        {
+            let selected = turn_data.images.last().map(|info| info.id);
+            sidebar = sidebar.push(
+                row(ctx.image_variants.iter().map(|(id, handle)| {
+                    button(widget::image(handle).height(80))
+                        .style(if Some(*id) == selected {
+                            button::primary
+                        } else {
+                            button::secondary
+                        })
+                        .on_press(MyMessage::SelectImage(*id).into())
+                        .into()
+                }))
+                .spacing(5),
+            );
+        }
+
+        if let Ok(turn_data) = ctx.sub_state.turn_data()
+            && ctx.game.imgmod.is_some()
+        {
+            let seed = turn_data.images.last().and_then(|info| info.seed);
+            sidebar = sidebar.push(if ctx.regenerating_image {
+                widget::text("Generating a new image...").into_elem()
+            } else {
+                let mut buttons =
+                    row![button("New image").on_press(MyMessage::RegenerateImage.into())]
+                        .spacing(10);
+                if let Some(seed) = seed {
+                    buttons = buttons.push(
+                        button("Same seed")
+                            .on_press(MyMessage::RegenerateImageWithSeed(seed).into()),
+                    );
+                }
+                if !turn_data.images.is_empty()
+                    && ctx.game.imgmod.as_ref().is_some_and(|m| m.can_edit())
+                {
+                    buttons = buttons
+                        .push(button("Edit image").on_press(MyMessage::EditImagePressed.into()));
+                }
+                buttons.into_elem()
+            });
+            if let Some(seed) = seed {
+                sidebar = sidebar.push(
+                    row![
+                        widget::text!("Seed {seed}"),
+                        button("📌").on_press(MyMessage::PinSeedPressed(seed).into()),
+                    ]
+                    .align_y(Vertical::Center)
+                    .spacing(10),
+                );
+            }
+        }
+        sidebar = sidebar.extend(ctx.game.data.pinned_seeds.iter().map(|(name, seed)| {
+            row![
+                widget::text!("📌 {name}: {seed}"),
+                button("✕").on_press(MyMessage::UnpinSeed(name.clone()).into()),
+            ]
+            .align_y(Vertical::Center)
+            .spacing(10)
+            .into()
+        }));
+
+        if let Some(ImageProgressData {
+            status,
+            fraction,
+            preview,
+        }) = &ctx.image_progress
+        {
+            if let Some(preview) = preview {
+                sidebar = sidebar.push(widget::image(preview).height(300));
+            }
+            sidebar = sidebar.push(widget::text!("Image: {status}"));
+            if let Some(fraction) = fraction {
+                sidebar = sidebar.push(progress_bar(0.0..=1.0, *fraction).length(300).girth(10));
+            }
+        }
+
+        if ctx.image_stuck {
+            sidebar = sidebar.extend(elem_list![
+                widget::text("The image takes much longer than usual."),
+                button("Continue without image").on_press(MyMessage::CancelImage.into()),
+            ]);
+        }
+        sidebar = sidebar.extend(image_jobs.iter().map(|job| {
+            let state = match job.state {
+                JobState::Waiting => "waiting".to_string(),
+                JobState::Running { attempt: 1 } => "in progress".to_string(),
+                JobState::Running { attempt } => format!("attempt {attempt}"),
+                JobState::Retrying { attempt } => {
+                    format!("attempt {attempt} failed, retrying soon")
+                }
+            };
+            widget::text!("{}: {state}", job.model).size(14).into()
+        }));
 
         let mut main_col: Vec<Element<UiMessage>> = vec![];
         let mut text_col: Vec<Element<UiMessage>> = vec![];
@@ -281,8 +606,18 @@ impl State for Playing {
             text_col.push(widget::rule::horizontal(2).into());
         }
 
+        // the thoughts belong to the latest turn
+        if !ctx.thoughts.is_empty() && !matches!(ctx.sub_state, SubState::InThePast(_)) {
+            text_col.push(mk_thoughts(&ctx.thoughts, self.show_thoughts));
+        }
+
+        text_col.extend(
+            ctx.rolls()
+                .iter()
+                .map(|roll| widget::text!("🎲 {roll}").size(14).into()),
+        );
         text_col
-            .push(markdown::view(&ctx.output_markdown, Theme::TokyoNight).map(|_| unreachable!()));
+            .push(markdown::view(ctx.output_markdown(), Theme::TokyoNight).map(|_| unreachable!()));
 
         main_col.push(widget::column(text_col).spacing(20).into());
 
@@ -294,23 +629,25 @@ impl State for Playing {
                     button_w,
                     &self.action_text_content,
                     &self.gm_instruction_text_content,
+                    self.prompt_estimate,
                 )
                 .into_iter()
                 .chain(elem_list![
                     widget::rule::horizontal(1),
-                    mk_turn_selection_buttons(
-                        ctx,
-                        ctx.game.current_turn(),
-                        &self.goto_turn_string(),
-                    ),
+                    mk_turn_selection_buttons(ctx.turn_cursor(), &self.goto_turn_string()),
                     row![
                         space::horizontal(),
                         button("change turn").on_press(MyMessage::RegenerateButtonPressed.into()),
+                        button("undo turn").on_press_maybe(
+                            (ctx.game.current_turn() > 1)
+                                .then_some(MyMessage::UndoTurnPressed.into())
+                        ),
                         space::horizontal(),
                     ]
+                    .spacing(10)
                 ]);
                 main_col.extend([
-                    below_output_buttons(),
+                    below_output_buttons(ctx),
                     widget::column(elems)
                         .max_width(500)
                         .spacing(15)
@@ -318,29 +655,44 @@ impl State for Playing {
                         .into(),
                 ]);
             }
-            SubState::InThePast(InThePast {
-                completed_turn: turn,
-                data: _data,
-            }) => {
+            SubState::InThePast(_) => {
                 let elems = elem_list![
                     widget::Space::new().height(20),
-                    mk_turn_selection_buttons(ctx, *turn + 1, &self.goto_turn_string()),
+                    mk_turn_selection_buttons(ctx.turn_cursor(), &self.goto_turn_string()),
                     button("Goto current turn").on_press(MyMessage::GoToCurrentTurn.into()),
                     button("Load game from here")
-                        .on_press(MyMessage::LoadGameFromCurrentPastButtonPressed.into())
+                        .on_press(MyMessage::LoadGameFromCurrentPastButtonPressed.into()),
+                    button("New timeline from here...")
+                        .on_press(MyMessage::NewTimelinePressed.into()),
+                    button("Edit & replay").on_press_maybe(
+                        ctx.turn_cursor()
+                            .is_some_and(|c| c.has_prev())
+                            .then_some(MyMessage::EditAndReplayPressed.into())
+                    ),
+                    button("Branch from here...").on_press(MyMessage::BranchPressed.into())
                 ];
                 main_col.extend(elem_list![
-                    below_output_buttons(),
+                    below_output_buttons(ctx),
                     widget::column(elems)
                         .max_width(500)
                         .spacing(15)
                         .align_x(Horizontal::Center)
                 ]);
             }
+            SubState::WaitingForOutput(_) if ctx.can_cancel_turn() => {
+                main_col.push(
+                    row![
+                        space::horizontal(),
+                        button("Cancel").on_press(MyMessage::CancelTurn.into()),
+                        space::horizontal(),
+                    ]
+                    .into(),
+                );
+            }
             _ => {}
         }
 
-        let text_row = row![
+        let mut text_row = row![
             widget::column![
                 button("↑").on_press(MyMessage::ScrollOutputToTop.into()),
                 button("↓").on_press(MyMessage::ScrollOutputToBottom.into()),
@@ -364,13 +716,18 @@ impl State for Playing {
             .width(700)
             .padding(10)
             .style(|_theme| container::background(Color::from_rgb(0.95, 0.95, 0.95))),
-            sidebar
-                .align_x(Horizontal::Center)
-                .height(Length::Fill)
-                .spacing(5.)
         ]
         .spacing(20)
         .align_y(Vertical::Top);
+        // text-only games have nothing to show there
+        if !text_only {
+            text_row = text_row.push(
+                sidebar
+                    .align_x(Horizontal::Center)
+                    .height(Length::Fill)
+                    .spacing(5.),
+            );
+        }
 
         let main_col = widget::column![
             mk_header(ctx),
@@ -402,52 +759,75 @@ fn mk_header<'a>(ctx: &'a Context) -> Container<'a, UiMessage> {
         widget::row![
             widget::row![
                 button("☰").on_press(MyMessage::ToMainMenu.into()),
+                button("Gallery").on_press(MyMessage::OpenGallery.into()),
+                button("Codex").on_press(MyMessage::OpenCodex.into()),
+                button("Timelines").on_press(MyMessage::OpenTimelines.into()),
+                button("Bookmarks").on_press(MyMessage::OpenBookmarks.into()),
+                button("World State").on_press(MyMessage::ShowWorldState.into()),
+                button("GM Tools").on_press(MyMessage::OpenGmTools.into()),
+                button("Save as...").on_press(MyMessage::SaveAsPressed.into()),
+                button("Export story...").on_press(MyMessage::ExportStoryPressed.into()),
                 widget::space::horizontal()
             ]
             .align_y(Vertical::Center)
             .width(Length::FillPortion(1)),
-            widget::text!("{} - Turn {}", ctx.game.world_name(), ctx.current_turn()).size(32),
-            widget::Space::new().width(Length::FillPortion(1))
+            widget::column![
+                widget::text!("{} - Turn {}", ctx.game.world_name(), ctx.current_turn()).size(32),
+                widget::text(ctx.in_game_time().to_string()).size(16),
+            ]
+            .align_x(Horizontal::Center),
+            widget::row![
+                widget::space::horizontal(),
+                widget::checkbox(ctx.game.data.text_only)
+                    .label("Text only")
+                    .on_toggle(|b| MyMessage::TextOnlyToggled(b).into()),
+            ]
+            .align_y(Vertical::Center)
+            .width(Length::FillPortion(1)),
         ]
         .align_y(Vertical::Center),
     )
     .padding(10)
 }
+fn mk_thoughts(thoughts: &str, expanded: bool) -> Element<'_, UiMessage> {
+    let toggle = button(if expanded {
+        "▾ GM thoughts"
+    } else {
+        "▸ GM thoughts"
+    })
+    .style(button::text)
+    .on_press(MyMessage::ToggleThoughts.into());
+    if expanded {
+        widget::column![toggle, italic_text(thoughts)]
+            .spacing(5)
+            .into()
+    } else {
+        toggle.into()
+    }
+}
+
 fn proposed_action_button<'a>(text: &'a str) -> Button<'a, UiMessage> {
     button(text).on_press(MyMessage::ProposedActionButtonPressed(text.into()).into())
 }
 
 fn mk_turn_selection_buttons<'a>(
-    ctx: &'a Context,
-    current_turn: usize,
+    cursor: Option<TurnCursor>,
     goto_turn_input: &str,
 ) -> row::Row<'a, UiMessage> {
-    let mut row = vec![];
-    if current_turn > 1 {
-        row.push(
-            widget::button("←")
-                .on_press(MyMessage::PrevTurnButtonPressed.into())
-                .into_elem(),
-        );
-    }
-
-    row.extend(elem_list![
+    let has_prev = cursor.is_some_and(TurnCursor::has_prev);
+    let has_next = cursor.is_some_and(TurnCursor::has_next);
+    row![
+        widget::button("←")
+            .on_press_maybe(has_prev.then_some(MyMessage::PrevTurnButtonPressed.into())),
         widget::space::horizontal(),
         text_input("turn", goto_turn_input)
             .on_input(|t| MyMessage::UpdateTurnInput(t).into())
             .on_submit(MyMessage::GotoTurnPressed.into()),
         widget::button("Goto Turn").on_press(MyMessage::GotoTurnPressed.into()),
-        widget::space::horizontal()
-    ]);
-    if current_turn < ctx.game.current_turn() {
-        row.push(
-            widget::button("→")
-                .on_press(MyMessage::NextTurnButtonPressed.into())
-                .into(),
-        );
-    }
-
-    widget::row(row)
+        widget::space::horizontal(),
+        widget::button("→")
+            .on_press_maybe(has_next.then_some(MyMessage::NextTurnButtonPressed.into())),
+    ]
 }
 
 fn mk_input_ui_portion<'a>(
@@ -455,7 +835,25 @@ fn mk_input_ui_portion<'a>(
     button_w: u32,
     action_text_content: &'a text_editor::Content,
     gm_instruction_text_content: &'a text_editor::Content,
+    estimate: Option<PromptEstimate>,
 ) -> impl IntoIterator<Item = Element<'a, UiMessage>> {
+    let estimate_text = estimate.map_or_else(String::new, |estimate| {
+        let mut res = match estimate.dropped_turns {
+            0 => format!("about {} tokens", estimate.tokens),
+            1 => format!(
+                "about {} tokens, the oldest turn is left out",
+                estimate.tokens
+            ),
+            n => format!(
+                "about {} tokens, the oldest {n} turns are left out",
+                estimate.tokens
+            ),
+        };
+        if estimate.exceeds_context {
+            res.push_str(", ⚠ more than the LLM can take in");
+        }
+        res
+    });
     elem_list![
         widget::Space::new().height(20),
         proposed_action_button(&output.proposed_next_actions[0]).width(button_w),
@@ -473,22 +871,27 @@ fn mk_input_ui_portion<'a>(
             space::horizontal()
         ],
         widget::text_editor(gm_instruction_text_content)
-            .placeholder("Type an action")
+            .placeholder("Type an instruction, \"/set key = value\" changes the world state")
             .on_action(|a| MyMessage::UpdateGMInstructionText(a).into())
             .width(button_w),
         row![
+            widget::text(estimate_text).size(14),
             space::horizontal(),
             button("Go").on_press(MyMessage::Submit.into())
-        ],
+        ]
+        .align_y(Vertical::Center),
     ]
 }
 
-fn below_output_buttons() -> Element<'static, UiMessage> {
+fn below_output_buttons(ctx: &Context) -> Element<'static, UiMessage> {
+    let bookmarked = ctx.sub_state.turn_data().is_ok_and(|td| td.bookmarked);
     widget::row![
         space::horizontal(),
+        button(if bookmarked { "★" } else { "☆" }).on_press(MyMessage::BookmarkPressed.into()),
         button("✎").on_press(MyMessage::EditOutputPressed.into()),
         button("👁").on_press(MyMessage::ShowHiddenText.into()),
-        button("🧾").on_press(MyMessage::ShowSummary.into())
+        button("🧾").on_press(MyMessage::ShowSummary.into()),
+        button("📜").on_press(MyMessage::ShowTranscript.into())
     ]
     .spacing(10)
     .width(Length::Fill)
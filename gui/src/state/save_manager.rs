@@ -0,0 +1,680 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{Report, Result, eyre::bail};
+use engine::{
+    audit_log::AuditLog,
+    error::EngineError,
+    replay::Recorder,
+    save_archive::{SaveArchive, SaveMetadata, backup},
+    thumbnail::make_thumbnail,
+};
+use iced::{
+    Length, Task,
+    advanced::image::Handle as ImgHandle,
+    widget::{Space, button, column, image, row, space, text, tooltip},
+};
+use log::debug;
+
+use crate::{
+    TryIntoExt, bold_text,
+    context::{Context, copy_audit_log_of, game_context::to_handle, save_path_in},
+    elem_list, italic_text, load_active_game_save_path, load_remembered_saves,
+    message::ui_messages::SaveManager as MyMessage,
+    save_active_game_save_path, save_remembered_saves, saves_dir,
+    state::{MainMenu, Modal, Playing, State, StateCommand, cmd},
+    top_level_container,
+};
+
+const THUMBNAIL_SIZE: u32 = 96;
+const COVER_SIZE: u32 = 64;
+
+#[derive(Clone, Debug)]
+pub struct SaveManager {
+    saves: Vec<RememberedSaveEntry>,
+}
+
+#[derive(Clone, Debug)]
+struct RememberedSaveEntry {
+    path: PathBuf,
+    /// when it was last played, or else the modification time of the file
+    modified: Option<SystemTime>,
+    /// the latest image of the save
+    thumbnail: Option<ImgHandle>,
+    /// the cover of the save's world
+    cover: Option<ImgHandle>,
+    /// unknown for encrypted saves
+    metadata: Option<SaveMetadata>,
+    /// encrypted saves have neither a thumbnail nor a cover until they are loaded
+    encrypted: bool,
+}
+
+impl RememberedSaveEntry {
+    fn new(path: PathBuf) -> Self {
+        let ((metadata, thumbnail), encrypted) = match load_preview(&path) {
+            Ok(preview) => (preview, false),
+            Err(e) => {
+                debug!("No thumbnail for {path:?}: {e:#}");
+                (Default::default(), needs_password(&e))
+            }
+        };
+        Self {
+            modified: metadata
+                .as_ref()
+                .map(|m| m.last_played)
+                .or_else(|| fs::metadata(&path).and_then(|x| x.modified()).ok()),
+            thumbnail,
+            cover: metadata
+                .as_ref()
+                .and_then(|m| m.cover.as_ref())
+                .map(|cover| ImgHandle::from_bytes(cover.bytes().to_vec())),
+            metadata,
+            encrypted,
+            path,
+        }
+    }
+
+    /// The name the save was given, i.e. its file name without the extension
+    fn name(&self) -> String {
+        self.path
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .unwrap_or("<invalid file name>")
+            .to_string()
+    }
+
+    fn filename(&self) -> String {
+        self.path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("<invalid file name>")
+            .to_string()
+    }
+}
+
+impl SaveManager {
+    pub fn try_new() -> Result<Self> {
+        let mut saves = load_remembered_saves()?
+            .into_iter()
+            .map(RememberedSaveEntry::new)
+            .collect::<Vec<_>>();
+
+        saves.sort_by_key(|save| std::cmp::Reverse(save.modified));
+
+        debug!(
+            "Remembered saves:\n{}",
+            saves
+                .iter()
+                .map(|save| format!("{} -> {:?}", save.filename(), save.path))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+
+        Ok(Self { saves })
+    }
+
+    fn write_remembered_saves_index(&self) -> Result<()> {
+        let remembered: Vec<_> = self.saves.iter().map(|save| save.path.clone()).collect();
+        save_remembered_saves(&remembered)
+    }
+
+    fn open_save_via_dialog(&mut self) -> Result<Option<PathBuf>> {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("World Weaver saves", &["wwsave"])
+            .pick_file()
+        else {
+            return Ok(None);
+        };
+        let entry = RememberedSaveEntry::new(path.clone());
+
+        if let Some(existing) = self.saves.iter_mut().find(|save| save.path == path) {
+            *existing = entry;
+        } else {
+            self.saves.push(entry);
+            self.write_remembered_saves_index()?;
+        }
+        self.saves
+            .sort_by_key(|save| std::cmp::Reverse(save.modified));
+        Ok(Some(path))
+    }
+
+    /// Asks for the password if the save is encrypted, and again if it was wrong
+    pub fn load(
+        &self,
+        ctx: &mut Context,
+        path: PathBuf,
+        password: Option<String>,
+    ) -> Result<StateCommand> {
+        match ctx.load_game_from_path(&path, password.as_deref()) {
+            Ok(_) => {}
+            Err(e) if needs_password(&e) => {
+                return cmd::transition(Modal::password(
+                    State::clone(self),
+                    password_prompt(&password),
+                    move |password| {
+                        Task::done(MyMessage::LoadWithPassword(path.clone(), password).into())
+                    },
+                ));
+            }
+            Err(e) => return Err(e),
+        }
+        save_active_game_save_path(&path)?;
+        cmd::transition(Playing::new())
+    }
+
+    /// Writes the save to a zip file, after asking for its password if it's encrypted
+    fn export(&self, path: PathBuf, password: Option<String>) -> Result<StateCommand> {
+        let mut archive = match SaveArchive::open_read_only(&path, password.as_deref()) {
+            Ok(archive) => archive,
+            Err(EngineError::PasswordRequired | EngineError::WrongPassword) => {
+                return cmd::transition(Modal::password(
+                    State::clone(self),
+                    password_prompt(&password),
+                    move |password| {
+                        Task::done(MyMessage::ExportWithPassword(path.clone(), password).into())
+                    },
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let Some(bundle) = rfd::FileDialog::new()
+            .add_filter("Zip files", &["zip"])
+            .set_file_name(format!("{stem}.zip"))
+            .save_file()
+        else {
+            return cmd::none();
+        };
+        archive.export_bundle(&bundle)?;
+        cmd::transition(Modal::message(
+            State::clone(self),
+            "Save exported",
+            format!(
+                "Wrote the game data and the images to {}. The bundle isn't encrypted.",
+                bundle.display()
+            ),
+        ))
+    }
+
+    /// Continues the save at `path` from its snapshot after `turn` in a new save, after asking
+    /// for its password if it's encrypted
+    fn load_snapshot(
+        &self,
+        ctx: &mut Context,
+        path: PathBuf,
+        turn: usize,
+        name: String,
+        password: Option<String>,
+    ) -> Result<StateCommand> {
+        match ctx.load_snapshot(&path, turn, &name, password.as_deref()) {
+            Ok(_) => cmd::transition(Playing::new()),
+            Err(e) if needs_password(&e) => cmd::transition(Modal::password(
+                State::clone(self),
+                password_prompt(&password),
+                move |password| {
+                    Task::done(
+                        MyMessage::SnapshotWithPassword(path.clone(), turn, name.clone(), password)
+                            .into(),
+                    )
+                },
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the images no turn refers to from the save, after asking for its password if
+    /// it's encrypted
+    fn compact(
+        &mut self,
+        ctx: &mut Context,
+        path: PathBuf,
+        password: Option<String>,
+    ) -> Result<StateCommand> {
+        let compaction = if is_running(ctx, &path)? {
+            ctx.game.as_mut().unwrap().compact_save()?
+        } else {
+            let archive = match password.as_deref() {
+                Some(password) => SaveArchive::open_with_password(&path, password),
+                None => SaveArchive::open(&path),
+            };
+            let mut archive = match archive {
+                Ok(archive) => archive,
+                Err(EngineError::PasswordRequired | EngineError::WrongPassword) => {
+                    return cmd::transition(Modal::password(
+                        State::clone(self),
+                        password_prompt(&password),
+                        move |password| {
+                            Task::done(
+                                MyMessage::CompactWithPassword(path.clone(), password).into(),
+                            )
+                        },
+                    ));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            archive.set_backups(ctx.config.backups);
+            archive.compact()?
+        };
+        self.refresh(&path);
+        cmd::transition(Modal::message(
+            State::clone(self),
+            "Save compacted",
+            format!(
+                "Removed {} images, {} KiB",
+                compaction.removed_images,
+                compaction.freed_bytes / 1024
+            ),
+        ))
+    }
+
+    /// Renames the save, and its backups, audit log and recording with it
+    fn rename(&mut self, ctx: &Context, path: PathBuf, name: String) -> Result<StateCommand> {
+        if is_running(ctx, &path)? {
+            bail!("The running game is played in this save, load another one to rename it");
+        }
+        let new_path = save_path_in(path.parent().unwrap_or(Path::new("")), &name)?;
+        let backups = backup::list(&path);
+        fs::rename(&path, &new_path)?;
+        for (n, (backup, _)) in (1..).zip(backups) {
+            fs::rename(backup, backup::path_for(&new_path, n))?;
+        }
+        if AuditLog::path_for(&path).exists() {
+            fs::rename(AuditLog::path_for(&path), AuditLog::path_for(&new_path))?;
+        }
+        if Recorder::path_for(&path).exists() {
+            fs::rename(Recorder::path_for(&path), Recorder::path_for(&new_path))?;
+        }
+        if load_active_game_save_path()?.as_deref() == Some(path.as_path()) {
+            save_active_game_save_path(&new_path)?;
+        }
+        for save in self.saves.iter_mut().filter(|save| save.path == path) {
+            *save = RememberedSaveEntry::new(new_path.clone());
+        }
+        self.write_remembered_saves_index()?;
+        cmd::none()
+    }
+
+    /// Copies the save next to it, and lists the copy below it
+    fn duplicate(&mut self, path: PathBuf, name: String) -> Result<StateCommand> {
+        let copy = save_path_in(path.parent().unwrap_or(Path::new("")), &name)?;
+        fs::copy(&path, &copy)?;
+        copy_audit_log_of(&path, &copy)?;
+        let idx = self
+            .saves
+            .iter()
+            .position(|save| save.path == path)
+            .map_or(0, |i| i + 1);
+        self.saves.insert(idx, RememberedSaveEntry::new(copy));
+        self.write_remembered_saves_index()?;
+        cmd::none()
+    }
+
+    /// Deletes the save, its backups, its audit log and its recording
+    fn delete(&mut self, ctx: &Context, path: PathBuf) -> Result<StateCommand> {
+        if is_running(ctx, &path)? {
+            bail!("The running game is played in this save, load another one to delete it");
+        }
+        for (backup, _) in backup::list(&path) {
+            fs::remove_file(backup)?;
+        }
+        if AuditLog::path_for(&path).exists() {
+            fs::remove_file(AuditLog::path_for(&path))?;
+        }
+        if Recorder::path_for(&path).exists() {
+            fs::remove_file(Recorder::path_for(&path))?;
+        }
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        self.saves.retain(|save| save.path != path);
+        self.write_remembered_saves_index()?;
+        cmd::none()
+    }
+
+    /// Reads the preview of the save at `path` again, after it was changed
+    fn refresh(&mut self, path: &Path) {
+        for save in self.saves.iter_mut().filter(|save| save.path == path) {
+            *save = RememberedSaveEntry::new(path.to_path_buf());
+        }
+    }
+
+    /// Creates a save from a zip file that was exported before, and lists it
+    fn import(&mut self) -> Result<StateCommand> {
+        let Some(bundle) = rfd::FileDialog::new()
+            .add_filter("Zip files", &["zip"])
+            .pick_file()
+        else {
+            return cmd::none();
+        };
+        let dir = saves_dir()?;
+        fs::create_dir_all(&dir)?;
+        let stem = bundle.file_stem().unwrap_or_default().to_string_lossy();
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("World Weaver saves", &["wwsave"])
+            .set_directory(&dir)
+            .set_file_name(format!("{stem}.wwsave"))
+            .save_file()
+        else {
+            return cmd::none();
+        };
+        SaveArchive::import_bundle(&bundle, &path)?;
+        self.saves.retain(|save| save.path != path);
+        self.saves.insert(0, RememberedSaveEntry::new(path.clone()));
+        self.write_remembered_saves_index()?;
+        cmd::transition(Modal::message(
+            State::clone(self),
+            "Save imported",
+            format!("Created {}", path.display()),
+        ))
+    }
+}
+
+fn password_prompt(password: &Option<String>) -> &'static str {
+    match password {
+        Some(_) => "The password is wrong, please try again:",
+        None => "This save is encrypted, please enter its password:",
+    }
+}
+
+/// Whether `path` is the save of the game that is loaded, which holds it open
+fn is_running(ctx: &Context, path: &Path) -> Result<bool> {
+    Ok(ctx.game.is_some() && load_active_game_save_path()?.as_deref() == Some(path))
+}
+
+/// Whether opening a save failed only because the password is missing or wrong
+pub fn needs_password(err: &Report) -> bool {
+    matches!(
+        err.downcast_ref::<EngineError>(),
+        Some(EngineError::PasswordRequired | EngineError::WrongPassword)
+    )
+}
+
+impl State for SaveManager {
+    fn update(
+        &mut self,
+        event: crate::message::UiMessage,
+        ctx: &mut crate::context::Context,
+    ) -> Result<super::StateCommand> {
+        let msg: MyMessage = event.try_into_ex()?;
+        use MyMessage::*;
+
+        match msg {
+            OpenSave => {
+                let Some(path) = self.open_save_via_dialog()? else {
+                    return cmd::none();
+                };
+                self.load(ctx, path, None)
+            }
+            LoadSave(i) => self.load(ctx, self.saves[i].path.clone(), None),
+            LoadWithPassword(path, password) => self.load(ctx, path, Some(password)),
+            ExportSave(i) => self.export(self.saves[i].path.clone(), None),
+            ExportWithPassword(path, password) => self.export(path, Some(password)),
+            ImportBundle => self.import(),
+            LoadSnapshot(path, turn) => cmd::transition(Modal::input(
+                State::clone(self),
+                format!("Continue from turn {} in a new save called", turn + 1),
+                "Name",
+                move |name| Task::done(SnapshotNamed(path.clone(), turn, name).into()),
+            )),
+            SnapshotNamed(path, turn, name) => self.load_snapshot(ctx, path, turn, name, None),
+            SnapshotWithPassword(path, turn, name, password) => {
+                self.load_snapshot(ctx, path, turn, name, Some(password))
+            }
+            RenameSave(i) => {
+                let path = self.saves[i].path.clone();
+                cmd::transition(Modal::input(
+                    State::clone(self),
+                    format!("Rename {} to", self.saves[i].name()),
+                    "Name",
+                    move |name| Task::done(Rename(path.clone(), name).into()),
+                ))
+            }
+            Rename(path, name) => self.rename(ctx, path, name),
+            DuplicateSave(i) => {
+                let path = self.saves[i].path.clone();
+                cmd::transition(Modal::input(
+                    State::clone(self),
+                    format!("Copy {} to a save called", self.saves[i].name()),
+                    "Name",
+                    move |name| Task::done(Duplicate(path.clone(), name).into()),
+                ))
+            }
+            Duplicate(path, name) => self.duplicate(path, name),
+            DeleteSave(i) => cmd::transition(Modal::confirm(
+                State::clone(self),
+                format!(
+                    "Delete {}? Its backups and its log of LLM requests are deleted as well.",
+                    self.saves[i].name()
+                ),
+                Some(Delete(self.saves[i].path.clone()).into()),
+                None,
+            )),
+            Delete(path) => self.delete(ctx, path),
+            CompactSave(i) => self.compact(ctx, self.saves[i].path.clone(), None),
+            CompactWithPassword(path, password) => self.compact(ctx, path, Some(password)),
+            Back => cmd::transition(MainMenu::try_new()?),
+            ForgetSave(i) => {
+                self.saves.remove(i);
+                self.write_remembered_saves_index()?;
+                cmd::none()
+            }
+        }
+    }
+
+    fn view<'a>(
+        &'a self,
+        _ctx: &'a crate::context::Context,
+    ) -> iced::Element<'a, crate::message::UiMessage> {
+        let mut tlc = Vec::from(elem_list![
+            bold_text("Saves").width(Length::Fill).center(),
+            Space::new().height(30),
+            row![
+                space::horizontal(),
+                button("Open...").on_press(MyMessage::OpenSave.into()),
+                button("Import...").on_press(MyMessage::ImportBundle.into()),
+                button("Back").on_press(MyMessage::Back.into()),
+                space::horizontal()
+            ]
+            .spacing(10)
+        ]);
+
+        for (i, save) in self.saves.iter().enumerate() {
+            let is_available = save.path.exists();
+            let warning: iced::Element<'_, crate::message::UiMessage> = if is_available {
+                Space::new()
+                    .width(Length::Shrink)
+                    .height(Length::Shrink)
+                    .into()
+            } else {
+                tooltip(
+                    text("⚠"),
+                    "This save file is missing or unreadable.",
+                    tooltip::Position::Top,
+                )
+                .into()
+            };
+
+            let time = save
+                .modified
+                .map(format_system_time_utc)
+                .unwrap_or_else(|| "<unavailable>".to_string());
+
+            // only forgetting and deleting work without the file
+            let on_press = |msg: MyMessage| is_available.then(|| msg.into());
+            let actions = row![
+                button("forget").on_press(MyMessage::ForgetSave(i).into()),
+                button("Delete").on_press(MyMessage::DeleteSave(i).into()),
+                button("Rename...").on_press_maybe(on_press(MyMessage::RenameSave(i))),
+                button("Duplicate...").on_press_maybe(on_press(MyMessage::DuplicateSave(i))),
+                button("Compact").on_press_maybe(on_press(MyMessage::CompactSave(i))),
+                button("Export...").on_press_maybe(on_press(MyMessage::ExportSave(i))),
+                button("Load").on_press_maybe(on_press(MyMessage::LoadSave(i))),
+            ]
+            .spacing(10);
+
+            let thumbnail: iced::Element<'_, crate::message::UiMessage> = match &save.thumbnail {
+                Some(handle) => image(handle).width(THUMBNAIL_SIZE as f32).into(),
+                None => Space::new().width(THUMBNAIL_SIZE as f32).into(),
+            };
+            let cover: iced::Element<'_, crate::message::UiMessage> = match &save.cover {
+                Some(handle) => image(handle).width(COVER_SIZE as f32).into(),
+                None => Space::new().width(COVER_SIZE as f32).into(),
+            };
+
+            let mut info = column![text(save.name())].spacing(4);
+            if let Some(title) = save.metadata.as_ref().and_then(|m| m.title.as_deref()) {
+                info = info.push(italic_text(title));
+            }
+            info = info
+                .push(text(save.path.display().to_string()).size(14))
+                .push(text(time).size(14));
+            if let Some(m) = &save.metadata {
+                info = info.push(
+                    text!(
+                        "{} in {}, {} turns, played for {}",
+                        m.character,
+                        m.world_name,
+                        m.turns,
+                        format_playtime(m.playtime)
+                    )
+                    .size(14),
+                );
+            }
+            if save.encrypted {
+                info = info.push(text("Encrypted").size(14));
+            }
+            if let Some(m) = &save.metadata
+                && !m.snapshots.is_empty()
+                && is_available
+            {
+                let snapshots = m.snapshots.iter().map(|&turn| {
+                    button(text!("Turn {}", turn + 1).size(14))
+                        .padding([2, 6])
+                        .on_press(MyMessage::LoadSnapshot(save.path.clone(), turn).into())
+                        .into()
+                });
+                info = info.push(
+                    row![text("Snapshots:").size(14)]
+                        .extend(snapshots)
+                        .spacing(6)
+                        .align_y(iced::alignment::Vertical::Center),
+                );
+            }
+
+            tlc.push(
+                row![
+                    warning,
+                    cover,
+                    thumbnail,
+                    info,
+                    space::horizontal(),
+                    actions
+                ]
+                .spacing(10)
+                .into(),
+            );
+        }
+
+        top_level_container(
+            column(tlc)
+                .spacing(20)
+                .width(Length::Fill)
+                .height(Length::Fill),
+        )
+        .into()
+    }
+
+    fn clone(&self) -> Box<dyn State> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+/// Returns the metadata of the save, and the thumbnail of its latest image
+fn load_preview(path: &Path) -> Result<(Option<SaveMetadata>, Option<ImgHandle>)> {
+    let mut archive = SaveArchive::open_read_only(path, None)?;
+    let metadata = archive.read_metadata()?;
+    let Some(id) = metadata.thumbnail else {
+        return Ok((Some(metadata), None));
+    };
+    let bytes = match archive.read_thumbnail(id)? {
+        Some(bytes) => bytes,
+        None => archive.read_image(id)?,
+    };
+    let thumbnail = make_thumbnail(&bytes, THUMBNAIL_SIZE)?;
+    Ok((Some(metadata), Some(to_handle(&thumbnail))))
+}
+
+fn format_playtime(playtime: Duration) -> String {
+    let minutes = playtime.as_secs() / 60;
+    match minutes / 60 {
+        0 => format!("{minutes} min"),
+        hours => format!("{hours} h {} min", minutes % 60),
+    }
+}
+
+fn format_system_time_utc(t: SystemTime) -> String {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => return "<invalid time>".into(),
+    };
+
+    const SECS_PER_MIN: u64 = 60;
+    const SECS_PER_HOUR: u64 = 60 * SECS_PER_MIN;
+    const SECS_PER_DAY: u64 = 24 * SECS_PER_HOUR;
+
+    let days = secs / SECS_PER_DAY;
+    let secs_of_day = secs % SECS_PER_DAY;
+
+    let hour = secs_of_day / SECS_PER_HOUR;
+    let min = (secs_of_day % SECS_PER_HOUR) / SECS_PER_MIN;
+    let sec = secs_of_day % SECS_PER_MIN;
+
+    let (year, month, day) = days_to_ymd(days as i64);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, hour, min, sec
+    )
+}
+
+fn days_to_ymd(mut days: i64) -> (i32, u32, u32) {
+    let mut year = 1970;
+
+    while days >= if is_leap(year) { 366 } else { 365 } {
+        days -= if is_leap(year) { 366 } else { 365 };
+        year += 1;
+    }
+
+    let month_days = [
+        31,
+        if is_leap(year) { 29 } else { 28 },
+        31,
+        30,
+        31,
+        30,
+        31,
+        31,
+        30,
+        31,
+        30,
+        31,
+    ];
+
+    let mut month = 0;
+    for &month_len in &month_days {
+        if days < month_len {
+            break;
+        }
+        days -= month_len;
+        month += 1;
+    }
+
+    (year, (month + 1) as u32, (days + 1) as u32)
+}
+
+fn is_leap(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
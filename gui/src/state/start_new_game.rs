@@ -1,16 +1,21 @@
-use color_eyre::eyre::Result;
+use std::{path::Path, sync::Arc};
+
+use color_eyre::eyre::{Result, ensure};
 use engine::{
-    game::{Game, WorldDescription},
+    audit_log::AuditLog,
+    game::{Difficulty, Game, WorldDescription},
+    replay::Recorder,
     save_archive::SaveArchive,
 };
 use iced::{
     Font, Length, Task,
-    widget::{Space, button, column, text},
+    widget::{Space, button, checkbox, column, radio, row, text, text_input},
 };
+use strum::IntoEnumIterator;
 
 use crate::{
-    Config, TryIntoExt, bold_default_font, load_remembered_saves,
-    save_active_game_save_path, save_remembered_saves,
+    Config, TryIntoExt, bold_default_font, remember_save,
+    save_active_game_save_path,
     context::{Context, game_context::GameContext},
     elem_list,
     message::{ContextMessage, Message, UiMessage, ui_messages::StartNewGame as MyMessage},
@@ -23,21 +28,48 @@ use super::{State, StateCommand};
 #[derive(Debug, Clone)]
 pub struct StartNewGame {
     world: WorldDescription,
+    difficulty: Difficulty,
+    /// whether the save is encrypted with `password`
+    encrypt: bool,
+    password: String,
+    /// must match `password`, so a typo doesn't lock the player out of their save
+    password_repeated: String,
 }
 
 impl StartNewGame {
     pub fn new(world: WorldDescription) -> Self {
-        Self { world }
+        Self {
+            world,
+            difficulty: Difficulty::default(),
+            encrypt: false,
+            password: String::new(),
+            password_repeated: String::new(),
+        }
     }
 
-    fn create_game(&self, c: String, config: &Config) -> Result<Game> {
-        Game::try_new(
+    fn create_game(&self, c: String, config: &Config, save_path: &Path) -> Result<Game> {
+        let config = config.for_world(&self.world);
+        let mut game = Game::try_new(
             config.get_llm()?,
-            config.get_image_model()?,
+            config.get_image_model(),
             self.world.clone(),
             c,
             config.active_style().cloned(),
-        )
+            self.difficulty,
+        )?;
+        game.summary_llm = config.get_summary_llm()?;
+        game.context_budget = config.context_budget;
+        game.context_window = config.context_window();
+        game.history_turns = config.history_turns();
+        game.summary_interval = config.summary_interval();
+        game.batch_summaries = config.batch_summaries;
+        game.extra_image_variants = config.extra_image_variants;
+        game.snapshot_every = config.snapshot_every;
+        game.audit_log = Some(Arc::new(AuditLog::open(&AuditLog::path_for(save_path))?));
+        if config.record_sessions {
+            game.recorder = Some(Arc::new(Recorder::open(&Recorder::path_for(save_path))?));
+        }
+        Ok(game)
     }
 
     fn default_save_filename(&self, character: &str) -> String {
@@ -53,6 +85,13 @@ impl State for StartNewGame {
         use MyMessage::*;
         match event.try_into_ex()? {
             Selected(c) => {
+                if self.encrypt {
+                    ensure!(!self.password.is_empty(), "Please enter a password for the save");
+                    ensure!(
+                        self.password == self.password_repeated,
+                        "The passwords don't match"
+                    );
+                }
                 let Some(path) = rfd::FileDialog::new()
                     .add_filter("World Weaver saves", &["wwsave"])
                     .set_file_name(self.default_save_filename(&c))
@@ -62,15 +101,16 @@ impl State for StartNewGame {
                 };
 
                 ctx.game = None;
-                let game = self.create_game(c, &ctx.config)?;
-                let archive = SaveArchive::create(&path)?;
-                ctx.game = Some(GameContext::try_new(game, archive)?);
+                let game = self.create_game(c, &ctx.config, &path)?;
+                let mut archive = if self.encrypt {
+                    SaveArchive::create_encrypted(&path, &self.password)?
+                } else {
+                    SaveArchive::create(&path)?
+                };
+                archive.set_backups(ctx.config.backups);
+                ctx.game = Some(GameContext::try_new(game, archive, ctx.config.image_storage)?);
 
-                let mut remembered_saves = load_remembered_saves()?;
-                if !remembered_saves.contains(&path) {
-                    remembered_saves.push(path.clone());
-                    save_remembered_saves(&remembered_saves)?;
-                }
+                remember_save(&path)?;
                 save_active_game_save_path(&path)?;
 
                 cmd::transition_with_task::<Message>(
@@ -78,14 +118,66 @@ impl State for StartNewGame {
                     Task::done(ContextMessage::Init.into()),
                 )
             }
+            DifficultySelected(difficulty) => {
+                self.difficulty = difficulty;
+                cmd::none()
+            }
+            EncryptToggled(encrypt) => {
+                self.encrypt = encrypt;
+                cmd::none()
+            }
+            PasswordChanged(password) => {
+                self.password = password;
+                cmd::none()
+            }
+            PasswordRepeated(password) => {
+                self.password_repeated = password;
+                cmd::none()
+            }
         }
     }
 
-    fn view<'a>(&'a self, _ctx: &'a Context) -> iced::Element<'a, UiMessage> {
+    fn view<'a>(&'a self, ctx: &'a Context) -> iced::Element<'a, UiMessage> {
         let mut tlc = Vec::from(elem_list![
             text!("New Game - {}", self.world.name)
                 .font(bold_default_font())
                 .size(20),
+        ]);
+        if let Some(model) = self.world.preferred_llm {
+            tlc.push(text!("This world is narrated by {model}, instead of {}.", ctx.config.current_llm).into());
+        }
+        if let Some(model) = self.world.preferred_image_model {
+            tlc.push(text!("Its images are made by {model}, instead of {}.", ctx.config.current_img_model).into());
+        }
+        tlc.push(
+            row([text("Difficulty:").into()]
+                .into_iter()
+                .chain(Difficulty::iter().map(|d| {
+                    radio(format!("{d}"), d, Some(self.difficulty), |d| {
+                        MyMessage::DifficultySelected(d).into()
+                    })
+                    .into()
+                })))
+            .spacing(20)
+            .into(),
+        );
+        tlc.push(
+            checkbox(self.encrypt)
+                .label("Encrypt the save with a password. Without it, the save can't be loaded.")
+                .on_toggle(|b| MyMessage::EncryptToggled(b).into())
+                .into(),
+        );
+        if self.encrypt {
+            tlc.extend(elem_list![
+                text_input("Password", &self.password)
+                    .secure(true)
+                    .on_input(|s| MyMessage::PasswordChanged(s).into()),
+                text_input("Repeat the password", &self.password_repeated)
+                    .secure(true)
+                    .on_input(|s| MyMessage::PasswordRepeated(s).into()),
+            ]);
+        }
+        tlc.extend(elem_list![
             text("Select a Character:"),
             Space::new().height(20)
         ]);
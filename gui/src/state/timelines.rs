@@ -0,0 +1,131 @@
+//! The branches of the running game, see `engine::game::Branch`. They are called timelines
+//! here, since branching a game already means saving a copy of it.
+
+use color_eyre::{Result, eyre::eyre};
+use iced::{
+    Element, Length,
+    widget::{button, column, row, rule, scrollable, space, text},
+};
+
+use crate::{
+    TryIntoExt, bold_text,
+    context::{Context, game_context::GameContext},
+    message::{UiMessage, ui_messages::Timelines as MyMessage},
+    state::{State, StateCommand, cmd},
+    top_level_container,
+};
+
+#[derive(Debug)]
+pub struct Timelines {
+    /// returned to when a timeline is chosen
+    parent: Box<dyn State>,
+    current: String,
+    n_turns: usize,
+    others: Vec<Timeline>,
+}
+
+#[derive(Debug, Clone)]
+struct Timeline {
+    name: String,
+    forked_after: usize,
+    last_turn: usize,
+}
+
+impl Timelines {
+    pub fn new(parent: Box<dyn State>, ctx: &GameContext) -> Self {
+        let data = &ctx.game.data;
+        Self {
+            parent,
+            current: data.branch_name().into(),
+            n_turns: data.turn_data.len(),
+            others: data
+                .branches
+                .iter()
+                .map(|b| Timeline {
+                    name: b.name.clone(),
+                    forked_after: b.forked_after,
+                    last_turn: b.last_turn(),
+                })
+                .collect(),
+        }
+    }
+
+    fn view_timeline((i, timeline): (usize, &Timeline)) -> Element<'_, UiMessage> {
+        column![
+            row![
+                bold_text(&timeline.name).size(20),
+                text(format!(
+                    "Leaves this one after turn {}, and goes on to turn {}",
+                    timeline.forked_after + 1,
+                    timeline.last_turn + 1
+                )),
+                space::horizontal(),
+                button("Continue here").on_press(MyMessage::Switch(i).into()),
+            ]
+            .spacing(10)
+            .align_y(iced::alignment::Vertical::Center),
+            rule::horizontal(1),
+        ]
+        .spacing(5)
+        .into()
+    }
+}
+
+impl State for Timelines {
+    fn update(&mut self, event: UiMessage, ctx: &mut Context) -> Result<StateCommand> {
+        let ctx = ctx.game.as_mut().ok_or(eyre!("There is no running game"))?;
+        use MyMessage::*;
+        match event.try_into_ex()? {
+            Back => cmd::transition(self.parent.clone()),
+            Switch(i) => {
+                ctx.switch_branch(i)?;
+                cmd::transition(self.parent.clone())
+            }
+        }
+    }
+
+    fn view<'a>(&'a self, _ctx: &'a Context) -> Element<'a, UiMessage> {
+        let others: Element<'_, UiMessage> = if self.others.is_empty() {
+            text(
+                "There are no other timelines yet. Go to a past turn and choose \
+                \"New timeline from here...\" to try something else without losing what \
+                happened since.",
+            )
+            .into()
+        } else {
+            scrollable(column(self.others.iter().enumerate().map(Self::view_timeline)).spacing(15))
+                .height(Length::Fill)
+                .into()
+        };
+
+        top_level_container(
+            column![
+                row![
+                    button("Back").on_press(MyMessage::Back.into()),
+                    space::horizontal(),
+                    bold_text("Timelines").size(32),
+                    space::horizontal(),
+                ]
+                .align_y(iced::alignment::Vertical::Center),
+                text(format!(
+                    "You are playing \"{}\", which has {} turns.",
+                    self.current, self.n_turns
+                )),
+                others,
+            ]
+            .spacing(20)
+            .width(Length::Fill)
+            .height(Length::Fill),
+        )
+        .into()
+    }
+
+    fn clone(&self) -> Box<dyn State> {
+        Box::new(Self {
+            parent: self.parent.clone(),
+            current: self.current.clone(),
+            n_turns: self.n_turns,
+            others: self.others.clone(),
+        })
+    }
+}
@@ -13,24 +13,33 @@ use crate::{
     save_remembered_worlds,
     state::{
         MainMenu, Modal, Playing, StateCommand, StateExt, WorldMenu, cmd,
-        start_new_game::StartNewGame,
+        modal::gm_tools::tone_sliders, start_new_game::StartNewGame,
     },
 };
 
 use color_eyre::{
     Result,
-    eyre::{bail, ensure, eyre},
+    eyre::{WrapErr as _, bail, ensure, eyre},
 };
-use engine::game::{PcDescription, WorldDescription};
+use engine::game::{
+    ContentRating, DEFAULT_MAX_TOKENS, DEFAULT_TARGET_OUTPUT_WORDS, DetailKind, PcDescription,
+    Script, Tone, WorldDescription, WorldDetail, summary, system_prompt,
+};
+use engine::image_model;
+use engine::llm::{self, Sampling};
+use engine::world_cover::{self, Cover};
 use engine::world_markdown::world_to_markdown;
 use iced::{
     Color, Font, Length, Task, padding,
+    advanced::image::Handle as ImgHandle,
     widget::{
-        Space, button, column, container, row, rule, scrollable, space, text, text_editor,
-        text_input,
+        Space, button, checkbox, column, container, image, radio, row, rule, scrollable, space,
+        text, text_editor, text_input,
     },
 };
 
+use strum::IntoEnumIterator;
+
 use super::State;
 
 type ActionFnArc = Arc<dyn Fn(&mut WorldEditor, &mut Context) -> Result<StateCommand>>;
@@ -41,6 +50,29 @@ pub struct WorldEditor {
     description: text_editor::Content,
     init_action: text_editor::Content,
     characters: BTreeMap<String, CharacterInputs>,
+    /// the locations, factions and key NPCs, a kind without entry has none
+    details: BTreeMap<DetailKind, Vec<DetailInputs>>,
+    /// the tab of the details of that kind, `None` for the one with everything else
+    tab: Option<DetailKind>,
+    sampling: SamplingInputs,
+    output_length: OutputLengthInputs,
+    /// empty if the world uses the default template
+    system_prompt: text_editor::Content,
+    /// empty if the world uses the default prompt
+    summary_prompt: text_editor::Content,
+    /// empty if the world uses the number from the options
+    history_turns: String,
+    /// empty if the world has no script
+    script: text_editor::Content,
+    /// `None` leaves the choice to the options
+    preferred_llm: Option<llm::ProvidedModel>,
+    preferred_image_model: Option<image_model::ProvidedModel>,
+    text_only: bool,
+    /// `None` if the world isn't rated
+    content_rating: Option<ContentRating>,
+    tone: Tone,
+    cover: Option<Cover>,
+    generating_cover: bool,
     editing_character_name: Option<(String, String)>,
     current_file_path: Option<PathBuf>,
     buttons: BTreeMap<String, ActionFnArc>,
@@ -50,6 +82,95 @@ pub struct WorldEditor {
 struct CharacterInputs {
     description: text_editor::Content,
     initial_action: text_editor::Content,
+    /// one `name: modifier` per line
+    stats: text_editor::Content,
+}
+
+#[derive(Debug, Clone, Default)]
+struct DetailInputs {
+    name: String,
+    description: text_editor::Content,
+}
+
+/// Empty inputs leave the value to the provider
+#[derive(Debug, Clone, Default)]
+struct SamplingInputs {
+    temperature: String,
+    top_p: String,
+    /// one per line
+    stop_sequences: text_editor::Content,
+}
+
+impl SamplingInputs {
+    fn new(sampling: &Sampling) -> Self {
+        let fmt = |x: Option<f32>| x.map(|x| x.to_string()).unwrap_or_default();
+        Self {
+            temperature: fmt(sampling.temperature),
+            top_p: fmt(sampling.top_p),
+            stop_sequences: text_editor::Content::with_text(&sampling.stop_sequences.join("\n")),
+        }
+    }
+
+    fn to_sampling(&self) -> Result<Sampling> {
+        let parse = |name: &str, val: &str| -> Result<Option<f32>> {
+            if val.trim().is_empty() {
+                return Ok(None);
+            }
+            let x = val
+                .trim()
+                .parse()
+                .map_err(|_| eyre!("{name} must be a number, but it is: {val}"))?;
+            Ok(Some(x))
+        };
+        Ok(Sampling {
+            temperature: parse("Temperature", &self.temperature)?,
+            top_p: parse("Top P", &self.top_p)?,
+            stop_sequences: self
+                .stop_sequences
+                .text()
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// Empty inputs use the defaults of the engine
+#[derive(Debug, Clone, Default)]
+struct OutputLengthInputs {
+    target_words: String,
+    max_tokens: String,
+}
+
+impl OutputLengthInputs {
+    fn new(wd: &WorldDescription) -> Self {
+        let fmt = |x: Option<usize>| x.map(|x| x.to_string()).unwrap_or_default();
+        Self {
+            target_words: fmt(wd.target_output_words),
+            max_tokens: fmt(wd.max_tokens),
+        }
+    }
+
+    /// Returns the target word count and the max tokens
+    fn parse(&self) -> Result<(Option<usize>, Option<usize>)> {
+        let parse = |name: &str, val: &str| -> Result<Option<usize>> {
+            if val.trim().is_empty() {
+                return Ok(None);
+            }
+            let x = val
+                .trim()
+                .parse()
+                .ok()
+                .filter(|x| *x > 0)
+                .ok_or_else(|| eyre!("{name} must be a positive whole number, but it is: {val}"))?;
+            Ok(Some(x))
+        };
+        Ok((
+            parse("Target word count", &self.target_words)?,
+            parse("Max tokens", &self.max_tokens)?,
+        ))
+    }
 }
 
 impl fmt::Debug for WorldEditor {
@@ -59,6 +180,21 @@ impl fmt::Debug for WorldEditor {
             .field("description", &self.description)
             .field("init_action", &self.init_action)
             .field("characters", &self.characters)
+            .field("details", &self.details)
+            .field("tab", &self.tab)
+            .field("sampling", &self.sampling)
+            .field("output_length", &self.output_length)
+            .field("system_prompt", &self.system_prompt)
+            .field("summary_prompt", &self.summary_prompt)
+            .field("history_turns", &self.history_turns)
+            .field("script", &self.script)
+            .field("preferred_llm", &self.preferred_llm)
+            .field("preferred_image_model", &self.preferred_image_model)
+            .field("text_only", &self.text_only)
+            .field("content_rating", &self.content_rating)
+            .field("tone", &self.tone)
+            .field("cover", &self.cover)
+            .field("generating_cover", &self.generating_cover)
             .field("editing_character_name", &self.editing_character_name)
             .field("current_file_path", &self.current_file_path)
             .field(
@@ -88,10 +224,26 @@ impl WorldEditor {
                         CharacterInputs {
                             description: text_editor::Content::with_text(&v.description),
                             initial_action: text_editor::Content::with_text(&v.initial_action),
+                            stats: text_editor::Content::with_text(&v.stats_text()),
                         },
                     )
                 })
                 .collect(),
+            details: details_inputs(wd),
+            tab: None,
+            sampling: SamplingInputs::new(&wd.sampling),
+            output_length: OutputLengthInputs::new(wd),
+            system_prompt: system_prompt_content(wd),
+            summary_prompt: summary_prompt_content(wd),
+            history_turns: history_turns_input(wd),
+            script: script_content(wd),
+            preferred_llm: wd.preferred_llm,
+            preferred_image_model: wd.preferred_image_model,
+            text_only: wd.text_only,
+            content_rating: wd.content_rating,
+            tone: wd.tone,
+            cover: wd.cover.clone(),
+            generating_cover: false,
             editing_character_name: None,
             current_file_path: None,
             buttons: [
@@ -182,10 +334,26 @@ impl WorldEditor {
                             CharacterInputs {
                                 description: text_editor::Content::with_text(&v.description),
                                 initial_action: text_editor::Content::with_text(&v.initial_action),
+                                stats: text_editor::Content::with_text(&v.stats_text()),
                             },
                         )
                     })
                     .collect(),
+                details: details_inputs(wd),
+                tab: None,
+                sampling: SamplingInputs::new(&wd.sampling),
+                output_length: OutputLengthInputs::new(wd),
+                system_prompt: system_prompt_content(wd),
+                summary_prompt: summary_prompt_content(wd),
+                history_turns: history_turns_input(wd),
+                script: script_content(wd),
+                preferred_llm: wd.preferred_llm,
+                preferred_image_model: wd.preferred_image_model,
+                text_only: wd.text_only,
+                content_rating: wd.content_rating,
+                tone: wd.tone,
+                cover: wd.cover.clone(),
+                generating_cover: false,
                 editing_character_name: None,
                 current_file_path: Some(path),
                 buttons,
@@ -196,6 +364,21 @@ impl WorldEditor {
                 description: text_editor::Content::default(),
                 init_action: text_editor::Content::default(),
                 characters: BTreeMap::new(),
+                details: BTreeMap::new(),
+                tab: None,
+                sampling: SamplingInputs::default(),
+                output_length: OutputLengthInputs::default(),
+                system_prompt: text_editor::Content::default(),
+                summary_prompt: text_editor::Content::default(),
+                history_turns: String::new(),
+                script: text_editor::Content::default(),
+                preferred_llm: None,
+                preferred_image_model: None,
+                text_only: false,
+                content_rating: None,
+                tone: Tone::default(),
+                cover: None,
+                generating_cover: false,
                 editing_character_name: None,
                 current_file_path: None,
                 buttons,
@@ -211,7 +394,7 @@ impl WorldEditor {
         } else {
             return Ok(None);
         };
-        let world = self.mk_world();
+        let world = self.mk_world()?;
         fs::create_dir_all(path.parent().unwrap())?;
         fs::write(&path, world_to_markdown(&world))?;
         self.current_file_path = Some(path.clone());
@@ -228,25 +411,77 @@ impl WorldEditor {
         Ok(Some(world))
     }
 
-    fn mk_world(&self) -> WorldDescription {
-        WorldDescription {
+    fn mk_world(&self) -> Result<WorldDescription> {
+        let (target_output_words, max_tokens) = self.output_length.parse()?;
+        let system_prompt_template = Some(self.system_prompt.text())
+            .filter(|t| !t.trim().is_empty());
+        if let Some(template) = &system_prompt_template {
+            system_prompt::validate(template)?;
+        }
+        let summary_prompt = Some(self.summary_prompt.text())
+            .filter(|p| !p.trim().is_empty());
+        let history_turns = Some(self.history_turns.trim())
+            .filter(|n| !n.is_empty())
+            .map(|n| {
+                n.parse()
+                    .map_err(|_| eyre!("Verbatim turns must be a whole number, but it is: {n}"))
+            })
+            .transpose()?;
+        let details = |kind: DetailKind| -> Result<Vec<WorldDetail>> {
+            self.details
+                .get(&kind)
+                .into_iter()
+                .flatten()
+                .map(|inputs| {
+                    let name = inputs.name.trim();
+                    ensure!(!name.is_empty(), "One of the {kind} has no name");
+                    Ok(WorldDetail {
+                        name: name.into(),
+                        description: inputs.description.text(),
+                    })
+                })
+                .collect()
+        };
+        let script = Some(self.script.text()).filter(|s| !s.trim().is_empty());
+        if let Some(script) = &script {
+            Script::compile(script)?;
+        }
+        Ok(WorldDescription {
             name: self.name.clone(),
             main_description: self.description.text(),
             pc_descriptions: self
                 .characters
                 .iter()
                 .map(|(k, v)| {
-                    (
+                    Ok((
                         k.clone(),
                         PcDescription {
                             description: v.description.text(),
                             initial_action: v.initial_action.text(),
+                            stats: PcDescription::parse_stats(&v.stats.text())
+                                .wrap_err_with(|| format!("Invalid stats of {k}"))?,
                         },
-                    )
+                    ))
                 })
-                .collect(),
+                .collect::<Result<_>>()?,
             init_action: self.init_action.text(),
-        }
+            sampling: self.sampling.to_sampling()?,
+            target_output_words,
+            max_tokens,
+            system_prompt_template,
+            summary_prompt,
+            history_turns,
+            preferred_llm: self.preferred_llm,
+            preferred_image_model: self.preferred_image_model,
+            text_only: self.text_only,
+            content_rating: self.content_rating,
+            tone: self.tone,
+            locations: details(DetailKind::Locations)?,
+            factions: details(DetailKind::Factions)?,
+            npcs: details(DetailKind::Npcs)?,
+            script,
+            cover: self.cover.clone(),
+        })
     }
 
     fn choose_save_path(&self) -> Option<PathBuf> {
@@ -266,12 +501,336 @@ impl WorldEditor {
         }
     }
 
+    fn view_cover(&self) -> iced::Element<'_, UiMessage> {
+        let preview: iced::Element<'_, UiMessage> = match &self.cover {
+            Some(cover) => image(ImgHandle::from_bytes(cover.bytes().to_vec()))
+                .height(200)
+                .into(),
+            None => text("This world has no cover.").into(),
+        };
+        let generate_button = if self.generating_cover {
+            button("Generating...")
+        } else {
+            button("Generate").on_press(MyMessage::GenerateCover.into())
+        };
+        let remove_button = button("Remove")
+            .on_press_maybe(self.cover.is_some().then_some(MyMessage::RemoveCover.into()));
+        column![
+            preview,
+            row![
+                generate_button,
+                button("Import...").on_press(MyMessage::ImportCover.into()),
+                remove_button,
+            ]
+            .spacing(10),
+        ]
+        .spacing(10)
+        .into()
+    }
+
+    /// The tab with everything but the details
+    fn view_world(&self) -> Vec<iced::Element<'_, UiMessage>> {
+        let mut tlc = Vec::from(elem_list![
+            bold_text("New World").size(24).width(Length::Fill).center(),
+            text_input("World name", &self.name).on_input(|n| MyMessage::NameUpdate(n).into()),
+            text("Description:"),
+            text_editor(&self.description).on_action(|a| MyMessage::DescriptionUpdate(a).into()),
+            text("Initial Action:"),
+            text_editor(&self.init_action).on_action(|a| MyMessage::InitActionUpdate(a).into()),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Sampling")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text("Controls how wild the narration gets. Empty fields use the defaults of the LLM."),
+            row![
+                text("Temperature").width(200),
+                text_input("e.g. 1.0", &self.sampling.temperature)
+                    .on_input(|t| MyMessage::TemperatureUpdate(t).into()),
+            ]
+            .spacing(10),
+            row![
+                text("Top P").width(200),
+                text_input("e.g. 0.95", &self.sampling.top_p)
+                    .on_input(|p| MyMessage::TopPUpdate(p).into()),
+            ]
+            .spacing(10),
+            text("Stop sequences, one per line:"),
+            text_editor(&self.sampling.stop_sequences)
+                .on_action(|a| MyMessage::StopSequencesUpdate(a).into()),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Output Length")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text(format!(
+                "Empty fields use the defaults: about {DEFAULT_TARGET_OUTPUT_WORDS} words per turn, \
+                 and at most {DEFAULT_MAX_TOKENS} tokens for the whole reply."
+            )),
+            row![
+                text("Target word count").width(200),
+                text_input("e.g. 500", &self.output_length.target_words)
+                    .on_input(|w| MyMessage::TargetWordsUpdate(w).into()),
+            ]
+            .spacing(10),
+            row![
+                text("Max tokens").width(200),
+                text_input("e.g. 3000", &self.output_length.max_tokens)
+                    .on_input(|t| MyMessage::MaxTokensUpdate(t).into()),
+            ]
+            .spacing(10),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Models")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text("Games of this world can use other models than the ones that are selected in the options."),
+            text("LLM:"),
+            radio(
+                "As in the options",
+                None,
+                Some(self.preferred_llm),
+                |m| MyMessage::SelectPreferredLLM(m).into()
+            ),
+            column(llm::ProvidedModel::iter().map(|m| {
+                radio(format!("{m}"), Some(m), Some(self.preferred_llm), |m| {
+                    MyMessage::SelectPreferredLLM(m).into()
+                })
+                .into()
+            }))
+            .spacing(10),
+            text("Image model:"),
+            radio(
+                "As in the options",
+                None,
+                Some(self.preferred_image_model),
+                |m| MyMessage::SelectPreferredImageModel(m).into()
+            ),
+            column(image_model::ProvidedModel::iter().map(|m| {
+                radio(format!("{m}"), Some(m), Some(self.preferred_image_model), |m| {
+                    MyMessage::SelectPreferredImageModel(m).into()
+                })
+                .into()
+            }))
+            .spacing(10),
+            checkbox(self.text_only)
+                .label("Text only: new games make no images, which is cheaper and faster")
+                .on_toggle(|b| MyMessage::TextOnlyToggled(b).into()),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Content Rating")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text("Tells players what to expect, and holds the story and the image model to it."),
+            radio(
+                "Not rated",
+                None,
+                Some(self.content_rating),
+                |r| MyMessage::SelectContentRating(r).into()
+            ),
+            column(ContentRating::iter().map(|r| {
+                radio(
+                    format!("{r}: {}", r.description()),
+                    Some(r),
+                    Some(self.content_rating),
+                    |r| MyMessage::SelectContentRating(r).into(),
+                )
+                .into()
+            }))
+            .spacing(10),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Tone")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text("Each step away from the middle adds an instruction to the system prompt. \
+                  The tone can also be changed during a game, with the GM tools."),
+            tone_sliders(&self.tone, |knob, level| MyMessage::ToneChanged(knob, level).into()),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("System Prompt")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text("The instructions for the LLM. Leave it empty to use the default. Variables like {player} \
+                  or {world_description} are replaced, literal braces must be written as \\{."),
+            text_editor(&self.system_prompt)
+                .height(200)
+                .on_action(|a| MyMessage::SystemPromptUpdate(a).into()),
+            button("Start from the default").on_press(MyMessage::UseDefaultSystemPrompt.into()),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Summary Prompt")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text("The instructions for the LLM that summarizes the story every few turns. Leave it empty to use the default."),
+            text_editor(&self.summary_prompt)
+                .height(200)
+                .on_action(|a| MyMessage::SummaryPromptUpdate(a).into()),
+            button("Start from the default").on_press(MyMessage::UseDefaultSummaryPrompt.into()),
+            text("The last turns a summary covers are still sent as they are. Leave it empty to use the number from the options."),
+            row![
+                text("Verbatim turns").width(200),
+                text_input("e.g. 4", &self.history_turns)
+                    .on_input(|n| MyMessage::HistoryTurnsUpdate(n).into()),
+            ]
+            .spacing(10),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Script")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text("Rhai code for custom mechanics, like hunger or reputation. It can define the hooks \
+                  on_before_request, on_parse_output and on_turn_complete, which get the turn and the \
+                  world state as `this`. Leave it empty if the world needs none."),
+            text_editor(&self.script)
+                .height(200)
+                .on_action(|a| MyMessage::ScriptUpdate(a).into()),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Cover")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+            text("Shown in the lists of worlds and saves. It can be generated from the description, \
+                  with the image model the world's games use."),
+            self.view_cover(),
+            Space::new().height(20),
+            rule::horizontal(2),
+            bold_text("Characters")
+                .size(20)
+                .width(Length::Fill)
+                .center(),
+        ]);
+
+        let char_col =
+            self.characters
+                .iter()
+                .map(|(name, content)| {
+                    let name_row: iced::Element<'_, UiMessage> =
+                        if matches!(&self.editing_character_name, Some((edited_name, _)) if edited_name == name)
+                        {
+                            let edited_name = &self
+                                .editing_character_name
+                                .as_ref()
+                                .expect("checked above")
+                                .1;
+                            row![
+                                text_input("Character name", edited_name)
+                                    .on_input(|n| MyMessage::UpdateCharacterName(n).into())
+                                    .on_submit(MyMessage::ConfirmCharacterNameEdit.into()),
+                                button("ok").on_press(MyMessage::ConfirmCharacterNameEdit.into()),
+                                button("delete")
+                                    .on_press(MyMessage::DeleteCharacter(name.clone()).into()),
+                            ]
+                            .spacing(10)
+                            .width(Length::Fill)
+                            .into()
+                        } else {
+                            row![
+                                text(name)
+                                    .font(Font {
+                                        weight: iced::font::Weight::Semibold,
+                                        ..Font::DEFAULT
+                                    })
+                                    .size(16),
+                                space::horizontal(),
+                                button("✎")
+                                    .on_press(MyMessage::EditCharacterName(name.clone()).into()),
+                                button("delete")
+                                    .on_press(MyMessage::DeleteCharacter(name.clone()).into()),
+                            ]
+                            .spacing(10)
+                            .width(Length::Fill)
+                            .into()
+                        };
+                    column![
+                        name_row,
+                        text_editor(&content.description)
+                            .on_action(|a| MyMessage::UpdateCharacter(name.clone(), a).into()),
+                        text("Initial Action:"),
+                        text_editor(&content.initial_action).on_action(|a| {
+                            MyMessage::UpdateCharacterInitAction(name.clone(), a).into()
+                        }),
+                        text("Stats (one \"name: modifier\" per line, optional):"),
+                        text_editor(&content.stats)
+                            .placeholder("Stealth: +2")
+                            .on_action(|a| {
+                                MyMessage::UpdateCharacterStats(name.clone(), a).into()
+                            }),
+                    ]
+                    .spacing(10)
+                    .into()
+                })
+                .chain([button("Add Character")
+                    .on_press(MyMessage::AddCharacterButton.into())
+                    .into()]);
+
+        tlc.push(
+            container(column(char_col).spacing(20))
+                .padding([30, 0])
+                .into(),
+        );
+        tlc
+    }
+
+    fn view_details(&self, kind: DetailKind) -> Vec<iced::Element<'_, UiMessage>> {
+        let details = self
+            .details
+            .get(&kind)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let intro = format!(
+            "Each gets a line in the {kind} section of the system prompt, so the story keeps them \
+             consistent."
+        );
+        let mut col = Vec::from(elem_list![
+            bold_text(kind.to_string()).size(24).width(Length::Fill).center(),
+            text(intro),
+        ]);
+        col.extend(details.iter().enumerate().map(|(i, detail)| {
+            column![
+                row![
+                    text_input("Name", &detail.name)
+                        .on_input(move |n| MyMessage::DetailNameUpdate(kind, i, n).into()),
+                    button("delete").on_press(MyMessage::RemoveDetail(kind, i).into()),
+                ]
+                .spacing(10),
+                text_editor(&detail.description)
+                    .placeholder("Description")
+                    .on_action(move |a| MyMessage::DetailDescriptionUpdate(kind, i, a).into()),
+            ]
+            .spacing(10)
+            .into()
+        }));
+        col.push(
+            button("Add")
+                .on_press(MyMessage::AddDetail(kind).into())
+                .into(),
+        );
+        col
+    }
+
+    fn detail_mut(&mut self, kind: DetailKind, i: usize) -> Result<&mut DetailInputs> {
+        self.details
+            .get_mut(&kind)
+            .and_then(|details| details.get_mut(i))
+            .ok_or_else(|| eyre!("There is no {kind} entry {i}"))
+    }
+
     fn try_save_world_to_context(&mut self, ctx: &mut Context) -> Result<()> {
         let Some(gctx) = &mut ctx.game else {
             bail!("running try_save_world_to_context without game context");
         };
 
-        gctx.upate_world_description(self.mk_world())?;
+        gctx.upate_world_description(self.mk_world()?)?;
         Ok(())
     }
 
@@ -325,6 +884,30 @@ impl State for WorldEditor {
                 self.characters.insert(name, CharacterInputs::default());
                 cmd::none()
             }
+            SelectTab(tab) => {
+                self.tab = tab;
+                cmd::none()
+            }
+            AddDetail(kind) => {
+                self.details
+                    .entry(kind)
+                    .or_default()
+                    .push(DetailInputs::default());
+                cmd::none()
+            }
+            RemoveDetail(kind, i) => {
+                self.detail_mut(kind, i)?;
+                self.details.entry(kind).or_default().remove(i);
+                cmd::none()
+            }
+            DetailNameUpdate(kind, i, name) => {
+                self.detail_mut(kind, i)?.name = name;
+                cmd::none()
+            }
+            DetailDescriptionUpdate(kind, i, a) => {
+                self.detail_mut(kind, i)?.description.perform(a);
+                cmd::none()
+            }
             EditCharacterName(name) => {
                 self.begin_edit_character_name(name);
                 cmd::none()
@@ -363,6 +946,14 @@ impl State for WorldEditor {
                     .perform(a);
                 cmd::none()
             }
+            UpdateCharacterStats(name, a) => {
+                self.characters
+                    .get_mut(&name)
+                    .ok_or(eyre!("Character name invalid"))?
+                    .stats
+                    .perform(a);
+                cmd::none()
+            }
             DescriptionUpdate(a) => {
                 self.description.perform(a);
                 cmd::none()
@@ -375,6 +966,105 @@ impl State for WorldEditor {
                 self.init_action.perform(a);
                 cmd::none()
             }
+            TemperatureUpdate(t) => {
+                self.sampling.temperature = t;
+                cmd::none()
+            }
+            TopPUpdate(p) => {
+                self.sampling.top_p = p;
+                cmd::none()
+            }
+            StopSequencesUpdate(a) => {
+                self.sampling.stop_sequences.perform(a);
+                cmd::none()
+            }
+            TargetWordsUpdate(w) => {
+                self.output_length.target_words = w;
+                cmd::none()
+            }
+            MaxTokensUpdate(t) => {
+                self.output_length.max_tokens = t;
+                cmd::none()
+            }
+            SystemPromptUpdate(a) => {
+                self.system_prompt.perform(a);
+                cmd::none()
+            }
+            SummaryPromptUpdate(a) => {
+                self.summary_prompt.perform(a);
+                cmd::none()
+            }
+            HistoryTurnsUpdate(n) => {
+                self.history_turns = n;
+                cmd::none()
+            }
+            ScriptUpdate(a) => {
+                self.script.perform(a);
+                cmd::none()
+            }
+            SelectPreferredLLM(model) => {
+                self.preferred_llm = model;
+                cmd::none()
+            }
+            SelectPreferredImageModel(model) => {
+                self.preferred_image_model = model;
+                cmd::none()
+            }
+            TextOnlyToggled(text_only) => {
+                self.text_only = text_only;
+                cmd::none()
+            }
+            SelectContentRating(rating) => {
+                self.content_rating = rating;
+                cmd::none()
+            }
+            ToneChanged(knob, level) => {
+                self.tone.set(knob, level);
+                cmd::none()
+            }
+            GenerateCover => {
+                let world = self.mk_world()?;
+                // the model the world's games would use
+                let config = ctx.config.for_world(&world);
+                let model = config
+                    .get_image_model()
+                    .ok_or(eyre!("There is no API key for the selected image model"))?;
+                let style = config.active_style().cloned();
+                self.generating_cover = true;
+                cmd::task(Task::perform(
+                    async move { world_cover::generate(&world, model, style).await },
+                    |res| -> UiMessage {
+                        MyMessage::CoverGenerated(res.map_err(|e| format!("{e:#}"))).into()
+                    },
+                ))
+            }
+            CoverGenerated(res) => {
+                self.generating_cover = false;
+                self.cover = Some(res.map_err(|e| eyre!("Failed to generate the cover: {e}"))?);
+                cmd::none()
+            }
+            ImportCover => {
+                let Some(path) = rfd::FileDialog::new()
+                    .add_filter("Images", &["jpg", "jpeg", "png", "webp"])
+                    .pick_file()
+                else {
+                    return cmd::none();
+                };
+                self.cover = Some(Cover::from_image(&fs::read(path)?)?);
+                cmd::none()
+            }
+            RemoveCover => {
+                self.cover = None;
+                cmd::none()
+            }
+            UseDefaultSystemPrompt => {
+                self.system_prompt = text_editor::Content::with_text(system_prompt::DEFAULT_TEMPLATE);
+                cmd::none()
+            }
+            UseDefaultSummaryPrompt => {
+                self.summary_prompt = text_editor::Content::with_text(summary::DEFAULT_PROMPT);
+                cmd::none()
+            }
             Button(which) => {
                 let handler = self
                     .buttons
@@ -387,83 +1077,10 @@ impl State for WorldEditor {
     }
 
     fn view<'a>(&'a self, _ctx: &'a Context) -> iced::Element<'a, UiMessage> {
-        let mut tlc = Vec::from(elem_list![
-            bold_text("New World").size(24).width(Length::Fill).center(),
-            text_input("World name", &self.name).on_input(|n| MyMessage::NameUpdate(n).into()),
-            text("Description:"),
-            text_editor(&self.description).on_action(|a| MyMessage::DescriptionUpdate(a).into()),
-            text("Initial Action:"),
-            text_editor(&self.init_action).on_action(|a| MyMessage::InitActionUpdate(a).into()),
-            Space::new().height(20),
-            rule::horizontal(2),
-            bold_text("Characters")
-                .size(20)
-                .width(Length::Fill)
-                .center(),
-        ]);
-
-        let char_col =
-            self.characters
-                .iter()
-                .map(|(name, content)| {
-                    let name_row: iced::Element<'_, UiMessage> =
-                        if matches!(&self.editing_character_name, Some((edited_name, _)) if edited_name == name)
-                        {
-                            let edited_name = &self
-                                .editing_character_name
-                                .as_ref()
-                                .expect("checked above")
-                                .1;
-                            row![
-                                text_input("Character name", edited_name)
-                                    .on_input(|n| MyMessage::UpdateCharacterName(n).into())
-                                    .on_submit(MyMessage::ConfirmCharacterNameEdit.into()),
-                                button("ok").on_press(MyMessage::ConfirmCharacterNameEdit.into()),
-                                button("delete")
-                                    .on_press(MyMessage::DeleteCharacter(name.clone()).into()),
-                            ]
-                            .spacing(10)
-                            .width(Length::Fill)
-                            .into()
-                        } else {
-                            row![
-                                text(name)
-                                    .font(Font {
-                                        weight: iced::font::Weight::Semibold,
-                                        ..Font::DEFAULT
-                                    })
-                                    .size(16),
-                                space::horizontal(),
-                                button("✎")
-                                    .on_press(MyMessage::EditCharacterName(name.clone()).into()),
-                                button("delete")
-                                    .on_press(MyMessage::DeleteCharacter(name.clone()).into()),
-                            ]
-                            .spacing(10)
-                            .width(Length::Fill)
-                            .into()
-                        };
-                    column![
-                        name_row,
-                        text_editor(&content.description)
-                            .on_action(|a| MyMessage::UpdateCharacter(name.clone(), a).into()),
-                        text("Initial Action:"),
-                        text_editor(&content.initial_action).on_action(|a| {
-                            MyMessage::UpdateCharacterInitAction(name.clone(), a).into()
-                        }),
-                    ]
-                    .spacing(10)
-                    .into()
-                })
-                .chain([button("Add Character")
-                    .on_press(MyMessage::AddCharacterButton.into())
-                    .into()]);
-
-        tlc.push(
-            container(column(char_col).spacing(20))
-                .padding([30, 0])
-                .into(),
-        );
+        let tlc = match self.tab {
+            Some(kind) => self.view_details(kind),
+            None => self.view_world(),
+        };
 
         let mut button_row = vec![space::horizontal().into()];
         for bcaption in self.buttons.keys() {
@@ -475,6 +1092,14 @@ impl State for WorldEditor {
         }
 
         button_row.push(space::horizontal().into());
+        let tabs = row(std::iter::once((None, "World".to_string()))
+            .chain(DetailKind::iter().map(|kind| (Some(kind), kind.to_string())))
+            .map(|(tab, label)| {
+                button(text(label))
+                    .on_press_maybe((self.tab != tab).then(|| MyMessage::SelectTab(tab).into()))
+                    .into()
+            }))
+        .spacing(10);
         let content = container(
             scrollable(
                 container(column(tlc).width(Length::Fill).spacing(20))
@@ -487,6 +1112,7 @@ impl State for WorldEditor {
         container(
             container(
                 column![
+                    container(tabs).padding(10),
                     content,
                     container(row(button_row).spacing(10).width(Length::Fill)).padding(10)
                 ]
@@ -512,3 +1138,35 @@ where
 {
     Arc::new(f)
 }
+
+fn system_prompt_content(wd: &WorldDescription) -> text_editor::Content {
+    text_editor::Content::with_text(wd.system_prompt_template.as_deref().unwrap_or_default())
+}
+
+fn history_turns_input(wd: &WorldDescription) -> String {
+    wd.history_turns.map(|n| n.to_string()).unwrap_or_default()
+}
+
+fn summary_prompt_content(wd: &WorldDescription) -> text_editor::Content {
+    text_editor::Content::with_text(wd.summary_prompt.as_deref().unwrap_or_default())
+}
+
+fn details_inputs(wd: &WorldDescription) -> BTreeMap<DetailKind, Vec<DetailInputs>> {
+    DetailKind::iter()
+        .map(|kind| {
+            let inputs = wd
+                .details(kind)
+                .iter()
+                .map(|detail| DetailInputs {
+                    name: detail.name.clone(),
+                    description: text_editor::Content::with_text(&detail.description),
+                })
+                .collect();
+            (kind, inputs)
+        })
+        .collect()
+}
+
+fn script_content(wd: &WorldDescription) -> text_editor::Content {
+    text_editor::Content::with_text(wd.script.as_deref().unwrap_or_default())
+}
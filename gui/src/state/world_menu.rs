@@ -1,10 +1,14 @@
 use std::path::PathBuf;
 
 use color_eyre::Result;
-use engine::{game::WorldDescription, world_markdown::world_from_markdown};
+use engine::{
+    game::{ContentRating, WorldDescription},
+    world_markdown::world_from_markdown,
+};
 use iced::{
     Length,
-    widget::{Space, button, column, row, space, text, tooltip},
+    advanced::image::Handle as ImgHandle,
+    widget::{Space, button, column, image, row, space, text, tooltip},
 };
 use log::debug;
 
@@ -15,6 +19,8 @@ use crate::{
     top_level_container,
 };
 
+const COVER_SIZE: f32 = 96.0;
+
 #[derive(Clone, Debug)]
 pub struct WorldMenu {
     worlds: Vec<RememberedWorldEntry>,
@@ -25,6 +31,7 @@ struct RememberedWorldEntry {
     path: PathBuf,
     last_known_name: String,
     loaded_world: Option<WorldDescription>,
+    cover: Option<ImgHandle>,
 }
 
 impl RememberedWorldEntry {
@@ -40,6 +47,7 @@ impl RememberedWorldEntry {
         Self {
             path: world.path,
             last_known_name,
+            cover: loaded_world.as_ref().and_then(cover_handle),
             loaded_world,
         }
     }
@@ -51,6 +59,10 @@ impl RememberedWorldEntry {
             .unwrap_or(&self.last_known_name)
     }
 
+    fn content_rating(&self) -> Option<ContentRating> {
+        self.loaded_world.as_ref()?.content_rating
+    }
+
     fn remember(&self) -> RememberedWorld {
         RememberedWorld {
             path: self.path.clone(),
@@ -99,14 +111,17 @@ impl WorldMenu {
         let src = std::fs::read_to_string(&path)?;
         let world = world_from_markdown(&src)?;
 
+        let cover = cover_handle(&world);
         if let Some(existing) = self.worlds.iter_mut().find(|entry| entry.path == path) {
             existing.last_known_name = world.name.clone();
             existing.loaded_world = Some(world);
+            existing.cover = cover;
         } else {
             self.worlds.push(RememberedWorldEntry {
                 path,
                 last_known_name: world.name.clone(),
                 loaded_world: Some(world),
+                cover,
             });
         }
 
@@ -197,14 +212,22 @@ impl super::State for WorldMenu {
                 button("start")
             };
 
+            let cover: iced::Element<'_, crate::message::UiMessage> = match &world.cover {
+                Some(handle) => image(handle).width(COVER_SIZE).into(),
+                None => Space::new().width(COVER_SIZE).into(),
+            };
+
+            let mut info = column![text(world.display_name())].spacing(4);
+            if let Some(rating) = world.content_rating() {
+                info = info.push(text!("Rated {rating}: {}", rating.description()).size(14));
+            }
+            let info = info.push(text(world.path.display().to_string()).size(14));
+
             tlc.push(
                 row![
                     warning,
-                    column![
-                        text(world.display_name()),
-                        text(world.path.display().to_string()).size(14)
-                    ]
-                    .spacing(4),
+                    cover,
+                    info,
                     space::horizontal(),
                     button("forget").on_press(MyMessage::ForgetWorld(i).into()),
                     edit_button,
@@ -228,3 +251,11 @@ impl super::State for WorldMenu {
         Box::new(Clone::clone(self))
     }
 }
+
+/// Decoding happens when the handle is drawn first, so it's made once per entry
+pub(crate) fn cover_handle(world: &WorldDescription) -> Option<ImgHandle> {
+    world
+        .cover
+        .as_ref()
+        .map(|cover| ImgHandle::from_bytes(cover.bytes().to_vec()))
+}